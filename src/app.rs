@@ -0,0 +1,2228 @@
+//! TUI の画面状態（表示行・入力行・スクロール・過去ログ・入力履歴）とその操作。
+//! 端末やネットワークには触れないので、Action の適用まで単体テストできる。
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+
+use p2witter::core::rpc::{self, SigState};
+use p2witter::storage::{self, MessageRecord};
+use p2witter::{config, utils};
+
+use crate::check;
+use crate::commands::{Action, PeerQuery, PickFor};
+use crate::template::Template;
+use crate::theme::{self, HandleColors, Theme};
+use crate::{format_peer_table, reply_quote, split_at_char};
+
+/// 差分描画用の状態
+pub struct DrawState {
+    pub last_msg_len: usize,
+    /// 前回描画時の端末サイズ（変われば全体を描き直す）
+    pub last_size: (u16, u16),
+    /// 前回描画時の折返し後の行数
+    pub last_total_lines: usize,
+    pub last_input_len: usize,
+    pub last_cursor_pos: usize,
+    pub force_full: bool,
+    pub theme: Theme,
+    /// 自分の投稿を見分けるためのハンドル
+    pub own_handle: String,
+    /// 中継 ON/OFF（ステータスバー表示用）
+    pub relay: bool,
+    /// 部屋のトピック（ステータスバー表示用）
+    pub topic: Option<String>,
+    /// ブックマーク済みのメッセージID（行末に印を付ける）
+    pub bookmarks: HashSet<String>,
+    /// 過去ログで /find が見つけた行（past_messages の位置、反転表示する）
+    pub found: Option<usize>,
+    /// ハンドルごとの文字色
+    pub handle_colors: HandleColors,
+    /// 同じハンドルの投稿が続いたら 2 行目以降の "@handle:" を省く
+    pub compact: bool,
+    /// 接続中のピア数（ステータスバー・プロンプトの {peers}）
+    pub peers: usize,
+    /// status_format。None なら従来の表示
+    pub status_format: Option<Template>,
+    /// prompt_format（既定は "> "）
+    pub prompt: Template,
+    /// 上へスクロールしている間に届いた行数（ステータスバーに出す）
+    pub new_below: usize,
+    /// おやすみモード中（ステータスバーに出す）
+    pub dnd: bool,
+    /// 集中モード中に隠している投稿の数（集中モードでなければ None）
+    pub focus_hidden: Option<usize>,
+    /// これより長い行は先頭だけ表示する（文字数、0 なら縮めない）
+    pub max_display_chars: usize,
+    /// /show で全文表示中の行（表示中の一覧内の位置）
+    pub expanded: Option<usize>,
+}
+
+impl DrawState {
+    pub fn new() -> Self {
+        Self {
+            last_msg_len: 0,
+            last_size: (0, 0),
+            last_total_lines: 0,
+            last_input_len: 0,
+            last_cursor_pos: 0,
+            force_full: true,
+            theme: Theme::default(),
+            own_handle: String::new(),
+            relay: true,
+            topic: None,
+            bookmarks: HashSet::new(),
+            found: None,
+            handle_colors: HandleColors::default(),
+            compact: false,
+            peers: 0,
+            status_format: None,
+            prompt: Template::default(),
+            new_below: 0,
+            dnd: false,
+            focus_hidden: None,
+            max_display_chars: 0,
+            expanded: None,
+        }
+    }
+}
+
+/// メッセージ領域の描画方法
+#[derive(Debug, PartialEq, Eq)]
+pub enum Repaint {
+    /// ステータスバーとメッセージ領域を全て描き直す
+    Full,
+    /// messages[from..] だけを末尾に描き足す（ステータスバーも更新）
+    Tail { from: usize },
+    /// 変化なし
+    None,
+}
+
+impl DrawState {
+    /// 前回の描画からの変化を見て描画方法を決める。
+    /// 最下端を表示中に行が増えただけなら、何件増えても末尾の描き足し1回にまとめる
+    pub fn plan(&self, msg_len: usize, at_bottom: bool, size: (u16, u16)) -> Repaint {
+        if self.force_full || size != self.last_size || msg_len < self.last_msg_len {
+            Repaint::Full
+        } else if msg_len == self.last_msg_len {
+            Repaint::None
+        } else if at_bottom {
+            Repaint::Tail {
+                from: self.last_msg_len,
+            }
+        } else {
+            Repaint::Full
+        }
+    }
+
+    /// 描画が終わった状態を記録する
+    pub fn rendered(&mut self, msg_len: usize, size: (u16, u16), total_lines: usize) {
+        self.last_msg_len = msg_len;
+        self.last_size = size;
+        self.last_total_lines = total_lines;
+        self.force_full = false;
+    }
+}
+
+pub struct Tui {
+    pub messages: Vec<String>,
+    /// メッセージID → messages 内の行（編集・削除で差し替える）
+    pub tagged: HashMap<String, usize>,
+    /// ID 付与待ちの自分の投稿（ローカルエコー行。None は押し出されて画面に無いもの）
+    pub pending_echo: VecDeque<Option<usize>>,
+    /// pending_echo の先頭から何件が送信待ち (Queued) か
+    pub queued: usize,
+    /// messages 中の受信投稿の署名状態
+    pub sig_counts: SigCounts,
+    /// past_messages 中の受信投稿の署名状態
+    pub past_sig_counts: SigCounts,
+    pub input: String,
+    /// 入力カーソル（文字単位）
+    pub cursor_pos: usize,
+    /// 次に入力行を消したときに代わりに入れる本文（/draft load）
+    next_input: Option<String>,
+    pub status_msg: String,
+    pub running: bool,
+    // 過去ログモード関連
+    pub past_mode: bool,
+    pub past_messages: Vec<String>,
+    pub past_dates: Vec<String>,
+    pub past_date_range: String,
+    /// 読み込み済みで最も古い day の past_dates index
+    pub past_earliest_idx: Option<usize>,
+    /// 0=最新 (一番下)。増えると過去へ。
+    pub scroll_offset: usize,
+    /// 過去ログ用の独立オフセット
+    pub past_scroll_offset: usize,
+    pub history: Vec<String>,
+    /// history 内のインデックス (0..len-1)。None は編集中の新規行。
+    pub history_pos: Option<usize>,
+    /// VS Code 統合ターミナルでのマウス選択・コピー用モード
+    /// F2 でトグル: 有効時は MouseCapture を解除し、画面更新を止めて選択しやすくする
+    pub copy_mode: bool,
+    /// マウスのイベントを受け取れているか（EnableMouseCapture に失敗したら false）
+    pub mouse_capture: bool,
+    pub draw: DrawState,
+    /// 過去ログ内の /find の検索状態
+    pub find: Option<FindState>,
+    /// NO_COLOR 環境変数か [theme] no_color = true なら /theme で切り替えても色を出さない
+    pub force_no_color: bool,
+    /// messages に残す最大行数（0 は無制限）。古い行は保存済みなので過去ログで見られる
+    pub scrollback_max: usize,
+    /// /dnd on で入れたおやすみモード
+    pub dnd_manual: bool,
+    /// dnd_hours の時間帯（この間は /dnd off でもおやすみモード）
+    pub quiet_hours: Option<QuietHours>,
+    /// 今が quiet_hours の中か（refresh_dnd で更新）
+    quiet_now: bool,
+    /// 次の描画でベルを鳴らす
+    pub bell: bool,
+    /// 他人の至急の印を目立たせるか（honor_urgent。false ならブックマークでも上に並べない）
+    pub honor_urgent: bool,
+    /// おやすみモード中でも至急の投稿ならベルを鳴らす（urgent_bell_in_dnd）
+    pub urgent_bell_in_dnd: bool,
+    /// 集中モード中に届き、まだ画面に出していない投稿のイベント
+    focus: Option<Vec<rpc::Event>>,
+    /// 落ちても失わないよう入力行を書き出す先
+    pub inflight: Option<Inflight>,
+    /// 開いているピアの選択（開いている間はキー入力をこちらで受ける）
+    pub picker: Option<PeerPicker>,
+}
+
+/// 書きかけの入力行を書き出す間隔
+pub const INFLIGHT_SAVE_INTERVAL_MS: u64 = 1000;
+
+/// 書きかけの入力行（と カーソル位置）の退避ファイル。
+/// /draft と違い自動で書き、送信するか Esc で消すとファイルも消す
+pub struct Inflight {
+    path: PathBuf,
+    /// 最後に書き出した (入力, カーソル)
+    saved: (String, usize),
+    last_write: u64,
+}
+
+impl Inflight {
+    /// 退避ファイルを開き、前回の書きかけが残っていれば一緒に返す
+    pub fn load(path: PathBuf) -> (Self, Option<(String, usize)>) {
+        let left = std::fs::read_to_string(&path).ok().and_then(|s| {
+            let (cursor, text) = s.split_once('\n')?;
+            Some((text.to_string(), cursor.parse().ok()?)).filter(|(t, _)| !t.is_empty())
+        });
+        let saved = left.clone().unwrap_or_default();
+        let file = Self {
+            path,
+            saved,
+            last_write: 0,
+        };
+        (file, left)
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// 変わっていれば書き出す（間隔を空ける）。空になったらすぐ消す
+    fn save(&mut self, input: &str, cursor: usize, now: u64) {
+        if self.saved.0 == input && self.saved.1 == cursor {
+            return;
+        }
+        if input.is_empty() {
+            let _ = std::fs::remove_file(&self.path);
+        } else if now.saturating_sub(self.last_write) < INFLIGHT_SAVE_INTERVAL_MS {
+            return;
+        } else {
+            let _ = std::fs::write(&self.path, format!("{}\n{}", cursor, input));
+        }
+        self.saved = (input.to_string(), cursor);
+        self.last_write = now;
+    }
+}
+
+/// おやすみモードの時間帯（0 時からの分、start > end なら日をまたぐ）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl QuietHours {
+    /// "22:00-07:00" の形を読む
+    pub fn parse(s: &str) -> Option<Self> {
+        let minutes = |t: &str| {
+            let (h, m) = t.trim().split_once(':')?;
+            let (h, m) = (h.parse::<u16>().ok()?, m.parse::<u16>().ok()?);
+            (h < 24 && m < 60).then_some(h * 60 + m)
+        };
+        let (a, b) = s.split_once('-')?;
+        Some(Self {
+            start: minutes(a)?,
+            end: minutes(b)?,
+        })
+    }
+
+    pub fn contains(&self, minute: u16) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl std::fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// scrollback_max の既定値
+pub const DEFAULT_SCROLLBACK_MAX: usize = 10_000;
+
+/// /find が一致を探して自動で読み足す過去の日数（1 回の検索あたり）
+pub const MAX_FIND_LOAD_DAYS: usize = 30;
+
+/// 過去ログ内の /find の状態
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FindState {
+    pub query: String,
+    /// 今見ている一致の past_messages 内の位置
+    pub hit: Option<usize>,
+}
+
+/// 引数なしの /dm・/disconnect で開く、接続中のピアの選択
+#[derive(Debug)]
+pub struct PeerPicker {
+    pub purpose: PickFor,
+    /// 選べるピア（id 順）。ネットワークスレッドから一覧が届くまでは None
+    pub peers: Option<Vec<rpc::PeerInfo>>,
+    /// peers 内の選択位置
+    pub selected: usize,
+}
+
+impl PeerPicker {
+    pub fn new(purpose: PickFor) -> Self {
+        Self {
+            purpose,
+            peers: None,
+            selected: 0,
+        }
+    }
+
+    /// 届いた一覧を id 順に並べる。DM は HELLO を終えた相手にしか送れないのでそれだけ残す
+    pub fn set_peers(&mut self, mut peers: Vec<rpc::PeerInfo>) {
+        if self.purpose == PickFor::Dm {
+            peers.retain(|p| p.state == rpc::PeerState::Ready);
+        }
+        peers.sort_by_key(|p| p.id);
+        self.selected = 0;
+        self.peers = Some(peers);
+    }
+
+    /// 選択を上下に動かす（端で止まる）
+    pub fn move_selection(&mut self, up: bool) {
+        let len = self.peers.as_ref().map_or(0, Vec::len);
+        self.selected = if up {
+            self.selected.saturating_sub(1)
+        } else {
+            (self.selected + 1).min(len.saturating_sub(1))
+        };
+    }
+
+    pub fn selected_peer(&self) -> Option<&rpc::PeerInfo> {
+        self.peers.as_ref()?.get(self.selected)
+    }
+
+    /// 決めたときに入力行へ入れるコマンド。DM は本文を続けて打てるよう末尾を空け、
+    /// 一覧を取った後に接続番号がずれても同じ相手に届くよう指紋で指定する
+    pub fn command(&self) -> Option<String> {
+        let peer = self.selected_peer()?;
+        Some(match self.purpose {
+            PickFor::Dm => match &peer.fingerprint {
+                Some(fp) => format!("/dm {} ", fp),
+                None => format!("/dm {} ", peer.id),
+            },
+            PickFor::Disconnect => format!("/disconnect {}", peer.id),
+        })
+    }
+
+    /// 画面に重ねて出す行（見出しと、選択中の行の位置）
+    pub fn lines(&self) -> (Vec<String>, Option<usize>) {
+        let title = match self.purpose {
+            PickFor::Dm => "DM の宛先",
+            PickFor::Disconnect => "切断するピア",
+        };
+        let mut lines = vec![format!(
+            "{}を選択 (↑↓ で移動、Enter で決定、Esc で閉じる)",
+            title
+        )];
+        let Some(peers) = &self.peers else {
+            lines.push("  一覧を取得中...".into());
+            return (lines, None);
+        };
+        if peers.is_empty() {
+            lines.push("  選べるピアがいません".into());
+            return (lines, None);
+        }
+        lines.extend(peers.iter().enumerate().map(|(i, p)| {
+            format!(
+                "{} id={} {}{} 指紋={}",
+                if i == self.selected { "▶" } else { " " },
+                p.id,
+                p.handle.as_deref().unwrap_or("?"),
+                if p.handle_suspect { " ⚠" } else { "" },
+                p.fingerprint.as_deref().unwrap_or("?")
+            )
+        }));
+        (lines, Some(self.selected + 1))
+    }
+}
+
+/// マウスのスクロールが使えないときに出す、キーボードでのスクロールの案内
+pub const KEYBOARD_SCROLL_HINT: &str =
+    "PageUp / PageDown で 1 画面、Shift+↑↓ で 1 行スクロール、End で最新に戻れます";
+
+/// 送信待ちの自分の投稿に付ける印
+pub const QUEUED_MARK: &str = " (送信待ち)";
+/// 送れなかった自分の投稿に付ける印
+pub const UNSENT_MARK: &str = " (未送信)";
+
+/// 表示中の受信投稿の署名状態の内訳（ステータスバー用）
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SigCounts {
+    pub valid: usize,
+    pub unsigned: usize,
+    pub invalid: usize,
+}
+
+impl SigCounts {
+    pub fn add(&mut self, sig: SigState) {
+        match sig {
+            SigState::Valid => self.valid += 1,
+            SigState::Unsigned => self.unsigned += 1,
+            SigState::Invalid => self.invalid += 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl std::fmt::Display for SigCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "✓{} ・{} ×{}", self.valid, self.unsigned, self.invalid)
+    }
+}
+
+/// 集中モードで隠す、届いた投稿のイベント。
+/// 隠した投稿への差し替えも、表示するときに順に当てるので一緒に取っておく
+fn is_live_post(ev: &rpc::Event, tagged: &HashMap<String, usize>) -> bool {
+    match ev {
+        rpc::Event::Post { .. } | rpc::Event::Chat { .. } => true,
+        rpc::Event::Replace { id, .. } => !tagged.contains_key(id),
+        _ => false,
+    }
+}
+
+// 1 日分の保存済みレコードを過去ログの表示行にし、受信投稿の署名状態を数える
+fn load_past_day(day: &str) -> (Vec<String>, SigCounts) {
+    let mut counts = SigCounts::default();
+    let lines = storage::load_structured_day(day)
+        .into_iter()
+        .map(|r| {
+            if r.from_peer_id.is_some() {
+                counts.add(r.signature);
+            }
+            past_line(r)
+        })
+        .collect();
+    (lines, counts)
+}
+
+// 保存済みレコードを過去ログの表示行にする
+// 可能ならハンドル、なければ送り主の指紋（古い記録は from_peer_id）で擬似表記
+fn past_line(r: MessageRecord) -> String {
+    // お知らせと自分の画面の行は表示行のまま保存してある
+    if r.kind == storage::MsgKind::System {
+        return r.text;
+    }
+    let mark = r.signature.mark();
+    if r.handle.is_some() {
+        format!("{} {}", r.text, mark)
+    } else if let Some(fp) = r
+        .peer_fingerprint
+        .as_deref()
+        .filter(|_| r.from_peer_id.is_some())
+    {
+        // 接続番号はその時だけのものなので、送り主は鍵の指紋で示す
+        format!("指紋={}: {} {}", fp, r.text, mark)
+    } else if let Some(pid) = r.from_peer_id {
+        // 指紋を保存する前の記録
+        format!("@{}: {} {}", pid, r.text, mark)
+    } else {
+        r.text
+    }
+}
+
+impl Tui {
+    pub fn new(status_msg: String, draw: DrawState, force_no_color: bool) -> Self {
+        Self {
+            messages: Vec::new(),
+            tagged: HashMap::new(),
+            pending_echo: VecDeque::new(),
+            queued: 0,
+            sig_counts: SigCounts::default(),
+            past_sig_counts: SigCounts::default(),
+            input: String::new(),
+            cursor_pos: 0,
+            next_input: None,
+            status_msg,
+            running: true,
+            past_mode: false,
+            past_messages: Vec::new(),
+            past_dates: Vec::new(),
+            past_date_range: String::new(),
+            past_earliest_idx: None,
+            scroll_offset: 0,
+            past_scroll_offset: 0,
+            history: Vec::new(),
+            history_pos: None,
+            copy_mode: false,
+            mouse_capture: true,
+            draw,
+            find: None,
+            force_no_color,
+            scrollback_max: DEFAULT_SCROLLBACK_MAX,
+            dnd_manual: false,
+            quiet_hours: None,
+            quiet_now: false,
+            bell: false,
+            honor_urgent: true,
+            urgent_bell_in_dnd: false,
+            focus: None,
+            inflight: None,
+            picker: None,
+        }
+    }
+
+    /// 画面への追加のみ（保存しない）
+    /// 行を追加する。描き直しは render が行数の差分から判断する
+    pub fn push_msg(&mut self, msg: String) {
+        self.messages.push(msg);
+        if self.scroll_offset > 0 {
+            self.draw.new_below += 1;
+        }
+        let max = self.scrollback_max;
+        if max > 0 && self.messages.len() > max {
+            // 毎行の全描き直しを避けるため、上限を超えたら 1 割ぶん余分に押し出す
+            self.evict_oldest(self.messages.len() - max + max / 10);
+        }
+    }
+
+    /// 古い行を n 行捨てる。行位置を持つ索引はずらし、捨てた行を指すものは外す。
+    /// scroll_offset は最下端からの行数なので、見ている内容はそのまま保たれる
+    fn evict_oldest(&mut self, n: usize) {
+        let n = n.min(self.messages.len());
+        self.messages.drain(..n);
+        self.tagged.retain(|_, idx| {
+            *idx = idx.wrapping_sub(n);
+            *idx < self.messages.len()
+        });
+        for slot in self.pending_echo.iter_mut() {
+            *slot = slot.and_then(|idx| idx.checked_sub(n));
+        }
+        if !self.past_mode {
+            self.draw.expanded = self.draw.expanded.and_then(|idx| idx.checked_sub(n));
+        }
+        self.draw.force_full = true;
+    }
+
+    /// ユーザー投稿として画面に追加し保存
+    pub fn push_user_msg(&mut self, msg: String) {
+        let now = utils::current_unix_millis();
+        storage::append_message(now, &msg);
+        self.push_msg(msg);
+    }
+
+    /// デバッグ専用ログ。config の debug=true のときのみ流す
+    pub fn push_debug_msg(&mut self, msg: impl Into<String>) {
+        if config::is_debug() {
+            self.push_msg(format!("[DEBUG] {}", msg.into()));
+        }
+    }
+
+    pub fn set_status(&mut self, msg: impl Into<String>) {
+        self.status_msg = msg.into();
+        self.draw.force_full = true;
+    }
+
+    /// 現在の表示（通常 / 過去ログ）の署名状態の内訳
+    pub fn shown_sig_counts(&self) -> SigCounts {
+        if self.past_mode {
+            self.past_sig_counts
+        } else {
+            self.sig_counts
+        }
+    }
+
+    /// 画面の表示行を消す（保存済みの履歴はそのまま）
+    pub fn clear_screen(&mut self) {
+        self.messages.clear();
+        self.tagged.clear();
+        self.pending_echo.clear();
+        self.queued = 0;
+        self.sig_counts = SigCounts::default();
+        self.scroll_offset = 0;
+        self.draw.new_below = 0;
+        self.draw.force_full = true;
+    }
+
+    /// ネットワークスレッドからのイベントを画面に反映する
+    pub fn on_event(&mut self, ev: rpc::Event, peers: &PeerQuery) {
+        // 集中モード中は届いた投稿を取っておくだけにする（中継と保存はネットワーク側で済んでいる）
+        if let Some(hidden) = self.focus.as_mut()
+            && is_live_post(&ev, &self.tagged)
+        {
+            hidden.push(ev);
+            self.draw.focus_hidden = Some(hidden.len());
+            self.draw.force_full = true;
+            return;
+        }
+        match ev {
+            rpc::Event::Message(m) => self.push_msg(m),
+            rpc::Event::DebugMessage(m) => self.push_debug_msg(m),
+            rpc::Event::PeerList {
+                listening,
+                peers: list,
+            } => {
+                // ピアの選択が一覧を待っていれば、表には出さずそちらで使う
+                match self.picker.as_mut().filter(|p| p.peers.is_none()) {
+                    Some(picker) => picker.set_peers(list),
+                    None => self.push_msg(format_peer_table(&listening, &list, peers)),
+                }
+            }
+            rpc::Event::Post { line, sig } => {
+                self.sig_counts.add(sig);
+                self.push_msg(line);
+            }
+            rpc::Event::DmArrived { from } => self.notify(format!("新着 DM: {}", from)),
+            rpc::Event::UrgentArrived { from } => self.notify_urgent(format!("至急: {}", from)),
+            rpc::Event::Chat { id, line, reply_to } => {
+                if let Some(r) = reply_to {
+                    let quote = reply_quote(&self.messages, &self.tagged, &r);
+                    self.push_msg(quote);
+                }
+                self.sig_counts.add(SigState::Valid);
+                self.tagged.insert(id, self.messages.len());
+                self.push_msg(line);
+            }
+            rpc::Event::Queued => {
+                if let Some(&slot) = self.pending_echo.get(self.queued) {
+                    if let Some(idx) = slot {
+                        self.messages[idx].push_str(QUEUED_MARK);
+                    }
+                    self.queued += 1;
+                    self.draw.force_full = true;
+                }
+            }
+            rpc::Event::Sent { id } => {
+                if let Some(idx) = self.pop_pending_echo() {
+                    self.messages[idx] = format!("#{} {}", id, self.messages[idx]);
+                    self.tagged.insert(id, idx);
+                }
+            }
+            rpc::Event::Unsent => {
+                if let Some(idx) = self.pop_pending_echo() {
+                    self.messages[idx].push_str(UNSENT_MARK);
+                }
+            }
+            rpc::Event::Topic { text, by } => {
+                self.push_msg(format!("トピック: {} (指紋={})", text, by));
+                self.draw.topic = Some(text);
+                self.draw.force_full = true;
+            }
+            rpc::Event::Replace { id, line } => match self.tagged.get(&id) {
+                Some(&idx) => {
+                    self.messages[idx] = line;
+                    self.draw.force_full = true;
+                }
+                None => self.push_msg(line),
+            },
+            rpc::Event::PeerConnected { id, token, inbound } => {
+                let what = if inbound {
+                    "接続受入"
+                } else {
+                    "接続完了"
+                };
+                self.draw.peers += 1;
+                self.push_msg(format!("{} (token={}) id={}", what, token, id));
+            }
+            rpc::Event::PeerDisconnected { id, reason } => {
+                self.draw.peers = self.draw.peers.saturating_sub(1);
+                self.push_msg(format!("切断しました id={} ({})", id, reason));
+            }
+            rpc::Event::HandshakeComplete {
+                id,
+                handle,
+                fingerprint,
+            } => {
+                self.push_msg(format!(
+                    "HELLO 受信: id={} {} 指紋={}",
+                    id, handle, fingerprint
+                ));
+            }
+            rpc::Event::VerifiedKeyChanged {
+                id,
+                handle,
+                verified,
+                got,
+            } => {
+                self.push_msg(format!(
+                    "⚠⚠⚠ 検証済みの {} が別の鍵で現れました: 検証済み指紋={} 今回の指紋={} (id={})。本人に確かめるまで信用しないでください",
+                    handle, verified, got, id
+                ));
+                self.set_status(format!(
+                    "⚠ 検証済みの {} の鍵が変わっています (id={})",
+                    handle, id
+                ));
+            }
+            rpc::Event::SignatureFailed { id } => {
+                self.set_status(format!("署名検証に失敗したフレームを受信: id={}", id));
+            }
+        }
+    }
+
+    /// ベルとステータスで知らせる。おやすみモード中は何もしない（行の表示と保存はそのまま）
+    fn notify(&mut self, status: String) {
+        if self.draw.dnd {
+            return;
+        }
+        self.bell = true;
+        self.set_status(status);
+    }
+
+    /// 至急の投稿の知らせ。urgent_bell_in_dnd ならおやすみモード中でも鳴らす
+    fn notify_urgent(&mut self, status: String) {
+        if self.draw.dnd && !self.urgent_bell_in_dnd {
+            return;
+        }
+        self.bell = true;
+        self.set_status(status);
+    }
+
+    /// minute（ローカル時刻の 0 時からの分）で dnd_hours の中かを見直す
+    pub fn refresh_dnd(&mut self, minute: u16) {
+        self.quiet_now = self.quiet_hours.is_some_and(|q| q.contains(minute));
+        self.update_dnd();
+    }
+
+    fn update_dnd(&mut self) {
+        let on = self.dnd_manual || self.quiet_now;
+        if on != self.draw.dnd {
+            self.draw.dnd = on;
+            self.draw.force_full = true;
+        }
+    }
+
+    /// 集中モードの切り替え。解くときは隠していた投稿を届いた順に画面へ出す
+    fn set_focus(&mut self, on: bool) {
+        if on {
+            self.focus.get_or_insert_with(Vec::new);
+            self.draw.focus_hidden = Some(self.focus.as_ref().map_or(0, Vec::len));
+            self.draw.force_full = true;
+            self.set_status("集中モード: ON (届いた投稿は隠します。中継と保存は続けます)");
+            return;
+        }
+        let hidden = self.focus.take().unwrap_or_default();
+        self.draw.focus_hidden = None;
+        let n = hidden.len();
+        for ev in hidden {
+            self.on_event(ev, &PeerQuery::default());
+        }
+        self.set_status(format!("集中モード: OFF (隠していた {} 件を表示)", n));
+    }
+
+    /// ID 待ちの先頭のローカルエコーを取り出し、送信待ちの印を外してその行を返す
+    fn pop_pending_echo(&mut self) -> Option<usize> {
+        let slot = self.pending_echo.pop_front()?;
+        let was_queued = self.queued > 0;
+        self.queued = self.queued.saturating_sub(1);
+        let idx = slot?;
+        if was_queued {
+            let len = self.messages[idx].len();
+            self.messages[idx].truncate(len - QUEUED_MARK.len());
+        }
+        self.draw.force_full = true;
+        Some(idx)
+    }
+
+    /// 画面だけで完結する Action を適用する。
+    /// ネットワークスレッドが必要なもの (Send / SpawnAndSend / Exit) はそのまま返す
+    pub fn apply(&mut self, action: Action) -> Option<Action> {
+        match action {
+            Action::Send(ref cmd) | Action::SpawnAndSend(ref cmd) => {
+                // 直前のローカルエコーに後から ID を付ける
+                if matches!(cmd, rpc::Command::Chat(..)) && !self.messages.is_empty() {
+                    self.pending_echo.push_back(Some(self.messages.len() - 1));
+                }
+                return Some(action);
+            }
+            Action::Exit => return Some(action),
+            Action::ClearScreen => self.clear_screen(),
+            Action::Show(m) => self.push_msg(m),
+            Action::ShowUser(m) => self.push_user_msg(m),
+            Action::Quote(id) => {
+                let quote = reply_quote(&self.messages, &self.tagged, &id);
+                self.push_msg(quote);
+            }
+            Action::Status(m) => self.set_status(m),
+            Action::SaveConfig(path, value) => {
+                if let Err(e) = config::upsert_value_and_save(path, value) {
+                    self.set_status(format!("設定の保存に失敗 ({}): {}", path, e));
+                }
+            }
+            Action::PickPeer(purpose) => self.picker = Some(PeerPicker::new(purpose)),
+            Action::TogglePast => {
+                if self.past_mode {
+                    self.leave_past_mode();
+                } else {
+                    self.enter_past_mode();
+                }
+            }
+            Action::SetTheme(t) => {
+                self.draw.theme = t;
+                self.draw.theme.no_color |= self.force_no_color;
+                self.draw.force_full = true;
+            }
+            Action::SetCompact(on) => {
+                self.draw.compact = on;
+                self.draw.force_full = true;
+            }
+            Action::SetHandleColor(handle, color) => {
+                if let Err(e) = storage::set_handle_color(&handle, &color) {
+                    self.set_status(format!("色の保存に失敗: {e}"));
+                    return None;
+                }
+                self.draw.handle_colors.set(&handle, &color);
+                self.draw.force_full = true;
+                self.set_status(format!("{} の色: {}", handle, color));
+            }
+            Action::ShowLegend => {
+                let shown = if self.past_mode {
+                    &self.past_messages
+                } else {
+                    &self.messages
+                };
+                let mut handles: Vec<&str> = Vec::new();
+                for line in shown {
+                    if let Some((s, e)) = theme::handle_span(line)
+                        && !handles.contains(&&line[s..e])
+                    {
+                        handles.push(&line[s..e]);
+                    }
+                }
+                let text = if handles.is_empty() {
+                    "表示中のハンドルはありません".to_string()
+                } else {
+                    let mut lines = vec![format!("色の凡例 {} 件:", handles.len())];
+                    lines.extend(handles.iter().map(|h| {
+                        let color = self.draw.handle_colors.describe(h, &self.draw.own_handle);
+                        format!("{} {}", h, color)
+                    }));
+                    lines.join("\n")
+                };
+                self.push_msg(text);
+            }
+            Action::ShowAudit(n) => {
+                let entries = storage::recent_audit(n);
+                let text = if entries.is_empty() {
+                    "監査ログなし".to_string()
+                } else {
+                    let mut lines = vec!["監査ログ:".to_string()];
+                    lines.extend(entries.iter().map(|e| e.to_string()));
+                    lines.join("\n")
+                };
+                self.push_msg(text);
+            }
+            Action::ShowKnown => {
+                let known = storage::known_peers();
+                let text = if known.is_empty() {
+                    "これまでに接続した相手はいません".to_string()
+                } else {
+                    let mut lines = vec![format!("既知の相手 {} 件:", known.len())];
+                    lines.extend(known.iter().map(|k| k.to_string()));
+                    lines.join("\n")
+                };
+                self.push_msg(text);
+            }
+            Action::Trust(fp) => {
+                let handle = storage::known_peers()
+                    .into_iter()
+                    .find(|k| k.fingerprint == fp)
+                    .and_then(|k| k.handle);
+                let now = utils::current_unix_millis();
+                let status = match storage::set_verified(&fp, handle.as_deref(), now) {
+                    Ok(()) => format!(
+                        "指紋 {} ({}) を検証済みにしました",
+                        fp,
+                        handle.as_deref().unwrap_or("?")
+                    ),
+                    Err(e) => format!("検証済みの保存に失敗: {e}"),
+                };
+                self.set_status(status);
+            }
+            Action::ShowConfig(key) => {
+                let text = match (config::try_config(), key.and_then(config::find_config_key)) {
+                    (None, _) => "設定が読み込まれていません".to_string(),
+                    (Some(tbl), Some(spec)) => config::describe_config_key(&tbl, spec),
+                    (Some(tbl), None) => config::describe_config(&tbl),
+                };
+                self.push_msg(text);
+            }
+            Action::ShowTrusted => {
+                let verified = storage::verified_peers();
+                let text = if verified.is_empty() {
+                    "検証済みの相手はいません (/trust <指紋>)".to_string()
+                } else {
+                    let mut lines = vec![format!("検証済みの相手 {} 件:", verified.len())];
+                    lines.extend(verified.iter().map(|v| {
+                        format!("{} {}", v.fingerprint, v.handle.as_deref().unwrap_or("?"))
+                    }));
+                    lines.join("\n")
+                };
+                self.push_msg(text);
+            }
+            Action::Reverify => {
+                let status = match storage::reverify_all() {
+                    Ok(c) => format!(
+                        "署名を再検証しました: {} 件中 {} 件の状態が変化 (検証材料なし {} 件)",
+                        c.checked, c.changed, c.skipped
+                    ),
+                    Err(e) => format!("署名の再検証に失敗: {e}"),
+                };
+                self.set_status(status);
+            }
+            Action::Backup(path) => {
+                let res = std::fs::File::create(&path)
+                    .map_err(Into::into)
+                    .and_then(|f| storage::export_binary(std::io::BufWriter::new(f)));
+                let status = match res {
+                    Ok(n) => format!("{} 件を {} にバックアップしました", n, path),
+                    Err(e) => format!("バックアップに失敗: {e}"),
+                };
+                self.set_status(status);
+            }
+            Action::Restore(path) => {
+                let res = std::fs::File::open(&path)
+                    .map_err(Into::into)
+                    .and_then(|f| storage::import_binary(std::io::BufReader::new(f)));
+                let status = match res {
+                    Ok((added, skipped)) => {
+                        format!("復元しました (追加 {} 件 / 重複 {} 件)", added, skipped)
+                    }
+                    Err(e) => format!("復元に失敗: {e}"),
+                };
+                self.set_status(status);
+            }
+            Action::SaveDraft(name, text) => {
+                let status = match storage::save_draft(&name, &text) {
+                    Ok(()) => format!("下書き '{}' を保存しました", name),
+                    Err(e) => format!("下書きの保存に失敗: {e}"),
+                };
+                self.set_status(status);
+            }
+            Action::LoadDraft(name) => match storage::load_draft(&name) {
+                Some(text) => {
+                    self.next_input = Some(text);
+                    self.set_status(format!("下書き '{}' を読み込みました", name));
+                }
+                None => self.set_status(format!("下書き '{}' はありません", name)),
+            },
+            Action::ToggleBookmark(id) => {
+                if !self.tagged.contains_key(&id) && storage::get_by_id(&id).is_none() {
+                    self.set_status(format!("メッセージ #{} が見つかりません", id));
+                    return None;
+                }
+                let on = !self.draw.bookmarks.contains(&id);
+                if let Err(e) = storage::set_bookmark(&id, on) {
+                    self.set_status(format!("ブックマークの保存に失敗: {e}"));
+                    return None;
+                }
+                if on {
+                    self.draw.bookmarks.insert(id.clone());
+                    self.set_status(format!("#{} をブックマークしました", id));
+                } else {
+                    self.draw.bookmarks.remove(&id);
+                    self.set_status(format!("#{} のブックマークを外しました", id));
+                }
+                self.draw.force_full = true;
+            }
+            Action::ShowBookmarks => self.show_bookmarks(),
+            Action::ShowDmThread(handle) => self.show_dm_thread(&handle),
+            Action::ScrollToBottom => self.scroll_to_bottom(),
+            Action::ShowFull(line) => self.show_full(line),
+            Action::Find(query) => self.find(query, true),
+            Action::SetDnd(on) => {
+                self.dnd_manual = on;
+                self.update_dnd();
+                let status = match (on, self.quiet_hours.filter(|_| self.quiet_now)) {
+                    (true, _) => "おやすみモード: ON (通知を止めます)".to_string(),
+                    (false, Some(q)) => format!("おやすみモード: OFF (ただし {} の間は ON)", q),
+                    (false, None) => "おやすみモード: OFF".to_string(),
+                };
+                self.set_status(status);
+            }
+            Action::SetFocus(on) => self.set_focus(on),
+            Action::SelfTest => {
+                let (text, ok) = check::report(&check::self_test());
+                self.push_msg(format!("自己診断:\n{}", text));
+                self.set_status(if ok {
+                    "自己診断: 問題なし"
+                } else {
+                    "自己診断: 失敗あり"
+                });
+            }
+            Action::ListDrafts => {
+                let names = storage::list_drafts();
+                let text = if names.is_empty() {
+                    "下書きなし".to_string()
+                } else {
+                    format!("下書き: {}", names.join(", "))
+                };
+                self.push_msg(text);
+            }
+            Action::ClearHistory => {
+                let mut status = match storage::clear_all() {
+                    Ok(n) => format!("履歴を削除しました ({} 件)", n),
+                    Err(e) => format!("履歴の削除に失敗: {e}"),
+                };
+                self.past_messages.clear();
+                self.past_sig_counts = SigCounts::default();
+                self.past_dates.clear();
+                self.past_date_range.clear();
+                self.past_earliest_idx = None;
+                self.past_scroll_offset = 0;
+                self.clear_find();
+                if self.past_mode {
+                    status = format!("{} / 過去ログなし", status);
+                }
+                self.set_status(status);
+            }
+        }
+        None
+    }
+
+    /// 過去ログモードに入り、最新日付のみロードする
+    pub fn enter_past_mode(&mut self) {
+        self.past_mode = true;
+        self.clear_find();
+        if storage::history_disabled() {
+            self.past_messages.clear();
+            self.past_sig_counts = SigCounts::default();
+            self.past_date_range.clear();
+            self.past_earliest_idx = None;
+            self.status_msg = "履歴の保存は無効です (no_history)".into();
+            self.draw.force_full = true;
+            return;
+        }
+        self.past_dates = storage::list_dates();
+        self.past_dates.sort();
+        if let Some(last_idx) = self.past_dates.len().checked_sub(1) {
+            let day = self.past_dates[last_idx].clone();
+            (self.past_messages, self.past_sig_counts) = load_past_day(&day);
+            self.past_scroll_offset = 0;
+            self.past_date_range = format!("{}~{}", day, day);
+            self.past_earliest_idx = Some(last_idx);
+            self.status_msg = format!("過去ログモード {}", self.past_date_range);
+        } else {
+            self.status_msg = "過去ログなし".into();
+        }
+        self.draw.force_full = true;
+    }
+
+    /// ブックマークした投稿を過去ログモードと同じ画面で一覧する（前日の追加ロードはしない）
+    pub fn show_bookmarks(&mut self) {
+        let mut counts = SigCounts::default();
+        let mut found = storage::bookmarks();
+        // 至急の投稿を先頭に（それぞれの中は時刻順のまま）
+        let is_urgent = |r: &storage::MessageRecord| {
+            r.signature == SigState::Valid && r.proof.as_ref().is_some_and(|p| p.is_urgent())
+        };
+        if self.honor_urgent {
+            found.sort_by_key(|(_, r)| !is_urgent(r));
+        }
+        self.past_messages = found
+            .into_iter()
+            .map(|(id, r)| {
+                if r.from_peer_id.is_some() {
+                    counts.add(r.signature);
+                }
+                let urgent = self.honor_urgent && is_urgent(&r);
+                let mut line = format!("#{} {}", id, past_line(r));
+                if urgent {
+                    line.push_str(rpc::URGENT_MARK);
+                }
+                line
+            })
+            .collect();
+        self.past_sig_counts = counts;
+        self.past_mode = true;
+        self.clear_find();
+        self.past_scroll_offset = 0;
+        self.past_earliest_idx = None;
+        self.past_date_range = "ブックマーク".into();
+        self.status_msg = if self.past_messages.is_empty() {
+            "ブックマークなし".into()
+        } else {
+            format!("ブックマーク {} 件", self.past_messages.len())
+        };
+        self.draw.force_full = true;
+    }
+
+    /// 1 人の相手との DM を時刻順に過去ログ表示する
+    pub fn show_dm_thread(&mut self, handle: &str) {
+        let mut counts = SigCounts::default();
+        self.past_messages = storage::dm_thread(handle)
+            .into_iter()
+            .map(|r| {
+                if r.from_peer_id.is_some() {
+                    counts.add(r.signature);
+                }
+                past_line(r)
+            })
+            .collect();
+        self.past_sig_counts = counts;
+        self.past_mode = true;
+        self.clear_find();
+        self.past_scroll_offset = 0;
+        self.past_earliest_idx = None;
+        self.past_date_range = format!("DM {}", handle);
+        self.status_msg = if self.past_messages.is_empty() {
+            "DM なし".into()
+        } else {
+            format!("{} との DM {} 件", handle, self.past_messages.len())
+        };
+        self.draw.force_full = true;
+    }
+
+    /// 過去ログモードを抜ける。スクロールは通常表示側を採用し、過去ログ側は保持
+    pub fn leave_past_mode(&mut self) {
+        self.past_mode = false;
+        self.clear_find();
+        self.set_status("過去ログモード終了");
+    }
+
+    /// 1 行過去へスクロール。view_h はメッセージ領域の高さ
+    pub fn scroll_up(&mut self, view_h: usize) {
+        if !self.past_mode {
+            self.scroll_offset = self.scroll_offset.saturating_add(1);
+            self.draw.force_full = true;
+            return;
+        }
+        self.past_scroll_offset = self.past_scroll_offset.saturating_add(1);
+        // 過去ログモードで最上端に到達したら前日を追加ロード
+        if let Some(earliest_idx) = self.past_earliest_idx
+            && earliest_idx > 0
+        {
+            // 現在の最大スクロール量を概算: 行折返し考慮せず改行分割のみ
+            let flat_len: usize = self
+                .past_messages
+                .iter()
+                .map(|m| m.split('\n').count())
+                .sum();
+            let max_scroll = flat_len.saturating_sub(view_h);
+            if self.past_scroll_offset >= max_scroll {
+                self.load_previous_day(earliest_idx - 1);
+            }
+        }
+        self.draw.force_full = true;
+    }
+
+    // 先頭に古い日を挿入（古→新）し、視点保持のため scroll_offset を行数ぶん加算
+    fn load_previous_day(&mut self, load_idx: usize) {
+        let day = self.past_dates[load_idx].clone();
+        let (day_lines, counts) = load_past_day(&day);
+        let inserted = day_lines.len();
+        // 空の日も読み込み済みにして、次は更に前の日へ進む
+        self.past_earliest_idx = Some(load_idx);
+        if inserted == 0 {
+            return;
+        }
+        self.past_messages.splice(0..0, day_lines);
+        self.past_sig_counts.valid += counts.valid;
+        self.past_sig_counts.unsigned += counts.unsigned;
+        self.past_sig_counts.invalid += counts.invalid;
+        self.past_scroll_offset = self.past_scroll_offset.saturating_add(inserted);
+        self.draw.expanded = self.draw.expanded.map(|idx| idx + inserted);
+        // 日付レンジ更新（開始日を差し替え）
+        if let Some(pos) = self.past_date_range.find('~') {
+            let end_part = self.past_date_range[pos + 1..].to_string();
+            self.past_date_range = format!("{}~{}", day, end_part);
+        }
+        self.status_msg = format!("過去ログ拡張 {}", self.past_date_range);
+    }
+
+    /// 過去ログ内で query を探す（None なら前回の語で続きを探す）。
+    /// older なら古い方へ進み、読み込み済みの中に無ければ前の日を MAX_FIND_LOAD_DAYS 日まで読み足す
+    pub fn find(&mut self, query: Option<String>, older: bool) {
+        if !self.past_mode {
+            self.set_status("/find は過去ログモード (/past) で使えます");
+            return;
+        }
+        if let Some(q) = query {
+            self.find = Some(FindState {
+                query: q,
+                hit: None,
+            });
+        }
+        let Some(FindState { query, mut hit }) = self.find.clone() else {
+            self.set_status("使い方: /find <text>");
+            return;
+        };
+        let needle = query.to_lowercase();
+        let matches = |line: &String| line.to_lowercase().contains(&needle);
+        let mut gave_up = false;
+        let found = if older {
+            let from = hit.unwrap_or(self.past_messages.len());
+            let mut found = self.past_messages[..from].iter().rposition(matches);
+            let mut loaded = 0;
+            while found.is_none() {
+                let Some(earliest) = self.past_earliest_idx.filter(|&i| i > 0) else {
+                    break;
+                };
+                if loaded == MAX_FIND_LOAD_DAYS {
+                    gave_up = true;
+                    break;
+                }
+                let before = self.past_messages.len();
+                self.load_previous_day(earliest - 1);
+                loaded += 1;
+                // 先頭に差し込まれたぶん、今の位置もずれる
+                let inserted = self.past_messages.len() - before;
+                hit = hit.map(|h| h + inserted);
+                found = self.past_messages[..inserted].iter().rposition(matches);
+            }
+            found
+        } else {
+            let from = hit.map_or(self.past_messages.len(), |h| h + 1);
+            self.past_messages[from..]
+                .iter()
+                .position(matches)
+                .map(|i| i + from)
+        };
+        let hit = found.or(hit);
+        self.find = Some(FindState {
+            query: query.clone(),
+            hit,
+        });
+        self.draw.found = hit;
+        self.draw.force_full = true;
+        let Some(i) = found else {
+            let dir = if older { "古い" } else { "新しい" };
+            self.set_status(if gave_up {
+                format!(
+                    "'{}' は {} 日分さかのぼっても見つかりません（n で続きを探す）",
+                    query, MAX_FIND_LOAD_DAYS
+                )
+            } else {
+                format!("これより{}方に '{}' はありません", dir, query)
+            });
+            return;
+        };
+        // 一致した行がメッセージ領域の中ほどに来るようにスクロールする（折返しは考えない）
+        let below: usize = self.past_messages[i + 1..]
+            .iter()
+            .map(|m| m.split('\n').count())
+            .sum();
+        let view_h = (self.draw.last_size.1 as usize).saturating_sub(2);
+        self.past_scroll_offset = below.saturating_sub(view_h / 2);
+        self.set_status(format!(
+            "'{}' が見つかりました ({}) n: 古い方 / N: 新しい方",
+            query, self.past_date_range
+        ));
+    }
+
+    // 表示する一覧が入れ替わるときに呼ぶ（/show で開いた行も閉じる）
+    fn clear_find(&mut self) {
+        self.find = None;
+        self.draw.found = None;
+        self.draw.expanded = None;
+    }
+
+    /// 表示中の一覧の line 行目 (1 始まり) を全文で表示する。None なら閉じる
+    pub fn show_full(&mut self, line: Option<usize>) {
+        let shown = if self.past_mode {
+            &self.past_messages
+        } else {
+            &self.messages
+        };
+        match line {
+            None => {
+                self.draw.expanded = None;
+                self.set_status("全文表示を閉じました");
+            }
+            Some(n) if (1..=shown.len()).contains(&n) => {
+                self.draw.expanded = Some(n - 1);
+                self.set_status(format!("{} 行目を全文表示中 (/show で閉じる)", n));
+            }
+            Some(n) => {
+                self.set_status(format!("{} 行目はありません", n));
+                return;
+            }
+        }
+        self.draw.force_full = true;
+    }
+
+    /// 1 画面ぶん過去へ (PageUp)。1 行ずつ送るので過去ログの前日の読み足しも同じように働く
+    pub fn page_up(&mut self, view_h: usize) {
+        for _ in 0..view_h.saturating_sub(1).max(1) {
+            self.scroll_up(view_h);
+        }
+    }
+
+    /// 1 画面ぶん最新側へ (PageDown)
+    pub fn page_down(&mut self, view_h: usize) {
+        for _ in 0..view_h.saturating_sub(1).max(1) {
+            self.scroll_down();
+        }
+    }
+
+    /// 1 行最新側へスクロール
+    pub fn scroll_down(&mut self) {
+        let off = if self.past_mode {
+            &mut self.past_scroll_offset
+        } else {
+            &mut self.scroll_offset
+        };
+        if *off > 0 {
+            *off -= 1;
+            self.draw.force_full = true;
+        }
+        if self.scroll_offset == 0 {
+            self.draw.new_below = 0;
+        }
+    }
+
+    /// 最新（一番下）へ戻る。通常表示なら新着の数も消す
+    pub fn scroll_to_bottom(&mut self) {
+        if self.past_mode {
+            self.past_scroll_offset = 0;
+        } else {
+            self.scroll_offset = 0;
+            self.draw.new_below = 0;
+        }
+        self.draw.force_full = true;
+    }
+
+    pub fn insert_char(&mut self, ch: char) {
+        let (mut left, right) = split_at_char(&self.input, self.cursor_pos);
+        left.push(ch);
+        self.input = left + &right;
+        self.cursor_pos += 1;
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor_pos == 0 {
+            return;
+        }
+        let (mut left, right) = split_at_char(&self.input, self.cursor_pos);
+        left.pop(); // 1 文字削除（pop は UTF-8 末尾 1 文字）
+        self.input = left + &right;
+        self.cursor_pos -= 1;
+    }
+
+    /// カーソルを左へ。word なら単語単位
+    pub fn move_left(&mut self, word: bool) {
+        if self.cursor_pos == 0 {
+            return;
+        }
+        if !word {
+            self.cursor_pos -= 1;
+            return;
+        }
+        let chars: Vec<char> = self.input.chars().collect();
+        let mut new_pos = self.cursor_pos;
+        // 直前が空白ならスキップ
+        while new_pos > 0 && (chars[new_pos - 1].is_whitespace() || chars[new_pos - 1] == '=') {
+            new_pos -= 1;
+        }
+        // 単語の先頭まで移動
+        while new_pos > 0 && (!chars[new_pos - 1].is_whitespace() || chars[new_pos - 1] == '=') {
+            new_pos -= 1;
+        }
+        self.cursor_pos = new_pos;
+    }
+
+    /// カーソルを右へ。word なら単語単位
+    pub fn move_right(&mut self, word: bool) {
+        let chars: Vec<char> = self.input.chars().collect();
+        if self.cursor_pos >= chars.len() {
+            return;
+        }
+        if !word {
+            self.cursor_pos += 1;
+            return;
+        }
+        let mut new_pos = self.cursor_pos;
+        // 直後が空白ならスキップ
+        while new_pos < chars.len() && (chars[new_pos].is_whitespace() || chars[new_pos] == '=') {
+            new_pos += 1;
+        }
+        // 単語の末尾まで移動
+        while new_pos < chars.len() && (!chars[new_pos].is_whitespace() || chars[new_pos] == '=') {
+            new_pos += 1;
+        }
+        self.cursor_pos = new_pos;
+    }
+
+    /// 前回落ちたときの書きかけを入力行に戻す
+    pub fn restore_inflight(&mut self, text: String, cursor: usize) {
+        self.cursor_pos = cursor.min(text.chars().count());
+        self.input = text;
+        self.set_status("前回の書きかけの入力を戻しました (Enter で送信 / Esc で捨てる)");
+    }
+
+    /// 入力行が変わっていれば退避ファイルに書く。now はミリ秒
+    pub fn save_inflight(&mut self, now: u64) {
+        if let Some(f) = self.inflight.as_mut() {
+            f.save(&self.input, self.cursor_pos, now);
+        }
+    }
+
+    /// ピアの選択で選んでいる相手に決め、コマンドを入力行に入れる
+    pub fn confirm_pick(&mut self) {
+        let Some(cmd) = self.picker.as_ref().and_then(PeerPicker::command) else {
+            return;
+        };
+        self.close_picker();
+        self.cursor_pos = cmd.chars().count();
+        self.input = cmd;
+        self.history_pos = None;
+    }
+
+    pub fn close_picker(&mut self) {
+        self.picker = None;
+        // 重ねて描いた一覧を消す
+        self.draw.force_full = true;
+    }
+
+    /// 入力行を消す。commit が Some なら履歴に積む
+    pub fn clear_input(&mut self, commit: Option<String>) {
+        // 読み込んだ下書きがあれば末尾にカーソルを置いて入れる
+        self.input = self.next_input.take().unwrap_or_default();
+        self.cursor_pos = self.input.chars().count();
+        match commit {
+            Some(line) if !line.is_empty() => {
+                self.history.push(line);
+                self.history_pos = None;
+            }
+            Some(_) => {}
+            None => self.history_pos = None,
+        }
+    }
+
+    pub fn history_prev(&mut self) {
+        if self.history.is_empty() {
+            return;
+        }
+        let new_pos = match self.history_pos {
+            None => self.history.len().saturating_sub(1),
+            Some(p) => p.saturating_sub(1),
+        };
+        self.history_pos = Some(new_pos);
+        self.input = self.history[new_pos].clone();
+        self.cursor_pos = self.input.chars().count();
+    }
+
+    pub fn history_next(&mut self) {
+        let Some(p) = self.history_pos else {
+            return;
+        };
+        if p + 1 < self.history.len() {
+            self.history_pos = Some(p + 1);
+            self.input = self.history[p + 1].clone();
+        } else {
+            self.history_pos = None;
+            self.input.clear();
+        }
+        self.cursor_pos = self.input.chars().count();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{self, AppState};
+
+    fn app() -> AppState {
+        AppState {
+            handle: "@alice".into(),
+            network_running: false,
+            peers: PeerQuery::default(),
+            public_key: None,
+            spectator: false,
+            relay: true,
+            developer: false,
+            first_run: false,
+        }
+    }
+
+    fn tui() -> Tui {
+        Tui::new(String::new(), DrawState::new(), false)
+    }
+
+    // 入力行 → handle_command → apply の流れ。残った Action はネットワーク向け
+    fn submit(tui: &mut Tui, app: &mut AppState, line: &str) -> Vec<Action> {
+        let actions = commands::handle_command(line, app);
+        let rest = actions.into_iter().filter_map(|a| tui.apply(a)).collect();
+        tui.clear_input(Some(line.to_string()));
+        rest
+    }
+
+    fn peer(id: usize, handle: &str, fp: &str, state: rpc::PeerState) -> rpc::PeerInfo {
+        rpc::PeerInfo {
+            id,
+            token: "tok".into(),
+            fingerprint: Some(fp.into()),
+            handle: Some(handle.into()),
+            state,
+            rtt_ms: None,
+            bytes_in: 0,
+            queued_bytes: 0,
+            via_port: None,
+            handle_suspect: false,
+        }
+    }
+
+    #[test]
+    fn peer_picker_maps_selection_to_the_chosen_peer() {
+        let mut tui = tui();
+        let mut app = app();
+        app.network_running = true;
+        let rest = submit(&mut tui, &mut app, "/dm");
+        assert!(matches!(
+            rest.as_slice(),
+            [Action::Send(rpc::Command::PeerList)]
+        ));
+        assert!(tui.picker.as_ref().unwrap().peers.is_none());
+
+        // 届いた一覧は表に出さず、HELLO 済みの相手だけを id 順に並べる
+        let before = tui.messages.len();
+        let list = vec![
+            peer(5, "@carol", "cccccccccccccccc", rpc::PeerState::Ready),
+            peer(
+                1,
+                "@mallory",
+                "dddddddddddddddd",
+                rpc::PeerState::Handshaking,
+            ),
+            peer(2, "@bob", "bbbbbbbbbbbbbbbb", rpc::PeerState::Ready),
+        ];
+        tui.on_event(
+            rpc::Event::PeerList {
+                listening: vec![],
+                peers: list.clone(),
+            },
+            &PeerQuery::default(),
+        );
+        assert_eq!(tui.messages.len(), before);
+        let picker = tui.picker.as_mut().unwrap();
+        assert_eq!(picker.selected_peer().unwrap().id, 2);
+        picker.move_selection(false);
+        picker.move_selection(false);
+        assert_eq!(picker.selected_peer().unwrap().id, 5);
+        assert_eq!(picker.lines().1, Some(2));
+
+        // 決めると指紋で宛先を入れ、本文を続けて打てる
+        tui.confirm_pick();
+        assert!(tui.picker.is_none());
+        assert_eq!(tui.input, "/dm cccccccccccccccc ");
+        assert_eq!(tui.cursor_pos, tui.input.chars().count());
+
+        // 切断は接続中の相手すべてから選び、Esc で何もせず閉じる
+        let mut picker = PeerPicker::new(PickFor::Disconnect);
+        picker.set_peers(list);
+        picker.move_selection(true);
+        assert_eq!(picker.command().as_deref(), Some("/disconnect 1"));
+        tui.picker = Some(picker);
+        tui.close_picker();
+        assert!(tui.picker.is_none());
+        assert_eq!(tui.input, "/dm cccccccccccccccc ");
+    }
+
+    #[test]
+    fn commands_update_screen_and_pass_network_actions_through() {
+        let mut tui = tui();
+        let mut app = app();
+        let rest = submit(&mut tui, &mut app, "/help");
+        assert!(rest.is_empty());
+        assert!(tui.messages.last().unwrap().contains("/connect"));
+
+        let rest = submit(&mut tui, &mut app, "/theme mono");
+        assert!(rest.is_empty());
+        assert!(tui.draw.theme.no_color);
+
+        let rest = submit(&mut tui, &mut app, "/exit");
+        assert!(matches!(rest.as_slice(), [Action::Exit]));
+        assert_eq!(tui.status_msg, "終了中...");
+        assert_eq!(tui.history, vec!["/help", "/theme mono", "/exit"]);
+    }
+
+    #[test]
+    fn sent_event_tags_pending_echo() {
+        let mut tui = tui();
+        tui.push_msg("@me: hi".into());
+        let rest = tui.apply(Action::Send(rpc::Command::Chat("hi".into(), None, false)));
+        assert!(rest.is_some());
+        tui.on_event(
+            rpc::Event::Sent {
+                id: "0a1b2c3d4e5f6071".into(),
+            },
+            &PeerQuery::default(),
+        );
+        assert_eq!(tui.messages[0], "#0a1b2c3d4e5f6071 @me: hi");
+        tui.on_event(
+            rpc::Event::Replace {
+                id: "0a1b2c3d4e5f6071".into(),
+                line: "#0a1b2c3d4e5f6071 @me: [deleted]".into(),
+            },
+            &PeerQuery::default(),
+        );
+        assert_eq!(tui.messages.len(), 1);
+        assert!(tui.messages[0].ends_with("[deleted]"));
+    }
+
+    #[test]
+    fn queued_echo_is_marked_until_sent() {
+        let mut tui = tui();
+        tui.push_msg("@me: first".into());
+        tui.apply(Action::SpawnAndSend(rpc::Command::Chat(
+            "first".into(),
+            None,
+            false,
+        )));
+        tui.push_msg("@me: second".into());
+        tui.apply(Action::Send(rpc::Command::Chat(
+            "second".into(),
+            None,
+            false,
+        )));
+        tui.on_event(rpc::Event::Queued, &PeerQuery::default());
+        tui.on_event(rpc::Event::Queued, &PeerQuery::default());
+        assert_eq!(tui.messages[1], "@me: second (送信待ち)");
+        tui.on_event(rpc::Event::Sent { id: "aa".into() }, &PeerQuery::default());
+        assert_eq!(tui.messages[0], "#aa @me: first");
+        assert_eq!(tui.queued, 1);
+    }
+
+    #[test]
+    fn unsent_echo_does_not_shift_later_ids() {
+        let mut tui = tui();
+        for text in ["first", "second"] {
+            tui.push_msg(format!("@me: {}", text));
+            tui.apply(Action::Send(rpc::Command::Chat(text.into(), None, false)));
+        }
+        tui.on_event(rpc::Event::Unsent, &PeerQuery::default());
+        tui.on_event(rpc::Event::Sent { id: "bb".into() }, &PeerQuery::default());
+        assert_eq!(tui.messages[0], "@me: first (未送信)");
+        assert_eq!(tui.messages[1], "#bb @me: second");
+        assert!(tui.pending_echo.is_empty());
+    }
+
+    #[test]
+    fn signature_counts_follow_received_posts_and_reset_on_clear() {
+        let mut tui = tui();
+        let mut app = app();
+        let post = |line: &str, sig| rpc::Event::Post {
+            line: line.into(),
+            sig,
+        };
+        tui.on_event(post("@2: a ・", SigState::Unsigned), &PeerQuery::default());
+        tui.on_event(post("@bob: b ×", SigState::Invalid), &PeerQuery::default());
+        tui.on_event(
+            rpc::Event::Chat {
+                id: "aa".into(),
+                line: "#aa @bob: c ○".into(),
+                reply_to: None,
+            },
+            &PeerQuery::default(),
+        );
+        tui.on_event(
+            rpc::Event::Message("接続完了 id=0".into()),
+            &PeerQuery::default(),
+        );
+        assert_eq!(tui.shown_sig_counts().to_string(), "✓1 ・1 ×1");
+
+        // 過去ログ表示中はそちらの内訳を出す
+        tui.past_mode = true;
+        assert!(tui.shown_sig_counts().is_empty());
+        tui.past_mode = false;
+
+        submit(&mut tui, &mut app, "/clear");
+        assert!(tui.messages.is_empty());
+        assert!(tui.shown_sig_counts().is_empty());
+    }
+
+    #[test]
+    fn input_editing_and_history() {
+        let mut tui = tui();
+        for ch in "ab cd".chars() {
+            tui.insert_char(ch);
+        }
+        tui.move_left(true);
+        assert_eq!(tui.cursor_pos, 3);
+        tui.backspace();
+        assert_eq!(tui.input, "abcd");
+        tui.clear_input(Some("first".into()));
+        tui.clear_input(Some("second".into()));
+        tui.history_prev();
+        tui.history_prev();
+        assert_eq!(tui.input, "first");
+        tui.history_next();
+        tui.history_next();
+        assert_eq!(tui.input, "");
+        assert!(tui.history_pos.is_none());
+        tui.scroll_up(10);
+        tui.scroll_down();
+        tui.scroll_down();
+        assert_eq!(tui.scroll_offset, 0);
+    }
+
+    #[test]
+    fn topic_event_updates_status_bar_state() {
+        let mut tui = tui();
+        let ev = rpc::Event::Topic {
+            text: "今日の話題".into(),
+            by: "0123456789abcdef".into(),
+        };
+        tui.on_event(ev, &PeerQuery::default());
+        assert_eq!(tui.draw.topic.as_deref(), Some("今日の話題"));
+        assert!(
+            tui.messages
+                .last()
+                .unwrap()
+                .starts_with("トピック: 今日の話題")
+        );
+    }
+
+    #[test]
+    fn loaded_draft_replaces_the_cleared_input() {
+        let mut tui = tui();
+        let mut app = app();
+        assert!(submit(&mut tui, &mut app, "/draft list").is_empty());
+        assert_eq!(tui.messages.last().map(String::as_str), Some("下書きなし"));
+
+        // /draft load が見つけた本文は、コマンド行を消した後の入力行に入る
+        tui.next_input = Some("書きかけの長い文章".into());
+        tui.clear_input(Some("/draft load long".into()));
+        assert_eq!(tui.input, "書きかけの長い文章");
+        assert_eq!(tui.cursor_pos, 9);
+        tui.clear_input(None);
+        assert!(tui.input.is_empty());
+    }
+
+    #[test]
+    fn bookmark_toggles_on_tagged_lines() {
+        let mut tui = tui();
+        let mut app = app();
+        tui.on_event(
+            rpc::Event::Chat {
+                id: "0a1b2c3d4e5f6071".into(),
+                line: "#0a1b2c3d4e5f6071 @bob: hi ○".into(),
+                reply_to: None,
+            },
+            &PeerQuery::default(),
+        );
+        submit(&mut tui, &mut app, "/bookmark #0a1b2c3d4e5f6071");
+        assert!(tui.draw.bookmarks.contains("0a1b2c3d4e5f6071"));
+        submit(&mut tui, &mut app, "/bookmark 0a1b2c3d4e5f6071");
+        assert!(tui.draw.bookmarks.is_empty());
+
+        submit(&mut tui, &mut app, "/bookmark ffff");
+        assert!(tui.draw.bookmarks.is_empty());
+        assert!(tui.status_msg.contains("見つかりません"));
+
+        submit(&mut tui, &mut app, "/bookmarks");
+        assert!(tui.past_mode);
+        assert_eq!(tui.status_msg, "ブックマークなし");
+    }
+
+    #[test]
+    fn burst_of_messages_is_one_tail_repaint() {
+        let mut tui = tui();
+        let size = (80, 24);
+        tui.push_msg("接続完了 id=0".into());
+        assert_eq!(tui.draw.plan(tui.messages.len(), true, size), Repaint::Full);
+        tui.draw.rendered(tui.messages.len(), size, 1);
+
+        for i in 0..50 {
+            tui.on_event(
+                rpc::Event::Post {
+                    line: format!("@bob: {} ○", i),
+                    sig: SigState::Valid,
+                },
+                &PeerQuery::default(),
+            );
+        }
+        // 50件届いても描画は末尾の描き足し1回
+        assert_eq!(
+            tui.draw.plan(tui.messages.len(), true, size),
+            Repaint::Tail { from: 1 }
+        );
+        tui.draw.rendered(tui.messages.len(), size, 51);
+        assert_eq!(tui.draw.plan(tui.messages.len(), true, size), Repaint::None);
+
+        // スクロール中・端末サイズ変更・画面クリアは全体を描き直す
+        tui.push_msg("@bob: more ○".into());
+        assert_eq!(
+            tui.draw.plan(tui.messages.len(), false, size),
+            Repaint::Full
+        );
+        assert_eq!(
+            tui.draw.plan(tui.messages.len(), true, (100, 30)),
+            Repaint::Full
+        );
+        tui.clear_screen();
+        assert_eq!(tui.draw.plan(0, true, size), Repaint::Full);
+    }
+
+    #[test]
+    fn find_walks_back_through_past_messages_and_cycles() {
+        let mut tui = tui();
+        let mut app = app();
+        submit(&mut tui, &mut app, "/find hello");
+        assert!(tui.status_msg.contains("過去ログモード"));
+
+        tui.past_mode = true;
+        tui.past_messages = [
+            "@a: Hello ○",
+            "@b: 別の話 ○",
+            "@c: hello again ○",
+            "@d: 最新 ○",
+        ]
+        .map(String::from)
+        .to_vec();
+        tui.draw.last_size = (80, 4);
+        submit(&mut tui, &mut app, "/find hello");
+        // 新しい方から古い方へ、大文字小文字を区別せずに探す
+        assert_eq!(tui.draw.found, Some(2));
+        assert_eq!(tui.past_scroll_offset, 0);
+        tui.find(None, true);
+        assert_eq!(tui.draw.found, Some(0));
+        assert_eq!(tui.past_scroll_offset, 2);
+        // それより古いものは無いので位置はそのまま
+        tui.find(None, true);
+        assert_eq!(tui.draw.found, Some(0));
+        assert!(tui.status_msg.contains("古い方"));
+        tui.find(None, false);
+        assert_eq!(tui.draw.found, Some(2));
+
+        tui.leave_past_mode();
+        assert!(tui.find.is_none() && tui.draw.found.is_none());
+    }
+
+    #[test]
+    fn find_stops_loading_older_days_at_the_limit() {
+        let mut tui = tui();
+        tui.past_mode = true;
+        tui.past_messages = vec!["@a: hi ○".into()];
+        // 読み込める日はあるが（このテストでは中身が空）、見つからなければ上限で諦める
+        tui.past_dates = (0..100).map(|d| format!("2024{:04}", d)).collect();
+        tui.past_earliest_idx = Some(99);
+        tui.find(Some("nowhere".into()), true);
+        assert_eq!(tui.past_earliest_idx, Some(99 - MAX_FIND_LOAD_DAYS));
+        assert!(tui.status_msg.contains("見つかりません"));
+        tui.find(None, true);
+        assert_eq!(tui.past_earliest_idx, Some(99 - 2 * MAX_FIND_LOAD_DAYS));
+    }
+
+    #[test]
+    fn scrollback_cap_evicts_oldest_lines_but_keeps_them_stored() {
+        let dir = std::env::temp_dir().join(format!("p2w-scrollback-{}", std::process::id()));
+        storage::init_storage(&dir).unwrap();
+        let mut tui = tui();
+        tui.scrollback_max = 10;
+        tui.on_event(
+            rpc::Event::Chat {
+                id: "0a1b2c3d4e5f6071".into(),
+                line: "#0a1b2c3d4e5f6071 @bob: old ○".into(),
+                reply_to: None,
+            },
+            &PeerQuery::default(),
+        );
+        tui.push_msg("@me: 送信中".into());
+        tui.apply(Action::Send(rpc::Command::Chat(
+            "送信中".into(),
+            None,
+            false,
+        )));
+        for i in 0..25 {
+            tui.push_user_msg(format!("@me: scrollback {i}"));
+            assert!(tui.messages.len() <= 10);
+        }
+        tui.on_event(
+            rpc::Event::Chat {
+                id: "ffffffffffffffff".into(),
+                line: "#ffffffffffffffff @bob: new ○".into(),
+                reply_to: None,
+            },
+            &PeerQuery::default(),
+        );
+        tui.scroll_offset = 3;
+        let seen = tui.messages[tui.messages.len() - 1 - 3].clone();
+        tui.push_user_msg("@me: scrollback 25".into());
+        tui.push_user_msg("@me: scrollback 26".into());
+        // 最下端からの位置なので、押し出しの後も同じ行より新しい行ぶんだけずれる
+        assert_eq!(tui.messages[tui.messages.len() - 1 - 5], seen);
+        assert_eq!(tui.messages.last().unwrap(), "@me: scrollback 26");
+
+        // 押し出された行の索引は外れ、残った行は正しい位置を指す
+        assert!(!tui.tagged.contains_key("0a1b2c3d4e5f6071"));
+        let idx = tui.tagged["ffffffffffffffff"];
+        assert_eq!(tui.messages[idx], "#ffffffffffffffff @bob: new ○");
+        assert_eq!(tui.pending_echo, [None]);
+        tui.on_event(
+            rpc::Event::Sent {
+                id: "1111111111111111".into(),
+            },
+            &PeerQuery::default(),
+        );
+        assert!(tui.pending_echo.is_empty());
+        assert!(!tui.tagged.contains_key("1111111111111111"));
+
+        // 画面から消えても保存はすべて残っている
+        let stored = storage::list_dates()
+            .iter()
+            .flat_map(|d| storage::load_structured_day(d))
+            .filter(|r| r.text.starts_with("@me: scrollback "))
+            .count();
+        assert_eq!(stored, 27);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn past_line_names_sender_by_fingerprint() {
+        let rec = MessageRecord {
+            ts_millis: 0,
+            recv_ts_millis: 0,
+            kind: storage::MsgKind::Dm,
+            from_peer_id: Some(2),
+            to_peer_id: None,
+            handle: None,
+            text: "hi".into(),
+            signature: rpc::SigState::Valid,
+            reply_to: None,
+            binary: None,
+            proof: None,
+            peer_fingerprint: Some("0a1b2c3d4e5f6071".into()),
+        };
+        let mark = rpc::SigState::Valid.mark();
+        assert_eq!(
+            past_line(rec.clone()),
+            format!("指紋=0a1b2c3d4e5f6071: hi {}", mark)
+        );
+        let legacy = MessageRecord {
+            peer_fingerprint: None,
+            ..rec
+        };
+        assert_eq!(past_line(legacy), format!("@2: hi {}", mark));
+    }
+
+    #[test]
+    fn keyboard_paging_scrolls_without_mouse_events() {
+        let mut tui = tui();
+        tui.mouse_capture = false;
+        for i in 0..40 {
+            tui.push_msg(format!("@bob: {} ○", i));
+        }
+        tui.page_up(10);
+        assert_eq!(tui.scroll_offset, 9);
+        tui.page_up(10);
+        assert_eq!(tui.scroll_offset, 18);
+        tui.push_msg("@bob: new ○".into());
+        assert_eq!(tui.draw.new_below, 1);
+        tui.page_down(10);
+        assert_eq!(tui.scroll_offset, 9);
+        // 最新より下へは行かず、戻りきったら新着の数も消える
+        tui.page_down(10);
+        tui.page_down(10);
+        assert_eq!(tui.scroll_offset, 0);
+        assert_eq!(tui.draw.new_below, 0);
+        // 狭い画面でも 1 行は動く
+        tui.page_up(1);
+        assert_eq!(tui.scroll_offset, 1);
+    }
+
+    #[test]
+    fn counts_lines_that_arrive_while_scrolled_up() {
+        let mut tui = tui();
+        let mut app = app();
+        tui.push_msg("@bob: 1 ○".into());
+        assert_eq!(tui.draw.new_below, 0);
+
+        tui.scroll_up(10);
+        tui.scroll_up(10);
+        for i in 2..5 {
+            tui.push_msg(format!("@bob: {} ○", i));
+        }
+        assert_eq!(tui.draw.new_below, 3);
+        // 1 行戻っただけではまだ下に新着がある
+        tui.scroll_down();
+        assert_eq!(tui.draw.new_below, 3);
+        tui.scroll_down();
+        assert_eq!(tui.draw.new_below, 0);
+
+        tui.scroll_up(10);
+        tui.push_msg("@bob: 5 ○".into());
+        assert_eq!(tui.draw.new_below, 1);
+        submit(&mut tui, &mut app, "/bottom");
+        assert_eq!(tui.scroll_offset, 0);
+        assert_eq!(tui.draw.new_below, 0);
+    }
+
+    #[test]
+    fn show_expands_one_line_and_follows_eviction() {
+        let mut tui = tui();
+        let mut app = app();
+        for i in 0..3 {
+            tui.push_msg(format!("@bob: {} ○", i));
+        }
+        submit(&mut tui, &mut app, "/show 2");
+        assert_eq!(tui.draw.expanded, Some(1));
+        assert!(tui.draw.force_full);
+        // 範囲外は開かない
+        submit(&mut tui, &mut app, "/show 9");
+        assert_eq!(tui.draw.expanded, Some(1));
+        submit(&mut tui, &mut app, "/show x");
+        assert_eq!(tui.status_msg, "使い方: /show [line]");
+
+        // 古い行が押し出されても同じ行を指し続ける
+        tui.evict_oldest(1);
+        assert_eq!(tui.draw.expanded, Some(0));
+        tui.evict_oldest(1);
+        assert_eq!(tui.draw.expanded, None);
+
+        submit(&mut tui, &mut app, "/show 1");
+        assert_eq!(tui.draw.expanded, Some(0));
+        submit(&mut tui, &mut app, "/show");
+        assert_eq!(tui.draw.expanded, None);
+    }
+
+    #[test]
+    fn urgent_post_stands_out_and_may_ring_through_dnd() {
+        let mut tui = tui();
+        let mut app = app();
+        let urgent = |tui: &mut Tui| {
+            let line = format!("#0a1b @bob: 障害発生 ○{}", rpc::URGENT_MARK);
+            tui.on_event(
+                rpc::Event::Chat {
+                    id: "0a1b".into(),
+                    line,
+                    reply_to: None,
+                },
+                &PeerQuery::default(),
+            );
+            tui.on_event(
+                rpc::Event::UrgentArrived {
+                    from: "@bob".into(),
+                },
+                &PeerQuery::default(),
+            );
+        };
+        urgent(&mut tui);
+        assert!(std::mem::take(&mut tui.bell));
+        assert_eq!(tui.status_msg, "至急: @bob");
+        let last = tui.messages.last().unwrap();
+        assert_eq!(theme::classify_line(last, "@me"), theme::LineKind::Urgent);
+
+        // おやすみモード中は既定では鳴らさず、urgent_bell_in_dnd なら鳴らす
+        submit(&mut tui, &mut app, "/dnd on");
+        urgent(&mut tui);
+        assert!(!tui.bell);
+        tui.urgent_bell_in_dnd = true;
+        urgent(&mut tui);
+        assert!(tui.bell);
+    }
+
+    #[test]
+    fn dnd_silences_dm_bell_but_keeps_the_line() {
+        let mut tui = tui();
+        let mut app = app();
+        let dm = |tui: &mut Tui| {
+            tui.on_event(
+                rpc::Event::Post {
+                    line: "@bob: 内緒 ○".into(),
+                    sig: SigState::Unsigned,
+                },
+                &PeerQuery::default(),
+            );
+            tui.on_event(
+                rpc::Event::DmArrived {
+                    from: "@bob".into(),
+                },
+                &PeerQuery::default(),
+            );
+        };
+        dm(&mut tui);
+        assert!(std::mem::take(&mut tui.bell));
+        assert_eq!(tui.status_msg, "新着 DM: @bob");
+
+        submit(&mut tui, &mut app, "/dnd on");
+        assert!(tui.draw.dnd);
+        dm(&mut tui);
+        assert!(!tui.bell);
+        assert_eq!(tui.status_msg, "おやすみモード: ON (通知を止めます)");
+        assert_eq!(tui.messages.len(), 2);
+        assert_eq!(tui.messages[1], "@bob: 内緒 ○");
+
+        // 時間帯の中は /dnd off でもおやすみのまま、外に出たら解ける
+        tui.quiet_hours = QuietHours::parse("22:00-07:00");
+        tui.refresh_dnd(23 * 60);
+        submit(&mut tui, &mut app, "/dnd off");
+        assert!(tui.draw.dnd);
+        assert_eq!(
+            tui.status_msg,
+            "おやすみモード: OFF (ただし 22:00-07:00 の間は ON)"
+        );
+        tui.refresh_dnd(7 * 60);
+        assert!(!tui.draw.dnd);
+        dm(&mut tui);
+        assert!(tui.bell);
+    }
+
+    #[test]
+    fn quiet_hours_may_wrap_past_midnight() {
+        let night = QuietHours::parse("22:00-07:00").unwrap();
+        assert!(night.contains(22 * 60));
+        assert!(night.contains(3 * 60));
+        assert!(!night.contains(7 * 60));
+        assert!(!night.contains(12 * 60));
+        let lunch = QuietHours::parse("12:00-13:30").unwrap();
+        assert!(lunch.contains(13 * 60));
+        assert!(!lunch.contains(13 * 60 + 30));
+        assert_eq!(lunch.to_string(), "12:00-13:30");
+        for bad in ["24:00-01:00", "12-13", "12:00", "ab:cd-01:00"] {
+            assert_eq!(QuietHours::parse(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn inflight_line_is_restored_and_cleared_after_sending() {
+        let path =
+            std::env::temp_dir().join(format!("p2w-inflight-{}.inflight", std::process::id()));
+        std::fs::write(&path, "3\n@bob へ長い返事を書いている途中").unwrap();
+        let (file, left) = Inflight::load(path.clone());
+        let (text, cursor) = left.unwrap();
+        let mut tui = tui();
+        let mut app = app();
+        tui.inflight = Some(file);
+        tui.restore_inflight(text, cursor);
+        assert_eq!(tui.input, "@bob へ長い返事を書いている途中");
+        assert_eq!(tui.cursor_pos, 3);
+
+        // 書き足した分は間隔を空けて書き出す
+        tui.insert_char('!');
+        tui.save_inflight(1_000);
+        tui.insert_char('!');
+        tui.save_inflight(1_500);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "4\n@bo!b へ長い返事を書いている途中"
+        );
+        tui.save_inflight(2_000);
+        assert!(
+            std::fs::read_to_string(&path)
+                .unwrap()
+                .starts_with("5\n@bo!!b")
+        );
+
+        // 送信して入力行が空になればすぐ消す
+        let line = tui.input.clone();
+        submit(&mut tui, &mut app, &line);
+        tui.save_inflight(2_001);
+        assert!(!path.exists());
+        let (_, left) = Inflight::load(path);
+        assert_eq!(left, None);
+    }
+
+    #[test]
+    fn focus_hides_live_posts_but_keeps_them_stored() {
+        let dir = std::env::temp_dir().join(format!("p2w-focus-{}", std::process::id()));
+        storage::init_storage(&dir).unwrap();
+        let mut tui = tui();
+        let mut app = app();
+        submit(&mut tui, &mut app, "/focus on");
+        assert_eq!(tui.draw.focus_hidden, Some(0));
+
+        // ネットワーク側が保存してから画面へ送る流れを真似る
+        let now = utils::current_unix_millis();
+        let rec = MessageRecord {
+            ts_millis: now,
+            recv_ts_millis: now,
+            kind: storage::MsgKind::Chat,
+            from_peer_id: Some(0),
+            to_peer_id: None,
+            handle: Some("@bob".into()),
+            text: "@bob: 集中中の投稿".into(),
+            signature: SigState::Valid,
+            reply_to: None,
+            binary: None,
+            proof: None,
+            peer_fingerprint: None,
+        };
+        storage::store_structured(&rec, Some("1212121212121212")).unwrap();
+        let shown = tui.messages.len();
+        tui.on_event(
+            rpc::Event::Chat {
+                id: "1212121212121212".into(),
+                line: "#1212121212121212 @bob: 集中中の投稿 ○".into(),
+                reply_to: None,
+            },
+            &PeerQuery::default(),
+        );
+        tui.on_event(
+            rpc::Event::Replace {
+                id: "1212121212121212".into(),
+                line: "#1212121212121212 @bob: 直した投稿 ○ (編集済み)".into(),
+            },
+            &PeerQuery::default(),
+        );
+        // システムの知らせは隠さない
+        tui.on_event(
+            rpc::Event::Message("接続しました".into()),
+            &PeerQuery::default(),
+        );
+        assert_eq!(tui.messages.len(), shown + 1);
+        assert_eq!(tui.draw.focus_hidden, Some(2));
+        assert!(tui.shown_sig_counts().is_empty());
+
+        // 隠している間も過去ログでは読める
+        tui.enter_past_mode();
+        assert!(
+            tui.past_messages
+                .iter()
+                .any(|l| l.contains("@bob: 集中中の投稿")),
+            "{:?}",
+            tui.past_messages
+        );
+        tui.leave_past_mode();
+
+        // 解くと届いた順に出し、差し替えも当たる
+        submit(&mut tui, &mut app, "/focus off");
+        assert_eq!(tui.draw.focus_hidden, None);
+        assert_eq!(tui.status_msg, "集中モード: OFF (隠していた 2 件を表示)");
+        assert_eq!(tui.messages.len(), shown + 2);
+        let idx = tui.tagged["1212121212121212"];
+        assert_eq!(
+            tui.messages[idx],
+            "#1212121212121212 @bob: 直した投稿 ○ (編集済み)"
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,248 @@
+//! `p2witter --check`: TUI もネットワークも起動せずに、設定・鍵・ハンドル・DB を確かめる。
+//! デプロイ前やスクリプトから使う。1つでも失敗があれば終了コード 1 で終わる。
+//! /selftest 用に、暗号とプロトコルがこの環境で動くかを確かめる self_test も置く
+
+use p2witter::core::{crypto, protocol};
+use p2witter::{config, storage};
+use std::path::Path;
+
+/// 1項目の結果（Ok は補足、Err は失敗の理由）
+pub struct CheckItem {
+    pub name: &'static str,
+    pub result: Result<String, String>,
+}
+
+/// 設定ファイルと DB を調べる。設定ファイルが無くても既定値を書き出さない
+pub fn run_checks(config_path: &Path, db_path: &Path) -> Vec<CheckItem> {
+    let mut items = Vec::new();
+    let cfg = std::fs::read_to_string(config_path)
+        .map_err(|e| format!("{} を読めません: {}", config_path.display(), e))
+        .and_then(|s| {
+            s.parse::<toml::Table>()
+                .map_err(|e| format!("{} の形式が不正です: {}", config_path.display(), e))
+        });
+    items.push(CheckItem {
+        name: "設定",
+        result: cfg
+            .as_ref()
+            .map(|_| config_path.display().to_string())
+            .map_err(Clone::clone),
+    });
+    if let Ok(cfg) = &cfg {
+        items.push(CheckItem {
+            name: "ハンドル",
+            result: check_handle(cfg),
+        });
+        items.push(CheckItem {
+            name: "鍵",
+            result: check_keys(cfg),
+        });
+    }
+    items.push(CheckItem {
+        name: "DB",
+        result: storage::check_db(db_path)
+            .map(|_| db_path.display().to_string())
+            .map_err(|e| format!("{} を開けません: {}", db_path.display(), e)),
+    });
+    items
+}
+
+fn check_handle(cfg: &toml::Table) -> Result<String, String> {
+    let handle = config::get_value_in(cfg, config::keys::USER_HANDLE)
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .ok_or("user.handle が未設定です")?;
+    if config::is_valid_handle_in(cfg, &handle) {
+        Ok(handle)
+    } else {
+        Err(format!("user.handle '{}' が不正です", handle))
+    }
+}
+
+fn check_keys(cfg: &toml::Table) -> Result<String, String> {
+    let hex = |key: &str| {
+        let s = config::get_value_in(cfg, key)
+            .and_then(|v| v.as_str().map(|s| s.to_string()))
+            .ok_or(format!("{} が未設定です (/init で生成)", key))?;
+        crypto::from_hex(&s).map_err(|_| format!("{} が16進として読めません", key))
+    };
+    let (pkcs8, public) = (
+        hex(config::keys::KEY_PKCS8)?,
+        hex(config::keys::KEY_PUBLIC)?,
+    );
+    let derived = crypto::public_key_from_pkcs8(&pkcs8)
+        .map_err(|_| "key.pkcs8 が Ed25519 の秘密鍵として読めません".to_string())?;
+    if derived != public {
+        return Err("key.public が key.pkcs8 の公開鍵と一致しません".into());
+    }
+    Ok(format!("指紋={}", &crypto::fingerprint_hex(&public)[..16]))
+}
+
+/// 使い捨ての鍵で署名・フレーム・DM 暗号・トークンを往復させる。設定や DB には触れない
+pub fn self_test() -> Vec<CheckItem> {
+    let mut items = Vec::new();
+    let keys = crypto::generate_ed25519_keypair().map_err(|e| e.to_string());
+    items.push(CheckItem {
+        name: "鍵の生成",
+        result: keys
+            .as_ref()
+            .map(|k| format!("指紋={}", &crypto::fingerprint_hex(&k.public)[..16]))
+            .map_err(Clone::clone),
+    });
+    let msg = protocol::Message::chat("@selftest: 自己診断", 1_700_000_000_000);
+    let signed = keys
+        .as_ref()
+        .map_err(|_| "鍵が無いので省略".to_string())
+        .and_then(|k| {
+            let sig = crypto::sign_ed25519(&protocol::signing_bytes(&msg), &k.pkcs8)
+                .map_err(|e| e.to_string())?;
+            Ok(msg.clone().with_key_sig(k.public.clone(), sig))
+        });
+    items.push(CheckItem {
+        name: "署名と検証",
+        result: signed.as_ref().map_err(Clone::clone).and_then(|m| {
+            let pk = m.public_key.as_deref().unwrap_or_default();
+            let sig = m.signature.as_deref().unwrap_or_default();
+            crypto::verify_ed25519(&protocol::signing_bytes(m), sig, pk)
+                .map_err(|e| e.to_string())?;
+            // 1 バイト変えたら通ってはいけない
+            let mut tampered = protocol::signing_bytes(m);
+            tampered[0] ^= 1;
+            match crypto::verify_ed25519(&tampered, sig, pk) {
+                Ok(()) => Err("改ざんした本文でも検証が通りました".into()),
+                Err(_) => Ok("改ざんも検出".into()),
+            }
+        }),
+    });
+    items.push(CheckItem {
+        name: "フレーム",
+        result: frame_round_trip(signed.unwrap_or(msg)),
+    });
+    items.push(CheckItem {
+        name: "DM 暗号",
+        result: {
+            let plain = "@selftest: 内緒の話".as_bytes();
+            crypto::encrypt_dm_payload(plain)
+                .and_then(|c| crypto::decrypt_dm_payload(&c))
+                .map_err(|e| e.to_string())
+                .and_then(|p| match p == plain {
+                    true => Ok(format!("{} バイト", p.len())),
+                    false => Err("復号した本文が元と違います".into()),
+                })
+        },
+    });
+    items.push(CheckItem {
+        name: "トークン",
+        result: {
+            let addr = "127.0.0.1:2234";
+            crypto::encrypt_conninfo_to_hex(addr)
+                .and_then(|t| crypto::decrypt_conninfo_from_hex(&t))
+                .map_err(|e| e.to_string())
+                .and_then(|a| match a == addr {
+                    true => Ok(a),
+                    false => Err(format!("復号した接続先が違います: {}", a)),
+                })
+        },
+    });
+    items
+}
+
+// 2 回に分けて流し込み、継ぎ目をまたいでも 1 通に戻るか
+fn frame_round_trip(msg: protocol::Message) -> Result<String, String> {
+    let bytes = protocol::encode(&msg);
+    let (head, tail) = bytes.split_at(bytes.len() / 2);
+    let mut decoder = protocol::Decoder::new();
+    decoder.feed(head);
+    let mut got = decoder.drain().map_err(|e| e.to_string())?;
+    decoder.feed(tail);
+    got.extend(decoder.drain().map_err(|e| e.to_string())?);
+    match got.as_slice() {
+        [m] if *m == msg => Ok(format!("{} バイト", bytes.len())),
+        _ => Err(format!("{} 通に復元されました", got.len())),
+    }
+}
+
+/// 表示用の報告と、全項目が通ったか
+pub fn report(items: &[CheckItem]) -> (String, bool) {
+    let mut lines = Vec::new();
+    let mut failed = 0;
+    for item in items {
+        match &item.result {
+            Ok(detail) => lines.push(format!("[OK] {}: {}", item.name, detail)),
+            Err(reason) => {
+                failed += 1;
+                lines.push(format!("[NG] {}: {}", item.name, reason));
+            }
+        }
+    }
+    if failed == 0 {
+        lines.push("結果: 問題なし".into());
+    } else {
+        lines.push(format!("結果: 失敗 {} 件", failed));
+    }
+    (lines.join("\n"), failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("p2witter-check-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn good_config_passes_and_broken_one_fails() {
+        let dir = temp_dir("good");
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let good = format!(
+            "[user]\nhandle = \"@alice\"\n[key]\npkcs8 = \"{}\"\npublic = \"{}\"\n",
+            crypto::to_hex(&keys.pkcs8),
+            crypto::to_hex(&keys.public),
+        );
+        std::fs::write(dir.join("config.toml"), &good).unwrap();
+        let items = run_checks(&dir.join("config.toml"), &dir.join("p2witter.db"));
+        let (text, ok) = report(&items);
+        assert!(ok, "{}", text);
+        assert!(text.ends_with("結果: 問題なし"));
+
+        // 別の鍵の公開鍵・不正なハンドル
+        let other = crypto::generate_ed25519_keypair().unwrap();
+        let bad = good
+            .replace(
+                &format!("public = \"{}\"", crypto::to_hex(&keys.public)),
+                &format!("public = \"{}\"", crypto::to_hex(&other.public)),
+            )
+            .replace("@alice", "alice");
+        std::fs::write(dir.join("config.toml"), bad).unwrap();
+        let items = run_checks(&dir.join("config.toml"), &dir.join("p2witter.db"));
+        let (text, ok) = report(&items);
+        assert!(!ok);
+        assert!(text.contains("[NG] ハンドル"));
+        assert!(text.contains("[NG] 鍵: key.public が key.pkcs8 の公開鍵と一致しません"));
+        assert!(text.contains("[OK] DB"));
+        assert!(text.ends_with("結果: 失敗 2 件"));
+
+        // 設定ファイルが無いときは作らずに失敗する
+        let missing = dir.join("missing.toml");
+        let (_, ok) = report(&run_checks(&missing, &dir.join("p2witter.db")));
+        assert!(!ok);
+        assert!(!missing.exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn self_test_passes_every_step() {
+        let items = self_test();
+        let (text, ok) = report(&items);
+        assert!(ok, "{}", text);
+        let names: Vec<_> = items.iter().map(|i| i.name).collect();
+        assert_eq!(
+            names,
+            ["鍵の生成", "署名と検証", "フレーム", "DM 暗号", "トークン"]
+        );
+        assert!(text.contains("[OK] 署名と検証: 改ざんも検出"));
+    }
+}
@@ -0,0 +1,1838 @@
+//! TUI の入力行を解釈し、実行すべき動作 (Action) の列に変換する。
+//! 画面やネットワークには直接触れないので単体テストできる。
+
+use p2witter::core::{crypto, protocol, rpc};
+use p2witter::{config, network_handler};
+
+use crate::theme::{self, Theme};
+
+// コマンド仕様（説明・使い方）
+#[derive(Clone, Copy)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub usage: &'static str,
+}
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "/help",
+        description: "コマンド一覧または詳細を表示",
+        usage: "/help [name]",
+    },
+    CommandSpec {
+        name: "/open",
+        description: "ローカルで待受を開始し、トークンを表示（save= でファイルにも書き出す）",
+        usage: "/open <port> [save=<path>]",
+    },
+    CommandSpec {
+        name: "/close",
+        description: "待受を終了（ポート指定なしなら全部）",
+        usage: "/close [port]",
+    },
+    CommandSpec {
+        name: "/token",
+        description: "待受中の接続トークンをもう一度表示（ポートマッピング中は外部用も）",
+        usage: "/token",
+    },
+    CommandSpec {
+        name: "/discover",
+        description: "接続先から紹介されたピアの待受アドレスとトークンを表示",
+        usage: "/discover",
+    },
+    CommandSpec {
+        name: "/topology",
+        description: "届く範囲のピアのつながりを隣接リストで表示（share_topology のノードだけ答える）",
+        usage: "/topology",
+    },
+    CommandSpec {
+        name: "/connect",
+        description: "トークンで接続（@<path> ならファイルから読む）",
+        usage: "/connect <token|@path>",
+    },
+    CommandSpec {
+        name: "/inspect",
+        description: "トークンを復号して接続先を表示（接続はしない）",
+        usage: "/inspect <token>",
+    },
+    CommandSpec {
+        name: "/disconnect",
+        description: "接続を切断（id を省くと一覧から選ぶ）",
+        usage: "/disconnect [id]",
+    },
+    CommandSpec {
+        name: "/peers",
+        description: "接続中のピア一覧を表示（sort=id|handle|rtt で並べ替え、文字列でハンドル・指紋を絞り込み）",
+        usage: "/peers [sort=id|handle|rtt] [filter]",
+    },
+    CommandSpec {
+        name: "/whois",
+        description: "ピアの署名鍵と、接続元アドレスの逆引き・AS（本人確認ではない参考情報）を表示",
+        usage: "/whois <id>",
+    },
+    CommandSpec {
+        name: "/config",
+        description: "設定できるキーを今の値と説明付きで一覧（キーを指定で詳細、値も付けると保存）",
+        usage: "/config [key [value]]",
+    },
+    CommandSpec {
+        name: "/whoami",
+        description: "自分のハンドル・公開鍵・指紋と待受中のトークンを表示（別の経路で相手に伝える用）",
+        usage: "/whoami",
+    },
+    CommandSpec {
+        name: "/trust",
+        description: "別の経路で確かめた指紋を検証済みにする（引数なしで一覧）",
+        usage: "/trust [fingerprint]",
+    },
+    CommandSpec {
+        name: "/known",
+        description: "これまでに接続したことのある相手を最後に見た日時付きで表示（未接続も含む）",
+        usage: "/known",
+    },
+    CommandSpec {
+        name: "/reverify",
+        description: "保存済みメッセージの署名を保存時の材料で検証し直し、変わった件数を表示",
+        usage: "/reverify",
+    },
+    CommandSpec {
+        name: "/certs",
+        description: "ピア証明書（公開鍵）一覧を表示",
+        usage: "/certs",
+    },
+    CommandSpec {
+        name: "/cert",
+        description: "指定ピアの公開鍵詳細を表示",
+        usage: "/cert <id>",
+    },
+    CommandSpec {
+        name: "/dm",
+        description: "指定ピア（@handle なら同名のうち検証済み・署名の通る相手、または16桁の指紋の相手）にダイレクトメッセージを送信（引数なしで一覧から選ぶ）",
+        usage: "/dm <to_id|@handle|指紋> <message> | /dm",
+    },
+    CommandSpec {
+        name: "/edm",
+        description: "保存されない揮発 DM を送信（自分にも相手にも履歴が残らない）",
+        usage: "/edm <to_id> <message>",
+    },
+    CommandSpec {
+        name: "/msg",
+        description: "全体にメッセージを送信（未接続なら接続後に送信）",
+        usage: "/msg <message>",
+    },
+    CommandSpec {
+        name: "/urgent",
+        description: "至急の印を付けて全体に送信（受け手の画面で目立ち、ベルが鳴る）",
+        usage: "/urgent <message>",
+    },
+    CommandSpec {
+        name: "/reply",
+        description: "指定した投稿に返信",
+        usage: "/reply <id> <message>",
+    },
+    CommandSpec {
+        name: "/edit",
+        description: "自分の投稿を編集（ID は行頭の #xxxx）",
+        usage: "/edit <id> <message>",
+    },
+    CommandSpec {
+        name: "/delete",
+        description: "自分の投稿を削除",
+        usage: "/delete <id>",
+    },
+    CommandSpec {
+        name: "/topic",
+        description: "部屋のトピックを設定（後から接続したピアにも届く）",
+        usage: "/topic <text>",
+    },
+    CommandSpec {
+        name: "/bookmark",
+        description: "投稿のブックマークを付け外し（ID は行頭の #xxxx）",
+        usage: "/bookmark <id>",
+    },
+    CommandSpec {
+        name: "/bookmarks",
+        description: "ブックマークした投稿を過去ログ表示で一覧（/past で戻る）",
+        usage: "/bookmarks",
+    },
+    CommandSpec {
+        name: "/dms",
+        description: "指定したハンドルとの DM だけを過去ログ表示で一覧（/past で戻る）",
+        usage: "/dms @handle",
+    },
+    CommandSpec {
+        name: "/bottom",
+        description: "最新の行まで戻る（End キーと同じ）",
+        usage: "/bottom",
+    },
+    CommandSpec {
+        name: "/show",
+        description: "縮めて表示した長い行を全文で表示（行番号を省くと閉じる）",
+        usage: "/show [line]",
+    },
+    CommandSpec {
+        name: "/selftest",
+        description: "暗号とプロトコルがこの環境で動くかを確かめる（設定・履歴には触れない）",
+        usage: "/selftest",
+    },
+    CommandSpec {
+        name: "/dnd",
+        description: "おやすみモード（DM のベルと通知を止める。表示と保存はそのまま）",
+        usage: "/dnd <on|off>",
+    },
+    CommandSpec {
+        name: "/focus",
+        description: "集中モード（届いた投稿を画面に出さず件数だけ表示。中継と保存は続け、off でまとめて表示）",
+        usage: "/focus <on|off>",
+    },
+    CommandSpec {
+        name: "/find",
+        description: "過去ログ内を古い方へ検索して移動（n/N で次・前、語を省くと続きを探す）",
+        usage: "/find [text]",
+    },
+    CommandSpec {
+        name: "/draft",
+        description: "入力途中の本文を名前を付けて保存・入力行に復元・一覧表示",
+        usage: "/draft save <name> <text> | /draft load <name> | /draft list",
+    },
+    CommandSpec {
+        name: "/handle",
+        description: "自分のハンドル名を設定（@から始まり空白・制御文字なし、既定で80文字未満）",
+        usage: "/handle @name",
+    },
+    CommandSpec {
+        name: "/init",
+        description: "署名鍵を生成して保存（既存の鍵は force 指定時のみ上書き）",
+        usage: "/init [force]",
+    },
+    CommandSpec {
+        name: "/rotatekey",
+        description: "署名鍵を作り直し、旧鍵で署名した通知を接続中のピアに送る",
+        usage: "/rotatekey",
+    },
+    CommandSpec {
+        name: "/clear",
+        description: "画面の表示をクリア（保存済みの履歴は残る）",
+        usage: "/clear",
+    },
+    CommandSpec {
+        name: "/history",
+        description: "保存済みの履歴を全削除（yes で確定）",
+        usage: "/history clear yes",
+    },
+    CommandSpec {
+        name: "/backup",
+        description: "保存済みの履歴をバイナリ形式でファイルに書き出す",
+        usage: "/backup <path>",
+    },
+    CommandSpec {
+        name: "/restore",
+        description: "/backup で書き出したファイルから履歴を復元（重複は飛ばす）",
+        usage: "/restore <path>",
+    },
+    CommandSpec {
+        name: "/audit",
+        description: "直近の監査ログ（署名不正・鍵変更・切断）を表示",
+        usage: "/audit [count]",
+    },
+    CommandSpec {
+        name: "/relay",
+        description: "受信メッセージの中継を ON/OFF（off で leaf ノード）",
+        usage: "/relay <on|off>",
+    },
+    CommandSpec {
+        name: "/fanout",
+        description: "1 ティックに中継で送る宛先数の上限（off で無制限。超えた分は次のティックへ）",
+        usage: "/fanout <n|off>",
+    },
+    CommandSpec {
+        name: "/theme",
+        description: "配色テーマを切り替え（default|dark|light|mono）",
+        usage: "/theme <name>",
+    },
+    CommandSpec {
+        name: "/compact",
+        description: "同じ人の連続した投稿をハンドル 1 つにまとめて表示",
+        usage: "/compact <on|off>",
+    },
+    CommandSpec {
+        name: "/color",
+        description: "ハンドルの文字色を指定（再起動後も使う。指定なしはハンドルごとに自動）",
+        usage: "/color @handle <color>",
+    },
+    CommandSpec {
+        name: "/legend",
+        description: "画面に出ているハンドルとその色を一覧",
+        usage: "/legend",
+    },
+    CommandSpec {
+        name: "/timers",
+        description: "ping・無通信タイムアウトなどのタイマー設定を表示",
+        usage: "/timers",
+    },
+    CommandSpec {
+        name: "/version",
+        description: "クレート/プロトコルのバージョンと接続中ピアのバージョンを表示",
+        usage: "/version",
+    },
+    CommandSpec {
+        name: "/raw",
+        description: "16進のバイト列をそのままピアへ送る（開発者向け: developer = true）",
+        usage: "/raw [id] <hex>",
+    },
+    CommandSpec {
+        name: "/dump",
+        description: "ピアから最後に受信した生バイト列を16進で表示（開発者向け）",
+        usage: "/dump <id>",
+    },
+    CommandSpec {
+        name: "/exit",
+        description: "アプリケーションを終了",
+        usage: "/exit",
+    },
+];
+pub fn find_command(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+const NO_NETWORK: &str = "ネットワークスレッドがありません。";
+const SPECTATOR: &str = "観戦モード中は発言できません";
+const DEVELOPER_ONLY: &str = "開発者向けコマンドです (config に developer = true)";
+/// /raw で送れる最大バイト数
+pub const MAX_RAW_BYTES: usize = 64 * 1024;
+const QUEUED: &str = "未接続のため送信待ちにしました。/open か /connect で接続すると送信します";
+const DEFAULT_AUDIT_COUNT: usize = 20;
+
+/// /peers の並び順
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerSort {
+    #[default]
+    Id,
+    Handle,
+    Rtt,
+}
+
+impl PeerSort {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "id" => Some(Self::Id),
+            "handle" => Some(Self::Handle),
+            "rtt" => Some(Self::Rtt),
+            _ => None,
+        }
+    }
+}
+
+/// 引数なしの /dm・/disconnect で開くピア選択の用途
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PickFor {
+    Dm,
+    Disconnect,
+}
+
+/// /peers の並び順と絞り込み
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PeerQuery {
+    pub sort: PeerSort,
+    /// ハンドルか指紋にこの文字列を含むピアだけ表示（大文字小文字は区別しない）
+    pub filter: Option<String>,
+}
+
+impl PeerQuery {
+    pub fn matches(&self, peer: &rpc::PeerInfo) -> bool {
+        let Some(f) = &self.filter else {
+            return true;
+        };
+        let f = f.to_lowercase();
+        [&peer.handle, &peer.fingerprint]
+            .into_iter()
+            .flatten()
+            .any(|s| s.to_lowercase().contains(&f))
+    }
+}
+
+/// コマンド解釈に必要なアプリ状態
+pub struct AppState {
+    /// 自分のハンドル（config::is_valid_handle の規則）
+    pub handle: String,
+    /// ネットワークスレッドが起動済みか
+    pub network_running: bool,
+    /// 次に届くピア一覧の並び順と絞り込み
+    pub peers: PeerQuery,
+    /// 保存済みの自分の公開鍵
+    pub public_key: Option<Vec<u8>>,
+    /// 観戦モード: 受信・中継はするが自分からは発言しない。
+    /// HELLO は送るので公開鍵は相手に見える
+    pub spectator: bool,
+    /// 受信した Chat を中継するか (false なら leaf ノード)
+    pub relay: bool,
+    /// /raw・/dump などの開発者向けコマンドを使えるか
+    pub developer: bool,
+    /// 初回起動: ハンドルが決まるまで、/ で始まらない入力をハンドルとして扱う
+    pub first_run: bool,
+}
+
+impl AppState {
+    pub fn has_valid_handle(&self) -> bool {
+        config::is_valid_handle(&self.handle)
+    }
+}
+
+/// handle_command が返す動作。イベントループが順に実行する。
+#[derive(Debug)]
+pub enum Action {
+    /// 起動済みのネットワークスレッドへコマンドを送る
+    Send(rpc::Command),
+    /// ネットワークスレッドが無ければ起動してからコマンドを送る
+    SpawnAndSend(rpc::Command),
+    /// 画面に追加（保存しない）
+    Show(String),
+    /// ユーザー投稿として画面に追加し保存
+    ShowUser(String),
+    /// 返信先 (メッセージID) の引用を画面に追加
+    Quote(String),
+    /// ステータスバーを更新
+    Status(String),
+    /// 設定値を保存
+    SaveConfig(&'static str, toml::Value),
+    /// 過去ログモードの ON/OFF
+    TogglePast,
+    /// ピアの選択を開く（一覧は続けて送る PeerList の応答で埋まる）
+    PickPeer(PickFor),
+    /// 画面の表示行と署名状態の内訳を消す（保存済みの履歴は残す）
+    ClearScreen,
+    /// 保存済み履歴の全削除
+    ClearHistory,
+    /// 直近 n 件の監査ログを表示
+    ShowAudit(usize),
+    /// これまでに見た相手の一覧を表示
+    ShowKnown,
+    /// 設定キーの一覧 (None) か 1 つの詳細を表示
+    ShowConfig(Option<&'static str>),
+    /// 保存済みメッセージの署名を検証し直す
+    Reverify,
+    /// 指紋を検証済みとして保存する
+    Trust(String),
+    /// 検証済みの相手の一覧を表示
+    ShowTrusted,
+    /// 配色テーマを切り替える
+    SetTheme(Theme),
+    /// 同じハンドルの連続した投稿をまとめて表示するか
+    SetCompact(bool),
+    /// ハンドルの色を指定して保存する
+    SetHandleColor(String, String),
+    /// 表示中のハンドルと色の一覧を表示
+    ShowLegend,
+    /// 履歴をファイルへバイナリで書き出す
+    Backup(String),
+    /// バイナリのバックアップから履歴を復元する
+    Restore(String),
+    /// 下書きを名前付きで保存
+    SaveDraft(String, String),
+    /// 下書きを入力行に戻す
+    LoadDraft(String),
+    /// 下書きの一覧を表示
+    ListDrafts,
+    /// メッセージID のブックマークを付け外し
+    ToggleBookmark(String),
+    /// ブックマークした投稿を一覧表示
+    ShowBookmarks,
+    /// 指定したハンドルとの DM を一覧表示
+    ShowDmThread(String),
+    /// 最新の行までスクロールを戻す
+    ScrollToBottom,
+    /// おやすみモードの ON/OFF
+    SetDnd(bool),
+    /// 集中モードの ON/OFF
+    SetFocus(bool),
+    /// 暗号とプロトコルの自己診断を実行して結果を表示
+    SelfTest,
+    /// 長い行を全文表示（1 始まりの行番号、None なら閉じる）
+    ShowFull(Option<usize>),
+    /// 過去ログ内を古い方へ検索（None なら前回の語で次を探す）
+    Find(Option<String>),
+    /// アプリケーション終了
+    Exit,
+}
+
+/// 1 行の入力を解釈して Action の列を返す。
+pub fn handle_command(line: &str, state: &mut AppState) -> Vec<Action> {
+    let line = line.trim();
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if state.first_run && !state.has_valid_handle() && !line.is_empty() && !line.starts_with('/') {
+        return first_run_setup(line, state);
+    }
+    // ローカルエコーは行わない (サーバ経由で戻る表示と二重防止)
+    match parts.first().copied() {
+        Some("/help") => {
+            if let Some(target) = parts.get(1) {
+                let key = if target.starts_with('/') {
+                    target.to_string()
+                } else {
+                    format!("/{}", target)
+                };
+                if let Some(spec) = find_command(&key) {
+                    vec![Action::Show(format!(
+                        "{}\n  説明: {}\n  使い方: {}",
+                        spec.name, spec.description, spec.usage
+                    ))]
+                } else {
+                    vec![Action::Status(format!(
+                        "不明なコマンド: {} (/help で一覧)",
+                        key
+                    ))]
+                }
+            } else {
+                let mut lines = vec!["コマンド一覧:".to_string()];
+                for c in COMMANDS.iter() {
+                    if c.name == "/past" {
+                        lines.push(format!("{:10} - {}", c.name, "過去ログモードのON/OFFを切替。ONで最新日を読み込み。スクロール最上端到達で前日追加ロード"));
+                    } else {
+                        lines.push(format!("{:10} - {}", c.name, c.description));
+                    }
+                }
+                vec![Action::Show(lines.join("\n"))]
+            }
+        }
+        Some("/open") => {
+            let Some(port) = parts.get(1) else {
+                return vec![Action::Status("使い方: /open <port>".into())];
+            };
+            if !state.has_valid_handle() {
+                return vec![Action::Status(
+                    "ハンドル未設定です。/handle @name を先に実行してください".into(),
+                )];
+            }
+            let save = match parts.get(2).map(|o| o.strip_prefix("save=")) {
+                None => None,
+                Some(Some(path)) if !path.is_empty() => Some(path.to_string()),
+                Some(_) => {
+                    return vec![Action::Status("使い方: /open <port> [save=<path>]".into())];
+                }
+            };
+            vec![Action::SpawnAndSend(rpc::Command::Open(
+                port.to_string(),
+                save,
+            ))]
+        }
+        Some("/connect") => {
+            let Some(arg) = parts.get(1) else {
+                return vec![Action::Status("使い方: /connect <token>".into())];
+            };
+            if !state.has_valid_handle() {
+                return vec![Action::Status(
+                    "ハンドル未設定です。/handle @name を先に実行してください".into(),
+                )];
+            }
+            // @<path> ならファイルから読み、平文アドレスなら自動でトークン化して送る
+            let token = if let Some(path) = arg.strip_prefix('@') {
+                match network_handler::read_token_file(path) {
+                    Ok(token) => token,
+                    Err(e) => return vec![Action::Status(e)],
+                }
+            } else if arg.contains(':') {
+                crypto::encrypt_conninfo_to_hex(arg).unwrap_or_else(|_| arg.to_string())
+            } else {
+                arg.to_string()
+            };
+            vec![Action::SpawnAndSend(rpc::Command::Connect(token))]
+        }
+        Some("/inspect") => match parts.get(1) {
+            Some(token) => vec![inspect_token(token)],
+            None => vec![Action::Status("使い方: /inspect <token>".into())],
+        },
+        Some("/handle") => {
+            let Some(name) = parts.get(1).map(|n| config::normalize_handle(n)) else {
+                return vec![Action::Status("使い方: /handle @name".into())];
+            };
+            if !config::is_valid_handle(&name) {
+                return vec![Action::Status(format!(
+                    "使い方: /handle @name （{}）",
+                    config::handle_rule()
+                ))];
+            }
+            state.handle = name;
+            let mut actions = vec![
+                Action::SaveConfig(
+                    config::keys::USER_HANDLE,
+                    toml::Value::String(state.handle.clone()),
+                ),
+                Action::Status(format!("ハンドルを {} に設定", state.handle)),
+            ];
+            // ネットワークスレッドがあれば伝える
+            if state.network_running {
+                actions.push(Action::Send(rpc::Command::Handle(state.handle.clone())));
+            }
+            actions
+        }
+        Some("/past") => vec![Action::TogglePast],
+        Some("/compact") => {
+            let on = match parts.get(1).copied() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return vec![Action::Status("使い方: /compact <on|off>".into())],
+            };
+            vec![
+                Action::SetCompact(on),
+                Action::SaveConfig(config::keys::COMPACT, toml::Value::Boolean(on)),
+                Action::Status(if on {
+                    "同じ人の連続した投稿をまとめて表示します".into()
+                } else {
+                    "投稿ごとにハンドルを表示します".into()
+                }),
+            ]
+        }
+        Some("/relay") => {
+            let on = match parts.get(1).copied() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return vec![Action::Status("使い方: /relay <on|off>".into())],
+            };
+            state.relay = on;
+            let mut actions = vec![
+                Action::SaveConfig(config::keys::RELAY, toml::Value::Boolean(on)),
+                Action::Status(if on {
+                    "中継 ON".into()
+                } else {
+                    "中継 OFF (leaf): 受信と表示のみ行います".into()
+                }),
+            ];
+            if state.network_running {
+                actions.push(Action::Send(rpc::Command::Relay(on)));
+            }
+            actions
+        }
+        Some("/fanout") => {
+            let limit = match parts.get(1).copied() {
+                Some("off") => None,
+                Some(n) => match n.parse::<usize>() {
+                    Ok(n) if n > 0 => Some(n),
+                    _ => return vec![Action::Status("使い方: /fanout <n|off>".into())],
+                },
+                None => return vec![Action::Status("使い方: /fanout <n|off>".into())],
+            };
+            let saved = limit.map_or(0, |n| n as i64);
+            let mut actions = vec![
+                Action::SaveConfig(
+                    config::keys::RELAY_FANOUT_PER_TICK,
+                    toml::Value::Integer(saved),
+                ),
+                Action::Status(match limit {
+                    Some(n) => format!("中継の送信上限: {} 件/ティック", n),
+                    None => "中継の送信上限: 無制限".into(),
+                }),
+            ];
+            if state.network_running {
+                actions.push(Action::Send(rpc::Command::Fanout(limit)));
+            }
+            actions
+        }
+        Some("/theme") => {
+            let presets = theme::PRESETS.join(", ");
+            let Some(name) = parts.get(1) else {
+                return vec![Action::Status(format!("使い方: /theme <{}>", presets))];
+            };
+            match Theme::preset(name) {
+                Some(t) => vec![
+                    Action::SetTheme(t),
+                    Action::SaveConfig(
+                        config::keys::THEME_PRESET,
+                        toml::Value::String(name.to_string()),
+                    ),
+                    Action::Status(format!("テーマ: {}", name)),
+                ],
+                None => vec![Action::Status(format!(
+                    "不明なテーマ: {} (利用可能: {})",
+                    name, presets
+                ))],
+            }
+        }
+        Some("/color") => match (parts.get(1), parts.get(2), parts.len()) {
+            (Some(handle), Some(color), 3) if config::is_valid_peer_handle(handle) => {
+                if crossterm::style::Color::try_from(*color).is_ok() {
+                    vec![Action::SetHandleColor(
+                        handle.to_string(),
+                        color.to_string(),
+                    )]
+                } else {
+                    vec![Action::Status(format!(
+                        "不明な色: {} (例: cyan, dark_green, yellow)",
+                        color
+                    ))]
+                }
+            }
+            _ => vec![Action::Status("使い方: /color @handle <color>".into())],
+        },
+        Some("/legend") => vec![Action::ShowLegend],
+        Some("/audit") => match parts.get(1).map(|n| n.parse::<usize>()) {
+            None => vec![Action::ShowAudit(DEFAULT_AUDIT_COUNT)],
+            Some(Ok(n)) if n > 0 => vec![Action::ShowAudit(n)],
+            Some(_) => vec![Action::Status("使い方: /audit [count]".into())],
+        },
+        Some("/known") => vec![Action::ShowKnown],
+        Some("/reverify") => vec![Action::Reverify],
+        Some("/trust") => {
+            let Some(fp) = parts.get(1) else {
+                return vec![Action::ShowTrusted];
+            };
+            // /known・/peers に出る先頭 16 桁か、それより長い指紋
+            let fp = fp.to_ascii_lowercase();
+            if fp.len() < 16 || !fp.chars().all(|c| c.is_ascii_hexdigit()) {
+                return vec![Action::Status(
+                    "使い方: /trust <指紋 (16桁以上の16進数)>".into(),
+                )];
+            }
+            let fp = fp[..16].to_string();
+            let mut actions = vec![Action::Trust(fp.clone())];
+            if state.network_running {
+                actions.push(Action::Send(rpc::Command::Trust(fp)));
+            }
+            actions
+        }
+        Some("/history") => match (parts.get(1).copied(), parts.get(2).copied()) {
+            (Some("clear"), Some("yes")) => vec![Action::ClearHistory],
+            (Some("clear"), _) => vec![Action::Status(
+                "履歴を全て削除します。よろしければ /history clear yes".into(),
+            )],
+            _ => vec![Action::Status("使い方: /history clear yes".into())],
+        },
+        Some("/version") => {
+            let mut actions = vec![Action::Show(format!(
+                "p2witter {} protocol={} (受け付ける最古={})",
+                env!("CARGO_PKG_VERSION"),
+                protocol::PROTOCOL_VERSION,
+                protocol::MIN_PROTOCOL_VERSION
+            ))];
+            if state.network_running {
+                actions.push(Action::Send(rpc::Command::Version));
+            }
+            actions
+        }
+        Some("/clear") => vec![Action::ClearScreen],
+        Some("/exit") => vec![Action::Status("終了中...".into()), Action::Exit],
+        Some("/init") => {
+            let force = parts.get(1) == Some(&"force");
+            if let (Some(pk), false) = (state.public_key.as_ref(), force) {
+                return vec![Action::Status(format!(
+                    "鍵は既に存在します (指紋={})。上書きすると元の ID は失われます: /init force",
+                    &crypto::fingerprint_hex(pk)[..16]
+                ))];
+            }
+            match crypto::generate_ed25519_keypair() {
+                Ok(k) => {
+                    state.public_key = Some(k.public.clone());
+                    vec![
+                        Action::SaveConfig(
+                            config::keys::KEY_PKCS8,
+                            toml::Value::String(crypto::to_hex(&k.pkcs8)),
+                        ),
+                        Action::SaveConfig(
+                            config::keys::KEY_PUBLIC,
+                            toml::Value::String(crypto::to_hex(&k.public)),
+                        ),
+                        Action::Status(format!("鍵生成完了 public_len={}", k.public.len())),
+                    ]
+                }
+                Err(e) => vec![Action::Status(format!("鍵生成失敗: {e}"))],
+            }
+        }
+        Some("/rotatekey") => match crypto::generate_ed25519_keypair() {
+            Ok(k) => {
+                state.public_key = Some(k.public.clone());
+                let mut actions = vec![
+                    Action::SaveConfig(
+                        config::keys::KEY_PKCS8,
+                        toml::Value::String(crypto::to_hex(&k.pkcs8)),
+                    ),
+                    Action::SaveConfig(
+                        config::keys::KEY_PUBLIC,
+                        toml::Value::String(crypto::to_hex(&k.public)),
+                    ),
+                    Action::Status(format!("鍵を更新しました public_len={}", k.public.len())),
+                ];
+                // 接続中のピアへは旧鍵で署名した通知を送る
+                if state.network_running {
+                    actions.push(Action::Send(rpc::Command::RotateKey(k.pkcs8, k.public)));
+                }
+                actions
+            }
+            Err(e) => vec![Action::Status(format!("鍵生成失敗: {e}"))],
+        },
+        Some("/peers") => {
+            let mut query = PeerQuery::default();
+            for opt in &parts[1..] {
+                match opt.strip_prefix("sort=") {
+                    Some(key) => match PeerSort::parse(key) {
+                        Some(s) => query.sort = s,
+                        None => {
+                            return vec![Action::Status(format!(
+                                "不明な並び順 '{}' (sort=id|handle|rtt)",
+                                key
+                            ))];
+                        }
+                    },
+                    None => query.filter = Some(opt.to_string()),
+                }
+            }
+            state.peers = query;
+            network_only(state, rpc::Command::PeerList)
+        }
+        Some("/close") => match parts.get(1).map(|p| p.parse::<u16>()) {
+            None => network_only(state, rpc::Command::Close(None)),
+            Some(Ok(port)) => network_only(state, rpc::Command::Close(Some(port))),
+            Some(Err(_)) => vec![Action::Status("使い方: /close [port]".into())],
+        },
+        Some("/token" | "/export-token") => network_only(state, rpc::Command::Token),
+        Some("/config") => {
+            let Some(key) = parts.get(1) else {
+                return vec![Action::ShowConfig(None)];
+            };
+            let Some(spec) = config::find_config_key(key) else {
+                return vec![Action::Status(format!(
+                    "不明な設定: {} (/config で一覧)",
+                    key
+                ))];
+            };
+            if parts.len() == 2 {
+                return vec![Action::ShowConfig(Some(spec.key))];
+            }
+            // ハンドルは検証とネットワークへの通知が要るので専用のコマンドで
+            if spec.key == config::keys::USER_HANDLE {
+                return vec![Action::Status(
+                    "ハンドルは /handle @name で変更してください".into(),
+                )];
+            }
+            let raw = parts[2..].join(" ");
+            match spec.kind.parse(&raw) {
+                Some(value) => vec![
+                    Action::SaveConfig(spec.key, value),
+                    Action::Status(format!(
+                        "{} = {} を保存しました（多くは再起動後に反映）",
+                        spec.key, raw
+                    )),
+                ],
+                None => vec![Action::Status(format!(
+                    "{} の値として読めません: {}",
+                    spec.key, raw
+                ))],
+            }
+        }
+        Some("/whoami") => {
+            let Some(pk) = state.public_key.as_ref() else {
+                return vec![Action::Status("鍵未生成 (/init を先に実行)".into())];
+            };
+            let fp = crypto::fingerprint_hex(pk);
+            let mut actions = vec![
+                Action::Show(format!("ハンドル: {}", state.handle)),
+                Action::Show(format!("公開鍵: {}", crypto::to_hex(pk))),
+                Action::Show(format!("指紋: {} (短縮 {})", fp, &fp[..16])),
+            ];
+            // 待受の有無とトークンはネットワークスレッドが答える
+            if state.network_running {
+                actions.push(Action::Send(rpc::Command::Token));
+            } else {
+                actions.push(Action::Show("待受: なし (/open <port>)".into()));
+            }
+            actions
+        }
+        Some("/certs") => network_only(state, rpc::Command::Certs),
+        Some("/timers") => network_only(state, rpc::Command::Timers),
+        Some("/discover") => network_only(state, rpc::Command::Discover),
+        Some("/topology") => network_only(state, rpc::Command::Topology),
+        Some("/disconnect") => match parts.get(1) {
+            Some(id) => network_only(state, rpc::Command::Disconnect(id.to_string())),
+            None => pick_peer(state, PickFor::Disconnect),
+        },
+        Some("/whois") => match parts.get(1) {
+            Some(id) => network_only(state, rpc::Command::Whois(id.to_string())),
+            None => vec![Action::Status("使い方: /whois <id>".into())],
+        },
+        Some("/cert") => match parts.get(1) {
+            Some(id) => network_only(state, rpc::Command::Cert(id.to_string())),
+            None => vec![Action::Status("使い方: /cert <id>".into())],
+        },
+        Some(cmd @ ("/dm" | "/edm")) => {
+            if state.spectator {
+                return vec![Action::Status(SPECTATOR.into())];
+            }
+            if cmd == "/dm" && parts.len() == 1 {
+                return pick_peer(state, PickFor::Dm);
+            }
+            if parts.len() < 3 {
+                return vec![Action::Status(format!("使い方: {} <to_id> <message>", cmd))];
+            }
+            if !state.network_running {
+                return vec![Action::Status(NO_NETWORK.into())];
+            }
+            if !state.has_valid_handle() {
+                return vec![Action::Status("ハンドル未設定です。/handle @name".into())];
+            }
+            let value = parts[2..].join(" ");
+            let echo = format!("{}: {} ○", state.handle, value);
+            let ephemeral = cmd == "/edm";
+            // ローカルエコー（ユーザー投稿は保存。揮発 DM は表示だけ）
+            let show = if ephemeral {
+                Action::Show(format!("{}{}", echo, rpc::EPHEMERAL_MARK))
+            } else {
+                Action::ShowUser(echo)
+            };
+            let to = parts[1].to_string();
+            vec![show, Action::Send(rpc::Command::DM(to, value, ephemeral))]
+        }
+        Some("/reply") => {
+            if parts.len() < 3 {
+                return vec![Action::Status("使い方: /reply <id> <message>".into())];
+            }
+            let id = parts[1].trim_start_matches('#').to_string();
+            let mut actions = chat(state, parts[2..].join(" "));
+            if let Some(Action::Send(rpc::Command::Chat(_, reply_to, _))) = actions.last_mut() {
+                *reply_to = Some(id.clone());
+                actions.insert(0, Action::Quote(id));
+            }
+            actions
+        }
+        Some("/edit") => {
+            if state.spectator {
+                return vec![Action::Status(SPECTATOR.into())];
+            }
+            if parts.len() < 3 {
+                return vec![Action::Status("使い方: /edit <id> <message>".into())];
+            }
+            let id = parts[1].trim_start_matches('#').to_string();
+            network_only(state, rpc::Command::Edit(id, parts[2..].join(" ")))
+        }
+        Some("/delete") => {
+            if state.spectator {
+                return vec![Action::Status(SPECTATOR.into())];
+            }
+            match parts.get(1) {
+                Some(id) => network_only(
+                    state,
+                    rpc::Command::Delete(id.trim_start_matches('#').to_string()),
+                ),
+                None => vec![Action::Status("使い方: /delete <id>".into())],
+            }
+        }
+        Some("/topic") => {
+            if state.spectator {
+                return vec![Action::Status(SPECTATOR.into())];
+            }
+            if parts.len() < 2 {
+                return vec![Action::Status("使い方: /topic <text>".into())];
+            }
+            let text = parts[1..].join(" ");
+            if text.chars().count() > protocol::MAX_TOPIC_CHARS {
+                return vec![Action::Status(format!(
+                    "トピックは{}文字までです",
+                    protocol::MAX_TOPIC_CHARS
+                ))];
+            }
+            network_only(state, rpc::Command::Topic(text))
+        }
+        Some("/raw") => {
+            if !state.developer {
+                return vec![Action::Status(DEVELOPER_ONLY.into())];
+            }
+            let (target, hex) = match parts.as_slice() {
+                [_, hex] => (None, *hex),
+                [_, id, hex] => match id.parse::<usize>() {
+                    Ok(id) => (Some(id), *hex),
+                    Err(_) => return vec![Action::Status(format!("不正なピア id: {}", id))],
+                },
+                _ => return vec![Action::Status("使い方: /raw [id] <hex>".into())],
+            };
+            if hex.len() / 2 > MAX_RAW_BYTES {
+                return vec![Action::Status(format!(
+                    "/raw は {} バイトまでです",
+                    MAX_RAW_BYTES
+                ))];
+            }
+            match crypto::from_hex(hex) {
+                Ok(bytes) if !bytes.is_empty() => {
+                    network_only(state, rpc::Command::Raw(target, bytes))
+                }
+                _ => vec![Action::Status("16進のバイト列を指定してください".into())],
+            }
+        }
+        Some("/dump") => {
+            if !state.developer {
+                return vec![Action::Status(DEVELOPER_ONLY.into())];
+            }
+            match parts.get(1).and_then(|id| id.parse::<usize>().ok()) {
+                Some(id) => network_only(state, rpc::Command::Dump(id)),
+                None => vec![Action::Status("使い方: /dump <id>".into())],
+            }
+        }
+        Some("/bookmark") => match parts.get(1) {
+            Some(id) => vec![Action::ToggleBookmark(
+                id.trim_start_matches('#').to_string(),
+            )],
+            None => vec![Action::Status("使い方: /bookmark <id>".into())],
+        },
+        Some("/bookmarks") => vec![Action::ShowBookmarks],
+        Some("/bottom") => vec![Action::ScrollToBottom],
+        Some("/selftest") => vec![Action::SelfTest],
+        Some("/dnd") => match parts.get(1).copied() {
+            Some("on") => vec![Action::SetDnd(true)],
+            Some("off") => vec![Action::SetDnd(false)],
+            _ => vec![Action::Status("使い方: /dnd <on|off>".into())],
+        },
+        Some("/focus") => match parts.get(1).copied() {
+            Some("on") => vec![Action::SetFocus(true)],
+            Some("off") => vec![Action::SetFocus(false)],
+            _ => vec![Action::Status("使い方: /focus <on|off>".into())],
+        },
+        Some("/show") => match parts.get(1).map(|n| n.parse::<usize>()) {
+            None => vec![Action::ShowFull(None)],
+            Some(Ok(n)) if n > 0 => vec![Action::ShowFull(Some(n))],
+            Some(_) => vec![Action::Status("使い方: /show [line]".into())],
+        },
+        Some("/dms") => match parts.get(1) {
+            Some(h) if h.starts_with('@') => vec![Action::ShowDmThread(h.to_string())],
+            _ => vec![Action::Status("使い方: /dms @handle".into())],
+        },
+        Some("/find") => {
+            let query = line["/find".len()..].trim();
+            vec![Action::Find((!query.is_empty()).then(|| query.to_string()))]
+        }
+        Some(cmd @ ("/backup" | "/restore")) => {
+            let path = line[cmd.len()..].trim();
+            if path.is_empty() {
+                return vec![Action::Status(format!("使い方: {} <path>", cmd))];
+            }
+            if cmd == "/backup" {
+                vec![Action::Backup(path.to_string())]
+            } else {
+                vec![Action::Restore(path.to_string())]
+            }
+        }
+        Some("/draft") => match parts.get(1..).unwrap_or_default() {
+            ["save", name, text @ ..] if !text.is_empty() => {
+                vec![Action::SaveDraft(name.to_string(), text.join(" "))]
+            }
+            ["load", name] => vec![Action::LoadDraft(name.to_string())],
+            ["list"] => vec![Action::ListDrafts],
+            _ => vec![Action::Status(
+                "使い方: /draft save <name> <text> | /draft load <name> | /draft list".into(),
+            )],
+        },
+        Some("/msg") => {
+            if parts.len() < 2 {
+                return vec![Action::Status("使い方: /msg <message>".into())];
+            }
+            chat(state, parts[1..].join(" "))
+        }
+        Some("/urgent") => {
+            if parts.len() < 2 {
+                return vec![Action::Status("使い方: /urgent <message>".into())];
+            }
+            let mut actions = chat(state, parts[1..].join(" "));
+            for a in actions.iter_mut() {
+                match a {
+                    Action::ShowUser(echo) => echo.push_str(rpc::URGENT_MARK),
+                    Action::Send(rpc::Command::Chat(_, _, urgent))
+                    | Action::SpawnAndSend(rpc::Command::Chat(_, _, urgent)) => *urgent = true,
+                    _ => {}
+                }
+            }
+            actions
+        }
+        Some(other) if other.starts_with('/') => {
+            let hint = if find_command(other).is_some() {
+                ""
+            } else {
+                " (/help で一覧)"
+            };
+            vec![Action::Status(format!("不明なコマンド: {}{}", other, hint))]
+        }
+        Some(_) => chat(state, line.to_string()),
+        None => Vec::new(),
+    }
+}
+
+// トークンの中身（接続先の候補）を表示する。復号できなければ理由を出す
+fn inspect_token(token: &str) -> Action {
+    let target = match crypto::decrypt_conninfo_from_hex(token) {
+        Ok(target) => target,
+        Err(e @ crypto::CryptoError::Hex) => {
+            return Action::Status(format!(
+                "トークンを読めません: {} (途中で切れていないか確認してください)",
+                e
+            ));
+        }
+        Err(e) => {
+            return Action::Status(format!(
+                "トークンを読めません: {} (別のアプリのトークンか、壊れています)",
+                e
+            ));
+        }
+    };
+    let addrs: Vec<&str> = target
+        .split(',')
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+        .collect();
+    let mut out = format!("トークンの接続先 {} 件 (接続はしていません)", addrs.len());
+    for (i, addr) in addrs.iter().enumerate() {
+        let note = if addr.parse::<std::net::SocketAddr>().is_ok() {
+            ""
+        } else {
+            " (名前解決が必要)"
+        };
+        out.push_str(&format!("\n  {}. {}{}", i + 1, addr, note));
+    }
+    Action::Show(out)
+}
+
+// ネットワークスレッドが必要なだけのコマンド
+fn network_only(state: &AppState, cmd: rpc::Command) -> Vec<Action> {
+    if state.network_running {
+        vec![Action::Send(cmd)]
+    } else {
+        vec![Action::Status(NO_NETWORK.into())]
+    }
+}
+
+// 接続中のピアの一覧を取り寄せ、選択を開く
+fn pick_peer(state: &AppState, purpose: PickFor) -> Vec<Action> {
+    if state.network_running {
+        vec![
+            Action::PickPeer(purpose),
+            Action::Send(rpc::Command::PeerList),
+        ]
+    } else {
+        vec![Action::Status(NO_NETWORK.into())]
+    }
+}
+
+// 初回起動の入力をハンドルとして保存し、鍵が無ければ生成する
+fn first_run_setup(line: &str, state: &mut AppState) -> Vec<Action> {
+    let line = line.trim();
+    let name = if line.starts_with('@') {
+        line.to_string()
+    } else {
+        format!("@{}", line)
+    };
+    if !config::is_valid_handle(&name) {
+        return vec![Action::Status(format!(
+            "はじめに: ハンドルを入力してください（{}）",
+            config::handle_rule()
+        ))];
+    }
+    let mut actions = handle_command(&format!("/handle {}", name), state);
+    if state.public_key.is_none() {
+        actions.extend(handle_command("/init", state));
+    }
+    state.first_run = false;
+    if let Some(pk) = &state.public_key {
+        actions.push(Action::Show(format!(
+            "初期設定が完了しました: {} (指紋={})",
+            state.handle,
+            &crypto::fingerprint_hex(pk)[..16]
+        )));
+    }
+    actions.push(Action::Status(
+        "準備完了。/open <port> または /connect <token> で始めましょう。/help でコマンド一覧"
+            .into(),
+    ));
+    actions
+}
+
+// 全体チャット。ネットワークなしならスレッドを起動し、ピアが接続するまで送信待ちにする
+fn chat(state: &AppState, value: String) -> Vec<Action> {
+    if state.spectator {
+        return vec![Action::Status(SPECTATOR.into())];
+    }
+    if !state.has_valid_handle() {
+        return vec![Action::Status("ハンドル未設定です。/handle @name".into())];
+    }
+    let echo = Action::ShowUser(format!("{}: {} ○", state.handle, value));
+    let cmd = rpc::Command::Chat(value, None, false);
+    if !state.network_running {
+        return vec![
+            echo,
+            Action::SpawnAndSend(cmd),
+            Action::Status(QUEUED.into()),
+        ];
+    }
+    vec![echo, Action::Send(cmd)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(handle: &str, network_running: bool) -> AppState {
+        AppState {
+            handle: handle.to_string(),
+            network_running,
+            peers: PeerQuery::default(),
+            public_key: None,
+            spectator: false,
+            relay: true,
+            developer: false,
+            first_run: false,
+        }
+    }
+
+    fn status_of(actions: &[Action]) -> Option<&str> {
+        actions.iter().find_map(|a| match a {
+            Action::Status(s) => Some(s.as_str()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn empty_line_does_nothing() {
+        assert!(handle_command("   ", &mut state("@alice", true)).is_empty());
+    }
+
+    #[test]
+    fn open_requires_port_and_handle() {
+        let actions = handle_command("/open", &mut state("@alice", false));
+        assert_eq!(status_of(&actions), Some("使い方: /open <port>"));
+
+        let actions = handle_command("/open 2234", &mut state("", false));
+        assert!(status_of(&actions).unwrap().starts_with("ハンドル未設定"));
+
+        let actions = handle_command("/open 2234", &mut state("@alice", false));
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::SpawnAndSend(rpc::Command::Open(p, None))] if p == "2234"
+        ));
+        let actions = handle_command("/open 2234 save=token.txt", &mut state("@alice", false));
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::SpawnAndSend(rpc::Command::Open(_, Some(f)))] if f == "token.txt"
+        ));
+        let actions = handle_command("/open 2234 token.txt", &mut state("@alice", false));
+        assert_eq!(
+            status_of(&actions),
+            Some("使い方: /open <port> [save=<path>]")
+        );
+    }
+
+    #[test]
+    fn connect_tokenizes_plain_address() {
+        let actions = handle_command("/connect 127.0.0.1:2234", &mut state("@alice", false));
+        let [Action::SpawnAndSend(rpc::Command::Connect(token))] = actions.as_slice() else {
+            panic!("unexpected actions: {:?}", actions);
+        };
+        assert_eq!(
+            crypto::decrypt_conninfo_from_hex(token).unwrap(),
+            "127.0.0.1:2234"
+        );
+    }
+
+    #[test]
+    fn inspect_shows_token_addresses_without_connecting() {
+        let mut st = state("", false);
+        let token = crypto::encrypt_conninfo_to_hex("127.0.0.1:2234, example.org:2234").unwrap();
+        let actions = handle_command(&format!("/inspect {}", token), &mut st);
+        let [Action::Show(text)] = actions.as_slice() else {
+            panic!("unexpected actions: {:?}", actions);
+        };
+        assert_eq!(
+            text,
+            "トークンの接続先 2 件 (接続はしていません)\n  1. 127.0.0.1:2234\n  2. example.org:2234 (名前解決が必要)"
+        );
+
+        // 16進数でないもの・このアプリの鍵で復号できないもの
+        for (bad, reason) in [
+            ("xyz", "16進数として読めません"),
+            (&"00".repeat(40)[..], "復号に失敗"),
+        ] {
+            let actions = handle_command(&format!("/inspect {}", bad), &mut st);
+            let [Action::Status(msg)] = actions.as_slice() else {
+                panic!("unexpected actions: {:?}", actions);
+            };
+            assert!(msg.contains(reason), "{}", msg);
+        }
+    }
+
+    #[test]
+    fn handle_updates_state_and_notifies_network() {
+        let mut st = state("", true);
+        let actions = handle_command("/handle @bob", &mut st);
+        assert_eq!(st.handle, "@bob");
+        assert!(matches!(
+            actions.as_slice(),
+            [
+                Action::SaveConfig("user.handle", _),
+                Action::Status(_),
+                Action::Send(rpc::Command::Handle(h))
+            ] if h == "@bob"
+        ));
+    }
+
+    #[test]
+    fn invalid_handle_is_rejected() {
+        let mut st = state("@alice", false);
+        let actions = handle_command("/handle bob", &mut st);
+        assert_eq!(st.handle, "@alice");
+        assert!(status_of(&actions).unwrap().starts_with("使い方"));
+
+        let long = format!("/handle @{}", "x".repeat(80));
+        handle_command(&long, &mut st);
+        assert_eq!(st.handle, "@alice");
+
+        // 制御文字やゼロ幅スペース入りは画面を崩すので拒否
+        for bad in ["/handle @bo\x07b", "/handle @bob\u{200b}\u{200b}"] {
+            let actions = handle_command(bad, &mut st);
+            assert_eq!(st.handle, "@alice");
+            assert!(status_of(&actions).unwrap().contains("制御文字"));
+        }
+    }
+
+    #[test]
+    fn dm_checks_args_network_and_handle() {
+        let actions = handle_command("/dm 0", &mut state("@alice", true));
+        assert_eq!(status_of(&actions), Some("使い方: /dm <to_id> <message>"));
+
+        let actions = handle_command("/dm 0 hi", &mut state("@alice", false));
+        assert_eq!(status_of(&actions), Some(NO_NETWORK));
+
+        let actions = handle_command("/dm 0 hi", &mut state("", true));
+        assert!(status_of(&actions).unwrap().starts_with("ハンドル未設定"));
+
+        let actions = handle_command("/dm 0 hello there", &mut state("@alice", true));
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::ShowUser(_), Action::Send(rpc::Command::DM(to, body, false))]
+                if to == "0" && body == "hello there"
+        ));
+
+        // 揮発 DM のローカルエコーは保存しない Show
+        let actions = handle_command("/edm 0 secret", &mut state("@alice", true));
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::Show(line), Action::Send(rpc::Command::DM(_, _, true))]
+                if line.ends_with(rpc::EPHEMERAL_MARK)
+        ));
+    }
+
+    #[test]
+    fn plain_text_is_sent_as_chat() {
+        let actions = handle_command("hello world", &mut state("@alice", true));
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::ShowUser(echo), Action::Send(rpc::Command::Chat(body, None, false))]
+                if echo == "@alice: hello world ○" && body == "hello world"
+        ));
+    }
+
+    #[test]
+    fn chat_without_network_is_queued() {
+        let actions = handle_command("/msg hi", &mut state("@alice", false));
+        assert!(matches!(
+            actions.as_slice(),
+            [
+                Action::ShowUser(_),
+                Action::SpawnAndSend(rpc::Command::Chat(body, None, false)),
+                Action::Status(_),
+            ] if body == "hi"
+        ));
+        assert_eq!(status_of(&actions), Some(QUEUED));
+    }
+
+    #[test]
+    fn urgent_marks_echo_and_sets_the_flag() {
+        let actions = handle_command("/urgent 障害発生", &mut state("@alice", true));
+        assert!(matches!(
+            actions.as_slice(),
+            [
+                Action::ShowUser(echo),
+                Action::Send(rpc::Command::Chat(body, None, true)),
+            ] if body == "障害発生" && echo.ends_with(rpc::URGENT_MARK)
+        ));
+    }
+
+    #[test]
+    fn dm_and_disconnect_without_target_open_the_picker() {
+        for (cmd, purpose) in [("/dm", PickFor::Dm), ("/disconnect", PickFor::Disconnect)] {
+            let actions = handle_command(cmd, &mut state("@alice", true));
+            assert!(
+                matches!(
+                    actions.as_slice(),
+                    [Action::PickPeer(p), Action::Send(rpc::Command::PeerList)] if *p == purpose
+                ),
+                "{}",
+                cmd
+            );
+            let actions = handle_command(cmd, &mut state("@alice", false));
+            assert_eq!(status_of(&actions), Some(NO_NETWORK), "{}", cmd);
+        }
+    }
+
+    #[test]
+    fn network_commands_need_network_thread() {
+        for cmd in [
+            "/peers",
+            "/close",
+            "/close 2234",
+            "/token",
+            "/certs",
+            "/discover",
+            "/topology",
+            "/whois 0",
+            "/cert 0",
+            "/disconnect 0",
+        ] {
+            let actions = handle_command(cmd, &mut state("@alice", false));
+            assert_eq!(status_of(&actions), Some(NO_NETWORK), "{}", cmd);
+            let actions = handle_command(cmd, &mut state("@alice", true));
+            assert!(matches!(actions.as_slice(), [Action::Send(_)]), "{}", cmd);
+        }
+    }
+
+    #[test]
+    fn cert_shows_the_key_instead_of_disconnecting() {
+        let actions = handle_command("/cert 3", &mut state("@alice", true));
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::Send(rpc::Command::Cert(id))] if id == "3"
+        ));
+        let actions = handle_command("/cert", &mut state("@alice", true));
+        assert_eq!(status_of(&actions), Some("使い方: /cert <id>"));
+    }
+
+    #[test]
+    fn peers_accepts_sort_option() {
+        let mut st = state("@alice", true);
+        let actions = handle_command("/peers sort=rtt @Al", &mut st);
+        assert_eq!(st.peers.sort, PeerSort::Rtt);
+        assert_eq!(st.peers.filter.as_deref(), Some("@Al"));
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::Send(rpc::Command::PeerList)]
+        ));
+
+        let actions = handle_command("/peers sort=latency", &mut st);
+        assert!(status_of(&actions).unwrap().contains("id|handle|rtt"));
+        // 省略時は id 順・絞り込みなし
+        handle_command("/peers", &mut st);
+        assert_eq!(st.peers, PeerQuery::default());
+    }
+
+    #[test]
+    fn config_lists_details_and_saves_typed_values() {
+        let mut st = state("@alice", false);
+        assert!(matches!(
+            handle_command("/config", &mut st)[..],
+            [Action::ShowConfig(None)]
+        ));
+        assert!(matches!(
+            handle_command("/config relay", &mut st)[..],
+            [Action::ShowConfig(Some("relay"))]
+        ));
+        let actions = handle_command("/config relay off", &mut st);
+        assert!(matches!(
+            &actions[0],
+            Action::SaveConfig("relay", toml::Value::Boolean(false))
+        ));
+        let actions = handle_command("/config welcome_message ようこそ 皆さん", &mut st);
+        assert!(matches!(
+            &actions[0],
+            Action::SaveConfig("welcome_message", toml::Value::String(s)) if s == "ようこそ 皆さん"
+        ));
+        let actions = handle_command("/config scrollback_max many", &mut st);
+        assert!(status_of(&actions).unwrap().contains("読めません"));
+        let actions = handle_command("/config nope", &mut st);
+        assert!(status_of(&actions).unwrap().starts_with("不明な設定"));
+        let actions = handle_command("/config user.handle @bob", &mut st);
+        assert!(!actions.iter().any(|a| matches!(a, Action::SaveConfig(..))));
+    }
+
+    #[test]
+    fn whoami_shows_own_key_and_fingerprint() {
+        let mut st = state("@alice", false);
+        let actions = handle_command("/whoami", &mut st);
+        assert!(status_of(&actions).unwrap().contains("/init"));
+
+        let k = crypto::generate_ed25519_keypair().unwrap();
+        st.public_key = Some(k.public.clone());
+        let text = handle_command("/whoami", &mut st)
+            .into_iter()
+            .filter_map(|a| match a {
+                Action::Show(s) => Some(s),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert!(text.contains("@alice"));
+        assert!(text.contains(&crypto::to_hex(&k.public)));
+        assert!(text.contains(&crypto::fingerprint_hex(&k.public)));
+        assert!(text.contains("待受: なし"));
+
+        st.network_running = true;
+        let actions = handle_command("/whoami", &mut st);
+        assert!(matches!(
+            actions.last(),
+            Some(Action::Send(rpc::Command::Token))
+        ));
+    }
+
+    #[test]
+    fn init_refuses_to_overwrite_existing_key() {
+        let mut st = state("@alice", false);
+        st.public_key = Some(vec![1u8; 32]);
+        let actions = handle_command("/init", &mut st);
+        assert!(
+            status_of(&actions)
+                .unwrap()
+                .starts_with("鍵は既に存在します")
+        );
+        assert!(!actions.iter().any(|a| matches!(a, Action::SaveConfig(..))));
+        assert_eq!(st.public_key, Some(vec![1u8; 32]));
+    }
+
+    #[test]
+    fn init_force_overwrites_key() {
+        let mut st = state("@alice", false);
+        st.public_key = Some(vec![1u8; 32]);
+        let actions = handle_command("/init force", &mut st);
+        assert!(matches!(
+            actions.as_slice(),
+            [
+                Action::SaveConfig("key.pkcs8", _),
+                Action::SaveConfig("key.public", _),
+                Action::Status(_)
+            ]
+        ));
+        assert_ne!(st.public_key, Some(vec![1u8; 32]));
+
+        let mut fresh = state("@alice", false);
+        handle_command("/init", &mut fresh);
+        assert!(fresh.public_key.is_some());
+    }
+
+    #[test]
+    fn spectator_cannot_originate_messages() {
+        let mut st = state("@alice", true);
+        st.spectator = true;
+        for line in ["/msg hi", "/dm 0 hi", "hello"] {
+            let actions = handle_command(line, &mut st);
+            assert_eq!(status_of(&actions), Some(SPECTATOR), "{}", line);
+            assert!(
+                !actions
+                    .iter()
+                    .any(|a| matches!(a, Action::Send(_) | Action::ShowUser(_)))
+            );
+        }
+        // 受信系のコマンドは使える
+        let actions = handle_command("/peers", &mut st);
+        assert!(matches!(actions.as_slice(), [Action::Send(_)]));
+    }
+
+    #[test]
+    fn reply_quotes_target_and_sends_reply_to() {
+        let mut st = state("@bob", true);
+        let actions = handle_command("/reply #0a1b2c3d4e5f6071 そうだね", &mut st);
+        assert!(matches!(
+            actions.as_slice(),
+            [
+                Action::Quote(q),
+                Action::ShowUser(_),
+                Action::Send(rpc::Command::Chat(body, Some(to), false)),
+            ] if q == "0a1b2c3d4e5f6071" && to == q && body == "そうだね"
+        ));
+
+        // ネットワーク無しでは通常のチャットと同じくローカルエコーのみ
+        let mut st = state("@bob", false);
+        let actions = handle_command("/reply 0a1b2c3d4e5f6071 hi", &mut st);
+        assert!(!actions.iter().any(|a| matches!(a, Action::Quote(_))));
+    }
+
+    #[test]
+    fn edit_accepts_displayed_id_prefix() {
+        let mut st = state("@alice", true);
+        let actions = handle_command("/edit #0a1b2c3d4e5f6071 fixed text", &mut st);
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::Send(rpc::Command::Edit(id, body))]
+                if id == "0a1b2c3d4e5f6071" && body == "fixed text"
+        ));
+        st.spectator = true;
+        let actions = handle_command("/delete 0a1b2c3d4e5f6071", &mut st);
+        assert!(matches!(actions.as_slice(), [Action::Status(_)]));
+    }
+
+    #[test]
+    fn relay_off_updates_state_and_network() {
+        let mut st = state("@alice", true);
+        let actions = handle_command("/relay off", &mut st);
+        assert!(!st.relay);
+        assert!(
+            actions
+                .iter()
+                .any(|a| matches!(a, Action::Send(rpc::Command::Relay(false))))
+        );
+        assert!(matches!(
+            handle_command("/relay maybe", &mut st).as_slice(),
+            [Action::Status(_)]
+        ));
+    }
+
+    #[test]
+    fn fanout_is_saved_and_sent_to_network() {
+        let mut st = state("@alice", true);
+        let actions = handle_command("/fanout 3", &mut st);
+        assert!(actions.iter().any(|a| matches!(
+            a,
+            Action::SaveConfig("relay_fanout_per_tick", toml::Value::Integer(3))
+        )));
+        assert!(
+            actions
+                .iter()
+                .any(|a| matches!(a, Action::Send(rpc::Command::Fanout(Some(3)))))
+        );
+        let actions = handle_command("/fanout off", &mut st);
+        assert!(
+            actions
+                .iter()
+                .any(|a| matches!(a, Action::Send(rpc::Command::Fanout(None))))
+        );
+        assert!(matches!(
+            handle_command("/fanout 0", &mut st).as_slice(),
+            [Action::Status(_)]
+        ));
+    }
+
+    #[test]
+    fn unknown_theme_lists_presets() {
+        let mut st = state("@alice", false);
+        let actions = handle_command("/theme neon", &mut st);
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::Status(m)] if m.contains("neon") && theme::PRESETS.iter().all(|p| m.contains(p))
+        ));
+        let actions = handle_command("/theme dark", &mut st);
+        assert!(matches!(actions.first(), Some(Action::SetTheme(_))));
+    }
+
+    #[test]
+    fn audit_count_defaults_and_validates() {
+        let mut st = state("@alice", false);
+        assert!(matches!(
+            handle_command("/audit", &mut st).as_slice(),
+            [Action::ShowAudit(DEFAULT_AUDIT_COUNT)]
+        ));
+        assert!(matches!(
+            handle_command("/audit 5", &mut st).as_slice(),
+            [Action::ShowAudit(5)]
+        ));
+        assert!(matches!(
+            handle_command("/audit x", &mut st).as_slice(),
+            [Action::Status(_)]
+        ));
+        // /known はネットワークスレッドが無くても保存済みの一覧を出す
+        assert!(matches!(
+            handle_command("/known", &mut st).as_slice(),
+            [Action::ShowKnown]
+        ));
+        assert!(matches!(
+            handle_command("/reverify", &mut st).as_slice(),
+            [Action::Reverify]
+        ));
+    }
+
+    #[test]
+    fn history_clear_requires_confirmation() {
+        let mut st = state("@alice", false);
+        let actions = handle_command("/history clear", &mut st);
+        assert!(!actions.iter().any(|a| matches!(a, Action::ClearHistory)));
+        let actions = handle_command("/history clear yes", &mut st);
+        assert!(matches!(actions.as_slice(), [Action::ClearHistory]));
+    }
+
+    #[test]
+    fn unknown_command_reports_hint() {
+        let actions = handle_command("/nope", &mut state("@alice", true));
+        assert_eq!(
+            status_of(&actions),
+            Some("不明なコマンド: /nope (/help で一覧)")
+        );
+    }
+
+    #[test]
+    fn exit_and_past_are_forwarded_to_loop() {
+        let actions = handle_command("/exit", &mut state("@alice", false));
+        assert!(matches!(actions.last(), Some(Action::Exit)));
+        let actions = handle_command("/past", &mut state("@alice", false));
+        assert!(matches!(actions.as_slice(), [Action::TogglePast]));
+    }
+
+    #[test]
+    fn topic_is_sent_unless_too_long() {
+        let actions = handle_command("/topic 今日の 話題", &mut state("@alice", true));
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::Send(rpc::Command::Topic(t))] if t == "今日の 話題"
+        ));
+
+        let long = format!("/topic {}", "x".repeat(protocol::MAX_TOPIC_CHARS + 1));
+        let actions = handle_command(&long, &mut state("@alice", true));
+        assert!(status_of(&actions).unwrap().starts_with("トピックは"));
+        assert!(!actions.iter().any(|a| matches!(a, Action::Send(_))));
+    }
+
+    #[test]
+    fn raw_is_gated_and_size_limited() {
+        let mut st = state("@alice", true);
+        let actions = handle_command("/raw 0103", &mut st);
+        assert_eq!(status_of(&actions), Some(DEVELOPER_ONLY));
+
+        st.developer = true;
+        assert!(matches!(
+            handle_command("/raw 2 0103", &mut st).as_slice(),
+            [Action::Send(rpc::Command::Raw(Some(2), b))] if b == &[1, 3]
+        ));
+        assert!(matches!(
+            handle_command("/raw zz", &mut st).as_slice(),
+            [Action::Status(_)]
+        ));
+        let huge = format!("/raw {}", "00".repeat(MAX_RAW_BYTES + 1));
+        assert!(
+            status_of(&handle_command(&huge, &mut st))
+                .unwrap()
+                .contains("バイトまで")
+        );
+    }
+
+    #[test]
+    fn draft_subcommands_are_parsed() {
+        let mut st = state("@alice", false);
+        assert!(matches!(
+            handle_command("/draft save long 書きかけ の本文", &mut st).as_slice(),
+            [Action::SaveDraft(n, t)] if n == "long" && t == "書きかけ の本文"
+        ));
+        assert!(matches!(
+            handle_command("/draft load long", &mut st).as_slice(),
+            [Action::LoadDraft(n)] if n == "long"
+        ));
+        assert!(matches!(
+            handle_command("/draft list", &mut st).as_slice(),
+            [Action::ListDrafts]
+        ));
+        assert!(status_of(&handle_command("/draft save long", &mut st)).is_some());
+    }
+
+    #[test]
+    fn backup_and_restore_take_the_rest_of_the_line_as_path() {
+        let mut st = state("@alice", false);
+        assert!(matches!(
+            handle_command("/backup /tmp/my backup.p2wb", &mut st).as_slice(),
+            [Action::Backup(p)] if p == "/tmp/my backup.p2wb"
+        ));
+        assert!(matches!(
+            handle_command("/restore  old.p2wb ", &mut st).as_slice(),
+            [Action::Restore(p)] if p == "old.p2wb"
+        ));
+        assert_eq!(
+            status_of(&handle_command("/restore", &mut st)),
+            Some("使い方: /restore <path>")
+        );
+    }
+
+    #[test]
+    fn find_takes_the_rest_of_the_line_or_repeats() {
+        let mut st = state("@alice", false);
+        assert!(matches!(
+            handle_command("/find  また 明日 ", &mut st).as_slice(),
+            [Action::Find(Some(q))] if q == "また 明日"
+        ));
+        assert!(matches!(
+            handle_command("/find", &mut st).as_slice(),
+            [Action::Find(None)]
+        ));
+    }
+
+    #[test]
+    fn color_needs_a_handle_and_a_known_color() {
+        let mut st = state("@alice", false);
+        assert!(matches!(
+            handle_command("/color @bob cyan", &mut st).as_slice(),
+            [Action::SetHandleColor(h, c)] if h == "@bob" && c == "cyan"
+        ));
+        assert_eq!(
+            status_of(&handle_command("/color @bob nope", &mut st)),
+            Some("不明な色: nope (例: cyan, dark_green, yellow)")
+        );
+        assert_eq!(
+            status_of(&handle_command("/color bob cyan", &mut st)),
+            Some("使い方: /color @handle <color>")
+        );
+    }
+
+    #[test]
+    fn compact_toggles_display_and_is_saved() {
+        let mut st = state("@alice", false);
+        assert!(matches!(
+            handle_command("/compact on", &mut st).as_slice(),
+            [
+                Action::SetCompact(true),
+                Action::SaveConfig("compact", toml::Value::Boolean(true)),
+                Action::Status(_)
+            ]
+        ));
+        assert!(matches!(
+            handle_command("/compact", &mut st).as_slice(),
+            [Action::Status(_)]
+        ));
+    }
+
+    #[test]
+    fn trust_takes_a_fingerprint_prefix() {
+        let mut st = state("@alice", false);
+        assert!(matches!(
+            handle_command("/trust", &mut st).as_slice(),
+            [Action::ShowTrusted]
+        ));
+        let actions = handle_command("/trust 0A1B2C3D4E5F60718293", &mut st);
+        assert!(matches!(actions.as_slice(), [Action::Trust(fp)] if fp == "0a1b2c3d4e5f6071"));
+        st.network_running = true;
+        let actions = handle_command("/trust 0a1b2c3d4e5f6071", &mut st);
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::Trust(_), Action::Send(rpc::Command::Trust(fp))] if fp == "0a1b2c3d4e5f6071"
+        ));
+        for bad in ["/trust 0a1b", "/trust zzzzzzzzzzzzzzzz"] {
+            assert!(matches!(
+                handle_command(bad, &mut st).as_slice(),
+                [Action::Status(_)]
+            ));
+        }
+    }
+
+    #[test]
+    fn first_run_input_becomes_handle_and_generates_key() {
+        let mut st = state("", false);
+        st.first_run = true;
+        let actions = handle_command("has space", &mut st);
+        assert!(status_of(&actions).unwrap().starts_with("はじめに"));
+        assert!(actions.iter().all(|a| !matches!(a, Action::SaveConfig(..))));
+
+        let actions = handle_command("alice", &mut st);
+        assert_eq!(st.handle, "@alice");
+        assert!(!st.first_run);
+        let saved: Vec<&str> = actions
+            .iter()
+            .filter_map(|a| match a {
+                Action::SaveConfig(path, _) => Some(*path),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(saved, ["user.handle", "key.pkcs8", "key.public"]);
+        assert!(st.public_key.is_some());
+        // 以降の入力は普通のチャット
+        let actions = handle_command("hello", &mut st);
+        assert!(matches!(actions[0], Action::ShowUser(_)));
+    }
+
+    #[test]
+    fn dms_requires_handle() {
+        let actions = handle_command("/dms", &mut state("@alice", false));
+        assert_eq!(status_of(&actions), Some("使い方: /dms @handle"));
+        let actions = handle_command("/dms @bob", &mut state("@alice", false));
+        assert!(matches!(actions.as_slice(), [Action::ShowDmThread(h)] if h == "@bob"));
+    }
+}
@@ -1,32 +1,84 @@
+use crate::error::Error;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{OnceLock, RwLock};
 use toml::{Table, Value};
 
 static CONFIG: OnceLock<RwLock<Table>> = OnceLock::new();
+/// init_config_path で読み込んだファイル（保存・再読み込み先）
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// 既定プロファイルの設定ファイル
+pub const DEFAULT_CONFIG_PATH: &str = "./config.toml";
+/// 既定プロファイルの DB
+pub const DEFAULT_DB_PATH: &str = "./p2witter.db";
+
+/// プロファイルごとの設定ファイルと DB のパス
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub config: PathBuf,
+    pub db: PathBuf,
+}
+
+impl Profile {
+    /// None か "default" なら従来どおりカレントの config.toml / p2witter.db、
+    /// それ以外は ./profiles/<name>/ 以下。名前に区切り文字や ".." は使えない
+    pub fn resolve(name: Option<&str>) -> Result<Self, String> {
+        let name = match name {
+            None | Some("default") => {
+                return Ok(Self {
+                    config: PathBuf::from(DEFAULT_CONFIG_PATH),
+                    db: PathBuf::from(DEFAULT_DB_PATH),
+                });
+            }
+            Some(n) => n,
+        };
+        if name.is_empty() || name == ".." || name == "." || name.contains(['/', '\\']) {
+            return Err(format!("不正なプロファイル名: '{}'", name));
+        }
+        let dir = Path::new("./profiles").join(name);
+        Ok(Self {
+            config: dir.join("config.toml"),
+            db: dir.join("p2witter.db"),
+        })
+    }
+}
 
 /// パスを指定して初期化。ファイルが存在しなければデフォルトを書き出してから読む。
 /// すでに初期化済みなら何もしない。
-pub fn init_config_path(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+pub fn init_config_path(path: impl AsRef<Path>) -> crate::Result<()> {
     if CONFIG.get().is_some() {
         return Ok(());
     }
-    let p = Path::new(path);
+    let table = load_or_create(path.as_ref())?;
+    let _ = CONFIG_PATH.set(path.as_ref().to_path_buf());
+    let _ = CONFIG.set(RwLock::new(table));
+    Ok(())
+}
+
+/// 設定ファイルを読む。無ければデフォルトを書き出す（親ディレクトリも作る）
+fn load_or_create(p: &Path) -> crate::Result<Table> {
     let content = if p.exists() {
         fs::read_to_string(p)?
     } else {
         let default = default_toml_string();
-        if let Some(parent) = p.parent() {
-            if !parent.as_os_str().is_empty() {
-                fs::create_dir_all(parent)?;
-            }
+        if let Some(parent) = p.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent)?;
         }
         fs::write(p, &default)?;
         default
     };
-    let table: Table = content.parse()?;
-    let _ = CONFIG.set(RwLock::new(table));
-    Ok(())
+    Ok(content.parse()?)
+}
+
+/// 保存・再読み込み先。未初期化なら既定の ./config.toml
+fn config_path() -> &'static Path {
+    CONFIG_PATH
+        .get()
+        .map(PathBuf::as_path)
+        .unwrap_or(Path::new(DEFAULT_CONFIG_PATH))
 }
 
 fn default_toml_string() -> String {
@@ -35,17 +87,109 @@ fn default_toml_string() -> String {
     let mut t = Table::new();
     t.insert("testconfig".into(), Value::String("kurowasa-nn".into()));
     // デフォルトではデバッグログを無効
-    t.insert("debug".into(), Value::Boolean(false));
+    t.insert(keys::DEBUG.into(), Value::Boolean(false));
+    // 起動時に自動で /open するか (listen_port と併用)
+    t.insert(keys::AUTO_OPEN.into(), Value::Boolean(false));
     t.to_string()
 }
 
 /// 設定ファイルの `debug` フラグを簡単に取得するヘルパ
 pub fn is_debug() -> bool {
-    get_value("debug")
+    get_value(keys::DEBUG)
         .and_then(|v| v.as_bool())
         .unwrap_or(false)
 }
 
+/// ハンドル文字数の上限（この文字数未満なら有効）の既定値。プロトコルの上限と同じで、
+/// 設定で狭めることはできても広げることはできない（ピアに受け取ってもらえなくなる）
+pub const DEFAULT_MAX_HANDLE_LEN: usize = crate::core::protocol::MAX_HANDLE_CHARS + 1;
+
+/// 設定の `user.max_handle_len`（未設定や不正値なら既定値）
+pub fn max_handle_len() -> usize {
+    try_config().map_or(DEFAULT_MAX_HANDLE_LEN, |tbl| max_handle_len_in(&tbl))
+}
+
+pub fn max_handle_len_in(tbl: &Table) -> usize {
+    get_value_in(tbl, keys::USER_MAX_HANDLE_LEN)
+        .and_then(|v| v.as_integer())
+        .and_then(|n| usize::try_from(n).ok())
+        .map_or(DEFAULT_MAX_HANDLE_LEN, |n| n.min(DEFAULT_MAX_HANDLE_LEN))
+}
+
+/// ハンドルの表示幅の上限（この幅以下なら有効）の既定値。文字数と同じく広げられない
+pub const DEFAULT_MAX_HANDLE_WIDTH: usize = crate::core::protocol::MAX_HANDLE_WIDTH;
+
+/// 設定の `user.max_handle_width`（未設定や不正値なら既定値）
+pub fn max_handle_width_in(tbl: &Table) -> usize {
+    get_value_in(tbl, keys::USER_MAX_HANDLE_WIDTH)
+        .and_then(|v| v.as_integer())
+        .and_then(|n| usize::try_from(n).ok())
+        .map_or(DEFAULT_MAX_HANDLE_WIDTH, |n| {
+            n.min(DEFAULT_MAX_HANDLE_WIDTH)
+        })
+}
+
+/// 入力されたハンドルの前後の空白を落とす
+pub fn normalize_handle(name: &str) -> String {
+    name.trim().to_string()
+}
+
+/// ピアから受け取ったハンドルとして有効か。文字の規則は自分のハンドルと同じで、
+/// 長さはこのノードの設定ではなくプロトコルの上限で見る
+pub fn is_valid_peer_handle(name: &str) -> bool {
+    use crate::core::protocol::{MAX_HANDLE_CHARS, MAX_HANDLE_WIDTH};
+    is_valid_handle_with(name, MAX_HANDLE_CHARS + 1, MAX_HANDLE_WIDTH)
+}
+
+/// 自分のハンドルとして有効か（@から始まり上限文字数未満・上限幅以下で、
+/// 空白・制御文字・幅ゼロの文字を含まない）
+pub fn is_valid_handle(name: &str) -> bool {
+    match try_config() {
+        Some(tbl) => is_valid_handle_in(&tbl, name),
+        None => is_valid_handle_with(name, DEFAULT_MAX_HANDLE_LEN, DEFAULT_MAX_HANDLE_WIDTH),
+    }
+}
+
+pub fn is_valid_handle_in(tbl: &Table, name: &str) -> bool {
+    is_valid_handle_with(name, max_handle_len_in(tbl), max_handle_width_in(tbl))
+}
+
+/// 結合文字も幅ゼロとして弾くので、分解された形 (NFD) の名前は受け付けない
+pub fn is_valid_handle_with(name: &str, max_len: usize, max_width: usize) -> bool {
+    use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+    // 制御文字は width() が None、ゼロ幅スペースや結合文字は Some(0)
+    let visible = |c: char| !c.is_whitespace() && c.width().is_some_and(|w| w > 0);
+    name.starts_with('@')
+        && name.chars().all(visible)
+        && name.chars().count() < max_len
+        && name.width() <= max_width
+}
+
+/// HELLO に付けるひとことが有効か（上限文字数以下で、半角スペース以外の空白・
+/// 制御文字・幅ゼロの文字を含まない。ハンドルと同じく結合文字も弾く）
+pub fn is_valid_bio(bio: &str) -> bool {
+    use unicode_width::UnicodeWidthChar;
+    let visible = |c: char| c == ' ' || (!c.is_whitespace() && c.width().is_some_and(|w| w > 0));
+    !bio.trim().is_empty()
+        && bio.chars().all(visible)
+        && bio.chars().count() <= crate::core::protocol::MAX_BIO_CHARS
+}
+
+/// 設定の `user.bio`（未設定や不正なら None。前後の空白は落とす）
+pub fn bio() -> Option<String> {
+    get_value(keys::USER_BIO)
+        .and_then(|v| v.as_str().map(|s| s.trim().to_string()))
+        .filter(|b| is_valid_bio(b))
+}
+
+/// 不正なハンドルを知らせる文言に添える規則
+pub fn handle_rule() -> String {
+    format!(
+        "@で開始し、空白・制御文字を含まない{}文字未満",
+        max_handle_len()
+    )
+}
+
 pub fn config() -> std::sync::RwLockReadGuard<'static, Table> {
     CONFIG
         .get()
@@ -54,8 +198,19 @@ pub fn config() -> std::sync::RwLockReadGuard<'static, Table> {
         .expect("config lock poisoned")
 }
 
+/// 初期化済みなら設定を返す。未初期化やロック破損なら None (panic しない)。
+pub fn try_config() -> Option<std::sync::RwLockReadGuard<'static, Table>> {
+    CONFIG.get()?.read().ok()
+}
+
+/// 値を取得。設定が未初期化なら None。
 pub fn get_value(path: &str) -> Option<Value> {
-    let tbl = config();
+    let tbl = try_config()?;
+    get_value_in(&tbl, path)
+}
+
+/// 任意のテーブルからドット区切りのパスで値を取得する。
+pub fn get_value_in(tbl: &Table, path: &str) -> Option<Value> {
     let mut cur: Option<&Value> = None;
     for (i, seg) in path.split('.').enumerate() {
         cur = if i == 0 {
@@ -63,67 +218,739 @@ pub fn get_value(path: &str) -> Option<Value> {
         } else {
             cur.and_then(|v| v.get(seg))
         };
-        if cur.is_none() {
-            return None;
-        }
+        cur?;
     }
     cur.cloned()
 }
 
-/// 任意のパスに値を挿入 (存在しなければ中間テーブルも作成) し、保存してからディスクから再読み込みする。
-pub fn upsert_value_and_save(path: &str, value: Value) -> Result<(), String> {
-    {
-        let lock = CONFIG.get().ok_or("config not initialized")?;
-        let mut root = lock.write().map_err(|_| "config lock poisoned")?;
-        let mut cur: &mut Table = &mut *root;
-        let mut segments: Vec<&str> = path.split('.').collect();
-        if segments.is_empty() {
-            return Err("empty path".into());
+/// 任意のパスに値を挿入 (存在しなければ中間テーブルも作成) して保存する。
+/// 書き換えから保存までを 1 つの書き込みロックの中で行うので、
+/// TUI とネットワークスレッドが同時に書いても互いの値を消さない
+pub fn upsert_value_and_save(path: &str, value: Value) -> crate::Result<()> {
+    let lock = CONFIG.get().ok_or(Error::ConfigNotInitialized)?;
+    let mut root = lock
+        .write()
+        .map_err(|_| Error::Invalid("config lock poisoned".into()))?;
+    let mut cur: &mut Table = &mut root;
+    let mut segments: Vec<&str> = path.split('.').collect();
+    if segments.is_empty() {
+        return Err(Error::Invalid("empty path".into()));
+    }
+    while segments.len() > 1 {
+        let seg = segments.remove(0);
+        let next = cur
+            .entry(seg.to_string())
+            .or_insert_with(|| Value::Table(Table::new()));
+        match next {
+            Value::Table(t) => {
+                cur = t;
+            }
+            _ => {
+                return Err(Error::Invalid(format!("segment '{}' is not a table", seg)));
+            }
         }
-        while segments.len() > 1 {
-            let seg = segments.remove(0);
-            let next = cur
-                .entry(seg.to_string())
-                .or_insert_with(|| Value::Table(Table::new()));
-            match next {
-                Value::Table(t) => {
-                    cur = t;
-                }
-                _ => {
-                    return Err(format!("segment '{}' is not a table", seg));
+    }
+    let last = segments.remove(0);
+    cur.insert(last.to_string(), value);
+    fs::write(config_path(), root.to_string())?;
+    Ok(())
+}
+
+/// 設定を現在の内容で保存。ファイルへの書き込みが重ならないよう書き込みロックを取る
+pub fn save() -> crate::Result<()> {
+    if let Some(lock) = CONFIG.get() {
+        let cfg = lock.write().expect("config lock poisoned");
+        fs::write(config_path(), cfg.to_string())?;
+    }
+    Ok(())
+}
+
+/// 設定の値の種類（/config で値を書き換えるときの読み方）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigKind {
+    Bool,
+    Int,
+    Str,
+}
+
+impl ConfigKind {
+    /// 入力された文字列をこの種類の値として読む
+    pub fn parse(self, s: &str) -> Option<Value> {
+        match self {
+            ConfigKind::Bool => match s {
+                "true" | "on" => Some(Value::Boolean(true)),
+                "false" | "off" => Some(Value::Boolean(false)),
+                _ => None,
+            },
+            ConfigKind::Int => s.parse().ok().map(Value::Integer),
+            ConfigKind::Str => Some(Value::String(s.to_string())),
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            ConfigKind::Bool => "true/false",
+            ConfigKind::Int => "整数",
+            ConfigKind::Str => "文字列",
+        }
+    }
+}
+
+/// 設定できるキーの説明（/config の一覧に出す）
+pub struct ConfigKey {
+    pub key: &'static str,
+    pub kind: ConfigKind,
+    /// 未設定のときの値（表示用）
+    pub default: &'static str,
+    pub description: &'static str,
+}
+
+/// 設定キーの名前。読む側も CONFIG_KEYS もここの定数を使う
+pub mod keys {
+    pub const USER_HANDLE: &str = "user.handle";
+    pub const USER_BIO: &str = "user.bio";
+    pub const USER_MAX_HANDLE_LEN: &str = "user.max_handle_len";
+    pub const USER_MAX_HANDLE_WIDTH: &str = "user.max_handle_width";
+    pub const LISTEN_PORT: &str = "listen_port";
+    pub const AUTO_OPEN: &str = "auto_open";
+    pub const DEBUG: &str = "debug";
+    pub const DEVELOPER: &str = "developer";
+    pub const SPECTATE: &str = "spectate";
+    pub const COMPACT: &str = "compact";
+    pub const MAX_DISPLAY_CHARS: &str = "max_display_chars";
+    pub const SCROLLBACK_MAX: &str = "scrollback_max";
+    pub const STATUS_FORMAT: &str = "status_format";
+    pub const PROMPT_FORMAT: &str = "prompt_format";
+    pub const THEME_PRESET: &str = "theme.preset";
+    pub const THEME_NO_COLOR: &str = "theme.no_color";
+    pub const THEME_STATUS_FG: &str = "theme.status_fg";
+    pub const THEME_STATUS_BG: &str = "theme.status_bg";
+    pub const THEME_OWN: &str = "theme.own";
+    pub const THEME_VALID: &str = "theme.valid";
+    pub const THEME_VERIFIED: &str = "theme.verified";
+    pub const THEME_INVALID: &str = "theme.invalid";
+    pub const THEME_HANDLE: &str = "theme.handle";
+    pub const THEME_SYSTEM: &str = "theme.system";
+    pub const THEME_ANNOUNCE: &str = "theme.announce";
+    pub const THEME_URGENT: &str = "theme.urgent";
+    pub const HONOR_URGENT: &str = "honor_urgent";
+    pub const URGENT_BELL_IN_DND: &str = "urgent_bell_in_dnd";
+    pub const DND_HOURS: &str = "dnd_hours";
+    pub const NO_HISTORY: &str = "no_history";
+    pub const STORAGE_BACKEND: &str = "storage_backend";
+    pub const DURABILITY: &str = "durability";
+    pub const CHAT_RETENTION_DAYS: &str = "chat_retention_days";
+    pub const DM_RETENTION_DAYS: &str = "dm_retention_days";
+    pub const RELAY: &str = "relay";
+    pub const RELAY_DMS: &str = "relay_dms";
+    pub const RELAY_FANOUT_PER_TICK: &str = "relay_fanout_per_tick";
+    pub const SUPPRESS_OWN_ECHO: &str = "suppress_own_echo";
+    pub const HISTORY_SYNC: &str = "history_sync";
+    pub const ADVERTISE: &str = "advertise";
+    pub const SHARE_TOPOLOGY: &str = "share_topology";
+    pub const PEER_LOOKUP: &str = "peer_lookup";
+    pub const DNS_SERVER: &str = "dns_server";
+    pub const ASN_DB: &str = "asn_db";
+    pub const PORT_MAPPING: &str = "port_mapping";
+    pub const NAT_GATEWAY: &str = "nat_gateway";
+    pub const METRICS: &str = "metrics";
+    pub const METRICS_ADDR: &str = "metrics_addr";
+    pub const CONNECT_TIMEOUT_SECS: &str = "connect_timeout_secs";
+    pub const CONNECT_PUZZLE_DIFFICULTY: &str = "connect_puzzle_difficulty";
+    pub const PING_INTERVAL_SECS: &str = "ping_interval_secs";
+    pub const IDLE_TIMEOUT_SECS: &str = "idle_timeout_secs";
+    pub const READ_BUFFER_BYTES: &str = "read_buffer_bytes";
+    pub const SEND_BUFFER_LIMIT_BYTES: &str = "send_buffer_limit_bytes";
+    pub const HANDLE_CHANGE_MIN_SECS: &str = "handle_change_min_secs";
+    pub const WELCOME_MESSAGE: &str = "welcome_message";
+    pub const RECORD_FRAMES: &str = "record_frames";
+
+    /// 鍵は /init と /keygen で書き換える。秘密鍵を /config に出さないよう一覧には載せない
+    pub const KEY_PKCS8: &str = "key.pkcs8";
+    pub const KEY_PUBLIC: &str = "key.public";
+}
+
+/// 読み取っている設定キーの一覧。新しいキーを読むときは keys にも足す
+pub const CONFIG_KEYS: &[ConfigKey] = &[
+    ConfigKey {
+        key: keys::USER_HANDLE,
+        kind: ConfigKind::Str,
+        default: "(未設定)",
+        description: "自分のハンドル (/handle で変更)",
+    },
+    ConfigKey {
+        key: keys::USER_BIO,
+        kind: ConfigKind::Str,
+        default: "(なし)",
+        description: "HELLO に付けるひとこと",
+    },
+    ConfigKey {
+        key: keys::USER_MAX_HANDLE_LEN,
+        kind: ConfigKind::Int,
+        default: "80",
+        description: "受け付けるハンドルの最大文字数",
+    },
+    ConfigKey {
+        key: keys::USER_MAX_HANDLE_WIDTH,
+        kind: ConfigKind::Int,
+        default: "80",
+        description: "受け付けるハンドルの最大表示幅",
+    },
+    ConfigKey {
+        key: keys::LISTEN_PORT,
+        kind: ConfigKind::Int,
+        default: "(なし)",
+        description: "auto_open で待ち受けるポート",
+    },
+    ConfigKey {
+        key: keys::AUTO_OPEN,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "起動時に listen_port で自動的に待ち受ける",
+    },
+    ConfigKey {
+        key: keys::DEBUG,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "デバッグ用のメッセージも表示する",
+    },
+    ConfigKey {
+        key: keys::DEVELOPER,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "開発者向けの表示を有効にする",
+    },
+    ConfigKey {
+        key: keys::SPECTATE,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "観戦モードで起動する（投稿しない）",
+    },
+    ConfigKey {
+        key: keys::COMPACT,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "同じ人の連続した投稿をまとめて表示する (/compact)",
+    },
+    ConfigKey {
+        key: keys::MAX_DISPLAY_CHARS,
+        kind: ConfigKind::Int,
+        default: "0",
+        description: "1 行に表示する最大文字数（0 なら切らない）",
+    },
+    ConfigKey {
+        key: keys::SCROLLBACK_MAX,
+        kind: ConfigKind::Int,
+        default: "10000",
+        description: "画面に残す行数の上限",
+    },
+    ConfigKey {
+        key: keys::STATUS_FORMAT,
+        kind: ConfigKind::Str,
+        default: "(既定の表示)",
+        description: "ステータスバーの書式",
+    },
+    ConfigKey {
+        key: keys::PROMPT_FORMAT,
+        kind: ConfigKind::Str,
+        default: "(既定の表示)",
+        description: "入力行のプロンプトの書式",
+    },
+    ConfigKey {
+        key: keys::THEME_PRESET,
+        kind: ConfigKind::Str,
+        default: "(既定)",
+        description: "配色テーマ (/theme)",
+    },
+    ConfigKey {
+        key: keys::THEME_NO_COLOR,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "色を出さない",
+    },
+    ConfigKey {
+        key: keys::THEME_STATUS_FG,
+        kind: ConfigKind::Str,
+        default: "(テーマの色)",
+        description: "ステータスバーの文字色",
+    },
+    ConfigKey {
+        key: keys::THEME_STATUS_BG,
+        kind: ConfigKind::Str,
+        default: "(テーマの色)",
+        description: "ステータスバーの背景色",
+    },
+    ConfigKey {
+        key: keys::THEME_OWN,
+        kind: ConfigKind::Str,
+        default: "(テーマの色)",
+        description: "自分の投稿の色",
+    },
+    ConfigKey {
+        key: keys::THEME_VALID,
+        kind: ConfigKind::Str,
+        default: "(テーマの色)",
+        description: "署名を確かめた投稿の色",
+    },
+    ConfigKey {
+        key: keys::THEME_VERIFIED,
+        kind: ConfigKind::Str,
+        default: "(テーマの色)",
+        description: "/trust で検証済みにした相手の投稿の色",
+    },
+    ConfigKey {
+        key: keys::THEME_INVALID,
+        kind: ConfigKind::Str,
+        default: "(テーマの色)",
+        description: "署名が不正な投稿の色",
+    },
+    ConfigKey {
+        key: keys::THEME_HANDLE,
+        kind: ConfigKind::Str,
+        default: "(テーマの色)",
+        description: "ハンドルの色",
+    },
+    ConfigKey {
+        key: keys::THEME_SYSTEM,
+        kind: ConfigKind::Str,
+        default: "(テーマの色)",
+        description: "システムメッセージの色",
+    },
+    ConfigKey {
+        key: keys::THEME_ANNOUNCE,
+        kind: ConfigKind::Str,
+        default: "(テーマの色)",
+        description: "他ノードからのお知らせの色",
+    },
+    ConfigKey {
+        key: keys::THEME_URGENT,
+        kind: ConfigKind::Str,
+        default: "(テーマの色)",
+        description: "至急の投稿・DM の色",
+    },
+    ConfigKey {
+        key: keys::HONOR_URGENT,
+        kind: ConfigKind::Bool,
+        default: "true",
+        description: "他人が付けた至急の印を目立たせて通知する",
+    },
+    ConfigKey {
+        key: keys::URGENT_BELL_IN_DND,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "おやすみ中でも至急の投稿ではベルを鳴らす",
+    },
+    ConfigKey {
+        key: keys::DND_HOURS,
+        kind: ConfigKind::Str,
+        default: "(なし)",
+        description: "DM のベルと通知を止める時間帯 (例: 22:00-07:00)",
+    },
+    ConfigKey {
+        key: keys::NO_HISTORY,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "メッセージを一切保存しない",
+    },
+    ConfigKey {
+        key: keys::STORAGE_BACKEND,
+        kind: ConfigKind::Str,
+        default: "sled",
+        description: "保存先 (sled / memory)",
+    },
+    ConfigKey {
+        key: keys::DURABILITY,
+        kind: ConfigKind::Str,
+        default: "safe",
+        description: "投稿をディスクへ書き出す時機 (safe / fast / relaxed)",
+    },
+    ConfigKey {
+        key: keys::CHAT_RETENTION_DAYS,
+        kind: ConfigKind::Int,
+        default: "0",
+        description: "全体チャットとお知らせを残す日数（0 なら期限なし）",
+    },
+    ConfigKey {
+        key: keys::DM_RETENTION_DAYS,
+        kind: ConfigKind::Int,
+        default: "0",
+        description: "DM を残す日数（0 なら期限なし）",
+    },
+    ConfigKey {
+        key: keys::RELAY,
+        kind: ConfigKind::Bool,
+        default: "true",
+        description: "受信した投稿を他のピアへ中継する (/relay)",
+    },
+    ConfigKey {
+        key: keys::RELAY_DMS,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "他人宛ての ROUTED_DM を中継する",
+    },
+    ConfigKey {
+        key: keys::RELAY_FANOUT_PER_TICK,
+        kind: ConfigKind::Int,
+        default: "0",
+        description: "1 ティックに中継で書き込む回数の上限（0 なら無制限, /fanout）",
+    },
+    ConfigKey {
+        key: keys::SUPPRESS_OWN_ECHO,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "自分の鍵で署名された投稿が戻ってきても表示しない",
+    },
+    ConfigKey {
+        key: keys::HISTORY_SYNC,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "HELLO の後に相手にしかない投稿を取り寄せる",
+    },
+    ConfigKey {
+        key: keys::ADVERTISE,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "自分の待受アドレスを接続先に広告する",
+    },
+    ConfigKey {
+        key: keys::SHARE_TOPOLOGY,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "/topology の問い合わせに隣接ピアの指紋を答える",
+    },
+    ConfigKey {
+        key: keys::PEER_LOOKUP,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "/whois で接続元アドレスを逆引きする",
+    },
+    ConfigKey {
+        key: keys::DNS_SERVER,
+        kind: ConfigKind::Str,
+        default: "(システムの設定)",
+        description: "逆引きに使う DNS サーバー",
+    },
+    ConfigKey {
+        key: keys::ASN_DB,
+        kind: ConfigKind::Str,
+        default: "(なし)",
+        description: "/whois で AS を引く ip2asn 形式の表",
+    },
+    ConfigKey {
+        key: keys::PORT_MAPPING,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "NAT-PMP でルーターのポートを開ける",
+    },
+    ConfigKey {
+        key: keys::NAT_GATEWAY,
+        kind: ConfigKind::Str,
+        default: "(自動)",
+        description: "NAT-PMP で使うゲートウェイ",
+    },
+    ConfigKey {
+        key: keys::METRICS,
+        kind: ConfigKind::Bool,
+        default: "false",
+        description: "中継の統計を Prometheus 形式で公開する",
+    },
+    ConfigKey {
+        key: keys::METRICS_ADDR,
+        kind: ConfigKind::Str,
+        default: "127.0.0.1:9184",
+        description: "メトリクスを公開するアドレス",
+    },
+    ConfigKey {
+        key: keys::CONNECT_TIMEOUT_SECS,
+        kind: ConfigKind::Int,
+        default: "5",
+        description: "1 アドレスあたりの接続タイムアウト（秒）",
+    },
+    ConfigKey {
+        key: keys::CONNECT_PUZZLE_DIFFICULTY,
+        kind: ConfigKind::Int,
+        default: "0",
+        description: "受け入れたピアに解かせるパズルの難易度（0 なら無効）",
+    },
+    ConfigKey {
+        key: keys::PING_INTERVAL_SECS,
+        kind: ConfigKind::Int,
+        default: "0",
+        description: "ピアへ PING を送る間隔（秒、0 なら無通信タイムアウトの 1/3）",
+    },
+    ConfigKey {
+        key: keys::IDLE_TIMEOUT_SECS,
+        kind: ConfigKind::Int,
+        default: "0",
+        description: "無通信のピアを切断するまでの時間（秒、0 なら切らない）",
+    },
+    ConfigKey {
+        key: keys::READ_BUFFER_BYTES,
+        kind: ConfigKind::Int,
+        default: "2048",
+        description: "1 回の読み込みの大きさ（バイト）",
+    },
+    ConfigKey {
+        key: keys::SEND_BUFFER_LIMIT_BYTES,
+        kind: ConfigKind::Int,
+        default: "4194304",
+        description: "全ピア合計の送信待ちの上限（バイト）",
+    },
+    ConfigKey {
+        key: keys::HANDLE_CHANGE_MIN_SECS,
+        kind: ConfigKind::Int,
+        default: "60",
+        description: "同じ接続で相手がハンドルを変えられる間隔（秒、0 なら制限なし）",
+    },
+    ConfigKey {
+        key: keys::WELCOME_MESSAGE,
+        kind: ConfigKind::Str,
+        default: "(なし)",
+        description: "受け入れた相手に HELLO の後で DM する文",
+    },
+    ConfigKey {
+        key: keys::RECORD_FRAMES,
+        kind: ConfigKind::Str,
+        default: "(なし)",
+        description: "受信したバイト列を追記するファイル (--replay で再生)",
+    },
+];
+
+pub fn find_config_key(key: &str) -> Option<&'static ConfigKey> {
+    CONFIG_KEYS.iter().find(|k| k.key == key)
+}
+
+/// 表示用の値（文字列は引用符を付けない）
+fn show_value(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// /config の一覧。設定してあるキーは今の値、無いキーは既定値を (既定) 付きで出す
+pub fn describe_config(tbl: &Table) -> String {
+    let mut lines = vec!["設定一覧 (/config <key> で詳細):".to_string()];
+    for k in CONFIG_KEYS {
+        let value = match get_value_in(tbl, k.key) {
+            Some(v) => show_value(&v),
+            None => format!("{} (既定)", k.default),
+        };
+        lines.push(format!("{} = {} - {}", k.key, value, k.description));
+    }
+    lines.join("\n")
+}
+
+/// /config <key> の詳細
+pub fn describe_config_key(tbl: &Table, key: &ConfigKey) -> String {
+    let current = get_value_in(tbl, key.key).map_or("(未設定)".to_string(), |v| show_value(&v));
+    format!(
+        "{}\n  説明: {}\n  種類: {}\n  現在: {}\n  既定: {}\n  変更: /config {} <値>",
+        key.key,
+        key.description,
+        key.kind.label(),
+        current,
+        key.default,
+        key.key
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accessors_do_not_panic_before_init() {
+        // このテストバイナリでは init_config_path を呼ばない
+        assert!(try_config().is_none());
+        assert_eq!(get_value("user.handle"), None);
+        assert!(!is_debug());
+    }
+
+    #[test]
+    fn get_value_in_walks_dotted_path() {
+        let tbl: Table = "[user]\nhandle = \"@alice\"\n".parse().unwrap();
+        assert_eq!(
+            get_value_in(&tbl, "user.handle").and_then(|v| v.as_str().map(|s| s.to_string())),
+            Some("@alice".to_string())
+        );
+        assert_eq!(get_value_in(&tbl, "user.missing"), None);
+        assert_eq!(get_value_in(&tbl, "nope.handle"), None);
+    }
+
+    #[test]
+    fn config_listing_shows_current_values_and_defaults() {
+        let tbl: Table = "relay = false\n[user]\nhandle = \"@alice\"\n"
+            .parse()
+            .unwrap();
+        let text = describe_config(&tbl);
+        assert!(text.contains("relay = false - 受信した投稿を"), "{}", text);
+        assert!(text.contains("user.handle = @alice - "), "{}", text);
+        assert!(text.contains("history_sync = false (既定) - "), "{}", text);
+        let detail = describe_config_key(&tbl, find_config_key("relay").unwrap());
+        assert!(detail.contains("現在: false"), "{}", detail);
+        assert!(detail.contains("既定: true"), "{}", detail);
+        assert!(find_config_key("nope").is_none());
+        assert_eq!(ConfigKind::Bool.parse("on"), Some(Value::Boolean(true)));
+        assert_eq!(ConfigKind::Int.parse("x"), None);
+        // 一覧のキーは重複しない
+        let mut keys: Vec<_> = CONFIG_KEYS.iter().map(|k| k.key).collect();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), CONFIG_KEYS.len());
+    }
+
+    // src 以下の .rs を (パス, テストを除いた本文) で返す
+    fn sources() -> Vec<(PathBuf, String)> {
+        let mut dirs = vec![Path::new(env!("CARGO_MANIFEST_DIR")).join("src")];
+        let mut out = Vec::new();
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path.extension().is_some_and(|e| e == "rs") {
+                    let text = fs::read_to_string(&path).unwrap();
+                    let body = text.split("#[cfg(test)]\nmod tests").next().unwrap();
+                    out.push((path, body.to_string()));
                 }
             }
         }
-        let last = segments.remove(0);
-        cur.insert(last.to_string(), value);
+        out
+    }
+
+    #[test]
+    fn every_key_the_code_reads_is_listed() {
+        // keys の定数（鍵を除く）は一覧に載っていて、一覧には keys に無いキーが無い
+        let text = fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("src/config.rs"))
+            .unwrap();
+        let module = text.split("pub mod keys {").nth(1).unwrap();
+        let module = &module[..module.find("\n}\n").unwrap()];
+        let mut defined: Vec<&str> = module
+            .lines()
+            .filter_map(|l| l.trim().strip_prefix("pub const "))
+            .filter(|l| !l.starts_with("KEY_"))
+            .filter_map(|l| l.split('"').nth(1))
+            .collect();
+        let mut listed: Vec<&str> = CONFIG_KEYS.iter().map(|k| k.key).collect();
+        defined.sort();
+        listed.sort();
+        assert_eq!(defined, listed);
+        // 設定は keys の定数で読む（文字列で組み立てたキーは一覧から漏れる）
+        let literal_key = |line: &str| {
+            ["get_value(", "get_value_in("].iter().any(|call| {
+                line.split(call).skip(1).any(|rest| {
+                    let args = rest.split(')').next().unwrap_or("");
+                    args.split(',')
+                        .any(|a| a.trim().starts_with('"') || a.contains("format!("))
+                })
+            })
+        };
+        for (path, body) in sources() {
+            for (i, line) in body.lines().enumerate() {
+                assert!(!literal_key(line), "{}:{}: {}", path.display(), i + 1, line);
+            }
+        }
     }
 
-    // 保存
-    save().map_err(|e| format!("save failed: {}", e))?;
+    #[test]
+    fn handle_validation_boundaries() {
+        let ok = format!("@{}", "a".repeat(DEFAULT_MAX_HANDLE_LEN - 2));
+        let too_long = format!("@{}", "a".repeat(DEFAULT_MAX_HANDLE_LEN - 1));
+        assert!(is_valid_handle(&ok));
+        assert!(!is_valid_handle(&too_long));
+        assert!(!is_valid_handle("alice"));
+        assert!(!is_valid_handle(""));
+        // 全角も1文字として数える
+        assert!(is_valid_handle_with("@あいう", 5, 80));
+        assert!(!is_valid_handle_with("@あいうえ", 5, 80));
+        // 表示幅にも上限がある（全角は 2 桁）
+        assert!(is_valid_handle_with("@あいう", 80, 7));
+        assert!(!is_valid_handle_with("@あいう", 80, 6));
+    }
 
-    // 保存先ファイルから再読み込みしてメモリ上の CONFIG を更新する
-    let content =
-        fs::read_to_string("./config.toml").map_err(|e| format!("reload read failed: {}", e))?;
-    let table: Table = content
-        .parse()
-        .map_err(|e| format!("reload parse failed: {}", e))?;
+    #[test]
+    fn handle_rejects_control_and_zero_width_chars() {
+        assert!(!is_valid_handle("@ali\x1b[2Jce"));
+        assert!(!is_valid_handle("@bob\n"));
+        // ゼロ幅スペースや結合文字で水増ししたもの
+        assert!(!is_valid_handle("@bob\u{200b}\u{200b}"));
+        assert!(!is_valid_handle("@\u{200d}bob"));
+        assert!(!is_valid_handle("@e\u{301}"));
+        assert!(!is_valid_handle("@bob "));
+        assert!(!is_valid_handle("@a b"));
+        // 前後の空白は入力時に落とす
+        assert_eq!(normalize_handle("  @bob \t"), "@bob");
+        assert!(is_valid_handle(&normalize_handle("@bob ")));
+        assert!(is_valid_handle("@é"));
+    }
 
-    if let Some(lock) = CONFIG.get() {
-        let mut root = lock
-            .write()
-            .map_err(|_| "config lock poisoned".to_string())?;
-        *root = table;
-        Ok(())
-    } else {
-        Err("config not initialized".into())
+    #[test]
+    fn bio_allows_spaces_but_not_control_chars() {
+        assert!(is_valid_bio("作業中 しばらく返信できません"));
+        assert!(!is_valid_bio(""));
+        assert!(!is_valid_bio("  "));
+        assert!(!is_valid_bio("改行\nあり"));
+        assert!(!is_valid_bio("\x1b[2J消す"));
+        assert!(!is_valid_bio("幅ゼロ\u{200b}"));
+        let max = "x".repeat(crate::core::protocol::MAX_BIO_CHARS);
+        assert!(is_valid_bio(&max));
+        assert!(!is_valid_bio(&format!("{}x", max)));
     }
-}
 
-/// 設定を現在の内容で保存。
-pub fn save() -> Result<(), std::io::Error> {
-    if let Some(lock) = CONFIG.get() {
-        let cfg = lock.read().expect("config lock poisoned");
-        fs::write("./config.toml", cfg.to_string())?;
+    #[test]
+    fn max_handle_len_reads_config() {
+        let tbl: Table = "[user]\nmax_handle_len = 16\n".parse().unwrap();
+        assert_eq!(max_handle_len_in(&tbl), 16);
+        let tbl: Table = "[user]\nmax_handle_len = -1\n".parse().unwrap();
+        assert_eq!(max_handle_len_in(&tbl), DEFAULT_MAX_HANDLE_LEN);
+        assert_eq!(max_handle_len_in(&Table::new()), DEFAULT_MAX_HANDLE_LEN);
+        // プロトコルの上限より広げても、ピアに受け取ってもらえる長さまでにする
+        let tbl: Table = "[user]\nmax_handle_len = 500\nmax_handle_width = 500\n"
+            .parse()
+            .unwrap();
+        assert_eq!(max_handle_len_in(&tbl), DEFAULT_MAX_HANDLE_LEN);
+        assert_eq!(max_handle_width_in(&tbl), DEFAULT_MAX_HANDLE_WIDTH);
+    }
+
+    #[test]
+    fn peer_handles_use_the_protocol_limit_not_the_local_one() {
+        // 自分には 16 文字未満を課していても、ピアのハンドルはプロトコルの上限で見る
+        let tbl: Table = "[user]\nmax_handle_len = 16\n".parse().unwrap();
+        let name = format!("@{}", "a".repeat(20));
+        assert!(!is_valid_handle_in(&tbl, &name));
+        assert!(is_valid_peer_handle(&name));
+        let longest = format!(
+            "@{}",
+            "a".repeat(crate::core::protocol::MAX_HANDLE_CHARS - 1)
+        );
+        assert!(is_valid_peer_handle(&longest));
+        assert!(!is_valid_peer_handle(&format!("{}a", longest)));
+        assert!(!is_valid_peer_handle("@bob\u{200b}"));
+    }
+
+    #[test]
+    fn profiles_use_separate_files() {
+        let default = Profile::resolve(None).unwrap();
+        assert_eq!(default.config, PathBuf::from(DEFAULT_CONFIG_PATH));
+        assert_eq!(Profile::resolve(Some("default")).unwrap(), default);
+        let work = Profile::resolve(Some("work")).unwrap();
+        assert_eq!(work.config, Path::new("./profiles/work/config.toml"));
+        assert_eq!(work.db, Path::new("./profiles/work/p2witter.db"));
+        assert!(Profile::resolve(Some("../etc")).is_err());
+
+        // 2 つのプロファイルの設定ファイルは互いに影響しない
+        let root = std::env::temp_dir().join(format!("p2witter-profiles-{}", std::process::id()));
+        let a = root.join("a").join("config.toml");
+        let b = root.join("b").join("config.toml");
+        let mut tbl = load_or_create(&a).unwrap();
+        tbl.insert("listen_port".into(), Value::Integer(2234));
+        fs::write(&a, tbl.to_string()).unwrap();
+        assert_eq!(load_or_create(&b).unwrap().get("listen_port"), None);
+        assert_eq!(
+            load_or_create(&a).unwrap().get("listen_port"),
+            Some(&Value::Integer(2234))
+        );
+        let _ = fs::remove_dir_all(&root);
     }
-    Ok(())
 }
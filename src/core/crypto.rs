@@ -22,6 +22,10 @@ pub enum CryptoError {
     Verify,
     Encrypt,
     Decrypt,
+    /// 16進数として読めない（奇数桁・範囲外の文字）
+    Hex,
+    /// 同じ鍵での暗号化回数が上限に達した
+    Exhausted,
 }
 
 impl std::fmt::Display for CryptoError {
@@ -37,6 +41,8 @@ impl std::fmt::Display for CryptoError {
                 Verify => "検証に失敗",
                 Encrypt => "暗号化に失敗",
                 Decrypt => "復号に失敗",
+                Hex => "16進数として読めません",
+                Exhausted => "鍵の使用回数が上限に達しました",
             }
         )
     }
@@ -62,6 +68,12 @@ pub fn generate_ed25519_keypair() -> Result<Ed25519KeyPairMaterial, CryptoError>
     })
 }
 
+/// PKCS#8 秘密鍵を読み、対応する公開鍵を返す
+pub fn public_key_from_pkcs8(pkcs8_private_key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let keypair = Ed25519KeyPair::from_pkcs8(pkcs8_private_key).map_err(|_| CryptoError::Key)?;
+    Ok(keypair.public_key().as_ref().to_vec())
+}
+
 /// Ed25519署名を作成
 pub fn sign_ed25519(message: &[u8], pkcs8_private_key: &[u8]) -> Result<Vec<u8>, CryptoError> {
     let keypair = Ed25519KeyPair::from_pkcs8(pkcs8_private_key).map_err(|_| CryptoError::Key)?;
@@ -81,6 +93,12 @@ pub fn verify_ed25519(
         .map_err(|_| CryptoError::Verify)
 }
 
+/// 公開鍵の指紋 (SHA-256 の HEX)
+pub fn fingerprint_hex(public_key: &[u8]) -> String {
+    let d = ring::digest::digest(&ring::digest::SHA256, public_key);
+    to_hex(d.as_ref())
+}
+
 /// ランダムバイト列を生成 (鍵IDなどに利用)
 pub fn random_bytes(len: usize) -> Result<Vec<u8>, CryptoError> {
     let rng = SystemRandom::new();
@@ -89,6 +107,28 @@ pub fn random_bytes(len: usize) -> Result<Vec<u8>, CryptoError> {
     Ok(buf)
 }
 
+/// 接続パズルの判定: SHA-256(challenge || nonce) の先頭 difficulty ビットが 0 か
+pub fn puzzle_ok(challenge: &[u8], nonce: u64, difficulty: u8) -> bool {
+    let mut data = challenge.to_vec();
+    data.extend_from_slice(&nonce.to_be_bytes());
+    let d = ring::digest::digest(&ring::digest::SHA256, &data);
+    let mut zeros = 0u32;
+    for b in d.as_ref() {
+        zeros += b.leading_zeros();
+        if *b != 0 {
+            break;
+        }
+    }
+    zeros >= u32::from(difficulty)
+}
+
+/// 接続パズルを総当たりで解き、条件を満たす nonce を返す
+pub fn solve_puzzle(challenge: &[u8], difficulty: u8) -> u64 {
+    (0..u64::MAX)
+        .find(|&n| puzzle_ok(challenge, n, difficulty))
+        .unwrap_or(u64::MAX)
+}
+
 /// 簡易HEXエンコード
 pub fn to_hex(data: &[u8]) -> String {
     const HEX: &[u8; 16] = b"0123456789abcdef";
@@ -102,14 +142,14 @@ pub fn to_hex(data: &[u8]) -> String {
 
 /// HEXデコード (小文字/大文字両対応)
 pub fn from_hex(s: &str) -> Result<Vec<u8>, CryptoError> {
-    if s.len() % 2 != 0 {
-        return Err(CryptoError::Key);
+    if !s.len().is_multiple_of(2) {
+        return Err(CryptoError::Hex);
     }
     let mut out = Vec::with_capacity(s.len() / 2);
     let bytes = s.as_bytes();
     for i in (0..bytes.len()).step_by(2) {
-        let hi = hex_val(bytes[i]).ok_or(CryptoError::Key)?;
-        let lo = hex_val(bytes[i + 1]).ok_or(CryptoError::Key)?;
+        let hi = hex_val(bytes[i]).ok_or(CryptoError::Hex)?;
+        let lo = hex_val(bytes[i + 1]).ok_or(CryptoError::Hex)?;
         out.push((hi << 4) | lo);
     }
     Ok(out)
@@ -160,29 +200,145 @@ pub fn decrypt_conninfo_from_hex(token_hex: &str) -> Result<String, CryptoError>
         // nonce + 最小タグ
         return Err(CryptoError::Decrypt);
     }
-    let (nonce_bytes, mut ciphertext) = data.split_at_mut(12);
+    let (nonce_bytes, ciphertext) = data.split_at_mut(12);
     let key = LessSafeKey::new(
         UnboundKey::new(&aead::CHACHA20_POLY1305, &CONNINFO_KEY).map_err(|_| CryptoError::Key)?,
     );
     let nonce =
         Nonce::assume_unique_for_key(nonce_bytes.try_into().map_err(|_| CryptoError::Decrypt)?);
     let plain = key
-        .open_in_place(nonce, Aad::empty(), &mut ciphertext)
+        .open_in_place(nonce, Aad::empty(), ciphertext)
         .map_err(|_| CryptoError::Decrypt)?;
     let s = std::str::from_utf8(plain).map_err(|_| CryptoError::Decrypt)?;
     Ok(s.to_string())
 }
 
+/// 1つの NonceSequence で暗号化できる回数の上限
+pub const MAX_MESSAGES_PER_KEY: u64 = 1 << 32;
+
+/// DM 用のノンス列。ノンスは毎回 96bit すべてを乱数で作り、払い出した数だけを数える。
+///
+/// DM の鍵 (CONNINFO_KEY) は全ノード共通で、カウンタはノードや再起動のたびに 0 に戻るので、
+/// カウンタをノンスに入れると他のノードや前回のセッションと同じ値が出る。乱数だけなら
+/// 同じ鍵で q 通暗号化したときの衝突確率はおよそ q²/2^97 で、上限の 2^32 通でも 2^-33 に収まる。
+/// 上限に達したら鍵を替えるまで暗号化しない
+pub struct NonceSequence {
+    counter: u64,
+    limit: u64,
+}
+
+impl NonceSequence {
+    pub fn new() -> Self {
+        Self::with_limit(MAX_MESSAGES_PER_KEY)
+    }
+
+    /// 上限を指定して作る（MAX_MESSAGES_PER_KEY を超える値は切り詰める）
+    pub fn with_limit(limit: u64) -> Self {
+        Self {
+            counter: 0,
+            limit: limit.min(MAX_MESSAGES_PER_KEY),
+        }
+    }
+
+    /// これまでに払い出したノンスの数
+    pub fn used(&self) -> u64 {
+        self.counter
+    }
+
+    pub fn exhausted(&self) -> bool {
+        self.counter >= self.limit
+    }
+
+    /// 次のノンス。上限に達していれば Exhausted
+    pub fn next_nonce(&mut self) -> Result<[u8; 12], CryptoError> {
+        if self.exhausted() {
+            return Err(CryptoError::Exhausted);
+        }
+        let mut nonce = [0u8; 12];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| CryptoError::Rand)?;
+        self.counter += 1;
+        Ok(nonce)
+    }
+}
+
+impl Default for NonceSequence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ---- Ed25519 の鍵を X25519 (ECDH) に流用する ----
+//
+// Ed25519 と X25519 は同じ曲線の別表現なので、身元鍵 1 本で署名と鍵共有の両方ができ、
+// HELLO で送る公開鍵も 1 つで済む (libsodium の crypto_sign_ed25519_*_to_curve25519 と同じ変換)。
+// 曲線の演算は curve25519-dalek に任せる（ring の X25519 は ring が作った一時鍵しか受け付けない）。
+// 注意点:
+// - 同じ秘密から署名と DH の両方を行うので、どちらかの実装から秘密が漏れれば両方が破られる。
+//   署名と鍵共有で同じ鍵を使っても互いの安全性を損なわないことは知られているが、
+//   身元鍵を鍵共有以外の用途（別プロトコルの署名など）にさらに流用しないこと
+// - 身元鍵から作った DH 鍵は長期鍵なので前方秘匿性は無い。セッション鍵は一時鍵と組み合わせて導くこと
+// - 相手の公開鍵は曲線上の点に戻せるかを確かめ、小位数の点は拒否する
+
+/// PKCS#8 (ring 形式) の Ed25519 秘密鍵から X25519 の秘密鍵を作る (SHA-512(seed) の前半をクランプ)
+pub fn ed25519_to_x25519_secret(pkcs8_private_key: &[u8]) -> Result<[u8; 32], CryptoError> {
+    // 鍵として読めるか ring で確かめてから、seed（OCTET STRING の中身）を取り出す
+    Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8_private_key).map_err(|_| CryptoError::Key)?;
+    const SEED_PREFIX: [u8; 9] = [0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20];
+    let seed = pkcs8_private_key
+        .get(7..48)
+        .filter(|b| b[..9] == SEED_PREFIX)
+        .map(|b| &b[9..])
+        .ok_or(CryptoError::Key)?;
+    let h = ring::digest::digest(&ring::digest::SHA512, seed);
+    let half: [u8; 32] = h.as_ref()[..32].try_into().map_err(|_| CryptoError::Key)?;
+    Ok(curve25519_dalek::scalar::clamp_integer(half))
+}
+
+/// Ed25519 の公開鍵 (32B) を X25519 の公開鍵に変換する。曲線上に無い点や小位数の点は拒否する
+pub fn ed25519_to_x25519_public(public_key: &[u8]) -> Result<[u8; 32], CryptoError> {
+    let y: [u8; 32] = public_key.try_into().map_err(|_| CryptoError::Key)?;
+    let point = curve25519_dalek::edwards::CompressedEdwardsY(y)
+        .decompress()
+        .filter(|p| !p.is_small_order())
+        .ok_or(CryptoError::Key)?;
+    Ok(point.to_montgomery().to_bytes())
+}
+
+/// X25519 で共有秘密を求める。相手の鍵が小位数の点で結果が 0 なら拒否する
+pub fn x25519(secret: &[u8; 32], peer_public: &[u8; 32]) -> Result<[u8; 32], CryptoError> {
+    let shared = curve25519_dalek::montgomery::MontgomeryPoint(*peer_public)
+        .mul_clamped(*secret)
+        .to_bytes();
+    if shared == [0; 32] {
+        return Err(CryptoError::Key);
+    }
+    Ok(shared)
+}
+
 /// DMペイロード暗号化: バイト列 -> 先頭12Bノンス + 暗号文+タグ
 pub fn encrypt_dm_payload(plain: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let rng = SystemRandom::new();
+    let mut nonce_bytes = [0u8; 12];
+    rng.fill(&mut nonce_bytes).map_err(|_| CryptoError::Rand)?;
+    seal_dm(plain, nonce_bytes)
+}
+
+/// DMペイロード暗号化（ノンスを nonces から払い出す）。形式は encrypt_dm_payload と同じ
+pub fn encrypt_dm_payload_with(
+    nonces: &mut NonceSequence,
+    plain: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    seal_dm(plain, nonces.next_nonce()?)
+}
+
+fn seal_dm(plain: &[u8], nonce_bytes: [u8; 12]) -> Result<Vec<u8>, CryptoError> {
     // CONNINFO_KEY を共有鍵として流用（デモ用途）。
     // 形式は encrypt_conninfo_to_hex と同じ（ノンス12B先頭付与）。
     let key = LessSafeKey::new(
         UnboundKey::new(&aead::CHACHA20_POLY1305, &CONNINFO_KEY).map_err(|_| CryptoError::Key)?,
     );
-    let rng = SystemRandom::new();
-    let mut nonce_bytes = [0u8; 12];
-    rng.fill(&mut nonce_bytes).map_err(|_| CryptoError::Rand)?;
     let nonce = Nonce::assume_unique_for_key(nonce_bytes);
 
     let mut in_out = plain.to_vec();
@@ -212,3 +368,117 @@ pub fn decrypt_dm_payload(nonce_and_ciphertext: &[u8]) -> Result<Vec<u8>, Crypto
         .map_err(|_| CryptoError::Decrypt)?;
     Ok(plain.to_vec())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 依存を増やさないための簡易乱数 (xorshift64)
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected_without_panicking() {
+        let mut rng = Rng(0x2396_5eed);
+        // 16進として正しい長さ・文字かに関係なく任意の文字列
+        let alphabet: Vec<char> = "0123456789abcdefABCDEFxyz -\n\té漢🦀".chars().collect();
+        for _ in 0..2000 {
+            let len = (rng.next() % 96) as usize;
+            let s: String = (0..len)
+                .map(|_| alphabet[(rng.next() % alphabet.len() as u64) as usize])
+                .collect();
+            let _ = from_hex(&s);
+            assert!(decrypt_conninfo_from_hex(&s).is_err(), "{:?}", s);
+        }
+        // 正しい16進で、ノンスだけ・タグ欠け・改ざんなどあらゆる長さ
+        for len in 0..80 {
+            let hex = to_hex(&rng.bytes(len));
+            assert_eq!(from_hex(&hex).unwrap().len(), len);
+            assert!(decrypt_conninfo_from_hex(&hex).is_err(), "len={}", len);
+            assert!(decrypt_dm_payload(&rng.bytes(len)).is_err(), "len={}", len);
+        }
+        // 本物のトークンを途中で切ったもの
+        let token = encrypt_conninfo_to_hex("127.0.0.1:2234").unwrap();
+        for end in 0..token.len() {
+            assert!(decrypt_conninfo_from_hex(&token[..end]).is_err());
+        }
+        assert_eq!(decrypt_conninfo_from_hex(&token).unwrap(), "127.0.0.1:2234");
+    }
+
+    #[test]
+    fn dm_nonces_are_random_and_counted() {
+        let mut nonces = NonceSequence::with_limit(2000);
+        let mut seen = std::collections::HashSet::new();
+        let mut prefixes = std::collections::HashSet::new();
+        for _ in 0..2000 {
+            let sealed = encrypt_dm_payload_with(&mut nonces, b"hi").unwrap();
+            assert!(seen.insert(sealed[..12].to_vec()));
+            prefixes.insert(sealed[..4].to_vec());
+            assert_eq!(decrypt_dm_payload(&sealed).unwrap(), b"hi");
+        }
+        assert_eq!(nonces.used(), 2000);
+        // 先頭も乱数（カウンタを入れると他のノードや前回のセッションと重なる）
+        assert!(prefixes.len() > 1990, "{}", prefixes.len());
+        // 上限に達したら鍵を替えるまで暗号化しない
+        assert!(nonces.exhausted());
+        assert!(matches!(
+            encrypt_dm_payload_with(&mut nonces, b"hi"),
+            Err(CryptoError::Exhausted)
+        ));
+        assert_eq!(
+            NonceSequence::with_limit(u64::MAX).limit,
+            MAX_MESSAGES_PER_KEY
+        );
+    }
+
+    #[test]
+    fn low_difficulty_puzzle_is_solved_and_verified() {
+        let challenge = b"p2witter-challenge";
+        let nonce = solve_puzzle(challenge, 12);
+        assert!(puzzle_ok(challenge, nonce, 12));
+        // 難易度 0 は何でも通る
+        assert!(puzzle_ok(challenge, 0, 0));
+        // それより前の nonce はどれも条件を満たさない
+        assert!((0..nonce).all(|n| !puzzle_ok(challenge, n, 12)));
+    }
+
+    #[test]
+    fn converted_identity_keys_agree_on_a_shared_secret() {
+        let alice = generate_ed25519_keypair().unwrap();
+        let bob = generate_ed25519_keypair().unwrap();
+        let a_sec = ed25519_to_x25519_secret(&alice.pkcs8).unwrap();
+        let b_sec = ed25519_to_x25519_secret(&bob.pkcs8).unwrap();
+        let a_pub = ed25519_to_x25519_public(&alice.public).unwrap();
+        let b_pub = ed25519_to_x25519_public(&bob.public).unwrap();
+
+        // 変換した公開鍵は、変換した秘密鍵 × 基点と一致する
+        let mut base = [0u8; 32];
+        base[0] = 9;
+        assert_eq!(x25519(&a_sec, &base).unwrap(), a_pub);
+        // 2 つのノードが互いの変換済み公開鍵から同じ共有秘密に着く
+        assert_eq!(
+            x25519(&a_sec, &b_pub).unwrap(),
+            x25519(&b_sec, &a_pub).unwrap()
+        );
+
+        // 小位数の点（u = 0、Edwards の単位元）や長さ違いは拒否する
+        assert!(x25519(&a_sec, &[0; 32]).is_err());
+        let mut identity = [0u8; 32];
+        identity[0] = 1;
+        assert!(ed25519_to_x25519_public(&identity).is_err());
+        assert!(ed25519_to_x25519_public(&alice.public[..31]).is_err());
+        assert!(ed25519_to_x25519_secret(b"not a key").is_err());
+    }
+}
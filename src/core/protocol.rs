@@ -2,7 +2,10 @@
 //!
 //! Frame layout (big endian for all multi-byte integers):
 //! - 0: version (u8)
-//! - 1: kind (u8) =1 Chat, =2 DM, =3 HELLO, =4 DISCONNECT
+//! - 1: kind (u8) =1 Chat, =2 DM, =3 HELLO, =4 DISCONNECT, =5 ROTATE, =6 EDIT, =7 DELETE,
+//!   =9 TOPIC, =10 EPHEMERAL_DM, =11 ROUTED_DM, =12 SYSTEM, =13 ADVERT, =14 CHALLENGE,
+//!   =15 SOLUTION, =16 SYNC_COUNTS, =17 SYNC_REQUEST, =18 SYNC_RECORDS, =19 TOPOLOGY_QUERY,
+//!   =20 TOPOLOGY_REPLY, =21 PING, =22 PONG (8 は欠番)
 //! - 2: attenuation (u8)
 //! - 3..7: payload length L (u32)
 //! - 7..11: public key length P (u32) (0 or 32 for Ed25519)
@@ -10,13 +13,54 @@
 //! - 15..23: timestamp (u64) = UNIX millis (UTC)
 //! - 23..(23+P): public key bytes
 //! - (23+P)..(23+P+S): signature bytes
-//! - (23+P+S)..(23+P+S+L): payload bytes
-//!   - Chat(kind=1): UTF-8 text
+//! - v2 で署名があるときだけ: 続く 32B が中継トークン H (下記)、その後 8B が送信者の通し番号 (u64, 0 = 無し)
+//! - 残り L バイト: payload bytes
+//!   - Chat(kind=1): UTF-8 text。返信のときは
+//!     CHAT_REPLY_TAG(1B) || 返信先メッセージID(8B) || UTF-8 text
+//!     (本文はハンドルの '@' で始まるので先頭 1B で見分けられる。返信先は署名の対象に含まれる)
+//!   - HELLO(kind=3): UTF-8 のハンドル。ひとことを付けるときは
+//!     HELLO_PROFILE_TAG(1B) || ハンドル長(u16) || ハンドル || ひとこと長(u16) || ひとこと
+//!     (ハンドルは '@' で始まるので先頭 1B で見分けられる。ひとことの無い HELLO は従来の形で送る)
 //!   - DM(kind=2): ChaCha20-Poly1305 bytes = nonce(12B) || ciphertext || tag(16B)
+//!   - ROTATE(kind=5): 新しい公開鍵(32B)。旧鍵で署名する
+//!   - EDIT(kind=6): 対象メッセージID(8B) || 新しい UTF-8 本文。元の投稿者の鍵で署名する
+//!   - DELETE(kind=7): 対象メッセージID(8B)。元の投稿者の鍵で署名する
+//!   - TOPIC(kind=9): UTF-8 のトピック本文。設定者の鍵で署名し、Chat と同様に中継する
+//!   - EPHEMERAL_DM(kind=10): DM と同じ形式。送信側・受信側とも保存しない
+//!   - ROUTED_DM(kind=11): 宛先の指紋(8B) || DM と同じ暗号文。宛先以外は復号せず中継だけする
+//!   - SYSTEM(kind=12): ノード発のお知らせ（参加など）の UTF-8 本文。送信ノードの鍵で署名し、中継する
+//!   - ADVERT(kind=13): 待受アドレス "ip:port" の UTF-8。本人が署名し、受け取ったノードは
+//!     署名ごと保持して後から来たピアへそのまま紹介する（中継はしない）
+//!   - CHALLENGE(kind=14): 難易度(1B) || 問題(16B)。署名なし・中継なし。接続パズルを有効にした待受側が
+//!     HELLO より前に送る
+//!   - SOLUTION(kind=15): 解答の nonce(u64)。署名なし・中継なし
+//!   - SYNC_COUNTS(kind=16): (日付 YYYYMMDD(8B) || 件数(u64)) の並び。署名なし・中継なし
+//!   - SYNC_REQUEST(kind=17): 日付(8B) || 持っているメッセージID(8B)の並び。署名なし・中継なし
+//!   - SYNC_RECORDS(kind=18): 日付(8B) || (フレーム長(u32) || encode 済みの署名付きフレーム)の並び。
+//!     署名は中の各フレームに付いている。中継なし
+//!   - TOPOLOGY_QUERY(kind=19): 問い合わせID(8B)。/topology の発信者が署名し、Chat と同様に中継する
+//!   - TOPOLOGY_REPLY(kind=20): 問い合わせID(8B) || 隣接ピアの指紋(8B)の並び。
+//!     応答者が署名し、問い合わせと同様に中継する。v2 でだけ使う（v1 のノードは知らない kind で切断するため）
+//!   - PING(kind=21): 番号(u64)。署名なし・中継なし。受けた側は同じ番号の PONG を返す。v2 のピアにだけ送る
+//!   - PONG(kind=22): PING の番号(u64)
 //!
 //! Signature (when present) is over:
-//! version || kind || payload_len(be) || timestamp || payload bytes.
-//! 公開鍵や署名サイズは署名対象外 (シンプル化)。
+//! - v1: version || kind || payload_len(be) || timestamp || payload bytes
+//! - v2: v1 と同じ並び || public key(32B) || anchor(32B) || 通し番号(u64)
+//!
+//! v2 の anchor = SHA-256 を (MAX_ATTENUATION - attenuation) 回 H に掛けたもの。
+//! 送信者は乱数の H を付けて減衰値 0 で署名し、中継するたびに減衰値を 1 進めて H = SHA-256(H) とする。
+//! 減衰値を戻すには H の原像が要るので、中継者は減衰値を進めることしかできず、
+//! 進めずに書き換えた減衰値や別の公開鍵は署名の検証で弾かれる。
+//!
+//! 最初のリリースの v1 ノードが知っている kind は 1〜4 (Chat, DM, HELLO, DISCONNECT) だけで、
+//! それ以外を受けると切断する。このノードは kind 5〜18 を交渉したバージョンで出し分けないので、
+//! そうしたノードへ ROTATE・EDIT・履歴同期などを送った時点で切断されうる。
+//! バージョンで出し分けているのは TOPOLOGY_QUERY / TOPOLOGY_REPLY と PING (v2 のピアにだけ送る) だけ。
+//!
+//! 署名の無いフレームは v1 と v2 で形が同じなので、常に v1 で送る。
+//! HELLO(kind=3) も v1 の署名で送り、中継されない attenuation 欄に対応する最大バージョンを入れる
+//! (v1 のノードは 0)。v1 のピアには v2 の署名付きフレームを送らない（自分の投稿は v1 で署名し直す）。
 
 use std::fmt;
 
@@ -30,20 +74,113 @@ impl MsgKind {
     pub const DM: u8 = 2; // ダイレクトメッセージ
     pub const HELLO: u8 = 3; // 接続直後の公開鍵交換
     pub const DISCONNECT: u8 = 4; // 切断通知（理由IDをpayloadに格納）
+    pub const ROTATE: u8 = 5; // 鍵ローテーション（新公開鍵をpayloadに格納）
+    pub const EDIT: u8 = 6; // 投稿の編集（対象ID + 新本文）
+    pub const DELETE: u8 = 7; // 投稿の削除（対象ID）
+    pub const TOPIC: u8 = 9; // 部屋のトピック（本文のみ）。新しく来たピアにも再送する
+    pub const EPHEMERAL_DM: u8 = 10; // 保存しない DM（形式は DM と同じ）
+    pub const ROUTED_DM: u8 = 11; // 宛先指紋付きの DM（宛先以外は中継のみ）
+    pub const SYSTEM: u8 = 12; // ノード発のお知らせ（署名必須）
+    pub const ADVERT: u8 = 13; // 待受アドレスの広告（署名必須。紹介用）
+    pub const CHALLENGE: u8 = 14; // 接続パズルの出題（難易度 + 問題。HELLO より前に待受側が送る）
+    pub const SOLUTION: u8 = 15; // 接続パズルの解答（nonce）
+    pub const SYNC_COUNTS: u8 = 16; // 履歴同期: 日ごとの保存件数（日付 + 件数 の並び）
+    pub const SYNC_REQUEST: u8 = 17; // 履歴同期: ある日の持っているID一覧（足りない分を要求）
+    pub const SYNC_RECORDS: u8 = 18; // 履歴同期: 相手に無い署名付き投稿（日付 + フレームの並び）
+    pub const TOPOLOGY_QUERY: u8 = 19; // 構成の問い合わせ（問い合わせID）
+    pub const TOPOLOGY_REPLY: u8 = 20; // 構成の返事（問い合わせID + 隣接ピアの指紋の並び）
+    pub const PING: u8 = 21; // 生存確認（番号）。受けたら同じ番号の PONG を返す
+    pub const PONG: u8 = 22; // 生存確認の返事（PING の番号）
+    /// 種別バイトの最上位ビット。立っていれば至急（v2 の署名付きフレームだけ）
+    pub const URGENT_FLAG: u8 = 0x80;
 }
 
-pub const PROTOCOL_VERSION: u8 = 1;
+pub const PROTOCOL_VERSION: u8 = 2;
+/// 受け付ける最も古いバージョン（署名の無いフレームと HELLO もこれで送る）
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
 pub const MAX_ATTENUATION: u8 = 50;
 pub const DEFAULT_MAX_PAYLOAD: u32 = 512 * 1024;
 pub const HEADER_LEN: usize = 23;
 pub const ED25519_PUBLIC_KEY_LEN: u32 = 32;
 pub const ED25519_SIGNATURE_LEN: u32 = 64;
+/// メッセージIDのバイト長
+pub const MESSAGE_ID_LEN: usize = 8;
+/// 構成の問い合わせIDのバイト長
+pub const TOPOLOGY_QUERY_ID_LEN: usize = 8;
+/// v2 の中継トークンのバイト長
+pub const HOP_TOKEN_LEN: usize = 32;
+/// v2 の通し番号のバイト長
+pub const SEQ_LEN: usize = 8;
+/// ROUTED_DM の宛先指紋（公開鍵 SHA-256 の先頭）のバイト長
+pub const ROUTE_FINGERPRINT_LEN: usize = 8;
+/// トピック本文の最大文字数
+pub const MAX_TOPIC_CHARS: usize = 100;
+/// SYSTEM 本文の最大文字数
+pub const MAX_SYSTEM_CHARS: usize = 200;
+/// HELLO のひとことの最大文字数
+pub const MAX_BIO_CHARS: usize = 80;
+/// ピアが名乗れるハンドルの最大文字数。受け取ったハンドルはこの値で確かめ、
+/// 受け取った側の user.max_handle_len には左右されない
+pub const MAX_HANDLE_CHARS: usize = 79;
+/// ピアが名乗れるハンドルの最大表示幅
+pub const MAX_HANDLE_WIDTH: usize = 80;
+/// ハンドルとひとことを長さ付きで並べた HELLO の先頭バイト（ハンドルの '@' とは重ならない）
+pub const HELLO_PROFILE_TAG: u8 = 0x01;
+/// 返信先付きの Chat の先頭バイト（本文の '@' とは重ならない）
+pub const CHAT_REPLY_TAG: u8 = 0x01;
+/// 接続パズルの問題の長さ
+pub const PUZZLE_CHALLENGE_LEN: usize = 16;
+/// 履歴同期で使う日付 (YYYYMMDD) のバイト長
+pub const SYNC_DATE_LEN: usize = 8;
+
+/// 至急の印を付けられる種別（投稿と DM）
+pub fn is_urgent_capable_kind(kind: u8) -> bool {
+    matches!(
+        kind,
+        MsgKind::CHAT | MsgKind::DM | MsgKind::EPHEMERAL_DM | MsgKind::ROUTED_DM
+    )
+}
+
+/// 送るときの種別バイト。至急なら最上位ビットを立てる
+fn wire_kind(msg: &Message) -> u8 {
+    if msg.urgent {
+        msg.kind | MsgKind::URGENT_FLAG
+    } else {
+        msg.kind
+    }
+}
 
 fn is_supported_kind(kind: u8) -> bool {
     kind == MsgKind::CHAT
         || kind == MsgKind::DM
         || kind == MsgKind::HELLO
         || kind == MsgKind::DISCONNECT
+        || kind == MsgKind::ROTATE
+        || kind == MsgKind::EDIT
+        || kind == MsgKind::DELETE
+        || kind == MsgKind::TOPIC
+        || kind == MsgKind::EPHEMERAL_DM
+        || kind == MsgKind::ROUTED_DM
+        || kind == MsgKind::SYSTEM
+        || kind == MsgKind::ADVERT
+        || kind == MsgKind::CHALLENGE
+        || kind == MsgKind::SOLUTION
+        || kind == MsgKind::SYNC_COUNTS
+        || kind == MsgKind::SYNC_REQUEST
+        || kind == MsgKind::SYNC_RECORDS
+        || kind == MsgKind::TOPOLOGY_QUERY
+        || kind == MsgKind::TOPOLOGY_REPLY
+        || kind == MsgKind::PING
+        || kind == MsgKind::PONG
+}
+
+fn is_sync_date(date: &[u8]) -> bool {
+    date.len() == SYNC_DATE_LEN && date.iter().all(u8::is_ascii_digit)
+}
+
+/// DM として扱う kind（中継せず、payload は暗号化されている）
+pub fn is_dm_kind(kind: u8) -> bool {
+    kind == MsgKind::DM || kind == MsgKind::EPHEMERAL_DM
 }
 
 fn validate_signature_field_lengths(pk_len: u32, sig_len: u32) -> Result<(), ProtocolError> {
@@ -70,18 +207,27 @@ pub struct Message {
     pub timestamp: u64,
     pub public_key: Option<Vec<u8>>, // 32 bytes when present
     pub signature: Option<Vec<u8>>,  // 64 bytes when present
+    /// v2 の署名付きフレームの中継トークン
+    pub hop: Option<[u8; HOP_TOKEN_LEN]>,
+    /// v2 の署名付きフレームに送信者が付ける通し番号（1 から。全ピアへ送るものだけに付ける）
+    pub seq: Option<u64>,
+    /// 至急の印。v2 の署名付き CHAT/DM 系だけに付き、署名の対象になる
+    pub urgent: bool,
 }
 
 impl Message {
     pub fn chat(text: &str, ts: u64) -> Self {
         Self {
-            version: PROTOCOL_VERSION,
+            version: MIN_PROTOCOL_VERSION,
             kind: MsgKind::CHAT,
             attenuation: 0,
             payload: text.as_bytes().to_vec(),
             timestamp: ts,
             public_key: None,
             signature: None,
+            hop: None,
+            seq: None,
+            urgent: false,
         }
     }
 
@@ -91,13 +237,33 @@ impl Message {
 
     pub fn dm_bytes(payload: Vec<u8>, ts: u64) -> Self {
         Self {
-            version: PROTOCOL_VERSION,
+            version: MIN_PROTOCOL_VERSION,
             kind: MsgKind::DM,
             attenuation: 0,
             payload,
             timestamp: ts,
             public_key: None,
             signature: None,
+            hop: None,
+            seq: None,
+            urgent: false,
+        }
+    }
+
+    pub fn ephemeral_dm_bytes(payload: Vec<u8>, ts: u64) -> Self {
+        Self {
+            kind: MsgKind::EPHEMERAL_DM,
+            ..Self::dm_bytes(payload, ts)
+        }
+    }
+
+    pub fn routed_dm(to: &[u8; ROUTE_FINGERPRINT_LEN], encrypted: &[u8], ts: u64) -> Self {
+        let mut p = Vec::with_capacity(ROUTE_FINGERPRINT_LEN + encrypted.len());
+        p.extend_from_slice(to);
+        p.extend_from_slice(encrypted);
+        Self {
+            kind: MsgKind::ROUTED_DM,
+            ..Self::dm_bytes(p, ts)
         }
     }
 
@@ -109,25 +275,238 @@ impl Message {
         p.extend_from_slice(&reason_id.to_be_bytes());
 
         Self {
-            version: PROTOCOL_VERSION,
+            version: MIN_PROTOCOL_VERSION,
             kind: MsgKind::DISCONNECT,
             attenuation: 0,
             payload: p,
             timestamp: ts,
             public_key: None,
             signature: None,
+            hop: None,
+            seq: None,
+            urgent: false,
         }
     }
 
-    pub fn hello(ts: u64, handle: &str) -> Self {
+    /// v1 の枠で送り、attenuation 欄で対応する最大バージョンを伝える。
+    /// ひとことが無ければハンドルだけの従来の形にする（古いノードもそのまま読める）
+    pub fn hello(ts: u64, handle: &str, bio: Option<&str>) -> Self {
+        let payload = match bio {
+            Some(bio) => {
+                let mut p = Vec::with_capacity(5 + handle.len() + bio.len());
+                p.push(HELLO_PROFILE_TAG);
+                p.extend_from_slice(&(handle.len() as u16).to_be_bytes());
+                p.extend_from_slice(handle.as_bytes());
+                p.extend_from_slice(&(bio.len() as u16).to_be_bytes());
+                p.extend_from_slice(bio.as_bytes());
+                p
+            }
+            None => handle.as_bytes().to_vec(),
+        };
         Self {
-            version: PROTOCOL_VERSION,
+            version: MIN_PROTOCOL_VERSION,
             kind: MsgKind::HELLO,
+            attenuation: PROTOCOL_VERSION,
+            payload,
+            timestamp: ts,
+            public_key: None,
+            signature: None,
+            hop: None,
+            seq: None,
+            urgent: false,
+        }
+    }
+
+    pub fn rotate(ts: u64, new_public_key: &[u8]) -> Self {
+        Self {
+            version: MIN_PROTOCOL_VERSION,
+            kind: MsgKind::ROTATE,
             attenuation: 0,
-            payload: handle.as_bytes().to_vec(),
+            payload: new_public_key.to_vec(),
             timestamp: ts,
             public_key: None,
             signature: None,
+            hop: None,
+            seq: None,
+            urgent: false,
+        }
+    }
+
+    pub fn edit(ts: u64, target: &[u8; MESSAGE_ID_LEN], text: &str) -> Self {
+        let mut p = Vec::with_capacity(MESSAGE_ID_LEN + text.len());
+        p.extend_from_slice(target);
+        p.extend_from_slice(text.as_bytes());
+        Self {
+            version: MIN_PROTOCOL_VERSION,
+            kind: MsgKind::EDIT,
+            attenuation: 0,
+            payload: p,
+            timestamp: ts,
+            public_key: None,
+            signature: None,
+            hop: None,
+            seq: None,
+            urgent: false,
+        }
+    }
+
+    /// 返信先付きの Chat
+    pub fn reply(ts: u64, reply_to: &[u8; MESSAGE_ID_LEN], text: &str) -> Self {
+        let mut p = Vec::with_capacity(1 + MESSAGE_ID_LEN + text.len());
+        p.push(CHAT_REPLY_TAG);
+        p.extend_from_slice(reply_to);
+        p.extend_from_slice(text.as_bytes());
+        Self {
+            payload: p,
+            ..Self::chat("", ts)
+        }
+    }
+
+    pub fn topic(ts: u64, text: &str) -> Self {
+        Self {
+            version: MIN_PROTOCOL_VERSION,
+            kind: MsgKind::TOPIC,
+            attenuation: 0,
+            payload: text.as_bytes().to_vec(),
+            timestamp: ts,
+            public_key: None,
+            signature: None,
+            hop: None,
+            seq: None,
+            urgent: false,
+        }
+    }
+
+    pub fn system(ts: u64, text: &str) -> Self {
+        Self {
+            kind: MsgKind::SYSTEM,
+            ..Self::topic(ts, text)
+        }
+    }
+
+    pub fn advert(ts: u64, addr: &str) -> Self {
+        Self {
+            kind: MsgKind::ADVERT,
+            ..Self::topic(ts, addr)
+        }
+    }
+
+    pub fn challenge(ts: u64, difficulty: u8, challenge: &[u8; PUZZLE_CHALLENGE_LEN]) -> Self {
+        let mut p = Vec::with_capacity(1 + PUZZLE_CHALLENGE_LEN);
+        p.push(difficulty);
+        p.extend_from_slice(challenge);
+        Self {
+            kind: MsgKind::CHALLENGE,
+            payload: p,
+            ..Self::topic(ts, "")
+        }
+    }
+
+    pub fn solution(ts: u64, nonce: u64) -> Self {
+        Self {
+            kind: MsgKind::SOLUTION,
+            payload: nonce.to_be_bytes().to_vec(),
+            ..Self::topic(ts, "")
+        }
+    }
+
+    /// 生存確認。隣のピアとだけやり取りする
+    pub fn ping(ts: u64, id: u64) -> Self {
+        Self {
+            kind: MsgKind::PING,
+            payload: id.to_be_bytes().to_vec(),
+            ..Self::topic(ts, "")
+        }
+    }
+
+    pub fn pong(ts: u64, id: u64) -> Self {
+        Self {
+            kind: MsgKind::PONG,
+            ..Self::ping(ts, id)
+        }
+    }
+
+    /// counts は (YYYYMMDD, 件数)。日付の形式が違うものは入れない
+    pub fn sync_counts(ts: u64, counts: &[(String, u64)]) -> Self {
+        let mut p = Vec::with_capacity(counts.len() * (SYNC_DATE_LEN + 8));
+        for (date, n) in counts {
+            if is_sync_date(date.as_bytes()) {
+                p.extend_from_slice(date.as_bytes());
+                p.extend_from_slice(&n.to_be_bytes());
+            }
+        }
+        Self {
+            kind: MsgKind::SYNC_COUNTS,
+            payload: p,
+            ..Self::topic(ts, "")
+        }
+    }
+
+    pub fn sync_request(ts: u64, date: &str, have: &[[u8; MESSAGE_ID_LEN]]) -> Self {
+        let mut p = Vec::with_capacity(SYNC_DATE_LEN + have.len() * MESSAGE_ID_LEN);
+        p.extend_from_slice(date.as_bytes());
+        for id in have {
+            p.extend_from_slice(id);
+        }
+        Self {
+            kind: MsgKind::SYNC_REQUEST,
+            payload: p,
+            ..Self::topic(ts, "")
+        }
+    }
+
+    /// frames は encode 済みのフレーム。それぞれ長さ (u32) を前に付けて並べる
+    pub fn sync_records(ts: u64, date: &str, frames: &[Vec<u8>]) -> Self {
+        let mut p = date.as_bytes().to_vec();
+        for f in frames {
+            p.extend_from_slice(&(f.len() as u32).to_be_bytes());
+            p.extend_from_slice(f);
+        }
+        Self {
+            kind: MsgKind::SYNC_RECORDS,
+            payload: p,
+            ..Self::topic(ts, "")
+        }
+    }
+
+    pub fn topology_query(ts: u64, id: &[u8; TOPOLOGY_QUERY_ID_LEN]) -> Self {
+        Self {
+            kind: MsgKind::TOPOLOGY_QUERY,
+            payload: id.to_vec(),
+            ..Self::topic(ts, "")
+        }
+    }
+
+    pub fn topology_reply(
+        ts: u64,
+        id: &[u8; TOPOLOGY_QUERY_ID_LEN],
+        neighbors: &[[u8; ROUTE_FINGERPRINT_LEN]],
+    ) -> Self {
+        let mut p =
+            Vec::with_capacity(TOPOLOGY_QUERY_ID_LEN + neighbors.len() * ROUTE_FINGERPRINT_LEN);
+        p.extend_from_slice(id);
+        for fp in neighbors {
+            p.extend_from_slice(fp);
+        }
+        Self {
+            kind: MsgKind::TOPOLOGY_REPLY,
+            payload: p,
+            ..Self::topic(ts, "")
+        }
+    }
+
+    pub fn delete(ts: u64, target: &[u8; MESSAGE_ID_LEN]) -> Self {
+        Self {
+            version: MIN_PROTOCOL_VERSION,
+            kind: MsgKind::DELETE,
+            attenuation: 0,
+            payload: target.to_vec(),
+            timestamp: ts,
+            public_key: None,
+            signature: None,
+            hop: None,
+            seq: None,
+            urgent: false,
         }
     }
 
@@ -141,13 +520,100 @@ impl Message {
         self.public_key = Some(pk);
         self
     }
+
+    /// 署名前に通し番号を付ける（v2 でだけ送られる）
+    pub fn with_seq(mut self, seq: u64) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+
+    /// v2 で署名する前の準備。公開鍵と中継トークンの種 (乱数) を付け、減衰値 0 から始める
+    pub fn for_signing(mut self, pk: Vec<u8>, seed: [u8; HOP_TOKEN_LEN]) -> Self {
+        self.version = PROTOCOL_VERSION;
+        self.attenuation = 0;
+        self.public_key = Some(pk);
+        self.signature = None;
+        self.hop = Some(seed);
+        self
+    }
+
+    /// 至急の印を付ける（署名前に。v1 には載らない）
+    pub fn with_urgent(mut self, urgent: bool) -> Self {
+        self.urgent = urgent;
+        self
+    }
+
+    /// v1 で署名し直すための写し（署名と中継トークン、至急の印を外す）
+    pub fn downgraded(&self) -> Self {
+        Self {
+            version: MIN_PROTOCOL_VERSION,
+            signature: None,
+            hop: None,
+            seq: None,
+            urgent: false,
+            ..self.clone()
+        }
+    }
+
+    /// 中継1回分。減衰値を進め、v2 なら中継トークンも1段ハッシュする
+    pub fn attenuate(&mut self) {
+        self.attenuation = self.attenuation.saturating_add(1);
+        if let Some(h) = self.hop.as_mut() {
+            *h = hash_token(h);
+        }
+    }
 }
 
-/// Errors that can occur during decoding.
+fn hash_token(token: &[u8; HOP_TOKEN_LEN]) -> [u8; HOP_TOKEN_LEN] {
+    let d = ring::digest::digest(&ring::digest::SHA256, token);
+    let mut out = [0u8; HOP_TOKEN_LEN];
+    out.copy_from_slice(d.as_ref());
+    out
+}
+
+/// 中継トークンを残りの段数だけハッシュした値。どこで受け取っても送信時と同じになる
+fn hop_anchor(msg: &Message) -> Option<[u8; HOP_TOKEN_LEN]> {
+    let mut h = msg.hop?;
+    for _ in msg.attenuation..MAX_ATTENUATION {
+        h = hash_token(&h);
+    }
+    Some(h)
+}
+
+/// 符号化済みのフレームに中継1回分 (Message::attenuate と同じ) を施す。書き換えるのは
+/// 減衰値と v2 の中継トークンだけで、公開鍵・署名・本文のバイト列には触れない
+/// （本文を圧縮していてもそのまま流せる）。フレームとして短すぎれば false
+pub fn attenuate_frame(frame: &mut [u8]) -> bool {
+    if frame.len() < HEADER_LEN {
+        return false;
+    }
+    let field = |at: usize| u32::from_be_bytes(frame[at..at + 4].try_into().unwrap()) as usize;
+    let (pk_len, sig_len) = (field(7), field(11));
+    let hop_at = HEADER_LEN + pk_len + sig_len;
+    let v2_signed = frame[0] >= 2 && sig_len > 0;
+    if v2_signed && frame.len() < hop_at + HOP_TOKEN_LEN {
+        return false;
+    }
+    frame[2] = frame[2].saturating_add(1);
+    if v2_signed {
+        let hop: &mut [u8; HOP_TOKEN_LEN] = (&mut frame[hop_at..hop_at + HOP_TOKEN_LEN])
+            .try_into()
+            .unwrap();
+        *hop = hash_token(hop);
+    }
+    true
+}
 
+/// HELLO から読み取る、相手と話すバージョン（v1 のノードは attenuation 欄が 0）
+pub fn hello_version(msg: &Message) -> u8 {
+    msg.attenuation
+        .max(msg.version)
+        .clamp(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)
+}
+
+/// Errors that can occur during decoding.
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[allow(dead_code)]
-
 pub enum ProtocolError {
     /// Header present but length field is implausible (too large).
     LengthTooLarge(u32),
@@ -172,6 +638,9 @@ pub enum ProtocolError {
 
     /// Attenuation value is abnormal
     BadAttenuation(u8),
+
+    /// Incomplete data buffered beyond the decoder's cap.
+    BufferOverflow(usize),
 }
 
 impl fmt::Display for ProtocolError {
@@ -200,6 +669,8 @@ impl fmt::Display for ProtocolError {
             ProtocolError::BadSignature => write!(f, "bad signature"),
 
             ProtocolError::BadAttenuation(a) => write!(f, "bad attenuation: {}", a),
+
+            ProtocolError::BufferOverflow(n) => write!(f, "buffer overflow: {} bytes", n),
         }
     }
 }
@@ -208,9 +679,25 @@ impl std::error::Error for ProtocolError {}
 
 pub fn encode(msg: &Message) -> Vec<u8> {
     debug_assert!(is_supported_kind(msg.kind));
+    debug_assert!(!msg.urgent || is_urgent_capable_kind(msg.kind));
     let pk_len = msg.public_key.as_ref().map_or(0u32, |pk| pk.len() as u32);
     let sig_len = msg.signature.as_ref().map_or(0u32, |sig| sig.len() as u32);
     debug_assert!(validate_signature_field_lengths(pk_len, sig_len).is_ok());
+    let v2_signed = msg.version >= 2 && sig_len > 0;
+    debug_assert!(
+        !msg.urgent || v2_signed,
+        "至急の印は v2 の署名付きフレームだけ"
+    );
+    let hop: &[u8] = match (v2_signed, msg.hop.as_ref()) {
+        (true, Some(h)) => h,
+        (true, None) => {
+            debug_assert!(false, "v2 の署名付きフレームに中継トークンが無い");
+            &[0u8; HOP_TOKEN_LEN]
+        }
+        (false, _) => &[],
+    };
+    let seq = v2_signed.then(|| msg.seq.unwrap_or(0).to_be_bytes());
+    let seq: &[u8] = seq.as_ref().map_or(&[], |s| s);
 
     let payload_len = msg.payload.len() as u32;
 
@@ -224,12 +711,13 @@ pub fn encode(msg: &Message) -> Vec<u8> {
         None => (0u32, &[][..]),
     };
 
-    let mut out =
-        Vec::with_capacity(HEADER_LEN + pk_bytes.len() + sig_bytes.len() + msg.payload.len());
+    let mut out = Vec::with_capacity(
+        HEADER_LEN + pk_bytes.len() + sig_bytes.len() + hop.len() + seq.len() + msg.payload.len(),
+    );
 
     out.push(msg.version);
 
-    out.push(msg.kind);
+    out.push(wire_kind(msg));
 
     out.push(msg.attenuation);
     out.extend_from_slice(&payload_len.to_be_bytes());
@@ -244,28 +732,61 @@ pub fn encode(msg: &Message) -> Vec<u8> {
 
     out.extend_from_slice(sig_bytes);
 
+    out.extend_from_slice(hop);
+
+    out.extend_from_slice(seq);
+
     out.extend_from_slice(&msg.payload);
 
     out
 }
 
+/// 受信したメッセージと、届いたときのバイト列。中継では raw を使い、
+/// 復号したものを符号化し直さない（署名したバイト列がそのまま次へ届く）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub msg: Message,
+    pub raw: Vec<u8>,
+}
+
+impl Frame {
+    /// 手元で組み立てたメッセージを、符号化したバイト列と組にする
+    pub fn encoded(msg: Message) -> Self {
+        let raw = encode(&msg);
+        Self { msg, raw }
+    }
+}
+
 /// Streaming decoder that can accept partial chunks and emit complete messages.
 #[allow(dead_code)]
 pub struct Decoder {
     buf: Vec<u8>,
     max_payload: u32,
+    max_buffered: usize,
 }
 
 #[allow(dead_code)]
 impl Decoder {
     /// Create a decoder with a maximum allowed payload (for DoS protection).
     pub fn with_max_payload(max_payload: u32) -> Self {
+        // 最大フレーム2つ分までは未完成のまま溜めてよい
+        let max_frame = HEADER_LEN
+            + ED25519_PUBLIC_KEY_LEN as usize
+            + ED25519_SIGNATURE_LEN as usize
+            + max_payload as usize;
         Self {
             buf: Vec::new(),
             max_payload,
+            max_buffered: max_frame.saturating_mul(2),
         }
     }
 
+    /// Override the cap on buffered (incomplete) bytes.
+    pub fn with_max_buffered(mut self, max_buffered: usize) -> Self {
+        self.max_buffered = max_buffered;
+        self
+    }
+
     /// Create a decoder with a default payload limit.
     pub fn new() -> Self {
         Self::with_max_payload(DEFAULT_MAX_PAYLOAD)
@@ -277,6 +798,11 @@ impl Decoder {
     }
 
     pub fn drain(&mut self) -> Result<Vec<Message>, ProtocolError> {
+        Ok(self.drain_frames()?.into_iter().map(|f| f.msg).collect())
+    }
+
+    /// drain と同じだが、各メッセージを届いたときのバイト列と組にして返す
+    pub fn drain_frames(&mut self) -> Result<Vec<Frame>, ProtocolError> {
         let mut out = Vec::new();
         let mut offset = 0usize;
 
@@ -288,7 +814,7 @@ impl Decoder {
             let base = offset;
             let version = self.buf[base];
 
-            if version != PROTOCOL_VERSION {
+            if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
                 if offset > 0 {
                     self.buf.drain(..offset);
                 }
@@ -296,8 +822,10 @@ impl Decoder {
             }
 
             let kind_byte = self.buf[base + 1];
+            let urgent = kind_byte & MsgKind::URGENT_FLAG != 0;
+            let kind = kind_byte & !MsgKind::URGENT_FLAG;
 
-            if !is_supported_kind(kind_byte) {
+            if !is_supported_kind(kind) || (urgent && !is_urgent_capable_kind(kind)) {
                 if offset > 0 {
                     self.buf.drain(..offset);
                 }
@@ -345,6 +873,13 @@ impl Decoder {
                 }
                 return Err(e);
             }
+            // 至急の印は署名で守られる v2 のフレームにしか付かない
+            if urgent && (version < 2 || sig_len == 0) {
+                if offset > 0 {
+                    self.buf.drain(..offset);
+                }
+                return Err(ProtocolError::UnsupportedKind(kind_byte));
+            }
 
             let timestamp = u64::from_be_bytes([
                 self.buf[base + 15],
@@ -357,7 +892,16 @@ impl Decoder {
                 self.buf[base + 22],
             ]);
 
-            let needed = HEADER_LEN + pk_len as usize + sig_len as usize + payload_len as usize;
+            let trailer_len = if version >= 2 && sig_len > 0 {
+                HOP_TOKEN_LEN + SEQ_LEN
+            } else {
+                0
+            };
+            let needed = HEADER_LEN
+                + pk_len as usize
+                + sig_len as usize
+                + trailer_len
+                + payload_len as usize;
 
             if self.buf.len().saturating_sub(offset) < needed {
                 break;
@@ -380,16 +924,35 @@ impl Decoder {
 
             cursor += sig_len as usize;
 
+            let (hop, seq) = if trailer_len > 0 {
+                let mut h = [0u8; HOP_TOKEN_LEN];
+                h.copy_from_slice(&self.buf[cursor..cursor + HOP_TOKEN_LEN]);
+                let mut n = [0u8; SEQ_LEN];
+                n.copy_from_slice(&self.buf[cursor + HOP_TOKEN_LEN..cursor + trailer_len]);
+                (Some(h), Some(u64::from_be_bytes(n)).filter(|&n| n > 0))
+            } else {
+                (None, None)
+            };
+
+            cursor += trailer_len;
+
             let payload = self.buf[cursor..cursor + payload_len as usize].to_vec();
 
-            out.push(Message {
+            let msg = Message {
                 version,
-                kind: kind_byte,
+                kind,
                 attenuation,
                 payload,
                 timestamp,
                 public_key: pk,
                 signature: sig,
+                hop,
+                seq,
+                urgent,
+            };
+            out.push(Frame {
+                msg,
+                raw: self.buf[base..base + needed].to_vec(),
             });
             offset += needed;
         }
@@ -398,6 +961,12 @@ impl Decoder {
             self.buf.drain(..offset);
         }
 
+        // フレームにならないまま溜まり続けるのを防ぐ。完成したフレームを切り出せた回でも、
+        // 残りの未完成部分が上限を超えていれば止める
+        if self.buf.len() > self.max_buffered {
+            return Err(ProtocolError::BufferOverflow(self.buf.len()));
+        }
+
         Ok(out)
     }
 
@@ -405,12 +974,38 @@ impl Decoder {
     pub fn buffered_len(&self) -> usize {
         self.buf.len()
     }
+
+    /// Cap on buffered (incomplete) bytes before drain reports an overflow.
+    pub fn max_buffered(&self) -> usize {
+        self.max_buffered
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub fn signing_bytes(msg: &Message) -> Vec<u8> {
+    let mut v = content_bytes(msg);
+    v[0] = msg.version;
+    if msg.version >= 2 {
+        // 至急の印は署名にだけ含める（メッセージIDは変えない）
+        v[1] = wire_kind(msg);
+        v.extend_from_slice(msg.public_key.as_deref().unwrap_or_default());
+        v.extend_from_slice(&hop_anchor(msg).unwrap_or_default());
+        v.extend_from_slice(&msg.seq.unwrap_or(0).to_be_bytes());
+    }
+    v
+}
+
+/// 版によらない署名対象の本体 (v1 の署名対象と同じ並び)。メッセージIDはこれから作るので、
+/// v1 で署名し直した写しとも同じ ID になる
+pub fn content_bytes(msg: &Message) -> Vec<u8> {
     let mut v = Vec::with_capacity(14 + msg.payload.len());
 
-    v.push(msg.version);
+    v.push(MIN_PROTOCOL_VERSION);
 
     v.push(msg.kind);
 
@@ -440,6 +1035,273 @@ pub fn disconnect_reason_id(msg: &Message) -> Option<u32> {
     ]))
 }
 
+/// 鍵ローテーションの新公開鍵を取得（payload が公開鍵長である必要）。
+pub fn rotation_new_key(msg: &Message) -> Option<&[u8]> {
+    if msg.kind != MsgKind::ROTATE || msg.payload.len() != ED25519_PUBLIC_KEY_LEN as usize {
+        return None;
+    }
+    Some(&msg.payload)
+}
+
+/// EDIT/DELETE の対象IDと本文を取得（DELETE の本文は空）。
+pub fn amend_target(msg: &Message) -> Option<([u8; MESSAGE_ID_LEN], &[u8])> {
+    if msg.kind != MsgKind::EDIT && msg.kind != MsgKind::DELETE {
+        return None;
+    }
+    if msg.payload.len() < MESSAGE_ID_LEN
+        || (msg.kind == MsgKind::DELETE && msg.payload.len() != MESSAGE_ID_LEN)
+    {
+        return None;
+    }
+    let (id, rest) = msg.payload.split_at(MESSAGE_ID_LEN);
+    Some((id.try_into().ok()?, rest))
+}
+
+/// 返信先付きの Chat なら (返信先ID, 本文) に分ける。
+fn reply_parts(msg: &Message) -> Option<([u8; MESSAGE_ID_LEN], &[u8])> {
+    if msg.kind != MsgKind::CHAT {
+        return None;
+    }
+    let (&tag, rest) = msg.payload.split_first()?;
+    if tag != CHAT_REPLY_TAG {
+        return None;
+    }
+    let (id, text) = rest.split_first_chunk::<MESSAGE_ID_LEN>()?;
+    Some((*id, text))
+}
+
+/// 返信先付きの Chat の返信先IDを取得。
+pub fn reply_target(msg: &Message) -> Option<[u8; MESSAGE_ID_LEN]> {
+    reply_parts(msg).map(|(id, _)| id)
+}
+
+/// ROUTED_DM の宛先指紋と暗号文を取得。
+pub fn routed_dm_parts(msg: &Message) -> Option<([u8; ROUTE_FINGERPRINT_LEN], &[u8])> {
+    if msg.kind != MsgKind::ROUTED_DM || msg.payload.len() < ROUTE_FINGERPRINT_LEN {
+        return None;
+    }
+    let (to, rest) = msg.payload.split_at(ROUTE_FINGERPRINT_LEN);
+    Some((to.try_into().ok()?, rest))
+}
+
+/// TOPIC の本文を取得（UTF-8 で MAX_TOPIC_CHARS 文字以内である必要）。
+pub fn topic_text(msg: &Message) -> Option<&str> {
+    if msg.kind != MsgKind::TOPIC {
+        return None;
+    }
+    let text = std::str::from_utf8(&msg.payload).ok()?;
+    (text.chars().count() <= MAX_TOPIC_CHARS).then_some(text)
+}
+
+/// SYSTEM の本文を取得（UTF-8 で MAX_SYSTEM_CHARS 文字以内である必要）。
+pub fn system_text(msg: &Message) -> Option<&str> {
+    if msg.kind != MsgKind::SYSTEM {
+        return None;
+    }
+    let text = std::str::from_utf8(&msg.payload).ok()?;
+    (text.chars().count() <= MAX_SYSTEM_CHARS).then_some(text)
+}
+
+/// ADVERT の待受アドレスを取得（"ip:port" として読める必要）。
+pub fn advert_addr(msg: &Message) -> Option<std::net::SocketAddr> {
+    if msg.kind != MsgKind::ADVERT {
+        return None;
+    }
+    std::str::from_utf8(&msg.payload).ok()?.parse().ok()
+}
+
+/// HELLO のハンドルとひとことを取得（ひとことは UTF-8 で MAX_BIO_CHARS 文字以内である必要）。
+/// 先頭が HELLO_PROFILE_TAG でなければハンドルだけの従来の形とみなす
+pub fn hello_parts(msg: &Message) -> Option<(&str, Option<&str>)> {
+    if msg.kind != MsgKind::HELLO {
+        return None;
+    }
+    let p = msg.payload.as_slice();
+    if p.first() != Some(&HELLO_PROFILE_TAG) {
+        return Some((std::str::from_utf8(p).ok()?, None));
+    }
+    let (handle, rest) = length_prefixed(&p[1..])?;
+    let (bio, rest) = length_prefixed(rest)?;
+    if !rest.is_empty() {
+        return None;
+    }
+    let handle = std::str::from_utf8(handle).ok()?;
+    let bio = std::str::from_utf8(bio).ok()?;
+    (bio.chars().count() <= MAX_BIO_CHARS).then_some((handle, Some(bio)))
+}
+
+/// 長さ(u16) || 本体 を 1 つ読み、本体と残りを返す
+fn length_prefixed(b: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (len, rest) = b.split_first_chunk::<2>()?;
+    let len = u16::from_be_bytes(*len) as usize;
+    (rest.len() >= len).then(|| rest.split_at(len))
+}
+
+/// CHALLENGE の難易度と問題を取得。
+pub fn challenge_parts(msg: &Message) -> Option<(u8, [u8; PUZZLE_CHALLENGE_LEN])> {
+    if msg.kind != MsgKind::CHALLENGE || msg.payload.len() != 1 + PUZZLE_CHALLENGE_LEN {
+        return None;
+    }
+    Some((msg.payload[0], msg.payload[1..].try_into().ok()?))
+}
+
+/// SOLUTION の nonce を取得。
+pub fn solution_nonce(msg: &Message) -> Option<u64> {
+    if msg.kind != MsgKind::SOLUTION {
+        return None;
+    }
+    Some(u64::from_be_bytes(msg.payload.as_slice().try_into().ok()?))
+}
+
+/// PING / PONG の番号を取得。
+pub fn ping_id(msg: &Message) -> Option<u64> {
+    if msg.kind != MsgKind::PING && msg.kind != MsgKind::PONG {
+        return None;
+    }
+    Some(u64::from_be_bytes(msg.payload.as_slice().try_into().ok()?))
+}
+
+/// signing_bytes の逆。署名は付かない。
+/// v1 は公開鍵も付かず attenuation は 0、v2 は公開鍵と anchor を戻し、これ以上中継されない減衰値にする
+pub fn from_signing_bytes(b: &[u8]) -> Option<Message> {
+    let (&version, rest) = b.split_first()?;
+    let (&kind_byte, rest) = rest.split_first()?;
+    let (len, rest) = rest.split_first_chunk::<4>()?;
+    let (ts, rest) = rest.split_first_chunk::<8>()?;
+    let len = u32::from_be_bytes(*len) as usize;
+    let urgent = kind_byte & MsgKind::URGENT_FLAG != 0;
+    let kind = kind_byte & !MsgKind::URGENT_FLAG;
+    if !is_supported_kind(kind) || (urgent && !is_urgent_capable_kind(kind)) || len > rest.len() {
+        return None;
+    }
+    if urgent && version != PROTOCOL_VERSION {
+        return None;
+    }
+    let (payload, trailer) = rest.split_at(len);
+    let (attenuation, public_key, hop, seq) = match version {
+        MIN_PROTOCOL_VERSION if trailer.is_empty() => (0, None, None, None),
+        PROTOCOL_VERSION => {
+            let (pk, rest) = trailer.split_first_chunk::<32>()?;
+            let (anchor, seq) = rest.split_first_chunk::<HOP_TOKEN_LEN>()?;
+            let seq = u64::from_be_bytes(seq.try_into().ok()?);
+            let seq = Some(seq).filter(|&n| n > 0);
+            (MAX_ATTENUATION, Some(pk.to_vec()), Some(*anchor), seq)
+        }
+        _ => return None,
+    };
+    Some(Message {
+        version,
+        kind,
+        attenuation,
+        payload: payload.to_vec(),
+        timestamp: u64::from_be_bytes(*ts),
+        public_key,
+        signature: None,
+        hop,
+        seq,
+        urgent,
+    })
+}
+
+/// SYNC_COUNTS の (YYYYMMDD, 件数) を取得。
+pub fn sync_counts(msg: &Message) -> Option<Vec<(String, u64)>> {
+    const ENTRY: usize = SYNC_DATE_LEN + 8;
+    if msg.kind != MsgKind::SYNC_COUNTS || !msg.payload.len().is_multiple_of(ENTRY) {
+        return None;
+    }
+    msg.payload
+        .chunks(ENTRY)
+        .map(|e| {
+            let (date, n) = e.split_at(SYNC_DATE_LEN);
+            is_sync_date(date).then(|| {
+                (
+                    String::from_utf8_lossy(date).into_owned(),
+                    u64::from_be_bytes(n.try_into().unwrap()),
+                )
+            })
+        })
+        .collect()
+}
+
+/// SYNC_REQUEST の日付と、相手が既に持っているメッセージIDを取得。
+pub fn sync_request_parts(msg: &Message) -> Option<(String, Vec<[u8; MESSAGE_ID_LEN]>)> {
+    if msg.kind != MsgKind::SYNC_REQUEST || msg.payload.len() < SYNC_DATE_LEN {
+        return None;
+    }
+    let (date, ids) = msg.payload.split_at(SYNC_DATE_LEN);
+    if !is_sync_date(date) || !ids.len().is_multiple_of(MESSAGE_ID_LEN) {
+        return None;
+    }
+    let ids = ids
+        .chunks(MESSAGE_ID_LEN)
+        .map(|id| id.try_into().unwrap())
+        .collect();
+    Some((String::from_utf8_lossy(date).into_owned(), ids))
+}
+
+/// TOPOLOGY_QUERY の問い合わせIDを取得。
+pub fn topology_query_id(msg: &Message) -> Option<[u8; TOPOLOGY_QUERY_ID_LEN]> {
+    if msg.kind != MsgKind::TOPOLOGY_QUERY {
+        return None;
+    }
+    msg.payload.as_slice().try_into().ok()
+}
+
+/// TOPOLOGY_REPLY の (問い合わせID, 隣接ピアの指紋) を取得。
+pub fn topology_reply_parts(
+    msg: &Message,
+) -> Option<(
+    [u8; TOPOLOGY_QUERY_ID_LEN],
+    Vec<[u8; ROUTE_FINGERPRINT_LEN]>,
+)> {
+    if msg.kind != MsgKind::TOPOLOGY_REPLY {
+        return None;
+    }
+    let (id, rest) = msg.payload.split_first_chunk::<TOPOLOGY_QUERY_ID_LEN>()?;
+    if !rest.len().is_multiple_of(ROUTE_FINGERPRINT_LEN) {
+        return None;
+    }
+    let neighbors = rest
+        .chunks_exact(ROUTE_FINGERPRINT_LEN)
+        .map(|c| c.try_into().unwrap())
+        .collect();
+    Some((*id, neighbors))
+}
+
+/// SYNC_RECORDS の日付と、中のメッセージを取得（1つでも壊れていれば None）。
+pub fn sync_records_parts(msg: &Message) -> Option<(String, Vec<Message>)> {
+    if msg.kind != MsgKind::SYNC_RECORDS || msg.payload.len() < SYNC_DATE_LEN {
+        return None;
+    }
+    let (date, mut rest) = msg.payload.split_at(SYNC_DATE_LEN);
+    if !is_sync_date(date) {
+        return None;
+    }
+    let mut out = Vec::new();
+    while !rest.is_empty() {
+        let (len, tail) = rest.split_first_chunk::<4>()?;
+        let len = u32::from_be_bytes(*len) as usize;
+        if tail.len() < len {
+            return None;
+        }
+        let (frame, tail) = tail.split_at(len);
+        let mut d = Decoder::new();
+        d.feed(frame);
+        let mut msgs = d.drain().ok()?;
+        if msgs.len() != 1 || d.buffered_len() != 0 {
+            return None;
+        }
+        out.push(msgs.remove(0));
+        rest = tail;
+    }
+    Some((String::from_utf8_lossy(date).into_owned(), out))
+}
+
+/// Chat の本文部分（返信先付きなら返信先IDを除く）。
+pub fn chat_text(msg: &Message) -> &[u8] {
+    reply_parts(msg).map_or(&msg.payload, |(_, text)| text)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -542,7 +1404,7 @@ mod tests {
 
     #[test]
     fn test_hello_message() {
-        let msg = Message::hello(5000, "@alice");
+        let msg = Message::hello(5000, "@alice", None);
         let encoded = encode(&msg);
 
         let mut decoder = Decoder::new();
@@ -552,6 +1414,36 @@ mod tests {
         assert_eq!(decoded.len(), 1);
         assert_eq!(decoded[0].kind, MsgKind::HELLO);
         assert_eq!(String::from_utf8_lossy(&decoded[0].payload), "@alice");
+        // ひとことの無い HELLO は従来どおりハンドルだけ
+        assert_eq!(hello_parts(&decoded[0]), Some(("@alice", None)));
+    }
+
+    #[test]
+    fn hello_with_bio_round_trips() {
+        let msg = Message::hello(5000, "@alice", Some("作業中です"));
+        let decoded = decode_one(&encode(&msg));
+        assert_eq!(decoded.payload[0], HELLO_PROFILE_TAG);
+        assert_eq!(hello_parts(&decoded), Some(("@alice", Some("作業中です"))));
+
+        // 古いノードが送るハンドルだけの HELLO もそのまま読める
+        let legacy = Message {
+            payload: b"@bob".to_vec(),
+            ..msg.clone()
+        };
+        assert_eq!(
+            hello_parts(&decode_one(&encode(&legacy))),
+            Some(("@bob", None))
+        );
+
+        // 長すぎるひとことや長さの合わない形は読まない
+        let long = "x".repeat(MAX_BIO_CHARS + 1);
+        assert_eq!(hello_parts(&Message::hello(1, "@alice", Some(&long))), None);
+        let mut broken = msg.clone();
+        broken.payload.pop();
+        assert_eq!(hello_parts(&broken), None);
+        broken.payload.extend_from_slice(b"!!");
+        assert_eq!(hello_parts(&broken), None);
+        assert_eq!(hello_parts(&Message::chat("@alice", 1)), None);
     }
 
     #[test]
@@ -570,6 +1462,90 @@ mod tests {
         assert_eq!(reason, Some(42));
     }
 
+    #[test]
+    fn test_rotate_message() {
+        let msg = Message::rotate(7000, &[8u8; 32]);
+        let encoded = encode(&msg);
+
+        let mut decoder = Decoder::new();
+        decoder.feed(&encoded);
+        let decoded = decoder.drain().unwrap();
+
+        assert_eq!(decoded[0].kind, MsgKind::ROTATE);
+        assert_eq!(rotation_new_key(&decoded[0]), Some(&[8u8; 32][..]));
+        assert_eq!(rotation_new_key(&Message::rotate(7000, &[8u8; 31])), None);
+    }
+
+    #[test]
+    fn test_edit_and_delete_messages() {
+        let target = [3u8; MESSAGE_ID_LEN];
+        let mut decoder = Decoder::new();
+        decoder.feed(&encode(&Message::edit(8000, &target, "@alice: fixed")));
+        decoder.feed(&encode(&Message::delete(8001, &target)));
+        let decoded = decoder.drain().unwrap();
+
+        assert_eq!(decoded[0].kind, MsgKind::EDIT);
+        assert_eq!(
+            amend_target(&decoded[0]),
+            Some((target, &b"@alice: fixed"[..]))
+        );
+        assert_eq!(decoded[1].kind, MsgKind::DELETE);
+        assert_eq!(amend_target(&decoded[1]), Some((target, &[][..])));
+        assert_eq!(amend_target(&Message::chat("x", 1)), None);
+    }
+
+    #[test]
+    fn test_reply_message() {
+        let target = [4u8; MESSAGE_ID_LEN];
+        let mut decoder = Decoder::new();
+        decoder.feed(&encode(&Message::reply(9000, &target, "@bob: そうだね")));
+        let decoded = decoder.drain().unwrap();
+
+        assert_eq!(decoded[0].kind, MsgKind::CHAT);
+        assert_eq!(reply_target(&decoded[0]), Some(target));
+        assert_eq!(chat_text(&decoded[0]), "@bob: そうだね".as_bytes());
+        assert_eq!(reply_target(&Message::chat("x", 1)), None);
+        assert_eq!(chat_text(&Message::chat("x", 1)), b"x");
+        // 返信先が欠けていれば普通の本文として読む
+        let short = Message::chat("\u{1}abc", 1);
+        assert_eq!(reply_target(&short), None);
+        assert_eq!(chat_text(&short), "\u{1}abc".as_bytes());
+        // 8 番 (返信専用の種別だったもの) は知らない種別として弾く
+        let mut old = encode(&Message::chat("@bob: hi", 1));
+        old[1] = 8;
+        let mut decoder = Decoder::new();
+        decoder.feed(&old);
+        assert!(matches!(
+            decoder.drain(),
+            Err(ProtocolError::UnsupportedKind(8))
+        ));
+    }
+
+    #[test]
+    fn test_topic_message() {
+        let mut decoder = Decoder::new();
+        decoder.feed(&encode(&Message::topic(9100, "今日の話題")));
+        let decoded = decoder.drain().unwrap();
+
+        assert_eq!(decoded[0].kind, MsgKind::TOPIC);
+        assert_eq!(topic_text(&decoded[0]), Some("今日の話題"));
+        let long = "あ".repeat(MAX_TOPIC_CHARS + 1);
+        assert_eq!(topic_text(&Message::topic(1, &long)), None);
+        assert_eq!(topic_text(&Message::chat("x", 1)), None);
+    }
+
+    #[test]
+    fn test_routed_dm_message() {
+        let to = [5u8; ROUTE_FINGERPRINT_LEN];
+        let mut decoder = Decoder::new();
+        decoder.feed(&encode(&Message::routed_dm(&to, &[1, 2, 3], 9200)));
+        let decoded = decoder.drain().unwrap();
+
+        assert_eq!(decoded[0].kind, MsgKind::ROUTED_DM);
+        assert_eq!(routed_dm_parts(&decoded[0]), Some((to, &[1u8, 2, 3][..])));
+        assert_eq!(routed_dm_parts(&Message::dm("x", 1)), None);
+    }
+
     #[test]
     fn test_malformed_version() {
         let mut invalid = vec![99u8]; // invalid version
@@ -684,6 +1660,43 @@ mod tests {
         assert!(matches!(result, Err(ProtocolError::LengthTooLarge(_))));
     }
 
+    #[test]
+    fn test_buffer_overflow_on_incomplete_prefix() {
+        let msg = Message::chat(&"x".repeat(200), 1);
+        let encoded = encode(&msg);
+
+        let mut decoder = Decoder::with_max_payload(1024).with_max_buffered(64);
+        decoder.feed(&encoded[..100]);
+        let result = decoder.drain();
+
+        assert!(matches!(result, Err(ProtocolError::BufferOverflow(100))));
+    }
+
+    #[test]
+    fn test_buffer_overflow_after_complete_frame() {
+        // 完成したフレームの後ろに、上限を超える未完成部分が続く
+        let first = encode(&Message::chat("hi", 1));
+        let second = encode(&Message::chat(&"x".repeat(200), 2));
+        let mut decoder = Decoder::with_max_payload(1024).with_max_buffered(64);
+        decoder.feed(&first);
+        decoder.feed(&second[..100]);
+        let result = decoder.drain();
+
+        assert!(matches!(result, Err(ProtocolError::BufferOverflow(100))));
+    }
+
+    #[test]
+    fn test_default_buffer_cap_allows_max_frame() {
+        let msg = Message::chat(&"x".repeat(64), 1).with_key_sig(vec![1u8; 32], vec![2u8; 64]);
+        let encoded = encode(&msg);
+
+        let mut decoder = Decoder::with_max_payload(64);
+        decoder.feed(&encoded[..encoded.len() - 1]);
+        assert_eq!(decoder.drain().unwrap().len(), 0);
+        decoder.feed(&encoded[encoded.len() - 1..]);
+        assert_eq!(decoder.drain().unwrap().len(), 1);
+    }
+
     #[test]
     fn test_bad_attenuation() {
         let mut msg = vec![PROTOCOL_VERSION, MsgKind::CHAT, 99u8]; // version, kind, bad attenuation (>MAX_ATTENUATION)
@@ -706,7 +1719,7 @@ mod tests {
         assert_eq!(sig_bytes.len(), expected_len);
 
         // バージョン確認
-        assert_eq!(sig_bytes[0], PROTOCOL_VERSION);
+        assert_eq!(sig_bytes[0], MIN_PROTOCOL_VERSION);
         // kind確認
         assert_eq!(sig_bytes[1], MsgKind::CHAT);
     }
@@ -815,9 +1828,12 @@ mod tests {
     fn test_all_message_kinds() {
         let chat = Message::chat("Chat", 1000);
         let dm = Message::dm("DM", 2000);
-        let hello = Message::hello(3000, "@user");
+        let hello = Message::hello(3000, "@user", None);
         let disconnect = Message::disconnect(4000, 1);
+        let edm = Message::ephemeral_dm_bytes(vec![1, 2], 5000);
 
+        assert_eq!(edm.kind, MsgKind::EPHEMERAL_DM);
+        assert!(is_dm_kind(edm.kind) && is_dm_kind(dm.kind) && !is_dm_kind(chat.kind));
         assert_eq!(chat.kind, MsgKind::CHAT);
         assert_eq!(dm.kind, MsgKind::DM);
         assert_eq!(hello.kind, MsgKind::HELLO);
@@ -841,4 +1857,190 @@ mod tests {
         let _ = decoder.drain();
         assert_eq!(decoder.buffered_len(), 0); // After drain
     }
+
+    fn decode_one(frame: &[u8]) -> Message {
+        let mut d = Decoder::new();
+        d.feed(frame);
+        d.drain().unwrap().remove(0)
+    }
+
+    #[test]
+    fn sync_frames_round_trip() {
+        let counts = vec![("20250101".to_string(), 3), ("20250102".to_string(), 0)];
+        let m = Message::sync_counts(1, &counts);
+        assert_eq!(sync_counts(&decode_one(&encode(&m))), Some(counts));
+        // 日付でないものは送らない
+        let m = Message::sync_counts(1, &[("2025-1-1".to_string(), 1)]);
+        assert_eq!(sync_counts(&m), Some(vec![]));
+
+        let ids = [[1u8; MESSAGE_ID_LEN], [2u8; MESSAGE_ID_LEN]];
+        let m = Message::sync_request(1, "20250101", &ids);
+        assert_eq!(
+            sync_request_parts(&m),
+            Some(("20250101".to_string(), ids.to_vec()))
+        );
+        assert_eq!(
+            sync_request_parts(&Message::sync_request(1, "abc", &[])),
+            None
+        );
+
+        let chat = Message::chat("@alice: hi", 42).with_key_sig(vec![7; 32], vec![9; 64]);
+        let m = Message::sync_records(1, "20250101", &[encode(&chat), encode(&chat)]);
+        assert_eq!(
+            sync_records_parts(&decode_one(&encode(&m))),
+            Some(("20250101".to_string(), vec![chat.clone(), chat.clone()]))
+        );
+        let mut broken = m.clone();
+        broken.payload.pop();
+        assert_eq!(sync_records_parts(&broken), None);
+    }
+
+    #[test]
+    fn signing_bytes_can_be_parsed_back() {
+        let reply = Message::reply(1234, &[3; MESSAGE_ID_LEN], "@bob: yes");
+        assert_eq!(
+            from_signing_bytes(&signing_bytes(&reply)),
+            Some(reply.clone())
+        );
+        let mut cut = signing_bytes(&reply);
+        cut.pop();
+        assert_eq!(from_signing_bytes(&cut), None);
+    }
+
+    #[test]
+    fn both_versions_decode_and_hello_advertises_the_newest() {
+        let v1 = Message::chat("hi", 1).with_key_sig(vec![1; 32], vec![2; 64]);
+        let v2 = Message::chat("hi", 1)
+            .for_signing(vec![1; 32], [3; HOP_TOKEN_LEN])
+            .with_key_sig(vec![1; 32], vec![2; 64]);
+        assert_eq!(decode_one(&encode(&v1)), v1);
+        assert_eq!(decode_one(&encode(&v2)), v2);
+        assert_eq!(
+            encode(&v2).len(),
+            encode(&v1).len() + HOP_TOKEN_LEN + SEQ_LEN
+        );
+
+        // 中継トークンは1段ずつ進み、anchor は変わらない
+        let mut relayed = v2.clone();
+        relayed.attenuate();
+        assert_ne!(relayed.hop, v2.hop);
+        assert_eq!(signing_bytes(&relayed), signing_bytes(&v2));
+        // v2 の署名対象からも元の投稿を組み立て直せる
+        let rebuilt = from_signing_bytes(&signing_bytes(&v2)).unwrap();
+        assert_eq!(rebuilt.public_key, v2.public_key);
+        assert_eq!(signing_bytes(&rebuilt), signing_bytes(&v2));
+
+        let hello = Message::hello(1, "@alice", None);
+        assert_eq!(hello.version, MIN_PROTOCOL_VERSION);
+        assert_eq!(hello_version(&hello), PROTOCOL_VERSION);
+        // v1 のノードの HELLO は attenuation 欄が 0
+        let old = Message {
+            attenuation: 0,
+            ..hello
+        };
+        assert_eq!(hello_version(&old), MIN_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn attenuating_the_frame_touches_only_the_hop_fields() {
+        // 圧縮済みのような読めない本文でも、本文と署名のバイト列はそのまま残る
+        let opaque: Vec<u8> = (0..4096u32).map(|i| (i * 7 % 251) as u8).collect();
+        let v2 = Message {
+            payload: opaque.clone(),
+            ..Message::chat("", 1)
+        }
+        .with_seq(9)
+        .for_signing(vec![1; 32], [3; HOP_TOKEN_LEN])
+        .with_key_sig(vec![1; 32], vec![2; 64]);
+        let v1 = Message::chat("hi", 1).with_key_sig(vec![1; 32], vec![2; 64]);
+        for msg in [v2, v1, Message::chat("plain", 1)] {
+            let received = encode(&msg);
+            let mut frame = received.clone();
+            assert!(attenuate_frame(&mut frame));
+            let mut expected = msg.clone();
+            expected.attenuate();
+            assert_eq!(frame, encode(&expected));
+            let decoded = decode_one(&frame);
+            assert_eq!(decoded.payload, msg.payload);
+            assert_eq!(decoded.signature, msg.signature);
+            assert_eq!(signing_bytes(&decoded), signing_bytes(&msg));
+            let changed = (0..frame.len())
+                .filter(|&i| frame[i] != received[i])
+                .count();
+            assert!(changed <= 1 + HOP_TOKEN_LEN, "{}", changed);
+        }
+        assert!(!attenuate_frame(&mut [2u8; HEADER_LEN - 1]));
+    }
+
+    #[test]
+    fn urgent_flag_round_trips_under_the_signature() {
+        let plain = Message::chat("@alice: 障害発生", 1000)
+            .with_seq(1)
+            .for_signing(vec![1u8; 32], [2u8; HOP_TOKEN_LEN]);
+        let urgent = plain.clone().with_urgent(true);
+        let decoded = decode_one(&encode(
+            &urgent.clone().with_key_sig(vec![1u8; 32], vec![3u8; 64]),
+        ));
+        assert!(decoded.urgent);
+        assert_eq!(decoded.kind, MsgKind::CHAT);
+
+        // 印は署名の対象だが、メッセージIDの元（content_bytes）は変えない
+        assert_ne!(signing_bytes(&urgent), signing_bytes(&plain));
+        assert_eq!(content_bytes(&urgent), content_bytes(&plain));
+        assert!(from_signing_bytes(&signing_bytes(&urgent)).unwrap().urgent);
+        // v1 の写しには載らない
+        assert!(!urgent.downgraded().urgent);
+
+        // 署名なしのフレームや、投稿・DM 以外の種別に付いた印は受け付けない
+        let drain = |frame: &[u8]| {
+            let mut d = Decoder::new();
+            d.feed(frame);
+            d.drain()
+        };
+        let mut unsigned = encode(&Message::chat("x", 1));
+        unsigned[1] |= MsgKind::URGENT_FLAG;
+        assert!(matches!(
+            drain(&unsigned),
+            Err(ProtocolError::UnsupportedKind(_))
+        ));
+        let mut topic = encode(
+            &Message::topic(1, "t")
+                .for_signing(vec![1u8; 32], [2u8; HOP_TOKEN_LEN])
+                .with_key_sig(vec![1u8; 32], vec![3u8; 64]),
+        );
+        topic[1] |= MsgKind::URGENT_FLAG;
+        assert!(matches!(
+            drain(&topic),
+            Err(ProtocolError::UnsupportedKind(_))
+        ));
+    }
+
+    #[test]
+    fn topology_frames_round_trip() {
+        let id = [7u8; TOPOLOGY_QUERY_ID_LEN];
+        let q = Message::topology_query(1, &id);
+        assert_eq!(topology_query_id(&decode_one(&encode(&q))), Some(id));
+        assert_eq!(topology_query_id(&Message::chat("x", 1)), None);
+
+        let fps = [[1u8; ROUTE_FINGERPRINT_LEN], [2u8; ROUTE_FINGERPRINT_LEN]];
+        let r = Message::topology_reply(1, &id, &fps);
+        assert_eq!(
+            topology_reply_parts(&decode_one(&encode(&r))),
+            Some((id, fps.to_vec()))
+        );
+        let mut broken = r.clone();
+        broken.payload.pop();
+        assert_eq!(topology_reply_parts(&broken), None);
+    }
+
+    #[test]
+    fn ping_and_pong_carry_the_same_id() {
+        let ping = decode_one(&encode(&Message::ping(1, 42)));
+        assert_eq!(ping.kind, MsgKind::PING);
+        assert_eq!(ping_id(&ping), Some(42));
+        let pong = decode_one(&encode(&Message::pong(2, 42)));
+        assert_eq!(pong.kind, MsgKind::PONG);
+        assert_eq!(ping_id(&pong), Some(42));
+        assert_eq!(ping_id(&Message::solution(1, 42)), None);
+    }
 }
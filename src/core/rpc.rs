@@ -1,19 +1,210 @@
 #[derive(Debug)]
 pub enum Command {
-    Open(String),
+    /// 待受ポートと、トークンを書き出すファイル（/open <port> save=<path>）
+    Open(String, Option<String>),
     Connect(String),
     Handle(String),
-    Close,
+    /// 待受を閉じる（ポート指定なしなら全部）
+    Close(Option<u16>),
+    /// 待受中のトークンを作り直して表示する
+    Token,
+    /// config から読んだタイマー類を表示する
+    Timers,
+    /// 接続先から紹介されたピアの待受アドレスを表示する
+    Discover,
+    /// 届く範囲のピアに隣接ピアを問い合わせ、つながりを隣接リストで表示する
+    Topology,
+    /// 指紋を検証済みにした（以降その鍵の投稿に印を付ける）
+    Trust(String),
     Disconnect(String),
     PeerList,
-    DM(String, String),
+    /// ピアの署名鍵と、接続元アドレスから調べたネットワーク情報を分けて表示する
+    Whois(String),
+    /// 宛先 id・本文・揮発 (true なら送受信とも保存しない)
+    DM(String, String, bool),
     Certs,
-    Chat(String),
+    /// 指定ピアの公開鍵の詳細を表示する
+    Cert(String),
+    Version,
+    /// 新しい鍵ペア (pkcs8, public) に切り替え、旧鍵で署名したローテーションを通知
+    RotateKey(Vec<u8>, Vec<u8>),
+    /// 全体チャット: 本文・返信先の ID (あれば返信先付きの Chat として送る)・至急
+    Chat(String, Option<String>, bool),
+    /// 受信した Chat の中継を ON/OFF する (OFF で leaf ノード)
+    Relay(bool),
+    /// 1 ティックに中継で書き込む回数の上限を変える（None で無制限）
+    Fanout(Option<usize>),
+    /// 自分の投稿 (ID) の本文を差し替える
+    Edit(String, String),
+    /// 自分の投稿 (ID) を削除する
+    Delete(String),
+    /// 部屋のトピックを署名して全ピアへ送る
+    Topic(String),
+    /// 開発者向け: バイト列を encode・署名を通さずそのまま送る（宛先 None なら全ピア）
+    Raw(Option<usize>, Vec<u8>),
+    /// 開発者向け: ピアから最後に受信した生バイト列を表示する
+    Dump(usize),
     Shutdown,
 }
 
+/// 接続の段階。DM や中継は Ready の相手にだけ送る
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    /// 受け入れ直後で、接続パズルの解答を待っている
+    Connecting,
+    /// 署名付き HELLO をまだ受け取っていない
+    Handshaking,
+    /// HELLO の署名を確かめた
+    Ready,
+}
+
+impl PeerState {
+    /// /peers に出す名前
+    pub fn label(self) -> &'static str {
+        match self {
+            PeerState::Connecting => "接続中",
+            PeerState::Handshaking => "HELLO待ち",
+            PeerState::Ready => "準備完了",
+        }
+    }
+}
+
+/// /peers 用のピア情報
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub id: usize,
+    pub token: String,
+    /// 公開鍵 SHA-256 の先頭16桁 (HELLO 未受信なら None)
+    pub fingerprint: Option<String>,
+    pub handle: Option<String>,
+    pub state: PeerState,
+    /// 往復遅延 (未計測なら None)
+    pub rtt_ms: Option<u64>,
+    /// 受信バイト数
+    pub bytes_in: u64,
+    /// 送信待ちのバイト数（相手が読まずに詰まっている分）
+    pub queued_bytes: usize,
+    /// 受け入れた待受のポート（自分から接続したなら None）
+    pub via_port: Option<u16>,
+    /// 同じハンドルを名乗るピアが他にいて、@handle ではこのピアが選ばれない
+    pub handle_suspect: bool,
+}
+
+/// 受信投稿の署名状態（保存形式 MessageRecord::signature にもそのまま使う）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum SigState {
+    Valid,
+    Unsigned,
+    Invalid,
+}
+
+impl SigState {
+    /// 表示行の末尾に付ける記号
+    pub fn mark(self) -> &'static str {
+        match self {
+            SigState::Valid => "○",
+            SigState::Unsigned => "・",
+            SigState::Invalid => "×",
+        }
+    }
+
+    /// 旧保存形式 (signed_ok: Option<bool>) からの変換
+    pub fn from_signed_ok(v: Option<bool>) -> Self {
+        match v {
+            Some(true) => SigState::Valid,
+            Some(false) => SigState::Invalid,
+            None => SigState::Unsigned,
+        }
+    }
+}
+
+/// 揮発 DM の表示行に付ける印
+pub const EPHEMERAL_MARK: &str = " (揮発)";
+
+/// /trust で検証済みにした相手の投稿の表示行に付ける印
+pub const VERIFIED_MARK: &str = " ✔";
+
+/// ノード発のお知らせ (SYSTEM) の表示行の先頭に付ける印
+pub const SYSTEM_MARK: &str = "[通知] ";
+
+/// 至急の印が付いた投稿・DM の表示行の末尾に付ける印
+pub const URGENT_MARK: &str = " ‼至急";
+
 #[derive(Debug)]
 pub enum Event {
     Message(String),
     DebugMessage(String),
+    PeerList {
+        /// 待受中のポート
+        listening: Vec<u16>,
+        peers: Vec<PeerInfo>,
+    },
+    /// ID の付かない受信投稿（署名なし・署名不正・DM など）
+    Post {
+        line: String,
+        sig: SigState,
+    },
+    /// 自分宛ての DM が届いた（表示は Post で済んでいる。通知用、from はハンドルか指紋）
+    DmArrived {
+        from: String,
+    },
+    /// 至急の印が付いた投稿か DM が届いた（表示は Chat / Post で済んでいる。通知用）
+    UrgentArrived {
+        from: String,
+    },
+    /// 署名検証済みでメッセージID付きの表示行（後から Replace で差し替えられる）
+    Chat {
+        id: String,
+        line: String,
+        /// 返信先のメッセージID
+        reply_to: Option<String>,
+    },
+    /// 自分の投稿を送信した（直前のローカルエコーに ID を付ける）
+    Sent {
+        id: String,
+    },
+    /// ピア未接続のため自分の投稿を送信待ちにした（後で Sent が届く）
+    Queued,
+    /// 自分の投稿を送れなかった（直前のローカルエコーは ID 待ちから外す）
+    Unsent,
+    /// 部屋のトピックが変わった（by は設定者の指紋）
+    Topic {
+        text: String,
+        by: String,
+    },
+    /// 編集・削除による表示行の差し替え
+    Replace {
+        id: String,
+        line: String,
+    },
+    /// ピアと TCP で接続した（inbound なら待受で受け入れた側）。鍵は HandshakeComplete で分かる
+    PeerConnected {
+        id: usize,
+        token: String,
+        inbound: bool,
+    },
+    /// ピアとの接続が切れた（id は切断時点のもの）
+    PeerDisconnected {
+        id: usize,
+        reason: String,
+    },
+    /// 署名付き HELLO を受け取り、相手のハンドルと鍵の指紋（先頭16桁）が分かった
+    HandshakeComplete {
+        id: usize,
+        handle: String,
+        fingerprint: String,
+    },
+    /// ピアから届いたフレームの署名検証に失敗した
+    SignatureFailed {
+        id: usize,
+    },
+    /// 検証済みの相手が別の鍵で現れた（ハンドルが同じで指紋が違う）
+    VerifiedKeyChanged {
+        id: usize,
+        handle: String,
+        /// /trust で検証した指紋
+        verified: String,
+        /// 今回の鍵の指紋
+        got: String,
+    },
 }
@@ -0,0 +1,46 @@
+//! クレート共通のエラー型。各モジュールのエラーをまとめ、公開 API はこの Result を返す
+
+use crate::core::crypto::CryptoError;
+use crate::core::protocol::ProtocolError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// 保存先 (sled) の読み書きに失敗
+    #[error("DB エラー: {0}")]
+    Db(#[from] sled::Error),
+    /// 保存する値の符号化・復号に失敗
+    #[error("符号化エラー: {0}")]
+    Encode(#[from] postcard::Error),
+    #[error("入出力エラー: {0}")]
+    Io(#[from] std::io::Error),
+    /// 設定ファイルを TOML として読めない
+    #[error("設定を読めません: {0}")]
+    ConfigParse(#[from] toml::de::Error),
+    #[error("暗号エラー: {0}")]
+    Crypto(#[from] CryptoError),
+    #[error("プロトコルエラー: {0}")]
+    Protocol(#[from] ProtocolError),
+    /// 保存先がまだ初期化されていない（または no_history で保存しない）
+    #[error("保存先が初期化されていません")]
+    NotInitialized,
+    /// 設定の読み込み前に書き込もうとした
+    #[error("設定が初期化されていません")]
+    ConfigNotInitialized,
+    /// 読み込んだデータや指定された値が形式に合わない
+    #[error("{0}")]
+    Invalid(String),
+}
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(e: std::str::Utf8Error) -> Self {
+        Error::Invalid(format!("UTF-8 として読めません: {}", e))
+    }
+}
+
+impl From<std::string::FromUtf8Error> for Error {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        e.utf8_error().into()
+    }
+}
@@ -1,8 +1,16 @@
 // Library root for p2witter
 // Exposes modules for testing and external use
 
-pub mod core;
 pub mod config;
-pub mod storage;
+pub mod core;
+pub mod error;
+pub mod metrics;
+pub mod nat;
+pub mod netinfo;
 pub mod network_handler;
+pub mod replay;
+pub mod storage;
+pub mod transport;
 pub mod utils;
+
+pub use error::{Error, Result};
@@ -1,91 +1,22 @@
+use p2witter::core::{crypto, rpc};
+use p2witter::{config, metrics, network_handler, replay, storage};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::io::{self, Write};
 use std::time::Duration;
 use tokio::sync::mpsc;
-mod config;
-mod core;
-mod network_handler;
-mod storage;
-use core::{crypto, rpc};
-mod utils;
-use utils::current_unix_millis;
+mod app;
+mod check;
+mod commands;
+mod setup;
+mod template;
+mod theme;
+use app::{DrawState, PeerPicker, Repaint, SigCounts, Tui};
+use commands::{Action, AppState, PeerQuery, PeerSort};
+use theme::Theme;
 
-// コマンド仕様（説明・使い方）
-#[derive(Clone, Copy)]
-struct CommandSpec {
-    name: &'static str,
-    description: &'static str,
-    usage: &'static str,
-}
-const COMMANDS: &[CommandSpec] = &[
-    CommandSpec {
-        name: "/help",
-        description: "コマンド一覧または詳細を表示",
-        usage: "/help [name]",
-    },
-    CommandSpec {
-        name: "/open",
-        description: "ローカルで待受を開始し、トークンを表示",
-        usage: "/open <port>",
-    },
-    CommandSpec {
-        name: "/close",
-        description: "待受を終了",
-        usage: "/close",
-    },
-    CommandSpec {
-        name: "/connect",
-        description: "トークンで接続",
-        usage: "/connect <token>",
-    },
-    CommandSpec {
-        name: "/disconnect",
-        description: "接続を切断",
-        usage: "/disconnect <id>",
-    },
-    CommandSpec {
-        name: "/peers",
-        description: "接続中のピア一覧を表示",
-        usage: "/peers",
-    },
-    CommandSpec {
-        name: "/certs",
-        description: "ピア証明書（公開鍵）一覧を表示",
-        usage: "/certs",
-    },
-    CommandSpec {
-        name: "/cert",
-        description: "指定ピアの公開鍵詳細を表示",
-        usage: "/cert <id>",
-    },
-    CommandSpec {
-        name: "/dm",
-        description: "指定ピアにダイレクトメッセージを送信",
-        usage: "/dm <to_id> <message>",
-    },
-    CommandSpec {
-        name: "/msg",
-        description: "全体にメッセージを送信",
-        usage: "/msg <message>",
-    },
-    CommandSpec {
-        name: "/handle",
-        description: "自分のハンドル名を設定（@から始まり80文字未満）",
-        usage: "/handle @name",
-    },
-    CommandSpec {
-        name: "/init",
-        description: "署名鍵を生成して保存",
-        usage: "/init",
-    },
-    CommandSpec {
-        name: "/exit",
-        description: "アプリケーションを終了",
-        usage: "/exit",
-    },
-];
-fn find_command(name: &str) -> Option<&'static CommandSpec> {
-    COMMANDS.iter().find(|c| c.name == name)
-}
+/// 保存期間切れの履歴を削除する間隔
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 // 表示桁（全角=2, 半角=1 等）を考慮して安全に切り詰める
 fn display_width(s: &str) -> usize {
@@ -109,6 +40,106 @@ fn truncate_display(s: &str, max_cols: usize) -> String {
     out
 }
 
+// 表示幅が width になるよう右側を空白で埋める
+fn pad_display(s: &str, width: usize) -> String {
+    let w = display_width(s);
+    format!("{}{}", s, " ".repeat(width.saturating_sub(w)))
+}
+
+// ピア一覧を表示幅で桁揃えした表にする
+fn format_peer_table(listening: &[u16], peers: &[rpc::PeerInfo], query: &PeerQuery) -> String {
+    let total = peers.len();
+    let mut peers: Vec<&rpc::PeerInfo> = peers.iter().filter(|p| query.matches(p)).collect();
+    match query.sort {
+        PeerSort::Id => peers.sort_by_key(|p| p.id),
+        PeerSort::Handle => peers.sort_by(|a, b| a.handle.cmp(&b.handle).then(a.id.cmp(&b.id))),
+        // 未計測は末尾
+        PeerSort::Rtt => peers.sort_by_key(|p| (p.rtt_ms.is_none(), p.rtt_ms, p.id)),
+    }
+    let header = [
+        "id",
+        "handle",
+        "指紋",
+        "状態",
+        "rtt",
+        "受信",
+        "送信待ち",
+        "経由",
+        "token",
+    ];
+    let rows: Vec<[String; 9]> = peers
+        .iter()
+        .map(|p| {
+            [
+                p.id.to_string(),
+                // @handle で選ばれない同名のピアには印を付ける
+                match (&p.handle, p.handle_suspect) {
+                    (Some(h), true) => format!("{} ⚠", h),
+                    (Some(h), false) => h.clone(),
+                    (None, _) => "?".into(),
+                },
+                p.fingerprint.clone().unwrap_or_else(|| "?".into()),
+                p.state.label().to_string(),
+                p.rtt_ms
+                    .map(|r| format!("{}ms", r))
+                    .unwrap_or_else(|| "-".into()),
+                format!("{}B", p.bytes_in),
+                format!("{}B", p.queued_bytes),
+                // 受け入れた待受のポート。自分から接続したなら "発信"
+                p.via_port
+                    .map(|port| format!(":{}", port))
+                    .unwrap_or_else(|| "発信".into()),
+                p.token.clone(),
+            ]
+        })
+        .collect();
+    let mut widths: Vec<usize> = header.iter().map(|h| display_width(h)).collect();
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row.iter()) {
+            *w = (*w).max(display_width(cell));
+        }
+    }
+    let render_row = |cells: Vec<&str>| -> String {
+        let last = cells.len() - 1;
+        cells
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                if i == last {
+                    c.to_string()
+                } else {
+                    pad_display(c, widths[i])
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("  ")
+    };
+    let listening = if listening.is_empty() {
+        "なし".to_string()
+    } else {
+        listening
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let mut lines = vec![match &query.filter {
+        Some(f) => format!(
+            "ピア数={}/{} 待受={} 絞り込み='{}'",
+            peers.len(),
+            total,
+            listening,
+            f
+        ),
+        None => format!("ピア数={} 待受={}", peers.len(), listening),
+    }];
+    lines.push(render_row(header.to_vec()));
+    for row in &rows {
+        lines.push(render_row(row.iter().map(|c| c.as_str()).collect()));
+    }
+    lines.join("\n")
+}
+
 // 長いテキストを指定幅で折り返して複数行に分割
 fn wrap_text(s: &str, max_cols: usize) -> Vec<String> {
     use unicode_width::UnicodeWidthChar;
@@ -177,6 +208,166 @@ fn split_at_char(s: &str, idx: usize) -> (String, String) {
     (left, right)
 }
 
+// 返信先の引用行。表示中の行 → 保存済みメッセージの順に探す
+fn reply_quote(messages: &[String], tagged: &HashMap<String, usize>, id: &str) -> String {
+    let snippet = tagged
+        .get(id)
+        .and_then(|&i| messages.get(i).cloned())
+        .or_else(|| storage::get_by_id(id).map(|r| r.text));
+    match snippet {
+        Some(s) => format!("  > {}", truncate_display(&s, 40)),
+        None => format!("  > (in reply to #{}…)", id.get(..8).unwrap_or(id)),
+    }
+}
+
+// ネットワークスレッドを起動し、コマンド送信口とハンドルを返す
+fn spawn_network_thread(
+    tx_main: mpsc::Sender<rpc::Event>,
+) -> (mpsc::Sender<rpc::Command>, tokio::task::JoinHandle<()>) {
+    let (tx_thread, rx_thread) = mpsc::channel(100);
+    let handle_task = tokio::spawn(async move {
+        network_handler::network_handler(tx_main, rx_thread).await;
+    });
+    (tx_thread, handle_task)
+}
+
+// コマンドライン引数の --profile <name> / --profile=<name>
+fn profile_arg(args: impl IntoIterator<Item = String>) -> Option<String> {
+    arg_value(args, "--profile")
+}
+
+// コマンドライン引数の <flag> <value> / <flag>=<value>
+fn arg_value(args: impl IntoIterator<Item = String>, flag: &str) -> Option<String> {
+    let mut args = args.into_iter();
+    while let Some(a) = args.next() {
+        if a == flag {
+            return args.next();
+        }
+        if let Some(value) = a.strip_prefix(flag).and_then(|v| v.strip_prefix('=')) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+// --replay <file>: record_frames の記録をネットワークなしで再生し、表示される行を出力する。
+// 設定と DB は読まず（保存先はメモリ）、いつ誰が実行しても同じ結果になるようにする
+async fn run_replay(path: &str) -> i32 {
+    let data = match std::fs::read(path) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return 1;
+        }
+    };
+    let (entries, note) = replay::read_log(&data);
+    if let Some(note) = note {
+        eprintln!("{}", note);
+    }
+    if entries.is_empty() {
+        return 1;
+    }
+    let (frames, problems) = replay::decode_entries(&entries);
+    for p in &problems {
+        eprintln!("{}", p);
+    }
+    eprintln!(
+        "記録 {} 件（接続 {} 本）からフレーム {} 個を再生します",
+        entries.len(),
+        entries.iter().map(|e| e.conn).collect::<HashSet<_>>().len(),
+        frames.len()
+    );
+    storage::init_storage_with(Box::new(storage::MemoryStorage::default()));
+    let (tx, mut rx) = mpsc::channel(100);
+    let task = tokio::spawn(async move { replay::replay(&entries, tx).await });
+    let mut tui = Tui::new(String::new(), DrawState::new(), true);
+    while let Some(ev) = rx.recv().await {
+        tui.on_event(ev, &PeerQuery::default());
+    }
+    let _ = task.await;
+    for line in &tui.messages {
+        println!("{}", line);
+    }
+    0
+}
+
+// 起動時の自動待受。auto_open=false なら None、
+// 有効だがハンドル・鍵・ポートが揃っていなければ理由を Err で返す
+fn auto_open_command(cfg: &toml::Table) -> Option<Result<rpc::Command, String>> {
+    let enabled = config::get_value_in(cfg, config::keys::AUTO_OPEN)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    if !enabled {
+        return None;
+    }
+    let handle = config::get_value_in(cfg, config::keys::USER_HANDLE)
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .unwrap_or_default();
+    if !config::is_valid_handle_in(cfg, &handle) {
+        return Some(Err(
+            "自動待受: ハンドル未設定です。/handle @name を先に実行してください".into(),
+        ));
+    }
+    let has_key = [config::keys::KEY_PKCS8, config::keys::KEY_PUBLIC]
+        .iter()
+        .all(|k| {
+            config::get_value_in(cfg, k)
+                .and_then(|v| v.as_str().and_then(|s| crypto::from_hex(s).ok()))
+                .is_some_and(|b| !b.is_empty())
+        });
+    if !has_key {
+        return Some(Err("自動待受: 鍵未生成 (/init を先に実行)".into()));
+    }
+    let port = match config::get_value_in(cfg, config::keys::LISTEN_PORT) {
+        Some(toml::Value::Integer(p)) if (1..=65535).contains(&p) => p.to_string(),
+        Some(toml::Value::String(p)) if p.parse::<u16>().is_ok_and(|p| p != 0) => p,
+        _ => return Some(Err("自動待受: listen_port が未設定か不正です".into())),
+    };
+    Some(Ok(rpc::Command::Open(port, None)))
+}
+
+/// 端末を元に戻す（マウス捕捉・代替画面・raw mode の解除）。終了・パニックのどちらでも呼ぶ
+fn restore_terminal(out: &mut impl Write) {
+    use crossterm::event::DisableMouseCapture;
+    use crossterm::terminal::{LeaveAlternateScreen, disable_raw_mode};
+    crossterm::execute!(out, DisableMouseCapture, LeaveAlternateScreen).ok();
+    disable_raw_mode().ok();
+}
+
+/// パニックしたら、メッセージを出す前に restore で端末を戻す
+fn install_panic_hook(restore: impl Fn() + Send + Sync + 'static) {
+    let prev = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore();
+        prev(info);
+    }));
+}
+
+/// 終了シグナルを待つ。受けたら /exit と同じ手順で終わらせる
+#[cfg(unix)]
+async fn wait_for_signal() -> &'static str {
+    use tokio::signal::unix::{SignalKind, signal};
+    let (Ok(mut term), Ok(mut hup)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::hangup()),
+    ) else {
+        return std::future::pending().await;
+    };
+    tokio::select! {
+        _ = term.recv() => "SIGTERM",
+        _ = hup.recv() => "SIGHUP",
+        Ok(()) = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> &'static str {
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => "Ctrl+C",
+        Err(_) => std::future::pending().await,
+    }
+}
+
 #[tokio::main]
 async fn main() {
     // ---- 初期セットアップ ----
@@ -184,107 +375,254 @@ async fn main() {
     let mut active_thread_tx: Option<mpsc::Sender<rpc::Command>> = None;
     let mut active_thread_handle: Option<tokio::task::JoinHandle<()>> = None;
 
-    if let Err(e) = config::init_config_path("./config.toml") {
+    if let Some(path) = arg_value(std::env::args(), "--replay") {
+        std::process::exit(run_replay(&path).await);
+    }
+    // --profile <name> で ./profiles/<name>/ 以下の設定と DB を使う
+    let profile = match config::Profile::resolve(profile_arg(std::env::args()).as_deref()) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{e}");
+            return;
+        }
+    };
+    // --setup --handle @name ならハンドルと鍵を書き込んで、TUI を起動せずに終了する
+    if std::env::args().any(|a| a == "--setup") {
+        let Some(handle) = setup::handle_arg(std::env::args()) else {
+            eprintln!("使い方: p2witter --setup --handle @name [--force]");
+            std::process::exit(2);
+        };
+        let result = config::init_config_path(&profile.config)
+            .map_err(|e| format!("設定初期化に失敗: {e}"))
+            .and_then(|_| {
+                setup::run_setup(&handle, std::env::args().any(|a| a == "--force"))
+                    .map_err(|e| e.to_string())
+            });
+        match result {
+            Ok(text) => println!("{}\n設定: {}", text, profile.config.display()),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    // --check なら設定・鍵・DB を確かめて、TUI を起動せずに終了する
+    if std::env::args().any(|a| a == "--check") {
+        let (text, ok) = check::report(&check::run_checks(&profile.config, &profile.db));
+        println!("{}", text);
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+    if let Err(e) = config::init_config_path(&profile.config) {
         eprintln!("設定初期化に失敗: {e}");
     }
-    // ストレージ初期化（sled）
-    let _ = storage::init_storage("./p2witter.db");
-
-    // TUI 状態
-    let mut messages: Vec<String> = Vec::new();
-    let mut past_messages: Vec<String> = Vec::new();
-    let mut input = String::new();
-    let mut running = true;
-    // ハンドル（@から始まり80文字未満）: 必須（デフォルト廃止）
-    let mut handle: String = config::get_value("user.handle")
-        .and_then(|v| v.as_str().map(|s| s.to_string()))
+    // 投稿をいつディスクへ書き出すか（safe / fast / relaxed。既定は safe）
+    let durability_value =
+        config::get_value(config::keys::DURABILITY).and_then(|v| v.as_str().map(str::to_string));
+    let durability = durability_value
+        .as_deref()
+        .and_then(storage::DurabilityMode::parse)
         .unwrap_or_default();
+    // ストレージ初期化（既定は sled、storage_backend = "memory" なら終了時に消える保存先）。
+    // 開けなくてもチャットはできるようにする
+    let storage_warning = match config::get_value(config::keys::STORAGE_BACKEND)
+        .and_then(|v| v.as_str().map(str::to_string))
+        .as_deref()
+    {
+        Some("memory") => {
+            storage::init_storage_with(Box::new(storage::MemoryStorage::default()));
+            None
+        }
+        _ => storage::init_storage_or_memory(&profile.db, durability).err(),
+    };
+    // 旧形式 (ts|text) の行が残っていれば一度だけ構造化した記録に書き換える
+    let migration_note = match storage::migrate_legacy() {
+        Ok(0) => None,
+        Ok(n) => Some(format!("旧形式の履歴 {} 件を新しい形式に変換しました", n)),
+        Err(e) => Some(format!("⚠ 旧形式の履歴の変換に失敗: {}", e)),
+    };
+    // no_history = true なら受信・送信したメッセージを一切保存しない
+    storage::set_history_disabled(
+        config::get_value(config::keys::NO_HISTORY)
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+    );
+    // metrics = true なら中継の統計を Prometheus 形式で公開する（既定は無効）
+    let metrics_note = config::get_value(config::keys::METRICS)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+        .then(|| {
+            let addr = config::get_value(config::keys::METRICS_ADDR)
+                .and_then(|v| v.as_str().map(str::to_string))
+                .unwrap_or_else(|| metrics::DEFAULT_METRICS_ADDR.to_string());
+            match metrics::serve(&addr) {
+                Ok(a) => format!("メトリクス: http://{}/metrics", a),
+                Err(e) => format!("⚠ メトリクスの待受に失敗 ({}): {}", addr, e),
+            }
+        });
+    // chat_retention_days / dm_retention_days があれば起動時と定期的に期限切れを消す
+    let retention = config::try_config()
+        .map(|cfg| storage::Retention::from_config(&cfg))
+        .unwrap_or_default();
+    if retention.is_enabled() {
+        tokio::spawn(async move {
+            loop {
+                let now = p2witter::utils::current_unix_millis();
+                let _ = tokio::task::spawn_blocking(move || {
+                    let _ = storage::prune_expired(&retention, now);
+                })
+                .await;
+                tokio::time::sleep(PRUNE_INTERVAL).await;
+            }
+        });
+    }
+
+    // ハンドル（config::is_valid_handle の規則）: 必須（デフォルト廃止）
+    let mut app = AppState {
+        handle: config::get_value(config::keys::USER_HANDLE)
+            .and_then(|v| v.as_str().map(|s| s.to_string()))
+            .unwrap_or_default(),
+        network_running: false,
+        peers: PeerQuery::default(),
+        public_key: config::get_value(config::keys::KEY_PUBLIC)
+            .and_then(|v| v.as_str().and_then(|s| crypto::from_hex(s).ok()))
+            .filter(|b| !b.is_empty()),
+        // --spectate または config の spectate = true で観戦モード
+        spectator: std::env::args().any(|a| a == "--spectate")
+            || config::get_value(config::keys::SPECTATE)
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+        relay: config::get_value(config::keys::RELAY)
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true),
+        developer: config::get_value(config::keys::DEVELOPER)
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+        first_run: false,
+    };
+    // ハンドルが無ければ、最初の入力行をハンドルとして受け取る
+    app.first_run = !app.has_valid_handle();
 
     use crossterm::event::{
         DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind,
         KeyModifiers,
     };
-    use crossterm::terminal::{
-        EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode,
-    };
+    use crossterm::terminal::{EnterAlternateScreen, enable_raw_mode};
     use crossterm::{event, execute};
 
     enable_raw_mode().expect("raw mode に移行できません");
     let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture).ok();
-    // 差分描画用状態と関数 + スクロール/履歴状態 + ステータスバー
-    struct DrawState {
-        last_msg_len: usize,
-        last_input_len: usize,
-        last_cursor_pos: usize,
-        force_full: bool,
-    }
-    impl DrawState {
-        fn new() -> Self {
-            Self {
-                last_msg_len: 0,
-                last_input_len: 0,
-                last_cursor_pos: 0,
-                force_full: true,
+    execute!(stdout, EnterAlternateScreen).ok();
+    // マウスを取れない端末でも描画は続け、キーボードでスクロールしてもらう
+    let mouse_error = execute!(stdout, EnableMouseCapture).err();
+    install_panic_hook(|| restore_terminal(&mut io::stdout()));
+    let (tx_signal, mut rx_signal) = mpsc::channel::<&'static str>(1);
+    tokio::spawn(async move {
+        let _ = tx_signal.send(wait_for_signal().await).await;
+    });
+    let mut stopped_by: Option<&'static str> = None;
+    // 差分描画 + ステータスバー
+    // '\n' を実際の改行として扱い、行ごとに表示するために平坦化
+    // 長い行は unicode_width を使って適切に折り返す
+    // 各行には配色用に元メッセージの種類と、ハンドル色を付けるかを持たせる
+    // found は /find で見つけた messages 内の位置（その行は反転表示）
+    // before は messages の直前の行（compact で同じハンドルが続くかの判定用）
+    // first は messages[0] が表示中の一覧の何番目か（長い行を縮めたときの /show の行番号用）
+    fn flatten(
+        messages: &[String],
+        first: usize,
+        before: Option<&str>,
+        safe_w: usize,
+        st: &DrawState,
+        found: Option<usize>,
+    ) -> Vec<(String, theme::LineKind, bool)> {
+        let mut flat_lines: Vec<(String, theme::LineKind, bool)> = Vec::new();
+        let shown: Vec<Cow<str>> = if st.compact {
+            theme::compact_lines(messages, before)
+        } else {
+            messages.iter().map(|m| Cow::Borrowed(m.as_str())).collect()
+        };
+        for (i, (msg, line)) in messages.iter().zip(&shown).enumerate() {
+            let kind = if found == Some(i) {
+                theme::LineKind::Found
+            } else {
+                theme::classify_line(msg, &st.own_handle)
+            };
+            let line = if st.expanded == Some(first + i) {
+                Cow::Borrowed(line.as_ref())
+            } else {
+                theme::collapse_long(line, st.max_display_chars, first + i + 1)
+            };
+            let msg = theme::with_bookmark_mark(&line, &st.bookmarks);
+            for (pi, part) in msg.split('\n').enumerate() {
+                if display_width(part) > safe_w {
+                    // 長い行は複数行に折り返す
+                    for (wi, wrapped_line) in wrap_text(part, safe_w).into_iter().enumerate() {
+                        flat_lines.push((wrapped_line, kind, pi == 0 && wi == 0));
+                    }
+                } else {
+                    flat_lines.push((part.to_string(), kind, pi == 0));
+                }
             }
         }
+        flat_lines
     }
-    fn redraw_full(
+    #[allow(clippy::too_many_arguments)]
+    fn draw_status_bar(
         stdout: &mut io::Stdout,
-        messages: &Vec<String>,
-        scroll_offset: usize,
+        safe_w: usize,
+        off: usize,
+        max_scroll: usize,
         status_msg: &str,
         past_mode: bool,
         date_range: &str,
-    ) -> (u16, u16) {
+        sig_counts: SigCounts,
+        st: &DrawState,
+    ) {
+        let theme = &st.theme;
         use crossterm::style::{self};
         use crossterm::terminal::{Clear, ClearType};
-        use crossterm::{cursor, queue, terminal};
-        let (w, h) = terminal::size().unwrap_or((80, 24));
-        let safe_w = w.saturating_sub(1) as usize; // 末尾1桁は未使用にして自動折返しを回避
-        let input_row = h.saturating_sub(1); // 最下行
-        // メッセージ領域の高さは後続の view_h で計算
-        // スクロールオフセット: 0 が最新。offset が増えると過去方向
-        // 旧カウントは flat_lines で再計算するため削除
-        // 画面全消去は避けステータス+メッセージ領域のみクリア
-        queue!(stdout, cursor::Hide).ok();
-        // '\n' を実際の改行として扱い、行ごとに表示するために平坦化
-        // 長い行は unicode_width を使って適切に折り返す
-        let mut flat_lines: Vec<String> = Vec::new();
-        for msg in messages.iter() {
-            for part in msg.split('\n') {
-                if display_width(part) > safe_w {
-                    // 長い行は複数行に折り返す
-                    for wrapped_line in wrap_text(part, safe_w) {
-                        flat_lines.push(wrapped_line);
-                    }
-                } else {
-                    flat_lines.push(part.to_string());
-                }
-            }
-        }
-        let total = flat_lines.len();
-        let view_h = if input_row > 1 {
-            (input_row - 1) as usize
-        } else {
-            0
-        };
-        let max_scroll = total.saturating_sub(view_h);
-        let off = scroll_offset.min(max_scroll);
+        use crossterm::{cursor, queue};
         // ステータスバークリア
         queue!(stdout, cursor::MoveTo(0, 0), Clear(ClearType::CurrentLine)).ok();
         // ステータス文字列組み立て
-        let bar_core = if past_mode {
-            let range = if date_range.is_empty() {
-                "過去ログ".into()
-            } else {
-                date_range.to_string()
-            };
+        let range = match (past_mode, date_range.is_empty()) {
+            (false, _) => "",
+            (true, true) => "過去ログ",
+            (true, false) => date_range,
+        };
+        let bar_core = if let Some(t) = &st.status_format {
+            t.render(&template::Values {
+                handle: &st.own_handle,
+                peers: st.peers,
+                scroll: &format!("{}/{}", off, max_scroll),
+                range,
+            })
+        } else if past_mode {
             format!(" p2witter | {} (scroll {} / {}) ", range, off, max_scroll)
         } else {
             format!(" p2witter | スクロール:{}/{} ", off, max_scroll)
         };
         let mut bar = bar_core.clone();
+        if !past_mode && off > 0 && st.new_below > 0 {
+            bar.push_str(&format!("| ↓新着 {} 件 (End で最新へ) ", st.new_below));
+        }
+        // 表示中の受信投稿の署名状態 (検証済み / 署名なし / 不正)
+        if !sig_counts.is_empty() {
+            bar.push_str(&format!("| {} ", sig_counts));
+        }
+        if let Some(topic) = &st.topic {
+            bar.push_str(&format!("| {} ", topic));
+        }
+        if !st.relay {
+            bar.push_str("| 中継OFF ");
+        }
+        if st.dnd {
+            bar.push_str("| おやすみ ");
+        }
+        if let Some(n) = st.focus_hidden {
+            bar.push_str(&format!("| 集中モード: {} 件非表示 ", n));
+        }
         if !status_msg.is_empty() {
             bar.push_str(status_msg);
         }
@@ -292,10 +630,108 @@ async fn main() {
             bar = truncate_display(&bar, safe_w);
         }
         queue!(stdout, cursor::MoveTo(0, 0)).ok();
-        // 反転表示 (端末対応簡易)
-        queue!(stdout, style::SetAttribute(style::Attribute::Reverse)).ok();
+        if theme.no_color {
+            // 反転表示 (端末対応簡易)
+            queue!(stdout, style::SetAttribute(style::Attribute::Reverse)).ok();
+        } else {
+            queue!(
+                stdout,
+                style::SetForegroundColor(theme.status_fg),
+                style::SetBackgroundColor(theme.status_bg)
+            )
+            .ok();
+        }
         let _ = write!(stdout, "{}", bar);
-        queue!(stdout, style::SetAttribute(style::Attribute::Reset)).ok();
+        queue!(
+            stdout,
+            style::SetAttribute(style::Attribute::Reset),
+            style::ResetColor
+        )
+        .ok();
+    }
+    fn draw_line(
+        stdout: &mut io::Stdout,
+        y: u16,
+        (line, kind, first): &(String, theme::LineKind, bool),
+        st: &DrawState,
+    ) {
+        let theme = &st.theme;
+        use crossterm::style::{self};
+        use crossterm::{cursor, queue};
+        queue!(stdout, cursor::MoveTo(0, y)).ok();
+        // 色なしでも分かるよう反転表示にする
+        if *kind == theme::LineKind::Found {
+            queue!(stdout, style::SetAttribute(style::Attribute::Reverse)).ok();
+            let _ = write!(stdout, "{}", line);
+            queue!(stdout, style::SetAttribute(style::Attribute::Reset)).ok();
+            return;
+        }
+        let Some(color) = theme.line_color(*kind) else {
+            let _ = write!(stdout, "{}", line);
+            return;
+        };
+        queue!(stdout, style::SetForegroundColor(color)).ok();
+        match theme::handle_span(line).filter(|_| *first) {
+            Some((s, e)) => {
+                let _ = write!(stdout, "{}", &line[..s]);
+                let c = st
+                    .handle_colors
+                    .color_of(&line[s..e], &st.own_handle, theme);
+                queue!(stdout, style::SetForegroundColor(c)).ok();
+                let _ = write!(stdout, "{}", &line[s..e]);
+                queue!(stdout, style::SetForegroundColor(color)).ok();
+                let _ = write!(stdout, "{}", &line[e..]);
+            }
+            None => {
+                let _ = write!(stdout, "{}", line);
+            }
+        }
+        queue!(stdout, style::ResetColor).ok();
+    }
+    // メッセージ領域の高さ（ステータスバーと入力行を除く）
+    fn view_height(h: u16) -> usize {
+        let input_row = h.saturating_sub(1);
+        if input_row > 1 {
+            (input_row - 1) as usize
+        } else {
+            0
+        }
+    }
+    /// 全体を描き直し、折返し後の行数を返す
+    #[allow(clippy::too_many_arguments)]
+    fn redraw_full(
+        stdout: &mut io::Stdout,
+        messages: &[String],
+        scroll_offset: usize,
+        status_msg: &str,
+        past_mode: bool,
+        date_range: &str,
+        sig_counts: SigCounts,
+        st: &DrawState,
+    ) -> usize {
+        use crossterm::terminal::{Clear, ClearType};
+        use crossterm::{cursor, queue, terminal};
+        let (w, h) = terminal::size().unwrap_or((80, 24));
+        let safe_w = w.saturating_sub(1) as usize; // 末尾1桁は未使用にして自動折返しを回避
+        let input_row = h.saturating_sub(1); // 最下行
+        // スクロールオフセット: 0 が最新。offset が増えると過去方向
+        // 画面全消去は避けステータス+メッセージ領域のみクリア
+        queue!(stdout, cursor::Hide).ok();
+        let flat_lines = flatten(
+            messages,
+            0,
+            None,
+            safe_w,
+            st,
+            st.found.filter(|_| past_mode),
+        );
+        let total = flat_lines.len();
+        let view_h = view_height(h);
+        let max_scroll = total.saturating_sub(view_h);
+        let off = scroll_offset.min(max_scroll);
+        draw_status_bar(
+            stdout, safe_w, off, max_scroll, status_msg, past_mode, date_range, sig_counts, st,
+        );
         // メッセージ領域クリア & 描画 (y=1 .. input_row-1)
         for y in 1..input_row {
             queue!(stdout, cursor::MoveTo(0, y), Clear(ClearType::CurrentLine)).ok();
@@ -309,20 +745,73 @@ async fn main() {
             if y >= input_row {
                 break;
             }
-            queue!(stdout, cursor::MoveTo(0, y)).ok();
-            let _ = write!(stdout, "{}", line);
+            draw_line(stdout, y, line, st);
+        }
+        total
+    }
+    /// 最下端を表示中に増えた行だけを描き足す。
+    /// 領域が埋まっていればメッセージ領域だけをスクロールさせて下端に描く。
+    /// 描き足しで済まなければ None を返す（呼び出し側で全体を描き直す）
+    #[allow(clippy::too_many_arguments)]
+    fn redraw_tail(
+        stdout: &mut io::Stdout,
+        new_messages: &[String],
+        from: usize,
+        before: Option<&str>,
+        prev_total: usize,
+        status_msg: &str,
+        sig_counts: SigCounts,
+        st: &DrawState,
+    ) -> Option<usize> {
+        use crossterm::terminal::{Clear, ClearType, ScrollUp};
+        use crossterm::{cursor, queue, terminal};
+        let (w, h) = terminal::size().unwrap_or((80, 24));
+        let safe_w = w.saturating_sub(1) as usize;
+        let view_h = view_height(h);
+        let lines = flatten(new_messages, from, before, safe_w, st, None);
+        if lines.len() >= view_h {
+            return None;
+        }
+        queue!(stdout, cursor::Hide).ok();
+        let total = prev_total + lines.len();
+        let max_scroll = total.saturating_sub(view_h);
+        draw_status_bar(
+            stdout, safe_w, 0, max_scroll, status_msg, false, "", sig_counts, st,
+        );
+        let shown = prev_total.min(view_h);
+        let overflow = (shown + lines.len()).saturating_sub(view_h);
+        if overflow > 0 {
+            // スクロール範囲をメッセージ領域 (1始まりで 2..=view_h+1 行) に限る
+            let _ = write!(stdout, "\x1b[2;{}r", view_h + 1);
+            queue!(stdout, ScrollUp(overflow as u16)).ok();
+            let _ = write!(stdout, "\x1b[r");
+        }
+        let top = shown - overflow;
+        for (i, line) in lines.iter().enumerate() {
+            let y = (top + i) as u16 + 1;
+            queue!(stdout, cursor::MoveTo(0, y), Clear(ClearType::CurrentLine)).ok();
+            draw_line(stdout, y, line, st);
         }
-        (w, h)
+        Some(total)
     }
-    fn redraw_input(stdout: &mut io::Stdout, input: &str, cursor_pos: usize) {
+    fn redraw_input(stdout: &mut io::Stdout, input: &str, cursor_pos: usize, st: &DrawState) {
+        use crossterm::style;
         use crossterm::terminal::{Clear, ClearType};
         use crossterm::{cursor, queue, terminal};
         let (w, h) = terminal::size().unwrap_or((80, 24));
         let y = h.saturating_sub(1);
         let safe_w = w.saturating_sub(1) as usize; // 自動折返し回避
         queue!(stdout, cursor::MoveTo(0, y), Clear(ClearType::CurrentLine)).ok();
+        // プロンプトは入力欄の半分まで
+        let prompt = st.prompt.render(&template::Values {
+            handle: &st.own_handle,
+            peers: st.peers,
+            ..Default::default()
+        });
+        let prompt = truncate_display(&prompt, safe_w / 2);
+        let prompt_w = display_width(&prompt);
         // 入力の表示幅でスクロールしつつ表示（カーソル位置を中心に可視化）
-        let max_input_cols = safe_w.saturating_sub(2); // "> " のぶん、末尾1桁は空ける
+        let max_input_cols = safe_w.saturating_sub(prompt_w); // プロンプトのぶん、末尾1桁は空ける
         let (left, right) = split_at_char(input, cursor_pos);
         let left_w = display_width(&left);
         let shown_input = if left_w <= max_input_cols {
@@ -332,14 +821,19 @@ async fn main() {
         } else {
             take_last_display(&left, max_input_cols)
         };
-        let prompt = format!("> {}", shown_input);
+        // プロンプト記号は自分の投稿と同じ色
+        if let Some(c) = st.theme.line_color(theme::LineKind::Own) {
+            queue!(stdout, style::SetForegroundColor(c)).ok();
+        }
         let _ = write!(stdout, "{}", prompt);
+        queue!(stdout, style::ResetColor).ok();
+        let _ = write!(stdout, "{}", shown_input);
         let caret_cols_in_prompt = if left_w <= max_input_cols {
             left_w
         } else {
             display_width(&shown_input)
         };
-        let caret_cols_total = 2usize + caret_cols_in_prompt;
+        let caret_cols_total = prompt_w + caret_cols_in_prompt;
         let caret_x = if w == 0 {
             0
         } else {
@@ -347,820 +841,591 @@ async fn main() {
         } as u16;
         queue!(stdout, cursor::MoveTo(caret_x, y), cursor::Show).ok();
     }
-    fn render(
-        stdout: &mut io::Stdout,
-        messages: &Vec<String>,
-        input: &str,
-        st: &mut DrawState,
-        scroll_offset: usize,
-        status_msg: &str,
-        cursor_pos: usize,
-        past_mode: bool,
-        date_range: &str,
-    ) {
-        let need_full = st.force_full || st.last_msg_len != messages.len();
-        if need_full {
+    /// ピアの選択をメッセージ領域の上に重ねて描く。選んでいる行が見えるようずらす
+    fn draw_picker(stdout: &mut io::Stdout, picker: &PeerPicker) {
+        use crossterm::style;
+        use crossterm::terminal::{Clear, ClearType};
+        use crossterm::{cursor, queue, terminal};
+        let (w, h) = terminal::size().unwrap_or((80, 24));
+        let safe_w = w.saturating_sub(1) as usize;
+        let view_h = view_height(h);
+        if view_h == 0 {
+            return;
+        }
+        let (lines, selected) = picker.lines();
+        let skip = selected.map_or(0, |s| s.saturating_sub(view_h - 1));
+        let shown = lines
+            .iter()
+            .enumerate()
+            .take(1)
+            .chain(lines.iter().enumerate().skip(1 + skip))
+            .take(view_h);
+        queue!(stdout, cursor::Hide).ok();
+        for (y, (i, line)) in shown.enumerate() {
+            let y = y as u16 + 1;
+            queue!(stdout, cursor::MoveTo(0, y), Clear(ClearType::CurrentLine)).ok();
+            let line = pad_display(&truncate_display(line, safe_w), safe_w);
+            if Some(i) == selected {
+                queue!(stdout, style::SetAttribute(style::Attribute::Reverse)).ok();
+                let _ = write!(stdout, "{}", line);
+                queue!(stdout, style::SetAttribute(style::Attribute::Reset)).ok();
+            } else {
+                let _ = write!(stdout, "{}", line);
+            }
+        }
+    }
+    fn render(stdout: &mut io::Stdout, tui: &mut Tui) {
+        let (messages, scroll_offset) = if tui.past_mode {
+            (&tui.past_messages, tui.past_scroll_offset)
+        } else {
+            (&tui.messages, tui.scroll_offset)
+        };
+        let sig_counts = tui.shown_sig_counts();
+        let size = crossterm::terminal::size().unwrap_or((80, 24));
+        let st = &mut tui.draw;
+        // 受信はループ先頭でまとめて取り込んであるので、ここで描くのは1回だけ
+        let plan = match st.plan(messages.len(), scroll_offset == 0, size) {
+            Repaint::Tail { .. } if tui.past_mode => Repaint::Full,
+            p => p,
+        };
+        let total = match plan {
+            Repaint::Full => None,
+            Repaint::Tail { from } => redraw_tail(
+                stdout,
+                &messages[from..],
+                from,
+                from.checked_sub(1).map(|i| messages[i].as_str()),
+                st.last_total_lines,
+                &tui.status_msg,
+                sig_counts,
+                st,
+            ),
+            Repaint::None => Some(st.last_total_lines),
+        };
+        let total = total.unwrap_or_else(|| {
             redraw_full(
                 stdout,
                 messages,
                 scroll_offset,
-                status_msg,
-                past_mode,
-                date_range,
-            );
-            st.last_msg_len = messages.len();
-            st.force_full = false;
+                &tui.status_msg,
+                tui.past_mode,
+                &tui.past_date_range,
+                sig_counts,
+                st,
+            )
+        });
+        let repainted = plan != Repaint::None;
+        st.rendered(messages.len(), size, total);
+        if repainted || st.last_input_len != tui.input.len() || st.last_cursor_pos != tui.cursor_pos
+        {
+            redraw_input(stdout, &tui.input, tui.cursor_pos, st);
+            st.last_input_len = tui.input.len();
+            st.last_cursor_pos = tui.cursor_pos;
         }
-        if need_full || st.last_input_len != input.len() || st.last_cursor_pos != cursor_pos {
-            redraw_input(stdout, input, cursor_pos);
-            st.last_input_len = input.len();
-            st.last_cursor_pos = cursor_pos;
+        if let Some(picker) = &tui.picker {
+            draw_picker(stdout, picker);
         }
         let _ = stdout.flush();
     }
     let mut draw_state = DrawState::new();
-    // 画面への追加のみ（保存しない）
-    fn push_msg(messages: &mut Vec<String>, st: &mut DrawState, msg: String) {
-        messages.push(msg);
-        st.force_full = true;
-    }
-    // ユーザー投稿のみ保存するための専用関数
-    fn push_user_msg(messages: &mut Vec<String>, st: &mut DrawState, msg: String) {
-        let now = crate::current_unix_millis();
-        storage::append_message(now, &msg);
-        messages.push(msg);
-        st.force_full = true;
-    }
-    // デバッグ専用ログ。config の debug=true のときのみ流す
-    fn push_debug_msg(messages: &mut Vec<String>, st: &mut DrawState, msg: impl Into<String>) {
-        if crate::config::is_debug() {
-            push_msg(messages, st, format!("[DEBUG] {}", msg.into()));
-        }
+    // NO_COLOR 環境変数か [theme] no_color = true なら /theme で切り替えても色を出さない
+    let force_no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty())
+        || config::get_value(config::keys::THEME_NO_COLOR)
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+    draw_state.theme = config::try_config()
+        .map(|cfg| Theme::from_config(&cfg))
+        .unwrap_or_default();
+    draw_state.theme.no_color |= force_no_color;
+    draw_state.own_handle = app.handle.clone();
+    draw_state.relay = app.relay;
+    draw_state.compact = config::get_value(config::keys::COMPACT)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    draw_state.max_display_chars = config::get_value(config::keys::MAX_DISPLAY_CHARS)
+        .and_then(|v| v.as_integer())
+        .and_then(|n| usize::try_from(n).ok())
+        .unwrap_or(0);
+    // status_format / prompt_format は起動時に一度だけ解析する（不正なら既定の表示）
+    let mut format_errors = Vec::new();
+    let mut parse_format = |key: &str| {
+        let s = config::get_value(key).and_then(|v| v.as_str().map(str::to_string))?;
+        template::Template::parse(&s)
+            .map_err(|e| format_errors.push(format!("⚠ {}: {}", key, e)))
+            .ok()
+    };
+    draw_state.status_format = parse_format(config::keys::STATUS_FORMAT);
+    if let Some(t) = parse_format(config::keys::PROMPT_FORMAT) {
+        draw_state.prompt = t;
     }
-    let mut status_msg = if handle.starts_with('@') && handle.chars().count() < 80 {
+    draw_state.bookmarks = storage::bookmarked_ids().into_iter().collect();
+    draw_state.handle_colors = theme::HandleColors::from_saved(storage::handle_colors());
+    let status_msg = if let Some(w) = &storage_warning {
+        format!("⚠ {}", w)
+    } else if app.spectator {
+        "観戦モード: 受信と中継のみ行います（発言不可）".into()
+    } else if app.has_valid_handle() {
         "TUI開始。/help でコマンド一覧。/open <port> または /connect <token>。/exit で終了。[F2: 選択/コピーモード切替]".into()
+    } else if app.public_key.is_none() {
+        "はじめに: 使うハンドルを入力してください (例: @alice)。署名鍵も一緒に作ります".into()
     } else {
-        "ハンドル未設定です。/handle @name を先に実行してください".into()
+        "はじめに: 使うハンドルを入力してください (例: @alice)".into()
     };
-    // 過去ログモード関連
-    let mut past_mode: bool = false; // 過去ログモード
-    let mut past_dates: Vec<String> = Vec::new();
-    let mut past_date_range: String = String::new();
-    let mut past_earliest_idx: Option<usize> = None; // 読み込み済みで最も古い day の past_dates index
-
-    // スクロールと履歴状態
-    let mut scroll_offset: usize = 0; // 0=最新 (一番下)。増えると過去へ。
-    let mut past_scroll_offset: usize = 0; // 過去ログ用の独立オフセット
-    let mut history: Vec<String> = Vec::new();
-    let mut history_pos: Option<usize> = None; // history 内のインデックス (0..len-1)。None は編集中の新規行。
-    // VS Code 統合ターミナルでのマウス選択・コピー用モード
-    // F2 でトグル: 有効時は MouseCapture を解除し、画面更新を止めて選択しやすくする
-    let mut copy_mode: bool = false;
-
-    // 入力カーソル（文字単位）
-    let mut cursor_pos: usize = 0;
+    // TUI 状態
+    let mut tui = Tui::new(status_msg, draw_state, force_no_color);
+    if let Some(n) = config::get_value(config::keys::SCROLLBACK_MAX)
+        .and_then(|v| v.as_integer())
+        .and_then(|n| usize::try_from(n).ok())
+    {
+        tui.scrollback_max = n;
+    }
+    tui.honor_urgent = config::get_value(config::keys::HONOR_URGENT)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    tui.urgent_bell_in_dnd = config::get_value(config::keys::URGENT_BELL_IN_DND)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    // dnd_hours = "22:00-07:00" の間は DM のベルと通知を止める（ローカル時刻）
+    if let Some(s) =
+        config::get_value(config::keys::DND_HOURS).and_then(|v| v.as_str().map(str::to_string))
+    {
+        match app::QuietHours::parse(&s) {
+            Some(q) => tui.quiet_hours = Some(q),
+            None => tui.push_msg(format!(
+                "⚠ dnd_hours: '{}' を読めません (例: 22:00-07:00)",
+                s
+            )),
+        }
+    }
+    // 前回落ちたときの書きかけの入力があれば戻す（ハンドル入力中は戻さない）
+    let (inflight, left) = app::Inflight::load(profile.db.with_extension("inflight"));
+    if let Some((text, cursor)) = left.filter(|_| !app.first_run) {
+        tui.restore_inflight(text, cursor);
+    }
+    let inflight_path = inflight.path().to_path_buf();
+    tui.inflight = Some(inflight);
+    install_panic_hook(move || {
+        restore_terminal(&mut io::stdout());
+        if inflight_path.exists() {
+            eprintln!(
+                "書きかけの入力は {} に残っています (次回の起動で戻ります)",
+                inflight_path.display()
+            );
+        }
+    });
+    // ステータスバーはすぐ上書きされるので画面にも残す
+    if let Some(v) = durability_value.filter(|v| storage::DurabilityMode::parse(v).is_none()) {
+        tui.push_msg(format!(
+            "⚠ durability: '{}' を読めません (safe / fast / relaxed)。safe で保存します",
+            v
+        ));
+    }
+    if let Some(w) = storage_warning {
+        tui.push_msg(format!("⚠ {}", w));
+    }
+    if let Some(e) = mouse_error {
+        tui.mouse_capture = false;
+        tui.push_msg(format!(
+            "マウスを使えない端末のようです ({e})。{}",
+            app::KEYBOARD_SCROLL_HINT
+        ));
+    }
+    if let Some(note) = migration_note {
+        tui.push_msg(note);
+    }
+    if let Some(note) = metrics_note {
+        tui.push_msg(note);
+    }
+    for e in format_errors {
+        tui.push_msg(e);
+    }
+    // auto_open=true なら起動直後に待受を開始（トークンはネットワークスレッドから届く）
+    let auto_open = config::try_config().and_then(|cfg| auto_open_command(&cfg));
+    match auto_open {
+        Some(Ok(cmd)) => {
+            let (tx_thread, handle_task) = spawn_network_thread(tx_to_main.clone());
+            let _ = tx_thread.send(cmd).await;
+            active_thread_tx = Some(tx_thread);
+            active_thread_handle = Some(handle_task);
+        }
+        Some(Err(reason)) => tui.status_msg = reason,
+        None => {}
+    }
 
-    while running {
+    while tui.running {
+        if let Ok(sig) = rx_signal.try_recv() {
+            stopped_by = Some(sig);
+            break;
+        }
+        {
+            use chrono::Timelike;
+            let now = chrono::Local::now();
+            tui.refresh_dnd((now.hour() * 60 + now.minute()) as u16);
+        }
         // ネットワークからのメッセージ取り込み (先に集めてからイベント / 描画判定)
         while let Ok(ev) = rx_from_threads.try_recv() {
-            match ev {
-                rpc::Event::Message(m) => {
-                    push_msg(&mut messages, &mut draw_state, m);
-                    if scroll_offset == 0 { /* stay bottom */ }
-                }
-                rpc::Event::DebugMessage(m) => {
-                    push_debug_msg(&mut messages, &mut draw_state, m);
-                }
-            }
+            tui.on_event(ev, &app.peers);
+        }
+        tui.save_inflight(p2witter::utils::current_unix_millis());
+        if std::mem::take(&mut tui.bell) {
+            let _ = write!(stdout, "\x07");
         }
 
         // イベント待ち (50ms)
-        if event::poll(Duration::from_millis(50)).unwrap_or(false) {
-            if let Ok(ev) = event::read() {
-                match ev {
-                    Event::Key(KeyEvent {
-                        code,
-                        modifiers,
-                        kind,
-                        ..
-                    }) => {
-                        // KeyEventKind を見て Press のみ処理 (Release/Repeat を無視) → 二重入力防止
-                        if kind != KeyEventKind::Press {
-                            continue;
-                        }
-                        // 選択/コピーモード中は F2 のみ受け付け、それ以外は UI 操作を抑止
-                        if copy_mode {
-                            match code {
-                                KeyCode::F(2) => {
-                                    push_debug_msg(&mut messages, &mut draw_state, "F2 push");
-                                    copy_mode = false;
-                                    // マウスキャプチャを再度有効化（失敗時はステータスに表示）
-                                    if let Err(e) = execute!(stdout, EnableMouseCapture) {
-                                        status_msg = format!(
-                                            "選択/コピーモード終了（MouseCapture再有効化失敗: {e}）"
-                                        );
-                                    } else {
-                                        status_msg = "選択/コピーモード終了".into();
-                                    }
-                                    draw_state.force_full = true;
-                                    // 復帰時に即再描画
-                                    let view: &Vec<String> =
-                                        if past_mode { &past_messages } else { &messages };
-                                    let off = if past_mode {
-                                        past_scroll_offset
-                                    } else {
-                                        scroll_offset
-                                    };
-                                    render(
-                                        &mut stdout,
-                                        view,
-                                        &input,
-                                        &mut draw_state,
-                                        off,
-                                        &status_msg,
-                                        cursor_pos,
-                                        past_mode,
-                                        &past_date_range,
-                                    );
-                                }
-                                _ => { /* 選択の邪魔をしない */ }
+        if event::poll(Duration::from_millis(50)).unwrap_or(false)
+            && let Ok(ev) = event::read()
+        {
+            match ev {
+                Event::Key(KeyEvent {
+                    code,
+                    modifiers,
+                    kind,
+                    ..
+                }) => {
+                    // KeyEventKind を見て Press のみ処理 (Release/Repeat を無視) → 二重入力防止
+                    if kind != KeyEventKind::Press {
+                        continue;
+                    }
+                    // 選択/コピーモード中は F2 のみ受け付け、それ以外は UI 操作を抑止
+                    if tui.copy_mode {
+                        if code == KeyCode::F(2) {
+                            tui.push_debug_msg("F2 push");
+                            tui.copy_mode = false;
+                            // マウスキャプチャを再度有効化（失敗時はステータスに表示）
+                            if let Err(e) = execute!(stdout, EnableMouseCapture) {
+                                tui.mouse_capture = false;
+                                tui.set_status(format!(
+                                    "選択/コピーモード終了（MouseCapture再有効化失敗: {e}）。{}",
+                                    app::KEYBOARD_SCROLL_HINT
+                                ));
+                            } else {
+                                tui.mouse_capture = true;
+                                tui.set_status("選択/コピーモード終了");
                             }
-                            continue;
+                            // 復帰時に即再描画
+                            render(&mut stdout, &mut tui);
                         }
+                        continue;
+                    }
+                    let word = modifiers.contains(KeyModifiers::CONTROL);
+                    let shift = modifiers.contains(KeyModifiers::SHIFT);
+                    // メッセージ領域の高さ（ステータスバーと入力行を除く）
+                    let view_h = crossterm::terminal::size()
+                        .map(|(_, h)| h.saturating_sub(2) as usize)
+                        .unwrap_or(22);
+                    // ピアの選択中は移動・決定・閉じるだけを受け付ける
+                    if let Some(picker) = tui.picker.as_mut() {
                         match code {
-                            // F2 で選択/コピーモードに入る
-                            KeyCode::F(2) => {
-                                // 先に MouseCapture を解除（失敗時はステータスに表示）
-                                let mut msg = "選択/コピーモード: マウスで選択し、Ctrl+Shift+C でコピー、F2 で復帰".to_string();
-                                if let Err(e) = execute!(stdout, DisableMouseCapture) {
-                                    msg = format!("選択/コピーモード: MouseCapture解除失敗: {e}");
-                                }
-                                status_msg = msg;
-                                draw_state.force_full = true;
-                                copy_mode = true;
-                                // 案内を描画（この直後からはループ末尾の描画は抑止される）
-                                let view: &Vec<String> =
-                                    if past_mode { &past_messages } else { &messages };
-                                let off = if past_mode {
-                                    past_scroll_offset
-                                } else {
-                                    scroll_offset
-                                };
-                                render(
-                                    &mut stdout,
-                                    view,
-                                    &input,
-                                    &mut draw_state,
-                                    off,
-                                    &status_msg,
-                                    cursor_pos,
-                                    past_mode,
-                                    &past_date_range,
-                                );
-                            }
-                            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
-                                running = false;
-                            }
-                            KeyCode::Char(ch) => {
-                                let (mut left, right) = split_at_char(&input, cursor_pos);
-                                left.push(ch);
-                                input = left + &right;
-                                cursor_pos += 1;
-                            }
-                            KeyCode::Backspace => {
-                                if cursor_pos > 0 {
-                                    let (left, right) = split_at_char(&input, cursor_pos);
-                                    let mut left2 = left;
-                                    left2.pop(); // 1 文字削除（pop は UTF-8 末尾 1 文字）
-                                    input = left2 + &right;
-                                    cursor_pos -= 1;
-                                }
-                            }
-                            KeyCode::Left => {
-                                if cursor_pos > 0 {
-                                    if modifiers.contains(KeyModifiers::CONTROL) {
-                                        // 単語単位で左へ
-                                        let mut new_pos = cursor_pos;
-                                        let chars: Vec<char> = input.chars().collect();
-                                        // 直前が空白ならスキップ
-                                        while new_pos > 0
-                                            && (chars[new_pos - 1].is_whitespace()
-                                                || chars[new_pos - 1] == '=')
-                                        {
-                                            new_pos -= 1;
-                                        }
-                                        // 単語の先頭まで移動
-                                        while new_pos > 0
-                                            && (!chars[new_pos - 1].is_whitespace()
-                                                || chars[new_pos - 1] == '=')
-                                        {
-                                            new_pos -= 1;
-                                        }
-                                        cursor_pos = new_pos;
-                                    } else {
-                                        cursor_pos -= 1;
-                                    }
-                                }
-                            }
-                            KeyCode::Right => {
-                                if cursor_pos < input.chars().count() {
-                                    if modifiers.contains(KeyModifiers::CONTROL) {
-                                        // 単語単位で右へ
-                                        let mut new_pos = cursor_pos;
-                                        let chars: Vec<char> = input.chars().collect();
-                                        // 直後が空白ならスキップ
-                                        while new_pos < chars.len()
-                                            && (chars[new_pos].is_whitespace()
-                                                || chars[new_pos] == '=')
-                                        {
-                                            new_pos += 1;
-                                        }
-                                        // 単語の末尾まで移動
-                                        while new_pos < chars.len()
-                                            && (!chars[new_pos].is_whitespace()
-                                                || chars[new_pos] == '=')
-                                        {
-                                            new_pos += 1;
-                                        }
-                                        cursor_pos = new_pos;
-                                    } else {
-                                        cursor_pos += 1;
-                                    }
-                                }
+                            KeyCode::Up => picker.move_selection(true),
+                            KeyCode::Down => picker.move_selection(false),
+                            KeyCode::Enter => tui.confirm_pick(),
+                            KeyCode::Esc => tui.close_picker(),
+                            KeyCode::Char('c') if word => tui.running = false,
+                            _ => {}
+                        }
+                        render(&mut stdout, &mut tui);
+                        continue;
+                    }
+                    match code {
+                        // F2 で選択/コピーモードに入る
+                        KeyCode::F(2) => {
+                            // 先に MouseCapture を解除（失敗時はステータスに表示）
+                            let mut msg = "選択/コピーモード: マウスで選択し、Ctrl+Shift+C でコピー、F2 で復帰".to_string();
+                            if tui.mouse_capture
+                                && let Err(e) = execute!(stdout, DisableMouseCapture)
+                            {
+                                msg = format!("選択/コピーモード: MouseCapture解除失敗: {e}");
                             }
-                            KeyCode::Enter => {
-                                let line = input.trim().to_string();
-                                let parts: Vec<String> =
-                                    line.split_whitespace().map(|s| s.to_string()).collect();
-                                // ローカルエコーは行わない (サーバ経由で戻る表示と二重防止)
-                                match parts.get(0).map(|s| s.as_str()) {
-                                    Some("/help") => {
-                                        if parts.len() >= 2 {
-                                            let target = &parts[1];
-                                            let key = if target.starts_with('/') {
-                                                target.clone()
-                                            } else {
-                                                format!("/{}", target)
-                                            };
-                                            if let Some(spec) = find_command(&key) {
-                                                let msg = format!(
-                                                    "{}\n  説明: {}\n  使い方: {}",
-                                                    spec.name, spec.description, spec.usage
-                                                );
-                                                push_msg(&mut messages, &mut draw_state, msg);
-                                            } else {
-                                                status_msg = format!(
-                                                    "不明なコマンド: {} (/help で一覧)",
-                                                    key
-                                                );
-                                                draw_state.force_full = true;
-                                            }
-                                        } else {
-                                            let mut lines = vec!["コマンド一覧:".to_string()];
-                                            for c in COMMANDS.iter() {
-                                                if c.name == "/past" {
-                                                    lines.push(format!("{:10} - {}", c.name, "過去ログモードのON/OFFを切替。ONで最新日を読み込み。スクロール最上端到達で前日追加ロード"));
-                                                } else {
-                                                    lines.push(format!(
-                                                        "{:10} - {}",
-                                                        c.name, c.description
-                                                    ));
-                                                }
-                                            }
-                                            push_msg(
-                                                &mut messages,
-                                                &mut draw_state,
-                                                lines.join("\n"),
-                                            );
-                                        }
-                                    }
-                                    Some("/open") => {
-                                        if let Some(port) = parts.get(1) {
-                                            if !(handle.starts_with('@')
-                                                && handle.chars().count() < 80)
-                                            {
-                                                status_msg = "ハンドル未設定です。/handle @name を先に実行してください".into();
-                                                draw_state.force_full = true;
-                                                continue;
-                                            }
-                                            if active_thread_tx.is_none() {
-                                                let tx_main = tx_to_main.clone();
-                                                let (tx_thread, rx_thread) = mpsc::channel(100);
-                                                let handle_task = tokio::spawn(async move {
-                                                    network_handler::network_handler(
-                                                        tx_main, rx_thread,
-                                                    )
-                                                    .await;
-                                                });
-                                                active_thread_tx = Some(tx_thread);
-                                                active_thread_handle = Some(handle_task);
-                                            }
-                                            if let Some(ref tx) = active_thread_tx {
-                                                let _ =
-                                                    tx.send(rpc::Command::Open(port.clone())).await;
-                                            }
-                                        } else {
-                                            status_msg = "使い方: /open <port>".into();
-                                            draw_state.force_full = true;
-                                        }
-                                    }
-                                    Some("/connect") => {
-                                        if let Some(arg) = parts.get(1) {
-                                            if !(handle.starts_with('@')
-                                                && handle.chars().count() < 80)
-                                            {
-                                                status_msg = "ハンドル未設定です。/handle @name を先に実行してください".into();
-                                                draw_state.force_full = true;
-                                                continue;
-                                            }
-                                            if active_thread_tx.is_none() {
-                                                let tx_main = tx_to_main.clone();
-                                                let (tx_thread, rx_thread) = mpsc::channel(100);
-                                                let handle_task = tokio::spawn(async move {
-                                                    network_handler::network_handler(
-                                                        tx_main, rx_thread,
-                                                    )
-                                                    .await;
-                                                });
-                                                active_thread_tx = Some(tx_thread);
-                                                active_thread_handle = Some(handle_task);
-                                            }
-                                            // 入力が平文アドレスなら自動でトークン化して送る
-                                            let token = if arg.contains(':') {
-                                                match crypto::encrypt_conninfo_to_hex(arg) {
-                                                    Ok(t) => t,
-                                                    Err(_) => arg.clone(),
-                                                }
-                                            } else {
-                                                arg.clone()
-                                            };
-                                            if let Some(ref tx) = active_thread_tx {
-                                                let _ = tx.send(rpc::Command::Connect(token)).await;
-                                            }
-                                        } else {
-                                            status_msg = "使い方: /connect <token>".into();
-                                            draw_state.force_full = true;
-                                        }
-                                    }
-                                    Some("/handle") => {
-                                        if let Some(name) = parts.get(1) {
-                                            let valid =
-                                                name.starts_with('@') && name.chars().count() < 80;
-                                            if valid {
-                                                handle = name.clone();
-                                                // 保存
-                                                let _ = config::upsert_value_and_save(
-                                                    "user.handle",
-                                                    toml::Value::String(handle.clone()),
-                                                );
-                                                status_msg =
-                                                    format!("ハンドルを {} に設定", handle);
-                                                // ネットワークスレッドがあれば伝える
-                                                if let Some(ref tx) = active_thread_tx {
-                                                    let _ = tx
-                                                        .send(rpc::Command::Handle(handle.clone()))
-                                                        .await;
-                                                }
-                                            } else {
-                                                status_msg =
-                                                    "使い方: /handle @name （@で開始し80文字未満）"
-                                                        .into();
-                                            }
-                                            draw_state.force_full = true;
-                                        } else {
-                                            status_msg = "使い方: /handle @name".into();
-                                            draw_state.force_full = true;
-                                        }
-                                    }
-
-                                    Some("/past") => {
-                                        if past_mode {
-                                            // モード OFF
-                                            past_mode = false;
-                                            status_msg = "過去ログモード終了".into();
-                                            draw_state.force_full = true;
-                                            // スクロールは通常表示側を採用
-                                            // 過去ログ側は保持
-                                        } else {
-                                            past_mode = true; // モード ON
-                                            past_dates = storage::list_dates();
-                                            past_dates.sort();
-                                            // 最新日付のみロードし、date_range を設定
-                                            if let Some(last_idx) = past_dates.len().checked_sub(1)
-                                            {
-                                                let day = &past_dates[last_idx];
-                                                // 構造化読み込みに切替
-                                                let recs = storage::load_structured_day(day);
-                                                past_messages.clear();
-                                                past_scroll_offset = 0;
-                                                for r in recs {
-                                                    // 表示用フォーマット: 可能ならハンドル、なければ from_peer_id で擬似表記
-                                                    let line = if r.handle.is_some() {
-                                                        format!(
-                                                            "{} {}",
-                                                            r.text,
-                                                            if r.signed_ok == Some(true) {
-                                                                "○"
-                                                            } else {
-                                                                "・"
-                                                            }
-                                                        )
-                                                    } else if let Some(pid) = r.from_peer_id {
-                                                        format!(
-                                                            "@{}: {} {}",
-                                                            pid,
-                                                            r.text,
-                                                            if r.signed_ok == Some(true) {
-                                                                "○"
-                                                            } else {
-                                                                "・"
-                                                            }
-                                                        )
-                                                    } else {
-                                                        r.text
-                                                    };
-                                                    past_messages.push(line);
-                                                }
-                                                past_date_range = format!("{}~{}", day, day);
-                                                past_earliest_idx = Some(last_idx);
-                                                status_msg =
-                                                    format!("過去ログモード {}", past_date_range);
-                                            } else {
-                                                status_msg = "過去ログなし".into();
-                                            }
-                                            draw_state.force_full = true;
-                                        }
-                                    }
-                                    Some("/exit") => {
-                                        status_msg = "終了中...".into();
-                                        draw_state.force_full = true;
-                                        if let Some(ref tx) = active_thread_tx {
-                                            let _ = tx.send(rpc::Command::Shutdown).await;
-                                            drop(active_thread_tx.take());
-                                        }
-                                        if let Some(handle) = active_thread_handle.take() {
-                                            let _ = handle.await;
-                                        }
-                                        running = false;
-                                    }
-                                    Some("/init") => match crypto::generate_ed25519_keypair() {
-                                        Ok(k) => {
-                                            config::upsert_value_and_save(
-                                                "key.pkcs8",
-                                                toml::Value::String(crypto::to_hex(&k.pkcs8)),
-                                            )
-                                            .ok();
-                                            config::upsert_value_and_save(
-                                                "key.public",
-                                                toml::Value::String(crypto::to_hex(&k.public)),
-                                            )
-                                            .ok();
-                                            status_msg =
-                                                format!("鍵生成完了 public_len={}", k.public.len());
-                                            draw_state.force_full = true;
-                                        }
-                                        Err(e) => {
-                                            status_msg = format!("鍵生成失敗: {e}");
-                                            draw_state.force_full = true;
-                                        }
-                                    },
-                                    Some("/peers") => {
-                                        if let Some(ref tx) = active_thread_tx {
-                                            let _ = tx.send(rpc::Command::PeerList).await;
-                                        } else {
-                                            status_msg =
-                                                "ネットワークスレッドがありません。".into();
-                                            draw_state.force_full = true;
-                                        }
-                                    }
-                                    Some("/close") => {
+                            tui.set_status(msg);
+                            tui.copy_mode = true;
+                            // 案内を描画（この直後からはループ末尾の描画は抑止される）
+                            render(&mut stdout, &mut tui);
+                        }
+                        KeyCode::Char('c') if word => tui.running = false,
+                        // 過去ログで /find 中なら、空の入力行での n/N は次・前の一致へ
+                        KeyCode::Char(ch @ ('n' | 'N'))
+                            if tui.past_mode && tui.find.is_some() && tui.input.is_empty() =>
+                        {
+                            tui.find(None, ch == 'n')
+                        }
+                        KeyCode::Char(ch) => tui.insert_char(ch),
+                        KeyCode::Backspace => tui.backspace(),
+                        KeyCode::Left => tui.move_left(word),
+                        KeyCode::Right => tui.move_right(word),
+                        KeyCode::Enter => {
+                            let line = tui.input.trim().to_string();
+                            app.network_running = active_thread_tx.is_some();
+                            let actions = commands::handle_command(&line, &mut app);
+                            tui.draw.own_handle = app.handle.clone();
+                            tui.draw.relay = app.relay;
+                            for action in actions {
+                                match tui.apply(action) {
+                                    Some(Action::Send(cmd)) => {
                                         if let Some(ref tx) = active_thread_tx {
-                                            let _ = tx.send(rpc::Command::Close).await;
-                                        } else {
-                                            status_msg =
-                                                "ネットワークスレッドがありません。".into();
-                                            draw_state.force_full = true;
+                                            let _ = tx.send(cmd).await;
                                         }
                                     }
-                                    Some("/disconnect") => {
-                                        if parts.len() < 2 {
-                                            status_msg = "使い方: /disconnect <id>".into();
-                                            draw_state.force_full = true;
-                                        } else if let Some(ref tx) = active_thread_tx {
-                                            let _ = tx
-                                                .send(rpc::Command::Disconnect(parts[1].clone()))
-                                                .await;
-                                        } else {
-                                            status_msg =
-                                                "ネットワークスレッドがありません。".into();
-                                            draw_state.force_full = true;
+                                    Some(Action::SpawnAndSend(cmd)) => {
+                                        if active_thread_tx.is_none() {
+                                            let (tx_thread, handle_task) =
+                                                spawn_network_thread(tx_to_main.clone());
+                                            active_thread_tx = Some(tx_thread);
+                                            active_thread_handle = Some(handle_task);
                                         }
-                                    }
-                                    Some("/dm") => {
-                                        if parts.len() < 3 {
-                                            status_msg = "使い方: /dm <to_id> <message>".into();
-                                            draw_state.force_full = true;
-                                        } else if let Some(ref tx) = active_thread_tx {
-                                            if !(handle.starts_with('@')
-                                                && handle.chars().count() < 80)
-                                            {
-                                                status_msg =
-                                                    "ハンドル未設定です。/handle @name".into();
-                                                draw_state.force_full = true;
-                                                continue;
-                                            }
-                                            let to_id = &parts[1];
-                                            let value = parts[2..].join(" ");
-                                            // ローカルエコー（ユーザー投稿は保存）
-                                            push_user_msg(
-                                                &mut messages,
-                                                &mut draw_state,
-                                                format!("{}: {} ○", handle, value),
-                                            );
-                                            let _ = tx
-                                                .send(rpc::Command::DM(to_id.clone(), value))
-                                                .await;
-                                        } else {
-                                            status_msg =
-                                                "ネットワークスレッドがありません。".into();
-                                            draw_state.force_full = true;
-                                        }
-                                    }
-                                    Some("/certs") => {
                                         if let Some(ref tx) = active_thread_tx {
-                                            let _ = tx.send(rpc::Command::Certs).await;
-                                        } else {
-                                            status_msg =
-                                                "ネットワークスレッドがありません。".into();
-                                            draw_state.force_full = true;
-                                        }
-                                    }
-                                    Some("/cert") => {
-                                        if parts.len() < 2 {
-                                            status_msg = "使い方: /cert <id>".into();
-                                            draw_state.force_full = true;
-                                        } else if let Some(ref tx) = active_thread_tx {
-                                            let _ = tx
-                                                .send(rpc::Command::Disconnect(parts[1].clone()))
-                                                .await;
-                                        } else {
-                                            status_msg =
-                                                "ネットワークスレッドがありません。".into();
-                                            draw_state.force_full = true;
-                                        }
-                                    }
-                                    Some("/msg") => {
-                                        if parts.len() < 2 {
-                                            status_msg = "使い方: /msg <message>".into();
-                                            draw_state.force_full = true;
-                                        } else if let Some(ref tx) = active_thread_tx {
-                                            if !(handle.starts_with('@')
-                                                && handle.chars().count() < 80)
-                                            {
-                                                status_msg =
-                                                    "ハンドル未設定です。/handle @name".into();
-                                                draw_state.force_full = true;
-                                                continue;
-                                            }
-                                            let value = parts[1..].join(" ");
-                                            // ローカルエコー（ユーザー投稿は保存）
-                                            push_user_msg(
-                                                &mut messages,
-                                                &mut draw_state,
-                                                format!("{}: {} ○", handle, value),
-                                            );
-                                            let _ = tx.send(rpc::Command::Chat(value)).await;
-                                        } else {
-                                            // ネットワークなしでもローカルエコーは行う
-                                            let value = parts[1..].join(" ");
-                                            push_user_msg(
-                                                &mut messages,
-                                                &mut draw_state,
-                                                format!("{}: {} ○", handle, value),
-                                            );
-                                            status_msg =
-                                                "ネットワークスレッドがありません。".into();
-                                            draw_state.force_full = true;
-                                        }
-                                    }
-                                    Some(other) => {
-                                        if other.starts_with('/') {
-                                            let hint = if find_command(other).is_some() {
-                                                ""
-                                            } else {
-                                                " (/help で一覧)"
-                                            };
-                                            status_msg =
-                                                format!("不明なコマンド: {}{}", other, hint);
-                                            draw_state.force_full = true;
-                                        } else if let Some(ref tx) = active_thread_tx {
-                                            if !(handle.starts_with('@')
-                                                && handle.chars().count() < 80)
-                                            {
-                                                status_msg =
-                                                    "ハンドル未設定です。/handle @name".into();
-                                                draw_state.force_full = true;
-                                                continue;
-                                            }
-                                            // ローカルエコー（ユーザー投稿は保存）
-                                            push_user_msg(
-                                                &mut messages,
-                                                &mut draw_state,
-                                                format!("{}: {} ○", handle, line),
-                                            );
-                                            let _ = tx.send(rpc::Command::Chat(line.clone())).await;
-                                        } else {
-                                            // ネットワークなしでもローカルエコー
-                                            push_user_msg(
-                                                &mut messages,
-                                                &mut draw_state,
-                                                format!("{}: {} ○", handle, line),
-                                            );
-                                            status_msg =
-                                                "ネットワークスレッドがありません。".into();
-                                            draw_state.force_full = true;
+                                            let _ = tx.send(cmd).await;
                                         }
                                     }
-                                    None => {}
-                                }
-                                input.clear();
-                                cursor_pos = 0;
-                                if !line.is_empty() {
-                                    history.push(line);
-                                    history_pos = None;
-                                }
-                            }
-                            KeyCode::Esc => {
-                                input.clear();
-                                cursor_pos = 0;
-                                history_pos = None;
-                            }
-                            KeyCode::Up => {
-                                if history.is_empty() {
-                                    continue;
+                                    Some(Action::Exit) => tui.running = false,
+                                    _ => {}
                                 }
-                                let new_pos = match history_pos {
-                                    None => history.len().saturating_sub(1),
-                                    Some(p) => p.saturating_sub(1),
-                                };
-                                history_pos = Some(new_pos);
-                                input = history[new_pos].clone();
-                                cursor_pos = input.chars().count();
                             }
-                            KeyCode::Down => {
-                                if history.is_empty() {
-                                    continue;
-                                }
-                                if let Some(p) = history_pos {
-                                    if p + 1 < history.len() {
-                                        history_pos = Some(p + 1);
-                                        input = history[p + 1].clone();
-                                    } else {
-                                        history_pos = None;
-                                        input.clear();
-                                    }
-                                    cursor_pos = input.chars().count();
-                                }
-                            }
-                            KeyCode::Tab => {}
-                            _ => {}
+                            tui.clear_input(Some(line));
                         }
+                        KeyCode::Esc => tui.clear_input(None),
+                        // マウスのホイールと同じスクロールをキーボードでも
+                        KeyCode::PageUp => tui.page_up(view_h),
+                        KeyCode::PageDown => tui.page_down(view_h),
+                        KeyCode::Up if shift => tui.scroll_up(view_h),
+                        KeyCode::Down if shift => tui.scroll_down(),
+                        KeyCode::Up => tui.history_prev(),
+                        KeyCode::Down => tui.history_next(),
+                        KeyCode::End => tui.scroll_to_bottom(),
+                        _ => {}
                     }
-                    Event::Mouse(me) => {
-                        use crossterm::event::MouseEventKind;
-                        use crossterm::terminal;
-                        // 入力行(最終行)以外でのスクロールのみ反応
-                        let (_w, h) = terminal::size().unwrap_or((80, 24));
-                        let input_row = h.saturating_sub(1); // 入力行
-                        if me.row == 0 { /* ステータス行: クリック等は今は無視 */ }
-                        if me.row == input_row {
-                            continue;
-                        }
-                        match me.kind {
-                            MouseEventKind::ScrollUp => {
-                                if past_mode {
-                                    past_scroll_offset = past_scroll_offset.saturating_add(1);
-                                } else {
-                                    scroll_offset = scroll_offset.saturating_add(1);
-                                }
-                                // 過去ログモードで最上端に到達したら前日を追加ロード
-                                if past_mode {
-                                    use crossterm::terminal;
-                                    if let (Some(earliest_idx), true) = (past_earliest_idx, true) {
-                                        if earliest_idx > 0 {
-                                            // 現在の最大スクロール量を概算: 行折返し考慮せず改行分割のみ
-                                            let (_w, h) = terminal::size().unwrap_or((80, 24));
-                                            let view_h = h.saturating_sub(2) as usize; // (入力行 + ステータス行を除く)
-                                            let flat_len: usize = past_messages
-                                                .iter()
-                                                .map(|m| m.split('\n').count())
-                                                .sum();
-                                            let max_scroll = flat_len.saturating_sub(view_h);
-                                            if past_scroll_offset >= max_scroll {
-                                                // 最上端にいる
-                                                let load_idx = earliest_idx - 1;
-                                                let day = &past_dates[load_idx];
-                                                let recs = storage::load_structured_day(day);
-                                                // 先頭に古い日を挿入（古→新）
-                                                let mut day_lines: Vec<String> = Vec::new();
-                                                for r in recs {
-                                                    let line = if r.handle.is_some() {
-                                                        format!(
-                                                            "{} {}",
-                                                            r.text,
-                                                            if r.signed_ok == Some(true) {
-                                                                "○"
-                                                            } else {
-                                                                "・"
-                                                            }
-                                                        )
-                                                    } else if let Some(pid) = r.from_peer_id {
-                                                        format!(
-                                                            "@{}: {} {}",
-                                                            pid,
-                                                            r.text,
-                                                            if r.signed_ok == Some(true) {
-                                                                "○"
-                                                            } else {
-                                                                "・"
-                                                            }
-                                                        )
-                                                    } else {
-                                                        r.text
-                                                    };
-                                                    day_lines.push(line);
-                                                }
-                                                let inserted = day_lines.len();
-                                                if inserted > 0 {
-                                                    // 先頭に挿入
-                                                    past_messages
-                                                        .splice(0..0, day_lines.into_iter());
-                                                    // 視点保持のため scroll_offset を行数ぶん加算
-                                                    past_scroll_offset =
-                                                        past_scroll_offset.saturating_add(inserted);
-                                                    past_earliest_idx = Some(load_idx);
-                                                    // 日付レンジ更新（開始日を差し替え）
-                                                    if let Some(pos) = past_date_range.find('~') {
-                                                        let end_part =
-                                                            past_date_range[pos + 1..].to_string();
-                                                        past_date_range =
-                                                            format!("{}~{}", day, end_part);
-                                                    }
-                                                    status_msg =
-                                                        format!("過去ログ拡張 {}", past_date_range);
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                                draw_state.force_full = true;
-                            }
-                            MouseEventKind::ScrollDown => {
-                                if past_mode {
-                                    if past_scroll_offset > 0 {
-                                        past_scroll_offset -= 1;
-                                        draw_state.force_full = true;
-                                    }
-                                } else {
-                                    if scroll_offset > 0 {
-                                        scroll_offset -= 1;
-                                        draw_state.force_full = true;
-                                    }
-                                }
-                            }
-                            _ => {}
-                        }
+                }
+                Event::Mouse(me) => {
+                    use crossterm::event::MouseEventKind;
+                    use crossterm::terminal;
+                    // 入力行(最終行)以外でのスクロールのみ反応
+                    let (_w, h) = terminal::size().unwrap_or((80, 24));
+                    let input_row = h.saturating_sub(1); // 入力行
+                    if me.row == input_row {
+                        continue;
+                    }
+                    match me.kind {
+                        // (入力行 + ステータス行を除く) 高さで最上端を判定
+                        MouseEventKind::ScrollUp => tui.scroll_up(h.saturating_sub(2) as usize),
+                        MouseEventKind::ScrollDown => tui.scroll_down(),
+                        _ => {}
                     }
-                    Event::Resize(_, _) => { /* 再描画は次ループで常に行う */ }
-                    _ => {}
                 }
+                Event::Resize(_, _) => { /* 再描画は次ループで常に行う */ }
+                _ => {}
             }
         }
 
         // 選択/コピーモード中は描画更新を止め、選択が崩れないようにする
-        if !copy_mode {
-            let view: &Vec<String> = if past_mode { &past_messages } else { &messages };
-            let off = if past_mode {
-                past_scroll_offset
-            } else {
-                scroll_offset
-            };
-            render(
-                &mut stdout,
-                view,
-                &input,
-                &mut draw_state,
-                off,
-                &status_msg,
-                cursor_pos,
-                past_mode,
-                &past_date_range,
-            );
+        if !tui.copy_mode {
+            render(&mut stdout, &mut tui);
         }
     }
 
-    // クリーンアップ
-    execute!(stdout, DisableMouseCapture, LeaveAlternateScreen).ok();
-    disable_raw_mode().ok();
-    println!("終了しました");
+    // クリーンアップ（/exit・Ctrl+C・シグナル共通）。待受のポートマッピングもここで消える
+    if let Some(tx) = active_thread_tx.take() {
+        let _ = tx.send(rpc::Command::Shutdown).await;
+    }
+    if let Some(handle) = active_thread_handle.take() {
+        let _ = handle.await;
+    }
+    restore_terminal(&mut stdout);
+    // durability が fast / relaxed でも、終了時には必ず書き出す
+    if let Err(e) = storage::flush() {
+        eprintln!("⚠ 履歴の書き出しに失敗: {}", e);
+    }
+    match stopped_by {
+        Some(sig) => println!("{} を受けたので終了しました", sig),
+        None => println!("終了しました"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 先頭に top のキーを置いた、ハンドルと鍵が揃った設定を作る
+    fn cfg_with_identity(top: &str) -> toml::Table {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        format!(
+            "{}\n[user]\nhandle = \"@alice\"\n[key]\npkcs8 = \"{}\"\npublic = \"{}\"\n",
+            top,
+            crypto::to_hex(&keys.pkcs8),
+            crypto::to_hex(&keys.public),
+        )
+        .parse()
+        .unwrap()
+    }
+
+    fn peer(id: usize, handle: &str, rtt_ms: Option<u64>) -> rpc::PeerInfo {
+        rpc::PeerInfo {
+            id,
+            token: "tok".into(),
+            fingerprint: Some("0123456789abcdef".into()),
+            handle: Some(handle.into()),
+            state: rpc::PeerState::Ready,
+            rtt_ms,
+            bytes_in: 10,
+            queued_bytes: 0,
+            via_port: None,
+            handle_suspect: false,
+        }
+    }
+
+    #[test]
+    fn peer_table_columns_align_by_display_width() {
+        let mut peers = vec![peer(0, "@あいう", Some(5)), peer(1, "@bob", None)];
+        peers[1].via_port = Some(2235);
+        let table = format_peer_table(&[2234, 2235], &peers, &PeerQuery::default());
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "ピア数=2 待受=2234,2235");
+        // どの待受に来たピアか（自分から接続したなら発信）
+        assert!(lines[2].contains("発信"));
+        assert!(lines[3].contains(":2235"));
+        // token 列の開始位置が全行で揃う
+        let col = |l: &str| display_width(&l[..l.rfind("  ").unwrap()]);
+        assert_eq!(col(lines[1]), col(lines[2]));
+        assert_eq!(col(lines[2]), col(lines[3]));
+    }
+
+    #[test]
+    fn peer_table_marks_handle_suspects() {
+        let mut peers = vec![peer(0, "@bob", None), peer(1, "@bob", None)];
+        peers[1].handle_suspect = true;
+        let table = format_peer_table(&[], &peers, &PeerQuery::default());
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(!lines[2].contains('⚠'), "{}", lines[2]);
+        assert!(lines[3].contains("@bob ⚠"), "{}", lines[3]);
+    }
+
+    #[test]
+    fn peer_table_sorts_by_rtt_with_unknown_last() {
+        let peers = vec![
+            peer(0, "@a", None),
+            peer(1, "@b", Some(30)),
+            peer(2, "@c", Some(10)),
+        ];
+        let query = PeerQuery {
+            sort: PeerSort::Rtt,
+            filter: None,
+        };
+        let table = format_peer_table(&[], &peers, &query);
+        let ids: Vec<&str> = table
+            .lines()
+            .skip(2)
+            .map(|l| l.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["2", "1", "0"]);
+    }
+
+    #[test]
+    fn peer_table_filters_by_handle_substring() {
+        let peers = vec![
+            peer(0, "@alice", Some(20)),
+            peer(1, "@bob", Some(5)),
+            peer(2, "@Alfred", Some(10)),
+        ];
+        let query = PeerQuery {
+            sort: PeerSort::Rtt,
+            filter: Some("@al".into()),
+        };
+        let table = format_peer_table(&[2234], &peers, &query);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "ピア数=2/3 待受=2234 絞り込み='@al'");
+        let ids: Vec<&str> = lines[2..]
+            .iter()
+            .map(|l| l.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["2", "0"]);
+    }
+
+    #[test]
+    fn auto_open_issues_open_command_when_enabled() {
+        let cfg = cfg_with_identity("auto_open = true\nlisten_port = 2234");
+        assert!(matches!(
+            auto_open_command(&cfg),
+            Some(Ok(rpc::Command::Open(ref p, None))) if p == "2234"
+        ));
+    }
+
+    #[test]
+    fn auto_open_is_skipped_when_disabled() {
+        let cfg = cfg_with_identity("listen_port = 2234");
+        assert!(auto_open_command(&cfg).is_none());
+    }
+
+    #[test]
+    fn auto_open_requires_handle_and_port() {
+        let cfg: toml::Table = "auto_open = true\nlisten_port = 2234\n".parse().unwrap();
+        assert!(matches!(auto_open_command(&cfg), Some(Err(_))));
+        let cfg = cfg_with_identity("auto_open = true");
+        assert!(matches!(auto_open_command(&cfg), Some(Err(_))));
+    }
+
+    #[test]
+    fn profile_is_read_from_either_arg_form() {
+        let args = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            profile_arg(args(&["p2witter", "--profile", "work"])),
+            Some("work".into())
+        );
+        assert_eq!(
+            profile_arg(args(&["p2witter", "--spectate", "--profile=home"])),
+            Some("home".into())
+        );
+        assert_eq!(profile_arg(args(&["p2witter", "--spectate"])), None);
+        assert_eq!(
+            arg_value(args(&["p2witter", "--replay=a.log"]), "--replay").as_deref(),
+            Some("a.log")
+        );
+        assert_eq!(
+            arg_value(args(&["p2witter", "--replayx"]), "--replay"),
+            None
+        );
+    }
+
+    #[test]
+    fn reply_quote_uses_known_line_or_generic_marker() {
+        let messages = vec!["#0a1b2c3d4e5f6071 @alice: hello ○".to_string()];
+        let tagged = HashMap::from([("0a1b2c3d4e5f6071".to_string(), 0)]);
+        assert_eq!(
+            reply_quote(&messages, &tagged, "0a1b2c3d4e5f6071"),
+            "  > #0a1b2c3d4e5f6071 @alice: hello ○"
+        );
+        assert_eq!(
+            reply_quote(&messages, &tagged, "ffffffffffffffff"),
+            "  > (in reply to #ffffffff…)"
+        );
+    }
+
+    #[test]
+    fn restore_leaves_alternate_screen_and_mouse_capture() {
+        let mut out = Vec::new();
+        restore_terminal(&mut out);
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.contains("\x1b[?1049l"),
+            "代替画面を抜けていない: {:?}",
+            out
+        );
+        assert!(
+            out.contains("\x1b[?1000l"),
+            "マウス捕捉を解除していない: {:?}",
+            out
+        );
+        assert!(!crossterm::terminal::is_raw_mode_enabled().unwrap_or(false));
+    }
+
+    #[test]
+    fn panic_hook_restores_before_reporting() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let restored = Arc::new(AtomicBool::new(false));
+        let flag = restored.clone();
+        install_panic_hook(move || flag.store(true, Ordering::SeqCst));
+        let result = std::panic::catch_unwind(|| panic!("テスト用のパニック"));
+        // 既定のフックに戻す
+        let _ = std::panic::take_hook();
+        assert!(result.is_err());
+        assert!(restored.load(Ordering::SeqCst));
+    }
 }
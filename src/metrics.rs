@@ -0,0 +1,174 @@
+//! 中継ノード運用向けの簡易メトリクス（Prometheus のテキスト形式）。
+//!
+//! カウンタはネットワークハンドラが直接数え、`metrics = true` のときだけ
+//! 小さな HTTP サーバーのスレッドが `GET /metrics` に答える
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// metrics_addr が無いときの待受アドレス（外には出さない）
+pub const DEFAULT_METRICS_ADDR: &str = "127.0.0.1:9184";
+
+/// プロセス全体のカウンタ
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub messages_relayed: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub active_peers: AtomicU64,
+    pub signature_failures: AtomicU64,
+    pub dropped_frames: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Self {
+            messages_relayed: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            active_peers: AtomicU64::new(0),
+            signature_failures: AtomicU64::new(0),
+            dropped_frames: AtomicU64::new(0),
+        }
+    }
+
+    /// Prometheus のテキスト形式 (0.0.4) で書き出す
+    pub fn render(&self) -> String {
+        let rows: [(&str, &str, &str, &AtomicU64); 6] = [
+            (
+                "p2witter_messages_relayed_total",
+                "counter",
+                "他のピアへ中継したメッセージ数",
+                &self.messages_relayed,
+            ),
+            (
+                "p2witter_bytes_in_total",
+                "counter",
+                "ピアから受信したバイト数",
+                &self.bytes_in,
+            ),
+            (
+                "p2witter_bytes_out_total",
+                "counter",
+                "ピアへ送信したバイト数",
+                &self.bytes_out,
+            ),
+            (
+                "p2witter_active_peers",
+                "gauge",
+                "接続中のピア数",
+                &self.active_peers,
+            ),
+            (
+                "p2witter_signature_failures_total",
+                "counter",
+                "署名検証に失敗したフレーム数",
+                &self.signature_failures,
+            ),
+            (
+                "p2witter_dropped_frames_total",
+                "counter",
+                "不正・重複などで捨てた受信フレーム数",
+                &self.dropped_frames,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, kind, help, value) in rows {
+            out.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {}\n",
+                value.load(Ordering::Relaxed)
+            ));
+        }
+        out
+    }
+}
+
+/// カウンタに n を足す
+pub fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+/// 待受を開き、別スレッドで /metrics に答え続ける。実際に開いたアドレスを返す
+pub fn serve(addr: &str) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = respond(stream, &METRICS);
+        }
+    });
+    Ok(local)
+}
+
+// 1 リクエストだけ読んで答え、接続を閉じる
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let mut req = Vec::new();
+    let mut buf = [0u8; 512];
+    // ヘッダの終わりまで（長すぎるものは打ち切る）
+    while !req.windows(4).any(|w| w == b"\r\n\r\n") && req.len() < 8192 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        req.extend_from_slice(&buf[..n]);
+    }
+    let line = req.split(|&b| b == b'\r').next().unwrap_or_default();
+    let (status, body) = if line.starts_with(b"GET /metrics ") {
+        ("200 OK", metrics.render())
+    } else {
+        ("404 Not Found", "not found\n".to_string())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut s = TcpStream::connect(addr).unwrap();
+        write!(s, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut resp = String::new();
+        s.read_to_string(&mut resp).unwrap();
+        resp
+    }
+
+    #[test]
+    fn endpoint_serves_counters_in_prometheus_format() {
+        add(&METRICS.messages_relayed, 3);
+        add(&METRICS.bytes_in, 100);
+        let addr = serve("127.0.0.1:0").unwrap();
+
+        let resp = get(addr, "/metrics");
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        for name in [
+            "p2witter_messages_relayed_total",
+            "p2witter_bytes_in_total",
+            "p2witter_bytes_out_total",
+            "p2witter_active_peers",
+            "p2witter_signature_failures_total",
+            "p2witter_dropped_frames_total",
+        ] {
+            assert!(resp.contains(&format!("# TYPE {name} ")), "{name}");
+        }
+        // 他のテストも数えるので、足した分以上になっていることだけ確かめる
+        let relayed: u64 = resp
+            .lines()
+            .find_map(|l| l.strip_prefix("p2witter_messages_relayed_total "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(relayed >= 3);
+
+        assert!(get(addr, "/").starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,213 @@
+//! NAT-PMP (RFC 6886) による自動ポートマッピング。
+//! 家庭用ルーターの外から /open した待受へ届くよう、ゲートウェイに TCP ポートの転送を頼み、
+//! 外部 IP を調べて接続トークンに入れる。UPnP IGD (SSDP + SOAP) には未対応。
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, timeout};
+
+/// NAT-PMP のゲートウェイ側ポート
+pub const NAT_PMP_PORT: u16 = 5351;
+/// マッピングの有効期間（秒）。RFC 推奨値
+pub const DEFAULT_LIFETIME_SECS: u32 = 7200;
+/// 最初の応答待ち時間。再送ごとに倍にする
+const INITIAL_WAIT_MS: u64 = 250;
+const MAX_ATTEMPTS: u32 = 3;
+
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_TCP: u8 = 2;
+
+/// ゲートウェイに作ったポートマッピング
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub gateway: SocketAddr,
+    pub external_ip: Ipv4Addr,
+    pub internal_port: u16,
+    pub external_port: u16,
+    pub lifetime_secs: u32,
+}
+
+impl Mapping {
+    /// 外から届くアドレス ("ip:port")。接続トークンの中身になる
+    pub fn external_addr(&self) -> String {
+        external_token_addr(self.external_ip, self.external_port)
+    }
+}
+
+/// 発見した外部 IP とポートから接続トークン用のアドレスを作る
+pub fn external_token_addr(ip: Ipv4Addr, port: u16) -> String {
+    SocketAddrV4::new(ip, port).to_string()
+}
+
+/// /proc/net/route の内容からデフォルトゲートウェイを探す（Linux 用）
+pub fn parse_default_gateway(route_table: &str) -> Option<Ipv4Addr> {
+    route_table.lines().skip(1).find_map(|line| {
+        let cols: Vec<&str> = line.split_whitespace().collect();
+        // Iface Destination Gateway ...（値はリトルエンディアンの16進）
+        if cols.len() < 3 || cols[1] != "00000000" {
+            return None;
+        }
+        let gw = u32::from_str_radix(cols[2], 16).ok()?;
+        (gw != 0).then(|| Ipv4Addr::from(gw.to_le_bytes()))
+    })
+}
+
+/// config の nat_gateway、無ければ OS のルーティング表からゲートウェイを決める
+pub fn default_gateway(configured: Option<&str>) -> Option<SocketAddr> {
+    let ip = match configured {
+        Some(s) => s.parse().ok()?,
+        None => parse_default_gateway(&std::fs::read_to_string("/proc/net/route").ok()?)?,
+    };
+    Some(SocketAddr::V4(SocketAddrV4::new(ip, NAT_PMP_PORT)))
+}
+
+fn external_address_request() -> [u8; 2] {
+    [0, OP_EXTERNAL_ADDRESS]
+}
+
+fn map_request(internal_port: u16, external_port: u16, lifetime_secs: u32) -> [u8; 12] {
+    let mut req = [0u8; 12];
+    req[1] = OP_MAP_TCP;
+    req[4..6].copy_from_slice(&internal_port.to_be_bytes());
+    req[6..8].copy_from_slice(&external_port.to_be_bytes());
+    req[8..12].copy_from_slice(&lifetime_secs.to_be_bytes());
+    req
+}
+
+// 応答ヘッダ (version=0, op=128+要求op, 結果コード) を確かめる
+fn check_header(resp: &[u8], op: u8, len: usize) -> Result<(), String> {
+    if resp.len() < len || resp[0] != 0 || resp[1] != 128 + op {
+        return Err("不正な NAT-PMP 応答".into());
+    }
+    match u16::from_be_bytes([resp[2], resp[3]]) {
+        0 => Ok(()),
+        1 => Err("ゲートウェイが未対応のバージョン".into()),
+        2 => Err("ゲートウェイでマッピングが禁止されています".into()),
+        3 => Err("ゲートウェイがネットワークに接続されていません".into()),
+        4 => Err("ゲートウェイのリソース不足".into()),
+        code => Err(format!("NAT-PMP エラー code={}", code)),
+    }
+}
+
+fn parse_external_address(resp: &[u8]) -> Result<Ipv4Addr, String> {
+    check_header(resp, OP_EXTERNAL_ADDRESS, 12)?;
+    Ok(Ipv4Addr::new(resp[8], resp[9], resp[10], resp[11]))
+}
+
+/// (内部ポート, 外部ポート, 有効期間)
+fn parse_map_response(resp: &[u8]) -> Result<(u16, u16, u32), String> {
+    check_header(resp, OP_MAP_TCP, 16)?;
+    Ok((
+        u16::from_be_bytes([resp[8], resp[9]]),
+        u16::from_be_bytes([resp[10], resp[11]]),
+        u32::from_be_bytes([resp[12], resp[13], resp[14], resp[15]]),
+    ))
+}
+
+// 要求を送り、応答が来るまで待ち時間を倍にしながら再送する
+async fn request(gateway: SocketAddr, req: &[u8]) -> Result<Vec<u8>, String> {
+    let sock = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .map_err(|e| format!("{:?}", e.kind()))?;
+    sock.connect(gateway)
+        .await
+        .map_err(|e| format!("{:?}", e.kind()))?;
+    let mut wait = Duration::from_millis(INITIAL_WAIT_MS);
+    let mut buf = [0u8; 16];
+    for _ in 0..MAX_ATTEMPTS {
+        sock.send(req)
+            .await
+            .map_err(|e| format!("{:?}", e.kind()))?;
+        match timeout(wait, sock.recv(&mut buf)).await {
+            Ok(Ok(n)) => return Ok(buf[..n].to_vec()),
+            Ok(Err(e)) => return Err(format!("{:?}", e.kind())),
+            Err(_) => wait *= 2,
+        }
+    }
+    Err(format!("{} から応答がありません", gateway))
+}
+
+/// 外部 IP を調べ、待受ポートの TCP マッピングを作る
+pub async fn map_port(gateway: SocketAddr, port: u16) -> Result<Mapping, String> {
+    let external_ip =
+        parse_external_address(&request(gateway, &external_address_request()).await?)?;
+    let resp = request(gateway, &map_request(port, port, DEFAULT_LIFETIME_SECS)).await?;
+    let (internal_port, external_port, lifetime_secs) = parse_map_response(&resp)?;
+    Ok(Mapping {
+        gateway,
+        external_ip,
+        internal_port,
+        external_port,
+        lifetime_secs,
+    })
+}
+
+/// マッピングを削除する（有効期間0で要求し直す）
+pub async fn unmap_port(mapping: &Mapping) -> Result<(), String> {
+    let resp = request(mapping.gateway, &map_request(mapping.internal_port, 0, 0)).await?;
+    parse_map_response(&resp).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::crypto;
+
+    #[test]
+    fn token_carries_the_discovered_external_address() {
+        let mut resp = vec![0, 128, 0, 0, 0, 0, 0, 9];
+        resp.extend_from_slice(&[203, 0, 113, 7]);
+        let ip = parse_external_address(&resp).unwrap();
+        let token = crypto::encrypt_conninfo_to_hex(&external_token_addr(ip, 2234)).unwrap();
+        assert_eq!(
+            crypto::decrypt_conninfo_from_hex(&token).unwrap(),
+            "203.0.113.7:2234"
+        );
+
+        // 結果コードが 0 以外なら失敗として扱う
+        resp[3] = 2;
+        assert!(parse_external_address(&resp).is_err());
+    }
+
+    #[test]
+    fn default_route_gateway_is_parsed() {
+        let table = "Iface\tDestination\tGateway \tFlags\n\
+                     eth0\t0000A8C0\t00000000\t0001\n\
+                     eth0\t00000000\t0100A8C0\t0003\n";
+        assert_eq!(
+            parse_default_gateway(table),
+            Some(Ipv4Addr::new(192, 168, 0, 1))
+        );
+        assert_eq!(
+            default_gateway(Some("10.0.0.1")),
+            Some("10.0.0.1:5351".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn port_is_mapped_through_a_gateway() {
+        // 外部 IP と TCP マッピングの要求に答える偽のゲートウェイ
+        let gw = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway = gw.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 16];
+            for _ in 0..2 {
+                let (n, from) = gw.recv_from(&mut buf).await.unwrap();
+                let resp = if n == 2 {
+                    vec![0, 128, 0, 0, 0, 0, 0, 1, 198, 51, 100, 2]
+                } else {
+                    let mut r = vec![0, 130, 0, 0, 0, 0, 0, 1];
+                    r.extend_from_slice(&buf[4..6]);
+                    r.extend_from_slice(&40000u16.to_be_bytes());
+                    r.extend_from_slice(&buf[8..12]);
+                    r
+                };
+                gw.send_to(&resp, from).await.unwrap();
+            }
+        });
+        let mapping = map_port(gateway, 2234).await.unwrap();
+        assert_eq!(mapping.internal_port, 2234);
+        assert_eq!(mapping.external_addr(), "198.51.100.2:40000");
+        assert_eq!(mapping.lifetime_secs, DEFAULT_LIFETIME_SECS);
+    }
+}
@@ -0,0 +1,327 @@
+//! /whois 用の、接続元アドレスから調べるネットワーク情報。
+//! PTR（逆引き DNS）と、手元の ip2asn 形式の表から引く AS 番号。
+//! どちらも相手が誰かを示すものではなく、本人確認は署名鍵だけで行う。
+
+use crate::core::crypto;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, timeout};
+
+const DNS_PORT: u16 = 53;
+/// 逆引きの応答待ち時間
+const LOOKUP_WAIT_MS: u64 = 2_000;
+const TYPE_PTR: u16 = 12;
+const CLASS_IN: u16 = 1;
+/// 圧縮ポインタを辿る回数の上限（ループ対策）
+const MAX_NAME_JUMPS: usize = 16;
+
+/// 調べた結果。無効にしているのか、調べて分からなかったのかを区別して出す
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup<T> {
+    Disabled,
+    Unknown,
+    Found(T),
+}
+
+/// ip2asn の表の 1 行分
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsnEntry {
+    pub asn: u32,
+    pub country: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrInfo {
+    pub ptr: Lookup<String>,
+    pub asn: Lookup<AsnEntry>,
+}
+
+/// 逆引きに使う名前 (4.3.2.1.in-addr.arpa / ニブル逆順の ip6.arpa)
+pub fn ptr_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(v6) => {
+            let mut labels = Vec::with_capacity(33);
+            for b in v6.octets().iter().rev() {
+                labels.push(format!("{:x}", b & 0x0f));
+                labels.push(format!("{:x}", b >> 4));
+            }
+            labels.push("ip6.arpa".into());
+            labels.join(".")
+        }
+    }
+}
+
+fn ptr_query(id: u16, name: &str) -> Vec<u8> {
+    let mut q = Vec::with_capacity(name.len() + 18);
+    q.extend_from_slice(&id.to_be_bytes());
+    // 再帰要求のみ。質問 1 件
+    q.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        q.push(label.len() as u8);
+        q.extend_from_slice(label.as_bytes());
+    }
+    q.push(0);
+    q.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    q.extend_from_slice(&CLASS_IN.to_be_bytes());
+    q
+}
+
+fn be16(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*msg.get(pos)?, *msg.get(pos + 1)?]))
+}
+
+// pos から名前を読み、(名前, 名前の直後の位置) を返す
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    for _ in 0..MAX_NAME_JUMPS {
+        loop {
+            let len = *msg.get(pos)? as usize;
+            if len & 0xc0 == 0xc0 {
+                let target = (be16(msg, pos)? & 0x3fff) as usize;
+                end.get_or_insert(pos + 2);
+                pos = target;
+                break;
+            }
+            if len == 0 {
+                return Some((labels.join("."), end.unwrap_or(pos + 1)));
+            }
+            let label = msg.get(pos + 1..pos + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+        }
+    }
+    None
+}
+
+/// 応答から最初の PTR レコードの名前を取り出す
+fn parse_ptr_answer(id: u16, msg: &[u8]) -> Option<String> {
+    // 自分の問い合わせへの応答で、エラーでないこと
+    if be16(msg, 0)? != id || msg.get(2)? & 0x80 == 0 || msg.get(3)? & 0x0f != 0 {
+        return None;
+    }
+    let questions = be16(msg, 4)?;
+    let answers = be16(msg, 6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+    for _ in 0..answers {
+        pos = read_name(msg, pos)?.1;
+        let kind = be16(msg, pos)?;
+        let rdlen = be16(msg, pos + 8)? as usize;
+        let rdata = pos + 10;
+        if kind == TYPE_PTR {
+            return read_name(msg, rdata).map(|(name, _)| name);
+        }
+        pos = rdata + rdlen;
+    }
+    None
+}
+
+/// /etc/resolv.conf の最初の nameserver
+pub fn parse_nameserver(resolv_conf: &str) -> Option<IpAddr> {
+    resolv_conf.lines().find_map(|line| {
+        let mut cols = line.split_whitespace();
+        (cols.next() == Some("nameserver"))
+            .then(|| cols.next()?.parse().ok())
+            .flatten()
+    })
+}
+
+/// config の dns_server、無ければ OS の設定から問い合わせ先を決める
+pub fn default_nameserver(configured: Option<&str>) -> Option<SocketAddr> {
+    let ip = match configured {
+        Some(s) => s.parse().ok()?,
+        None => parse_nameserver(&std::fs::read_to_string("/etc/resolv.conf").ok()?)?,
+    };
+    Some(SocketAddr::new(ip, DNS_PORT))
+}
+
+/// PTR を問い合わせる。応答が無い・見つからないときは None
+pub async fn reverse_lookup(server: SocketAddr, ip: IpAddr) -> Option<String> {
+    let bind = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let sock = UdpSocket::bind(bind).await.ok()?;
+    sock.connect(server).await.ok()?;
+    let r = crypto::random_bytes(2).ok()?;
+    let id = u16::from_be_bytes([r[0], r[1]]);
+    sock.send(&ptr_query(id, &ptr_name(ip))).await.ok()?;
+    let mut buf = [0u8; 512];
+    let n = timeout(Duration::from_millis(LOOKUP_WAIT_MS), sock.recv(&mut buf))
+        .await
+        .ok()?
+        .ok()?;
+    parse_ptr_answer(id, &buf[..n])
+}
+
+/// ip2asn 形式（開始 IP, 終了 IP, AS 番号, 国, 名前 のタブ区切り）の表から引く。
+/// AS 0 は「経路なし」なので見つからなかった扱い
+pub fn find_asn(table: &str, ip: IpAddr) -> Option<AsnEntry> {
+    table.lines().find_map(|line| {
+        let cols: Vec<&str> = line.split('\t').collect();
+        if cols.len() < 5 {
+            return None;
+        }
+        let start: IpAddr = cols[0].parse().ok()?;
+        let end: IpAddr = cols[1].parse().ok()?;
+        let asn: u32 = cols[2].parse().ok()?;
+        (asn != 0 && start <= ip && ip <= end).then(|| AsnEntry {
+            asn,
+            country: cols[3].to_string(),
+            name: cols[4].to_string(),
+        })
+    })
+}
+
+/// 設定に従って調べる。dns が None なら逆引きせず、asn_db が None なら AS を引かない
+pub async fn lookup(ip: IpAddr, dns: Option<SocketAddr>, asn_db: Option<String>) -> AddrInfo {
+    let ptr = match dns {
+        None => Lookup::Disabled,
+        Some(server) => reverse_lookup(server, ip)
+            .await
+            .map_or(Lookup::Unknown, Lookup::Found),
+    };
+    let asn = match asn_db {
+        None => Lookup::Disabled,
+        // 表は大きいことがあるので読むのはブロッキング用のスレッドで
+        Some(path) => {
+            tokio::task::spawn_blocking(move || find_asn(&std::fs::read_to_string(path).ok()?, ip))
+                .await
+                .ok()
+                .flatten()
+                .map_or(Lookup::Unknown, Lookup::Found)
+        }
+    };
+    AddrInfo { ptr, asn }
+}
+
+/// /whois のネットワーク情報の欄。署名鍵による本人確認とは別の見出しにする
+pub fn render(addr: SocketAddr, info: &AddrInfo) -> Vec<String> {
+    let ptr = match &info.ptr {
+        Lookup::Disabled => "無効 (peer_lookup = true で有効)".to_string(),
+        Lookup::Unknown => "不明".to_string(),
+        Lookup::Found(name) => name.clone(),
+    };
+    let asn = match &info.asn {
+        Lookup::Disabled => "無効 (asn_db 未設定)".to_string(),
+        Lookup::Unknown => "不明".to_string(),
+        Lookup::Found(e) => format!("AS{} {} ({})", e.asn, e.name, e.country),
+    };
+    vec![
+        "ネットワーク情報（接続元アドレスからの参考情報。本人確認ではありません）:".to_string(),
+        format!("  アドレス: {}", addr),
+        format!("  逆引き: {}", ptr),
+        format!("  AS: {}", asn),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn ptr_names_are_reversed() {
+        assert_eq!(
+            ptr_name(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10))),
+            "10.2.0.192.in-addr.arpa"
+        );
+        let v6 = ptr_name(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)));
+        assert!(v6.starts_with("1.0.0.0.0.0.0.0"), "{}", v6);
+        assert!(v6.ends_with("8.b.d.0.1.0.0.2.ip6.arpa"), "{}", v6);
+    }
+
+    #[test]
+    fn nameserver_comes_from_resolv_conf() {
+        let conf = "# comment\nsearch example\nnameserver 192.0.2.53\nnameserver 192.0.2.54\n";
+        assert_eq!(parse_nameserver(conf), Some("192.0.2.53".parse().unwrap()));
+        assert_eq!(
+            default_nameserver(Some("198.51.100.1")),
+            Some("198.51.100.1:53".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn asn_table_is_searched_by_range() {
+        let table = "1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET\n\
+                     192.0.2.0\t192.0.2.255\t0\tNone\tNot routed\n\
+                     203.0.113.0\t203.0.113.255\t64500\tJP\tEXAMPLE-NET\n";
+        let hit = find_asn(table, "203.0.113.9".parse().unwrap()).unwrap();
+        assert_eq!(hit.asn, 64500);
+        assert_eq!(hit.country, "JP");
+        assert_eq!(find_asn(table, "192.0.2.1".parse().unwrap()), None);
+        assert_eq!(find_asn(table, "::1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn network_info_is_rendered_apart_from_identity() {
+        let addr: SocketAddr = "203.0.113.9:2234".parse().unwrap();
+        let info = AddrInfo {
+            ptr: Lookup::Found("host.example.net".into()),
+            asn: Lookup::Found(AsnEntry {
+                asn: 64500,
+                country: "JP".into(),
+                name: "EXAMPLE-NET".into(),
+            }),
+        };
+        let lines = render(addr, &info);
+        assert!(lines[0].contains("本人確認ではありません"));
+        assert_eq!(lines[1], "  アドレス: 203.0.113.9:2234");
+        assert_eq!(lines[2], "  逆引き: host.example.net");
+        assert_eq!(lines[3], "  AS: AS64500 EXAMPLE-NET (JP)");
+
+        // 失敗と無効は書き分ける
+        let info = AddrInfo {
+            ptr: Lookup::Unknown,
+            asn: Lookup::Disabled,
+        };
+        let lines = render(addr, &info);
+        assert_eq!(lines[2], "  逆引き: 不明");
+        assert_eq!(lines[3], "  AS: 無効 (asn_db 未設定)");
+    }
+
+    #[tokio::test]
+    async fn ptr_is_resolved_through_a_server() {
+        // 問い合わせをそのまま返し、圧縮ポインタで質問の名前を指す PTR を 1 件付ける偽の DNS
+        let dns = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = dns.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (n, from) = dns.recv_from(&mut buf).await.unwrap();
+            let mut resp = buf[..n].to_vec();
+            resp[2] |= 0x80;
+            resp[7] = 1;
+            resp.extend_from_slice(&[0xc0, 12, 0, 12, 0, 1, 0, 0, 0, 60]);
+            let rd = b"\x04host\x07example\x00";
+            resp.extend_from_slice(&(rd.len() as u16).to_be_bytes());
+            resp.extend_from_slice(rd);
+            dns.send_to(&resp, from).await.unwrap();
+        });
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10));
+        assert_eq!(
+            reverse_lookup(server, ip).await.as_deref(),
+            Some("host.example")
+        );
+    }
+
+    #[test]
+    fn error_answers_are_unknown() {
+        let mut resp = ptr_query(7, "10.2.0.192.in-addr.arpa");
+        resp[2] |= 0x80;
+        // NXDOMAIN
+        resp[3] |= 3;
+        assert_eq!(parse_ptr_answer(7, &resp), None);
+        // 違う id の応答は受け取らない
+        resp[3] &= 0xf0;
+        assert_eq!(parse_ptr_answer(8, &resp), None);
+    }
+}
@@ -1,29 +1,209 @@
 use crate::core::{crypto, protocol, rpc};
-use crate::{config, utils::current_unix_millis};
-use std::collections::{HashSet, VecDeque};
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
+use crate::metrics::{self, METRICS};
+use crate::storage::{AuditEvent, AuditKind};
+use crate::transport::{self, Acceptor, Connection, Transport};
+use crate::utils::{Clock, SystemClock, current_unix_millis};
+use crate::{config, nat, netinfo, storage};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::time::{Duration, sleep};
 
 const FULL_RELAY_ATTENUATION: u8 = 6;
 const SEEN_MESSAGE_CACHE_CAPACITY: usize = 4096;
+/// connect_timeout_secs 未指定時の接続タイムアウト
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 5;
+/// send_buffer_limit_bytes 未指定時の、全ピア合計の送信待ちの上限
+const DEFAULT_SEND_BUFFER_LIMIT_BYTES: usize = 4 * 1024 * 1024;
+/// read_buffer_bytes 未指定時の、1 回の読み込みの大きさ
+const DEFAULT_READ_BUFFER_BYTES: usize = 2048;
+/// read_buffer_bytes に指定できる範囲
+const READ_BUFFER_BYTES_RANGE: std::ops::RangeInclusive<usize> = 512..=1024 * 1024;
 
-fn build_signed_chat(text: &str, pkcs8: &[u8], pubk: &[u8]) -> Option<protocol::Message> {
-    let ts = current_unix_millis();
-    let msg = protocol::Message::chat(text, ts);
-    let data = protocol::signing_bytes(&msg);
-    let sig = crypto::sign_ed25519(&data, pkcs8).ok()?;
+/// 公開鍵と中継トークンを付けて v2 で署名する
+fn sign_message(msg: protocol::Message, pkcs8: &[u8], pubk: &[u8]) -> Option<protocol::Message> {
+    let seed = crypto::random_bytes(protocol::HOP_TOKEN_LEN).ok()?;
+    let msg = msg.for_signing(pubk.to_vec(), seed.try_into().ok()?);
+    let sig = crypto::sign_ed25519(&protocol::signing_bytes(&msg), pkcs8).ok()?;
     Some(msg.with_key_sig(pubk.to_vec(), sig))
 }
 
-fn build_signed_dm(text: &str, pkcs8: &[u8], pubk: &[u8]) -> Option<protocol::Message> {
+/// 相手が v1 なら v1 で読める形にする。署名の無いフレームは版を下げるだけでよい。
+/// v2 の署名は v1 では検証できないので、自分の鍵で署名したものは v1 で署名し直し、
+/// 他人のものは送らない
+fn frame_for_peer(
+    msg: &protocol::Message,
+    peer_version: u8,
+    keys: Option<(&[u8], &[u8])>,
+) -> Option<Vec<u8>> {
+    if msg.version <= peer_version {
+        return Some(protocol::encode(msg));
+    }
+    let mut old = msg.downgraded();
+    old.version = peer_version;
+    match (msg.public_key.as_deref(), keys) {
+        (None, _) => Some(protocol::encode(&old)),
+        (Some(pk), Some((pkcs8, pubk))) if pk == pubk => {
+            let sig = crypto::sign_ed25519(&protocol::signing_bytes(&old), pkcs8).ok()?;
+            old.signature = Some(sig);
+            Some(protocol::encode(&old))
+        }
+        _ => None,
+    }
+}
+
+/// HELLO で決まった相手のバージョン。HELLO 前は最も古い版とみなす
+fn peer_version<C>(peers: &[Peer<C>], idx: usize) -> u8 {
+    peers
+        .get(idx)
+        .and_then(|p| p.meta.as_ref())
+        .and_then(|m| m.protocol_version)
+        .unwrap_or(protocol::MIN_PROTOCOL_VERSION)
+}
+
+/// 返信先があれば返信先付きの Chat として署名する
+fn build_signed_chat(
+    text: &str,
+    reply_to: Option<&[u8; protocol::MESSAGE_ID_LEN]>,
+    urgent: bool,
+    pkcs8: &[u8],
+    pubk: &[u8],
+    seq: u64,
+) -> Option<protocol::Message> {
     let ts = current_unix_millis();
-    let encrypted = crypto::encrypt_dm_payload(text.as_bytes()).ok()?;
-    let msg = protocol::Message::dm_bytes(encrypted, ts);
-    let data = protocol::signing_bytes(&msg);
-    let sig = crypto::sign_ed25519(&data, pkcs8).ok()?;
-    Some(msg.with_key_sig(pubk.to_vec(), sig))
+    let msg = match reply_to {
+        Some(target) => protocol::Message::reply(ts, target, text),
+        None => protocol::Message::chat(text, ts),
+    };
+    sign_message(msg.with_seq(seq).with_urgent(urgent), pkcs8, pubk)
+}
+
+fn build_signed_dm(
+    nonces: &mut crypto::NonceSequence,
+    text: &str,
+    ephemeral: bool,
+    pkcs8: &[u8],
+    pubk: &[u8],
+) -> Option<protocol::Message> {
+    let ts = current_unix_millis();
+    let encrypted = crypto::encrypt_dm_payload_with(nonces, text.as_bytes()).ok()?;
+    let msg = if ephemeral {
+        protocol::Message::ephemeral_dm_bytes(encrypted, ts)
+    } else {
+        protocol::Message::dm_bytes(encrypted, ts)
+    };
+    sign_message(msg, pkcs8, pubk)
+}
+
+/// ROUTED_DM の宛先に使う指紋（公開鍵 SHA-256 の先頭）
+fn route_fingerprint(public_key: &[u8]) -> [u8; protocol::ROUTE_FINGERPRINT_LEN] {
+    let d = ring::digest::digest(&ring::digest::SHA256, public_key);
+    let mut out = [0u8; protocol::ROUTE_FINGERPRINT_LEN];
+    out.copy_from_slice(&d.as_ref()[..protocol::ROUTE_FINGERPRINT_LEN]);
+    out
+}
+
+/// /dm の宛先が指紋（16桁の16進）なら宛先指紋を返す
+fn parse_route_fingerprint(s: &str) -> Option<[u8; protocol::ROUTE_FINGERPRINT_LEN]> {
+    if s.len() != protocol::ROUTE_FINGERPRINT_LEN * 2 {
+        return None;
+    }
+    crypto::from_hex(s).ok()?.try_into().ok()
+}
+
+fn build_signed_routed_dm(
+    nonces: &mut crypto::NonceSequence,
+    to: &[u8; protocol::ROUTE_FINGERPRINT_LEN],
+    text: &str,
+    pkcs8: &[u8],
+    pubk: &[u8],
+) -> Option<protocol::Message> {
+    let ts = current_unix_millis();
+    let encrypted = crypto::encrypt_dm_payload_with(nonces, text.as_bytes()).ok()?;
+    let msg = protocol::Message::routed_dm(to, &encrypted, ts);
+    sign_message(msg, pkcs8, pubk)
+}
+
+/// 受信した ROUTED_DM の扱い
+#[derive(Debug, PartialEq, Eq)]
+enum RoutedDm<'a> {
+    /// 自分宛て（暗号文）
+    ForMe(&'a [u8]),
+    /// 他人宛て。復号・表示・保存はせず中継だけする
+    Forward,
+    /// 他人宛てで relay_dms が無効、または形式不正
+    Drop,
+}
+
+fn route_dm<'a>(
+    msg: &'a protocol::Message,
+    own_public: Option<&[u8]>,
+    relay_dms: bool,
+) -> RoutedDm<'a> {
+    let Some((to, encrypted)) = protocol::routed_dm_parts(msg) else {
+        return RoutedDm::Drop;
+    };
+    if own_public.is_some_and(|pk| route_fingerprint(pk) == to) {
+        RoutedDm::ForMe(encrypted)
+    } else if relay_dms {
+        RoutedDm::Forward
+    } else {
+        RoutedDm::Drop
+    }
+}
+
+/// DM の保存用レコード。揮発 DM は送信側・受信側とも保存しないので None。
+/// peer_fingerprint は相手（受信なら送り主、送信なら宛先）の指紋
+fn dm_record(
+    msg: &protocol::Message,
+    from_peer_id: Option<usize>,
+    to_peer_id: Option<usize>,
+    handle: Option<String>,
+    text: String,
+    signature: rpc::SigState,
+    peer_fingerprint: Option<String>,
+) -> Option<crate::storage::MessageRecord> {
+    if msg.kind == protocol::MsgKind::EPHEMERAL_DM {
+        return None;
+    }
+    Some(crate::storage::MessageRecord {
+        ts_millis: msg.timestamp,
+        recv_ts_millis: current_unix_millis(),
+        kind: crate::storage::MsgKind::Dm,
+        from_peer_id,
+        to_peer_id,
+        handle,
+        text,
+        signature,
+        reply_to: None,
+        binary: None,
+        proof: signature_proof(msg),
+        peer_fingerprint,
+    })
+}
+
+/// DM の暗号文を復号して表示用の本文にする。UTF-8 として読めなければ
+/// `<binary N bytes>` を表示し、生のバイト列は保存用に別に返す
+fn decrypt_dm_text(payload: &[u8]) -> (String, Option<Vec<u8>>) {
+    match crypto::decrypt_dm_payload(payload).map(String::from_utf8) {
+        Ok(Ok(text)) => (text, None),
+        Ok(Err(e)) => {
+            let raw = e.into_bytes();
+            (format!("<binary {} bytes>", raw.len()), Some(raw))
+        }
+        Err(_) => ("<DM復号エラー>".to_string(), None),
+    }
+}
+
+/// 署名付きフレームなら、後で検証し直せるよう署名と署名対象を保存用に取り出す
+fn signature_proof(msg: &protocol::Message) -> Option<crate::storage::SignatureProof> {
+    Some(crate::storage::SignatureProof {
+        public_key: msg.public_key.clone()?,
+        signature: msg.signature.clone()?,
+        signed: protocol::signing_bytes(msg),
+    })
 }
 
 fn verify_signed_message(msg: &protocol::Message, sig: &[u8], pk: &[u8]) -> bool {
@@ -31,823 +211,7342 @@ fn verify_signed_message(msg: &protocol::Message, sig: &[u8], pk: &[u8]) -> bool
     crypto::verify_ed25519(&data, sig, pk).is_ok()
 }
 
-fn build_signed_hello(handle: &str, pkcs8: &[u8], pubk: &[u8]) -> Option<protocol::Message> {
+fn build_signed_hello(
+    handle: &str,
+    bio: Option<&str>,
+    pkcs8: &[u8],
+    pubk: &[u8],
+) -> Option<protocol::Message> {
     let ts = current_unix_millis();
-    let msg = protocol::Message::hello(ts, handle);
+    let msg = protocol::Message::hello(ts, handle, bio);
+    // HELLO はバージョンを決める前に届くので v1 のまま署名する
     let data = protocol::signing_bytes(&msg);
     let sig = crypto::sign_ed25519(&data, pkcs8).ok()?;
     Some(msg.with_key_sig(pubk.to_vec(), sig))
 }
 
-fn relay_probability_percent(attenuation: u8) -> u8 {
-    if attenuation <= FULL_RELAY_ATTENUATION {
-        return 100;
+/// 自分の鍵で署名されたフレームか（署名の検証は呼び出し側で済ませておく）
+fn is_own_frame(msg: &protocol::Message, own_public: Option<&[u8]>) -> bool {
+    msg.signature.is_some() && own_public.is_some_and(|own| msg.public_key.as_deref() == Some(own))
+}
+
+/// 署名対象の本文先頭にあるハンドル欄 ("@handle: ") を取り出す。
+/// ハンドルは空白を含まないので、本文中の "@mention ... :" はハンドル欄とみなさない。
+fn signed_handle_field(txt: &str) -> Option<&str> {
+    let (name, _) = txt.split_once(": ")?;
+    if name.starts_with('@') && !name.chars().any(char::is_whitespace) {
+        Some(name)
+    } else {
+        None
     }
-    if attenuation >= protocol::MAX_ATTENUATION {
-        return 0;
+}
+
+/// ハンドル欄がプロトコルの制限を超えていればその文字数を返す
+fn oversized_signed_handle(txt: &str) -> Option<usize> {
+    let name = signed_handle_field(txt)?;
+    if config::is_valid_peer_handle(name) {
+        None
+    } else {
+        Some(name.chars().count())
     }
+}
 
-    let remaining = (protocol::MAX_ATTENUATION - attenuation) as u16;
-    let window = (protocol::MAX_ATTENUATION - FULL_RELAY_ATTENUATION) as u16;
-    ((remaining * 100) / window) as u8
+fn build_signed_rotation(
+    new_public: &[u8],
+    old_pkcs8: &[u8],
+    old_public: &[u8],
+) -> Option<protocol::Message> {
+    let ts = current_unix_millis();
+    let msg = protocol::Message::rotate(ts, new_public);
+    sign_message(msg, old_pkcs8, old_public)
 }
 
-fn relay_bucket(msg: &protocol::Message, src: usize, dst: usize) -> u8 {
-    let mut seed = msg.timestamp
-        ^ ((msg.kind as u64) << 56)
-        ^ ((msg.attenuation as u64) << 48)
-        ^ ((msg.payload.len() as u64) << 16)
-        ^ ((src as u64) << 8)
-        ^ (dst as u64);
-    if let Some(pk) = msg.public_key.as_ref() {
-        for (i, b) in pk.iter().take(8).enumerate() {
-            seed ^= (*b as u64) << (i * 8);
+/// 既知の公開鍵で署名されたローテーションなら新しい公開鍵を返す
+fn verify_rotation(msg: &protocol::Message, known_public: &[u8]) -> Option<Vec<u8>> {
+    let new_key = protocol::rotation_new_key(msg)?;
+    let (pk, sig) = (msg.public_key.as_ref()?, msg.signature.as_ref()?);
+    if pk.as_slice() != known_public || !verify_signed_message(msg, sig, pk) {
+        return None;
+    }
+    Some(new_key.to_vec())
+}
+
+/// 署名付き投稿のメッセージID（公開鍵 + 署名対象の本体の SHA-256 先頭8バイト）。
+/// 署名対象 (kind, payload, timestamp) から決まるので、受信側が再計算して検証できる。
+/// 減衰値や版を含まないので、中継経路や v1 で署名し直した写しでも同じ ID になる。
+/// ランダム ID と違い内容を変えると ID も変わるため、編集は元 ID を参照する EDIT フレームで行う。
+/// 公開鍵も含めるのは、他人が同じ内容を先に送って ID の投稿者を横取りできないようにするため。
+fn message_id(msg: &protocol::Message) -> Option<[u8; protocol::MESSAGE_ID_LEN]> {
+    let pk = msg.public_key.as_ref()?;
+    let mut v = pk.clone();
+    v.extend_from_slice(&protocol::content_bytes(msg));
+    let d = ring::digest::digest(&ring::digest::SHA256, &v);
+    d.as_ref()[..protocol::MESSAGE_ID_LEN].try_into().ok()
+}
+
+fn parse_message_id(s: &str) -> Option<[u8; protocol::MESSAGE_ID_LEN]> {
+    crypto::from_hex(s).ok()?.try_into().ok()
+}
+
+/// (投稿者の公開鍵, タイムスタンプ) → 署名を確かめて受け取った版のメッセージID。
+/// 同じミリ秒に正規の投稿が複数あり得るので、ID は時刻ごとにいくつでも持つ。
+/// 署名の通らない版が同じ投稿者・時刻で届いたら中継途中の改ざんとみなす
+#[derive(Default)]
+struct IdLedger {
+    ids: HashMap<(Vec<u8>, u64), Vec<[u8; protocol::MESSAGE_ID_LEN]>>,
+    order: VecDeque<(Vec<u8>, u64)>,
+}
+
+impl IdLedger {
+    /// 署名の通らなかった版について、同じ投稿者・時刻の正規の ID を知っていれば返す。
+    /// 署名の通った版は内容が違っても別の投稿なので、ここには渡さない
+    fn conflicting(&self, msg: &protocol::Message) -> Option<[u8; protocol::MESSAGE_ID_LEN]> {
+        let pk = msg.public_key.as_ref()?;
+        let known = self.ids.get(&(pk.clone(), msg.timestamp))?;
+        let id = message_id(msg);
+        (!known.iter().any(|k| Some(*k) == id))
+            .then(|| known.first().copied())
+            .flatten()
+    }
+
+    /// 同じ ID の版を記録済みか（v1 で署名し直された写しは署名が違っても同じ ID）
+    fn knows(&self, msg: &protocol::Message) -> bool {
+        let (Some(pk), Some(id)) = (msg.public_key.as_ref(), message_id(msg)) else {
+            return false;
+        };
+        self.ids
+            .get(&(pk.clone(), msg.timestamp))
+            .is_some_and(|known| known.contains(&id))
+    }
+
+    /// 署名検証済みの版を記録する
+    fn record(&mut self, msg: &protocol::Message) {
+        let (Some(pk), Some(id)) = (msg.public_key.as_ref(), message_id(msg)) else {
+            return;
+        };
+        let key = (pk.clone(), msg.timestamp);
+        match self.ids.get_mut(&key) {
+            Some(known) if !known.contains(&id) => known.push(id),
+            Some(_) => {}
+            None => {
+                self.ids.insert(key.clone(), vec![id]);
+                self.order.push_back(key);
+                if self.order.len() > SEEN_MESSAGE_CACHE_CAPACITY
+                    && let Some(old) = self.order.pop_front()
+                {
+                    self.ids.remove(&old);
+                }
+            }
         }
     }
-    seed ^= seed << 13;
-    seed ^= seed >> 7;
-    seed ^= seed << 17;
-    (seed % 100) as u8
 }
 
-fn should_relay_to_peer(msg: &protocol::Message, src: usize, dst: usize) -> bool {
-    let chance = relay_probability_percent(msg.attenuation);
-    chance > 0 && relay_bucket(msg, src, dst) < chance
+/// 全ピアへ送る自分の署名付きフレームの通し番号。
+/// 受信側は接続ごとに最初に見た番号から数えるので、再起動して 1 に戻っても構わない
+#[derive(Default)]
+struct SendSeq(u64);
+
+impl SendSeq {
+    fn next(&mut self) -> u64 {
+        self.0 += 1;
+        self.0
+    }
 }
 
-fn message_identity(msg: &protocol::Message) -> [u8; 32] {
-    let pk_len = msg.public_key.as_ref().map_or(0, |pk| pk.len());
-    let sig_len = msg.signature.as_ref().map_or(0, |sig| sig.len());
-    let mut v = Vec::with_capacity(18 + msg.payload.len() + pk_len + sig_len);
-    v.push(msg.version);
-    v.push(msg.kind);
-    v.extend_from_slice(&msg.timestamp.to_be_bytes());
-    v.extend_from_slice(&(msg.payload.len() as u32).to_be_bytes());
-    v.extend_from_slice(&msg.payload);
-    if let Some(pk) = msg.public_key.as_ref() {
-        v.extend_from_slice(&(pk.len() as u32).to_be_bytes());
-        v.extend_from_slice(pk);
-    } else {
-        v.extend_from_slice(&0u32.to_be_bytes());
+/// 直接つながっているピア本人の通し番号の並び
+#[derive(Debug, PartialEq, Eq)]
+enum SeqCheck {
+    /// 期待どおり（または最初の 1 件）
+    InOrder,
+    /// 途中が抜けた（expected から got の手前までが届いていない）
+    Gap { expected: u64, got: u64 },
+    /// 既に過ぎた番号が後から届いた
+    Reordered { expected: u64, got: u64 },
+}
+
+/// next は次に届くはずの番号。逆行したものは次の期待値を戻さない
+fn check_seq(next: &mut Option<u64>, seq: u64) -> SeqCheck {
+    let result = match *next {
+        None => SeqCheck::InOrder,
+        Some(expected) if seq == expected => SeqCheck::InOrder,
+        Some(expected) if seq > expected => SeqCheck::Gap { expected, got: seq },
+        Some(expected) => SeqCheck::Reordered { expected, got: seq },
+    };
+    *next = Some(next.map_or(seq + 1, |n| n.max(seq + 1)));
+    result
+}
+
+/// 抜け・逆行があれば監査ログと警告に出す説明
+fn seq_problem(check: &SeqCheck) -> Option<String> {
+    match *check {
+        SeqCheck::InOrder => None,
+        SeqCheck::Gap { expected, got } => Some(format!(
+            "通し番号の抜け: 期待={} 受信={} ({} 件届いていません)",
+            expected,
+            got,
+            got - expected
+        )),
+        SeqCheck::Reordered { expected, got } => {
+            Some(format!("通し番号の逆行: 期待={} 受信={}", expected, got))
+        }
     }
-    if let Some(sig) = msg.signature.as_ref() {
-        v.extend_from_slice(&(sig.len() as u32).to_be_bytes());
-        v.extend_from_slice(sig);
-    } else {
-        v.extend_from_slice(&0u32.to_be_bytes());
+}
+
+/// メッセージID → 投稿者の公開鍵（編集・削除の権限確認用）
+#[derive(Default)]
+struct AuthorCache {
+    authors: HashMap<[u8; protocol::MESSAGE_ID_LEN], Vec<u8>>,
+    order: VecDeque<[u8; protocol::MESSAGE_ID_LEN]>,
+}
+
+impl AuthorCache {
+    fn remember(&mut self, id: [u8; protocol::MESSAGE_ID_LEN], public_key: &[u8]) {
+        if self.authors.insert(id, public_key.to_vec()).is_none() {
+            self.order.push_back(id);
+            if self.order.len() > SEEN_MESSAGE_CACHE_CAPACITY
+                && let Some(old) = self.order.pop_front()
+            {
+                self.authors.remove(&old);
+            }
+        }
+    }
+
+    /// 覚えていなければ、保存済みの投稿に残した署名の鍵を引いて覚える。
+    /// 再起動の前や、キャッシュから押し出された後の投稿への EDIT/DELETE も確かめられる
+    fn author(&mut self, id: &[u8; protocol::MESSAGE_ID_LEN]) -> Option<&[u8]> {
+        if !self.authors.contains_key(id) {
+            let pk = stored_author(id)?;
+            self.remember(*id, &pk);
+        }
+        self.authors.get(id).map(Vec::as_slice)
     }
-    let digest = ring::digest::digest(&ring::digest::SHA256, &v);
-    let mut out = [0u8; 32];
-    out.copy_from_slice(digest.as_ref());
-    out
 }
 
-fn is_duplicate_message(
+/// 保存済みの投稿のうち、署名を確かめて保存したものの作者の鍵
+fn stored_author(id: &[u8; protocol::MESSAGE_ID_LEN]) -> Option<Vec<u8>> {
+    let rec = crate::storage::get_by_id(&crypto::to_hex(id))?;
+    if rec.signature != rpc::SigState::Valid {
+        return None;
+    }
+    Some(rec.proof?.public_key)
+}
+
+fn build_signed_amend(
+    target: &[u8; protocol::MESSAGE_ID_LEN],
+    text: Option<&str>,
+    pkcs8: &[u8],
+    pubk: &[u8],
+    seq: u64,
+) -> Option<protocol::Message> {
+    let ts = current_unix_millis();
+    let msg = match text {
+        Some(t) => protocol::Message::edit(ts, target, t),
+        None => protocol::Message::delete(ts, target),
+    };
+    sign_message(msg.with_seq(seq), pkcs8, pubk)
+}
+
+/// 元の投稿者の鍵で署名された EDIT/DELETE なら (対象ID, 新本文) を返す。
+/// DELETE の新本文は None。
+fn authorize_amend(
     msg: &protocol::Message,
-    seen_messages: &mut HashSet<[u8; 32]>,
-    seen_order: &mut VecDeque<[u8; 32]>,
-) -> bool {
-    let id = message_identity(msg);
-    if seen_messages.contains(&id) {
-        return true;
+    authors: &mut AuthorCache,
+) -> Option<([u8; protocol::MESSAGE_ID_LEN], Option<String>)> {
+    let (target, body) = protocol::amend_target(msg)?;
+    let (pk, sig) = (msg.public_key.as_ref()?, msg.signature.as_ref()?);
+    if authors.author(&target)? != pk.as_slice() || !verify_signed_message(msg, sig, pk) {
+        return None;
     }
-    seen_messages.insert(id);
-    seen_order.push_back(id);
-    if seen_order.len() > SEEN_MESSAGE_CACHE_CAPACITY {
-        if let Some(old) = seen_order.pop_front() {
-            seen_messages.remove(&old);
+    let text =
+        (msg.kind == protocol::MsgKind::EDIT).then(|| String::from_utf8_lossy(body).to_string());
+    Some((target, text))
+}
+
+/// 編集・削除後の表示行
+fn amended_line(id: &str, text: Option<&str>) -> String {
+    match text {
+        Some(t) => format!("#{} {} ○ (編集済み)", id, t),
+        None => format!("#{} {}", id, crate::storage::DELETED_TEXT),
+    }
+}
+
+/// 自分の投稿を編集・削除して全ピアへ送り、ローカルの表示と保存も更新する。
+/// 切断すべきピア（致命的な書き込みエラー）の index を返す
+#[allow(clippy::too_many_arguments)]
+async fn amend_own<C: Connection>(
+    target_hex: &str,
+    text: Option<&str>,
+    keys: Option<(&[u8], &[u8])>,
+    authors: &mut AuthorCache,
+    seq: &mut SendSeq,
+    peers: &mut [Peer<C>],
+    tx_main: &Sender<rpc::Event>,
+) -> Vec<usize> {
+    let Some((pkcs8, pubk)) = keys else {
+        tx_main
+            .send(rpc::Event::Message("鍵未生成 (/init を先に実行)".into()))
+            .await
+            .ok();
+        return Vec::new();
+    };
+    let Some(target) = parse_message_id(target_hex) else {
+        tx_main
+            .send(rpc::Event::Message(format!(
+                "不正なメッセージID: {}",
+                target_hex
+            )))
+            .await
+            .ok();
+        return Vec::new();
+    };
+    if authors.author(&target) != Some(pubk) {
+        tx_main
+            .send(rpc::Event::Message(format!(
+                "自分の投稿ではないか不明な ID です: {}",
+                target_hex
+            )))
+            .await
+            .ok();
+        return Vec::new();
+    }
+    let Some(m) = build_signed_amend(&target, text, pkcs8, pubk, seq.next()) else {
+        tx_main
+            .send(rpc::Event::Message("署名生成失敗".into()))
+            .await
+            .ok();
+        return Vec::new();
+    };
+    let mut failed = Vec::new();
+    for i in 0..peers.len() {
+        // HELLO を確かめ終えた相手にだけ送る
+        if !is_ready(peers, i) {
+            continue;
+        }
+        let Some(frame) = frame_for_peer(&m, peer_version(peers, i), keys) else {
+            continue;
+        };
+        if let Flush::Drop(kind) = peers[i].send(&frame) {
+            tx_main
+                .send(rpc::Event::Message(format!("送信エラー {}: {:?}", i, kind)))
+                .await
+                .ok();
+            failed.push(i);
         }
     }
-    false
+    let id = crypto::to_hex(&target);
+    report_storage(crate::storage::amend_by_id(&id, text), tx_main);
+    let line = amended_line(&id, text);
+    tx_main.send(rpc::Event::Replace { id, line }).await.ok();
+    failed
 }
 
-pub async fn network_handler(tx_main: Sender<rpc::Event>, mut rx_thread: Receiver<rpc::Command>) {
-    tx_main
-        .send(rpc::Event::Message("ネットワークスレッド開始".to_string()))
-        .await
-        .ok();
-    let mut listener: Option<TcpListener> = None;
-    let mut clients: Vec<TcpStream> = Vec::new();
-    // 各 client ごとのデコーダ
-    let mut decoders: Vec<protocol::Decoder> = Vec::new();
-    #[derive(Clone, Debug)]
-    struct PeerMeta {
-        public_key: Vec<u8>,
-        last_valid: bool,
-        last_timestamp: u64,
-        handle: Option<String>,
-    }
-    let mut peer_meta: Vec<Option<PeerMeta>> = Vec::new();
-    let mut seen_messages: HashSet<[u8; 32]> = HashSet::new();
-    let mut seen_order: VecDeque<[u8; 32]> = VecDeque::new();
-    let mut buf = [0u8; 2048];
-    // ハンドル（必須）
-    let mut handle: String = config::get_value("user.handle")
-        .and_then(|v| v.as_str().map(|s| s.to_string()))
-        .unwrap_or_default();
+/// 保存の失敗は捨てずに画面へ出す（保存できなくてもチャットは続ける）
+fn report_storage<T>(res: crate::Result<T>, tx_main: &Sender<rpc::Event>) {
+    if let Err(e) = res {
+        let _ = tx_main.try_send(rpc::Event::Message(format!("⚠ 保存に失敗: {}", e)));
+    }
+}
 
-    // 署名用鍵を読む (存在しなければ None)
-    let mut pkcs8: Option<Vec<u8>> = None;
-    let mut public: Option<Vec<u8>> = None;
-    // 起動時に読み込み ( /init 後は再起動で有効 )。将来ホットリロードするなら /reload 等追加。
-    if let (Some(pk_hex), Some(pub_hex)) = (
-        config::get_value("key.pkcs8").and_then(|v| v.as_str().map(|s| s.to_string())),
-        config::get_value("key.public").and_then(|v| v.as_str().map(|s| s.to_string())),
+/// 全体チャットを署名して全ピアへ送り、保存して Sent を通知する。
+/// 切断すべきピア（致命的な書き込みエラー）の index を返す。一時的な失敗は送信キューに残す
+#[allow(clippy::too_many_arguments)]
+async fn send_chat<C: Connection>(
+    text: &str,
+    reply_to: Option<String>,
+    urgent: bool,
+    handle: &str,
+    keys: (&[u8], &[u8]),
+    authors: &mut AuthorCache,
+    seq: &mut SendSeq,
+    peers: &mut [Peer<C>],
+    tx_main: &Sender<rpc::Event>,
+) -> Vec<usize> {
+    let (pkcs8, pubk) = keys;
+    let target = reply_to.as_deref().and_then(parse_message_id);
+    // 送信本文にハンドルをプレーンで含める
+    let body = format!("{}: {}", handle, text);
+    let Some(m) = build_signed_chat(&body, target.as_ref(), urgent, pkcs8, pubk, seq.next()) else {
+        tx_main
+            .send(rpc::Event::Message("署名生成失敗".into()))
+            .await
+            .ok();
+        tx_main.send(rpc::Event::Unsent).await.ok();
+        return Vec::new();
+    };
+    let mut failed = Vec::new();
+    for i in 0..peers.len() {
+        // HELLO を確かめ終えた相手にだけ送る
+        if !is_ready(peers, i) {
+            continue;
+        }
+        let Some(frame) = frame_for_peer(&m, peer_version(peers, i), Some(keys)) else {
+            continue;
+        };
+        if let Flush::Drop(kind) = peers[i].send(&frame) {
+            tx_main
+                .send(rpc::Event::Message(format!("送信エラー {}: {:?}", i, kind)))
+                .await
+                .ok();
+            failed.push(i);
+        }
+    }
+    // 保存（送信メタ）
+    let rec = crate::storage::MessageRecord {
+        ts_millis: m.timestamp,
+        recv_ts_millis: current_unix_millis(),
+        kind: crate::storage::MsgKind::Chat,
+        from_peer_id: None,
+        to_peer_id: None,
+        handle: Some(handle.to_string()),
+        text: body,
+        signature: rpc::SigState::Valid,
+        reply_to,
+        binary: None,
+        proof: signature_proof(&m),
+        peer_fingerprint: None,
+    };
+    if let Some(mid) = message_id(&m) {
+        authors.remember(mid, pubk);
+        let id = crypto::to_hex(&mid);
+        report_storage(crate::storage::store_structured(&rec, Some(&id)), tx_main);
+        tx_main.send(rpc::Event::Sent { id }).await.ok();
+    } else {
+        report_storage(crate::storage::store_structured(&rec, None), tx_main);
+        tx_main.send(rpc::Event::Unsent).await.ok();
+    }
+    failed
+}
+
+/// トークン内の候補アドレス（カンマ区切り）へ並列に接続し、最初に成功したものを残す。
+/// 残りの試行は捨てる。全て失敗したら各アドレスの失敗理由を返す
+async fn dial_any<T: Transport>(
+    transport: &T,
+    addrs: &str,
+    timeout: Duration,
+) -> Result<(T::Conn, String), String> {
+    let mut set = tokio::task::JoinSet::new();
+    for addr in addrs.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+        let addr = addr.to_string();
+        let transport = transport.clone();
+        set.spawn(async move {
+            let res = match tokio::time::timeout(timeout, transport.connect(&addr)).await {
+                Ok(Ok(s)) => Ok(s),
+                Ok(Err(e)) => Err(format!("{:?}", e.kind())),
+                Err(_) => Err("タイムアウト".to_string()),
+            };
+            (addr, res)
+        });
+    }
+    let mut errors = Vec::new();
+    while let Some(joined) = set.join_next().await {
+        match joined {
+            Ok((addr, Ok(s))) => return Ok((s, addr)),
+            Ok((addr, Err(e))) => errors.push(format!("{}: {}", addr, e)),
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    if errors.is_empty() {
+        return Err("接続先がありません".into());
+    }
+    Err(errors.join(", "))
+}
+
+/// トークンのアドレス（名前解決済み）に実際の接続先が含まれなければ警告文を返す。
+/// 中継・MITM などで別の相手につながった可能性を知らせるための最小限の確認
+fn address_mismatch(expected: &[SocketAddr], actual: SocketAddr) -> Option<String> {
+    // IPv4 射影 IPv6 (::ffff:a.b.c.d) は IPv4 とみなす
+    let canon = |a: &SocketAddr| (a.ip().to_canonical(), a.port());
+    if expected.is_empty() || expected.iter().any(|e| canon(e) == canon(&actual)) {
+        return None;
+    }
+    let list: Vec<String> = expected.iter().map(|e| e.to_string()).collect();
+    Some(format!(
+        "トークン={} 実際の接続先={}",
+        list.join(","),
+        actual
+    ))
+}
+
+/// ピア未接続の間に打たれた全体チャット (本文, 返信先ID)。次に接続したピアへ送る
+#[derive(Default)]
+struct Outbox {
+    queue: VecDeque<(String, Option<String>, bool)>,
+}
+
+impl Outbox {
+    fn push(&mut self, text: String, reply_to: Option<String>, urgent: bool) {
+        self.queue.push_back((text, reply_to, urgent));
+    }
+
+    /// HELLO を済ませたピアがいれば送信待ちを古い順に取り出す
+    fn take_ready(&mut self, ready_peers: usize) -> Vec<(String, Option<String>, bool)> {
+        if ready_peers == 0 {
+            return Vec::new();
+        }
+        self.queue.drain(..).collect()
+    }
+}
+
+fn build_signed_topic(
+    text: &str,
+    pkcs8: &[u8],
+    pubk: &[u8],
+    seq: u64,
+) -> Option<protocol::Message> {
+    let ts = current_unix_millis();
+    let msg = protocol::Message::topic(ts, text);
+    sign_message(msg.with_seq(seq), pkcs8, pubk)
+}
+
+/// 現在の部屋のトピック。署名付き TOPIC フレームのうち最も新しいものを持ち、
+/// HELLO を送ってきたピアへそのまま再送する
+#[derive(Default)]
+struct RoomTopic {
+    frame: Option<protocol::Message>,
+}
+
+/// 受信した TOPIC の扱い
+#[derive(Debug, PartialEq, Eq)]
+enum TopicUpdate {
+    /// 採用した（本文, 設定者の指紋）
+    Accepted(String, String),
+    /// 保持中のものより古いか同じ
+    Stale,
+    /// 署名なし・署名不正・長すぎる
+    Rejected,
+}
+
+impl RoomTopic {
+    fn accept(&mut self, msg: &protocol::Message) -> TopicUpdate {
+        let (Some(text), Some(pk), Some(sig)) = (
+            protocol::topic_text(msg),
+            msg.public_key.as_ref(),
+            msg.signature.as_ref(),
+        ) else {
+            return TopicUpdate::Rejected;
+        };
+        if !verify_signed_message(msg, sig, pk) {
+            return TopicUpdate::Rejected;
+        }
+        if self
+            .frame
+            .as_ref()
+            .is_some_and(|f| f.timestamp >= msg.timestamp)
+        {
+            return TopicUpdate::Stale;
+        }
+        let text = text.to_string();
+        let by = crypto::fingerprint_hex(pk)[..16].to_string();
+        let mut frame = msg.clone();
+        // 再送先にとっては新しいフレームなので減衰値は戻しておく（v2 は署名対象なので届いたまま）
+        if frame.version < 2 {
+            frame.attenuation = 0;
+        }
+        self.frame = Some(frame);
+        TopicUpdate::Accepted(text, by)
+    }
+
+    /// 新しく来たピアへ送る現在のトピック（相手の版で読めるものだけ）
+    fn replay_frame(&self, version: u8) -> Option<Vec<u8>> {
+        self.frame
+            .as_ref()
+            .filter(|m| m.version <= version)
+            .map(protocol::encode)
+    }
+}
+
+fn build_signed_system(
+    text: &str,
+    pkcs8: &[u8],
+    pubk: &[u8],
+    seq: u64,
+) -> Option<protocol::Message> {
+    let ts = current_unix_millis();
+    let msg = protocol::Message::system(ts, text);
+    sign_message(msg.with_seq(seq), pkcs8, pubk)
+}
+
+fn build_signed_advert(addr: &str, pkcs8: &[u8], pubk: &[u8]) -> Option<protocol::Message> {
+    let ts = current_unix_millis();
+    let msg = protocol::Message::advert(ts, addr);
+    sign_message(msg, pkcs8, pubk)
+}
+
+/// /topology の返事を待つ時間。過ぎたら集まった分で表示する
+const TOPOLOGY_WAIT_MS: u64 = 3_000;
+
+/// 準備完了の隣接ピアの指紋
+fn neighbor_fingerprints<C>(
+    peers: &[Peer<C>],
+    removing: &[usize],
+) -> Vec<[u8; protocol::ROUTE_FINGERPRINT_LEN]> {
+    peers
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| is_ready(peers, *i) && !removing.contains(i))
+        .filter_map(|(_, p)| p.meta.as_ref().map(|m| route_fingerprint(&m.public_key)))
+        .collect()
+}
+
+/// 集計中の /topology。返事ごとに隣接を足し、向きは区別しない
+struct TopologyView {
+    id: [u8; protocol::TOPOLOGY_QUERY_ID_LEN],
+    own: String,
+    deadline: u64,
+    edges: BTreeMap<String, BTreeSet<String>>,
+    /// 返事をくれたノード数
+    replies: usize,
+}
+
+impl TopologyView {
+    fn new(
+        id: [u8; protocol::TOPOLOGY_QUERY_ID_LEN],
+        own: &[u8; protocol::ROUTE_FINGERPRINT_LEN],
+        neighbors: &[[u8; protocol::ROUTE_FINGERPRINT_LEN]],
+        now: u64,
+    ) -> Self {
+        let mut view = Self {
+            id,
+            own: crypto::to_hex(own),
+            deadline: now + TOPOLOGY_WAIT_MS,
+            edges: BTreeMap::new(),
+            replies: 0,
+        };
+        view.add(own, neighbors);
+        view
+    }
+
+    fn add(
+        &mut self,
+        from: &[u8; protocol::ROUTE_FINGERPRINT_LEN],
+        neighbors: &[[u8; protocol::ROUTE_FINGERPRINT_LEN]],
     ) {
-        let pk_bytes = crypto::from_hex(&pk_hex).unwrap_or_default();
-        let pub_bytes = crypto::from_hex(&pub_hex).unwrap_or_default();
-        if !pk_bytes.is_empty() && !pub_bytes.is_empty() {
-            pkcs8 = Some(pk_bytes);
-            public = Some(pub_bytes);
+        let from = crypto::to_hex(from);
+        self.edges.entry(from.clone()).or_default();
+        for n in neighbors {
+            let n = crypto::to_hex(n);
+            self.edges
+                .entry(from.clone())
+                .or_default()
+                .insert(n.clone());
+            self.edges.entry(n).or_default().insert(from.clone());
         }
     }
 
-    'main_loop: loop {
-        // コマンド処理: drain できるだけ読む
-        while let Ok(cmd) = rx_thread.try_recv() {
-            match cmd {
-                rpc::Command::Open(port) => {
-                    if listener.is_some() {
-                        tx_main
-                            .send(rpc::Event::Message(
-                                "既に待受中（/open は同時に1つまで）".into(),
-                            ))
-                            .await
-                            .ok();
-                    } else {
-                        match TcpListener::bind(format!("127.0.0.1:{}", port)).await {
-                            Ok(l) => {
-                                listener = Some(l);
-                                let addr = format!("127.0.0.1:{}", port);
-                                let tok = crypto::encrypt_conninfo_to_hex(&addr)
-                                    .unwrap_or_else(|_| "?".into());
-                                tx_main
-                                    .send(rpc::Event::Message(format!("待受開始 (token={})", tok)))
-                                    .await
-                                    .ok();
-                            }
-                            Err(e) => {
-                                tx_main
-                                    .send(rpc::Event::Message(format!("バインドエラー: {:?}", e)))
-                                    .await
-                                    .ok();
-                            }
-                        }
-                    }
-                }
-                rpc::Command::Connect(token) => {
-                    // トークンのみ受け付け。復号失敗ならエラー
-                    let target = match crypto::decrypt_conninfo_from_hex(&token) {
-                        Ok(s) => s,
+    /// この問い合わせへの、署名の正しい返事なら取り込む
+    fn absorb(&mut self, msg: &protocol::Message) -> bool {
+        let (Some((id, neighbors)), Some(pk), Some(sig)) = (
+            protocol::topology_reply_parts(msg),
+            msg.public_key.as_ref(),
+            msg.signature.as_ref(),
+        ) else {
+            return false;
+        };
+        if id != self.id || !verify_signed_message(msg, sig, pk) {
+            return false;
+        }
+        self.add(&route_fingerprint(pk), &neighbors);
+        self.replies += 1;
+        true
+    }
+
+    /// 隣接リスト（指紋順。自分には印を付ける）
+    fn render(&self) -> String {
+        let mut lines = vec![format!(
+            "構成 ({} ノード, 返事 {} 件):",
+            self.edges.len(),
+            self.replies
+        )];
+        for (node, neighbors) in &self.edges {
+            let mark = if *node == self.own { " (自分)" } else { "" };
+            let list: Vec<&str> = neighbors.iter().map(String::as_str).collect();
+            lines.push(format!("{}{} -> {}", node, mark, list.join(", ")));
+        }
+        lines.join("\n")
+    }
+}
+
+fn build_signed_topology_reply(
+    id: &[u8; protocol::TOPOLOGY_QUERY_ID_LEN],
+    neighbors: &[[u8; protocol::ROUTE_FINGERPRINT_LEN]],
+    pkcs8: &[u8],
+    pubk: &[u8],
+) -> Option<protocol::Message> {
+    let msg = protocol::Message::topology_reply(current_unix_millis(), id, neighbors);
+    sign_message(msg, pkcs8, pubk)
+}
+
+/// WouldBlock になるか max_bytes に達するまで読み、届いた分を out にまとめる。
+/// 何か読めた後の切断やエラーは、次のティックの読み込みで拾う
+fn read_burst<C: Connection>(
+    c: &mut C,
+    buf: &mut [u8],
+    max_bytes: usize,
+    out: &mut Vec<u8>,
+) -> std::io::Result<usize> {
+    out.clear();
+    while out.len() < max_bytes {
+        let want = buf.len().min(max_bytes - out.len());
+        match c.try_read(&mut buf[..want]) {
+            Ok(0) => break,
+            Ok(n) => out.extend_from_slice(&buf[..n]),
+            Err(e) if out.is_empty() => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(out.len())
+}
+
+/// 紹介用に覚えておくピア数の上限
+const MAX_DIRECTORY_ENTRIES: usize = 64;
+
+/// 紹介されたピアの待受アドレス。本人が署名した ADVERT を指紋ごとにそのまま持ち、
+/// HELLO を送ってきた新しいピアへ転送する（署名は本人にしか作れないので広告の同意を兼ねる）
+#[derive(Default)]
+struct Directory {
+    adverts: HashMap<String, protocol::Message>,
+}
+
+/// 受信した ADVERT の扱い
+#[derive(Debug, PartialEq, Eq)]
+enum AdvertUpdate {
+    /// 登録した（指紋, 待受アドレス）
+    Added(String, SocketAddr),
+    /// 保持中のものより古いか同じ、または上限に達している
+    Stale,
+    /// 署名なし・署名不正・アドレスとして読めない
+    Rejected,
+}
+
+impl Directory {
+    fn accept(&mut self, msg: &protocol::Message) -> AdvertUpdate {
+        let (Some(addr), Some(sig), Some(pk)) = (
+            protocol::advert_addr(msg),
+            msg.signature.as_ref(),
+            msg.public_key.as_ref(),
+        ) else {
+            return AdvertUpdate::Rejected;
+        };
+        if !verify_signed_message(msg, sig, pk) {
+            return AdvertUpdate::Rejected;
+        }
+        let fp = crypto::fingerprint_hex(pk)[..16].to_string();
+        match self.adverts.get(&fp) {
+            Some(old) if old.timestamp >= msg.timestamp => return AdvertUpdate::Stale,
+            None if self.adverts.len() >= MAX_DIRECTORY_ENTRIES => return AdvertUpdate::Stale,
+            _ => {}
+        }
+        self.adverts.insert(fp.clone(), msg.clone());
+        AdvertUpdate::Added(fp, addr)
+    }
+
+    /// public_key のピアへ紹介するフレーム（本人の広告と、相手の版で読めないものは除く）
+    fn introductions_for(&self, public_key: &[u8], version: u8) -> Vec<Vec<u8>> {
+        self.adverts
+            .values()
+            .filter(|m| m.public_key.as_deref() != Some(public_key) && m.version <= version)
+            .map(protocol::encode)
+            .collect()
+    }
+
+    /// /discover 用の一覧（指紋順）
+    fn describe(&self) -> String {
+        if self.adverts.is_empty() {
+            return "紹介されたピアはいません".into();
+        }
+        let mut entries: Vec<(&String, SocketAddr)> = self
+            .adverts
+            .iter()
+            .filter_map(|(fp, m)| Some((fp, protocol::advert_addr(m)?)))
+            .collect();
+        entries.sort();
+        let mut lines = vec!["紹介されたピア:".to_string()];
+        for (fp, addr) in entries {
+            let tok =
+                crypto::encrypt_conninfo_to_hex(&addr.to_string()).unwrap_or_else(|_| "?".into());
+            lines.push(format!("指紋={} {} (token={})", fp, addr, tok));
+        }
+        lines.join("\n")
+    }
+}
+
+/// 受信した SYSTEM の表示行。署名なし・署名不正・長すぎるものは None
+fn system_line(msg: &protocol::Message) -> Option<String> {
+    let text = protocol::system_text(msg)?;
+    let (sig, pk) = (msg.signature.as_ref()?, msg.public_key.as_ref()?);
+    verify_signed_message(msg, sig, pk).then(|| {
+        let fp = crypto::fingerprint_hex(pk);
+        format!("{}{} (指紋={})", rpc::SYSTEM_MARK, text, &fp[..16])
+    })
+}
+
+/// お知らせの保存用レコード。過去ログでも表示行のまま出す
+fn system_record(msg: &protocol::Message, line: String) -> crate::storage::MessageRecord {
+    crate::storage::MessageRecord {
+        ts_millis: msg.timestamp,
+        recv_ts_millis: current_unix_millis(),
+        kind: crate::storage::MsgKind::System,
+        from_peer_id: None,
+        to_peer_id: None,
+        handle: None,
+        text: line,
+        signature: rpc::SigState::Valid,
+        reply_to: None,
+        binary: None,
+        proof: signature_proof(msg),
+        peer_fingerprint: None,
+    }
+}
+
+/// 履歴同期で 1 フレームに詰める投稿の合計バイト数（最大ペイロードの半分まで）
+const SYNC_BATCH_BYTES: usize = protocol::DEFAULT_MAX_PAYLOAD as usize / 2;
+
+/// 保存済みの記録から、元の署名付き Chat を組み立て直す（署名材料が無ければ None）
+fn synced_message(rec: &storage::MessageRecord) -> Option<protocol::Message> {
+    let proof = rec.proof.as_ref()?;
+    let msg = protocol::from_signing_bytes(&proof.signed)?;
+    (msg.kind == protocol::MsgKind::CHAT)
+        .then(|| msg.with_key_sig(proof.public_key.clone(), proof.signature.clone()))
+}
+
+/// 履歴同期で照合する日の範囲（今日からさかのぼる日数）
+const SYNC_WINDOW_DAYS: u64 = 30;
+/// 1 回の SYNC_COUNTS から要求する日数と、同時に返す要求の数の上限
+const SYNC_MAX_DATES: usize = 7;
+/// 1 日分でやり取りする投稿の上限（要求に添える ID・返す件数・受け取る件数）
+const SYNC_MAX_RECORDS_PER_DATE: usize = 2000;
+/// 返事を組み立てるときに一度に読む件数
+const SYNC_PAGE_READ: usize = 64;
+/// 要求した日の返事を待つ時間。過ぎてから届いた SYNC_RECORDS は受け取らない
+const SYNC_REPLY_TIMEOUT_MS: u64 = 60_000;
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// 同期でやり取りしてよい日か（SYNC_WINDOW_DAYS 前から明日まで）
+fn sync_date_in_window(date: &str, now: u64) -> bool {
+    let oldest = storage::date_string(now.saturating_sub(SYNC_WINDOW_DAYS * DAY_MS));
+    let newest = storage::date_string(now + DAY_MS);
+    date.len() == oldest.len() && date >= oldest.as_str() && date <= newest.as_str()
+}
+
+/// 件数が食い違う日（相手が 1 件以上持っている日だけ）を、範囲内で新しい方から SYNC_MAX_DATES 日まで。
+/// 件数が同じでも中身が違うことはあるが、比べるのは件数までにしておく
+fn sync_dates_to_request(
+    ours: &[(String, u64)],
+    theirs: &[(String, u64)],
+    now: u64,
+) -> Vec<String> {
+    let ours: HashMap<&str, u64> = ours.iter().map(|(d, n)| (d.as_str(), *n)).collect();
+    let mut dates: Vec<String> = theirs
+        .iter()
+        .filter(|(d, n)| *n > 0 && ours.get(d.as_str()) != Some(n))
+        .filter(|(d, _)| sync_date_in_window(d, now))
+        .map(|(d, _)| d.clone())
+        .collect();
+    dates.sort_unstable_by(|a, b| b.cmp(a));
+    dates.truncate(SYNC_MAX_DATES);
+    dates
+}
+
+/// その日に持っている ID（SYNC_MAX_RECORDS_PER_DATE 件まで）を伝え、足りない投稿を送ってもらう
+fn sync_request_for(date: &str, ts: u64) -> protocol::Message {
+    let (recs, _) = storage::records_page(date, 0, SYNC_MAX_RECORDS_PER_DATE);
+    let have: Vec<[u8; protocol::MESSAGE_ID_LEN]> = recs
+        .iter()
+        .filter_map(|(_, id, _)| parse_message_id(id))
+        .collect();
+    protocol::Message::sync_request(ts, date, &have)
+}
+
+/// 返している途中の SYNC_REQUEST 1 件
+struct SyncServe {
+    date: String,
+    have: HashSet<[u8; protocol::MESSAGE_ID_LEN]>,
+    /// 次に読む連番
+    next: u64,
+    /// これまでに返した件数
+    sent: usize,
+}
+
+/// SYNC_REQUEST への返事を 1 ページ分組み立てる。相手の持っていない署名付き投稿を
+/// SYNC_BATCH_BYTES まで詰め、続きは次の呼び出しで読む。返すものが尽きたら None
+fn sync_reply_page(serve: &mut SyncServe, version: u8, ts: u64) -> Option<protocol::Message> {
+    let mut frames: Vec<Vec<u8>> = Vec::new();
+    let mut size = 0;
+    'read: while serve.sent < SYNC_MAX_RECORDS_PER_DATE {
+        let (recs, next) = storage::records_page(&serve.date, serve.next, SYNC_PAGE_READ);
+        if recs.is_empty() {
+            serve.next = next;
+            break;
+        }
+        for (seq, id, rec) in recs {
+            let Some(msg) = synced_message(&rec).filter(|m| m.version <= version) else {
+                serve.next = seq + 1;
+                continue;
+            };
+            // 保存時の ID と組み立て直した版が一致し、相手が持っていないものだけ送る
+            let wanted = message_id(&msg)
+                .filter(|m| Some(*m) == parse_message_id(&id) && !serve.have.contains(m));
+            if wanted.is_none() {
+                serve.next = seq + 1;
+                continue;
+            }
+            let frame = protocol::encode(&msg);
+            if size + frame.len() > SYNC_BATCH_BYTES && size > 0 {
+                break 'read;
+            }
+            size += frame.len();
+            frames.push(frame);
+            serve.next = seq + 1;
+            serve.sent += 1;
+            if serve.sent >= SYNC_MAX_RECORDS_PER_DATE {
+                break 'read;
+            }
+        }
+    }
+    (!frames.is_empty()).then(|| protocol::Message::sync_records(ts, &serve.date, &frames))
+}
+
+/// ピアごとの履歴同期の状態
+#[derive(Default)]
+struct PeerSync {
+    /// 返事を待っている日 → (締め切り, あと受け取れる件数)
+    awaiting: HashMap<String, (u64, usize)>,
+    /// 返している途中の要求（届いた順、SYNC_MAX_DATES 件まで）
+    serving: VecDeque<SyncServe>,
+}
+
+impl PeerSync {
+    /// その日の要求を組み立てる。同じ日の返事をまだ待っているなら None
+    fn request(&mut self, date: &str, now: u64) -> Option<protocol::Message> {
+        self.awaiting.retain(|_, (deadline, _)| *deadline > now);
+        if self.awaiting.contains_key(date) {
+            return None;
+        }
+        self.awaiting.insert(
+            date.to_string(),
+            (now + SYNC_REPLY_TIMEOUT_MS, SYNC_MAX_RECORDS_PER_DATE),
+        );
+        Some(sync_request_for(date, now))
+    }
+
+    /// 届いた SYNC_RECORDS の n 件のうち受け取ってよい件数。
+    /// 要求していない日・締め切りを過ぎた日は 0、上限を超えた分は切り捨てる
+    fn accept(&mut self, date: &str, n: usize, now: u64) -> usize {
+        let Some((deadline, left)) = self.awaiting.get_mut(date) else {
+            return 0;
+        };
+        if *deadline <= now {
+            self.awaiting.remove(date);
+            return 0;
+        }
+        let take = n.min(*left);
+        *left -= take;
+        take
+    }
+
+    /// 相手からの要求を返す順番に並べる。並びきれなければ false
+    fn serve(&mut self, date: String, have: Vec<[u8; protocol::MESSAGE_ID_LEN]>) -> bool {
+        if self.serving.len() >= SYNC_MAX_DATES {
+            return false;
+        }
+        self.serving.push_back(SyncServe {
+            date,
+            have: have.into_iter().collect(),
+            next: 0,
+            sent: 0,
+        });
+        true
+    }
+
+    /// 次に送る SYNC_RECORDS。返し終えた要求は外す
+    fn next_reply(&mut self, version: u8, ts: u64) -> Option<protocol::Message> {
+        while let Some(serve) = self.serving.front_mut() {
+            if let Some(page) = sync_reply_page(serve, version, ts) {
+                return Some(page);
+            }
+            self.serving.pop_front();
+        }
+        None
+    }
+}
+
+/// 同期で受け取った投稿 1 件の扱い
+#[derive(Debug, PartialEq, Eq)]
+enum SyncedPost {
+    Stored,
+    Duplicate,
+    Invalid,
+}
+
+/// 同期で受け取った投稿の署名を確かめ、まだ持っていなければ保存する
+fn absorb_synced(
+    msg: &protocol::Message,
+    src: usize,
+    now: u64,
+    authors: &mut AuthorCache,
+) -> SyncedPost {
+    let (Some(pk), Some(sig)) = (msg.public_key.as_ref(), msg.signature.as_ref()) else {
+        return SyncedPost::Invalid;
+    };
+    let kind_ok = msg.kind == protocol::MsgKind::CHAT;
+    let Some(mid) = message_id(msg).filter(|_| kind_ok && verify_signed_message(msg, sig, pk))
+    else {
+        return SyncedPost::Invalid;
+    };
+    let id = crypto::to_hex(&mid);
+    if storage::get_by_id(&id).is_some() {
+        return SyncedPost::Duplicate;
+    }
+    let text = String::from_utf8_lossy(protocol::chat_text(msg)).to_string();
+    let rec = storage::MessageRecord {
+        ts_millis: msg.timestamp,
+        recv_ts_millis: now,
+        kind: storage::MsgKind::Chat,
+        from_peer_id: Some(src),
+        to_peer_id: None,
+        handle: signed_handle_field(&text).map(str::to_string),
+        text,
+        signature: rpc::SigState::Valid,
+        reply_to: protocol::reply_target(msg).map(|t| crypto::to_hex(&t)),
+        binary: None,
+        proof: signature_proof(msg),
+        peer_fingerprint: Some(crypto::fingerprint_hex(pk)[..16].to_string()),
+    };
+    authors.remember(mid, pk);
+    match storage::store_structured(&rec, Some(&id)) {
+        Ok(()) => SyncedPost::Stored,
+        Err(_) => SyncedPost::Invalid,
+    }
+}
+
+/// /token 用: 待受中のアドレスとトークン。ポートマッピング中なら外部アドレスの分も並べる
+fn listener_tokens(port: u16, mapping: Option<&nat::Mapping>) -> String {
+    let line = |label: &str, addr: String| {
+        let tok = crypto::encrypt_conninfo_to_hex(&addr).unwrap_or_else(|_| "?".into());
+        format!("{} {} (token={})", label, addr, tok)
+    };
+    let mut lines = vec![line("待受中", format!("127.0.0.1:{}", port))];
+    if let Some(m) = mapping {
+        lines.push(line("外部", m.external_addr()));
+    }
+    lines.join("\n")
+}
+
+/// /open で作った待受。ポートマッピングしたならその情報も持つ
+struct Listener<L> {
+    socket: L,
+    mapping: Option<nat::Mapping>,
+}
+
+/// /open save=<path> 用に、トークンだけを 1 行でファイルに書く
+pub fn write_token_file(path: &str, token: &str) -> std::io::Result<()> {
+    std::fs::write(path, format!("{}\n", token))
+}
+
+/// /connect @<path> 用に、ファイルからトークンを読み、復号できるか確かめて返す
+pub fn read_token_file(path: &str) -> Result<String, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("トークンファイルを読めません ({}): {}", path, e))?;
+    let token = text.trim();
+    crypto::decrypt_conninfo_from_hex(token)
+        .map_err(|e| format!("トークンファイルの中身が不正です ({}): {}", path, e))?;
+    Ok(token.to_string())
+}
+
+/// /close・終了時にポートマッピングを消し、結果を表示する
+async fn release_mapping(mapping: Option<nat::Mapping>, tx_main: &Sender<rpc::Event>) {
+    let Some(m) = mapping else {
+        return;
+    };
+    let msg = match nat::unmap_port(&m).await {
+        Ok(()) => format!("ポートマッピング削除 ({})", m.external_addr()),
+        Err(e) => format!("ポートマッピング削除失敗: {}", e),
+    };
+    tx_main.send(rpc::Event::Message(msg)).await.ok();
+}
+
+/// /dump 用に 16 バイトごとに改行した16進表記
+fn hex_dump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, row)| format!("{:04x}: {}", i * 16, crypto::to_hex(row)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// 切断理由IDの説明
+fn disconnect_reason_text(reason: u32) -> &'static str {
+    match reason {
+        1 => "ハンドル長超過",
+        2 => "不正なHELLOハンドル",
+        3 => "HELLO署名不正",
+        4 => "不正な鍵ローテーション",
+        5 => "重複ID",
+        6 => "無通信タイムアウト",
+        7 => "不正なフレーム",
+        8 => "接続パズル不正解",
+        9 => "接続パズル時間切れ",
+        10 => "署名不正の繰り返し",
+        _ => "不明",
+    }
+}
+
+fn audit_event(
+    kind: AuditKind,
+    src: usize,
+    public_key: Option<&[u8]>,
+    detail: String,
+) -> AuditEvent {
+    AuditEvent {
+        ts_millis: current_unix_millis(),
+        kind,
+        peer_id: Some(src),
+        fingerprint: public_key.map(|pk| crypto::fingerprint_hex(pk)[..16].to_string()),
+        detail,
+    }
+}
+
+fn disconnect_audit(src: usize, public_key: Option<&[u8]>, reason: u32) -> AuditEvent {
+    let detail = format!("reason={} ({})", reason, disconnect_reason_text(reason));
+    audit_event(AuditKind::Disconnect, src, public_key, detail)
+}
+
+/// 切断理由を残す（先に付いた理由を優先）。削除するときに PeerDisconnected で知らせる
+fn note_drop_reason(reasons: &mut HashMap<usize, String>, idx: usize, reason: impl Into<String>) {
+    reasons.entry(idx).or_insert_with(|| reason.into());
+}
+
+/// 署名付きフレームの署名が不正なら監査イベントを返す
+fn signature_failure(msg: &protocol::Message, src: usize) -> Option<AuditEvent> {
+    let (sig, pk) = (msg.signature.as_ref()?, msg.public_key.as_ref()?);
+    if verify_signed_message(msg, sig, pk) {
+        return None;
+    }
+    let detail = format!("kind={} ts={}", msg.kind, msg.timestamp);
+    Some(audit_event(AuditKind::BadSignature, src, Some(pk), detail))
+}
+
+/// 署名不正をこれだけ繰り返したピアは切断する（監査ログにもここまでしか残さない）
+const BAD_SIGNATURE_LIMIT: u32 = 5;
+
+/// src の署名不正を 1 回数え、監査ログに残す分なら true を返す
+fn count_bad_signature<C>(peers: &mut [Peer<C>], src: usize) -> bool {
+    let Some(p) = peers.get_mut(src) else {
+        return false;
+    };
+    p.bad_signatures = p.bad_signatures.saturating_add(1);
+    p.bad_signatures <= BAD_SIGNATURE_LIMIT
+}
+
+fn audit(event: AuditEvent) {
+    let _ = storage::append_audit(&event);
+}
+
+fn relay_probability_percent(attenuation: u8) -> u8 {
+    if attenuation <= FULL_RELAY_ATTENUATION {
+        return 100;
+    }
+    if attenuation >= protocol::MAX_ATTENUATION {
+        return 0;
+    }
+
+    let remaining = (protocol::MAX_ATTENUATION - attenuation) as u16;
+    let window = (protocol::MAX_ATTENUATION - FULL_RELAY_ATTENUATION) as u16;
+    ((remaining * 100) / window) as u8
+}
+
+fn relay_bucket(msg: &protocol::Message, src: usize, dst: usize) -> u8 {
+    let mut seed = msg.timestamp
+        ^ ((msg.kind as u64) << 56)
+        ^ ((msg.attenuation as u64) << 48)
+        ^ ((msg.payload.len() as u64) << 16)
+        ^ ((src as u64) << 8)
+        ^ (dst as u64);
+    if let Some(pk) = msg.public_key.as_ref() {
+        for (i, b) in pk.iter().take(8).enumerate() {
+            seed ^= (*b as u64) << (i * 8);
+        }
+    }
+    seed ^= seed << 13;
+    seed ^= seed >> 7;
+    seed ^= seed << 17;
+    (seed % 100) as u8
+}
+
+fn should_relay_to_peer(msg: &protocol::Message, src: usize, dst: usize) -> bool {
+    let chance = relay_probability_percent(msg.attenuation);
+    chance > 0 && relay_bucket(msg, src, dst) < chance
+}
+
+fn message_identity(msg: &protocol::Message) -> [u8; 32] {
+    let pk_len = msg.public_key.as_ref().map_or(0, |pk| pk.len());
+    let sig_len = msg.signature.as_ref().map_or(0, |sig| sig.len());
+    let mut v = Vec::with_capacity(18 + msg.payload.len() + pk_len + sig_len);
+    v.push(msg.version);
+    v.push(msg.kind);
+    v.extend_from_slice(&msg.timestamp.to_be_bytes());
+    v.extend_from_slice(&(msg.payload.len() as u32).to_be_bytes());
+    v.extend_from_slice(&msg.payload);
+    if let Some(pk) = msg.public_key.as_ref() {
+        v.extend_from_slice(&(pk.len() as u32).to_be_bytes());
+        v.extend_from_slice(pk);
+    } else {
+        v.extend_from_slice(&0u32.to_be_bytes());
+    }
+    if let Some(sig) = msg.signature.as_ref() {
+        v.extend_from_slice(&(sig.len() as u32).to_be_bytes());
+        v.extend_from_slice(sig);
+    } else {
+        v.extend_from_slice(&0u32.to_be_bytes());
+    }
+    let digest = ring::digest::digest(&ring::digest::SHA256, &v);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(digest.as_ref());
+    out
+}
+
+fn is_duplicate_message(
+    msg: &protocol::Message,
+    seen_messages: &mut HashSet<[u8; 32]>,
+    seen_order: &mut VecDeque<[u8; 32]>,
+) -> bool {
+    let id = message_identity(msg);
+    if seen_messages.contains(&id) {
+        return true;
+    }
+    seen_messages.insert(id);
+    seen_order.push_back(id);
+    if seen_order.len() > SEEN_MESSAGE_CACHE_CAPACITY
+        && let Some(old) = seen_order.pop_front()
+    {
+        seen_messages.remove(&old);
+    }
+    false
+}
+
+#[derive(Clone, Debug)]
+struct PeerMeta {
+    public_key: Vec<u8>,
+    last_valid: bool,
+    last_timestamp: u64,
+    handle: Option<String>,
+    /// HELLO で相手が名乗ったプロトコルバージョン
+    protocol_version: Option<u8>,
+    /// HELLO に付いていたひとこと（署名済みだが内容は自己申告）
+    bio: Option<String>,
+    /// 相手本人の署名付きフレームで次に届くはずの通し番号（まだ見ていなければ None）
+    next_seq: Option<u64>,
+    /// HELLO 前に署名付きの投稿が届いたときは Handshaking のまま
+    state: rpc::PeerState,
+    /// 今のハンドルを受け付けた時刻 (ミリ秒)
+    handle_since: u64,
+    /// 返事を待っている PING の (番号, 送った時刻)
+    ping: Option<(u64, u64)>,
+    /// 最後に PONG が返ってくるまでの往復時間 (ミリ秒)
+    rtt_ms: Option<u64>,
+}
+
+impl PeerMeta {
+    /// PING を送る。前の PING の返事はもう待たない
+    fn start_ping(&mut self, id: u64, now: u64) -> Vec<u8> {
+        self.ping = Some((id, now));
+        protocol::encode(&protocol::Message::ping(now, id))
+    }
+
+    /// 待っていた PING の返事なら往復時間を記録する
+    fn finish_ping(&mut self, id: u64, now: u64) {
+        if let Some((_, sent)) = self.ping.take_if(|(want, _)| *want == id) {
+            self.rtt_ms = Some(now.saturating_sub(sent));
+        }
+    }
+}
+
+/// handle_change_min_secs 未指定時の、同じ接続でハンドルを変えられる間隔
+const DEFAULT_HANDLE_CHANGE_MIN_SECS: u64 = 60;
+
+/// 同じ接続から届いた HELLO のハンドル変更が前の変更から min_ms 経っていなければ、
+/// 使い続ける前のハンドルを返す。最初の HELLO とハンドルを変えない HELLO は None
+fn rate_limited_handle<'a>(
+    prev: Option<&'a PeerMeta>,
+    handle: &str,
+    now: u64,
+    min_ms: u64,
+) -> Option<&'a str> {
+    let prev = prev?;
+    let old = prev.handle.as_deref()?;
+    (old != handle && now.saturating_sub(prev.handle_since) < min_ms).then_some(old)
+}
+
+/// 同じ鍵の相手に歓迎メッセージを送り直すまでの間隔
+const WELCOME_REPEAT_MS: u64 = 60 * 60 * 1000;
+/// 歓迎メッセージを送る数の上限（WELCOME_WINDOW_MS の間に）。つなぎ直しを繰り返されても送り続けない
+const WELCOME_BURST: usize = 10;
+const WELCOME_WINDOW_MS: u64 = 60_000;
+
+/// welcome_message。受け入れた相手が HELLO を終えたら DM で送る（空なら送らない）
+#[derive(Default)]
+struct Welcome {
+    text: String,
+    /// 公開鍵 → 最後に送った時刻
+    sent_to: HashMap<Vec<u8>, u64>,
+    /// WELCOME_WINDOW_MS の間に送った時刻（古い順）
+    recent: VecDeque<u64>,
+}
+
+impl Welcome {
+    fn new(text: String) -> Self {
+        Self {
+            text,
+            ..Self::default()
+        }
+    }
+
+    /// この相手に今送ってよいか。よければ送ったものとして数える
+    fn take(&mut self, public_key: &[u8], now: u64) -> bool {
+        if self.text.is_empty() {
+            return false;
+        }
+        if self
+            .sent_to
+            .get(public_key)
+            .is_some_and(|&t| now.saturating_sub(t) < WELCOME_REPEAT_MS)
+        {
+            return false;
+        }
+        while self
+            .recent
+            .front()
+            .is_some_and(|&t| now.saturating_sub(t) >= WELCOME_WINDOW_MS)
+        {
+            self.recent.pop_front();
+        }
+        if self.recent.len() >= WELCOME_BURST {
+            return false;
+        }
+        self.recent.push_back(now);
+        self.sent_to.insert(public_key.to_vec(), now);
+        true
+    }
+}
+
+/// パズル待ちなら Connecting、メタが無いピアは HELLO 前
+fn peer_state(meta: Option<&PeerMeta>, puzzle_pending: bool) -> rpc::PeerState {
+    match meta {
+        _ if puzzle_pending => rpc::PeerState::Connecting,
+        Some(m) => m.state,
+        None => rpc::PeerState::Handshaking,
+    }
+}
+
+/// HELLO を確かめ終え、DM や中継を送ってよい相手か
+fn is_ready<C>(peers: &[Peer<C>], idx: usize) -> bool {
+    peers
+        .get(idx)
+        .filter(|p| p.solving.is_none())
+        .and_then(|p| p.meta.as_ref())
+        .is_some_and(|m| m.state == rpc::PeerState::Ready)
+}
+
+/// HELLO を確かめ終えたピアの数
+fn ready_peers<C>(peers: &[Peer<C>]) -> usize {
+    (0..peers.len()).filter(|&i| is_ready(peers, i)).count()
+}
+
+/// 最後に見た時刻を保存し直すまでの間隔（同じ相手から続けて届いても毎回は書かない）
+const LAST_SEEN_WRITE_INTERVAL_MS: u64 = 60_000;
+
+/// /known 用に、HELLO 済みの相手を最後に見た時刻を間引きながら保存する
+#[derive(Default)]
+struct LastSeen {
+    /// 指紋 → 最後に保存した時刻
+    written: HashMap<String, u64>,
+}
+
+impl LastSeen {
+    /// 保存すべきなら true を返して時刻を覚える
+    fn due(&mut self, fingerprint: &str, now: u64) -> bool {
+        match self.written.get(fingerprint) {
+            Some(&at) if now.saturating_sub(at) < LAST_SEEN_WRITE_INTERVAL_MS => false,
+            _ => {
+                self.written.insert(fingerprint.to_string(), now);
+                true
+            }
+        }
+    }
+
+    fn record(&mut self, meta: &PeerMeta, now: u64, force: bool) {
+        let fp = &crypto::fingerprint_hex(&meta.public_key)[..16];
+        if self.due(fp, now) || force {
+            let _ = storage::touch_known(fp, now, meta.handle.as_deref());
+        }
+    }
+}
+
+/// 投稿の鍵が /trust で検証済みの指紋か
+fn is_verified_key(verified: &HashSet<String>, public_key: Option<&[u8]>) -> bool {
+    public_key.is_some_and(|pk| verified.contains(&crypto::fingerprint_hex(pk)[..16]))
+}
+
+/// 同じハンドルを名乗るピアが複数いるときの優先順（大きいほど信用する）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum HandleTrust {
+    /// 最後に届いた署名が通らなかった
+    Unverified,
+    /// 署名は通っている
+    SignatureValid,
+    /// 署名が通り、指紋を /trust で検証済み
+    Verified,
+}
+
+fn handle_trust(meta: &PeerMeta, verified: &HashSet<String>) -> HandleTrust {
+    if !meta.last_valid {
+        HandleTrust::Unverified
+    } else if is_verified_key(verified, Some(&meta.public_key)) {
+        HandleTrust::Verified
+    } else {
+        HandleTrust::SignatureValid
+    }
+}
+
+/// @handle で指した相手。同じハンドルのピアのうち最も信用できる 1 人を選び、
+/// 残りは impostors として返す。最上位が並んだら決められないので Err（候補の id を添える）
+#[derive(Debug, PartialEq, Eq)]
+struct HandleMatch {
+    target: usize,
+    impostors: Vec<usize>,
+}
+
+fn resolve_handle<C>(
+    peers: &[Peer<C>],
+    verified: &HashSet<String>,
+    handle: &str,
+) -> Result<HandleMatch, String> {
+    let mut found: Vec<(HandleTrust, usize)> = peers
+        .iter()
+        .enumerate()
+        .filter_map(|(i, p)| {
+            let m = p.meta.as_ref()?;
+            (m.state == rpc::PeerState::Ready && m.handle.as_deref() == Some(handle))
+                .then(|| (handle_trust(m, verified), i))
+        })
+        .collect();
+    // 信用の高い順、同じなら id 順
+    found.sort_by_key(|&(trust, i)| (std::cmp::Reverse(trust), i));
+    match found.as_slice() {
+        [] => Err(format!("DM 宛先 {} のピアが見つかりません", handle)),
+        [(top, _), (next, _), ..] if top == next => {
+            let ids: Vec<String> = found
+                .iter()
+                .filter(|(t, _)| t == top)
+                .map(|(_, i)| i.to_string())
+                .collect();
+            Err(format!(
+                "DM 宛先 {} を名乗るピアが複数います (id={})。id で指定してください",
+                handle,
+                ids.join(",")
+            ))
+        }
+        [(_, target), rest @ ..] => Ok(HandleMatch {
+            target: *target,
+            impostors: rest.iter().map(|&(_, i)| i).collect(),
+        }),
+    }
+}
+
+/// ハンドルを名乗っているのに @handle では選ばれないピア（成りすましの疑い）か
+fn is_handle_suspect<C>(peers: &[Peer<C>], verified: &HashSet<String>, idx: usize) -> bool {
+    let Some(handle) = peers[idx].meta.as_ref().and_then(|m| m.handle.as_deref()) else {
+        return false;
+    };
+    match resolve_handle(peers, verified, handle) {
+        Ok(m) => m.impostors.contains(&idx),
+        // 最上位が並んでいれば、どちらも本人とは決められない
+        Err(_) => is_ready(peers, idx),
+    }
+}
+
+/// 検証済みの相手と同じハンドルが別の鍵で現れたら警告のイベントを作る
+fn verified_key_change_event(id: usize, handle: &str, fingerprint: &str) -> Option<rpc::Event> {
+    let prev = storage::verified_key_change(handle, fingerprint)?;
+    Some(rpc::Event::VerifiedKeyChanged {
+        id,
+        handle: handle.to_string(),
+        verified: prev.fingerprint,
+        got: fingerprint.to_string(),
+    })
+}
+
+/// デコーダが不正なフレームを検出したピアに切断通知（理由ID=7）を送り、
+/// どのピアか分かるようハンドル・指紋付きで表示と監査ログに残す。
+/// 以降そのデコーダは同じバイト列で失敗し続けるので、呼び出し側で必ず削除する
+async fn drop_malformed_peer<C: Connection>(
+    src: usize,
+    err: &protocol::ProtocolError,
+    peer: &mut Peer<C>,
+    tx_main: &Sender<rpc::Event>,
+) {
+    metrics::add(&METRICS.dropped_frames, 1);
+    let disc = protocol::Message::disconnect(current_unix_millis(), 7);
+    let _ = peer.send(&protocol::encode(&disc));
+    let meta = peer.meta.as_ref();
+    let public_key = meta.map(|m| m.public_key.as_slice());
+    let detail = format!("reason=7 ({}) {}", disconnect_reason_text(7), err);
+    audit(audit_event(AuditKind::Disconnect, src, public_key, detail));
+    let who = match (meta.and_then(|m| m.handle.as_deref()), public_key) {
+        (Some(h), _) => format!(" {}", h),
+        (None, Some(pk)) => format!(" 指紋={}", &crypto::fingerprint_hex(pk)[..16]),
+        (None, None) => String::new(),
+    };
+    tx_main
+        .send(rpc::Event::Message(format!(
+            "プロトコルエラー: id={}{} {} のため切断",
+            src, who, err
+        )))
+        .await
+        .ok();
+}
+
+/// 無通信チェックの間隔の上限
+const IDLE_CHECK_INTERVAL_MS: u64 = 1000;
+/// 無通信タイムアウトの間に送る PING の回数。何回か落としても切られないようにする
+const PINGS_PER_IDLE_TIMEOUT: u64 = 3;
+
+/// 起動時に config から読むタイマー類 (ミリ秒、0 は無効)。/timers で表示する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Timers {
+    /// keepalive の送信間隔 (ping_interval_secs、0 なら無通信タイムアウトの 1/3)
+    ping_interval_ms: u64,
+    /// 無通信で切断するまでの時間 (idle_timeout_secs)
+    idle_timeout_ms: u64,
+}
+
+impl Timers {
+    fn from_config(tbl: &toml::Table) -> Self {
+        let get = |key: &str| {
+            config::get_value_in(tbl, key)
+                .and_then(|v| v.as_integer())
+                .and_then(|n| u64::try_from(n).ok())
+        };
+        let d = Self::default();
+        Self {
+            ping_interval_ms: get(config::keys::PING_INTERVAL_SECS)
+                .map_or(d.ping_interval_ms, |s| s.saturating_mul(1000)),
+            idle_timeout_ms: get(config::keys::IDLE_TIMEOUT_SECS)
+                .map_or(d.idle_timeout_ms, |s| s.saturating_mul(1000)),
+        }
+    }
+
+    /// 実際に PING を送る間隔（指定が無ければ無通信タイムアウトの間に数回）
+    fn ping_every_ms(&self) -> u64 {
+        if self.ping_interval_ms > 0 {
+            self.ping_interval_ms
+        } else {
+            self.idle_timeout_ms / PINGS_PER_IDLE_TIMEOUT
+        }
+    }
+
+    fn describe(&self) -> String {
+        let ms = |v: u64| {
+            if v == 0 {
+                "無効".to_string()
+            } else {
+                format!("{}ms", v)
+            }
+        };
+        [
+            "タイマー:".to_string(),
+            format!("ping_interval={}", ms(self.ping_every_ms())),
+            format!("idle_timeout={}", ms(self.idle_timeout_ms)),
+        ]
+        .join("\n")
+    }
+}
+
+/// メインループから定期的に行う処理
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimerKind {
+    /// 無通信タイムアウトの確認
+    IdleCheck,
+    /// 静かなピアにも PING を送り、無通信タイムアウトで切らないようにする
+    Ping,
+}
+
+/// 周期タイマー。ループの各周回で due() を呼び、期限の来たものだけを返す。
+/// 待ち合わせはしないのでループは止まらない
+#[derive(Default)]
+struct Scheduler {
+    // (種類, 間隔, 次の期限) いずれも UNIX millis
+    entries: Vec<(TimerKind, u64, u64)>,
+}
+
+impl Scheduler {
+    /// interval_ms ごとに kind を発火させる。0 なら登録しない
+    fn every(&mut self, kind: TimerKind, interval_ms: u64, now: u64) {
+        if interval_ms > 0 {
+            self.entries.push((kind, interval_ms, now + interval_ms));
+        }
+    }
+
+    fn due(&mut self, now: u64) -> Vec<TimerKind> {
+        let mut fired = Vec::new();
+        for (kind, interval, next) in self.entries.iter_mut() {
+            if now >= *next {
+                fired.push(*kind);
+                *next = now + *interval;
+            }
+        }
+        fired
+    }
+}
+
+/// PING を送れる相手か。v1 のノードは PING を知らず切断してくるので送らない
+fn can_ping<C>(peers: &[Peer<C>], idx: usize) -> bool {
+    is_ready(peers, idx) && peer_version(peers, idx) >= 2
+}
+
+/// 無通信タイムアウトで切ってよい相手か。HELLO を終えた v1 のピアは PING で
+/// 生きているか確かめられないので、黙っているだけでは切らない
+fn may_time_out<C>(peers: &[Peer<C>], idx: usize) -> bool {
+    !is_ready(peers, idx) || can_ping(peers, idx)
+}
+
+/// 最後の受信から timeout_ms を超えたピア。timeout_ms=0 なら無効
+fn idle_peers<C>(peers: &[Peer<C>], now: u64, timeout_ms: u64) -> Vec<usize> {
+    if timeout_ms == 0 {
+        return Vec::new();
+    }
+    peers
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| now.saturating_sub(p.last_activity) > timeout_ms)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// 同じ公開鍵で既に HELLO を済ませている（切断予定でない）別のピアを探す。
+/// HELLO 前のメタは中継されてきた署名から作られたこともあるので見ない
+fn find_duplicate_identity<C>(
+    peers: &[Peer<C>],
+    src: usize,
+    public_key: &[u8],
+    removing: &[usize],
+) -> Option<usize> {
+    peers.iter().enumerate().find_map(|(i, p)| {
+        let m = p.meta.as_ref()?;
+        (i != src
+            && !removing.contains(&i)
+            && m.state == rpc::PeerState::Ready
+            && m.public_key == public_key)
+            .then_some(i)
+    })
+}
+
+/// 中継するフレーム（減衰値を1つ進めたもの）。中継OFF (leaf) なら None
+fn relayed_frame(msg: &protocol::Message, relay_enabled: bool) -> Option<protocol::Message> {
+    if !relay_enabled
+        || protocol::is_dm_kind(msg.kind)
+        || msg.attenuation >= protocol::MAX_ATTENUATION
+    {
+        return None;
+    }
+    let mut fwd = msg.clone();
+    fwd.attenuate();
+    Some(fwd)
+}
+
+/// 中継で送るバイト列。受け取ったフレームのバイト列に中継1回分だけを施して、
+/// 本文と署名は届いたままにする。版を下げる相手には frame_for_peer で作り直す
+fn relay_bytes(fwd: &protocol::Message, received: &[u8], peer_version: u8) -> Option<Vec<u8>> {
+    if fwd.version > peer_version {
+        return frame_for_peer(fwd, peer_version, None);
+    }
+    let mut frame = received.to_vec();
+    protocol::attenuate_frame(&mut frame).then_some(frame)
+}
+
+/// 接続パズルの解答を待つ時間
+const PUZZLE_TIMEOUT_MS: u64 = 10_000;
+/// 解答前に届いたフレームを保留しておく上限
+const MAX_PUZZLE_HELD_FRAMES: usize = 32;
+/// これより難しい出題は解くのに時間がかかりすぎるので出さず、答えもしない
+const MAX_PUZZLE_DIFFICULTY: u8 = 24;
+
+/// 待受側が出した接続パズル。解答が届くまで相手の HELLO などを保留する
+struct Puzzle {
+    challenge: [u8; protocol::PUZZLE_CHALLENGE_LEN],
+    difficulty: u8,
+    deadline: u64,
+    held: Vec<protocol::Frame>,
+}
+
+enum PuzzleStep {
+    /// 解答前なので保留した
+    Held,
+    /// 解けた。保留していたフレームを届いた順に返す
+    Solved(Vec<protocol::Frame>),
+    /// 解答が違う、または保留が多すぎる
+    Failed,
+}
+
+impl Puzzle {
+    fn new(difficulty: u8, now: u64) -> Option<Self> {
+        let challenge = crypto::random_bytes(protocol::PUZZLE_CHALLENGE_LEN).ok()?;
+        Some(Self {
+            challenge: challenge.try_into().ok()?,
+            difficulty,
+            deadline: now + PUZZLE_TIMEOUT_MS,
+            held: Vec::new(),
+        })
+    }
+
+    fn message(&self) -> protocol::Message {
+        protocol::Message::challenge(current_unix_millis(), self.difficulty, &self.challenge)
+    }
+
+    fn check(&mut self, frame: protocol::Frame) -> PuzzleStep {
+        if frame.msg.kind == protocol::MsgKind::SOLUTION {
+            return match protocol::solution_nonce(&frame.msg) {
+                Some(n) if crypto::puzzle_ok(&self.challenge, n, self.difficulty) => {
+                    PuzzleStep::Solved(std::mem::take(&mut self.held))
+                }
+                _ => PuzzleStep::Failed,
+            };
+        }
+        if self.held.len() >= MAX_PUZZLE_HELD_FRAMES {
+            return PuzzleStep::Failed;
+        }
+        self.held.push(frame);
+        PuzzleStep::Held
+    }
+
+    fn expired(&self, now: u64) -> bool {
+        now >= self.deadline
+    }
+}
+
+/// 自分から接続した相手に出された接続パズルを、別スレッドで解いている間の状態。
+/// 解答を送るまで相手は Ready にせず、届いたフレームも保留する
+struct Solving {
+    ticket: u64,
+    difficulty: u8,
+    held: Vec<protocol::Frame>,
+}
+
+/// 解き終わった接続パズル。ticket で出題したピアを探す（その間に id がずれても取り違えない）
+struct SolvedPuzzle {
+    ticket: u64,
+    nonce: u64,
+}
+
+/// 一時的な書き込み失敗を何回まで再送するか（ループ1周につき1回）
+const MAX_SEND_RETRIES: u32 = 5;
+
+/// 再送しても無駄な書き込みエラー（相手が切断済み）
+fn is_fatal_write_error(kind: std::io::ErrorKind) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(
+        kind,
+        BrokenPipe | ConnectionReset | ConnectionAborted | NotConnected | WriteZero
+    )
+}
+
+/// 送信キューを流した結果
+#[derive(Debug, PartialEq, Eq)]
+enum Flush {
+    /// 全部書けた（キューは空）
+    Done,
+    /// 一時的な失敗。次のループでもう一度試す
+    Pending,
+    /// 切断すべき（致命的なエラーか再送回数の上限）
+    Drop(std::io::ErrorKind),
+}
+
+/// ピアごとの送信待ちフレーム。一時的な書き込み失敗で書けなかった残りを順番どおりに持つ
+#[derive(Debug, Default)]
+struct SendQueue {
+    frames: VecDeque<Vec<u8>>,
+    /// 先頭フレームのうち書き込み済みのバイト数
+    offset: usize,
+    /// 連続して失敗した回数
+    failures: u32,
+    /// relay_fanout_per_tick の上限で次のティックへ回した中継フレーム（届いた順）
+    relay_backlog: VecDeque<Vec<u8>>,
+}
+
+impl SendQueue {
+    fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// まだ書けていないバイト数（中継の順番待ちも含む）
+    fn queued_bytes(&self) -> usize {
+        self.frames.iter().map(Vec::len).sum::<usize>() - self.offset
+            + self.relay_backlog.iter().map(Vec::len).sum::<usize>()
+    }
+
+    /// フレームを送る。先に詰まっているものがあれば順番を守って後ろに並べる
+    fn send<C: Connection>(&mut self, w: &mut C, frame: &[u8]) -> Flush {
+        self.frames.push_back(frame.to_vec());
+        if self.frames.len() > 1 {
+            return Flush::Pending;
+        }
+        self.flush(w)
+    }
+
+    /// 詰まっているフレームを、待たずに書けるところまで書く。
+    /// 相手が読まずに送信バッファが一杯 (WouldBlock) なら失敗には数えず、次のループで続ける
+    fn flush<C: Connection>(&mut self, w: &mut C) -> Flush {
+        while let Some(frame) = self.frames.front() {
+            match w.try_write(&frame[self.offset..]) {
+                Ok(0) => return Flush::Drop(std::io::ErrorKind::WriteZero),
+                Ok(n) => {
+                    metrics::add(&METRICS.bytes_out, n as u64);
+                    self.offset += n;
+                    self.failures = 0;
+                    if self.offset == frame.len() {
+                        self.frames.pop_front();
+                        self.offset = 0;
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Flush::Pending,
+                Err(e) if is_fatal_write_error(e.kind()) => return Flush::Drop(e.kind()),
+                Err(e) => {
+                    self.failures += 1;
+                    if self.failures > MAX_SEND_RETRIES {
+                        return Flush::Drop(e.kind());
+                    }
+                    return Flush::Pending;
+                }
+            }
+        }
+        Flush::Done
+    }
+}
+
+/// 受信したフレームを送り元ごとにまとめる。安定ソートなので同じピアの中では届いた順のまま。
+/// 時刻の単調性や ACK の突き合わせは、ピアごとに届いた順で処理される前提で書く
+fn order_by_source<F>(frames: &mut [(usize, F)]) {
+    frames.sort_by_key(|(src, _)| *src);
+}
+
+/// 接続中のピア 1 つ分の状態。index が /peers の id になる
+struct Peer<C> {
+    conn: C,
+    decoder: protocol::Decoder,
+    /// HELLO で分かった相手（HELLO 前は None）
+    meta: Option<PeerMeta>,
+    /// 受信バイト数
+    bytes: u64,
+    /// 最終受信時刻 (UNIX millis)。HELLO 前のピアも対象にするため PeerMeta とは別に持つ
+    last_activity: u64,
+    /// 最後に受信した生バイト列（/dump 用）
+    last_raw: Vec<u8>,
+    /// 送信キュー（一時的な書き込み失敗の再送用）
+    queue: SendQueue,
+    /// 受け入れた待受のポート（自分から接続したなら None）
+    listener: Option<u16>,
+    /// 出して解答待ちの接続パズル（受け入れた側だけ）
+    puzzle: Option<Puzzle>,
+    /// 解いている途中の接続パズル（自分から接続した側だけ）
+    solving: Option<Solving>,
+    /// 届いた署名不正の数（BAD_SIGNATURE_LIMIT に達したら切断）
+    bad_signatures: u32,
+    /// 履歴同期（返事待ちの日と、返している途中の要求）
+    sync: PeerSync,
+}
+
+impl<C> Peer<C> {
+    fn new(conn: C, now: u64, listener: Option<u16>) -> Self {
+        Self {
+            conn,
+            decoder: protocol::Decoder::new(),
+            meta: None,
+            bytes: 0,
+            last_activity: now,
+            last_raw: Vec::new(),
+            queue: SendQueue::default(),
+            listener,
+            puzzle: None,
+            solving: None,
+            bad_signatures: 0,
+            sync: PeerSync::default(),
+        }
+    }
+}
+
+impl<C> Peer<C> {
+    /// どちらかの側の接続パズルが済んでいない
+    fn puzzle_pending(&self) -> bool {
+        self.puzzle.is_some() || self.solving.is_some()
+    }
+}
+
+impl<C: Connection> Peer<C> {
+    /// 送信キューを通して送る
+    fn send(&mut self, frame: &[u8]) -> Flush {
+        self.queue.send(&mut self.conn, frame)
+    }
+}
+
+/// ピアを一覧から外し、切断を知らせる。後ろのピアの id は 1 つずつ詰まる
+async fn remove_peer<C>(
+    peers: &mut Vec<Peer<C>>,
+    idx: usize,
+    reason: impl Into<String>,
+    tx_main: &Sender<rpc::Event>,
+) -> Peer<C> {
+    let peer = peers.remove(idx);
+    tx_main
+        .send(rpc::Event::PeerDisconnected {
+            id: idx,
+            reason: reason.into(),
+        })
+        .await
+        .ok();
+    peer
+}
+
+/// 送信キューを通して 1 つのピアへ送る。書けなくなっていれば知らせて切断に回し、false を返す
+async fn send_or_drop<C: Connection>(
+    peers: &mut [Peer<C>],
+    idx: usize,
+    frame: &[u8],
+    tx_main: &Sender<rpc::Event>,
+    remove_indices: &mut Vec<usize>,
+    drop_reasons: &mut HashMap<usize, String>,
+) -> bool {
+    let Flush::Drop(kind) = peers[idx].send(frame) else {
+        return true;
+    };
+    tx_main
+        .send(rpc::Event::Message(format!(
+            "送信エラー {}: {:?}",
+            idx, kind
+        )))
+        .await
+        .ok();
+    remove_indices.push(idx);
+    note_drop_reason(drop_reasons, idx, "送信エラー");
+    false
+}
+
+/// 送信待ちの合計が上限を超えていれば、上限に収まるまで詰まっているピアから順に選ぶ。
+/// 全員を待たせたり適当に切ったりせず、読まない相手だけを落とす。
+/// 送信は待たずに書ける分だけ書くので、読まない相手の分だけがキューに溜まっていく。
+/// このティックで既に切ると決めたピア (dropping) は数えない
+fn shed_backlogged<C>(peers: &[Peer<C>], limit: usize, dropping: &[usize]) -> Vec<usize> {
+    let mut depths: Vec<(usize, usize)> = peers
+        .iter()
+        .map(|p| p.queue.queued_bytes())
+        .enumerate()
+        .filter(|&(idx, n)| n > 0 && !dropping.contains(&idx))
+        .collect();
+    let mut total: usize = depths.iter().map(|&(_, n)| n).sum();
+    // 多い順（同じなら番号の小さい順）
+    depths.sort_by_key(|&(idx, n)| (std::cmp::Reverse(n), idx));
+    let mut shed = Vec::new();
+    for (idx, n) in depths {
+        if total <= limit {
+            break;
+        }
+        total -= n;
+        shed.push(idx);
+    }
+    shed
+}
+
+/// 1 ティックに中継で書き込む回数の上限（relay_fanout_per_tick。None は無制限）。
+/// 大きな配信で CPU や帯域が一度に跳ねないよう、超えた宛先は次のティック以降に回す
+#[derive(Debug, Default)]
+struct Fanout {
+    limit: Option<usize>,
+    used: usize,
+}
+
+impl Fanout {
+    fn new(limit: Option<usize>) -> Self {
+        Self { limit, used: 0 }
+    }
+
+    fn start_tick(&mut self) {
+        self.used = 0;
+    }
+
+    /// このティックでもう 1 回中継してよいか（よければ 1 回分使う）
+    fn try_take(&mut self) -> bool {
+        if self.limit.is_some_and(|l| self.used >= l) {
+            return false;
+        }
+        self.used += 1;
+        true
+    }
+}
+
+/// 順番待ちの中継を、ティックの上限までピアを 1 つずつ順に回して送る。
+/// 切断すべきピアの index と理由を返す
+async fn release_relay_backlog<C: Connection>(
+    peers: &mut [Peer<C>],
+    fanout: &mut Fanout,
+) -> Vec<(usize, std::io::ErrorKind)> {
+    let mut dropped: Vec<(usize, std::io::ErrorKind)> = Vec::new();
+    loop {
+        let mut progressed = false;
+        for (idx, p) in peers.iter_mut().enumerate() {
+            if p.queue.relay_backlog.is_empty() || dropped.iter().any(|(i, _)| *i == idx) {
+                continue;
+            }
+            if !fanout.try_take() {
+                return dropped;
+            }
+            let Some(frame) = p.queue.relay_backlog.pop_front() else {
+                continue;
+            };
+            if let Flush::Drop(kind) = p.send(&frame) {
+                dropped.push((idx, kind));
+            }
+            progressed = true;
+        }
+        if !progressed {
+            return dropped;
+        }
+    }
+}
+
+/// DM は減衰せず、宛先に届いたら即中継終了。
+/// それ以外は減衰値を中継時にカウントアップし、最大値50で打ち止め。
+/// 送るのは届いたバイト列 (frame.raw) で、符号化し直さない
+#[allow(clippy::too_many_arguments)]
+async fn relay<C: Connection>(
+    frame: &protocol::Frame,
+    src: usize,
+    relay_enabled: bool,
+    peers: &mut [Peer<C>],
+    fanout: &mut Fanout,
+    tx_main: &Sender<rpc::Event>,
+    remove_indices: &mut Vec<usize>,
+) {
+    let Some(fwd) = relayed_frame(&frame.msg, relay_enabled) else {
+        return;
+    };
+    let mut relayed = false;
+    for idx in 0..peers.len() {
+        // HELLO を終えていない相手には流さない
+        if idx == src || !is_ready(peers, idx) {
+            continue;
+        }
+        if !should_relay_to_peer(&fwd, src, idx) {
+            continue;
+        }
+        // 他人の v2 署名は v1 のピアへは流せない
+        let Some(bytes) = relay_bytes(&fwd, &frame.raw, peer_version(peers, idx)) else {
+            continue;
+        };
+        relayed = true;
+        // 上限に達したか、先に順番待ちがあれば後ろに並べる（ピアごとの順番を守る）
+        let p = &mut peers[idx];
+        if !p.queue.relay_backlog.is_empty() || !fanout.try_take() {
+            p.queue.relay_backlog.push_back(bytes);
+            continue;
+        }
+
+        if let Flush::Drop(kind) = p.send(&bytes) {
+            tx_main
+                .send(rpc::Event::Message(format!(
+                    "Relay write error to {}: {:?}",
+                    idx, kind
+                )))
+                .await
+                .ok();
+            remove_indices.push(idx);
+        }
+    }
+    if relayed {
+        metrics::add(&METRICS.messages_relayed, 1);
+    }
+}
+
+pub async fn network_handler(tx_main: Sender<rpc::Event>, rx_thread: Receiver<rpc::Command>) {
+    network_handler_with_clock(tx_main, rx_thread, Arc::new(SystemClock)).await
+}
+
+/// network_handler と同じ。無通信タイムアウトなどの時刻判定に clock を使う（テストで時間を進める用）
+pub async fn network_handler_with_clock(
+    tx_main: Sender<rpc::Event>,
+    rx_thread: Receiver<rpc::Command>,
+    clock: Arc<dyn Clock>,
+) {
+    // record_frames を設定していれば受信したバイト列を記録する（--replay で再生できる）
+    let record =
+        config::get_value(config::keys::RECORD_FRAMES).and_then(|v| v.as_str().map(str::to_string));
+    if let Some(path) = record.filter(|p| !p.is_empty()) {
+        match crate::replay::Recording::create(transport::Tcp, std::path::Path::new(&path)) {
+            Ok(recording) => {
+                let msg = format!("受信したバイト列を {} に記録します", path);
+                tx_main.send(rpc::Event::Message(msg)).await.ok();
+                return network_handler_with_transport(tx_main, rx_thread, clock, recording).await;
+            }
+            Err(e) => {
+                let msg = format!(
+                    "記録ファイル {} を開けません（記録せずに続けます）: {}",
+                    path, e
+                );
+                tx_main.send(rpc::Event::Message(msg)).await.ok();
+            }
+        }
+    }
+    network_handler_with_transport(tx_main, rx_thread, clock, transport::Tcp).await
+}
+
+/// 待受と接続に transport を使う（テストではプロセス内の transport::Memory をつなぐ）
+pub async fn network_handler_with_transport<T: Transport>(
+    tx_main: Sender<rpc::Event>,
+    mut rx_thread: Receiver<rpc::Command>,
+    clock: Arc<dyn Clock>,
+    transport: T,
+) {
+    tx_main
+        .send(rpc::Event::Message("ネットワークスレッド開始".to_string()))
+        .await
+        .ok();
+    // 待受（ポートごと）
+    let mut listeners: BTreeMap<u16, Listener<T::Listener>> = BTreeMap::new();
+    // 接続中のピア
+    let mut peers: Vec<Peer<T::Conn>> = Vec::new();
+    // タイマー類（無通信タイムアウトなど、0 なら無効）
+    let timers = config::try_config()
+        .map(|tbl| Timers::from_config(&tbl))
+        .unwrap_or_default();
+    let mut scheduler = Scheduler::default();
+    scheduler.every(
+        TimerKind::IdleCheck,
+        timers.idle_timeout_ms.min(IDLE_CHECK_INTERVAL_MS),
+        clock.now_millis(),
+    );
+    scheduler.every(TimerKind::Ping, timers.ping_every_ms(), clock.now_millis());
+    // 送った PING の番号
+    let mut ping_seq: u64 = 0;
+    // 接続パズルは別スレッドで解き、解けたものをここで受け取る
+    let (tx_solved, mut rx_solved) = tokio::sync::mpsc::channel::<SolvedPuzzle>(16);
+    let mut puzzle_ticket: u64 = 0;
+    // 1 アドレスあたりの接続タイムアウト
+    let connect_timeout = Duration::from_secs(
+        config::get_value(config::keys::CONNECT_TIMEOUT_SECS)
+            .and_then(|v| v.as_integer())
+            .and_then(|n| u64::try_from(n).ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS),
+    );
+    let mut seen_messages: HashSet<[u8; 32]> = HashSet::new();
+    let mut last_seen = LastSeen::default();
+    let mut seen_order: VecDeque<[u8; 32]> = VecDeque::new();
+    let mut authors = AuthorCache::default();
+    let mut send_seq = SendSeq::default();
+    let mut ledger = IdLedger::default();
+    let mut outbox = Outbox::default();
+    let mut topic = RoomTopic::default();
+    // 最初のピアと HELLO を交わしたら一度だけ参加のお知らせを流す
+    let mut announced_join = false;
+    let mut directory = Directory::default();
+    // /trust で検証済みにした指紋（その鍵の投稿に印を付ける）
+    let mut verified: HashSet<String> = storage::verified_peers()
+        .into_iter()
+        .map(|p| p.fingerprint)
+        .collect();
+    // このセッションで送る DM のノンス（12B すべて乱数。使った回数を数え、
+    // MAX_MESSAGES_PER_KEY に達したらそれ以上暗号化しない）
+    let mut dm_nonces = crypto::NonceSequence::new();
+    // 自分の待受アドレスを接続先に広告し、その先のピアへ紹介してもらうか（既定は無効）
+    let advertise = config::get_value(config::keys::ADVERTISE)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    // /topology の問い合わせに隣接ピアの指紋を答えるか。つながりを明かすので既定は無効
+    let share_topology = config::get_value(config::keys::SHARE_TOPOLOGY)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    let mut topology: Option<TopologyView> = None;
+    // /whois で接続元アドレスを逆引きするか。DNS に問い合わせが出るので既定は無効
+    let peer_lookup = config::get_value(config::keys::PEER_LOOKUP)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    // /whois で AS を引く ip2asn 形式の表（未設定なら引かない）
+    let asn_db =
+        config::get_value(config::keys::ASN_DB).and_then(|v| v.as_str().map(|s| s.to_string()));
+    // NAT-PMP で外からの接続を受けるか。対応していないルーターも多いので既定は無効
+    let port_mapping = config::get_value(config::keys::PORT_MAPPING)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    // 1 回の読み込みの大きさ。大きなメッセージが多いなら 65536 などに増やすと読む回数が減る
+    let read_buffer = config::get_value(config::keys::READ_BUFFER_BYTES)
+        .and_then(|v| v.as_integer())
+        .and_then(|n| usize::try_from(n).ok())
+        .map(|n| {
+            n.clamp(
+                *READ_BUFFER_BYTES_RANGE.start(),
+                *READ_BUFFER_BYTES_RANGE.end(),
+            )
+        })
+        .unwrap_or(DEFAULT_READ_BUFFER_BYTES);
+    let mut buf = vec![0u8; read_buffer];
+    let mut burst = Vec::with_capacity(read_buffer);
+    // 受信した Chat を他のピアへ中継するか (false なら leaf ノード)
+    let mut relay_enabled = config::get_value(config::keys::RELAY)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    // 他人宛ての ROUTED_DM を復号せずに中継するか
+    let relay_dms = config::get_value(config::keys::RELAY_DMS)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    // 自分の鍵で署名された投稿が戻ってきても表示・保存しないか。
+    // 送信時のローカルエコーだけを出すので、自分自身や同じ鍵のノードとつないでも二重に出ない（既定は無効）
+    let suppress_own_echo = config::get_value(config::keys::SUPPRESS_OWN_ECHO)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    // 他人が付けた至急の印を目立たせ、通知するか（false なら普通の投稿と同じに扱う）
+    let honor_urgent = config::get_value(config::keys::HONOR_URGENT)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    // HELLO の後に日ごとの件数を比べ、相手にしかない投稿を取り寄せるか（既定は無効）
+    let history_sync = config::get_value(config::keys::HISTORY_SYNC)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    // 1 ティックに中継で書き込む回数の上限（0 か未設定なら無制限）。/fanout で変えられる
+    let mut fanout = Fanout::new(
+        config::get_value(config::keys::RELAY_FANOUT_PER_TICK)
+            .and_then(|v| v.as_integer())
+            .and_then(|n| usize::try_from(n).ok())
+            .filter(|&n| n > 0),
+    );
+    // 全ピア合計の送信待ちの上限。超えたらいちばん詰まっているピアから切断する
+    let send_buffer_limit = config::get_value(config::keys::SEND_BUFFER_LIMIT_BYTES)
+        .and_then(|v| v.as_integer())
+        .and_then(|n| usize::try_from(n).ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(DEFAULT_SEND_BUFFER_LIMIT_BYTES);
+    // 受け入れたピアに HELLO の前に解かせるパズルの難易度（先頭の 0 ビット数。0 なら無効）
+    let puzzle_difficulty = config::get_value(config::keys::CONNECT_PUZZLE_DIFFICULTY)
+        .and_then(|v| v.as_integer())
+        .and_then(|n| u8::try_from(n).ok())
+        .unwrap_or(0)
+        .min(MAX_PUZZLE_DIFFICULTY);
+    // ハンドル（必須）
+    let mut handle: String = config::get_value(config::keys::USER_HANDLE)
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .unwrap_or_default();
+    // HELLO に付けるひとこと（任意）
+    let bio = config::bio();
+    // 同じ接続で相手がハンドルを変えられる間隔（0 なら制限なし）。名前を次々に変えて紛らわすのを防ぐ
+    let handle_change_min_ms = config::get_value(config::keys::HANDLE_CHANGE_MIN_SECS)
+        .and_then(|v| v.as_integer())
+        .and_then(|n| u64::try_from(n).ok())
+        .unwrap_or(DEFAULT_HANDLE_CHANGE_MIN_SECS)
+        .saturating_mul(1000);
+    // 受け入れた相手に HELLO の後で DM する歓迎メッセージ（空なら送らない）
+    let mut welcome = Welcome::new(
+        config::get_value(config::keys::WELCOME_MESSAGE)
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_default(),
+    );
+
+    // 署名用鍵を読む (存在しなければ None)
+    let mut pkcs8: Option<Vec<u8>> = None;
+    let mut public: Option<Vec<u8>> = None;
+    // 起動時に読み込み ( /init 後は再起動で有効 )。将来ホットリロードするなら /reload 等追加。
+    if let (Some(pk_hex), Some(pub_hex)) = (
+        config::get_value(config::keys::KEY_PKCS8).and_then(|v| v.as_str().map(|s| s.to_string())),
+        config::get_value(config::keys::KEY_PUBLIC).and_then(|v| v.as_str().map(|s| s.to_string())),
+    ) {
+        let pk_bytes = crypto::from_hex(&pk_hex).unwrap_or_default();
+        let pub_bytes = crypto::from_hex(&pub_hex).unwrap_or_default();
+        if !pk_bytes.is_empty() && !pub_bytes.is_empty() {
+            pkcs8 = Some(pk_bytes);
+            public = Some(pub_bytes);
+        }
+    }
+
+    'main_loop: loop {
+        METRICS
+            .active_peers
+            .store(peers.len() as u64, Ordering::Relaxed);
+        // このティックで切断するピア。id がずれないよう最後にまとめて外す
+        let mut remove_indices: Vec<usize> = Vec::new();
+        let mut drop_reasons: HashMap<usize, String> = HashMap::new();
+        // コマンド処理: drain できるだけ読む
+        while let Ok(cmd) = rx_thread.try_recv() {
+            match cmd {
+                rpc::Command::Open(port, save) => {
+                    if let Some(p) = port
+                        .trim()
+                        .parse::<u16>()
+                        .ok()
+                        .filter(|p| listeners.contains_key(p))
+                    {
+                        tx_main
+                            .send(rpc::Event::Message(format!("既にポート {} で待受中", p)))
+                            .await
+                            .ok();
+                    } else {
+                        // 外から受けるときはループバック以外でも待ち受ける
+                        let host = if port_mapping { "0.0.0.0" } else { "127.0.0.1" };
+                        match transport.bind(&format!("{}:{}", host, port)).await {
+                            Ok(l) => {
+                                let port = l.local_addr().map(|a| a.port()).unwrap_or(0);
+                                let mut mapping = None;
+                                let mut addr = format!("127.0.0.1:{}", port);
+                                if port_mapping {
+                                    let gw = config::get_value(config::keys::NAT_GATEWAY)
+                                        .and_then(|v| v.as_str().map(|s| s.to_string()));
+                                    let res = match nat::default_gateway(gw.as_deref()) {
+                                        Some(gateway) => nat::map_port(gateway, port).await,
+                                        None => Err("ゲートウェイが見つかりません".into()),
+                                    };
+                                    let msg = match res {
+                                        Ok(m) => {
+                                            addr = m.external_addr();
+                                            mapping = Some(m);
+                                            format!(
+                                                "ポートマッピング成功: {} → {} (有効 {} 秒)",
+                                                addr, port, m.lifetime_secs
+                                            )
+                                        }
+                                        Err(e) => format!(
+                                            "ポートマッピング失敗: {} (LAN 内のアドレスで待受します)",
+                                            e
+                                        ),
+                                    };
+                                    tx_main.send(rpc::Event::Message(msg)).await.ok();
+                                }
+                                listeners.insert(port, Listener { socket: l, mapping });
+                                let tok = crypto::encrypt_conninfo_to_hex(&addr)
+                                    .unwrap_or_else(|_| "?".into());
+                                tx_main
+                                    .send(rpc::Event::Message(format!(
+                                        "待受開始 port={} (token={})",
+                                        port, tok
+                                    )))
+                                    .await
+                                    .ok();
+                                if let Some(path) = save {
+                                    let msg = match write_token_file(&path, &tok) {
+                                        Ok(()) => format!("トークンを {} に書き出しました", path),
+                                        Err(e) => format!("トークンの書き出しに失敗: {}", e),
+                                    };
+                                    tx_main.send(rpc::Event::Message(msg)).await.ok();
+                                }
+                            }
+                            Err(e) => {
+                                tx_main
+                                    .send(rpc::Event::Message(format!("バインドエラー: {:?}", e)))
+                                    .await
+                                    .ok();
+                            }
+                        }
+                    }
+                }
+                rpc::Command::Connect(token) => {
+                    // トークンのみ受け付け。復号失敗ならエラー。
+                    // 中身は 1 つ以上の addr:port（カンマ区切り）で、並列に接続を試す
+                    let target = match crypto::decrypt_conninfo_from_hex(&token) {
+                        Ok(s) => s,
                         Err(e) => {
                             tx_main
                                 .send(rpc::Event::Message(format!(
-                                    "接続トークンの復号エラー: {}",
-                                    e
+                                    "接続トークンの復号エラー: {}",
+                                    e
+                                )))
+                                .await
+                                .ok();
+                            continue;
+                        }
+                    };
+                    match dial_any(&transport, &target, connect_timeout).await {
+                        Ok((s, addr)) => {
+                            // トークンに書かれたアドレスと実際の接続先を突き合わせる
+                            let expected: Vec<SocketAddr> = tokio::net::lookup_host(&addr)
+                                .await
+                                .map(|it| it.collect())
+                                .unwrap_or_default();
+                            let mismatch = s
+                                .peer_addr()
+                                .ok()
+                                .and_then(|actual| address_mismatch(&expected, actual));
+                            peers.push(Peer::new(s, clock.now_millis(), None));
+                            let id = peers.len() - 1;
+                            // 接続直後に公開鍵ハンドシェイクを送信
+                            if let (Some(pubk), Some(pk)) = (public.as_ref(), pkcs8.as_ref())
+                                && let Some(hello) =
+                                    build_signed_hello(&handle, bio.as_deref(), pk, pubk)
+                            {
+                                let frame = protocol::encode(&hello);
+                                send_or_drop(
+                                    &mut peers,
+                                    id,
+                                    &frame,
+                                    &tx_main,
+                                    &mut remove_indices,
+                                    &mut drop_reasons,
+                                )
+                                .await;
+                            }
+                            tx_main
+                                .send(rpc::Event::PeerConnected {
+                                    id,
+                                    token: token.clone(),
+                                    inbound: false,
+                                })
+                                .await
+                                .ok();
+                            if let Some(detail) = mismatch {
+                                tx_main
+                                    .send(rpc::Event::Message(format!(
+                                        "⚠ 接続先アドレス不一致 id={}: {}",
+                                        id, detail
+                                    )))
+                                    .await
+                                    .ok();
+                                audit(audit_event(AuditKind::AddressMismatch, id, None, detail));
+                            }
+                        }
+                        Err(e) => {
+                            tx_main
+                                .send(rpc::Event::Message(format!(
+                                    "接続エラー (token={}): {}",
+                                    token, e
+                                )))
+                                .await
+                                .ok();
+                        }
+                    }
+                }
+                rpc::Command::Token => {
+                    let msg = if listeners.is_empty() {
+                        "待受は起動していません (/open <port>)".into()
+                    } else {
+                        listeners
+                            .iter()
+                            .map(|(port, l)| listener_tokens(*port, l.mapping.as_ref()))
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    };
+                    tx_main.send(rpc::Event::Message(msg)).await.ok();
+                }
+                rpc::Command::Discover => {
+                    tx_main
+                        .send(rpc::Event::Message(directory.describe()))
+                        .await
+                        .ok();
+                }
+                rpc::Command::Topology => {
+                    let Some((pk, pubk)) = pkcs8.as_deref().zip(public.as_deref()) else {
+                        tx_main
+                            .send(rpc::Event::Message("鍵未生成 (/init を先に実行)".into()))
+                            .await
+                            .ok();
+                        continue;
+                    };
+                    let Some(id) = crypto::random_bytes(protocol::TOPOLOGY_QUERY_ID_LEN)
+                        .ok()
+                        .and_then(|b| b.try_into().ok())
+                    else {
+                        continue;
+                    };
+                    let query = protocol::Message::topology_query(clock.now_millis(), &id);
+                    let Some(m) = sign_message(query, pk, pubk) else {
+                        tx_main
+                            .send(rpc::Event::Message("署名生成失敗".into()))
+                            .await
+                            .ok();
+                        continue;
+                    };
+                    // 中継で戻ってきた自分の問い合わせには答えない
+                    is_duplicate_message(&m, &mut seen_messages, &mut seen_order);
+                    let frame = protocol::encode(&m);
+                    let mut asked = 0;
+                    for i in 0..peers.len() {
+                        // v1 のノードは知らない kind を受けると切断するので送らない
+                        if is_ready(&peers, i)
+                            && peer_version(&peers, i) >= protocol::PROTOCOL_VERSION
+                            && send_or_drop(
+                                &mut peers,
+                                i,
+                                &frame,
+                                &tx_main,
+                                &mut remove_indices,
+                                &mut drop_reasons,
+                            )
+                            .await
+                        {
+                            asked += 1;
+                        }
+                    }
+                    let neighbors = neighbor_fingerprints(&peers, &[]);
+                    topology = Some(TopologyView::new(
+                        id,
+                        &route_fingerprint(pubk),
+                        &neighbors,
+                        clock.now_millis(),
+                    ));
+                    tx_main
+                        .send(rpc::Event::Message(format!(
+                            "構成を問い合わせ中: {} ピア ({} 秒待ちます)",
+                            asked,
+                            TOPOLOGY_WAIT_MS / 1000
+                        )))
+                        .await
+                        .ok();
+                }
+                rpc::Command::Timers => {
+                    tx_main
+                        .send(rpc::Event::Message(timers.describe()))
+                        .await
+                        .ok();
+                }
+                rpc::Command::Close(port) => {
+                    let closing: Vec<u16> = match port {
+                        Some(p) => listeners
+                            .contains_key(&p)
+                            .then_some(p)
+                            .into_iter()
+                            .collect(),
+                        None => listeners.keys().copied().collect(),
+                    };
+                    if closing.is_empty() {
+                        let msg = match port {
+                            Some(p) => format!("ポート {} では待受していません", p),
+                            None => "待受は起動していません".into(),
+                        };
+                        tx_main.send(rpc::Event::Message(msg)).await.ok();
+                    }
+                    for p in closing {
+                        if let Some(l) = listeners.remove(&p) {
+                            drop(l.socket);
+                            release_mapping(l.mapping, &tx_main).await;
+                            tx_main
+                                .send(rpc::Event::Message(format!(
+                                    "待受を終了しました (port={})",
+                                    p
+                                )))
+                                .await
+                                .ok();
+                        }
+                    }
+                }
+                rpc::Command::Disconnect(rest) => {
+                    if let Ok(id) = rest.trim().parse::<usize>() {
+                        if id < peers.len() {
+                            remove_indices.push(id);
+                            note_drop_reason(&mut drop_reasons, id, "自分から切断");
+                        } else {
+                            tx_main
+                                .send(rpc::Event::Message(format!("切断: 不正な id {}", id)))
+                                .await
+                                .ok();
+                        }
+                    } else {
+                        tx_main
+                            .send(rpc::Event::Message(format!(
+                                "切断: 解析エラー '{}': 数値を指定してください",
+                                rest
+                            )))
+                            .await
+                            .ok();
+                    }
+                }
+                rpc::Command::PeerList => {
+                    let mut list = Vec::with_capacity(peers.len());
+                    for (i, p) in peers.iter().enumerate() {
+                        let addr = p
+                            .conn
+                            .peer_addr()
+                            .map(|a| a.to_string())
+                            .unwrap_or_else(|_| "?".into());
+                        let token =
+                            crypto::encrypt_conninfo_to_hex(&addr).unwrap_or_else(|_| "?".into());
+                        let meta = p.meta.as_ref();
+                        let fingerprint = meta.map(|m| {
+                            let d = ring::digest::digest(&ring::digest::SHA256, &m.public_key);
+                            crypto::to_hex(d.as_ref())[..16].to_string()
+                        });
+                        list.push(rpc::PeerInfo {
+                            id: i,
+                            token,
+                            fingerprint,
+                            handle: meta.and_then(|m| m.handle.clone()),
+                            state: peer_state(meta, p.puzzle_pending()),
+                            rtt_ms: meta.and_then(|m| m.rtt_ms),
+                            bytes_in: p.bytes,
+                            queued_bytes: p.queue.queued_bytes(),
+                            via_port: p.listener,
+                            handle_suspect: is_handle_suspect(&peers, &verified, i),
+                        });
+                    }
+                    tx_main
+                        .send(rpc::Event::PeerList {
+                            listening: listeners.keys().copied().collect(),
+                            peers: list,
+                        })
+                        .await
+                        .ok();
+                }
+                rpc::Command::Whois(rest) => {
+                    let Some(id) = rest
+                        .trim()
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|&i| i < peers.len())
+                    else {
+                        tx_main
+                            .send(rpc::Event::Message(format!(
+                                "whois: 不正な id {}",
+                                rest.trim()
+                            )))
+                            .await
+                            .ok();
+                        continue;
+                    };
+                    let meta = peers.get(id).and_then(|p| p.meta.as_ref());
+                    let mut lines = vec![
+                        format!("id={} の情報", id),
+                        "本人確認（署名鍵）:".to_string(),
+                    ];
+                    match meta {
+                        Some(m) => {
+                            let fp = crypto::fingerprint_hex(&m.public_key)[..16].to_string();
+                            let mark = if verified.contains(&fp) {
+                                " ✔ 検証済み"
+                            } else {
+                                ""
+                            };
+                            lines.push(format!("  指紋: {}{}", fp, mark));
+                            lines.push(format!(
+                                "  ハンドル: {} (自己申告)",
+                                m.handle.as_deref().unwrap_or("?")
+                            ));
+                            if let Some(bio) = &m.bio {
+                                lines.push(format!("  ひとこと: {} (自己申告)", bio));
+                            }
+                        }
+                        None => lines.push("  HELLO 前のため鍵はまだ分かりません".to_string()),
+                    }
+                    lines.push(format!(
+                        "  状態: {}",
+                        peer_state(meta, peers[id].puzzle_pending()).label()
+                    ));
+                    let Ok(addr) = peers[id].conn.peer_addr() else {
+                        lines.push("ネットワーク情報: アドレス不明".to_string());
+                        tx_main
+                            .send(rpc::Event::Message(lines.join("\n")))
+                            .await
+                            .ok();
+                        continue;
+                    };
+                    let dns = if peer_lookup {
+                        let server = config::get_value(config::keys::DNS_SERVER)
+                            .and_then(|v| v.as_str().map(|s| s.to_string()));
+                        netinfo::default_nameserver(server.as_deref())
+                    } else {
+                        None
+                    };
+                    // 問い合わせの応答を待つ間も受信を止めないよう、調べて表示するのは別タスクで
+                    let tx = tx_main.clone();
+                    let db = asn_db.clone();
+                    tokio::spawn(async move {
+                        let mut info = netinfo::lookup(addr.ip(), dns, db).await;
+                        if peer_lookup && dns.is_none() {
+                            info.ptr = netinfo::Lookup::Unknown;
+                        }
+                        lines.extend(netinfo::render(addr, &info));
+                        tx.send(rpc::Event::Message(lines.join("\n"))).await.ok();
+                    });
+                }
+                rpc::Command::Certs => {
+                    let mut lines = vec!["証明書:".to_string()];
+                    for (i, p) in peers.iter().enumerate() {
+                        match &p.meta {
+                            Some(m) => {
+                                let d = ring::digest::digest(&ring::digest::SHA256, &m.public_key);
+                                let h = crypto::to_hex(d.as_ref());
+                                lines.push(format!(
+                                    "id={} 有効={} ts={} 公開鍵長={} 指紋={}",
+                                    i,
+                                    m.last_valid,
+                                    m.last_timestamp,
+                                    m.public_key.len(),
+                                    &h[..32]
+                                ));
+                            }
+                            None => lines.push(format!("id={} <鍵なし>", i)),
+                        }
+                    }
+                    tx_main
+                        .send(rpc::Event::Message(lines.join("\n")))
+                        .await
+                        .ok();
+                }
+                rpc::Command::Cert(rest) => {
+                    let Some(id) = rest
+                        .trim()
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|&i| i < peers.len())
+                    else {
+                        tx_main
+                            .send(rpc::Event::Message(format!(
+                                "cert: 不正な id {}",
+                                rest.trim()
+                            )))
+                            .await
+                            .ok();
+                        continue;
+                    };
+                    let lines = match peers.get(id).and_then(|p| p.meta.as_ref()) {
+                        Some(m) => vec![
+                            format!("id={} の証明書", id),
+                            format!("  指紋: {}", crypto::fingerprint_hex(&m.public_key)),
+                            format!("  公開鍵: {}", crypto::to_hex(&m.public_key)),
+                            format!("  公開鍵長: {}", m.public_key.len()),
+                            format!(
+                                "  最後の署名: {}",
+                                if m.last_valid { "有効" } else { "無効" }
+                            ),
+                            format!("  最終ts: {}", m.last_timestamp),
+                        ],
+                        None => vec![format!("id={} <鍵なし>（HELLO 未受信）", id)],
+                    };
+                    tx_main
+                        .send(rpc::Event::Message(lines.join("\n")))
+                        .await
+                        .ok();
+                }
+                rpc::Command::Version => {
+                    let mut lines = vec!["ピアのプロトコルバージョン:".to_string()];
+                    for (i, p) in peers.iter().enumerate() {
+                        let v = p
+                            .meta
+                            .as_ref()
+                            .and_then(|m| m.protocol_version)
+                            .map(|v| {
+                                let mark = if v == protocol::PROTOCOL_VERSION {
+                                    ""
+                                } else {
+                                    " (不一致)"
+                                };
+                                format!("{}{}", v, mark)
+                            })
+                            .unwrap_or_else(|| "? (HELLO未受信)".into());
+                        lines.push(format!("id={} protocol={}", i, v));
+                    }
+                    tx_main
+                        .send(rpc::Event::Message(lines.join("\n")))
+                        .await
+                        .ok();
+                }
+                rpc::Command::Handle(name) => {
+                    if config::is_valid_handle(&name) {
+                        handle = name.clone();
+                        tx_main
+                            .send(rpc::Event::Message(format!("ハンドル適用: {}", handle)))
+                            .await
+                            .ok();
+                    } else {
+                        tx_main
+                            .send(rpc::Event::Message(format!(
+                                "/handle は {}",
+                                config::handle_rule()
+                            )))
+                            .await
+                            .ok();
+                    }
+                }
+                rpc::Command::Chat(rest, reply_to, urgent) => {
+                    let target = reply_to.as_deref().and_then(parse_message_id);
+                    if let (Some(r), None) = (reply_to.as_ref(), target) {
+                        tx_main
+                            .send(rpc::Event::Message(format!("不正なメッセージID: {}", r)))
+                            .await
+                            .ok();
+                        tx_main.send(rpc::Event::Unsent).await.ok();
+                        continue;
+                    }
+                    let Some(keys) = pkcs8.as_deref().zip(public.as_deref()) else {
+                        tx_main
+                            .send(rpc::Event::Message("鍵未生成 (/init を先に実行)".into()))
+                            .await
+                            .ok();
+                        tx_main.send(rpc::Event::Unsent).await.ok();
+                        continue;
+                    };
+                    // HELLO を済ませたピアがいなければ、その相手が現れるまで送信待ち
+                    if ready_peers(&peers) == 0 {
+                        outbox.push(rest, reply_to, urgent);
+                        tx_main.send(rpc::Event::Queued).await.ok();
+                        continue;
+                    }
+                    let failed = send_chat(
+                        &rest,
+                        reply_to,
+                        urgent,
+                        &handle,
+                        keys,
+                        &mut authors,
+                        &mut send_seq,
+                        &mut peers,
+                        &tx_main,
+                    )
+                    .await;
+                    for i in failed {
+                        remove_indices.push(i);
+                        note_drop_reason(&mut drop_reasons, i, "送信エラー");
+                    }
+                }
+                rpc::Command::DM(to_str, msg_body, ephemeral) => {
+                    if dm_nonces.exhausted() {
+                        tx_main
+                            .send(rpc::Event::Message(format!(
+                                "DM の暗号化回数が上限 ({} 通) に達したため送信できません",
+                                dm_nonces.used()
+                            )))
+                            .await
+                            .ok();
+                        continue;
+                    }
+                    // /dm・/edm <to_id> <message>。宛先が指紋なら全ピアへ流して中継してもらう
+                    if let Some(to) = parse_route_fingerprint(&to_str) {
+                        let msg = if ephemeral {
+                            "揮発 DM は直接つながっているピアにだけ送れます".to_string()
+                        } else if let (Some(pk), Some(pubk)) = (pkcs8.as_ref(), public.as_ref()) {
+                            let body = format!("{}: {}", handle, msg_body);
+                            match build_signed_routed_dm(&mut dm_nonces, &to, &body, pk, pubk) {
+                                Some(m) => {
+                                    let keys = Some((pk.as_slice(), pubk.as_slice()));
+                                    let mut sent = 0;
+                                    for i in 0..peers.len() {
+                                        let v = peer_version(&peers, i);
+                                        if is_ready(&peers, i)
+                                            && let Some(frame) = frame_for_peer(&m, v, keys)
+                                            && send_or_drop(
+                                                &mut peers,
+                                                i,
+                                                &frame,
+                                                &tx_main,
+                                                &mut remove_indices,
+                                                &mut drop_reasons,
+                                            )
+                                            .await
+                                        {
+                                            sent += 1;
+                                        }
+                                    }
+                                    let handle = Some(handle.clone());
+                                    if let Some(rec) = dm_record(
+                                        &m,
+                                        None,
+                                        None,
+                                        handle,
+                                        body,
+                                        rpc::SigState::Valid,
+                                        Some(crypto::to_hex(&to)),
+                                    ) {
+                                        report_storage(
+                                            crate::storage::store_structured(&rec, None),
+                                            &tx_main,
+                                        );
+                                    }
+                                    format!("指紋 {} 宛ての DM を {} ピアへ送信", to_str, sent)
+                                }
+                                None => "DM署名生成失敗".to_string(),
+                            }
+                        } else {
+                            "鍵未生成 (/init を先に実行)".to_string()
+                        };
+                        tx_main.send(rpc::Event::Message(msg)).await.ok();
+                        continue;
+                    }
+                    // @handle なら同じハンドルのピアのうち最も信用できる相手へ送る
+                    let to_str = if to_str.starts_with('@') {
+                        match resolve_handle(&peers, &verified, &to_str) {
+                            Ok(m) => {
+                                if !m.impostors.is_empty() {
+                                    let ids: Vec<String> =
+                                        m.impostors.iter().map(usize::to_string).collect();
+                                    tx_main
+                                        .send(rpc::Event::Message(format!(
+                                            "⚠ {} を名乗るピアが他にもいます (id={})。信用できる id={} へ送ります",
+                                            to_str,
+                                            ids.join(","),
+                                            m.target
+                                        )))
+                                        .await
+                                        .ok();
+                                }
+                                m.target.to_string()
+                            }
+                            Err(msg) => {
+                                tx_main.send(rpc::Event::Message(msg)).await.ok();
+                                continue;
+                            }
+                        }
+                    } else {
+                        to_str
+                    };
+                    if let Ok(target) = to_str.parse::<usize>() {
+                        if target < peers.len() && !is_ready(&peers, target) {
+                            // 相手が誰かまだ確かめていないので送らない
+                            let state = peer_state(
+                                peers[target].meta.as_ref(),
+                                peers[target].puzzle_pending(),
+                            );
+                            tx_main
+                                .send(rpc::Event::Message(format!(
+                                    "DM 宛先 id {} はまだ HELLO を終えていません ({})",
+                                    target,
+                                    state.label()
+                                )))
+                                .await
+                                .ok();
+                        } else if target < peers.len() {
+                            if let (Some(pk), Some(pubk)) = (pkcs8.as_ref(), public.as_ref()) {
+                                let body = format!("{}: {}", handle, msg_body);
+                                if let Some(m) =
+                                    build_signed_dm(&mut dm_nonces, &body, ephemeral, pk, pubk)
+                                {
+                                    let keys = Some((pk.as_slice(), pubk.as_slice()));
+                                    let v = peer_version(&peers, target);
+                                    if let Some(frame) = frame_for_peer(&m, v, keys) {
+                                        send_or_drop(
+                                            &mut peers,
+                                            target,
+                                            &frame,
+                                            &tx_main,
+                                            &mut remove_indices,
+                                            &mut drop_reasons,
+                                        )
+                                        .await;
+                                    }
+                                    // 保存（送信メタ）。揮発 DM は保存しない
+                                    let handle = Some(handle.clone());
+                                    let peer_fp =
+                                        peers.get(target).and_then(|p| p.meta.as_ref()).map(|m| {
+                                            crypto::fingerprint_hex(&m.public_key)[..16].to_string()
+                                        });
+                                    let rec = dm_record(
+                                        &m,
+                                        None,
+                                        Some(target),
+                                        handle,
+                                        body,
+                                        rpc::SigState::Valid,
+                                        peer_fp,
+                                    );
+                                    if let Some(rec) = rec {
+                                        report_storage(
+                                            crate::storage::store_structured(&rec, None),
+                                            &tx_main,
+                                        );
+                                    }
+                                } else {
+                                    tx_main
+                                        .send(rpc::Event::Message("DM署名生成失敗".into()))
+                                        .await
+                                        .ok();
+                                }
+                            } else {
+                                tx_main
+                                    .send(rpc::Event::Message("鍵未生成 (/init を先に実行)".into()))
+                                    .await
+                                    .ok();
+                            }
+                        } else {
+                            tx_main
+                                .send(rpc::Event::Message(format!(
+                                    "DM 宛先 id {} が範囲外です",
+                                    target
                                 )))
                                 .await
                                 .ok();
+                        }
+                    } else {
+                        tx_main
+                            .send(rpc::Event::Message(format!("不正な DM 宛先: {}", to_str)))
+                            .await
+                            .ok();
+                    }
+                }
+                rpc::Command::Trust(fp) => {
+                    verified.insert(fp);
+                }
+                rpc::Command::Fanout(limit) => {
+                    fanout.limit = limit;
+                    let state =
+                        limit.map_or("無制限".to_string(), |n| format!("{} 件/ティック", n));
+                    tx_main
+                        .send(rpc::Event::Message(format!("中継の送信上限: {}", state)))
+                        .await
+                        .ok();
+                }
+                rpc::Command::Relay(on) => {
+                    relay_enabled = on;
+                    let state = if on { "ON" } else { "OFF (leaf)" };
+                    tx_main
+                        .send(rpc::Event::Message(format!("中継: {}", state)))
+                        .await
+                        .ok();
+                }
+                rpc::Command::Edit(id, text) => {
+                    let body = format!("{}: {}", handle, text);
+                    let keys = pkcs8.as_deref().zip(public.as_deref());
+                    let failed = amend_own(
+                        &id,
+                        Some(&body),
+                        keys,
+                        &mut authors,
+                        &mut send_seq,
+                        &mut peers,
+                        &tx_main,
+                    )
+                    .await;
+                    for i in failed {
+                        remove_indices.push(i);
+                        note_drop_reason(&mut drop_reasons, i, "送信エラー");
+                    }
+                }
+                rpc::Command::Delete(id) => {
+                    let keys = pkcs8.as_deref().zip(public.as_deref());
+                    let failed = amend_own(
+                        &id,
+                        None,
+                        keys,
+                        &mut authors,
+                        &mut send_seq,
+                        &mut peers,
+                        &tx_main,
+                    )
+                    .await;
+                    for i in failed {
+                        remove_indices.push(i);
+                        note_drop_reason(&mut drop_reasons, i, "送信エラー");
+                    }
+                }
+                rpc::Command::Topic(text) => {
+                    let Some((pk, pubk)) = pkcs8.as_deref().zip(public.as_deref()) else {
+                        tx_main
+                            .send(rpc::Event::Message("鍵未生成 (/init を先に実行)".into()))
+                            .await
+                            .ok();
+                        continue;
+                    };
+                    let Some(m) = build_signed_topic(&text, pk, pubk, send_seq.next()) else {
+                        tx_main
+                            .send(rpc::Event::Message("署名生成失敗".into()))
+                            .await
+                            .ok();
+                        continue;
+                    };
+                    let TopicUpdate::Accepted(text, by) = topic.accept(&m) else {
+                        tx_main
+                            .send(rpc::Event::Message(format!(
+                                "トピックは{}文字までです",
+                                protocol::MAX_TOPIC_CHARS
+                            )))
+                            .await
+                            .ok();
+                        continue;
+                    };
+                    // 自分に中継で戻ってきた分は重複として捨てる
+                    is_duplicate_message(&m, &mut seen_messages, &mut seen_order);
+                    for i in 0..peers.len() {
+                        let v = peer_version(&peers, i);
+                        let Some(frame) = frame_for_peer(&m, v, Some((pk, pubk))) else {
+                            continue;
+                        };
+                        send_or_drop(
+                            &mut peers,
+                            i,
+                            &frame,
+                            &tx_main,
+                            &mut remove_indices,
+                            &mut drop_reasons,
+                        )
+                        .await;
+                    }
+                    tx_main.send(rpc::Event::Topic { text, by }).await.ok();
+                }
+                rpc::Command::Raw(target, bytes) => {
+                    if let Some(id) = target.filter(|&id| id >= peers.len()) {
+                        tx_main
+                            .send(rpc::Event::Message(format!("/raw: 不正な id {}", id)))
+                            .await
+                            .ok();
+                        continue;
+                    }
+                    let mut sent = 0;
+                    for i in 0..peers.len() {
+                        if target.is_some_and(|t| t != i) {
                             continue;
                         }
+                        if send_or_drop(
+                            &mut peers,
+                            i,
+                            &bytes,
+                            &tx_main,
+                            &mut remove_indices,
+                            &mut drop_reasons,
+                        )
+                        .await
+                        {
+                            sent += 1;
+                        }
+                    }
+                    tx_main
+                        .send(rpc::Event::Message(format!(
+                            "/raw: {} バイトを {} ピアへ送信",
+                            bytes.len(),
+                            sent
+                        )))
+                        .await
+                        .ok();
+                }
+                rpc::Command::Dump(id) => {
+                    let msg = match peers.get(id).map(|p| &p.last_raw) {
+                        Some(raw) if raw.is_empty() => {
+                            format!("id={} はまだ何も受信していません", id)
+                        }
+                        Some(raw) => format!(
+                            "id={} 最終受信 {} バイト:\n{}",
+                            id,
+                            raw.len(),
+                            hex_dump(raw)
+                        ),
+                        None => format!("/dump: 不正な id {}", id),
                     };
-                    match TcpStream::connect(&target).await {
-                        Ok(s) => {
-                            clients.push(s);
-                            decoders.push(protocol::Decoder::new());
-                            peer_meta.push(None);
-                            let id = clients.len() - 1;
-                            // 接続直後に公開鍵ハンドシェイクを送信
-                            if let (Some(pubk), Some(pk)) = (public.as_ref(), pkcs8.as_ref()) {
-                                if let Some(hello) = build_signed_hello(&handle, pk, pubk) {
-                                    let frame = protocol::encode(&hello);
-                                    let _ = clients[id].write_all(&frame).await;
+                    tx_main.send(rpc::Event::Message(msg)).await.ok();
+                }
+                rpc::Command::RotateKey(new_pkcs8, new_public) => {
+                    if let (Some(pk), Some(pubk)) = (pkcs8.as_ref(), public.as_ref())
+                        && let Some(m) = build_signed_rotation(&new_public, pk, pubk)
+                    {
+                        let keys = Some((pk.as_slice(), pubk.as_slice()));
+                        for i in 0..peers.len() {
+                            let v = peer_version(&peers, i);
+                            let Some(frame) = frame_for_peer(&m, v, keys) else {
+                                continue;
+                            };
+                            send_or_drop(
+                                &mut peers,
+                                i,
+                                &frame,
+                                &tx_main,
+                                &mut remove_indices,
+                                &mut drop_reasons,
+                            )
+                            .await;
+                        }
+                    }
+                    pkcs8 = Some(new_pkcs8);
+                    public = Some(new_public);
+                    tx_main
+                        .send(rpc::Event::Message(format!(
+                            "鍵ローテーション完了 (通知先 {} ピア)",
+                            peers.len()
+                        )))
+                        .await
+                        .ok();
+                }
+                rpc::Command::Shutdown => {
+                    for (_, l) in std::mem::take(&mut listeners) {
+                        release_mapping(l.mapping, &tx_main).await;
+                    }
+                    // durability が fast / relaxed でも、止まる前に書き出しておく
+                    report_storage(storage::flush(), &tx_main);
+                    tx_main
+                        .send(rpc::Event::Message("ネットワークスレッド終了".into()))
+                        .await
+                        .ok();
+                    break 'main_loop;
+                }
+            }
+        }
+
+        // accept（接続待ちでコマンド処理を止めないよう、来ていなければすぐ戻る）
+        for (port, l) in listeners.iter() {
+            match tokio::time::timeout(Duration::from_millis(1), l.socket.accept())
+                .await
+                .unwrap_or_else(|_| Err(std::io::ErrorKind::WouldBlock.into()))
+            {
+                Ok((s, peer)) => {
+                    peers.push(Peer::new(s, clock.now_millis(), Some(*port)));
+                    let id = peers.len() - 1;
+                    // パズルが有効なら HELLO より先に出題する
+                    let puzzle = (puzzle_difficulty > 0)
+                        .then(|| Puzzle::new(puzzle_difficulty, clock.now_millis()))
+                        .flatten();
+                    if let Some(p) = &puzzle {
+                        send_or_drop(
+                            &mut peers,
+                            id,
+                            &protocol::encode(&p.message()),
+                            &tx_main,
+                            &mut remove_indices,
+                            &mut drop_reasons,
+                        )
+                        .await;
+                    }
+                    peers[id].puzzle = puzzle;
+                    // 受け入れ側も公開鍵を送信
+                    if let (Some(pubk), Some(pk)) = (public.as_ref(), pkcs8.as_ref())
+                        && let Some(hello) = build_signed_hello(&handle, bio.as_deref(), pk, pubk)
+                    {
+                        let frame = protocol::encode(&hello);
+                        send_or_drop(
+                            &mut peers,
+                            id,
+                            &frame,
+                            &tx_main,
+                            &mut remove_indices,
+                            &mut drop_reasons,
+                        )
+                        .await;
+                    }
+                    let token = crypto::encrypt_conninfo_to_hex(&peer.to_string())
+                        .unwrap_or_else(|_| "?".to_string());
+                    tx_main
+                        .send(rpc::Event::PeerConnected {
+                            id,
+                            token,
+                            inbound: true,
+                        })
+                        .await
+                        .ok();
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    tx_main
+                        .send(rpc::Event::Message(format!("受け入れエラー: {:?}", e)))
+                        .await
+                        .ok();
+                }
+            }
+        }
+
+        // 接続したピアへ送信待ちのチャットを送る
+        if let Some(keys) = pkcs8.as_deref().zip(public.as_deref()) {
+            let ready = outbox.take_ready(ready_peers(&peers));
+            let mut failed = Vec::new();
+            if !ready.is_empty() {
+                tx_main
+                    .send(rpc::Event::Message(format!(
+                        "送信待ち {} 件を送信します",
+                        ready.len()
+                    )))
+                    .await
+                    .ok();
+            }
+            for (text, reply_to, urgent) in ready {
+                failed.extend(
+                    send_chat(
+                        &text,
+                        reply_to,
+                        urgent,
+                        &handle,
+                        keys,
+                        &mut authors,
+                        &mut send_seq,
+                        &mut peers,
+                        &tx_main,
+                    )
+                    .await,
+                );
+            }
+            for i in failed {
+                remove_indices.push(i);
+                note_drop_reason(&mut drop_reasons, i, "送信エラー");
+            }
+        }
+
+        // 読み取り (バイナリプロトコル優先)
+        let mut received_frames: Vec<(usize, protocol::Frame)> = Vec::new();
+        // 解けた接続パズルの解答を送り、保留していたフレームを処理に回す
+        while let Ok(solved) = rx_solved.try_recv() {
+            let Some(idx) = peers.iter().position(|p| {
+                p.solving
+                    .as_ref()
+                    .is_some_and(|s| s.ticket == solved.ticket)
+            }) else {
+                continue;
+            };
+            let Some(solving) = peers[idx].solving.take() else {
+                continue;
+            };
+            let frame = protocol::encode(&protocol::Message::solution(
+                clock.now_millis(),
+                solved.nonce,
+            ));
+            send_or_drop(
+                &mut peers,
+                idx,
+                &frame,
+                &tx_main,
+                &mut remove_indices,
+                &mut drop_reasons,
+            )
+            .await;
+            tx_main
+                .send(rpc::Event::Message(format!(
+                    "接続パズルに解答: id={} 難易度={}",
+                    idx, solving.difficulty
+                )))
+                .await
+                .ok();
+            received_frames.extend(solving.held.into_iter().map(|m| (idx, m)));
+        }
+        // 前のティックで上限に達して残った中継を、今回の上限の範囲で送る
+        fanout.start_tick();
+        for (idx, kind) in release_relay_backlog(&mut peers, &mut fanout).await {
+            tx_main
+                .send(rpc::Event::Message(format!(
+                    "Relay write error to {}: {:?}",
+                    idx, kind
+                )))
+                .await
+                .ok();
+            remove_indices.push(idx);
+            note_drop_reason(&mut drop_reasons, idx, "送信エラー");
+        }
+        // 一時的な失敗で残った送信を再送し、上限を超えたら切断
+        for (idx, p) in peers.iter_mut().enumerate() {
+            if p.queue.is_empty() {
+                continue;
+            }
+            if let Flush::Drop(kind) = p.queue.flush(&mut p.conn) {
+                tx_main
+                    .send(rpc::Event::Message(format!(
+                        "送信を再試行しましたが失敗しました {}: {:?}",
+                        idx, kind
+                    )))
+                    .await
+                    .ok();
+                remove_indices.push(idx);
+                note_drop_reason(&mut drop_reasons, idx, "送信エラー");
+            }
+        }
+        // 履歴同期の返事は、送信キューが空いたピアへ 1 ティック 1 ページずつ送る
+        for idx in 0..peers.len() {
+            if !peers[idx].queue.is_empty() || remove_indices.contains(&idx) {
+                continue;
+            }
+            let version = peer_version(&peers, idx);
+            let Some(page) = peers[idx].sync.next_reply(version, clock.now_millis()) else {
+                continue;
+            };
+            if let Flush::Drop(kind) = peers[idx].send(&protocol::encode(&page)) {
+                tx_main
+                    .send(rpc::Event::Message(format!(
+                        "履歴同期の送信に失敗しました {}: {:?}",
+                        idx, kind
+                    )))
+                    .await
+                    .ok();
+                remove_indices.push(idx);
+                note_drop_reason(&mut drop_reasons, idx, "送信エラー");
+            }
+        }
+        for idx in shed_backlogged(&peers, send_buffer_limit, &remove_indices) {
+            let queued = peers[idx].queue.queued_bytes();
+            let pk = peers
+                .get(idx)
+                .and_then(|p| p.meta.as_ref())
+                .map(|m| m.public_key.as_slice());
+            audit(audit_event(
+                AuditKind::Backpressure,
+                idx,
+                pk,
+                format!("送信待ち={}B 上限={}B", queued, send_buffer_limit),
+            ));
+            tx_main
+                .send(rpc::Event::Message(format!(
+                    "送信待ちが上限を超えたため、最も詰まっているピア {} を切断します ({}B)",
+                    idx, queued
+                )))
+                .await
+                .ok();
+            remove_indices.push(idx);
+            note_drop_reason(&mut drop_reasons, idx, "送信待ちが多すぎる");
+        }
+        for (idx, peer) in peers.iter_mut().enumerate() {
+            // 切ると決めたピアからはもう読まない
+            if remove_indices.contains(&idx) {
+                continue;
+            }
+            // 1 ティックに読むのは、デコーダが溜めてよい量まで。送り続けるピアでも
+            // 上限を超える前に切り出せ、他のピアを待たせない
+            match read_burst(
+                &mut peer.conn,
+                &mut buf,
+                peer.decoder.max_buffered(),
+                &mut burst,
+            ) {
+                Ok(0) => {
+                    tx_main
+                        .send(rpc::Event::Message(format!(
+                            "クライアント {} が切断しました",
+                            idx
+                        )))
+                        .await
+                        .ok();
+                    remove_indices.push(idx);
+                    note_drop_reason(&mut drop_reasons, idx, "相手が切断");
+                }
+                Ok(n) => {
+                    if n > 0 {
+                        peer.bytes += n as u64;
+                        metrics::add(&METRICS.bytes_in, n as u64);
+                        peer.last_raw = burst.clone();
+                        peer.last_activity = clock.now_millis();
+                        peer.decoder.feed(&burst);
+                        match peer.decoder.drain_frames() {
+                            Ok(mut msgs) => {
+                                for m in msgs.drain(..) {
+                                    // 接続パズルの解答前は保留し、解けたらまとめて処理する
+                                    let step = match peer.puzzle.as_mut() {
+                                        Some(p) => p.check(m),
+                                        None => {
+                                            received_frames.push((idx, m));
+                                            continue;
+                                        }
+                                    };
+                                    match step {
+                                        PuzzleStep::Held => {}
+                                        PuzzleStep::Solved(held) => {
+                                            peer.puzzle = None;
+                                            received_frames
+                                                .extend(held.into_iter().map(|m| (idx, m)));
+                                            tx_main
+                                                .send(rpc::Event::Message(format!(
+                                                    "接続パズル正解: id={}",
+                                                    idx
+                                                )))
+                                                .await
+                                                .ok();
+                                        }
+                                        PuzzleStep::Failed => {
+                                            // 理由ID=8: 接続パズル不正解
+                                            let disc = protocol::Message::disconnect(
+                                                clock.now_millis(),
+                                                8,
+                                            );
+                                            let _ = peer.send(&protocol::encode(&disc));
+                                            audit(disconnect_audit(idx, None, 8));
+                                            tx_main
+                                                .send(rpc::Event::Message(format!(
+                                                    "接続パズル不正解: id={} 切断",
+                                                    idx
+                                                )))
+                                                .await
+                                                .ok();
+                                            remove_indices.push(idx);
+                                            note_drop_reason(
+                                                &mut drop_reasons,
+                                                idx,
+                                                disconnect_reason_text(8),
+                                            );
+                                            break;
+                                        }
+                                    }
                                 }
                             }
-                            tx_main
-                                .send(rpc::Event::Message(format!(
-                                    "接続完了 (token={}) id={}",
-                                    token, id
-                                )))
-                                .await
-                                .ok();
+                            Err(e) => {
+                                drop_malformed_peer(idx, &e, peer, &tx_main).await;
+                                remove_indices.push(idx);
+                                note_drop_reason(&mut drop_reasons, idx, disconnect_reason_text(7));
+                            }
                         }
-                        Err(e) => {
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    tx_main
+                        .send(rpc::Event::Message(format!("受信エラー {}: {:?}", idx, e)))
+                        .await
+                        .ok();
+                    remove_indices.push(idx);
+                    note_drop_reason(&mut drop_reasons, idx, "受信エラー");
+                }
+            }
+        }
+
+        // 中継と表示 + 署名検証
+        order_by_source(&mut received_frames);
+        // 処理の途中で切ると決めたピアの、それより後のフレームは処理しない
+        let dropped_while_reading = remove_indices.len();
+        for (src, frame) in received_frames.iter() {
+            let msg = &frame.msg;
+            if remove_indices[dropped_while_reading..].contains(src) {
+                metrics::add(&METRICS.dropped_frames, 1);
+                continue;
+            }
+            // 出された接続パズルを解き終えるまで、その相手のフレームは保留する
+            if let Some(solving) = peers[*src].solving.as_mut() {
+                if solving.held.len() >= MAX_PUZZLE_HELD_FRAMES {
+                    tx_main
+                        .send(rpc::Event::Message(format!(
+                            "接続パズルの解答前に届いたフレームが多すぎます: id={} 切断",
+                            src
+                        )))
+                        .await
+                        .ok();
+                    remove_indices.push(*src);
+                    note_drop_reason(
+                        &mut drop_reasons,
+                        *src,
+                        "接続パズルの解答前の受信が多すぎる",
+                    );
+                } else {
+                    solving.held.push(frame.clone());
+                }
+                continue;
+            }
+            if (msg.kind == protocol::MsgKind::CHAT
+                || protocol::is_dm_kind(msg.kind)
+                || msg.kind == protocol::MsgKind::EDIT
+                || msg.kind == protocol::MsgKind::DELETE
+                || msg.kind == protocol::MsgKind::TOPIC
+                || msg.kind == protocol::MsgKind::SYSTEM
+                || msg.kind == protocol::MsgKind::ROUTED_DM
+                || msg.kind == protocol::MsgKind::TOPOLOGY_QUERY
+                || msg.kind == protocol::MsgKind::TOPOLOGY_REPLY)
+                && is_duplicate_message(msg, &mut seen_messages, &mut seen_order)
+            {
+                metrics::add(&METRICS.dropped_frames, 1);
+                continue;
+            }
+            // 生存確認: PING には同じ番号で返し、PONG で往復時間を測る（中継はしない）
+            if msg.kind == protocol::MsgKind::PONG {
+                if let Some(id) = protocol::ping_id(msg)
+                    && let Some(m) = peers.get_mut(*src).and_then(|p| p.meta.as_mut())
+                {
+                    m.finish_ping(id, clock.now_millis());
+                }
+                continue;
+            }
+            if msg.kind == protocol::MsgKind::PING {
+                if let Some(id) = protocol::ping_id(msg) {
+                    let pong = protocol::encode(&protocol::Message::pong(clock.now_millis(), id));
+                    if let Flush::Drop(kind) = peers[*src].send(&pong) {
+                        tx_main
+                            .send(rpc::Event::Message(format!(
+                                "送信エラー {}: {:?}",
+                                src, kind
+                            )))
+                            .await
+                            .ok();
+                        remove_indices.push(*src);
+                        note_drop_reason(&mut drop_reasons, *src, "送信エラー");
+                    }
+                }
+                continue;
+            }
+            // 接続パズル: 自分から接続した相手の出題にだけ答える（解答は受信時に処理済み）
+            if msg.kind == protocol::MsgKind::CHALLENGE || msg.kind == protocol::MsgKind::SOLUTION {
+                let dialed = peers.get(*src).is_some_and(|p| p.listener.is_none());
+                if let Some((difficulty, challenge)) = protocol::challenge_parts(msg)
+                    && dialed
+                {
+                    if difficulty > MAX_PUZZLE_DIFFICULTY {
+                        tx_main
+                            .send(rpc::Event::Message(format!(
+                                "接続パズルが難しすぎるため解きません: id={} 難易度={}",
+                                src, difficulty
+                            )))
+                            .await
+                            .ok();
+                        continue;
+                    }
+                    // 解いている間も他のピアの受信を止めないよう、別スレッドで解いて結果を受け取る
+                    puzzle_ticket += 1;
+                    let ticket = puzzle_ticket;
+                    peers[*src].solving = Some(Solving {
+                        ticket,
+                        difficulty,
+                        held: Vec::new(),
+                    });
+                    let tx = tx_solved.clone();
+                    tokio::spawn(async move {
+                        let nonce = tokio::task::spawn_blocking(move || {
+                            crypto::solve_puzzle(&challenge, difficulty)
+                        })
+                        .await
+                        .unwrap_or_default();
+                        tx.send(SolvedPuzzle { ticket, nonce }).await.ok();
+                    });
+                    tx_main
+                        .send(rpc::Event::DebugMessage(format!(
+                            "接続パズルを解いています: id={} 難易度={}",
+                            src, difficulty
+                        )))
+                        .await
+                        .ok();
+                }
+                continue;
+            }
+            // 鍵ローテーション: 既知の旧鍵の署名を確認してから新鍵に差し替える
+            if msg.kind == protocol::MsgKind::ROTATE {
+                let known = peers
+                    .get(*src)
+                    .and_then(|p| p.meta.as_ref())
+                    .map(|m| m.public_key.clone());
+                match known.and_then(|k| verify_rotation(msg, &k)) {
+                    Some(new_key) => {
+                        let d = ring::digest::digest(&ring::digest::SHA256, &new_key);
+                        let h = crypto::to_hex(d.as_ref());
+                        audit(audit_event(
+                            AuditKind::KeyChange,
+                            *src,
+                            msg.public_key.as_deref(),
+                            format!("新指紋={}", &h[..16]),
+                        ));
+                        let mut key_change = None;
+                        if let Some(meta) = peers.get_mut(*src).and_then(|p| p.meta.as_mut()) {
+                            meta.public_key = new_key;
+                            meta.last_timestamp = msg.timestamp;
+                            key_change = meta
+                                .handle
+                                .as_deref()
+                                .and_then(|hd| verified_key_change_event(*src, hd, &h[..16]));
+                        }
+                        tx_main
+                            .send(rpc::Event::Message(format!(
+                                "鍵ローテーション: id={} 新指紋={}",
+                                src,
+                                &h[..16]
+                            )))
+                            .await
+                            .ok();
+                        if let Some(ev) = key_change {
+                            tx_main.send(ev).await.ok();
+                        }
+                    }
+                    None => {
+                        // 理由ID=4: 不正な鍵ローテーション
+                        let disc = protocol::Message::disconnect(clock.now_millis(), 4);
+                        audit(disconnect_audit(*src, msg.public_key.as_deref(), 4));
+                        let frame = protocol::encode(&disc);
+                        let _ = peers[*src].send(&frame);
+                        tx_main
+                            .send(rpc::Event::Message(format!(
+                                "不正な鍵ローテーション: id={} 切断",
+                                src
+                            )))
+                            .await
+                            .ok();
+                        remove_indices.push(*src);
+                        note_drop_reason(&mut drop_reasons, *src, disconnect_reason_text(4));
+                    }
+                }
+                continue;
+            }
+            // 編集・削除: 元の投稿者の鍵で署名されたものだけ適用して中継する
+            if msg.kind == protocol::MsgKind::EDIT || msg.kind == protocol::MsgKind::DELETE {
+                match authorize_amend(msg, &mut authors) {
+                    Some((target, text)) => {
+                        let id = crypto::to_hex(&target);
+                        report_storage(crate::storage::amend_by_id(&id, text.as_deref()), &tx_main);
+                        let line = amended_line(&id, text.as_deref());
+                        tx_main.send(rpc::Event::Replace { id, line }).await.ok();
+                        relay(
+                            frame,
+                            *src,
+                            relay_enabled,
+                            &mut peers,
+                            &mut fanout,
+                            &tx_main,
+                            &mut remove_indices,
+                        )
+                        .await;
+                    }
+                    None => {
+                        audit(audit_event(
+                            AuditKind::BadSignature,
+                            *src,
+                            msg.public_key.as_deref(),
+                            format!("不正な編集/削除 kind={}", msg.kind),
+                        ));
+                        metrics::add(&METRICS.dropped_frames, 1);
+                        tx_main
+                            .send(rpc::Event::Message(format!(
+                                "不正な編集/削除を破棄: id={}",
+                                src
+                            )))
+                            .await
+                            .ok();
+                    }
+                }
+                continue;
+            }
+            // 宛先付き DM: 自分宛てだけ復号し、他人宛ては中身を見ずに中継する
+            if msg.kind == protocol::MsgKind::ROUTED_DM {
+                match route_dm(msg, public.as_deref(), relay_dms) {
+                    RoutedDm::ForMe(encrypted) => {
+                        let (txt, binary) = decrypt_dm_text(encrypted);
+                        let sig = match (msg.signature.as_ref(), signature_failure(msg, *src)) {
+                            (None, _) => rpc::SigState::Unsigned,
+                            (Some(_), None) => rpc::SigState::Valid,
+                            (Some(_), Some(event)) => {
+                                if count_bad_signature(&mut peers, *src) {
+                                    audit(event);
+                                }
+                                metrics::add(&METRICS.signature_failures, 1);
+                                tx_main
+                                    .send(rpc::Event::SignatureFailed { id: *src })
+                                    .await
+                                    .ok();
+                                rpc::SigState::Invalid
+                            }
+                        };
+                        let urgent = honor_urgent && sig == rpc::SigState::Valid && msg.urgent;
+                        let mut line = format!("{} {}", txt, sig.mark());
+                        if urgent {
+                            line.push_str(rpc::URGENT_MARK);
+                        }
+                        tx_main.send(rpc::Event::Post { line, sig }).await.ok();
+                        let from = msg
+                            .public_key
+                            .as_ref()
+                            .map(|pk| crypto::fingerprint_hex(pk)[..16].to_string());
+                        let handle = signed_handle_field(&txt).map(str::to_string);
+                        let sender = handle.clone().or_else(|| from.clone());
+                        if let Some(mut rec) =
+                            dm_record(msg, Some(*src), None, handle, txt, sig, from)
+                        {
+                            rec.binary = binary;
+                            report_storage(crate::storage::store_structured(&rec, None), &tx_main);
+                        }
+                        let from = sender.unwrap_or_else(|| "?".into());
+                        if urgent {
                             tx_main
-                                .send(rpc::Event::Message(format!(
-                                    "接続エラー (token={}): {:?}",
-                                    token, e
-                                )))
+                                .send(rpc::Event::UrgentArrived { from: from.clone() })
                                 .await
                                 .ok();
                         }
+                        tx_main.send(rpc::Event::DmArrived { from }).await.ok();
+                    }
+                    RoutedDm::Forward => {
+                        relay(
+                            frame,
+                            *src,
+                            relay_enabled,
+                            &mut peers,
+                            &mut fanout,
+                            &tx_main,
+                            &mut remove_indices,
+                        )
+                        .await;
+                    }
+                    RoutedDm::Drop => {}
+                }
+                continue;
+            }
+            // トピック: 署名を確かめ、今のものより新しければ採用して中継する
+            if msg.kind == protocol::MsgKind::TOPIC {
+                match topic.accept(msg) {
+                    TopicUpdate::Accepted(text, by) => {
+                        tx_main.send(rpc::Event::Topic { text, by }).await.ok();
+                        relay(
+                            frame,
+                            *src,
+                            relay_enabled,
+                            &mut peers,
+                            &mut fanout,
+                            &tx_main,
+                            &mut remove_indices,
+                        )
+                        .await;
+                    }
+                    TopicUpdate::Stale => {}
+                    TopicUpdate::Rejected => {
+                        audit(audit_event(
+                            AuditKind::BadSignature,
+                            *src,
+                            msg.public_key.as_deref(),
+                            format!("不正なトピック ts={}", msg.timestamp),
+                        ));
+                        metrics::add(&METRICS.dropped_frames, 1);
+                        tx_main
+                            .send(rpc::Event::Message(format!(
+                                "不正なトピックを破棄: id={}",
+                                src
+                            )))
+                            .await
+                            .ok();
+                    }
+                }
+                continue;
+            }
+            // 履歴同期: 有効なときだけ、HELLO を済ませたピアとやり取りする（中継はしない）
+            if msg.kind == protocol::MsgKind::SYNC_COUNTS
+                || msg.kind == protocol::MsgKind::SYNC_REQUEST
+                || msg.kind == protocol::MsgKind::SYNC_RECORDS
+            {
+                let ready = history_sync
+                    && !storage::history_disabled()
+                    && peers.get(*src).is_some_and(|p| p.meta.is_some());
+                if !ready {
+                    metrics::add(&METRICS.dropped_frames, 1);
+                    continue;
+                }
+                let now = clock.now_millis();
+                if let Some(theirs) = protocol::sync_counts(msg) {
+                    let dates = sync_dates_to_request(&storage::day_counts(), &theirs, now);
+                    for date in &dates {
+                        if let Some(req) = peers[*src].sync.request(date, now) {
+                            let _ = peers[*src].send(&protocol::encode(&req));
+                        }
+                    }
+                    if !dates.is_empty() {
+                        tx_main
+                            .send(rpc::Event::Message(format!(
+                                "履歴同期: id={} と {} 日分を照合します",
+                                src,
+                                dates.len()
+                            )))
+                            .await
+                            .ok();
                     }
-                }
-                rpc::Command::Close => {
-                    if listener.is_some() {
-                        drop(listener.take());
+                } else if let Some((date, have)) = protocol::sync_request_parts(msg) {
+                    // 返事は送信キューが空いたときに 1 ページずつ送る
+                    if !sync_date_in_window(&date, now) || !peers[*src].sync.serve(date, have) {
+                        metrics::add(&METRICS.dropped_frames, 1);
+                    }
+                } else if let Some((date, posts)) = protocol::sync_records_parts(msg) {
+                    // 要求して返事を待っている日の分だけ、上限まで受け取る
+                    let take = peers[*src].sync.accept(&date, posts.len(), now);
+                    if take == 0 {
+                        metrics::add(&METRICS.dropped_frames, 1);
                         tx_main
-                            .send(rpc::Event::Message("待受を終了しました".into()))
+                            .send(rpc::Event::Message(format!(
+                                "履歴同期: id={} から要求していない {} の投稿を破棄",
+                                src, date
+                            )))
                             .await
                             .ok();
-                    } else {
+                        continue;
+                    }
+                    let (mut stored, mut dup, mut bad) = (0, 0, 0);
+                    for post in &posts[..take] {
+                        match absorb_synced(post, *src, now, &mut authors) {
+                            SyncedPost::Stored => stored += 1,
+                            SyncedPost::Duplicate => dup += 1,
+                            SyncedPost::Invalid => {
+                                bad += 1;
+                                metrics::add(&METRICS.signature_failures, 1);
+                            }
+                        }
+                    }
+                    if bad > 0 && count_bad_signature(&mut peers, *src) {
+                        audit(audit_event(
+                            AuditKind::BadSignature,
+                            *src,
+                            None,
+                            format!("履歴同期の不正な投稿 {} 件 ({})", bad, date),
+                        ));
+                    }
+                    tx_main
+                        .send(rpc::Event::Message(format!(
+                            "履歴同期: id={} から {} の投稿 {} 件を取り込み (重複 {} 件・不正 {} 件)",
+                            src, date, stored, dup, bad
+                        )))
+                        .await
+                        .ok();
+                } else {
+                    metrics::add(&METRICS.dropped_frames, 1);
+                }
+                continue;
+            }
+            // ピア紹介: 本人の署名を確かめて一覧に加える（中継はしない）
+            if msg.kind == protocol::MsgKind::ADVERT {
+                match directory.accept(msg) {
+                    AdvertUpdate::Added(fp, addr) => {
                         tx_main
-                            .send(rpc::Event::Message("待受は起動していません".into()))
+                            .send(rpc::Event::Message(format!(
+                                "ピア紹介: 指紋={} {} (/discover で一覧)",
+                                fp, addr
+                            )))
                             .await
                             .ok();
                     }
+                    AdvertUpdate::Stale => {}
+                    AdvertUpdate::Rejected => {
+                        audit(audit_event(
+                            AuditKind::BadSignature,
+                            *src,
+                            msg.public_key.as_deref(),
+                            format!("不正なピア紹介 ts={}", msg.timestamp),
+                        ));
+                    }
                 }
-                rpc::Command::Disconnect(rest) => {
-                    if let Ok(id) = rest.trim().parse::<usize>() {
-                        if id < clients.len() {
-                            clients.remove(id);
-                            decoders.remove(id);
-                            peer_meta.remove(id);
-                            tx_main
-                                .send(rpc::Event::Message(format!("切断しました id {}", id)))
-                                .await
-                                .ok();
-                        } else {
-                            tx_main
-                                .send(rpc::Event::Message(format!("切断: 不正な id {}", id)))
-                                .await
-                                .ok();
+                continue;
+            }
+            // 構成の問い合わせ: 同意していれば隣接ピアの指紋を署名して返す。問い合わせも返事も中継する
+            if msg.kind == protocol::MsgKind::TOPOLOGY_QUERY
+                || msg.kind == protocol::MsgKind::TOPOLOGY_REPLY
+            {
+                let signed = msg
+                    .public_key
+                    .as_ref()
+                    .zip(msg.signature.as_ref())
+                    .is_some_and(|(pk, sig)| verify_signed_message(msg, sig, pk));
+                if !signed {
+                    audit(audit_event(
+                        AuditKind::BadSignature,
+                        *src,
+                        msg.public_key.as_deref(),
+                        format!("不正な構成の問い合わせ kind={}", msg.kind),
+                    ));
+                    metrics::add(&METRICS.dropped_frames, 1);
+                    continue;
+                }
+                if let Some(view) = topology.as_mut() {
+                    view.absorb(msg);
+                }
+                if share_topology
+                    && let Some(id) = protocol::topology_query_id(msg)
+                    && let Some((k, p)) = pkcs8.as_deref().zip(public.as_deref())
+                    && let Some(reply) = build_signed_topology_reply(
+                        &id,
+                        &neighbor_fingerprints(&peers, &remove_indices),
+                        k,
+                        p,
+                    )
+                {
+                    is_duplicate_message(&reply, &mut seen_messages, &mut seen_order);
+                    let frame = protocol::encode(&reply);
+                    for i in 0..peers.len() {
+                        if is_ready(&peers, i)
+                            && peer_version(&peers, i) >= protocol::PROTOCOL_VERSION
+                        {
+                            send_or_drop(
+                                &mut peers,
+                                i,
+                                &frame,
+                                &tx_main,
+                                &mut remove_indices,
+                                &mut drop_reasons,
+                            )
+                            .await;
                         }
-                    } else {
+                    }
+                }
+                relay(
+                    frame,
+                    *src,
+                    relay_enabled,
+                    &mut peers,
+                    &mut fanout,
+                    &tx_main,
+                    &mut remove_indices,
+                )
+                .await;
+                continue;
+            }
+            // お知らせ: 署名を確かめてから表示・保存し、中継する
+            if msg.kind == protocol::MsgKind::SYSTEM {
+                match system_line(msg) {
+                    Some(line) => {
+                        report_storage(
+                            crate::storage::store_structured(
+                                &system_record(msg, line.clone()),
+                                None,
+                            ),
+                            &tx_main,
+                        );
+                        tx_main.send(rpc::Event::Message(line)).await.ok();
+                        relay(
+                            frame,
+                            *src,
+                            relay_enabled,
+                            &mut peers,
+                            &mut fanout,
+                            &tx_main,
+                            &mut remove_indices,
+                        )
+                        .await;
+                    }
+                    None => {
+                        audit(audit_event(
+                            AuditKind::BadSignature,
+                            *src,
+                            msg.public_key.as_deref(),
+                            format!("不正なお知らせ ts={}", msg.timestamp),
+                        ));
+                        metrics::add(&METRICS.dropped_frames, 1);
                         tx_main
                             .send(rpc::Event::Message(format!(
-                                "切断: 解析エラー '{}': 数値を指定してください",
-                                rest
+                                "不正なお知らせを破棄: id={}",
+                                src
                             )))
                             .await
                             .ok();
                     }
                 }
-                rpc::Command::PeerList => {
-                    let mut lines = Vec::new();
-                    lines.push(format!(
-                        "ピア数={} 待受={}",
-                        clients.len(),
-                        listener.is_some()
-                    ));
-                    for (i, c) in clients.iter().enumerate() {
-                        let addr = c
-                            .peer_addr()
-                            .map(|a| a.to_string())
-                            .unwrap_or_else(|_| "?".into());
-                        let tok =
-                            crypto::encrypt_conninfo_to_hex(&addr).unwrap_or_else(|_| "?".into());
-                        let fp = peer_meta
-                            .get(i)
-                            .and_then(|m| m.as_ref())
-                            .map(|m| {
-                                let d = ring::digest::digest(&ring::digest::SHA256, &m.public_key);
-                                let h = crypto::to_hex(d.as_ref());
-                                format!("指紋={}", &h[..16])
-                            })
-                            .unwrap_or_else(|| "指紋=?".into());
-                        lines.push(format!("id={} token={} {}", i, tok, fp));
+                continue;
+            }
+            // テキスト復号/デコード
+            let (txt, binary) = if protocol::is_dm_kind(msg.kind) {
+                decrypt_dm_text(&msg.payload)
+            } else {
+                (
+                    String::from_utf8_lossy(protocol::chat_text(msg)).to_string(),
+                    None,
+                )
+            };
+            // 不正検知: 本文のハンドル欄の長さチェック（中継・表示より前に行う）。
+            // 署名の有無や正否に関わらず見るので、署名なしの投稿でもすり抜けない
+            if let Some(count) = oversized_signed_handle(&txt) {
+                // 切断: 理由ID=1（ハンドル長超過）
+                let disc = protocol::Message::disconnect(clock.now_millis(), 1);
+                audit(disconnect_audit(*src, msg.public_key.as_deref(), 1));
+                let frame = protocol::encode(&disc);
+                let _ = peers[*src].send(&frame);
+                tx_main
+                    .send(rpc::Event::Message(format!(
+                        "不正検知: id={} のハンドル長({})が制限超過のため切断",
+                        src, count
+                    )))
+                    .await
+                    .ok();
+                remove_indices.push(*src);
+                note_drop_reason(&mut drop_reasons, *src, disconnect_reason_text(1));
+                continue;
+            }
+            let mut sig = if msg.signature.is_some() {
+                rpc::SigState::Valid
+            } else {
+                rpc::SigState::Unsigned
+            };
+            let mut good = true;
+            if let Some(pk) = msg.public_key.as_ref() {
+                if let Some(event) = signature_failure(msg, *src) {
+                    sig = rpc::SigState::Invalid;
+                    good = false;
+                    if count_bad_signature(&mut peers, *src) {
+                        audit(event);
                     }
+                    metrics::add(&METRICS.signature_failures, 1);
                     tx_main
-                        .send(rpc::Event::Message(lines.join("\n")))
+                        .send(rpc::Event::SignatureFailed { id: *src })
                         .await
                         .ok();
                 }
-                rpc::Command::Certs => {
-                    let mut lines = vec!["証明書:".to_string()];
-                    for (i, meta) in peer_meta.iter().enumerate() {
-                        match meta {
-                            Some(m) => {
-                                let d = ring::digest::digest(&ring::digest::SHA256, &m.public_key);
-                                let h = crypto::to_hex(d.as_ref());
-                                lines.push(format!(
-                                    "id={} 有効={} ts={} 公開鍵長={} 指紋={}",
-                                    i,
-                                    m.last_valid,
-                                    m.last_timestamp,
-                                    m.public_key.len(),
-                                    &h[..32]
-                                ));
-                            }
-                            None => lines.push(format!("id={} <鍵なし>", i)),
+                // メタ更新（既存のハンドル情報は維持）。
+                // 中継されてきた他人の鍵で隣接ピアの鍵を上書きしない
+                let mut seq_check = SeqCheck::InOrder;
+                match peers.get_mut(*src).map(|p| &mut p.meta) {
+                    Some(Some(meta)) if meta.public_key == *pk => {
+                        meta.last_valid = good;
+                        meta.last_timestamp = msg.timestamp;
+                        if let Some(seq) = msg.seq.filter(|_| good) {
+                            seq_check = check_seq(&mut meta.next_seq, seq);
                         }
                     }
+                    Some(slot @ None) => {
+                        *slot = Some(PeerMeta {
+                            public_key: pk.clone(),
+                            last_valid: good,
+                            last_timestamp: msg.timestamp,
+                            handle: None,
+                            protocol_version: None,
+                            bio: None,
+                            next_seq: None,
+                            state: rpc::PeerState::Handshaking,
+                            handle_since: 0,
+                            ping: None,
+                            rtt_ms: None,
+                        });
+                    }
+                    _ => {}
+                }
+                if let Some(detail) = seq_problem(&seq_check) {
+                    audit(audit_event(
+                        AuditKind::Sequence,
+                        *src,
+                        Some(pk),
+                        detail.clone(),
+                    ));
                     tx_main
-                        .send(rpc::Event::Message(lines.join("\n")))
+                        .send(rpc::Event::Message(format!("⚠ id={} {}", src, detail)))
                         .await
                         .ok();
-                }
-                rpc::Command::Handle(name) => {
-                    if name.starts_with('@') && name.chars().count() < 80 {
-                        handle = name.clone();
-                        tx_main
-                            .send(rpc::Event::Message(format!("ハンドル適用: {}", handle)))
-                            .await
-                            .ok();
-                    } else {
-                        tx_main
-                            .send(rpc::Event::Message(
-                                "/handle は @から始まり80文字未満".into(),
-                            ))
-                            .await
-                            .ok();
+                    // 抜けた分は履歴同期で取り寄せる（有効なときだけ）
+                    if matches!(seq_check, SeqCheck::Gap { .. })
+                        && history_sync
+                        && !storage::history_disabled()
+                    {
+                        let date = storage::date_string(msg.timestamp);
+                        let now = clock.now_millis();
+                        if sync_date_in_window(&date, now)
+                            && let Some(req) = peers[*src].sync.request(&date, now)
+                        {
+                            let _ = peers[*src].send(&protocol::encode(&req));
+                        }
                     }
                 }
-                rpc::Command::Chat(rest) => {
-                    // 送信メッセージをプロトコルフレーム化
-                    if let (Some(ref pk), Some(ref pubk)) = (pkcs8.as_ref(), public.as_ref()) {
-                        // 送信本文にハンドルをプレーンで含める
-                        let body = format!("{}: {}", handle, rest);
-                        if let Some(m) = build_signed_chat(&body, pk, pubk) {
-                            let frame = protocol::encode(&m);
-                            let mut remove = Vec::new();
-                            for (i, c) in clients.iter_mut().enumerate() {
-                                if let Err(e) = c.write_all(&frame).await {
-                                    tx_main
-                                        .send(rpc::Event::Message(format!(
-                                            "送信エラー {}: {:?}",
-                                            i, e
-                                        )))
-                                        .await
-                                        .ok();
-                                    remove.push(i);
-                                }
-                            }
-                            // 保存（送信メタ）
-                            let rec = crate::storage::MessageRecord {
-                                ts_millis: m.timestamp,
-                                recv_ts_millis: current_unix_millis(),
-                                kind: crate::storage::MsgKind::Chat,
-                                from_peer_id: None,
-                                to_peer_id: None,
-                                handle: Some(handle.clone()),
-                                text: body,
-                                signed_ok: Some(true),
-                            };
-                            let _ = crate::storage::store_structured(&rec);
-                            for i in remove.into_iter().rev() {
-                                clients.remove(i);
-                                decoders.remove(i);
-                            }
-                        } else {
+            }
+            if msg.kind == protocol::MsgKind::DISCONNECT {
+                let reason = protocol::disconnect_reason_id(msg).unwrap_or(0);
+                let known = peers.get(*src).and_then(|p| p.meta.as_ref());
+                audit(audit_event(
+                    AuditKind::Disconnect,
+                    *src,
+                    known.map(|m| m.public_key.as_slice()),
+                    format!(
+                        "相手から切断 reason={} ({})",
+                        reason,
+                        disconnect_reason_text(reason)
+                    ),
+                ));
+                tx_main
+                    .send(rpc::Event::Message(format!(
+                        "相手から切断通知 id={} reason={}",
+                        src, reason
+                    )))
+                    .await
+                    .ok();
+                remove_indices.push(*src);
+                note_drop_reason(
+                    &mut drop_reasons,
+                    *src,
+                    format!("相手から切断通知: {}", disconnect_reason_text(reason)),
+                );
+            } else if msg.kind == protocol::MsgKind::HELLO {
+                // 相手の公開鍵が含まれていれば保存
+                if let Some(pk) = msg.public_key.as_ref() {
+                    // HELLO 自体の署名検証
+                    if let Some(sig) = msg.signature.as_ref() {
+                        if !verify_signed_message(msg, sig, pk) {
+                            metrics::add(&METRICS.signature_failures, 1);
                             tx_main
-                                .send(rpc::Event::Message("署名生成失敗".into()))
+                                .send(rpc::Event::SignatureFailed { id: *src })
+                                .await
+                                .ok();
+                            // 理由ID=3: HELLO署名不正
+                            let disc = protocol::Message::disconnect(clock.now_millis(), 3);
+                            audit(disconnect_audit(*src, msg.public_key.as_deref(), 3));
+                            let frame = protocol::encode(&disc);
+                            let _ = peers[*src].send(&frame);
+                            tx_main
+                                .send(rpc::Event::Message(format!(
+                                    "不正HELLO署名: id={} 切断",
+                                    src
+                                )))
                                 .await
                                 .ok();
+                            remove_indices.push(*src);
+                            note_drop_reason(&mut drop_reasons, *src, disconnect_reason_text(3));
+                            continue;
                         }
                     } else {
+                        // 署名なし HELLO は不許可
+                        let disc = protocol::Message::disconnect(clock.now_millis(), 3);
+                        audit(disconnect_audit(*src, msg.public_key.as_deref(), 3));
+                        let frame = protocol::encode(&disc);
+                        let _ = peers[*src].send(&frame);
                         tx_main
-                            .send(rpc::Event::Message("鍵未生成 (/init を先に実行)".into()))
+                            .send(rpc::Event::Message(format!(
+                                "HELLO署名なし: id={} 切断",
+                                src
+                            )))
                             .await
                             .ok();
+                        remove_indices.push(*src);
+                        note_drop_reason(&mut drop_reasons, *src, disconnect_reason_text(3));
+                        continue;
                     }
-                }
-                rpc::Command::DM(to_str, msg_body) => {
-                    // /dm <to_id> <message>
-                    if let Ok(target) = to_str.parse::<usize>() {
-                        if target < clients.len() {
-                            if let (Some(ref pk), Some(ref pubk)) =
-                                (pkcs8.as_ref(), public.as_ref())
+
+                    if *src < peers.len() {
+                        let (peer_handle, peer_bio) = match protocol::hello_parts(msg) {
+                            Some((h, b)) => (h.to_string(), b),
+                            None => (String::from_utf8_lossy(&msg.payload).to_string(), None),
+                        };
+                        // ひとこともハンドルと同じく表示を崩す文字は受け付けない
+                        let bad_bio = peer_bio.is_some_and(|b| !config::is_valid_bio(b));
+                        if bad_bio || !config::is_valid_peer_handle(&peer_handle) {
+                            let disc = protocol::Message::disconnect(clock.now_millis(), 2);
+                            audit(disconnect_audit(*src, msg.public_key.as_deref(), 2));
+                            let frame = protocol::encode(&disc);
+                            let _ = peers[*src].send(&frame);
+                            let (field, value) = match peer_bio.filter(|_| bad_bio) {
+                                Some(b) => ("ひとこと", b),
+                                None => ("ハンドル", peer_handle.as_str()),
+                            };
+                            tx_main
+                                .send(rpc::Event::Message(format!(
+                                    "不正HELLO: id={} の{} '{}' が不正のため切断",
+                                    src,
+                                    field,
+                                    value.escape_debug()
+                                )))
+                                .await
+                                .ok();
+                            remove_indices.push(*src);
+                            note_drop_reason(&mut drop_reasons, *src, disconnect_reason_text(2));
+                        } else {
+                            // 同一 ID の二重接続は古い方を落とす。相手が気づかないうちに切れた
+                            // 接続が残っていても、つなぎ直しを締め出さない
+                            if let Some(existing) =
+                                find_duplicate_identity(&peers, *src, pk, &remove_indices)
                             {
-                                let body = format!("{}: {}", handle, msg_body);
-                                if let Some(m) = build_signed_dm(&body, pk, pubk) {
-                                    let frame = protocol::encode(&m);
-                                    if let Err(e) = clients[target].write_all(&frame).await {
-                                        tx_main
-                                            .send(rpc::Event::Message(format!(
-                                                "DM送信エラー {}: {:?}",
-                                                target, e
-                                            )))
-                                            .await
-                                            .ok();
-                                    }
-                                    // 保存（送信メタ）
-                                    let rec = crate::storage::MessageRecord {
-                                        ts_millis: m.timestamp,
-                                        recv_ts_millis: current_unix_millis(),
-                                        kind: crate::storage::MsgKind::Dm,
-                                        from_peer_id: None,
-                                        to_peer_id: Some(target),
-                                        handle: Some(handle.clone()),
-                                        text: body,
-                                        signed_ok: Some(true),
-                                    };
-                                    let _ = crate::storage::store_structured(&rec);
-                                } else {
+                                // 理由ID=5: 重複 ID
+                                let disc = protocol::Message::disconnect(clock.now_millis(), 5);
+                                audit(disconnect_audit(existing, msg.public_key.as_deref(), 5));
+                                let frame = protocol::encode(&disc);
+                                let _ = peers[existing].send(&frame);
+                                tx_main
+                                    .send(rpc::Event::Message(format!(
+                                        "重複 ID: id={} は新しい接続 id={} と同じ鍵のため古い方を切断",
+                                        existing, src
+                                    )))
+                                    .await
+                                    .ok();
+                                remove_indices.push(existing);
+                                note_drop_reason(
+                                    &mut drop_reasons,
+                                    existing,
+                                    disconnect_reason_text(5),
+                                );
+                            }
+                            let version = protocol::hello_version(msg);
+                            let keys = pkcs8.as_deref().zip(public.as_deref());
+                            // 続けざまのハンドル変更は無視し、前のハンドルのままにする
+                            let now = clock.now_millis();
+                            let prev = peers[*src].meta.as_ref();
+                            let kept =
+                                rate_limited_handle(prev, &peer_handle, now, handle_change_min_ms)
+                                    .map(str::to_string);
+                            if let Some(old) = &kept {
+                                tx_main
+                                    .send(rpc::Event::Message(format!(
+                                        "ハンドル変更を無視: id={} {} → {} (前の変更から{}秒以内)",
+                                        src,
+                                        old,
+                                        peer_handle,
+                                        handle_change_min_ms / 1000
+                                    )))
+                                    .await
+                                    .ok();
+                            }
+                            let handle_since = match prev {
+                                Some(m)
+                                    if kept.is_some()
+                                        || m.handle.as_deref() == Some(&peer_handle) =>
+                                {
+                                    m.handle_since
+                                }
+                                _ => now,
+                            };
+                            // 署名の確認で先にメタができているので、Ready になる前かで見る
+                            let first_hello = prev.is_none_or(|m| m.state != rpc::PeerState::Ready);
+                            let meta = PeerMeta {
+                                public_key: pk.clone(),
+                                last_valid: true,
+                                last_timestamp: msg.timestamp,
+                                handle: Some(kept.unwrap_or(peer_handle)),
+                                protocol_version: Some(version),
+                                bio: peer_bio.map(str::to_string),
+                                next_seq: None,
+                                state: rpc::PeerState::Ready,
+                                handle_since,
+                                ping: None,
+                                rtt_ms: prev.and_then(|m| m.rtt_ms),
+                            };
+                            peers[*src].meta = Some(meta);
+                            // 最初の HELLO の後すぐに往復時間を測る（以後は PING のタイマーで測り直す）
+                            if first_hello
+                                && can_ping(&peers, *src)
+                                && let Some(m) = peers[*src].meta.as_mut()
+                            {
+                                ping_seq += 1;
+                                let frame = m.start_ping(ping_seq, now);
+                                let _ = peers[*src].send(&frame);
+                            }
+                            // 後から来たピアにも現在のトピックを伝える
+                            if let Some(frame) = topic.replay_frame(version) {
+                                send_or_drop(
+                                    &mut peers,
+                                    *src,
+                                    &frame,
+                                    &tx_main,
+                                    &mut remove_indices,
+                                    &mut drop_reasons,
+                                )
+                                .await;
+                            }
+                            // 知っているピアを紹介し、同意していれば自分の待受アドレスも伝える
+                            for frame in directory.introductions_for(pk, version) {
+                                send_or_drop(
+                                    &mut peers,
+                                    *src,
+                                    &frame,
+                                    &tx_main,
+                                    &mut remove_indices,
+                                    &mut drop_reasons,
+                                )
+                                .await;
+                            }
+                            // 相手が来た待受、無ければ最初の待受を広告する
+                            let own = peers[*src]
+                                .listener
+                                .and_then(|p| listeners.get_key_value(&p))
+                                .or_else(|| listeners.iter().next());
+                            let own_addr = own.and_then(|(port, l)| match &l.mapping {
+                                Some(m) => Some(m.external_addr()),
+                                None => peers[*src]
+                                    .conn
+                                    .local_addr()
+                                    .ok()
+                                    .map(|a| SocketAddr::new(a.ip(), *port).to_string()),
+                            });
+                            if advertise
+                                && let Some(frame) = own_addr
+                                    .zip(keys)
+                                    .and_then(|(addr, (k, p))| build_signed_advert(&addr, k, p))
+                                    .and_then(|m| frame_for_peer(&m, version, keys))
+                            {
+                                send_or_drop(
+                                    &mut peers,
+                                    *src,
+                                    &frame,
+                                    &tx_main,
+                                    &mut remove_indices,
+                                    &mut drop_reasons,
+                                )
+                                .await;
+                            }
+                            // 履歴同期: 範囲内の日ごとの件数を伝える（足りない側が要求してくる）
+                            if history_sync && !storage::history_disabled() {
+                                let days: Vec<(String, u64)> = storage::day_counts()
+                                    .into_iter()
+                                    .filter(|(d, _)| sync_date_in_window(d, now))
+                                    .collect();
+                                let counts = protocol::Message::sync_counts(now, &days);
+                                let _ = peers[*src].send(&protocol::encode(&counts));
+                            }
+                            // 歓迎メッセージは受け入れた相手にだけ送る（自分からつないだ相手へ送ると、
+                            // 互いに設定しているノード同士で送り合ってしまう）
+                            if first_hello
+                                && peers[*src].listener.is_some()
+                                && let Some((k, p)) = keys
+                                && welcome.take(pk, now)
+                            {
+                                let body = format!("{}: {}", handle, welcome.text);
+                                if let Some(frame) =
+                                    build_signed_dm(&mut dm_nonces, &body, false, k, p)
+                                        .and_then(|m| frame_for_peer(&m, version, keys))
+                                {
+                                    send_or_drop(
+                                        &mut peers,
+                                        *src,
+                                        &frame,
+                                        &tx_main,
+                                        &mut remove_indices,
+                                        &mut drop_reasons,
+                                    )
+                                    .await;
                                     tx_main
-                                        .send(rpc::Event::Message("DM署名生成失敗".into()))
+                                        .send(rpc::Event::DebugMessage(format!(
+                                            "歓迎メッセージを送信: id={}",
+                                            src
+                                        )))
                                         .await
                                         .ok();
                                 }
-                            } else {
-                                tx_main
-                                    .send(rpc::Event::Message("鍵未生成 (/init を先に実行)".into()))
-                                    .await
-                                    .ok();
                             }
-                        } else {
-                            tx_main
-                                .send(rpc::Event::Message(format!(
-                                    "DM 宛先 id {} が範囲外です",
-                                    target
-                                )))
-                                .await
-                                .ok();
+                            if !announced_join
+                                && let Some(m) = keys.and_then(|(k, p)| {
+                                    let text = format!("{} が参加しました", handle);
+                                    build_signed_system(&text, k, p, send_seq.next())
+                                })
+                            {
+                                announced_join = true;
+                                is_duplicate_message(&m, &mut seen_messages, &mut seen_order);
+                                for i in 0..peers.len() {
+                                    let v = peer_version(&peers, i);
+                                    if let Some(frame) = frame_for_peer(&m, v, keys) {
+                                        send_or_drop(
+                                            &mut peers,
+                                            i,
+                                            &frame,
+                                            &tx_main,
+                                            &mut remove_indices,
+                                            &mut drop_reasons,
+                                        )
+                                        .await;
+                                    }
+                                }
+                            }
                         }
-                    } else {
+                    }
+                    let d = ring::digest::digest(&ring::digest::SHA256, pk);
+                    let h = crypto::to_hex(d.as_ref());
+                    let accepted = peers
+                        .get(*src)
+                        .and_then(|p| p.meta.as_ref())
+                        .and_then(|m| m.handle.clone())
+                        .filter(|_| !remove_indices.contains(src));
+                    let key_change = accepted
+                        .as_deref()
+                        .and_then(|hd| verified_key_change_event(*src, hd, &h[..16]));
+                    let ev = match accepted {
+                        Some(handle) => rpc::Event::HandshakeComplete {
+                            id: *src,
+                            handle,
+                            fingerprint: h[..16].to_string(),
+                        },
+                        None => {
+                            rpc::Event::Message(format!("HELLO 受信: id={} 指紋={}", src, &h[..16]))
+                        }
+                    };
+                    tx_main.send(ev).await.ok();
+                    if let Some(ev) = key_change {
+                        tx_main.send(ev).await.ok();
+                    }
+                } else {
+                    tx_main
+                        .send(rpc::Event::Message(format!(
+                            "HELLO 受信: id={} (公開鍵なし)",
+                            src
+                        )))
+                        .await
+                        .ok();
+                }
+            } else if protocol::is_dm_kind(msg.kind) {
+                // 受信表示: 本文 + 署名状態記号（揮発 DM は印を付ける）
+                let mut line = format!("{} {}", txt, sig.mark());
+                if good && is_verified_key(&verified, msg.public_key.as_deref()) {
+                    line.push_str(rpc::VERIFIED_MARK);
+                }
+                if msg.kind == protocol::MsgKind::EPHEMERAL_DM {
+                    line.push_str(rpc::EPHEMERAL_MARK);
+                }
+                let urgent = honor_urgent && good && msg.urgent;
+                if urgent {
+                    line.push_str(rpc::URGENT_MARK);
+                }
+                tx_main.send(rpc::Event::Post { line, sig }).await.ok();
+                // 保存（受信メタ）。揮発 DM は保存しない
+                let handle = peers
+                    .get(*src)
+                    .and_then(|p| p.meta.as_ref())
+                    .and_then(|m| m.handle.clone())
+                    .or_else(|| signed_handle_field(&txt).map(str::to_string));
+                let from = msg
+                    .public_key
+                    .as_ref()
+                    .map(|pk| crypto::fingerprint_hex(pk)[..16].to_string());
+                let sender = handle.clone().or_else(|| from.clone());
+                if let Some(mut rec) = dm_record(msg, Some(*src), None, handle, txt, sig, from) {
+                    rec.binary = binary;
+                    report_storage(crate::storage::store_structured(&rec, None), &tx_main);
+                }
+                let from = sender.unwrap_or_else(|| "?".into());
+                if urgent {
+                    tx_main
+                        .send(rpc::Event::UrgentArrived { from: from.clone() })
+                        .await
+                        .ok();
+                }
+                tx_main.send(rpc::Event::DmArrived { from }).await.ok();
+            } else {
+                // 改ざん検知: 署名が通らず、同じ投稿者・時刻の正規の版を受け取っていれば
+                // 表示だけして保存・中継しない
+                if !good && let Some(expected) = ledger.conflicting(msg) {
+                    let expected = crypto::to_hex(&expected);
+                    let got = message_id(msg)
+                        .map(|m| crypto::to_hex(&m))
+                        .unwrap_or_default();
+                    audit(audit_event(
+                        AuditKind::Tampered,
+                        *src,
+                        msg.public_key.as_deref(),
+                        format!("期待ID={} 受信ID={}", expected, got),
+                    ));
+                    let line = format!("{} {} ⚠改ざん (正規 #{})", txt, sig.mark(), expected);
+                    tx_main
+                        .send(rpc::Event::Post {
+                            line,
+                            sig: rpc::SigState::Invalid,
+                        })
+                        .await
+                        .ok();
+                    continue;
+                }
+                if good && msg.signature.is_some() {
+                    // 別の経路から届いた、版違いの同じ投稿
+                    if ledger.knows(msg) {
+                        metrics::add(&METRICS.dropped_frames, 1);
+                        continue;
+                    }
+                    ledger.record(msg);
+                }
+                // 自分の投稿はローカルエコーで表示・保存済みなので、中継だけする
+                if suppress_own_echo && good && is_own_frame(msg, public.as_deref()) {
+                    relay(
+                        frame,
+                        *src,
+                        relay_enabled,
+                        &mut peers,
+                        &mut fanout,
+                        &tx_main,
+                        &mut remove_indices,
+                    )
+                    .await;
+                    continue;
+                }
+                // 受信表示: 統一フォーマット（本文に '@handle: ' が含まれている想定）。
+                // 署名状態は末尾に半角スペース+記号を付ける。
+                let has_handle = peers
+                    .get(*src)
+                    .and_then(|p| p.meta.as_ref())
+                    .is_some_and(|m| m.handle.is_some());
+                let mut disp = if has_handle || txt.contains(':') {
+                    format!("{} {}", txt, sig.mark())
+                } else {
+                    format!("@{}: {} {}", src, txt, sig.mark())
+                };
+                if good && is_verified_key(&verified, msg.public_key.as_deref()) {
+                    disp.push_str(rpc::VERIFIED_MARK);
+                }
+                let urgent = honor_urgent && good && msg.urgent;
+                if urgent {
+                    disp.push_str(rpc::URGENT_MARK);
+                }
+                // 保存（受信メタ）
+                let rec = crate::storage::MessageRecord {
+                    ts_millis: msg.timestamp,
+                    recv_ts_millis: clock.now_millis(),
+                    kind: crate::storage::MsgKind::Chat,
+                    from_peer_id: Some(*src),
+                    to_peer_id: None,
+                    handle: peers
+                        .get(*src)
+                        .and_then(|p| p.meta.as_ref())
+                        .and_then(|m| m.handle.clone())
+                        .or_else(|| signed_handle_field(&txt).map(str::to_string)),
+                    text: txt.clone(),
+                    signature: sig,
+                    reply_to: protocol::reply_target(msg).map(|t| crypto::to_hex(&t)),
+                    binary: None,
+                    proof: signature_proof(msg),
+                    // 接続番号は再接続で変わるので、後から誰の投稿か分かるよう鍵の指紋も残す
+                    peer_fingerprint: msg
+                        .public_key
+                        .as_ref()
+                        .map(|pk| crypto::fingerprint_hex(pk)[..16].to_string()),
+                };
+
+                // 検証済みの署名付き投稿には ID を付け、後から編集・削除できるようにする
+                match (good, msg.public_key.as_ref(), message_id(msg)) {
+                    (true, Some(pk), Some(mid)) if msg.signature.is_some() => {
+                        authors.remember(mid, pk);
+                        let id = crypto::to_hex(&mid);
+                        report_storage(crate::storage::store_structured(&rec, Some(&id)), &tx_main);
+                        let line = format!("#{} {}", id, disp);
+                        let reply_to = rec.reply_to;
                         tx_main
-                            .send(rpc::Event::Message(format!("不正な DM 宛先: {}", to_str)))
+                            .send(rpc::Event::Chat { id, line, reply_to })
                             .await
                             .ok();
                     }
+                    _ => {
+                        let post = rpc::Event::Post { line: disp, sig };
+                        tx_main.send(post).await.ok();
+                        report_storage(crate::storage::store_structured(&rec, None), &tx_main);
+                    }
                 }
-                rpc::Command::Shutdown => {
+                if urgent {
+                    let from = rec.handle.or(rec.peer_fingerprint);
                     tx_main
-                        .send(rpc::Event::Message("ネットワークスレッド終了".into()))
+                        .send(rpc::Event::UrgentArrived {
+                            from: from.unwrap_or_else(|| "?".into()),
+                        })
                         .await
                         .ok();
-                    break 'main_loop;
                 }
+
+                relay(
+                    frame,
+                    *src,
+                    relay_enabled,
+                    &mut peers,
+                    &mut fanout,
+                    &tx_main,
+                    &mut remove_indices,
+                )
+                .await;
+            }
+        }
+
+        // /topology の待ち時間が過ぎたら集まった分で表示する
+        if let Some(view) = topology.take_if(|v| v.deadline <= clock.now_millis()) {
+            tx_main.send(rpc::Event::Message(view.render())).await.ok();
+        }
+
+        // 無通信タイムアウト。返事の PONG も受信なので、生きているピアは切られない
+        let fired = scheduler.due(clock.now_millis());
+        if fired.contains(&TimerKind::Ping) {
+            ping_seq += 1;
+            for idx in 0..peers.len() {
+                if !can_ping(&peers, idx) || remove_indices.contains(&idx) {
+                    continue;
+                }
+                let Some(m) = peers[idx].meta.as_mut() else {
+                    continue;
+                };
+                let frame = m.start_ping(ping_seq, clock.now_millis());
+                if let Flush::Drop(kind) = peers[idx].send(&frame) {
+                    tx_main
+                        .send(rpc::Event::Message(format!(
+                            "送信エラー {}: {:?}",
+                            idx, kind
+                        )))
+                        .await
+                        .ok();
+                    remove_indices.push(idx);
+                    note_drop_reason(&mut drop_reasons, idx, "送信エラー");
+                }
+            }
+        }
+        let idle = if fired.contains(&TimerKind::IdleCheck) {
+            idle_peers(&peers, clock.now_millis(), timers.idle_timeout_ms)
+        } else {
+            Vec::new()
+        };
+        for idx in idle {
+            if remove_indices.contains(&idx) || !may_time_out(&peers, idx) {
+                continue;
+            }
+            // 理由ID=6: 無通信タイムアウト
+            let disc = protocol::Message::disconnect(clock.now_millis(), 6);
+            let frame = protocol::encode(&disc);
+            let _ = peers[idx].send(&frame);
+            let known = peers.get(idx).and_then(|p| p.meta.as_ref());
+            audit(disconnect_audit(
+                idx,
+                known.map(|m| m.public_key.as_slice()),
+                6,
+            ));
+            tx_main
+                .send(rpc::Event::Message(format!(
+                    "無通信タイムアウト: id={} 切断",
+                    idx
+                )))
+                .await
+                .ok();
+            remove_indices.push(idx);
+            note_drop_reason(&mut drop_reasons, idx, disconnect_reason_text(6));
+        }
+
+        // 接続パズルの時間切れ
+        let now = clock.now_millis();
+        for (idx, peer) in peers.iter_mut().enumerate() {
+            let expired = peer.puzzle.as_ref().is_some_and(|p| p.expired(now));
+            if !expired || remove_indices.contains(&idx) {
+                continue;
+            }
+            // 理由ID=9: 接続パズル時間切れ
+            let disc = protocol::Message::disconnect(now, 9);
+            let _ = peer.send(&protocol::encode(&disc));
+            audit(disconnect_audit(idx, None, 9));
+            tx_main
+                .send(rpc::Event::Message(format!(
+                    "接続パズル時間切れ: id={} 切断",
+                    idx
+                )))
+                .await
+                .ok();
+            remove_indices.push(idx);
+            note_drop_reason(&mut drop_reasons, idx, disconnect_reason_text(9));
+        }
+
+        // 署名不正を繰り返すピアは切断する
+        for idx in 0..peers.len() {
+            if peers[idx].bad_signatures < BAD_SIGNATURE_LIMIT || remove_indices.contains(&idx) {
+                continue;
+            }
+            // 理由ID=10: 署名不正の繰り返し
+            let disc = protocol::Message::disconnect(clock.now_millis(), 10);
+            let _ = peers[idx].send(&protocol::encode(&disc));
+            let known = peers.get(idx).and_then(|p| p.meta.as_ref());
+            audit(disconnect_audit(
+                idx,
+                known.map(|m| m.public_key.as_slice()),
+                10,
+            ));
+            tx_main
+                .send(rpc::Event::Message(format!(
+                    "署名不正の繰り返し: id={} 切断",
+                    idx
+                )))
+                .await
+                .ok();
+            remove_indices.push(idx);
+            note_drop_reason(&mut drop_reasons, idx, disconnect_reason_text(10));
+        }
+
+        // HELLO 済みの相手から届いたら最後に見た時刻を残す
+        for (src, _) in received_frames.iter() {
+            if let Some(p) = peers.get(*src)
+                && let Some(m) = &p.meta
+            {
+                last_seen.record(m, p.last_activity, false);
+            }
+        }
+
+        // 削除
+        remove_indices.sort_unstable();
+        remove_indices.dedup();
+        for i in remove_indices.into_iter().rev() {
+            // 間引いて書いていない分も、切断時の最終受信時刻で残す
+            if let Some(m) = &peers[i].meta {
+                last_seen.record(m, peers[i].last_activity, true);
+            }
+            let reason = drop_reasons
+                .remove(&i)
+                .unwrap_or_else(|| "送信エラー".into());
+            remove_peer(&mut peers, i, reason, &tx_main).await;
+        }
+
+        sleep(Duration::from_millis(15)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn relay_probability_is_monotonic() {
+        assert_eq!(relay_probability_percent(0), 100);
+        assert_eq!(relay_probability_percent(FULL_RELAY_ATTENUATION), 100);
+        assert_eq!(relay_probability_percent(protocol::MAX_ATTENUATION), 0);
+        assert!(relay_probability_percent(20) < relay_probability_percent(10));
+    }
+
+    #[test]
+    fn relayed_bytes_keep_the_payload_and_verify_at_the_next_hop() {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        // 圧縮済みのような読めない本文
+        let opaque: Vec<u8> = (0..2048u32).map(|i| (i * 31 % 253) as u8).collect();
+        let msg = protocol::Message {
+            payload: opaque.clone(),
+            ..protocol::Message::chat("", 1_700_000_000_000)
+        }
+        .with_seq(3)
+        .for_signing(keys.public.clone(), [5; protocol::HOP_TOKEN_LEN]);
+        let sig = crypto::sign_ed25519(&protocol::signing_bytes(&msg), &keys.pkcs8).unwrap();
+        let msg = msg.with_key_sig(keys.public.clone(), sig);
+        let received = protocol::encode(&msg);
+
+        let fwd = relayed_frame(&msg, true).unwrap();
+        let frame = relay_bytes(&fwd, &received, protocol::PROTOCOL_VERSION).unwrap();
+        // 本文は末尾にそのまま残る
+        assert!(frame.ends_with(&opaque));
+        assert_eq!(frame.len(), received.len());
+        let mut dec = protocol::Decoder::new();
+        dec.feed(&frame);
+        let next_hop = dec.drain().unwrap().remove(0);
+        assert_eq!(next_hop.attenuation, 1);
+        crypto::verify_ed25519(
+            &protocol::signing_bytes(&next_hop),
+            next_hop.signature.as_deref().unwrap(),
+            &keys.public,
+        )
+        .unwrap();
+        // 他人の v2 署名は v1 のピアへは流さない
+        assert_eq!(relay_bytes(&fwd, &received, 1), None);
+    }
+
+    #[test]
+    fn duplicate_detection_ignores_attenuation() {
+        let mut seen_messages: HashSet<[u8; 32]> = HashSet::new();
+        let mut seen_order: VecDeque<[u8; 32]> = VecDeque::new();
+        let mut msg = protocol::Message::chat("hello", 12345);
+        msg.public_key = Some(vec![7; 32]);
+        msg.signature = Some(vec![9; 64]);
+        assert!(!is_duplicate_message(
+            &msg,
+            &mut seen_messages,
+            &mut seen_order
+        ));
+
+        let mut replay = msg.clone();
+        replay.attenuation = 40;
+        assert!(is_duplicate_message(
+            &replay,
+            &mut seen_messages,
+            &mut seen_order
+        ));
+    }
+
+    #[test]
+    fn long_mention_in_body_is_not_a_handle_violation() {
+        let mention = format!("@{}", "m".repeat(120));
+        let body = format!("@alice: {} こんにちは", mention);
+        assert_eq!(signed_handle_field(&body), Some("@alice"));
+        assert_eq!(oversized_signed_handle(&body), None);
+
+        // 本文が "@mention ... : text" の形でもハンドル欄とはみなさない
+        let body = format!("{} : text", mention);
+        assert_eq!(signed_handle_field(&body), None);
+        assert_eq!(oversized_signed_handle(&body), None);
+    }
+
+    #[test]
+    fn oversized_handle_field_is_detected() {
+        let name = format!("@{}", "x".repeat(90));
+        let body = format!("{}: hi", name);
+        assert_eq!(oversized_signed_handle(&body), Some(91));
+    }
+
+    #[test]
+    fn rotation_signed_by_known_key_is_accepted() {
+        let old = crypto::generate_ed25519_keypair().unwrap();
+        let new = crypto::generate_ed25519_keypair().unwrap();
+        let msg = build_signed_rotation(&new.public, &old.pkcs8, &old.public).unwrap();
+        assert_eq!(verify_rotation(&msg, &old.public), Some(new.public.clone()));
+    }
+
+    #[test]
+    fn forged_rotation_is_rejected() {
+        let old = crypto::generate_ed25519_keypair().unwrap();
+        let attacker = crypto::generate_ed25519_keypair().unwrap();
+        // 攻撃者が自分の鍵で署名したローテーション
+        let msg =
+            build_signed_rotation(&attacker.public, &attacker.pkcs8, &attacker.public).unwrap();
+        assert_eq!(verify_rotation(&msg, &old.public), None);
+
+        // 公開鍵欄だけ旧鍵に差し替えても署名が合わない
+        let mut spoofed = msg.clone();
+        spoofed.public_key = Some(old.public.clone());
+        assert_eq!(verify_rotation(&spoofed, &old.public), None);
+    }
+
+    fn meta_with_key(pk: &[u8]) -> Option<PeerMeta> {
+        Some(PeerMeta {
+            public_key: pk.to_vec(),
+            last_valid: true,
+            last_timestamp: 0,
+            handle: Some("@alice".into()),
+            protocol_version: Some(protocol::PROTOCOL_VERSION),
+            bio: None,
+            next_seq: None,
+            state: rpc::PeerState::Ready,
+            handle_since: 0,
+            ping: None,
+            rtt_ms: None,
+        })
+    }
+
+    /// 接続を持たないピアの一覧（メタだけを見る関数のテスト用）
+    fn peers_with(metas: Vec<Option<PeerMeta>>) -> Vec<Peer<()>> {
+        metas
+            .into_iter()
+            .map(|meta| Peer {
+                meta,
+                ..Peer::new((), 0, None)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn second_connection_with_same_key_is_duplicate() {
+        let key = [7u8; 32];
+        // id=0 が既に同じ鍵で接続済み、id=1 が新しく HELLO を送ってきた
+        let mut peers = peers_with(vec![meta_with_key(&key), None, meta_with_key(&[8u8; 32])]);
+        assert_eq!(find_duplicate_identity(&peers, 1, &key, &[]), Some(0));
+        assert_eq!(find_duplicate_identity(&peers, 1, &[9u8; 32], &[]), None);
+        // HELLO 前の相手（中継された署名で鍵を覚えただけかもしれない）は重複に数えない
+        peers[0].meta.as_mut().unwrap().state = rpc::PeerState::Handshaking;
+        assert_eq!(find_duplicate_identity(&peers, 1, &key, &[]), None);
+    }
+
+    #[tokio::test]
+    async fn reconnect_with_same_key_replaces_the_stale_connection() {
+        use tokio::io::AsyncReadExt;
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler(tx_main, rx_cmd));
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let port = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && let Some(rest) = m.strip_prefix("待受開始 port=")
+            {
+                break rest.split(' ').next().unwrap().parse::<u16>().unwrap();
+            }
+        };
+
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let hello = build_signed_hello("@bob", None, &keys.pkcs8, &keys.public).unwrap();
+        // 古い接続は開いたまま何もしない（相手側では既に死んでいる想定）
+        let mut stale = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stale.write_all(&protocol::encode(&hello)).await.unwrap();
+        loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::HandshakeComplete { id, .. }) = ev {
+                assert_eq!(id, 0);
+                break;
+            }
+        }
+
+        let mut fresh = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        fresh.write_all(&protocol::encode(&hello)).await.unwrap();
+        let (mut replaced, mut accepted) = (false, false);
+        while !(replaced && accepted) {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            match ev {
+                Some(rpc::Event::PeerDisconnected { id, reason }) => {
+                    assert_eq!(id, 0);
+                    assert_eq!(reason, disconnect_reason_text(5));
+                    replaced = true;
+                }
+                Some(rpc::Event::HandshakeComplete { id, handle, .. }) => {
+                    assert_eq!((id, handle.as_str()), (1, "@bob"));
+                    accepted = true;
+                }
+                _ => {}
+            }
+        }
+
+        // 古い接続には理由付きの DISCONNECT が届いて閉じられる
+        let mut dec = protocol::Decoder::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = tokio::time::timeout(wait, stale.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(n > 0, "DISCONNECT が届かないまま閉じられた");
+            dec.feed(&buf[..n]);
+            if let Some(reason) = dec
+                .drain()
+                .unwrap()
+                .iter()
+                .find_map(protocol::disconnect_reason_id)
+            {
+                assert_eq!(reason, 5);
+                break;
+            }
+        }
+        // 新しい接続だけが残る
+        tx_cmd.send(rpc::Command::PeerList).await.unwrap();
+        let peers = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::PeerList { peers, .. }) = ev {
+                break peers;
+            }
+        };
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].handle.as_deref(), Some("@bob"));
+        assert_eq!(
+            fresh.local_addr().unwrap().to_string(),
+            crypto::decrypt_conninfo_from_hex(&peers[0].token).unwrap()
+        );
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
+
+    #[test]
+    fn reconnect_after_dead_socket_is_allowed() {
+        let key = [7u8; 32];
+        let peers = peers_with(vec![meta_with_key(&key), None]);
+        // 古い接続は今回の読み取りで切断検知済み
+        assert_eq!(find_duplicate_identity(&peers, 1, &key, &[0]), None);
+    }
+
+    fn signed_chat_with_author(
+        authors: &mut AuthorCache,
+    ) -> (crypto::Ed25519KeyPairMaterial, [u8; 8]) {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let chat =
+            build_signed_chat("@alice: typo", None, false, &keys.pkcs8, &keys.public, 1).unwrap();
+        let id = message_id(&chat).unwrap();
+        authors.remember(id, &keys.public);
+        (keys, id)
+    }
+
+    #[test]
+    fn edit_by_original_author_is_applied() {
+        let mut authors = AuthorCache::default();
+        let (keys, id) = signed_chat_with_author(&mut authors);
+        let edit =
+            build_signed_amend(&id, Some("@alice: fixed"), &keys.pkcs8, &keys.public, 1).unwrap();
+        assert_eq!(
+            authorize_amend(&edit, &mut authors),
+            Some((id, Some("@alice: fixed".to_string())))
+        );
+        let delete = build_signed_amend(&id, None, &keys.pkcs8, &keys.public, 1).unwrap();
+        assert_eq!(authorize_amend(&delete, &mut authors), Some((id, None)));
+    }
+
+    #[test]
+    fn edit_by_other_key_is_rejected() {
+        let mut authors = AuthorCache::default();
+        let (_, id) = signed_chat_with_author(&mut authors);
+        let other = crypto::generate_ed25519_keypair().unwrap();
+        let edit = build_signed_amend(&id, Some("@mallory: mine"), &other.pkcs8, &other.public, 1)
+            .unwrap();
+        assert_eq!(authorize_amend(&edit, &mut authors), None);
+
+        // 未知のIDへの編集も適用しない
+        let unknown = build_signed_amend(&[0u8; 8], None, &other.pkcs8, &other.public, 1).unwrap();
+        assert_eq!(authorize_amend(&unknown, &mut authors), None);
+    }
+
+    #[test]
+    fn message_id_ignores_attenuation() {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let chat =
+            build_signed_chat("@alice: hi", None, false, &keys.pkcs8, &keys.public, 1).unwrap();
+        let mut relayed = chat.clone();
+        relayed.attenuation = 3;
+        assert_eq!(message_id(&chat), message_id(&relayed));
+        assert_eq!(message_id(&protocol::Message::chat("x", 1)), None);
+    }
+
+    #[test]
+    fn reply_is_signed_over_reply_to() {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let target = [5u8; 8];
+        let reply = build_signed_chat(
+            "@bob: re",
+            Some(&target),
+            false,
+            &keys.pkcs8,
+            &keys.public,
+            1,
+        )
+        .unwrap();
+        assert_eq!(protocol::reply_target(&reply), Some(target));
+        let sig = reply.signature.clone().unwrap();
+        assert!(verify_signed_message(&reply, &sig, &keys.public));
+
+        // 返信先を差し替えると署名が合わない
+        let mut forged = reply.clone();
+        forged.payload[0] ^= 1;
+        assert!(!verify_signed_message(&forged, &sig, &keys.public));
+    }
+
+    #[test]
+    fn tampered_attenuation_breaks_the_signature() {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let chat =
+            build_signed_chat("@alice: hi", None, false, &keys.pkcs8, &keys.public, 1).unwrap();
+        let sig = chat.signature.clone().unwrap();
+        assert_eq!(chat.version, protocol::PROTOCOL_VERSION);
+
+        // 正規の中継は何段進めても検証できる
+        let mut fwd = chat.clone();
+        for _ in 0..3 {
+            fwd = relayed_frame(&fwd, true).unwrap();
+        }
+        assert_eq!(fwd.attenuation, 3);
+        assert!(verify_signed_message(&fwd, &sig, &keys.public));
+        let mut d = protocol::Decoder::new();
+        d.feed(&protocol::encode(&fwd));
+        assert_eq!(d.drain().unwrap(), vec![fwd.clone()]);
+
+        // 減衰値だけを戻す・進める書き換えは弾かれる
+        let mut lowered = fwd.clone();
+        lowered.attenuation = 0;
+        assert!(!verify_signed_message(&lowered, &sig, &keys.public));
+        let mut raised = fwd.clone();
+        raised.attenuation = 40;
+        assert!(!verify_signed_message(&raised, &sig, &keys.public));
+
+        // 通し番号の付け替えも弾かれる
+        let mut reseq = fwd.clone();
+        reseq.seq = Some(2);
+        assert!(!verify_signed_message(&reseq, &sig, &keys.public));
+
+        // 公開鍵欄の差し替えも署名対象
+        let other = crypto::generate_ed25519_keypair().unwrap();
+        let mut swapped = fwd.clone();
+        swapped.public_key = Some(other.public.clone());
+        assert!(!verify_signed_message(&swapped, &sig, &keys.public));
+    }
+
+    #[test]
+    fn v1_peers_get_own_posts_resigned_and_nothing_else_signed_v2() {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let own = Some((keys.pkcs8.as_slice(), keys.public.as_slice()));
+        let chat =
+            build_signed_chat("@alice: hi", None, false, &keys.pkcs8, &keys.public, 1).unwrap();
+        let decode = |frame: Vec<u8>| {
+            let mut d = protocol::Decoder::new();
+            d.feed(&frame);
+            d.drain().unwrap().remove(0)
+        };
+
+        let v2 = decode(frame_for_peer(&chat, protocol::PROTOCOL_VERSION, own).unwrap());
+        assert_eq!(v2, chat);
+        let v1 = decode(frame_for_peer(&chat, protocol::MIN_PROTOCOL_VERSION, own).unwrap());
+        assert_eq!(v1.version, protocol::MIN_PROTOCOL_VERSION);
+        assert_eq!(v1.hop, None);
+        assert!(verify_signed_message(
+            &v1,
+            v1.signature.as_ref().unwrap(),
+            &keys.public
+        ));
+        // 同じ投稿として扱われる
+        assert_eq!(message_id(&v1), message_id(&chat));
+        let mut ledger = IdLedger::default();
+        ledger.record(&chat);
+        assert!(ledger.knows(&v1));
+        assert_eq!(ledger.conflicting(&v1), None);
+
+        // 他人の v2 署名は v1 のピアへ送れない。署名の無いものは版を下げるだけ
+        assert_eq!(
+            frame_for_peer(&chat, protocol::MIN_PROTOCOL_VERSION, None),
+            None
+        );
+        let disc = protocol::Message::disconnect(1, 2);
+        assert_eq!(
+            frame_for_peer(&disc, protocol::MIN_PROTOCOL_VERSION, None),
+            Some(protocol::encode(&disc))
+        );
+    }
+
+    #[test]
+    fn silent_peer_is_dropped_after_idle_timeout() {
+        let now = 10_000;
+        // id=0 は 5 秒前に受信、id=1 は 0.5 秒前に受信
+        let peers = [
+            Peer::new((), now - 5_000, None),
+            Peer::new((), now - 500, None),
+        ];
+        assert_eq!(idle_peers(&peers, now, 1_000), vec![0]);
+        assert_eq!(idle_peers(&peers, now + 1_000, 1_000), vec![0, 1]);
+        // 0 は無効
+        assert!(idle_peers(&peers, now, 0).is_empty());
+    }
+
+    #[test]
+    fn only_peers_that_can_be_pinged_time_out_after_hello() {
+        let mut peers = peers_with(vec![
+            meta_with_key(&[1u8; 32]),
+            meta_with_key(&[2u8; 32]),
+            None,
+        ]);
+        peers[1].meta.as_mut().unwrap().protocol_version = Some(1);
+        assert!(can_ping(&peers, 0) && may_time_out(&peers, 0));
+        // v1 のピアは PING を知らないので、黙っていても切らない
+        assert!(!can_ping(&peers, 1) && !may_time_out(&peers, 1));
+        // HELLO 前の相手は今まで通り切る
+        assert!(!can_ping(&peers, 2) && may_time_out(&peers, 2));
+    }
+
+    #[test]
+    fn leaf_node_does_not_forward_received_frames() {
+        let msg = protocol::Message::chat("@alice: hi", 1);
+        assert_eq!(relayed_frame(&msg, false), None);
+        let fwd = relayed_frame(&msg, true).unwrap();
+        assert_eq!(fwd.attenuation, 1);
+        // DM は中継 ON でも転送しない
+        assert_eq!(relayed_frame(&protocol::Message::dm("x", 1), true), None);
+    }
+
+    #[test]
+    fn mutated_relayed_payload_is_flagged() {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let original =
+            build_signed_chat("@alice: 10円", None, false, &keys.pkcs8, &keys.public, 1).unwrap();
+        let mut ledger = IdLedger::default();
+        assert_eq!(ledger.conflicting(&original), None);
+        ledger.record(&original);
+
+        // 中継で減衰値が変わっただけなら同じ版
+        let mut relayed = original.clone();
+        relayed.attenuation = 2;
+        assert_eq!(ledger.conflicting(&relayed), None);
+
+        // 中継ノードが本文を書き換えた版
+        let mut mutated = relayed.clone();
+        mutated.payload = "@alice: 1000円".as_bytes().to_vec();
+        assert_eq!(ledger.conflicting(&mutated), message_id(&original));
+        let sig = mutated.signature.clone().unwrap();
+        assert!(!verify_signed_message(&mutated, &sig, &keys.public));
+    }
+
+    #[test]
+    fn two_valid_posts_in_the_same_millisecond_are_not_flagged() {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let at = |text: &str| {
+            sign_message(
+                protocol::Message::chat(text, 1_700_000_000_000),
+                &keys.pkcs8,
+                &keys.public,
+            )
+            .unwrap()
+        };
+        let (first, second) = (at("@alice: 1つめ"), at("@alice: 2つめ"));
+        let mut ledger = IdLedger::default();
+        ledger.record(&first);
+        assert!(!ledger.knows(&second));
+        ledger.record(&second);
+
+        // どちらの写しが別の経路から届いても重複として分かる
+        assert!(ledger.knows(&first));
+        assert!(ledger.knows(&second));
+        assert_eq!(ledger.conflicting(&first), None);
+        assert_eq!(ledger.conflicting(&second), None);
+    }
+
+    #[test]
+    fn signed_dm_payload_is_binary_safe() {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let plain = "@alice: こんにちは";
+        let msg = build_signed_dm(
+            &mut crypto::NonceSequence::new(),
+            plain,
+            false,
+            &keys.pkcs8,
+            &keys.public,
+        )
+        .unwrap();
+
+        assert_eq!(msg.kind, protocol::MsgKind::DM);
+        let decrypted = crypto::decrypt_dm_payload(&msg.payload).unwrap();
+        assert_eq!(String::from_utf8_lossy(&decrypted), plain);
+    }
+
+    #[tokio::test]
+    async fn queued_chat_is_sent_once_a_peer_connects() {
+        use tokio::io::AsyncReadExt;
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(8);
+        let mut authors = AuthorCache::default();
+        let mut outbox = Outbox::default();
+
+        // 未接続の間は取り出されない
+        outbox.push("offline".into(), None, false);
+        assert!(outbox.take_ready(0).is_empty());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (mut peer, _) = accepted.unwrap();
+
+        // つないだだけで HELLO を済ませていない相手には送らない
+        let mut peers = vec![Peer::new(client.unwrap(), 0, None)];
+        assert!(outbox.take_ready(ready_peers(&peers)).is_empty());
+
+        peers[0].meta = meta_with_key(&[1; 32]);
+        let ready = outbox.take_ready(ready_peers(&peers));
+        assert_eq!(ready.len(), 1);
+        for (text, reply_to, urgent) in ready {
+            let failed = send_chat(
+                &text,
+                reply_to,
+                urgent,
+                "@me",
+                (&keys.pkcs8, &keys.public),
+                &mut authors,
+                &mut SendSeq::default(),
+                &mut peers,
+                &tx_main,
+            )
+            .await;
+            assert!(failed.is_empty());
+        }
+        assert!(outbox.take_ready(1).is_empty());
+
+        let mut buf = [0u8; 2048];
+        let n = peer.read(&mut buf).await.unwrap();
+        let mut decoder = protocol::Decoder::new();
+        decoder.feed(&buf[..n]);
+        let msgs = decoder.drain().unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(protocol::chat_text(&msgs[0]), b"@me: offline");
+        assert!(matches!(
+            rx_main.recv().await,
+            Some(rpc::Event::Sent { id })
+                if message_id(&msgs[0]).is_some_and(|m| crypto::to_hex(&m) == id)
+        ));
+    }
+
+    #[tokio::test]
+    async fn amend_is_sent_only_to_ready_peers() {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let (tx_main, _rx) = tokio::sync::mpsc::channel(8);
+        let net = transport::Memory::default();
+        let listener = net.bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (mut peers, mut remotes) = (Vec::new(), Vec::new());
+        for _ in 0..2 {
+            remotes.push(net.connect(&addr).await.unwrap());
+            peers.push(Peer::new(listener.accept().await.unwrap().0, 0, None));
+        }
+        // 0 は HELLO 済み、1 はまだ
+        peers[0].meta = meta_with_key(&[1; 32]);
+        let target = [5u8; protocol::MESSAGE_ID_LEN];
+        let mut authors = AuthorCache::default();
+        authors.remember(target, &keys.public);
+
+        let failed = amend_own(
+            &crypto::to_hex(&target),
+            Some("@me: edited"),
+            Some((&keys.pkcs8, &keys.public)),
+            &mut authors,
+            &mut SendSeq::default(),
+            &mut peers,
+            &tx_main,
+        )
+        .await;
+        assert!(failed.is_empty());
+
+        let mut buf = [0u8; 1024];
+        let n = remotes[0].try_read(&mut buf).unwrap();
+        let mut dec = protocol::Decoder::new();
+        dec.feed(&buf[..n]);
+        let msgs = dec.drain().unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].kind, protocol::MsgKind::EDIT);
+        assert!(remotes[1].try_read(&mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn dial_any_keeps_the_live_address() {
+        // 閉じたポート（接続拒否）と待受中のポート
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_addr = dead.local_addr().unwrap().to_string();
+        drop(dead);
+        let live = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live_addr = live.local_addr().unwrap().to_string();
+
+        let token =
+            crypto::encrypt_conninfo_to_hex(&format!("{},{}", dead_addr, live_addr)).unwrap();
+        let target = crypto::decrypt_conninfo_from_hex(&token).unwrap();
+        let (stream, addr) = dial_any(&transport::Tcp, &target, Duration::from_secs(2))
+            .await
+            .unwrap();
+        assert_eq!(addr, live_addr);
+        assert_eq!(stream.peer_addr().unwrap().to_string(), live_addr);
+
+        let err = dial_any(&transport::Tcp, &dead_addr, Duration::from_secs(2))
+            .await
+            .unwrap_err();
+        assert!(err.starts_with(&dead_addr));
+    }
+
+    #[tokio::test]
+    async fn malformed_frame_disconnects_peer_with_reason() {
+        use tokio::io::AsyncReadExt;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let client = client.unwrap();
+        let (mut peer, _) = accepted.unwrap();
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(8);
+
+        // 減衰値が上限を超えたフレーム
+        let mut frame = protocol::encode(&protocol::Message::chat("hi", 1));
+        frame[2] = u8::MAX;
+        let mut decoder = protocol::Decoder::new();
+        decoder.feed(&frame);
+        let err = decoder.drain().unwrap_err();
+        // 同じバイト列が残るので何度でも失敗する
+        assert!(decoder.drain().is_err());
+
+        let mut mallory = Peer::new(client, 0, None);
+        mallory.meta = Some(PeerMeta {
+            public_key: vec![7; 32],
+            last_valid: true,
+            last_timestamp: 0,
+            handle: Some("@mallory".into()),
+            protocol_version: None,
+            bio: None,
+            next_seq: None,
+            state: rpc::PeerState::Handshaking,
+            handle_since: 0,
+            ping: None,
+            rtt_ms: None,
+        });
+        drop_malformed_peer(0, &err, &mut mallory, &tx_main).await;
+
+        let mut buf = [0u8; 256];
+        let n = peer.read(&mut buf).await.unwrap();
+        let mut dec = protocol::Decoder::new();
+        dec.feed(&buf[..n]);
+        let msgs = dec.drain().unwrap();
+        assert_eq!(protocol::disconnect_reason_id(&msgs[0]), Some(7));
+        assert!(matches!(
+            rx_main.recv().await,
+            Some(rpc::Event::Message(m)) if m.contains("id=0 @mallory")
+        ));
+    }
+
+    #[test]
+    fn peer_address_differing_from_token_is_reported() {
+        let expected: Vec<SocketAddr> = vec!["192.168.0.5:2234".parse().unwrap()];
+        assert_eq!(
+            address_mismatch(&expected, "192.168.0.5:2234".parse().unwrap()),
+            None
+        );
+        // IPv4 射影 IPv6 は同じ相手
+        assert_eq!(
+            address_mismatch(&expected, "[::ffff:192.168.0.5]:2234".parse().unwrap()),
+            None
+        );
+        let warn = address_mismatch(&expected, "10.0.0.9:2234".parse().unwrap()).unwrap();
+        assert!(warn.contains("192.168.0.5:2234") && warn.contains("10.0.0.9:2234"));
+        // 名前解決できなかったときは判定しない
+        assert_eq!(
+            address_mismatch(&[], "10.0.0.9:2234".parse().unwrap()),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn late_joiner_receives_current_topic() {
+        use tokio::io::AsyncReadExt;
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let mut topic = RoomTopic::default();
+        assert_eq!(topic.replay_frame(protocol::PROTOCOL_VERSION), None);
+        let m = build_signed_topic("今日の話題", &keys.pkcs8, &keys.public, 1).unwrap();
+        assert!(matches!(topic.accept(&m), TopicUpdate::Accepted(..)));
+
+        // トピック設定後に接続してきたピアへ HELLO 受信時に再送する
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let mut late = client.unwrap();
+        let (mut to_late, _) = accepted.unwrap();
+        to_late
+            .write_all(&topic.replay_frame(protocol::PROTOCOL_VERSION).unwrap())
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 512];
+        let n = late.read(&mut buf).await.unwrap();
+        let mut decoder = protocol::Decoder::new();
+        decoder.feed(&buf[..n]);
+        let msgs = decoder.drain().unwrap();
+        let mut joiner = RoomTopic::default();
+        let by = crypto::fingerprint_hex(&keys.public)[..16].to_string();
+        assert_eq!(
+            joiner.accept(&msgs[0]),
+            TopicUpdate::Accepted("今日の話題".into(), by)
+        );
+        // 同じトピックが別経路で届いても採用し直さない
+        assert_eq!(joiner.accept(&msgs[0]), TopicUpdate::Stale);
+
+        // 署名なし・長すぎるものは拒否
+        let mut unsigned = protocol::Message::topic(current_unix_millis() + 1, "x");
+        assert_eq!(joiner.accept(&unsigned), TopicUpdate::Rejected);
+        unsigned.payload = "x".repeat(protocol::MAX_TOPIC_CHARS + 1).into_bytes();
+        let data = protocol::signing_bytes(&unsigned);
+        let sig = crypto::sign_ed25519(&data, &keys.pkcs8).unwrap();
+        let long = unsigned.with_key_sig(keys.public.clone(), sig);
+        assert_eq!(joiner.accept(&long), TopicUpdate::Rejected);
+    }
+
+    #[test]
+    fn hex_dump_breaks_rows_every_16_bytes() {
+        let bytes: Vec<u8> = (0..20).collect();
+        assert_eq!(
+            hex_dump(&bytes),
+            "0000: 000102030405060708090a0b0c0d0e0f\n0010: 10111213"
+        );
+    }
+
+    #[test]
+    fn ephemeral_dm_leaves_no_record() {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let db = crate::storage::tests::temp_db();
+        for ephemeral in [true, false] {
+            let m = build_signed_dm(
+                &mut crypto::NonceSequence::new(),
+                "@alice: secret",
+                ephemeral,
+                &keys.pkcs8,
+                &keys.public,
+            )
+            .unwrap();
+            // 送信側・受信側の両方
+            let sent = dm_record(
+                &m,
+                None,
+                Some(0),
+                None,
+                "sent".into(),
+                rpc::SigState::Valid,
+                None,
+            );
+            let recv = dm_record(
+                &m,
+                Some(0),
+                None,
+                None,
+                "recv".into(),
+                rpc::SigState::Valid,
+                None,
+            );
+            for rec in sent.into_iter().chain(recv) {
+                db.store_structured(&rec, None).unwrap();
+            }
+        }
+        let day = chrono::Utc::now().format("%Y%m%d").to_string();
+        let stored = db.load_structured_day(&day);
+        // 保存されたのは通常の DM の2件だけ
+        assert_eq!(stored.len(), 2);
+        assert!(stored.iter().all(|r| r.kind == crate::storage::MsgKind::Dm));
+    }
+
+    #[tokio::test]
+    async fn token_after_open_matches_bound_address() {
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = l.local_addr().unwrap().port();
+        let out = listener_tokens(port, None);
+        let tok = out.split("token=").nth(1).unwrap().trim_end_matches(')');
+        assert_eq!(
+            crypto::decrypt_conninfo_from_hex(tok).unwrap(),
+            l.local_addr().unwrap().to_string()
+        );
+
+        // マッピング中は外部アドレスのトークンも出す
+        let m = nat::Mapping {
+            gateway: "192.168.0.1:5351".parse().unwrap(),
+            external_ip: std::net::Ipv4Addr::new(203, 0, 113, 7),
+            internal_port: port,
+            external_port: 40000,
+            lifetime_secs: 7200,
+        };
+        let out = listener_tokens(port, Some(&m));
+        let external = out.lines().nth(1).unwrap();
+        let tok = external
+            .split("token=")
+            .nth(1)
+            .unwrap()
+            .trim_end_matches(')');
+        assert_eq!(
+            crypto::decrypt_conninfo_from_hex(tok).unwrap(),
+            "203.0.113.7:40000"
+        );
+    }
+
+    #[tokio::test]
+    async fn token_command_repeats_the_open_token() {
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(32);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler(tx_main, rx_cmd));
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        tx_cmd.send(rpc::Command::Token).await.unwrap();
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+
+        let mut addrs = Vec::new();
+        while let Ok(rpc::Event::Message(m)) = rx_main.try_recv() {
+            if let Some(rest) = m.split("token=").nth(1) {
+                let tok = rest.trim_end_matches(')');
+                addrs.push(crypto::decrypt_conninfo_from_hex(tok).unwrap());
             }
         }
+        // 待受開始時のトークンと /token のトークンが同じアドレスを指す
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[0], addrs[1]);
+        assert!(addrs[0].starts_with("127.0.0.1:") && !addrs[0].ends_with(":0"));
+    }
 
-        // accept
-        if let Some(l) = &listener {
-            match l.accept().await {
-                Ok((s, peer)) => {
-                    clients.push(s);
-                    decoders.push(protocol::Decoder::new());
-                    peer_meta.push(None);
-                    // 受け入れ側も公開鍵を送信
-                    let id = clients.len() - 1;
-                    if let (Some(pubk), Some(pk)) = (public.as_ref(), pkcs8.as_ref()) {
-                        if let Some(hello) = build_signed_hello(&handle, pk, pubk) {
-                            let frame = protocol::encode(&hello);
-                            let _ = clients[id].write_all(&frame).await;
-                        }
-                    }
-                    let token = crypto::encrypt_conninfo_to_hex(&peer.to_string())
-                        .unwrap_or_else(|_| "?".to_string());
-                    tx_main
-                        .send(rpc::Event::Message(format!(
-                            "接続受入 (token={}) id={}",
-                            token, id
-                        )))
-                        .await
-                        .ok();
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-                Err(e) => {
-                    tx_main
-                        .send(rpc::Event::Message(format!("受け入れエラー: {:?}", e)))
-                        .await
-                        .ok();
-                }
+    #[tokio::test]
+    async fn scheduled_timer_fires_after_its_interval() {
+        let tbl: toml::Table = "idle_timeout_secs = 3\n".parse().unwrap();
+        let timers = Timers::from_config(&tbl);
+        assert_eq!(timers.idle_timeout_ms, 3000);
+        assert_eq!(timers.ping_every_ms(), 1000);
+        // 指定があればそちらの間隔で PING する
+        let tbl: toml::Table = "idle_timeout_secs = 3\nping_interval_secs = 2\n"
+            .parse()
+            .unwrap();
+        assert_eq!(Timers::from_config(&tbl).ping_every_ms(), 2000);
+
+        let mut scheduler = Scheduler::default();
+        scheduler.every(TimerKind::IdleCheck, 20, current_unix_millis());
+        // 無効 (0) のタイマーは登録されない
+        scheduler.every(TimerKind::IdleCheck, 0, current_unix_millis());
+        assert!(scheduler.due(current_unix_millis()).is_empty());
+        sleep(Duration::from_millis(30)).await;
+        assert_eq!(
+            scheduler.due(current_unix_millis()),
+            vec![TimerKind::IdleCheck]
+        );
+        // 発火したら次の期限まで出ない
+        assert!(scheduler.due(current_unix_millis()).is_empty());
+    }
+
+    #[test]
+    fn middle_node_forwards_dm_for_someone_else() {
+        let sender = crypto::generate_ed25519_keypair().unwrap();
+        let recipient = crypto::generate_ed25519_keypair().unwrap();
+        let middle = crypto::generate_ed25519_keypair().unwrap();
+        let to = route_fingerprint(&recipient.public);
+        let msg = build_signed_routed_dm(
+            &mut crypto::NonceSequence::new(),
+            &to,
+            "@alice: 内緒",
+            &sender.pkcs8,
+            &sender.public,
+        )
+        .unwrap();
+
+        // 中間ノードは復号も保存もせず、中継だけする（減衰値で TTL を数える）
+        assert_eq!(
+            route_dm(&msg, Some(&middle.public), true),
+            RoutedDm::Forward
+        );
+        assert_eq!(relayed_frame(&msg, true).unwrap().attenuation, 1);
+        assert_eq!(route_dm(&msg, Some(&middle.public), false), RoutedDm::Drop);
+
+        let RoutedDm::ForMe(encrypted) = route_dm(&msg, Some(&recipient.public), false) else {
+            panic!("宛先で受け取れない");
+        };
+        let plain = crypto::decrypt_dm_payload(encrypted).unwrap();
+        assert_eq!(String::from_utf8_lossy(&plain), "@alice: 内緒");
+        assert_eq!(
+            parse_route_fingerprint(&crypto::fingerprint_hex(&recipient.public)[..16]),
+            Some(to)
+        );
+    }
+
+    #[test]
+    fn system_frame_round_trips_through_decoder_and_storage() {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let m = build_signed_system("@alice が参加しました", &keys.pkcs8, &keys.public, 1).unwrap();
+        let mut decoder = protocol::Decoder::new();
+        decoder.feed(&protocol::encode(&m));
+        let decoded = decoder.drain().unwrap().remove(0);
+        assert_eq!(decoded, m);
+
+        let line = system_line(&decoded).unwrap();
+        assert!(line.starts_with("[通知] @alice が参加しました (指紋="));
+        let db = crate::storage::tests::temp_db();
+        let day = chrono::DateTime::from_timestamp_millis(decoded.timestamp as i64)
+            .unwrap()
+            .format("%Y%m%d")
+            .to_string();
+        db.store_structured(&system_record(&decoded, line.clone()), None)
+            .unwrap();
+        let stored = db.load_structured_day(&day);
+        assert_eq!(stored[0].kind, crate::storage::MsgKind::System);
+        assert_eq!(stored[0].text, line);
+
+        // 署名なし・改ざん・長すぎる本文は表示しない
+        assert_eq!(system_line(&protocol::Message::system(1, "偽の通知")), None);
+        let mut forged = decoded.clone();
+        forged.payload = "@mallory が参加しました".as_bytes().to_vec();
+        assert_eq!(system_line(&forged), None);
+        let long = "x".repeat(protocol::MAX_SYSTEM_CHARS + 1);
+        let m = build_signed_system(&long, &keys.pkcs8, &keys.public, 1).unwrap();
+        assert_eq!(system_line(&m), None);
+    }
+
+    #[test]
+    fn new_node_learns_peers_through_introduction() {
+        let a = crypto::generate_ed25519_keypair().unwrap();
+        let b = crypto::generate_ed25519_keypair().unwrap();
+        let c = crypto::generate_ed25519_keypair().unwrap();
+
+        // B (advertise = true) が A に接続して待受アドレスを広告する
+        let advert = build_signed_advert("127.0.0.1:4001", &b.pkcs8, &b.public).unwrap();
+        let mut dir_a = Directory::default();
+        let fp_b = crypto::fingerprint_hex(&b.public)[..16].to_string();
+        let addr_b: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        assert_eq!(
+            dir_a.accept(&advert),
+            AdvertUpdate::Added(fp_b.clone(), addr_b)
+        );
+        assert_eq!(dir_a.accept(&advert), AdvertUpdate::Stale);
+        // 本人には自分を紹介しない
+        assert!(
+            dir_a
+                .introductions_for(&b.public, protocol::PROTOCOL_VERSION)
+                .is_empty()
+        );
+
+        // C が A に接続すると、A は B の広告を署名ごと転送する
+        let mut decoder = protocol::Decoder::new();
+        for frame in dir_a.introductions_for(&c.public, protocol::PROTOCOL_VERSION) {
+            decoder.feed(&frame);
+        }
+        let mut dir_c = Directory::default();
+        for m in decoder.drain().unwrap() {
+            assert_eq!(dir_c.accept(&m), AdvertUpdate::Added(fp_b.clone(), addr_b));
+        }
+        let listing = dir_c.describe();
+        let line = listing.lines().nth(1).unwrap();
+        assert!(line.starts_with(&format!("指紋={} 127.0.0.1:4001 (token=", fp_b)));
+        let token = line.rsplit("token=").next().unwrap().trim_end_matches(')');
+        assert_eq!(
+            crypto::decrypt_conninfo_from_hex(token).unwrap(),
+            "127.0.0.1:4001"
+        );
+
+        // A がアドレスを書き換えたり、署名のない広告を作ったりしても受け付けない
+        let mut forged = advert.clone();
+        forged.payload = b"203.0.113.9:4001".to_vec();
+        assert_eq!(dir_c.accept(&forged), AdvertUpdate::Rejected);
+        let unsigned = protocol::Message::advert(1, "127.0.0.1:4002");
+        assert_eq!(dir_c.accept(&unsigned), AdvertUpdate::Rejected);
+        let bogus = build_signed_advert("not an addr", &a.pkcs8, &a.public).unwrap();
+        assert_eq!(dir_c.accept(&bogus), AdvertUpdate::Rejected);
+    }
+
+    /// 決めた順にエラーを返し、尽きたら書き込みを受け付ける書き込み先
+    struct FlakyWriter {
+        errors: VecDeque<std::io::ErrorKind>,
+        written: Vec<u8>,
+    }
+
+    impl FlakyWriter {
+        fn write_some(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if let Some(kind) = self.errors.pop_front() {
+                return Err(kind.into());
             }
+            // 部分書き込みも起こるよう 3 バイトずつ受け取る
+            let n = buf.len().min(3);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
         }
+    }
 
-        // 読み取り (バイナリプロトコル優先)
-        let mut received_frames: Vec<(usize, protocol::Message)> = Vec::new();
-        let mut remove_indices: Vec<usize> = Vec::new();
-        for (idx, c) in clients.iter_mut().enumerate() {
-            match c.try_read(&mut buf) {
-                Ok(0) => {
-                    tx_main
-                        .send(rpc::Event::Message(format!(
-                            "クライアント {} が切断しました",
-                            idx
-                        )))
-                        .await
-                        .ok();
-                    remove_indices.push(idx);
-                }
-                Ok(n) => {
-                    if n > 0 {
-                        decoders[idx].feed(&buf[..n]);
-                        match decoders[idx].drain() {
-                            Ok(mut msgs) => {
-                                for m in msgs.drain(..) {
-                                    received_frames.push((idx, m));
-                                }
-                            }
-                            Err(e) => {
-                                tx_main
-                                    .send(rpc::Event::Message(format!(
-                                        "プロトコルエラー {}: {}",
-                                        idx, e
-                                    )))
-                                    .await
-                                    .ok();
-                                remove_indices.push(idx);
-                            }
-                        }
-                    }
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-                Err(e) => {
-                    tx_main
-                        .send(rpc::Event::Message(format!("受信エラー {}: {:?}", idx, e)))
-                        .await
-                        .ok();
-                    remove_indices.push(idx);
-                }
+    impl Connection for FlakyWriter {
+        fn try_read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::WouldBlock.into())
+        }
+
+        fn try_write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.write_some(buf)
+        }
+
+        fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+            Err(std::io::ErrorKind::NotConnected.into())
+        }
+
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            Err(std::io::ErrorKind::NotConnected.into())
+        }
+    }
+
+    impl tokio::io::AsyncRead for FlakyWriter {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Pending
+        }
+    }
+
+    impl tokio::io::AsyncWrite for FlakyWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::task::Poll::Ready(self.write_some(buf))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn transient_write_failure_is_retried_in_order() {
+        use std::io::ErrorKind;
+        let mut w = FlakyWriter {
+            errors: VecDeque::from([ErrorKind::WouldBlock]),
+            written: Vec::new(),
+        };
+        let mut q = SendQueue::default();
+        let first = protocol::encode(&protocol::Message::chat("first", 1));
+        let second = protocol::encode(&protocol::Message::chat("second", 2));
+        // 1 回目は一時的な失敗でキューに残り、後続も順番待ちになる
+        assert_eq!(q.send(&mut w, &first), Flush::Pending);
+        assert_eq!(q.send(&mut w, &second), Flush::Pending);
+        assert!(w.written.is_empty());
+        // 次のループで両方とも順番どおりに書ける
+        assert_eq!(q.flush(&mut w), Flush::Done);
+        assert!(q.is_empty());
+        assert_eq!(w.written, [first.clone(), second].concat());
+
+        // 失敗が続けば上限で諦める
+        w.errors =
+            std::iter::repeat_n(ErrorKind::TimedOut, MAX_SEND_RETRIES as usize + 1).collect();
+        assert_eq!(q.send(&mut w, &first), Flush::Pending);
+        for _ in 1..MAX_SEND_RETRIES {
+            assert_eq!(q.flush(&mut w), Flush::Pending);
+        }
+        assert_eq!(q.flush(&mut w), Flush::Drop(ErrorKind::TimedOut));
+
+        // 相手が切断済みなら再送せずすぐ切断
+        let mut w = FlakyWriter {
+            errors: VecDeque::from([ErrorKind::BrokenPipe]),
+            written: Vec::new(),
+        };
+        let mut q = SendQueue::default();
+        assert_eq!(q.send(&mut w, &first), Flush::Drop(ErrorKind::BrokenPipe));
+    }
+
+    #[tokio::test]
+    async fn send_queue_does_not_wait_for_a_peer_that_stopped_reading() {
+        use tokio::io::AsyncReadExt;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let mut w = client.unwrap();
+        let (mut reader, _) = accepted.unwrap();
+
+        // 相手は読まないので、カーネルのバッファが埋まったところで書けなくなる
+        let frame = vec![7u8; 64 * 1024];
+        let mut q = SendQueue::default();
+        let mut sent = 0;
+        while q.send(&mut w, &frame) == Flush::Done {
+            sent += 1;
+            assert!(sent < 10_000, "送信バッファが埋まらない");
+        }
+        assert!(q.queued_bytes() > 0);
+        // 何度流そうとしても待たずに戻り、失敗にも数えない
+        for _ in 0..=MAX_SEND_RETRIES {
+            assert_eq!(q.flush(&mut w), Flush::Pending);
+        }
+
+        // 読み始めれば残りも順番どおりに書ける
+        let total = (sent + 1) * frame.len();
+        let drain = tokio::spawn(async move {
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut got = 0;
+            while got < total {
+                let n = reader.read(&mut buf).await.unwrap();
+                assert!(n > 0 && buf[..n].iter().all(|&b| b == 7));
+                got += n;
             }
+        });
+        while q.flush(&mut w) == Flush::Pending {
+            sleep(Duration::from_millis(5)).await;
         }
+        assert!(q.is_empty());
+        tokio::time::timeout(Duration::from_secs(5), drain)
+            .await
+            .unwrap()
+            .unwrap();
+    }
 
-        // 中継と表示 + 署名検証
-        for (src, msg) in received_frames.iter() {
-            if (msg.kind == protocol::MsgKind::CHAT || msg.kind == protocol::MsgKind::DM)
-                && is_duplicate_message(msg, &mut seen_messages, &mut seen_order)
+    #[tokio::test]
+    async fn connections_are_accepted_on_every_listener() {
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler(tx_main, rx_cmd));
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+
+        let wait = Duration::from_secs(5);
+        let mut ports = Vec::new();
+        while ports.len() < 2 {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && let Some(rest) = m.strip_prefix("待受開始 port=")
             {
-                continue;
+                ports.push(rest.split(' ').next().unwrap().parse::<u16>().unwrap());
             }
-            // テキスト復号/デコード
-            let txt = if msg.kind == protocol::MsgKind::DM {
-                match crypto::decrypt_dm_payload(&msg.payload) {
-                    Ok(p) => String::from_utf8_lossy(&p).to_string(),
-                    Err(_) => "<DM復号エラー>".to_string(),
+        }
+        assert_ne!(ports[0], ports[1]);
+        let _a = TcpStream::connect(("127.0.0.1", ports[0])).await.unwrap();
+        let _b = TcpStream::connect(("127.0.0.1", ports[1])).await.unwrap();
+
+        // 両方の待受で受け入れ、どちらに来たかが /peers に出る
+        let peers = loop {
+            tx_cmd.send(rpc::Command::PeerList).await.unwrap();
+            let (listening, peers) = loop {
+                let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+                if let Some(rpc::Event::PeerList { listening, peers }) = ev {
+                    break (listening, peers);
                 }
-            } else {
-                String::from_utf8_lossy(&msg.payload).to_string()
             };
-            let mut signed_state = if msg.signature.is_some() {
-                "○"
-            } else {
-                "・"
+            let mut expected = ports.clone();
+            expected.sort_unstable();
+            assert_eq!(listening, expected);
+            if peers.len() == 2 {
+                break peers;
+            }
+            sleep(Duration::from_millis(20)).await;
+        };
+        let mut via: Vec<Option<u16>> = peers.iter().map(|p| p.via_port).collect();
+        via.sort_unstable();
+        let mut expected: Vec<Option<u16>> = ports.iter().copied().map(Some).collect();
+        expected.sort_unstable();
+        assert_eq!(via, expected);
+
+        // /close <port> はその待受だけを閉じる
+        tx_cmd
+            .send(rpc::Command::Close(Some(ports[0])))
+            .await
+            .unwrap();
+        tx_cmd.send(rpc::Command::PeerList).await.unwrap();
+        let listening = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::PeerList { listening, .. }) = ev {
+                break listening;
+            }
+        };
+        assert_eq!(listening, vec![ports[1]]);
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn token_saved_by_open_round_trips_through_connect_file() {
+        let path = std::env::temp_dir().join(format!("p2w-token-{}.txt", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let wait = Duration::from_secs(5);
+
+        let (tx_a, mut rx_a) = tokio::sync::mpsc::channel(64);
+        let (cmd_a, rx_cmd_a) = tokio::sync::mpsc::channel(8);
+        let task_a = tokio::spawn(network_handler(tx_a, rx_cmd_a));
+        cmd_a
+            .send(rpc::Command::Open("0".into(), Some(path.clone())))
+            .await
+            .unwrap();
+        loop {
+            let ev = tokio::time::timeout(wait, rx_a.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && m.starts_with("トークンを ")
+            {
+                break;
+            }
+        }
+
+        let token = read_token_file(&path).unwrap();
+        let (tx_b, mut rx_b) = tokio::sync::mpsc::channel(64);
+        let (cmd_b, rx_cmd_b) = tokio::sync::mpsc::channel(8);
+        let task_b = tokio::spawn(network_handler(tx_b, rx_cmd_b));
+        cmd_b.send(rpc::Command::Connect(token)).await.unwrap();
+        loop {
+            let ev = tokio::time::timeout(wait, rx_b.recv()).await.unwrap();
+            if let Some(rpc::Event::PeerConnected { inbound: false, .. }) = ev {
+                break;
+            }
+        }
+
+        std::fs::remove_file(&path).ok();
+        assert!(
+            read_token_file(&path)
+                .unwrap_err()
+                .starts_with("トークンファイルを読めません")
+        );
+        cmd_a.send(rpc::Command::Shutdown).await.unwrap();
+        cmd_b.send(rpc::Command::Shutdown).await.unwrap();
+        task_a.await.unwrap();
+        task_b.await.unwrap();
+    }
+
+    #[test]
+    fn non_utf8_dm_shows_placeholder_and_keeps_raw_bytes() {
+        let raw = vec![0xff, 0xfe, 0x00, 0x80, 0x41];
+        let encrypted = crypto::encrypt_dm_payload(&raw).unwrap();
+        let (txt, binary) = decrypt_dm_text(&encrypted);
+        assert_eq!(txt, "<binary 5 bytes>");
+        assert_eq!(binary.as_deref(), Some(raw.as_slice()));
+        // 文字列として読めるものはそのまま
+        let encrypted = crypto::encrypt_dm_payload("@alice: 内緒".as_bytes()).unwrap();
+        assert_eq!(
+            decrypt_dm_text(&encrypted),
+            ("@alice: 内緒".to_string(), None)
+        );
+
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let m = build_signed_dm(
+            &mut crypto::NonceSequence::new(),
+            "@alice: x",
+            false,
+            &keys.pkcs8,
+            &keys.public,
+        )
+        .unwrap();
+        let mut rec = dm_record(&m, Some(0), None, None, txt, rpc::SigState::Valid, None).unwrap();
+        rec.binary = binary;
+        let db = crate::storage::tests::temp_db();
+        db.store_structured(&rec, None).unwrap();
+        let day = chrono::Utc::now().format("%Y%m%d").to_string();
+        let stored = db.load_structured_day(&day);
+        assert_eq!(stored[0].text, "<binary 5 bytes>");
+        assert_eq!(stored[0].binary.as_deref(), Some(raw.as_slice()));
+    }
+
+    #[test]
+    fn connect_puzzle_holds_frames_until_solved() {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let hello = build_signed_hello("@alice", None, &keys.pkcs8, &keys.public).unwrap();
+        let mut puzzle = Puzzle::new(8, 0).unwrap();
+        let (difficulty, challenge) = protocol::challenge_parts(&puzzle.message()).unwrap();
+        assert_eq!(difficulty, 8);
+
+        // 解答前の HELLO は保留され、正しい解答で届いた順に返る
+        assert!(matches!(
+            puzzle.check(protocol::Frame::encoded(hello.clone())),
+            PuzzleStep::Held
+        ));
+        let nonce = crypto::solve_puzzle(&challenge, difficulty);
+        match puzzle.check(protocol::Frame::encoded(protocol::Message::solution(
+            0, nonce,
+        ))) {
+            PuzzleStep::Solved(held) => assert_eq!(held, vec![protocol::Frame::encoded(hello)]),
+            _ => panic!("正しい解答が通らない"),
+        }
+        assert!(puzzle.expired(PUZZLE_TIMEOUT_MS));
+        assert!(!puzzle.expired(PUZZLE_TIMEOUT_MS - 1));
+    }
+
+    #[test]
+    fn wrong_puzzle_solution_is_rejected() {
+        let mut puzzle = Puzzle::new(8, 0).unwrap();
+        let challenge = puzzle.challenge;
+        // 条件を満たさない nonce を探して送る
+        let wrong = (0..)
+            .find(|&n| !crypto::puzzle_ok(&challenge, n, 8))
+            .unwrap();
+        assert!(matches!(
+            puzzle.check(protocol::Frame::encoded(protocol::Message::solution(
+                0, wrong
+            ))),
+            PuzzleStep::Failed
+        ));
+        // 解答を送らずにフレームを送り続けても上限で打ち切る
+        let mut puzzle = Puzzle::new(8, 0).unwrap();
+        for _ in 0..MAX_PUZZLE_HELD_FRAMES {
+            assert!(matches!(
+                puzzle.check(protocol::Frame::encoded(protocol::Message::chat("spam", 0))),
+                PuzzleStep::Held
+            ));
+        }
+        assert!(matches!(
+            puzzle.check(protocol::Frame::encoded(protocol::Message::chat("spam", 0))),
+            PuzzleStep::Failed
+        ));
+    }
+
+    #[test]
+    fn sync_requests_only_days_whose_counts_differ() {
+        let ours = vec![("20250101".to_string(), 3), ("20250102".to_string(), 2)];
+        let theirs = vec![
+            ("20250101".to_string(), 3),
+            ("20250102".to_string(), 5),
+            ("20250103".to_string(), 1),
+            ("20250104".to_string(), 0),
+        ];
+        // 2025-01-05 00:00 UTC
+        let now = 1_736_035_200_000;
+        assert_eq!(
+            sync_dates_to_request(&ours, &theirs, now),
+            ["20250103", "20250102"]
+        );
+    }
+
+    #[test]
+    fn sync_requests_only_recent_days_and_at_most_a_few() {
+        let now = 1_736_035_200_000;
+        let day = |ts: u64| storage::date_string(ts);
+        // 範囲外（古すぎる日・先の日）と、壊れた日付は要求しない
+        let theirs: Vec<(String, u64)> = [
+            day(now - (SYNC_WINDOW_DAYS + 1) * DAY_MS),
+            day(now + 3 * DAY_MS),
+            "2025".to_string(),
+        ]
+        .into_iter()
+        .map(|d| (d, 1))
+        .collect();
+        assert!(sync_dates_to_request(&[], &theirs, now).is_empty());
+        // 違う日が多くても新しい方から SYNC_MAX_DATES 日まで
+        let theirs: Vec<(String, u64)> = (0..20).map(|i| (day(now - i * DAY_MS), 1)).collect();
+        let dates = sync_dates_to_request(&[], &theirs, now);
+        assert_eq!(dates.len(), SYNC_MAX_DATES);
+        assert_eq!(dates[0], day(now));
+        assert_eq!(dates[SYNC_MAX_DATES - 1], day(now - 6 * DAY_MS));
+    }
+
+    #[test]
+    fn sync_records_are_accepted_only_for_outstanding_requests() {
+        let mut sync = PeerSync::default();
+        // 要求していない日は受け取らない
+        assert_eq!(sync.accept("20250105", 3, 0), 0);
+        assert!(sync.request("20250105", 0).is_some());
+        // 返事を待っている間は同じ日を重ねて要求しない
+        assert!(sync.request("20250105", 1).is_none());
+        assert_eq!(sync.accept("20250104", 3, 1), 0);
+        assert_eq!(sync.accept("20250105", 3, 1), 3);
+        // 1 日分の上限を超えた分は切り捨てる
+        assert_eq!(
+            sync.accept("20250105", SYNC_MAX_RECORDS_PER_DATE, 2),
+            SYNC_MAX_RECORDS_PER_DATE - 3
+        );
+        assert_eq!(sync.accept("20250105", 1, 3), 0);
+        // 締め切りを過ぎたら受け取らず、また要求できる
+        assert!(sync.request("20250104", 0).is_some());
+        assert_eq!(sync.accept("20250104", 1, SYNC_REPLY_TIMEOUT_MS), 0);
+        assert!(sync.request("20250104", SYNC_REPLY_TIMEOUT_MS).is_some());
+    }
+
+    #[test]
+    fn sync_requests_queue_only_a_few_at_a_time() {
+        let mut sync = PeerSync::default();
+        for i in 0..SYNC_MAX_DATES {
+            assert!(sync.serve(format!("202501{:02}", i + 1), Vec::new()));
+        }
+        assert!(!sync.serve("20250120".into(), Vec::new()));
+    }
+
+    #[test]
+    fn stored_proof_rebuilds_the_original_signed_post() {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let target = [5u8; protocol::MESSAGE_ID_LEN];
+        for reply_to in [None, Some(&target)] {
+            let m = build_signed_chat("@alice: hi", reply_to, false, &keys.pkcs8, &keys.public, 1)
+                .unwrap();
+            let rec = storage::MessageRecord {
+                ts_millis: m.timestamp,
+                recv_ts_millis: m.timestamp,
+                kind: storage::MsgKind::Chat,
+                from_peer_id: None,
+                to_peer_id: None,
+                handle: Some("@alice".into()),
+                text: "@alice: hi".into(),
+                signature: rpc::SigState::Valid,
+                reply_to: None,
+                binary: None,
+                proof: signature_proof(&m),
+                peer_fingerprint: None,
             };
-            let mut good = true;
-            if let (Some(sig), Some(pk)) = (msg.signature.as_ref(), msg.public_key.as_ref()) {
-                if !verify_signed_message(msg, sig, pk) {
-                    signed_state = "×";
-                    good = false;
+            let rebuilt = synced_message(&rec).unwrap();
+            // v2 は中継トークンの代わりに anchor を持ち、これ以上中継されない減衰値で戻る
+            assert_eq!(rebuilt.payload, m.payload);
+            assert_eq!(rebuilt.attenuation, protocol::MAX_ATTENUATION);
+            assert!(verify_signed_message(
+                &rebuilt,
+                &m.signature.clone().unwrap(),
+                &keys.public
+            ));
+            assert_eq!(message_id(&rebuilt), message_id(&m));
+            // 署名材料の無い記録は送れない
+            assert!(synced_message(&storage::MessageRecord { proof: None, ..rec }).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_peer_is_shed_before_fast_ones() {
+        use tokio::io::AsyncReadExt;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut peers = Vec::new();
+        let mut readers = Vec::new();
+        for _ in 0..4 {
+            let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+            peers.push(Peer::new(client.unwrap(), 0, None));
+            readers.push(accepted.unwrap().0);
+        }
+        // 0 と 2 と 3 は読み続け、1 は読まずに詰まったまま
+        let stalled = readers.remove(1);
+        for mut r in readers {
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 64 * 1024];
+                while r.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+            });
+        }
+
+        let frame = vec![7u8; 64 * 1024];
+        let limit = 1024 * 1024;
+        for _ in 0..200 {
+            for p in peers.iter_mut() {
+                assert!(!matches!(p.send(&frame), Flush::Drop(_)));
+            }
+            if peers[1].queue.queued_bytes() > limit * 2 {
+                break;
+            }
+            sleep(Duration::from_millis(1)).await;
+        }
+        assert!(peers[1].queue.queued_bytes() > limit * 2);
+        // 読んでいるピアは送信待ちがあっても僅か
+        for i in [0, 2, 3] {
+            assert!(peers[i].queue.queued_bytes() < limit / 2, "{}", i);
+        }
+
+        // 上限に余裕があれば誰も切らない
+        let total: usize = peers.iter().map(|p| p.queue.queued_bytes()).sum();
+        assert!(shed_backlogged(&peers, total, &[]).is_empty());
+        // 超えたら詰まっているピアだけを落とす
+        assert_eq!(shed_backlogged(&peers, limit, &[]), vec![1]);
+        // 既に切ると決めたピアは数えない
+        assert!(shed_backlogged(&peers, limit, &[1]).is_empty());
+        drop(stalled);
+
+        // 全員が少しずつ遅れていても、落とすのは上限に収まるまで多い順
+        let mut peers: Vec<Peer<()>> = (0..4).map(|_| Peer::new((), 0, None)).collect();
+        let small = protocol::encode(&protocol::Message::chat("relay", 1));
+        peers[1].queue.frames.push_back(small.repeat(10));
+        peers[2].queue.frames.push_back(small.clone());
+        peers[3].queue.frames.push_back(small.repeat(2));
+        assert_eq!(shed_backlogged(&peers, small.len(), &[]), vec![1, 3]);
+    }
+
+    #[tokio::test]
+    async fn hello_with_zero_width_padded_handle_is_disconnected() {
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler(tx_main, rx_cmd));
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let port = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && let Some(rest) = m.strip_prefix("待受開始 port=")
+            {
+                break rest.split(' ').next().unwrap().parse::<u16>().unwrap();
+            }
+        };
+
+        // 見た目は @bob と同じだが、ゼロ幅スペースで別の名前になっている
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let hello = build_signed_hello("@bob\u{200b}", None, &keys.pkcs8, &keys.public).unwrap();
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        peer.write_all(&protocol::encode(&hello)).await.unwrap();
+        let reason = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            match ev {
+                Some(rpc::Event::HandshakeComplete { .. }) => panic!("受け入れてしまった"),
+                Some(rpc::Event::PeerDisconnected { reason, .. }) => break reason,
+                _ => {}
+            }
+        };
+        assert_eq!(reason, disconnect_reason_text(2));
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unsigned_chat_with_oversized_handle_is_disconnected() {
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler(tx_main, rx_cmd));
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let port = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && let Some(rest) = m.strip_prefix("待受開始 port=")
+            {
+                break rest.split(' ').next().unwrap().parse::<u16>().unwrap();
+            }
+        };
+
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let hello = build_signed_hello("@bob", None, &keys.pkcs8, &keys.public).unwrap();
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        peer.write_all(&protocol::encode(&hello)).await.unwrap();
+        // 署名を付けずに長すぎるハンドル欄を送っても見逃さない
+        let body = format!("@{}: hi", "x".repeat(120));
+        let chat = protocol::Message::chat(&body, current_unix_millis());
+        peer.write_all(&protocol::encode(&chat)).await.unwrap();
+        let reason = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            match ev {
+                Some(rpc::Event::Post { line, .. }) => panic!("表示してしまった: {}", line),
+                Some(rpc::Event::PeerDisconnected { reason, .. }) => break reason,
+                _ => {}
+            }
+        };
+        assert_eq!(reason, disconnect_reason_text(1));
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn hello_bio_is_shown_in_whois() {
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler(tx_main, rx_cmd));
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let port = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && let Some(rest) = m.strip_prefix("待受開始 port=")
+            {
+                break rest.split(' ').next().unwrap().parse::<u16>().unwrap();
+            }
+        };
+
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let hello = build_signed_hello(
+            "@bob",
+            Some("会議中 あとで読みます"),
+            &keys.pkcs8,
+            &keys.public,
+        )
+        .unwrap();
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        peer.write_all(&protocol::encode(&hello)).await.unwrap();
+        loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            match ev {
+                Some(rpc::Event::HandshakeComplete { handle, .. }) => {
+                    assert_eq!(handle, "@bob");
+                    break;
                 }
-                // メタ更新（既存のハンドル情報は維持）
-                if *src < peer_meta.len() {
-                    let existing_handle = peer_meta[*src].as_ref().and_then(|m| m.handle.clone());
-                    peer_meta[*src] = Some(PeerMeta {
-                        public_key: pk.clone(),
-                        last_valid: good,
-                        last_timestamp: msg.timestamp,
-                        handle: existing_handle,
-                    });
+                Some(rpc::Event::PeerDisconnected { reason, .. }) => {
+                    panic!("切断された: {}", reason)
                 }
+                _ => {}
             }
-            if msg.kind == protocol::MsgKind::DISCONNECT {
-                let reason = protocol::disconnect_reason_id(msg).unwrap_or(0);
-                tx_main
-                    .send(rpc::Event::Message(format!(
-                        "相手から切断通知 id={} reason={}",
-                        src, reason
-                    )))
-                    .await
-                    .ok();
-                remove_indices.push(*src);
-            } else if msg.kind == protocol::MsgKind::HELLO {
-                // 相手の公開鍵が含まれていれば保存
-                if let Some(pk) = msg.public_key.as_ref() {
-                    // HELLO 自体の署名検証
-                    if let Some(sig) = msg.signature.as_ref() {
-                        if !verify_signed_message(msg, sig, pk) {
-                            // 理由ID=3: HELLO署名不正
-                            let disc = protocol::Message::disconnect(current_unix_millis(), 3);
-                            let frame = protocol::encode(&disc);
-                            let _ = clients[*src].write_all(&frame).await;
-                            tx_main
-                                .send(rpc::Event::Message(format!(
-                                    "不正HELLO署名: id={} 切断",
-                                    src
-                                )))
-                                .await
-                                .ok();
-                            remove_indices.push(*src);
-                            continue;
-                        }
-                    } else {
-                        // 署名なし HELLO は不許可
-                        let disc = protocol::Message::disconnect(current_unix_millis(), 3);
-                        let frame = protocol::encode(&disc);
-                        let _ = clients[*src].write_all(&frame).await;
-                        tx_main
-                            .send(rpc::Event::Message(format!(
-                                "HELLO署名なし: id={} 切断",
-                                src
-                            )))
-                            .await
-                            .ok();
-                        remove_indices.push(*src);
-                        continue;
-                    }
+        }
+
+        tx_cmd.send(rpc::Command::Whois("0".into())).await.unwrap();
+        let msg = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && m.starts_with("id=0 の情報")
+            {
+                break m;
+            }
+        };
+        assert!(
+            msg.contains("ひとこと: 会議中 あとで読みます (自己申告)"),
+            "{}",
+            msg
+        );
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejected_chat_reports_unsent() {
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler(tx_main, rx_cmd));
+        tx_cmd
+            .send(rpc::Command::Chat("hi".into(), Some("zz".into()), false))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            match ev {
+                Some(rpc::Event::Unsent) => break,
+                Some(rpc::Event::Sent { .. } | rpc::Event::Queued) => panic!("{:?}", ev),
+                _ => {}
+            }
+        }
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cert_shows_the_peer_key_and_keeps_the_connection() {
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler(tx_main, rx_cmd));
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let port = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && let Some(rest) = m.strip_prefix("待受開始 port=")
+            {
+                break rest.split(' ').next().unwrap().parse::<u16>().unwrap();
+            }
+        };
 
-                    if *src < peer_meta.len() {
-                        let peer_handle = String::from_utf8_lossy(&msg.payload).to_string();
-                        let valid_handle =
-                            peer_handle.starts_with('@') && peer_handle.chars().count() < 80;
-                        if !valid_handle {
-                            let disc = protocol::Message::disconnect(current_unix_millis(), 2);
-                            let frame = protocol::encode(&disc);
-                            let _ = clients[*src].write_all(&frame).await;
-                            tx_main
-                                .send(rpc::Event::Message(format!(
-                                    "不正HELLO: id={} のハンドル '{}' が不正のため切断",
-                                    src, peer_handle
-                                )))
-                                .await
-                                .ok();
-                            remove_indices.push(*src);
-                        } else {
-                            let meta = PeerMeta {
-                                public_key: pk.clone(),
-                                last_valid: true,
-                                last_timestamp: msg.timestamp,
-                                handle: Some(peer_handle),
-                            };
-                            peer_meta[*src] = Some(meta);
-                        }
-                    }
-                    let d = ring::digest::digest(&ring::digest::SHA256, pk);
-                    let h = crypto::to_hex(d.as_ref());
-                    tx_main
-                        .send(rpc::Event::Message(format!(
-                            "HELLO 受信: id={} 指紋={}",
-                            src,
-                            &h[..16]
-                        )))
-                        .await
-                        .ok();
-                } else {
-                    tx_main
-                        .send(rpc::Event::Message(format!(
-                            "HELLO 受信: id={} (公開鍵なし)",
-                            src
-                        )))
-                        .await
-                        .ok();
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let hello = build_signed_hello("@bob", None, &keys.pkcs8, &keys.public).unwrap();
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        peer.write_all(&protocol::encode(&hello)).await.unwrap();
+        loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::HandshakeComplete { .. }) = ev {
+                break;
+            }
+        }
+
+        tx_cmd.send(rpc::Command::Cert("0".into())).await.unwrap();
+        let msg = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            match ev {
+                Some(rpc::Event::PeerDisconnected { reason, .. }) => {
+                    panic!("/cert で切断された: {}", reason)
                 }
-            } else if msg.kind == protocol::MsgKind::DM {
-                // 受信表示: 本文 + 署名状態記号
-                let disp = format!("{} {}", txt, signed_state);
-                tx_main.send(rpc::Event::Message(disp)).await.ok();
-                // 保存（受信メタ）
-                let rec = crate::storage::MessageRecord {
-                    ts_millis: msg.timestamp,
-                    recv_ts_millis: current_unix_millis(),
-                    kind: crate::storage::MsgKind::Dm,
-                    from_peer_id: Some(*src),
-                    to_peer_id: None,
-                    handle: peer_meta
-                        .get(*src)
-                        .and_then(|m| m.as_ref())
-                        .and_then(|m| m.handle.clone()),
-                    text: txt.clone(),
-                    signed_ok: Some(signed_state == "○"),
-                };
-                let _ = crate::storage::store_structured(&rec);
-            } else {
-                // 受信表示: 統一フォーマット（本文に '@handle: ' が含まれている想定）。
-                // 署名状態は末尾に半角スペース+記号を付ける。
-                let disp = if let Some(Some(meta)) = peer_meta.get(*src).cloned() {
-                    if meta.handle.is_some() {
-                        format!("{} {}", txt, signed_state)
-                    } else if txt.contains(':') {
-                        format!("{} {}", txt, signed_state)
-                    } else {
-                        format!("@{}: {} {}", src, txt, signed_state)
-                    }
-                } else if txt.contains(':') {
-                    format!("{} {}", txt, signed_state)
-                } else {
-                    format!("@{}: {} {}", src, txt, signed_state)
-                };
-                tx_main.send(rpc::Event::Message(disp)).await.ok();
-                // 保存（受信メタ）
-                let rec = crate::storage::MessageRecord {
-                    ts_millis: msg.timestamp,
-                    recv_ts_millis: current_unix_millis(),
-                    kind: crate::storage::MsgKind::Chat,
-                    from_peer_id: Some(*src),
-                    to_peer_id: None,
-                    handle: peer_meta
-                        .get(*src)
-                        .and_then(|m| m.as_ref())
-                        .and_then(|m| m.handle.clone()),
-                    text: txt.clone(),
-                    signed_ok: Some(signed_state == "○"),
-                };
+                Some(rpc::Event::Message(m)) if m.starts_with("id=0 の証明書") => break m,
+                _ => {}
+            }
+        };
+        let fp = crypto::fingerprint_hex(&keys.public);
+        assert!(msg.contains(&format!("指紋: {}", fp)), "{}", msg);
+        assert!(msg.contains(&crypto::to_hex(&keys.public)), "{}", msg);
 
-                let _ = crate::storage::store_structured(&rec);
+        // 存在しない id はそう伝えるだけ
+        tx_cmd.send(rpc::Command::Cert("9".into())).await.unwrap();
+        let msg = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && m.starts_with("cert:")
+            {
+                break m;
+            }
+        };
+        assert_eq!(msg, "cert: 不正な id 9");
+        tx_cmd.send(rpc::Command::PeerList).await.unwrap();
+        loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::PeerList { peers, .. }) = ev {
+                assert_eq!(peers.len(), 1);
+                break;
+            }
+        }
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
 
-                // DM は減衰せず、宛先に届いたら即中継終了
-                // それ以外は減衰値を中継時にカウントアップし、最大値50で打ち止め
-                let mut fwd = msg.clone();
-                if fwd.kind != protocol::MsgKind::DM && fwd.attenuation < protocol::MAX_ATTENUATION
-                {
-                    fwd.attenuation = fwd.attenuation.saturating_add(1);
-                    let frame = protocol::encode(&fwd);
-                    for (idx, c) in clients.iter_mut().enumerate() {
-                        if idx == *src {
-                            continue;
-                        }
-                        if !should_relay_to_peer(&fwd, *src, idx) {
-                            continue;
-                        }
+    #[tokio::test]
+    async fn rapid_handle_change_in_hello_is_ignored() {
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler(tx_main, rx_cmd));
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let port = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && let Some(rest) = m.strip_prefix("待受開始 port=")
+            {
+                break rest.split(' ').next().unwrap().parse::<u16>().unwrap();
+            }
+        };
 
-                        if let Err(e) = c.write_all(&frame).await {
-                            tx_main
-                                .send(rpc::Event::Message(format!(
-                                    "Relay write error to {}: {:?}",
-                                    idx, e
-                                )))
-                                .await
-                                .ok();
-                            remove_indices.push(idx);
-                        }
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut handles = Vec::new();
+        let mut ignored = None;
+        for name in ["@bob", "@mallory"] {
+            let hello = build_signed_hello(name, None, &keys.pkcs8, &keys.public).unwrap();
+            peer.write_all(&protocol::encode(&hello)).await.unwrap();
+            loop {
+                let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+                match ev {
+                    Some(rpc::Event::HandshakeComplete { handle, .. }) => {
+                        handles.push(handle);
+                        break;
                     }
+                    Some(rpc::Event::Message(m)) if m.starts_with("ハンドル変更を無視") => {
+                        ignored = Some(m)
+                    }
+                    Some(rpc::Event::PeerDisconnected { reason, .. }) => {
+                        panic!("切断された: {}", reason)
+                    }
+                    _ => {}
                 }
             }
+        }
+        // 最初の HELLO は受け付け、すぐ後の変更は前のハンドルのまま
+        assert_eq!(handles, ["@bob", "@bob"]);
+        assert!(ignored.unwrap().contains("@bob → @mallory"));
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
 
-            // 不正検知: ハンドル長チェック（"@...: " のプレフィクスを解析）
-            if let Some(colon) = txt.find(':') {
-                let name = &txt[..colon].trim();
-                if name.starts_with('@') {
-                    let count = name.chars().count();
-                    if count >= 80 {
-                        // 切断: 理由ID=1（ハンドル長超過）
-                        let reason_id: u32 = 1;
-                        if *src < clients.len() {
-                            let disc =
-                                protocol::Message::disconnect(current_unix_millis(), reason_id);
-                            let frame = protocol::encode(&disc);
-                            let _ = clients[*src].write_all(&frame).await;
-                        }
-                        tx_main
-                            .send(rpc::Event::Message(format!(
-                                "不正検知: id={} のハンドル長({})が制限超過のため切断",
-                                src, count
-                            )))
-                            .await
-                            .ok();
-                        remove_indices.push(*src);
-                        // 次のメッセージ処理へ
-                        continue;
-                    }
+    #[test]
+    fn welcome_is_rate_limited_per_key_and_overall() {
+        assert!(!Welcome::default().take(&[1; 32], 0));
+        let mut w = Welcome::new("ようこそ".into());
+        assert!(w.take(&[1; 32], 0));
+        // つなぎ直した同じ相手には間隔を空けるまで送らない
+        assert!(!w.take(&[1; 32], 1_000));
+        assert!(w.take(&[1; 32], WELCOME_REPEAT_MS));
+        // 別の相手でも短い間に送るのは WELCOME_BURST 通まで
+        let now = WELCOME_REPEAT_MS + 1;
+        for i in 2..(WELCOME_BURST as u8) {
+            assert!(w.take(&[i; 32], now), "{}", i);
+        }
+        assert!(w.take(&[99; 32], now));
+        assert!(!w.take(&[100; 32], now));
+        assert!(w.take(&[100; 32], now + WELCOME_WINDOW_MS));
+    }
+
+    #[test]
+    fn handle_resolution_prefers_verified_then_signed_peers() {
+        let mut impostor = meta_with_key(&[1; 32]).unwrap();
+        impostor.handle = Some("@bob".into());
+        let mut real = meta_with_key(&[2; 32]).unwrap();
+        real.handle = Some("@bob".into());
+        let mut forged = meta_with_key(&[3; 32]).unwrap();
+        forged.handle = Some("@bob".into());
+        forged.last_valid = false;
+        let peers = peers_with(vec![Some(impostor), None, Some(forged), Some(real)]);
+        let verified: HashSet<String> =
+            [crypto::fingerprint_hex(&[2; 32])[..16].to_string()].into();
+
+        // 検証済みの鍵が先に接続した方より優先され、他は成りすましの候補として返る
+        let m = resolve_handle(&peers, &verified, "@bob").unwrap();
+        assert_eq!(
+            m,
+            HandleMatch {
+                target: 3,
+                impostors: vec![0, 2]
+            }
+        );
+        // 検証済みがいなければ署名の通っている方
+        let m = resolve_handle(&peers[..3], &verified, "@bob").unwrap();
+        assert_eq!(m.target, 0);
+        assert_eq!(m.impostors, vec![2]);
+        // 同じ順位が並んだら選ばない
+        let err = resolve_handle(&peers, &HashSet::new(), "@bob").unwrap_err();
+        assert!(err.contains("id=0,3"), "{}", err);
+        assert!(resolve_handle(&peers, &verified, "@carol").is_err());
+        // /peers では選ばれない方に印を付ける
+        let suspects: Vec<usize> = (0..peers.len())
+            .filter(|&i| is_handle_suspect(&peers, &verified, i))
+            .collect();
+        assert_eq!(suspects, vec![0, 2]);
+    }
+
+    #[tokio::test]
+    async fn dm_to_a_shared_handle_goes_to_the_verified_peer() {
+        use tokio::io::AsyncReadExt;
+        let net = transport::Memory::default();
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler_with_transport(
+            tx_main,
+            rx_cmd,
+            Arc::new(SystemClock),
+            net.clone(),
+        ));
+        let own = crypto::generate_ed25519_keypair().unwrap();
+        tx_cmd
+            .send(rpc::Command::RotateKey(own.pkcs8, own.public))
+            .await
+            .unwrap();
+        tx_cmd
+            .send(rpc::Command::Handle("@alice".into()))
+            .await
+            .unwrap();
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let addr = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && let Some(rest) = m.strip_prefix("待受開始 port=")
+            {
+                break format!("127.0.0.1:{}", rest.split(' ').next().unwrap());
+            }
+        };
+
+        // 先につないだ成りすましと、後からつないだ本物が同じ @bob を名乗る
+        let mut conns = Vec::new();
+        let mut keys = Vec::new();
+        for _ in 0..2 {
+            let k = crypto::generate_ed25519_keypair().unwrap();
+            let mut c = net.connect(&addr).await.unwrap();
+            let hello = build_signed_hello("@bob", None, &k.pkcs8, &k.public).unwrap();
+            c.write_all(&protocol::encode(&hello)).await.unwrap();
+            loop {
+                let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+                if let Some(rpc::Event::HandshakeComplete { .. }) = ev {
+                    break;
                 }
             }
+            conns.push(c);
+            keys.push(k);
         }
+        let real_fp = crypto::fingerprint_hex(&keys[1].public)[..16].to_string();
+        tx_cmd.send(rpc::Command::Trust(real_fp)).await.unwrap();
+        tx_cmd
+            .send(rpc::Command::DM("@bob".into(), "本物だけに".into(), false))
+            .await
+            .unwrap();
+        let warning = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && m.starts_with("⚠ @bob")
+            {
+                break m;
+            }
+        };
+        assert!(warning.contains("id=0"), "{}", warning);
+        assert!(warning.contains("id=1 へ送ります"), "{}", warning);
 
-        // 削除
-        remove_indices.sort_unstable();
-        remove_indices.dedup();
-        for i in remove_indices.into_iter().rev() {
-            clients.remove(i);
-            decoders.remove(i);
-            peer_meta.remove(i);
+        // 本物の接続に DM が届く
+        let mut dec = protocol::Decoder::new();
+        let dm = loop {
+            let mut buf = [0u8; 4096];
+            let n = tokio::time::timeout(wait, conns[1].read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(n > 0);
+            dec.feed(&buf[..n]);
+            if let Some(m) = dec
+                .drain()
+                .unwrap()
+                .into_iter()
+                .find(|m| protocol::is_dm_kind(m.kind))
+            {
+                break m;
+            }
+        };
+        let plain = crypto::decrypt_dm_payload(&dm.payload).unwrap();
+        assert_eq!(String::from_utf8(plain).unwrap(), "@alice: 本物だけに");
+        // 成りすましの方には DM が来ていない
+        let mut dec = protocol::Decoder::new();
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = conns[0].try_read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            dec.feed(&buf[..n]);
         }
+        assert!(
+            !dec.drain()
+                .unwrap()
+                .iter()
+                .any(|m| protocol::is_dm_kind(m.kind))
+        );
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
 
-        sleep(Duration::from_millis(15)).await;
+    #[test]
+    fn pong_for_the_outstanding_ping_sets_rtt() {
+        let mut meta = meta_with_key(&[1; 32]).unwrap();
+        let frame = meta.start_ping(7, 1_000);
+        let mut dec = protocol::Decoder::new();
+        dec.feed(&frame);
+        assert_eq!(protocol::ping_id(&dec.drain().unwrap()[0]), Some(7));
+        // 古い番号の返事では測らない
+        meta.finish_ping(6, 1_040);
+        assert_eq!(meta.rtt_ms, None);
+        meta.finish_ping(7, 1_040);
+        assert_eq!(meta.rtt_ms, Some(40));
+        // 同じ返事が重なっても測り直さない
+        meta.finish_ping(7, 1_900);
+        assert_eq!(meta.rtt_ms, Some(40));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn peers_report_rtt_from_the_ping_after_hello() {
+        use tokio::io::AsyncReadExt;
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler(tx_main, rx_cmd));
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let port = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && let Some(rest) = m.strip_prefix("待受開始 port=")
+            {
+                break rest.split(' ').next().unwrap().parse::<u16>().unwrap();
+            }
+        };
+
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let hello = build_signed_hello("@bob", None, &keys.pkcs8, &keys.public).unwrap();
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        peer.write_all(&protocol::encode(&hello)).await.unwrap();
+
+        // HELLO の後に届く PING に、少し待ってから答える
+        let mut dec = protocol::Decoder::new();
+        let id = loop {
+            let mut buf = [0u8; 4096];
+            let n = tokio::time::timeout(wait, peer.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(n > 0);
+            dec.feed(&buf[..n]);
+            if let Some(id) = dec
+                .drain()
+                .unwrap()
+                .iter()
+                .find(|m| m.kind == protocol::MsgKind::PING)
+                .and_then(protocol::ping_id)
+            {
+                break id;
+            }
+        };
+        sleep(Duration::from_millis(50)).await;
+        let pong = protocol::Message::pong(current_unix_millis(), id);
+        peer.write_all(&protocol::encode(&pong)).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+
+        tx_cmd.send(rpc::Command::PeerList).await.unwrap();
+        let peers = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::PeerList { peers, .. }) = ev {
+                break peers;
+            }
+        };
+        let rtt = peers[0].rtt_ms.expect("往復時間が測れていない");
+        assert!((50..5_000).contains(&rtt), "{}", rtt);
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
 
     #[test]
-    fn relay_probability_is_monotonic() {
-        assert_eq!(relay_probability_percent(0), 100);
-        assert_eq!(relay_probability_percent(FULL_RELAY_ATTENUATION), 100);
-        assert_eq!(relay_probability_percent(protocol::MAX_ATTENUATION), 0);
-        assert!(relay_probability_percent(20) < relay_probability_percent(10));
+    fn handle_change_is_allowed_after_the_interval() {
+        let mut meta = meta_with_key(&[1; 32]).unwrap();
+        meta.handle_since = 1_000;
+        let prev = Some(&meta);
+        assert_eq!(rate_limited_handle(None, "@bob", 1_000, 60_000), None);
+        assert_eq!(rate_limited_handle(prev, "@alice", 2_000, 60_000), None);
+        assert_eq!(
+            rate_limited_handle(prev, "@bob", 2_000, 60_000),
+            Some("@alice")
+        );
+        assert_eq!(rate_limited_handle(prev, "@bob", 61_000, 60_000), None);
+        assert_eq!(rate_limited_handle(prev, "@bob", 2_000, 0), None);
+    }
+
+    #[tokio::test]
+    async fn urgent_chat_is_marked_and_notified() {
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler(tx_main, rx_cmd));
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let port = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && let Some(rest) = m.strip_prefix("待受開始 port=")
+            {
+                break rest.split(' ').next().unwrap().parse::<u16>().unwrap();
+            }
+        };
+
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let hello = build_signed_hello("@bob", None, &keys.pkcs8, &keys.public).unwrap();
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        peer.write_all(&protocol::encode(&hello)).await.unwrap();
+        let urgent =
+            build_signed_chat("@bob: 障害発生", None, true, &keys.pkcs8, &keys.public, 1).unwrap();
+        peer.write_all(&protocol::encode(&urgent)).await.unwrap();
+
+        let line = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Chat { line, .. }) = ev {
+                break line;
+            }
+        };
+        assert!(line.ends_with(rpc::URGENT_MARK), "{}", line);
+        let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+        assert!(
+            matches!(ev, Some(rpc::Event::UrgentArrived { ref from }) if from == "@bob"),
+            "{:?}",
+            ev
+        );
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn valid_posts_in_the_same_millisecond_are_both_shown() {
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler(tx_main, rx_cmd));
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let port = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && let Some(rest) = m.strip_prefix("待受開始 port=")
+            {
+                break rest.split(' ').next().unwrap().parse::<u16>().unwrap();
+            }
+        };
+
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let hello = build_signed_hello("@bob", None, &keys.pkcs8, &keys.public).unwrap();
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        peer.write_all(&protocol::encode(&hello)).await.unwrap();
+        let ts = current_unix_millis();
+        for text in ["@bob: 1つめ", "@bob: 2つめ"] {
+            let chat =
+                sign_message(protocol::Message::chat(text, ts), &keys.pkcs8, &keys.public).unwrap();
+            peer.write_all(&protocol::encode(&chat)).await.unwrap();
+        }
+
+        let mut lines = Vec::new();
+        while lines.len() < 2 {
+            match tokio::time::timeout(wait, rx_main.recv()).await.unwrap() {
+                Some(rpc::Event::Chat { line, .. }) => lines.push(line),
+                Some(rpc::Event::Post { line, .. }) => panic!("{}", line),
+                _ => {}
+            }
+        }
+        assert!(lines[0].contains("1つめ") && lines[1].contains("2つめ"));
+        assert!(lines.iter().all(|l| !l.contains("改ざん")), "{:?}", lines);
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn peer_before_hello_is_not_a_dm_target() {
+        let net = transport::Memory::default();
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler_with_transport(
+            tx_main,
+            rx_cmd,
+            Arc::new(SystemClock),
+            net.clone(),
+        ));
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let port = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && let Some(rest) = m.strip_prefix("待受開始 port=")
+            {
+                break rest.split(' ').next().unwrap().to_string();
+            }
+        };
+        // つないだだけで HELLO は送らない
+        let _peer = net.connect(&format!("127.0.0.1:{}", port)).await.unwrap();
+        loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::PeerConnected { .. }) = ev {
+                break;
+            }
+        }
+
+        tx_cmd.send(rpc::Command::PeerList).await.unwrap();
+        let peers = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::PeerList { peers, .. }) = ev {
+                break peers;
+            }
+        };
+        assert_eq!(peers[0].state, rpc::PeerState::Handshaking);
+
+        tx_cmd
+            .send(rpc::Command::DM("0".into(), "内緒".into(), false))
+            .await
+            .unwrap();
+        let msg = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && m.starts_with("DM ")
+            {
+                break m;
+            }
+        };
+        assert_eq!(
+            msg,
+            "DM 宛先 id 0 はまだ HELLO を終えていません (HELLO待ち)"
+        );
+
+        // /whois は鍵が無くてもネットワーク情報を別の見出しで出す
+        tx_cmd.send(rpc::Command::Whois("0".into())).await.unwrap();
+        let msg = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && m.starts_with("id=0 の情報")
+            {
+                break m;
+            }
+        };
+        assert!(
+            msg.contains("HELLO 前のため鍵はまだ分かりません"),
+            "{}",
+            msg
+        );
+        assert!(msg.contains("本人確認ではありません"), "{}", msg);
+        assert!(msg.contains("逆引き: 無効"), "{}", msg);
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
     }
 
     #[test]
-    fn duplicate_detection_ignores_attenuation() {
-        let mut seen_messages: HashSet<[u8; 32]> = HashSet::new();
-        let mut seen_order: VecDeque<[u8; 32]> = VecDeque::new();
-        let mut msg = protocol::Message::chat("hello", 12345);
-        msg.public_key = Some(vec![7; 32]);
-        msg.signature = Some(vec![9; 64]);
-        assert!(!is_duplicate_message(
-            &msg,
-            &mut seen_messages,
-            &mut seen_order
+    fn only_ready_peers_receive_relays() {
+        let mut peers = peers_with(vec![
+            meta_with_key(&[1u8; 32]),
+            None,
+            meta_with_key(&[2u8; 32]),
+        ]);
+        peers[2].meta.as_mut().unwrap().state = rpc::PeerState::Handshaking;
+        assert!(is_ready(&peers, 0));
+        assert!(!is_ready(&peers, 1));
+        assert!(!is_ready(&peers, 2));
+        assert!(!is_ready(&peers, 3));
+        assert_eq!(peer_state(None, true), rpc::PeerState::Connecting);
+        assert_eq!(peer_state(None, false), rpc::PeerState::Handshaking);
+    }
+
+    #[test]
+    fn peer_stays_pending_while_its_puzzle_is_being_solved() {
+        let mut peers = peers_with(vec![meta_with_key(&[1u8; 32])]);
+        peers[0].solving = Some(Solving {
+            ticket: 1,
+            difficulty: 8,
+            held: Vec::new(),
+        });
+        // 相手の HELLO が先に届いていても、解答を送るまでは何も流さない
+        assert!(!is_ready(&peers, 0));
+        let state = peer_state(peers[0].meta.as_ref(), peers[0].puzzle_pending());
+        assert_eq!(state, rpc::PeerState::Connecting);
+        peers[0].solving = None;
+        assert!(is_ready(&peers, 0));
+    }
+
+    #[test]
+    fn frames_are_grouped_by_source_in_arrival_order() {
+        let mut frames: Vec<_> = [(1, "b1"), (0, "a1"), (1, "b2"), (0, "a2")]
+            .into_iter()
+            .map(|(src, t)| (src, protocol::Message::chat(t, 0)))
+            .collect();
+        order_by_source(&mut frames);
+        let order: Vec<_> = frames
+            .iter()
+            .map(|(src, m)| (*src, String::from_utf8_lossy(&m.payload).into_owned()))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                (0, "a1".to_string()),
+                (0, "a2".to_string()),
+                (1, "b1".to_string()),
+                (1, "b2".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn frames_from_one_read_are_handled_in_order() {
+        let net = transport::Memory::default();
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler_with_transport(
+            tx_main,
+            rx_cmd,
+            Arc::new(SystemClock),
+            net.clone(),
         ));
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let port = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && let Some(rest) = m.strip_prefix("待受開始 port=")
+            {
+                break rest.split(' ').next().unwrap().to_string();
+            }
+        };
 
-        let mut replay = msg.clone();
-        replay.attenuation = 40;
-        assert!(is_duplicate_message(
-            &replay,
-            &mut seen_messages,
-            &mut seen_order
+        // HELLO と 2 件の投稿を 1 回の書き込みで送る
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let mut bytes =
+            protocol::encode(&build_signed_hello("@bob", None, &keys.pkcs8, &keys.public).unwrap());
+        // 同じ時刻だと改ざんとみなされるので 1ms ずらす
+        let ts = current_unix_millis();
+        for (i, text) in ["@bob: 1つ目", "@bob: 2つ目"].into_iter().enumerate() {
+            let chat = protocol::Message::chat(text, ts + i as u64);
+            let sig = crypto::sign_ed25519(&protocol::signing_bytes(&chat), &keys.pkcs8).unwrap();
+            bytes.extend(protocol::encode(
+                &chat.with_key_sig(keys.public.clone(), sig),
+            ));
+        }
+        let mut peer = net.connect(&format!("127.0.0.1:{}", port)).await.unwrap();
+        peer.write_all(&bytes).await.unwrap();
+
+        let mut seen = Vec::new();
+        while seen.len() < 3 {
+            match tokio::time::timeout(wait, rx_main.recv()).await.unwrap() {
+                Some(rpc::Event::HandshakeComplete { handle, .. }) => seen.push(handle),
+                Some(rpc::Event::Chat { line, .. }) => {
+                    let text = ["1つ目", "2つ目"].into_iter().find(|t| line.contains(t));
+                    seen.push(text.unwrap().to_string());
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(seen, ["@bob", "1つ目", "2つ目"]);
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn relayed_chat_keeps_the_received_bytes_and_verifies_at_the_last_hop() {
+        use tokio::io::AsyncReadExt;
+        let net = transport::Memory::default();
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler_with_transport(
+            tx_main,
+            rx_cmd,
+            Arc::new(SystemClock),
+            net.clone(),
         ));
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let addr = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && let Some(rest) = m.strip_prefix("待受開始 port=")
+            {
+                break format!("127.0.0.1:{}", rest.split(' ').next().unwrap());
+            }
+        };
+        async fn hello_done(rx: &mut tokio::sync::mpsc::Receiver<rpc::Event>, who: &str) {
+            loop {
+                let ev = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await;
+                if let Some(rpc::Event::HandshakeComplete { handle, .. }) = ev.unwrap()
+                    && handle == who
+                {
+                    break;
+                }
+            }
+        }
+
+        // 最後の受け手 carol
+        let carol_keys = crypto::generate_ed25519_keypair().unwrap();
+        let mut carol = net.connect(&addr).await.unwrap();
+        let hello =
+            build_signed_hello("@carol", None, &carol_keys.pkcs8, &carol_keys.public).unwrap();
+        carol.write_all(&protocol::encode(&hello)).await.unwrap();
+        hello_done(&mut rx_main, "@carol").await;
+
+        // 送り主 bob の署名付き投稿を、間のノードが carol へ中継する
+        let bob_keys = crypto::generate_ed25519_keypair().unwrap();
+        let mut bob = net.connect(&addr).await.unwrap();
+        let hello = build_signed_hello("@bob", None, &bob_keys.pkcs8, &bob_keys.public).unwrap();
+        bob.write_all(&protocol::encode(&hello)).await.unwrap();
+        hello_done(&mut rx_main, "@bob").await;
+        let chat = build_signed_chat(
+            "@bob: 中継の先でも検証できる",
+            None,
+            false,
+            &bob_keys.pkcs8,
+            &bob_keys.public,
+            1,
+        )
+        .unwrap();
+        let sent = protocol::encode(&chat);
+        bob.write_all(&sent).await.unwrap();
+
+        let mut dec = protocol::Decoder::new();
+        let relayed = loop {
+            let mut buf = [0u8; 4096];
+            let n = tokio::time::timeout(wait, carol.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(n > 0, "中継が届く前に切断された");
+            dec.feed(&buf[..n]);
+            let frames = dec.drain_frames().unwrap();
+            if let Some(f) = frames
+                .into_iter()
+                .find(|f| f.msg.kind == protocol::MsgKind::CHAT)
+            {
+                break f;
+            }
+        };
+        // 届いたバイト列に中継1回分を施しただけのもの
+        let mut expected = sent.clone();
+        assert!(protocol::attenuate_frame(&mut expected));
+        assert_eq!(relayed.raw, expected);
+        assert_eq!(relayed.msg.attenuation, 1);
+        let sig = relayed.msg.signature.clone().unwrap();
+        assert!(verify_signed_message(&relayed.msg, &sig, &bob_keys.public));
+
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
     }
 
     #[test]
-    fn signed_dm_payload_is_binary_safe() {
+    fn sequence_gaps_and_reordering_are_told_apart() {
+        let mut next = None;
+        assert_eq!(check_seq(&mut next, 5), SeqCheck::InOrder);
+        assert_eq!(check_seq(&mut next, 6), SeqCheck::InOrder);
+        assert_eq!(
+            check_seq(&mut next, 9),
+            SeqCheck::Gap {
+                expected: 7,
+                got: 9
+            }
+        );
+        // 遅れて届いた分は期待値を戻さない
+        assert_eq!(
+            check_seq(&mut next, 7),
+            SeqCheck::Reordered {
+                expected: 10,
+                got: 7
+            }
+        );
+        assert_eq!(check_seq(&mut next, 10), SeqCheck::InOrder);
+        assert_eq!(next, Some(11));
+    }
+
+    #[tokio::test]
+    async fn skipped_sequence_number_is_reported() {
+        let net = transport::Memory::default();
+        let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+        let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler_with_transport(
+            tx_main,
+            rx_cmd,
+            Arc::new(SystemClock),
+            net.clone(),
+        ));
+        tx_cmd
+            .send(rpc::Command::Open("0".into(), None))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        let port = loop {
+            let ev = tokio::time::timeout(wait, rx_main.recv()).await.unwrap();
+            if let Some(rpc::Event::Message(m)) = ev
+                && let Some(rest) = m.strip_prefix("待受開始 port=")
+            {
+                break rest.split(' ').next().unwrap().to_string();
+            }
+        };
+
+        // 1 番の次に 3 番が届く（2 番は途中で落ちた）
         let keys = crypto::generate_ed25519_keypair().unwrap();
-        let plain = "@alice: こんにちは";
-        let msg = build_signed_dm(plain, &keys.pkcs8, &keys.public).unwrap();
+        let mut bytes =
+            protocol::encode(&build_signed_hello("@bob", None, &keys.pkcs8, &keys.public).unwrap());
+        for (text, seq) in [("@bob: 1つ目", 1), ("@bob: 3つ目", 3)] {
+            let chat =
+                build_signed_chat(text, None, false, &keys.pkcs8, &keys.public, seq).unwrap();
+            bytes.extend(protocol::encode(&chat));
+        }
+        let mut peer = net.connect(&format!("127.0.0.1:{}", port)).await.unwrap();
+        peer.write_all(&bytes).await.unwrap();
 
-        assert_eq!(msg.kind, protocol::MsgKind::DM);
-        let decrypted = crypto::decrypt_dm_payload(&msg.payload).unwrap();
-        assert_eq!(String::from_utf8_lossy(&decrypted), plain);
+        let warning = loop {
+            match tokio::time::timeout(wait, rx_main.recv()).await.unwrap() {
+                Some(rpc::Event::Message(m)) if m.contains("通し番号") => break m,
+                Some(_) => {}
+                None => panic!("イベントが途切れた"),
+            }
+        };
+        assert!(
+            warning.contains("通し番号の抜け: 期待=2 受信=3"),
+            "{}",
+            warning
+        );
+        tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
+
+    #[test]
+    fn topology_view_links_both_directions() {
+        let (a, b, c) = ([1u8; 8], [2u8; 8], [3u8; 8]);
+        let mut view = TopologyView::new([0; 8], &a, &[b], 0);
+        view.add(&b, &[a, c]);
+        let out = view.render();
+        assert!(out.starts_with("構成 (3 ノード, 返事 0 件):"), "{}", out);
+        assert!(out.contains("0101010101010101 (自分) -> 0202020202020202"));
+        assert!(out.contains("0202020202020202 -> 0101010101010101, 0303030303030303"));
+        assert!(out.contains("0303030303030303 -> 0202020202020202"));
+    }
+
+    #[tokio::test]
+    async fn small_fanout_spreads_a_relay_over_ticks_in_order() {
+        let net = transport::Memory::default();
+        let listener = net.bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        // 0 が送り主、1..=6 が中継先
+        let (mut peers, mut remotes) = (Vec::new(), Vec::new());
+        for i in 0..7 {
+            remotes.push(net.connect(&addr).await.unwrap());
+            let conn = listener.accept().await.unwrap().0;
+            peers.push(Peer {
+                meta: meta_with_key(&[i as u8; 32]),
+                ..Peer::new(conn, 0, None)
+            });
+        }
+        let mut fanout = Fanout::new(Some(2));
+        let (tx_main, _rx) = tokio::sync::mpsc::channel(8);
+        let mut removed = Vec::new();
+
+        let first = protocol::Frame::encoded(protocol::Message::chat("@bob: 1", 1));
+        let second = protocol::Frame::encoded(protocol::Message::chat("@bob: 2", 2));
+        fanout.start_tick();
+        for frame in [&first, &second] {
+            relay(
+                frame,
+                0,
+                true,
+                &mut peers,
+                &mut fanout,
+                &tx_main,
+                &mut removed,
+            )
+            .await;
+        }
+        // 12 回分の送信を 2 回ずつ。1 ティック目はもう使い切っている
+        let mut ticks = 1;
+        while peers.iter().any(|p| !p.queue.relay_backlog.is_empty()) {
+            fanout.start_tick();
+            assert!(
+                release_relay_backlog(&mut peers, &mut fanout)
+                    .await
+                    .is_empty()
+            );
+            assert!(fanout.used <= 2);
+            ticks += 1;
+        }
+        assert_eq!(ticks, 6);
+        assert!(removed.is_empty());
+
+        let mut buf = [0u8; 1024];
+        for (i, remote) in remotes.iter_mut().enumerate() {
+            let mut dec = protocol::Decoder::new();
+            if let Ok(n) = remote.try_read(&mut buf) {
+                dec.feed(&buf[..n]);
+            }
+            let texts: Vec<Vec<u8>> = dec
+                .drain()
+                .unwrap()
+                .into_iter()
+                .map(|m| m.payload)
+                .collect();
+            if i == 0 {
+                assert!(texts.is_empty());
+            } else {
+                assert_eq!(
+                    texts,
+                    [b"@bob: 1".to_vec(), b"@bob: 2".to_vec()],
+                    "peer {}",
+                    i
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn large_frame_in_many_chunks_decodes_in_one_pass() {
+        let net = transport::Memory::default();
+        let listener = net.bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut sender = net.connect(&addr.to_string()).await.unwrap();
+        let (mut receiver, _) = listener.accept().await.unwrap();
+        // Memory の接続は 64KB までしか溜めないので、それより小さく
+        let text = "x".repeat(48 * 1024);
+        let frame = protocol::encode(&protocol::Message::chat(&text, 1));
+        for chunk in frame.chunks(frame.len() / 4 + 1) {
+            sender.write_all(chunk).await.unwrap();
+        }
+
+        // 読み込み 1 回は 2KB でも、WouldBlock まで読み続けるので 1 巡で 1 フレームになる
+        let mut dec = protocol::Decoder::new();
+        let mut buf = vec![0u8; DEFAULT_READ_BUFFER_BYTES];
+        let mut burst = Vec::new();
+        let n = read_burst(&mut receiver, &mut buf, dec.max_buffered(), &mut burst).unwrap();
+        assert_eq!(n, frame.len());
+        dec.feed(&burst);
+        let msgs = dec.drain().unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].payload, text.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn flooding_peer_is_read_at_most_the_decoder_cap_per_tick() {
+        let net = transport::Memory::default();
+        let listener = net.bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut sender = net.connect(&addr.to_string()).await.unwrap();
+        let (mut receiver, _) = listener.accept().await.unwrap();
+        let dec = protocol::Decoder::with_max_payload(1024);
+        let cap = dec.max_buffered();
+        sender.write_all(&vec![7u8; cap * 3 + 10]).await.unwrap();
+
+        let mut buf = vec![0u8; DEFAULT_READ_BUFFER_BYTES];
+        let mut burst = Vec::new();
+        let reads: Vec<usize> = (0..4)
+            .map(|_| read_burst(&mut receiver, &mut buf, cap, &mut burst).unwrap())
+            .collect();
+        assert_eq!(reads, vec![cap, cap, cap, 10]);
+    }
+
+    #[tokio::test]
+    async fn read_burst_reports_would_block_when_nothing_arrived() {
+        let net = transport::Memory::default();
+        let listener = net.bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _sender = net.connect(&addr.to_string()).await.unwrap();
+        let (mut receiver, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 16];
+        let mut out = Vec::new();
+        let e = read_burst(&mut receiver, &mut buf, 4, &mut out).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::WouldBlock);
     }
 }
@@ -0,0 +1,423 @@
+//! 受信したバイト列の記録 (config の record_frames) と、その再生 (`p2witter --replay <file>`)。
+//! 記録は接続ごとの番号を付けて届いた順に追記する。再生ではプロセス内の transport::Memory
+//! 越しにネットワークスレッドへ同じ順で流すので、署名の検証から表示・保存まで受信時と同じ経路を通る
+
+use crate::core::{protocol, rpc};
+use crate::network_handler;
+use crate::transport::{self, Acceptor, Connection, Transport};
+use crate::utils::{SystemClock, current_unix_millis};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// 記録ファイルの先頭
+const MAGIC: &[u8; 8] = b"P2WREC1\n";
+/// 1 件の見出し: 接続番号 (u32) + 受信時刻 (u64, ミリ秒) + 長さ (u32)。いずれもビッグエンディアン
+const ENTRY_HEADER_LEN: usize = 16;
+/// 再生用の待受のポート（transport::Memory の中だけのもの）
+const REPLAY_PORT: u16 = 1;
+
+/// 1 回の読み込みで届いたバイト列
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    /// 接続ごとの番号（記録を始めてから接続した順）
+    pub conn: u32,
+    pub ts_millis: u64,
+    pub bytes: Vec<u8>,
+}
+
+impl LogEntry {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(ENTRY_HEADER_LEN + self.bytes.len());
+        out.extend_from_slice(&self.conn.to_be_bytes());
+        out.extend_from_slice(&self.ts_millis.to_be_bytes());
+        out.extend_from_slice(&(self.bytes.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.bytes);
+        out
+    }
+}
+
+/// 記録ファイルを読む。途中で切れていれば読めたところまでと、その旨を返す
+pub fn read_log(data: &[u8]) -> (Vec<LogEntry>, Option<String>) {
+    let Some(mut rest) = data.strip_prefix(MAGIC.as_slice()) else {
+        return (Vec::new(), Some("記録ファイルではありません".into()));
+    };
+    let mut entries = Vec::new();
+    while !rest.is_empty() {
+        let Some((head, body)) = rest.split_at_checked(ENTRY_HEADER_LEN) else {
+            break;
+        };
+        let conn = u32::from_be_bytes(head[..4].try_into().unwrap());
+        let ts_millis = u64::from_be_bytes(head[4..12].try_into().unwrap());
+        let len = u32::from_be_bytes(head[12..].try_into().unwrap()) as usize;
+        let Some((bytes, next)) = body.split_at_checked(len) else {
+            break;
+        };
+        entries.push(LogEntry {
+            conn,
+            ts_millis,
+            bytes: bytes.to_vec(),
+        });
+        rest = next;
+    }
+    let note = (!rest.is_empty()).then(|| {
+        format!(
+            "末尾の {} バイトは途中で切れているため読みません",
+            rest.len()
+        )
+    });
+    (entries, note)
+}
+
+/// 記録を接続ごとの Decoder に通してフレームに戻す。壊れたフレームに当たったら
+/// その接続の読みかけを捨てて次の読み込みから読み直し、そのことを problems に残す
+pub fn decode_entries(entries: &[LogEntry]) -> (Vec<(u32, protocol::Message)>, Vec<String>) {
+    let mut decoders: HashMap<u32, protocol::Decoder> = HashMap::new();
+    let mut frames = Vec::new();
+    let mut problems = Vec::new();
+    for e in entries {
+        let dec = decoders.entry(e.conn).or_default();
+        dec.feed(&e.bytes);
+        match dec.drain() {
+            Ok(msgs) => frames.extend(msgs.into_iter().map(|m| (e.conn, m))),
+            Err(err) => {
+                problems.push(format!(
+                    "接続 {}: {} (読みかけを捨てて続けます)",
+                    e.conn, err
+                ));
+                decoders.insert(e.conn, protocol::Decoder::new());
+            }
+        }
+    }
+    (frames, problems)
+}
+
+/// 読み込んだバイト列を記録ファイルに追記する transport。中身の transport はそのまま使う
+#[derive(Clone)]
+pub struct Recording<T> {
+    inner: T,
+    log: Arc<Mutex<File>>,
+    next_conn: Arc<AtomicU32>,
+}
+
+impl<T: Transport> Recording<T> {
+    /// path に追記する（無ければ作って先頭を書く）
+    pub fn create(inner: T, path: &Path) -> io::Result<Self> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(MAGIC)?;
+        }
+        Ok(Self {
+            inner,
+            log: Arc::new(Mutex::new(file)),
+            next_conn: Arc::new(AtomicU32::new(0)),
+        })
+    }
+
+    fn wrap(&self, inner: T::Conn) -> RecordingConn<T::Conn> {
+        wrap_conn(inner, &self.log, &self.next_conn)
+    }
+}
+
+fn wrap_conn<C>(inner: C, log: &Arc<Mutex<File>>, next_conn: &AtomicU32) -> RecordingConn<C> {
+    RecordingConn {
+        inner,
+        conn: next_conn.fetch_add(1, Ordering::Relaxed),
+        log: log.clone(),
+    }
+}
+
+/// 読んだ分を記録する接続
+pub struct RecordingConn<C> {
+    inner: C,
+    conn: u32,
+    log: Arc<Mutex<File>>,
+}
+
+impl<C> RecordingConn<C> {
+    // 記録に失敗しても通信は続ける
+    fn record(&self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        let entry = LogEntry {
+            conn: self.conn,
+            ts_millis: current_unix_millis(),
+            bytes: bytes.to_vec(),
+        };
+        if let Ok(mut f) = self.log.lock() {
+            let _ = f.write_all(&entry.encode());
+        }
+    }
+}
+
+impl<C: Connection> Connection for RecordingConn<C> {
+    fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.try_read(buf)?;
+        self.record(&buf[..n]);
+        Ok(n)
+    }
+
+    fn try_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.try_write(buf)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+impl<C: Connection> AsyncRead for RecordingConn<C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            self.record(&buf.filled()[before..]);
+        }
+        res
+    }
+}
+
+impl<C: Connection> AsyncWrite for RecordingConn<C> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// 受け入れた接続を記録する待受
+pub struct RecordingListener<L> {
+    inner: L,
+    log: Arc<Mutex<File>>,
+    next_conn: Arc<AtomicU32>,
+}
+
+impl<L: Acceptor> Acceptor for RecordingListener<L> {
+    type Conn = RecordingConn<L::Conn>;
+
+    async fn accept(&self) -> io::Result<(Self::Conn, SocketAddr)> {
+        let (conn, addr) = self.inner.accept().await?;
+        Ok((wrap_conn(conn, &self.log, &self.next_conn), addr))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+impl<T: Transport> Transport for Recording<T> {
+    type Conn = RecordingConn<T::Conn>;
+    type Listener = RecordingListener<T::Listener>;
+
+    async fn bind(&self, addr: &str) -> io::Result<Self::Listener> {
+        Ok(RecordingListener {
+            inner: self.inner.bind(addr).await?,
+            log: self.log.clone(),
+            next_conn: self.next_conn.clone(),
+        })
+    }
+
+    async fn connect(&self, addr: &str) -> io::Result<Self::Conn> {
+        Ok(self.wrap(self.inner.connect(addr).await?))
+    }
+}
+
+/// ネットワークスレッドが手元のコマンドとその前に届いたバイト列を処理し終えるまで待つ。
+/// PeerList の応答はコマンドを処理した回の読み込みより先に届くので、2 往復する
+async fn settle(
+    tx_cmd: &Sender<rpc::Command>,
+    rx_ev: &mut Receiver<rpc::Event>,
+    tx_main: &Sender<rpc::Event>,
+) {
+    for _ in 0..2 {
+        if tx_cmd.send(rpc::Command::PeerList).await.is_err() {
+            return;
+        }
+        while let Some(ev) = rx_ev.recv().await {
+            if matches!(ev, rpc::Event::PeerList { .. }) {
+                break;
+            }
+            tx_main.send(ev).await.ok();
+        }
+    }
+}
+
+/// 記録をネットワークなしで再生する。記録の接続ごとにプロセス内の接続を張って同じ順に流し、
+/// ネットワークスレッドが出したイベントを tx_main へ送る。
+/// 設定は読まないので、自分の鍵が要るもの（自分宛ての DM の復号など）は再現しない
+pub async fn replay(entries: &[LogEntry], tx_main: Sender<rpc::Event>) {
+    let net = transport::Memory::default();
+    let (tx_cmd, rx_cmd) = mpsc::channel(8);
+    let (tx_ev, mut rx_ev) = mpsc::channel(100);
+    let task = tokio::spawn(network_handler::network_handler_with_transport(
+        tx_ev,
+        rx_cmd,
+        Arc::new(SystemClock),
+        net.clone(),
+    ));
+    tx_cmd
+        .send(rpc::Command::Open(REPLAY_PORT.to_string(), None))
+        .await
+        .ok();
+    settle(&tx_cmd, &mut rx_ev, &tx_main).await;
+
+    // 記録に出てきた順に接続する（ネットワークスレッドの接続番号も同じ順になる）
+    let mut writers = HashMap::new();
+    for e in entries {
+        if writers.contains_key(&e.conn) {
+            continue;
+        }
+        let addr = format!("127.0.0.1:{}", REPLAY_PORT);
+        let Ok(conn) = transport::Memory::connect(&net, &addr).await else {
+            break;
+        };
+        let (mut reader, writer) = tokio::io::split(conn);
+        // こちらへ送ってくる HELLO などは読み捨てる
+        tokio::spawn(async move {
+            let mut sink = [0u8; 4096];
+            while matches!(reader.read(&mut sink).await, Ok(n) if n > 0) {}
+        });
+        writers.insert(e.conn, writer);
+        settle(&tx_cmd, &mut rx_ev, &tx_main).await;
+    }
+
+    let mut prev = None;
+    for e in entries {
+        // 接続が変わるところでは、前の接続の分を処理し終えてから流す
+        if prev.is_some_and(|p| p != e.conn) {
+            settle(&tx_cmd, &mut rx_ev, &tx_main).await;
+        }
+        prev = Some(e.conn);
+        let Some(w) = writers.get_mut(&e.conn) else {
+            continue;
+        };
+        if w.write_all(&e.bytes).await.is_err() {
+            writers.remove(&e.conn);
+            let msg = format!(
+                "再生: 接続 {} は切断されたため、以降の記録を飛ばします",
+                e.conn
+            );
+            tx_main.send(rpc::Event::Message(msg)).await.ok();
+        }
+    }
+    settle(&tx_cmd, &mut rx_ev, &tx_main).await;
+    tx_cmd.send(rpc::Command::Shutdown).await.ok();
+    while let Some(ev) = rx_ev.recv().await {
+        tx_main.send(ev).await.ok();
+    }
+    let _ = task.await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::crypto;
+
+    fn signed_frames() -> Vec<protocol::Message> {
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let hello = protocol::Message::hello(1_700_000_000_000, "@bob", None);
+        let chat = protocol::Message::chat("@bob: 再生のテスト", 1_700_000_000_500);
+        [hello, chat]
+            .into_iter()
+            .map(|m| {
+                let sig = crypto::sign_ed25519(&protocol::signing_bytes(&m), &keys.pkcs8).unwrap();
+                m.with_key_sig(keys.public.clone(), sig)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn recorded_frames_replay_to_the_same_messages() {
+        let path = std::env::temp_dir().join(format!("p2witter-record-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let net = transport::Memory::default();
+        let recording = Recording::create(net.clone(), &path).unwrap();
+        let listener = recording.bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mut peer = net.connect(&addr).await.unwrap();
+        let (mut conn, _) = listener.accept().await.unwrap();
+
+        // 小分けに届いても、記録を読み戻せば同じフレームになる
+        let frames = signed_frames();
+        let wire: Vec<u8> = frames.iter().flat_map(protocol::encode).collect();
+        for chunk in wire.chunks(7) {
+            peer.write_all(chunk).await.unwrap();
+            let mut buf = [0u8; 7];
+            let n = conn.try_read(&mut buf).unwrap();
+            assert_eq!(n, chunk.len());
+        }
+        drop(recording);
+        drop(conn);
+        let data = std::fs::read(&path).unwrap();
+        let (entries, note) = read_log(&data);
+        assert_eq!(note, None);
+        assert_eq!(entries.len(), wire.chunks(7).count());
+        let (decoded, problems) = decode_entries(&entries);
+        assert!(problems.is_empty(), "{:?}", problems);
+        let decoded: Vec<_> = decoded.into_iter().map(|(_, m)| m).collect();
+        assert_eq!(decoded, frames);
+
+        // 途中で切れた記録は読めたところまで
+        let (cut, note) = read_log(&data[..data.len() - 3]);
+        assert_eq!(cut.len(), entries.len() - 1);
+        assert!(note.unwrap().contains("途中で切れて"));
+        // 壊れたバイト列はその接続の読みかけを捨てて、後のフレームは読む
+        let mut broken = vec![LogEntry {
+            conn: 0,
+            ts_millis: 0,
+            bytes: vec![0xff; 64],
+        }];
+        broken.push(LogEntry {
+            conn: 0,
+            ts_millis: 0,
+            bytes: wire.clone(),
+        });
+        let (decoded, problems) = decode_entries(&broken);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(decoded.len(), frames.len());
+
+        // ネットワークスレッドに流すと、受信時と同じ表示になる
+        let (tx_main, mut rx_main) = mpsc::channel(100);
+        let task = tokio::spawn(async move { replay(&entries, tx_main).await });
+        let mut chat = None;
+        while let Some(ev) = rx_main.recv().await {
+            if let rpc::Event::Chat { line, .. } = ev {
+                chat = Some(line);
+            }
+        }
+        task.await.unwrap();
+        let chat = chat.unwrap();
+        assert!(chat.contains("@bob: 再生のテスト"), "{}", chat);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,62 @@
+//! `p2witter --setup --handle @name`: TUI を開かずにハンドルと署名鍵を設定ファイルへ書く。
+//! 鍵が既にあれば使い回し、`--force` のときだけ作り直す
+
+use p2witter::core::crypto;
+use p2witter::{Error, config};
+
+/// `--handle <name>` / `--handle=<name>` の値
+pub fn handle_arg(args: impl IntoIterator<Item = String>) -> Option<String> {
+    let mut args = args.into_iter();
+    while let Some(a) = args.next() {
+        if a == "--handle" {
+            return args.next();
+        }
+        if let Some(name) = a.strip_prefix("--handle=") {
+            return Some(name.to_string());
+        }
+    }
+    None
+}
+
+/// 初期化済みの設定へハンドルと鍵を書き込み、表示用の結果を返す
+pub fn run_setup(handle: &str, force: bool) -> p2witter::Result<String> {
+    let handle = config::normalize_handle(handle);
+    let handle = handle.as_str();
+    if !config::is_valid_handle(handle) {
+        return Err(Error::Invalid(format!(
+            "ハンドル '{}' が不正です（{}）",
+            handle.escape_debug(),
+            config::handle_rule()
+        )));
+    }
+    // 読める鍵があれば残す（上書きすると元の ID は失われる）
+    let existing = config::get_value(config::keys::KEY_PKCS8)
+        .and_then(|v| v.as_str().and_then(|s| crypto::from_hex(s).ok()))
+        .and_then(|pkcs8| crypto::public_key_from_pkcs8(&pkcs8).ok());
+    config::upsert_value_and_save(
+        config::keys::USER_HANDLE,
+        toml::Value::String(handle.to_string()),
+    )?;
+    let (public, note) = match existing {
+        Some(public) if !force => (public, "既存の鍵を使います"),
+        _ => {
+            let k = crypto::generate_ed25519_keypair()?;
+            config::upsert_value_and_save(
+                config::keys::KEY_PKCS8,
+                toml::Value::String(crypto::to_hex(&k.pkcs8)),
+            )?;
+            (k.public, "鍵を生成しました")
+        }
+    };
+    // 公開鍵は秘密鍵から求め直して書く（食い違った設定を直す）
+    config::upsert_value_and_save(
+        config::keys::KEY_PUBLIC,
+        toml::Value::String(crypto::to_hex(&public)),
+    )?;
+    Ok(format!(
+        "ハンドル: {}\n{} (指紋={})",
+        handle,
+        note,
+        &crypto::fingerprint_hex(&public)[..16]
+    ))
+}
@@ -1,24 +1,545 @@
+use crate::core::rpc::SigState;
 use chrono::Datelike;
 use serde::{Deserialize, Serialize};
-use sled::Db;
-use std::sync::OnceLock;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
 
-static DB: OnceLock<Db> = OnceLock::new();
+use crate::error::Error;
 
-pub fn init_storage(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+type StorageResult<T> = crate::Result<T>;
+
+/// fetch_and_update に渡す書き換え（今の値 → 新しい値。None なら消す）
+pub type Updater<'a> = dyn FnMut(Option<&[u8]>) -> Option<Vec<u8>> + 'a;
+
+/// 名前の無い既定の表。メッセージ本体・日別カウンタ・日付の index を置く
+const MAIN_TREE: &str = "";
+
+/// 保存先の実装。履歴・監査ログ・下書きなどはすべて名前付きの表 (tree) への
+/// キーと値の読み書きで表すので、実装が用意するのはその基本操作だけでよい。
+/// 日ごとの追記や読み出しなど履歴の操作は、どの実装でも同じ既定のメソッドを使う
+pub trait Storage: Send + Sync {
+    fn get(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>>;
+    fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> StorageResult<()>;
+    /// 今の値を f で書き換え（None を返せば消す）、書き換える前の値を返す。
+    /// 読んでから書くまでの間に他の書き込みが割り込まない（連番の払い出しに使う）
+    fn fetch_and_update(
+        &self,
+        tree: &str,
+        key: &[u8],
+        f: &mut Updater,
+    ) -> StorageResult<Option<Vec<u8>>>;
+    /// 消した値を返す（無ければ None）
+    fn remove(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>>;
+    /// 表の中身をキーの昇順で返す
+    fn scan(&self, tree: &str) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>>;
+    /// prefix で始まるキーだけをキーの昇順で返す
+    fn scan_prefix(&self, tree: &str, prefix: &[u8]) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>>;
+    /// start 以上 end 未満（None なら末尾まで）のキーをキーの昇順で返す
+    fn scan_range(
+        &self,
+        tree: &str,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>>;
+    /// キーの大きい方から n 件を、キーの昇順で返す
+    fn scan_last(&self, tree: &str, n: usize) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>>;
+    fn clear(&self, tree: &str) -> StorageResult<()>;
+    /// 呼ぶたびに大きくなる番号（監査ログのキーに使う）
+    fn generate_id(&self) -> StorageResult<u64>;
+    /// 書いた内容を永続化する（永続化しない実装では何もしない）
+    fn flush(&self) -> StorageResult<()>;
+
+    /// 投稿を書き込んだあと、いつディスクへ書き出すか
+    fn durability(&self) -> DurabilityMode {
+        DurabilityMode::Safe
+    }
+
+    /// 投稿の書き込みのあとに呼ぶ。Safe のときだけすぐ flush する
+    fn flush_after_write(&self) -> StorageResult<()> {
+        match self.durability() {
+            DurabilityMode::Safe => self.flush(),
+            DurabilityMode::Fast | DurabilityMode::Relaxed => Ok(()),
+        }
+    }
+
+    fn contains(&self, tree: &str, key: &[u8]) -> StorageResult<bool> {
+        Ok(self.get(tree, key)?.is_some())
+    }
+
+    /// その日に保存した件数（削除済みの連番も含む）
+    fn day_total(&self, date: &str) -> u64 {
+        self.get(MAIN_TREE, format!("cnt:{}", date).as_bytes())
+            .ok()
+            .flatten()
+            .map(|v| decode_count(&v))
+            .unwrap_or(0)
+    }
+
+    /// 旧形式 (ts|text) で 1 件追記する
+    fn append(&self, ts_millis: u64, text: &str) -> StorageResult<()> {
+        let value = format!("{}|{}", ts_millis, text);
+        self.store_raw(&date_string(ts_millis), value.as_bytes(), None)
+            .map(|_| ())
+    }
+
+    /// 保存形式のバイト列を日 (YYYYMMDD) の末尾に追加し、index と ID 索引を更新する。
+    /// 保存したキー (YYYYMMDD + 連番) を返す
+    fn store_raw(&self, date: &str, data: &[u8], id: Option<&str>) -> StorageResult<String> {
+        // 連番は先に払い出す（同じ日に別のスレッドが書いても同じキーにならない）
+        let current = self
+            .fetch_and_update(MAIN_TREE, format!("cnt:{}", date).as_bytes(), &mut |old| {
+                Some(encode_count(old.map(decode_count).unwrap_or(0) + 1).to_vec())
+            })?
+            .map(|v| decode_count(&v))
+            .unwrap_or(0);
+        let msg_key = format!("{}{}", date, current);
+        self.insert(MAIN_TREE, msg_key.as_bytes(), data)?;
+        if current == 0 {
+            self.fetch_and_update(MAIN_TREE, b"index", &mut |old| {
+                let mut dates: Vec<&str> = old
+                    .map(|v| std::str::from_utf8(v).unwrap_or_default())
+                    .unwrap_or_default()
+                    .split('\n')
+                    .filter(|s| !s.is_empty())
+                    .collect();
+                if !dates.contains(&date) {
+                    dates.push(date);
+                    dates.sort();
+                }
+                Some(dates.join("\n").into_bytes())
+            })?;
+        }
+        if let Some(id) = id {
+            self.insert(ID_TREE, id.as_bytes(), msg_key.as_bytes())?;
+            self.insert(KEY_ID_TREE, msg_key.as_bytes(), id.as_bytes())?;
+        }
+        self.flush_after_write()?;
+        Ok(msg_key)
+    }
+
+    /// postcard で構造化して保存し、保存したキーを返す
+    fn store_structured(&self, rec: &MessageRecord, id: Option<&str>) -> StorageResult<String> {
+        let data = encode_record(rec)?;
+        self.store_raw(&date_string(rec.ts_millis), &data, id)
+    }
+
+    /// 1日の構造化メッセージを読み出し（古→新）
+    fn load_structured_day(&self, date: &str) -> Vec<MessageRecord> {
+        let mut out = Vec::new();
+        for i in 0..self.day_total(date) {
+            let key = format!("{}{}", date, i);
+            // 互換性: 旧フォーマット (ts|text) も記録として読む
+            if let Ok(Some(val)) = self.get(MAIN_TREE, key.as_bytes())
+                && let Some(rec) = decode_record(&val).or_else(|| decode_legacy(&val))
+            {
+                out.push(rec);
+            }
+        }
+        out
+    }
+
+    /// 保存のある日付（昇順 YYYYMMDD）
+    fn list_dates(&self) -> Vec<String> {
+        self.get(MAIN_TREE, b"index")
+            .ok()
+            .flatten()
+            .map(|v| {
+                String::from_utf8_lossy(&v)
+                    .split('\n')
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// 日ごとの保存件数 (YYYYMMDD, 件数)
+    fn day_counts(&self) -> Vec<(String, u64)> {
+        self.list_dates()
+            .into_iter()
+            .map(|date| {
+                let n = self.day_total(&date);
+                (date, n)
+            })
+            .collect()
+    }
+
+    /// ID で保存済みメッセージを読み出す（日単位の走査はしない）
+    fn get_by_id(&self, id: &str) -> Option<MessageRecord> {
+        let key = self.get(ID_TREE, id.as_bytes()).ok()??;
+        decode_record(&self.get(MAIN_TREE, &key).ok()??)
+    }
+
+    /// ID で保存済みメッセージの本文を差し替える。None なら削除済み (tombstone) にする。
+    /// 該当が無ければ false
+    fn amend_by_id(&self, id: &str, new_text: Option<&str>) -> StorageResult<bool> {
+        let Some(key) = self.get(ID_TREE, id.as_bytes())? else {
+            return Ok(false);
+        };
+        let Some(mut rec) = self
+            .get(MAIN_TREE, &key)?
+            .and_then(|val| decode_record(&val))
+        else {
+            return Ok(false);
+        };
+        rec.text = new_text.unwrap_or(DELETED_TEXT).to_string();
+        self.insert(MAIN_TREE, &key, &encode_record(&rec)?)?;
+        self.flush_after_write()?;
+        Ok(true)
+    }
+}
+
+/// 投稿を保存したあとディスクへ書き出す時機（config の durability）。
+/// 異常終了（停電・強制終了）で失いうるのは、最後に書き出してから後の投稿
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DurabilityMode {
+    /// 1 件ごとに書き出す。失うのは書き込み途中の 1 件だけだが、件数が多いと遅い
+    #[default]
+    Safe,
+    /// FAST_FLUSH_INTERVAL_MS ごとにまとめて書き出す。失うのは最大でその間隔ぶん
+    Fast,
+    /// 終了時だけ書き出す。異常終了では起動してから（前回の書き出しから）の投稿をすべて失いうる
+    Relaxed,
+}
+
+/// Fast で書き出す間隔
+pub const FAST_FLUSH_INTERVAL_MS: u64 = 1000;
+
+impl DurabilityMode {
+    /// "safe" / "fast" / "relaxed"
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "safe" => Some(Self::Safe),
+            "fast" => Some(Self::Fast),
+            "relaxed" => Some(Self::Relaxed),
+            _ => None,
+        }
+    }
+}
+
+/// sled の DB に保存する既定の実装
+pub struct SledStorage {
+    db: sled::Db,
+    durability: DurabilityMode,
+}
+
+impl SledStorage {
+    pub fn open(path: impl AsRef<std::path::Path>) -> StorageResult<Self> {
+        Self::open_with(path, DurabilityMode::Safe)
+    }
+
+    /// 書き出しの時機を指定して開く。Fast は sled の定期書き出しの間隔を FAST_FLUSH_INTERVAL_MS にし、
+    /// Relaxed は定期書き出しを止める
+    pub fn open_with(
+        path: impl AsRef<std::path::Path>,
+        durability: DurabilityMode,
+    ) -> StorageResult<Self> {
+        let every = match durability {
+            DurabilityMode::Safe => Some(500),
+            DurabilityMode::Fast => Some(FAST_FLUSH_INTERVAL_MS),
+            DurabilityMode::Relaxed => None,
+        };
+        let db = sled::Config::new()
+            .path(path)
+            .flush_every_ms(every)
+            .open()?;
+        Ok(Self { db, durability })
+    }
+
+    /// 終了時に消える一時 DB
+    pub fn temporary() -> StorageResult<Self> {
+        Ok(Self {
+            db: sled::Config::new().temporary(true).open()?,
+            durability: DurabilityMode::Safe,
+        })
+    }
+
+    fn tree(&self, name: &str) -> StorageResult<sled::Tree> {
+        if name == MAIN_TREE {
+            Ok((*self.db).clone())
+        } else {
+            Ok(self.db.open_tree(name)?)
+        }
+    }
+}
+
+fn collect_sled(
+    iter: impl Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>,
+) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+    iter.map(|kv| {
+        let (k, v) = kv?;
+        Ok((k.to_vec(), v.to_vec()))
+    })
+    .collect()
+}
+
+impl Storage for SledStorage {
+    fn get(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.tree(tree)?.get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> StorageResult<()> {
+        self.tree(tree)?.insert(key, value)?;
+        Ok(())
+    }
+
+    fn fetch_and_update(
+        &self,
+        tree: &str,
+        key: &[u8],
+        f: &mut Updater,
+    ) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self
+            .tree(tree)?
+            .fetch_and_update(key, f)?
+            .map(|v| v.to_vec()))
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.tree(tree)?.remove(key)?.map(|v| v.to_vec()))
+    }
+
+    fn scan(&self, tree: &str) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        collect_sled(self.tree(tree)?.iter())
+    }
+
+    fn scan_prefix(&self, tree: &str, prefix: &[u8]) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        collect_sled(self.tree(tree)?.scan_prefix(prefix))
+    }
+
+    fn scan_range(
+        &self,
+        tree: &str,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        collect_sled(
+            self.tree(tree)?
+                .range::<&[u8], _>((Bound::Included(start), end)),
+        )
+    }
+
+    fn scan_last(&self, tree: &str, n: usize) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut out = collect_sled(self.tree(tree)?.iter().rev().take(n))?;
+        out.reverse();
+        Ok(out)
+    }
+
+    fn clear(&self, tree: &str) -> StorageResult<()> {
+        self.tree(tree)?.clear()?;
+        Ok(())
+    }
+
+    fn generate_id(&self) -> StorageResult<u64> {
+        Ok(self.db.generate_id()?)
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn durability(&self) -> DurabilityMode {
+        self.durability
+    }
+}
+
+/// メモリ上の表 1 つ（キーの昇順）
+type MemoryTree = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// メモリだけに置く実装（テストや組み込み用。終了すると消える）
+#[derive(Default)]
+pub struct MemoryStorage {
+    trees: Mutex<HashMap<String, MemoryTree>>,
+    next_id: AtomicU64,
+}
+
+impl MemoryStorage {
+    fn with_tree<T>(&self, tree: &str, f: impl FnOnce(&mut MemoryTree) -> T) -> T {
+        let mut trees = self.trees.lock().unwrap_or_else(|e| e.into_inner());
+        f(trees.entry(tree.to_string()).or_default())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.with_tree(tree, |t| t.get(key).cloned()))
+    }
+
+    fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> StorageResult<()> {
+        self.with_tree(tree, |t| t.insert(key.to_vec(), value.to_vec()));
+        Ok(())
+    }
+
+    fn fetch_and_update(
+        &self,
+        tree: &str,
+        key: &[u8],
+        f: &mut Updater,
+    ) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.with_tree(tree, |t| {
+            let old = t.get(key).cloned();
+            match f(old.as_deref()) {
+                Some(v) => t.insert(key.to_vec(), v),
+                None => t.remove(key),
+            };
+            old
+        }))
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.with_tree(tree, |t| t.remove(key)))
+    }
+
+    fn scan(&self, tree: &str) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self.with_tree(tree, |t| {
+            t.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+        }))
+    }
+
+    fn scan_prefix(&self, tree: &str, prefix: &[u8]) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self.with_tree(tree, |t| {
+            t.range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(|(k, _)| k.starts_with(prefix))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        }))
+    }
+
+    fn scan_range(
+        &self,
+        tree: &str,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        Ok(self.with_tree(tree, |t| {
+            t.range::<[u8], _>((Bound::Included(start), end))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        }))
+    }
+
+    fn scan_last(&self, tree: &str, n: usize) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut out: Vec<_> = self.with_tree(tree, |t| {
+            t.iter()
+                .rev()
+                .take(n)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        });
+        out.reverse();
+        Ok(out)
+    }
+
+    fn clear(&self, tree: &str) -> StorageResult<()> {
+        self.with_tree(tree, MemoryTree::clear);
+        Ok(())
+    }
+
+    fn generate_id(&self) -> StorageResult<u64> {
+        Ok(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        Ok(())
+    }
+}
+
+static DB: OnceLock<Box<dyn Storage>> = OnceLock::new();
+/// config の no_history。立っている間はメッセージを一切書き込まない
+static HISTORY_DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// 保存先を sled の DB で初期化する
+pub fn init_storage(path: impl AsRef<std::path::Path>) -> StorageResult<()> {
+    init_storage_durable(path, DurabilityMode::Safe)
+}
+
+/// 書き出しの時機を指定して sled の DB で初期化する
+pub fn init_storage_durable(
+    path: impl AsRef<std::path::Path>,
+    durability: DurabilityMode,
+) -> StorageResult<()> {
     if DB.get().is_some() {
         return Ok(());
     }
-    let db = sled::open(path)?;
-    let _ = DB.set(db); // 既に初期化されていたら無視
+    init_storage_with(Box::new(SledStorage::open_with(path, durability)?));
     Ok(())
 }
 
-fn db_opt() -> Option<&'static Db> {
-    DB.get()
+/// まだ書き出していない分をディスクへ書き出す（終了時は durability によらず必ず呼ぶ）
+pub fn flush() -> StorageResult<()> {
+    match db_opt() {
+        Some(db) => db.flush(),
+        None => Ok(()),
+    }
+}
+
+/// 任意の実装を保存先にする。既に初期化されていたら何もしない
+pub fn init_storage_with(backend: Box<dyn Storage>) {
+    let _ = DB.set(backend);
+}
+
+/// DB を開けるか確かめてすぐ閉じる（--check 用。保存先の初期化はしない）
+pub fn check_db(path: impl AsRef<std::path::Path>) -> StorageResult<()> {
+    SledStorage::open(path)?.flush()
+}
+
+/// 開けなかったときに待つ回数（終了中の別プロセスがロックを持っている場合に備える）
+const OPEN_ATTEMPTS: u32 = 3;
+
+/// DB を開く。ロック中・破損などで開けなければ何度か待ってやり直し、
+/// それでも駄目ならメモリ上の保存先（終了時に消える）で続行する。
+/// 失敗した場合は画面に出す説明を Err で返す
+pub fn init_storage_or_memory(
+    path: impl AsRef<std::path::Path>,
+    durability: DurabilityMode,
+) -> Result<(), String> {
+    let path = path.as_ref();
+    let mut last_err = String::new();
+    for attempt in 0..OPEN_ATTEMPTS {
+        if attempt > 0 {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+        }
+        match init_storage_durable(path, durability) {
+            Ok(()) => return Ok(()),
+            Err(e) => last_err = e.to_string(),
+        }
+    }
+    init_storage_with(Box::new(MemoryStorage::default()));
+    Err(format!(
+        "DB を開けません ({}): {}。メモリのみで続行します（履歴は終了時に消えます）",
+        path.display(),
+        last_err
+    ))
+}
+
+fn db_opt() -> Option<&'static dyn Storage> {
+    DB.get().map(|b| b.as_ref())
+}
+
+/// 履歴の保存を止める・再開する
+pub fn set_history_disabled(disabled: bool) {
+    HISTORY_DISABLED.store(disabled, Ordering::Relaxed);
+}
+
+pub fn history_disabled() -> bool {
+    HISTORY_DISABLED.load(Ordering::Relaxed)
+}
+
+// メッセージを書き込む経路は必ずここを通す。no_history なら DB が無いのと同じ扱い
+fn history_db() -> Option<&'static dyn Storage> {
+    if history_disabled() {
+        return None;
+    }
+    db_opt()
 }
 
-fn date_string(ts_millis: u64) -> String {
+/// 保存の日付キー (YYYYMMDD, UTC)。履歴同期の日付にも使う
+pub fn date_string(ts_millis: u64) -> String {
     use chrono::{TimeZone, Utc};
     // ts is unix millis UTC
     let secs = (ts_millis / 1000) as i64;
@@ -51,7 +572,253 @@ pub struct MessageRecord {
     pub to_peer_id: Option<usize>,
     pub handle: Option<String>,
     pub text: String,
-    pub signed_ok: Option<bool>,
+    /// 署名状態（署名なし・検証済み・不正）
+    pub signature: SigState,
+    /// 返信先のメッセージID (hex)
+    pub reply_to: Option<String>,
+    /// UTF-8 でない DM 本文の生バイト（text には表示用の代わりの文字列が入る）
+    pub binary: Option<Vec<u8>>,
+    /// 署名の検証材料（/reverify 用。署名なし・自分の送信・旧形式は None）
+    pub proof: Option<SignatureProof>,
+    /// 相手の指紋（公開鍵 SHA-256 の先頭16桁）。受信なら送り主、送った DM なら宛先。
+    /// from_peer_id / to_peer_id は接続ごとの番号なので、後から相手を特定するにはこちらを使う
+    pub peer_fingerprint: Option<String>,
+}
+
+/// 受信した署名付きフレームの署名と、その対象のバイト列
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignatureProof {
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+    /// 署名対象 (protocol::signing_bytes)。DM は暗号文のまま、編集されても受信時のまま
+    pub signed: Vec<u8>,
+}
+
+impl SignatureProof {
+    /// 保存してある材料で署名を検証し直す
+    pub fn verify(&self) -> SigState {
+        match crate::core::crypto::verify_ed25519(&self.signed, &self.signature, &self.public_key) {
+            Ok(()) => SigState::Valid,
+            Err(_) => SigState::Invalid,
+        }
+    }
+
+    /// 署名対象に至急の印が含まれているか（v2 の署名付きフレームだけが持てる）
+    pub fn is_urgent(&self) -> bool {
+        crate::core::protocol::from_signing_bytes(&self.signed).is_some_and(|m| m.urgent)
+    }
+}
+
+/// peer_fingerprint 追加前の保存形式（読み込み互換用）
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct MessageRecordV5 {
+    ts_millis: u64,
+    recv_ts_millis: u64,
+    kind: MsgKind,
+    from_peer_id: Option<usize>,
+    to_peer_id: Option<usize>,
+    handle: Option<String>,
+    text: String,
+    signature: SigState,
+    reply_to: Option<String>,
+    binary: Option<Vec<u8>>,
+    proof: Option<SignatureProof>,
+}
+
+/// proof 追加前の保存形式（読み込み互換用）
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct MessageRecordV4 {
+    ts_millis: u64,
+    recv_ts_millis: u64,
+    kind: MsgKind,
+    from_peer_id: Option<usize>,
+    to_peer_id: Option<usize>,
+    handle: Option<String>,
+    text: String,
+    signature: SigState,
+    reply_to: Option<String>,
+    binary: Option<Vec<u8>>,
+}
+
+/// binary 追加前の保存形式（読み込み互換用）
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct MessageRecordV3 {
+    ts_millis: u64,
+    recv_ts_millis: u64,
+    kind: MsgKind,
+    from_peer_id: Option<usize>,
+    to_peer_id: Option<usize>,
+    handle: Option<String>,
+    text: String,
+    signature: SigState,
+    reply_to: Option<String>,
+}
+
+/// 署名状態を Option<bool> (None=署名なし, Some(false)=不正) で持っていた頃の保存形式（読み込み互換用）
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct MessageRecordV2 {
+    ts_millis: u64,
+    recv_ts_millis: u64,
+    kind: MsgKind,
+    from_peer_id: Option<usize>,
+    to_peer_id: Option<usize>,
+    handle: Option<String>,
+    text: String,
+    signed_ok: Option<bool>,
+    reply_to: Option<String>,
+}
+
+/// reply_to 追加前の保存形式（読み込み互換用）
+#[derive(Deserialize)]
+struct MessageRecordV1 {
+    ts_millis: u64,
+    recv_ts_millis: u64,
+    kind: MsgKind,
+    from_peer_id: Option<usize>,
+    to_peer_id: Option<usize>,
+    handle: Option<String>,
+    text: String,
+    signed_ok: Option<bool>,
+}
+
+/// 画面の 1 行を保存用の記録にする。行はそのまま System として残し、
+/// "@x: " で始まればそのハンドルも控えておく
+fn line_record(ts_millis: u64, text: &str) -> MessageRecord {
+    let handle = text
+        .split_once(": ")
+        .map(|(name, _)| name)
+        .filter(|name| crate::config::is_valid_handle(name))
+        .map(str::to_string);
+    MessageRecord {
+        ts_millis,
+        recv_ts_millis: ts_millis,
+        kind: MsgKind::System,
+        from_peer_id: None,
+        to_peer_id: None,
+        handle,
+        text: text.to_string(),
+        signature: SigState::Unsigned,
+        reply_to: None,
+        binary: None,
+        proof: None,
+        peer_fingerprint: None,
+    }
+}
+
+/// 旧形式 (ts|text) の値を記録として読む
+fn decode_legacy(val: &[u8]) -> Option<MessageRecord> {
+    let s = String::from_utf8_lossy(val);
+    let (ts, text) = s.split_once('|')?;
+    Some(line_record(ts.parse().unwrap_or(0), text))
+}
+
+/// 現行の保存形式の先頭バイト。旧形式は ts の varint（先頭ビットが立つ）か
+/// "ts|text"（数字）で始まるので取り違えない
+const RECORD_TAG: u8 = 6;
+/// peer_fingerprint 追加前の形式の先頭バイト
+const RECORD_TAG_V5: u8 = 5;
+/// proof 追加前の形式の先頭バイト
+const RECORD_TAG_V4: u8 = 4;
+/// binary 追加前の形式の先頭バイト
+const RECORD_TAG_V3: u8 = 3;
+
+fn encode_record(rec: &MessageRecord) -> Result<Vec<u8>, postcard::Error> {
+    let mut out = vec![RECORD_TAG];
+    out.extend_from_slice(&postcard::to_allocvec(rec)?);
+    Ok(out)
+}
+
+fn decode_record(val: &[u8]) -> Option<MessageRecord> {
+    match val.split_first() {
+        Some((&RECORD_TAG, body)) => return postcard::from_bytes(body).ok(),
+        Some((&RECORD_TAG_V5, body)) => {
+            let old = postcard::from_bytes::<MessageRecordV5>(body).ok()?;
+            return Some(MessageRecord {
+                ts_millis: old.ts_millis,
+                recv_ts_millis: old.recv_ts_millis,
+                kind: old.kind,
+                from_peer_id: old.from_peer_id,
+                to_peer_id: old.to_peer_id,
+                handle: old.handle,
+                text: old.text,
+                signature: old.signature,
+                reply_to: old.reply_to,
+                binary: old.binary,
+                proof: old.proof,
+                peer_fingerprint: None,
+            });
+        }
+        Some((&RECORD_TAG_V4, body)) => {
+            let old = postcard::from_bytes::<MessageRecordV4>(body).ok()?;
+            return Some(MessageRecord {
+                ts_millis: old.ts_millis,
+                recv_ts_millis: old.recv_ts_millis,
+                kind: old.kind,
+                from_peer_id: old.from_peer_id,
+                to_peer_id: old.to_peer_id,
+                handle: old.handle,
+                text: old.text,
+                signature: old.signature,
+                reply_to: old.reply_to,
+                binary: old.binary,
+                proof: None,
+                peer_fingerprint: None,
+            });
+        }
+        Some((&RECORD_TAG_V3, body)) => {
+            let old = postcard::from_bytes::<MessageRecordV3>(body).ok()?;
+            return Some(MessageRecord {
+                ts_millis: old.ts_millis,
+                recv_ts_millis: old.recv_ts_millis,
+                kind: old.kind,
+                from_peer_id: old.from_peer_id,
+                to_peer_id: old.to_peer_id,
+                handle: old.handle,
+                text: old.text,
+                signature: old.signature,
+                reply_to: old.reply_to,
+                binary: None,
+                proof: None,
+                peer_fingerprint: None,
+            });
+        }
+        _ => {}
+    }
+    if let Ok(old) = postcard::from_bytes::<MessageRecordV2>(val) {
+        return Some(MessageRecord {
+            ts_millis: old.ts_millis,
+            recv_ts_millis: old.recv_ts_millis,
+            kind: old.kind,
+            from_peer_id: old.from_peer_id,
+            to_peer_id: old.to_peer_id,
+            handle: old.handle,
+            text: old.text,
+            signature: SigState::from_signed_ok(old.signed_ok),
+            reply_to: old.reply_to,
+            binary: None,
+            proof: None,
+            peer_fingerprint: None,
+        });
+    }
+    let old = postcard::from_bytes::<MessageRecordV1>(val).ok()?;
+    Some(MessageRecord {
+        ts_millis: old.ts_millis,
+        recv_ts_millis: old.recv_ts_millis,
+        kind: old.kind,
+        from_peer_id: old.from_peer_id,
+        to_peer_id: old.to_peer_id,
+        handle: old.handle,
+        text: old.text,
+        signature: SigState::from_signed_ok(old.signed_ok),
+        reply_to: None,
+        binary: None,
+        proof: None,
+        peer_fingerprint: None,
+    })
 }
 
 /// メッセージの種類（最小限）
@@ -62,148 +829,1945 @@ pub enum MsgKind {
     System,
 }
 
-/// Append one message (ts|text) into sled. Maintains per-day counter and global index of dates.
-pub fn append_message(ts_millis: u64, text: &str) {
-    let Some(db) = db_opt() else {
-        return;
-    };
-    let date = date_string(ts_millis);
-    // counter key cnt:YYYYMMDD
-    let cnt_key = format!("cnt:{}", date);
-    let current = db
-        .get(&cnt_key)
-        .ok()
-        .flatten()
-        .map(|v| decode_count(&v))
-        .unwrap_or(0);
-    let msg_key = format!("{}{}", date, current); // e.g. 202511080
-    let value = format!("{}|{}", ts_millis, text);
-    let _ = db.insert(msg_key.as_bytes(), value.as_bytes());
-    let next = encode_count(current + 1);
-    let _ = db.insert(cnt_key.as_bytes(), &next);
-    if current == 0 {
-        // update index list of dates
-        let idx_key = b"index";
-        let mut dates: Vec<String> = db
-            .get(idx_key)
-            .ok()
-            .flatten()
-            .map(|v| {
-                String::from_utf8_lossy(&v)
-                    .split('\n')
-                    .filter(|s| !s.is_empty())
-                    .map(|s| s.to_string())
-                    .collect()
-            })
-            .unwrap_or_default();
-        if !dates.iter().any(|d| d == &date) {
-            dates.push(date.clone());
-            dates.sort();
-            let body = dates.join("\n");
-            let _ = db.insert(idx_key, body.as_bytes());
+/// 監査ログの種類
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum AuditKind {
+    /// 署名検証の失敗
+    BadSignature,
+    /// 公開鍵の変更（鍵ローテーション）
+    KeyChange,
+    /// 理由付きの切断（送信・受信とも）
+    Disconnect,
+    /// 中継途中の改ざん（メッセージIDの不一致）
+    Tampered,
+    /// トークンのアドレスと実際の接続先の不一致
+    AddressMismatch,
+    /// 保存期間を過ぎた履歴の削除
+    Prune,
+    /// 送信待ちが上限を超え、いちばん詰まっているピアを切断
+    Backpressure,
+    /// 隣接ピア本人の通し番号の抜け・逆行
+    Sequence,
+}
+
+/// セキュリティ関連イベントの監査ログ（チャット履歴とは別ツリーに追記のみ）
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AuditEvent {
+    pub ts_millis: u64,
+    pub kind: AuditKind,
+    pub peer_id: Option<usize>,
+    /// 公開鍵 SHA-256 の先頭16桁
+    pub fingerprint: Option<String>,
+    pub detail: String,
+}
+
+impl std::fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use chrono::{Local, TimeZone};
+        let at = Local
+            .timestamp_millis_opt(self.ts_millis as i64)
+            .single()
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| self.ts_millis.to_string());
+        write!(f, "{} {:?}", at, self.kind)?;
+        if let Some(id) = self.peer_id {
+            write!(f, " id={}", id)?;
         }
+        if let Some(fp) = &self.fingerprint {
+            write!(f, " 指紋={}", fp)?;
+        }
+        write!(f, " {}", self.detail)
     }
-    let _ = db.flush();
 }
 
-/// postcard で構造化して保存
-pub fn store_structured(rec: &MessageRecord) -> Result<(), Box<dyn std::error::Error>> {
+const AUDIT_TREE: &str = "audit";
+/// 監査ログに残す件数の上限（超えた分は古いものから消す）
+pub(crate) const AUDIT_MAX_ENTRIES: usize = 10_000;
+/// この件数ごとに上限を超えた分を消す
+const AUDIT_TRIM_EVERY: u64 = 256;
+/// この件数ごとに監査ログを書き出す
+const AUDIT_FLUSH_EVERY: u64 = 32;
+
+/// 監査ログに追記する。/history clear では消えない
+pub fn append_audit(event: &AuditEvent) -> StorageResult<()> {
     let Some(db) = db_opt() else {
         return Ok(());
     };
-    let date = date_string(rec.ts_millis);
-    let cnt_key = format!("cnt:{}", date);
-    let current = db
-        .get(&cnt_key)
-        .ok()
-        .flatten()
-        .map(|v| decode_count(&v))
-        .unwrap_or(0);
-    let msg_key = format!("{}{}", date, current);
-    let data = postcard::to_allocvec(rec)?;
-    db.insert(msg_key.as_bytes(), data)?;
-    let next = encode_count(current + 1);
-    db.insert(cnt_key.as_bytes(), &next)?;
-    if current == 0 {
-        let idx_key = b"index";
-        let mut dates: Vec<String> = db
-            .get(idx_key)
-            .ok()
-            .flatten()
-            .map(|v| {
-                String::from_utf8_lossy(&v)
-                    .split('\n')
-                    .filter(|s| !s.is_empty())
-                    .map(|s| s.to_string())
-                    .collect()
-            })
-            .unwrap_or_default();
-        if !dates.iter().any(|d| d == &date) {
-            dates.push(date.clone());
-            dates.sort();
-            let body = dates.join("\n");
-            db.insert(idx_key, body.as_bytes())?;
-        }
+    append_audit_in(db, event)
+}
+
+pub(crate) fn append_audit_in(db: &dyn Storage, event: &AuditEvent) -> StorageResult<()> {
+    // generate_id は単調増加なので挿入順に並ぶ
+    let id = db.generate_id()?;
+    db.insert(
+        AUDIT_TREE,
+        &id.to_be_bytes(),
+        &postcard::to_allocvec(event)?,
+    )?;
+    // 1 件ごとには書き出さない（間の分は保存先の定期書き出しに任せる）
+    if id % AUDIT_FLUSH_EVERY == 0 {
+        db.flush()?;
+    }
+    if id % AUDIT_TRIM_EVERY == 0 {
+        trim_audit_in(db, AUDIT_MAX_ENTRIES)?;
     }
-    db.flush()?;
     Ok(())
 }
 
-/// 1日の構造化メッセージを読み出し（古→新）
-pub fn load_structured_day(date: &str) -> Vec<MessageRecord> {
+/// 監査ログを新しい keep 件だけ残して古いものを消し、消した件数を返す
+pub(crate) fn trim_audit_in(db: &dyn Storage, keep: usize) -> StorageResult<usize> {
+    // 残す中でいちばん古いキーより前を消す
+    let kept = db.scan_last(AUDIT_TREE, keep.max(1))?;
+    let Some((oldest_kept, _)) = kept.first().filter(|_| kept.len() >= keep) else {
+        return Ok(0);
+    };
+    let mut old = db.scan_range(AUDIT_TREE, &[], Some(oldest_kept))?;
+    if keep == 0 {
+        old.extend(kept);
+    }
+    for (key, _) in &old {
+        db.remove(AUDIT_TREE, key)?;
+    }
+    Ok(old.len())
+}
+
+/// 直近 n 件の監査ログ（古→新）
+pub fn recent_audit(n: usize) -> Vec<AuditEvent> {
     let Some(db) = db_opt() else {
         return Vec::new();
     };
-    let cnt_key = format!("cnt:{}", date);
-    let total = db
-        .get(&cnt_key)
-        .ok()
-        .flatten()
-        .map(|v| decode_count(&v))
-        .unwrap_or(0);
-    let mut out = Vec::new();
-    for i in 0..total {
-        let key = format!("{}{}", date, i);
-        if let Ok(Some(val)) = db.get(key.as_bytes()) {
-            if let Ok(rec) = postcard::from_bytes::<MessageRecord>(&val) {
-                out.push(rec);
-            } else {
-                // 互換性: 旧フォーマット(ts|text)なら文字列として復元
-                let s = String::from_utf8_lossy(&val).to_string();
-                if let Some(pos) = s.find('|') {
-                    let ts = s[..pos].parse::<u64>().unwrap_or(0);
-                    let txt = s[pos + 1..].to_string();
-                    out.push(MessageRecord {
-                        ts_millis: ts,
-                        recv_ts_millis: ts,
-                        kind: MsgKind::System,
-                        from_peer_id: None,
-                        to_peer_id: None,
-                        handle: None,
-                        text: txt,
-                        signed_ok: None,
-                    });
-                }
+    recent_audit_in(db, n)
+}
+
+pub(crate) fn recent_audit_in(db: &dyn Storage, n: usize) -> Vec<AuditEvent> {
+    let Ok(entries) = db.scan_last(AUDIT_TREE, n) else {
+        return Vec::new();
+    };
+    entries
+        .iter()
+        .filter_map(|(_, v)| postcard::from_bytes(v).ok())
+        .collect()
+}
+
+/// 画面に出した自分の行を保存する（旧形式ではなく構造化した記録で）
+pub fn append_message(ts_millis: u64, text: &str) {
+    let Some(db) = history_db() else {
+        return;
+    };
+    let _ = db.store_structured(&line_record(ts_millis, text), None);
+}
+
+/// 旧形式の変換が済んだ印のキー（値は変換の版）。MAIN_TREE に置く
+const LEGACY_MIGRATION_KEY: &[u8] = b"migrated:legacy";
+/// 1: 旧形式の行を構造化した記録へ、2: ID 索引の逆引き (KEY_ID_TREE) を作る
+const LEGACY_MIGRATION_VERSION: u64 = 2;
+
+/// 旧形式 (ts|text) で保存された行を構造化した記録に書き換え、書き換えた件数を返す。
+/// 済んだら印を残すので、2 回目以降の起動では何もしない
+pub fn migrate_legacy() -> StorageResult<usize> {
+    let Some(db) = db_opt() else {
+        return Ok(0);
+    };
+    migrate_legacy_in(db)
+}
+
+pub(crate) fn migrate_legacy_in(db: &dyn Storage) -> StorageResult<usize> {
+    if db
+        .get(MAIN_TREE, LEGACY_MIGRATION_KEY)?
+        .is_some_and(|v| decode_count(&v) >= LEGACY_MIGRATION_VERSION)
+    {
+        return Ok(0);
+    }
+    // どちらも済んだ分は何もしないので、途中の版からでも全部やり直してよい
+    for (id, key) in db.scan(ID_TREE)? {
+        db.insert(KEY_ID_TREE, &key, &id)?;
+    }
+    let mut migrated = 0usize;
+    for date in db.list_dates() {
+        for i in 0..db.day_total(&date) {
+            let key = format!("{}{}", date, i);
+            let Some(val) = db.get(MAIN_TREE, key.as_bytes())? else {
+                continue;
+            };
+            if decode_record(&val).is_some() {
+                continue;
             }
+            let Some(rec) = decode_legacy(&val) else {
+                continue;
+            };
+            db.insert(MAIN_TREE, key.as_bytes(), &encode_record(&rec)?)?;
+            migrated += 1;
         }
     }
-    out
+    db.insert(
+        MAIN_TREE,
+        LEGACY_MIGRATION_KEY,
+        &encode_count(LEGACY_MIGRATION_VERSION),
+    )?;
+    db.flush()?;
+    Ok(migrated)
 }
 
-/// Get list of known dates (sorted ascending YYYYMMDD)
-pub fn list_dates() -> Vec<String> {
-    let Some(db) = db_opt() else {
-        return Vec::new();
+/// メッセージID → 保存先キー (YYYYMMDD + 連番) の索引ツリー
+const ID_TREE: &str = "ids";
+/// ID_TREE の逆引き（保存先キー → メッセージID）。キーが日付で始まるので日ごとに引ける
+const KEY_ID_TREE: &str = "ids_by_key";
+
+/// postcard で構造化して保存。id があれば索引に登録し、後から get_by_id で引けるようにする
+pub fn store_structured(rec: &MessageRecord, id: Option<&str>) -> StorageResult<()> {
+    let Some(db) = history_db() else {
+        return Ok(());
     };
-    db.get(b"index")
-        .ok()
-        .flatten()
-        .map(|v| {
-            String::from_utf8_lossy(&v)
-                .split('\n')
-                .filter(|s| !s.is_empty())
-                .map(|s| s.to_string())
+    db.store_structured(rec, id).map(|_| ())
+}
+
+/// ID で保存済みメッセージの本文を差し替える。None なら削除済み (tombstone) にする。
+/// 該当が無ければ false。
+pub fn amend_by_id(id: &str, new_text: Option<&str>) -> StorageResult<bool> {
+    let Some(db) = db_opt() else {
+        return Ok(false);
+    };
+    db.amend_by_id(id, new_text)
+}
+
+/// 削除されたメッセージの表示
+pub const DELETED_TEXT: &str = "[deleted]";
+
+/// ID で保存済みメッセージを読み出す（日単位の走査はしない）
+pub fn get_by_id(id: &str) -> Option<MessageRecord> {
+    db_opt()?.get_by_id(id)
+}
+
+/// 1日の構造化メッセージを読み出し（古→新）
+pub fn load_structured_day(date: &str) -> Vec<MessageRecord> {
+    let Some(db) = db_opt() else {
+        return Vec::new();
+    };
+    db.load_structured_day(date)
+}
+
+/// Get list of known dates (sorted ascending YYYYMMDD)
+pub fn list_dates() -> Vec<String> {
+    let Some(db) = db_opt() else {
+        return Vec::new();
+    };
+    db.list_dates()
+}
+
+/// 日ごとの保存件数 (YYYYMMDD, 件数)。履歴同期で相手と比べる
+pub fn day_counts() -> Vec<(String, u64)> {
+    let Some(db) = db_opt() else {
+        return Vec::new();
+    };
+    db.day_counts()
+}
+
+/// その日の ID 付きメッセージ (ID, 記録) を保存順に返す
+pub fn records_with_ids(date: &str) -> Vec<(String, MessageRecord)> {
+    let Some(db) = db_opt() else {
+        return Vec::new();
+    };
+    records_with_ids_in(db, date)
+}
+
+fn records_with_ids_in(db: &dyn Storage, date: &str) -> Vec<(String, MessageRecord)> {
+    let Ok(ids) = db.scan_prefix(KEY_ID_TREE, date.as_bytes()) else {
+        return Vec::new();
+    };
+    let mut out: Vec<(u64, String, MessageRecord)> = ids
+        .into_iter()
+        .filter_map(|(key, id)| {
+            // キーは YYYYMMDD + 連番
+            let seq = std::str::from_utf8(&key[date.len()..]).ok()?.parse().ok()?;
+            let rec = decode_record(&db.get(MAIN_TREE, &key).ok()??)?;
+            Some((seq, String::from_utf8(id).ok()?, rec))
+        })
+        .collect();
+    out.sort_by_key(|(seq, ..)| *seq);
+    out.into_iter().map(|(_, id, rec)| (id, rec)).collect()
+}
+
+/// その日の ID 付きメッセージを連番 `from` から最大 `limit` 件、(連番, ID, 記録) で返す。
+/// 2 つ目の値は次に読む連番（日の終わりまで読んだら日の件数と同じ）
+pub fn records_page(
+    date: &str,
+    from: u64,
+    limit: usize,
+) -> (Vec<(u64, String, MessageRecord)>, u64) {
+    let Some(db) = db_opt() else {
+        return (Vec::new(), from);
+    };
+    records_page_in(db, date, from, limit)
+}
+
+fn records_page_in(
+    db: &dyn Storage,
+    date: &str,
+    from: u64,
+    limit: usize,
+) -> (Vec<(u64, String, MessageRecord)>, u64) {
+    let total = db.day_total(date);
+    let mut out = Vec::new();
+    let mut seq = from;
+    while seq < total && out.len() < limit {
+        let key = format!("{}{}", date, seq);
+        seq += 1;
+        let Some(id) = db.get(KEY_ID_TREE, key.as_bytes()).ok().flatten() else {
+            continue;
+        };
+        let Some(rec) = db
+            .get(MAIN_TREE, key.as_bytes())
+            .ok()
+            .flatten()
+            .and_then(|v| decode_record(&v))
+        else {
+            continue;
+        };
+        if let Ok(id) = String::from_utf8(id) {
+            out.push((seq - 1, id, rec));
+        }
+    }
+    (out, seq)
+}
+
+/// 全メッセージ・日別カウンタ・index・ID 索引を削除し、削除したメッセージ数を返す。
+/// 設定や鍵 (config.toml) には触れない。
+pub fn clear_all() -> StorageResult<usize> {
+    let Some(db) = db_opt() else {
+        return Ok(0);
+    };
+    clear_all_in(db)
+}
+
+fn clear_all_in(db: &dyn Storage) -> StorageResult<usize> {
+    let mut removed = 0usize;
+    for date in db.list_dates() {
+        for i in 0..db.day_total(&date) {
+            let key = format!("{}{}", date, i);
+            if db.remove(MAIN_TREE, key.as_bytes())?.is_some() {
+                removed += 1;
+            }
+        }
+        db.remove(MAIN_TREE, format!("cnt:{}", date).as_bytes())?;
+    }
+    db.remove(MAIN_TREE, b"index")?;
+    db.clear(ID_TREE)?;
+    db.clear(KEY_ID_TREE)?;
+    db.flush()?;
+    Ok(removed)
+}
+
+/// 種類ごとの保存期間（日）。None なら期限なし
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// DM 以外（全体チャット・お知らせ・旧形式の行）
+    pub chat_days: Option<u64>,
+    pub dm_days: Option<u64>,
+}
+
+impl Retention {
+    /// config の chat_retention_days / dm_retention_days（未指定か0なら期限なし）
+    pub fn from_config(tbl: &toml::Table) -> Self {
+        let days = |key: &str| {
+            tbl.get(key)
+                .and_then(|v| v.as_integer())
+                .and_then(|n| u64::try_from(n).ok())
+                .filter(|&n| n > 0)
+        };
+        Self {
+            chat_days: days(crate::config::keys::CHAT_RETENTION_DAYS),
+            dm_days: days(crate::config::keys::DM_RETENTION_DAYS),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.chat_days.is_some() || self.dm_days.is_some()
+    }
+
+    fn days_for(&self, kind: MsgKind) -> Option<u64> {
+        match kind {
+            MsgKind::Dm => self.dm_days,
+            MsgKind::Chat | MsgKind::System => self.chat_days,
+        }
+    }
+}
+
+/// 保存期間切れで削除した件数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneCount {
+    pub chat: usize,
+    pub dm: usize,
+}
+
+/// 保存期間を過ぎたメッセージを種類ごとに削除する。消したものがあれば監査ログに残す
+pub fn prune_expired(retention: &Retention, now_millis: u64) -> StorageResult<PruneCount> {
+    let Some(db) = db_opt() else {
+        return Ok(PruneCount::default());
+    };
+    prune_expired_in(db, retention, now_millis)
+}
+
+pub(crate) fn prune_expired_in(
+    db: &dyn Storage,
+    retention: &Retention,
+    now_millis: u64,
+) -> StorageResult<PruneCount> {
+    const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+    let cutoff = |kind: MsgKind| {
+        retention
+            .days_for(kind)
+            .map(|d| now_millis.saturating_sub(d.saturating_mul(DAY_MILLIS)))
+    };
+    let mut count = PruneCount::default();
+    let mut removed_keys = std::collections::HashSet::new();
+    let mut dates = db.list_dates();
+    dates.retain(|date| {
+        let mut left = 0usize;
+        for i in 0..db.day_total(date) {
+            let key = format!("{}{}", date, i);
+            let Ok(Some(val)) = db.get(MAIN_TREE, key.as_bytes()) else {
+                continue;
+            };
+            // 旧形式 (ts|text) は System として読む
+            let (ts, kind) = match decode_record(&val) {
+                Some(r) => (r.ts_millis, r.kind),
+                None => {
+                    let s = String::from_utf8_lossy(&val);
+                    let ts = s.split('|').next().and_then(|t| t.parse().ok());
+                    (ts.unwrap_or(0), MsgKind::System)
+                }
+            };
+            if cutoff(kind).is_some_and(|c| ts < c) && db.remove(MAIN_TREE, key.as_bytes()).is_ok()
+            {
+                match kind {
+                    MsgKind::Dm => count.dm += 1,
+                    MsgKind::Chat | MsgKind::System => count.chat += 1,
+                }
+                removed_keys.insert(key.into_bytes());
+            } else {
+                left += 1;
+            }
+        }
+        // 空になった日は index からも外す
+        if left == 0 {
+            let _ = db.remove(MAIN_TREE, format!("cnt:{}", date).as_bytes());
+        }
+        left > 0
+    });
+    if removed_keys.is_empty() {
+        return Ok(count);
+    }
+    db.insert(MAIN_TREE, b"index", dates.join("\n").as_bytes())?;
+    for key in &removed_keys {
+        if let Some(id) = db.remove(KEY_ID_TREE, key)? {
+            db.remove(ID_TREE, &id)?;
+        }
+    }
+    db.flush()?;
+    let days = |d: Option<u64>| d.map_or("無期限".to_string(), |d| format!("{}日", d));
+    append_audit_in(
+        db,
+        &AuditEvent {
+            ts_millis: now_millis,
+            kind: AuditKind::Prune,
+            peer_id: None,
+            fingerprint: None,
+            detail: format!(
+                "保存期間切れを削除: チャット {} 件 (保存 {}) / DM {} 件 (保存 {})",
+                count.chat,
+                days(retention.chat_days),
+                count.dm,
+                days(retention.dm_days)
+            ),
+        },
+    )?;
+    Ok(count)
+}
+
+/// バイナリバックアップの先頭
+const BACKUP_MAGIC: &[u8; 4] = b"P2WB";
+/// バイナリバックアップの形式バージョン
+pub const BACKUP_VERSION: u8 = 1;
+
+/// 全メッセージをバイナリ形式で書き出し、件数を返す。
+/// 形式: "P2WB" || version(u8) || 件数(u64) || 件数ぶんの
+/// [日付(8B "YYYYMMDD") || ID長(u8, 0=なし) || ID || 値の長さ(u32) || 保存済みの値そのまま]。
+/// 整数はすべてビッグエンディアン。値は postcard のバイト列（旧形式の "ts|text" も）を加工しない
+pub fn export_binary(writer: impl std::io::Write) -> StorageResult<u64> {
+    let Some(db) = db_opt() else {
+        return Err(Error::NotInitialized);
+    };
+    export_binary_in(db, writer)
+}
+
+fn export_binary_in(db: &dyn Storage, mut writer: impl std::io::Write) -> StorageResult<u64> {
+    // 保存先キー → メッセージID
+    let mut ids: HashMap<Vec<u8>, Vec<u8>> = db
+        .scan(ID_TREE)?
+        .into_iter()
+        .map(|(id, key)| (key, id))
+        .collect();
+    let mut entries = Vec::new();
+    for date in db.list_dates() {
+        for i in 0..db.day_total(&date) {
+            let key = format!("{}{}", date, i);
+            if let Some(val) = db.get(MAIN_TREE, key.as_bytes())? {
+                entries.push((date.clone(), ids.remove(key.as_bytes()), val));
+            }
+        }
+    }
+    writer.write_all(BACKUP_MAGIC)?;
+    writer.write_all(&[BACKUP_VERSION])?;
+    writer.write_all(&(entries.len() as u64).to_be_bytes())?;
+    for (date, id, val) in &entries {
+        let id = id.as_deref().unwrap_or_default();
+        if date.len() != 8 || id.len() > u8::MAX as usize {
+            return Err(Error::Invalid(format!("書き出せないキー: {}", date)));
+        }
+        writer.write_all(date.as_bytes())?;
+        writer.write_all(&[id.len() as u8])?;
+        writer.write_all(id)?;
+        writer.write_all(&(val.len() as u32).to_be_bytes())?;
+        writer.write_all(val)?;
+    }
+    writer.flush()?;
+    Ok(entries.len() as u64)
+}
+
+/// export_binary の出力を読み込んで追加する。同じ日に同じ値があるもの・既知の ID は飛ばす。
+/// (追加した件数, 重複で飛ばした件数) を返す
+pub fn import_binary(reader: impl std::io::Read) -> StorageResult<(u64, u64)> {
+    let Some(db) = history_db() else {
+        return Err(Error::NotInitialized);
+    };
+    import_binary_in(db, reader)
+}
+
+fn import_binary_in(db: &dyn Storage, mut reader: impl std::io::Read) -> StorageResult<(u64, u64)> {
+    let mut header = [0u8; 13];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != BACKUP_MAGIC {
+        return Err(Error::Invalid("バックアップファイルではありません".into()));
+    }
+    if header[4] != BACKUP_VERSION {
+        return Err(Error::Invalid(format!(
+            "未対応のバックアップ形式です (version={})",
+            header[4]
+        )));
+    }
+    let count = u64::from_be_bytes(header[5..13].try_into().expect("8 バイト"));
+    // 日付ごとの既存の値（重複判定用）
+    let mut existing: HashMap<String, HashSet<Vec<u8>>> = HashMap::new();
+    let (mut imported, mut skipped) = (0u64, 0u64);
+    for _ in 0..count {
+        let mut date = [0u8; 8];
+        reader.read_exact(&mut date)?;
+        let date = std::str::from_utf8(&date)?.to_string();
+        if !date.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(Error::Invalid(format!("不正な日付: {}", date)));
+        }
+        let mut id_len = [0u8; 1];
+        reader.read_exact(&mut id_len)?;
+        let mut id = vec![0u8; id_len[0] as usize];
+        reader.read_exact(&mut id)?;
+        let id = (!id.is_empty())
+            .then(|| String::from_utf8(id))
+            .transpose()?;
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let mut val = vec![0u8; u32::from_be_bytes(len) as usize];
+        reader.read_exact(&mut val)?;
+
+        let seen = existing.entry(date.clone()).or_insert_with(|| {
+            (0..db.day_total(&date))
+                .filter_map(|i| {
+                    db.get(MAIN_TREE, format!("{}{}", date, i).as_bytes())
+                        .ok()
+                        .flatten()
+                })
                 .collect()
+        });
+        let known_id = match &id {
+            Some(id) => db.contains(ID_TREE, id.as_bytes())?,
+            None => false,
+        };
+        if known_id || seen.contains(&val) {
+            skipped += 1;
+            continue;
+        }
+        db.store_raw(&date, &val, id.as_deref())?;
+        seen.insert(val);
+        imported += 1;
+    }
+    Ok((imported, skipped))
+}
+
+/// 名前付きの下書き（入力途中の本文）の保存先ツリー。履歴の削除では消さない
+const DRAFT_TREE: &str = "drafts";
+
+/// 下書きを保存（同じ名前は上書き）
+pub fn save_draft(name: &str, text: &str) -> StorageResult<()> {
+    let Some(db) = db_opt() else {
+        return Ok(());
+    };
+    save_draft_in(db, name, text)
+}
+
+fn save_draft_in(db: &dyn Storage, name: &str, text: &str) -> StorageResult<()> {
+    db.insert(DRAFT_TREE, name.as_bytes(), text.as_bytes())?;
+    db.flush()?;
+    Ok(())
+}
+
+pub fn load_draft(name: &str) -> Option<String> {
+    load_draft_in(db_opt()?, name)
+}
+
+fn load_draft_in(db: &dyn Storage, name: &str) -> Option<String> {
+    let v = db.get(DRAFT_TREE, name.as_bytes()).ok()??;
+    Some(String::from_utf8_lossy(&v).to_string())
+}
+
+/// 保存済みの下書き名（名前順）
+pub fn list_drafts() -> Vec<String> {
+    let Some(db) = db_opt() else {
+        return Vec::new();
+    };
+    list_drafts_in(db)
+}
+
+fn list_drafts_in(db: &dyn Storage) -> Vec<String> {
+    tree_keys(db, DRAFT_TREE)
+}
+
+// 表のキーを文字列として並べる（キーの昇順）
+fn tree_keys(db: &dyn Storage, tree: &str) -> Vec<String> {
+    db.scan(tree)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(k, _)| String::from_utf8_lossy(&k).to_string())
+        .collect()
+}
+
+/// ブックマークしたメッセージID の保存先ツリー（値は空）
+const BOOKMARK_TREE: &str = "bookmarks";
+
+/// メッセージID のブックマークを付け外しする
+pub fn set_bookmark(id: &str, on: bool) -> StorageResult<()> {
+    let Some(db) = db_opt() else {
+        return Ok(());
+    };
+    set_bookmark_in(db, id, on)
+}
+
+fn set_bookmark_in(db: &dyn Storage, id: &str, on: bool) -> StorageResult<()> {
+    if on {
+        db.insert(BOOKMARK_TREE, id.as_bytes(), &[])?;
+    } else {
+        db.remove(BOOKMARK_TREE, id.as_bytes())?;
+    }
+    db.flush()?;
+    Ok(())
+}
+
+/// ブックマーク済みのメッセージID
+pub fn bookmarked_ids() -> Vec<String> {
+    let Some(db) = db_opt() else {
+        return Vec::new();
+    };
+    bookmarked_ids_in(db)
+}
+
+fn bookmarked_ids_in(db: &dyn Storage) -> Vec<String> {
+    tree_keys(db, BOOKMARK_TREE)
+}
+
+/// ブックマークしたメッセージを日をまたいで読み出す（古→新）。履歴から消えたものは除く
+pub fn bookmarks() -> Vec<(String, MessageRecord)> {
+    let Some(db) = db_opt() else {
+        return Vec::new();
+    };
+    bookmarks_in(db)
+}
+
+fn bookmarks_in(db: &dyn Storage) -> Vec<(String, MessageRecord)> {
+    let mut found: Vec<(String, MessageRecord)> = bookmarked_ids_in(db)
+        .into_iter()
+        .filter_map(|id| db.get_by_id(&id).map(|r| (id, r)))
+        .collect();
+    found.sort_by_key(|(_, r)| r.ts_millis);
+    found
+}
+
+/// /reverify の結果
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReverifyCount {
+    /// 検証し直した件数
+    pub checked: usize,
+    /// そのうち署名状態が変わった件数
+    pub changed: usize,
+    /// 検証材料が無く、そのままにした件数（署名なし・旧形式など）
+    pub skipped: usize,
+}
+
+/// 保存済みメッセージの署名を保存してある材料で検証し直し、状態が変わったものを書き換える
+pub fn reverify_all() -> StorageResult<ReverifyCount> {
+    let Some(db) = db_opt() else {
+        return Ok(ReverifyCount::default());
+    };
+    reverify_all_in(db)
+}
+
+pub(crate) fn reverify_all_in(db: &dyn Storage) -> StorageResult<ReverifyCount> {
+    let mut count = ReverifyCount::default();
+    for date in db.list_dates() {
+        for i in 0..db.day_total(&date) {
+            let key = format!("{}{}", date, i);
+            let Some(mut rec) = db
+                .get(MAIN_TREE, key.as_bytes())?
+                .and_then(|v| decode_record(&v))
+            else {
+                continue;
+            };
+            let Some(proof) = &rec.proof else {
+                count.skipped += 1;
+                continue;
+            };
+            count.checked += 1;
+            let state = proof.verify();
+            if state != rec.signature {
+                rec.signature = state;
+                db.insert(MAIN_TREE, key.as_bytes(), &encode_record(&rec)?)?;
+                count.changed += 1;
+            }
+        }
+    }
+    db.flush()?;
+    Ok(count)
+}
+
+/// /color で指定したハンドルの色 (ハンドル → 色の名前) のツリー
+const HANDLE_COLOR_TREE: &str = "handle_colors";
+
+/// ハンドルの色の指定を保存する
+pub fn set_handle_color(handle: &str, color: &str) -> StorageResult<()> {
+    let Some(db) = db_opt() else {
+        return Ok(());
+    };
+    set_handle_color_in(db, handle, color)
+}
+
+pub(crate) fn set_handle_color_in(
+    db: &dyn Storage,
+    handle: &str,
+    color: &str,
+) -> StorageResult<()> {
+    db.insert(HANDLE_COLOR_TREE, handle.as_bytes(), color.as_bytes())?;
+    db.flush()?;
+    Ok(())
+}
+
+/// 保存済みのハンドルの色 (ハンドル, 色の名前)
+pub fn handle_colors() -> Vec<(String, String)> {
+    let Some(db) = db_opt() else {
+        return Vec::new();
+    };
+    handle_colors_in(db)
+}
+
+pub(crate) fn handle_colors_in(db: &dyn Storage) -> Vec<(String, String)> {
+    db.scan(HANDLE_COLOR_TREE)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(k, v)| {
+            (
+                String::from_utf8_lossy(&k).to_string(),
+                String::from_utf8_lossy(&v).to_string(),
+            )
         })
+        .collect()
+}
+
+/// これまでに見た相手 (指紋 → KnownPeer) のツリー。/history clear では消えない
+const KNOWN_TREE: &str = "known";
+
+/// 一度でも接続したことのある相手と最後に見た時刻
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KnownPeer {
+    /// 公開鍵 SHA-256 の先頭16桁
+    pub fingerprint: String,
+    pub last_seen_millis: u64,
+    pub handle: Option<String>,
+}
+
+impl std::fmt::Display for KnownPeer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use chrono::{Local, TimeZone};
+        let at = Local
+            .timestamp_millis_opt(self.last_seen_millis as i64)
+            .single()
+            .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|| self.last_seen_millis.to_string());
+        write!(
+            f,
+            "{} {} 最終 {}",
+            self.fingerprint,
+            self.handle.as_deref().unwrap_or("?"),
+            at
+        )
+    }
+}
+
+/// 相手を見たことを記録する。古い時刻では上書きせず、ハンドルが None なら前のものを残す
+pub fn touch_known(fingerprint: &str, seen_millis: u64, handle: Option<&str>) -> StorageResult<()> {
+    let Some(db) = db_opt() else {
+        return Ok(());
+    };
+    touch_known_in(db, fingerprint, seen_millis, handle)
+}
+
+pub(crate) fn touch_known_in(
+    db: &dyn Storage,
+    fingerprint: &str,
+    seen_millis: u64,
+    handle: Option<&str>,
+) -> StorageResult<()> {
+    let prev: Option<KnownPeer> = db
+        .get(KNOWN_TREE, fingerprint.as_bytes())?
+        .and_then(|v| postcard::from_bytes(&v).ok());
+    if prev
+        .as_ref()
+        .is_some_and(|p| p.last_seen_millis > seen_millis)
+    {
+        return Ok(());
+    }
+    let peer = KnownPeer {
+        fingerprint: fingerprint.to_string(),
+        last_seen_millis: seen_millis,
+        handle: handle
+            .map(str::to_string)
+            .or_else(|| prev.and_then(|p| p.handle)),
+    };
+    db.insert(
+        KNOWN_TREE,
+        fingerprint.as_bytes(),
+        &postcard::to_allocvec(&peer)?,
+    )?;
+    db.flush()?;
+    Ok(())
+}
+
+/// これまでに見た相手（最後に見たのが新しい順）
+pub fn known_peers() -> Vec<KnownPeer> {
+    let Some(db) = db_opt() else {
+        return Vec::new();
+    };
+    known_peers_in(db)
+}
+
+pub(crate) fn known_peers_in(db: &dyn Storage) -> Vec<KnownPeer> {
+    let mut peers: Vec<KnownPeer> = tree_values(db, KNOWN_TREE);
+    peers.sort_by_key(|p| std::cmp::Reverse(p.last_seen_millis));
+    peers
+}
+
+// 表の値を postcard で読めたものだけ並べる（キーの昇順）
+fn tree_values<T: serde::de::DeserializeOwned>(db: &dyn Storage, tree: &str) -> Vec<T> {
+    db.scan(tree)
         .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(_, v)| postcard::from_bytes(&v).ok())
+        .collect()
+}
+
+/// handle の相手とやり取りした DM（送受信とも）を日をまたいで古→新で読み出す。
+/// 相手は指紋で見分け、指紋は既知のピアと受信した DM のハンドルから引く
+pub fn dm_thread(handle: &str) -> Vec<MessageRecord> {
+    let Some(db) = db_opt() else {
+        return Vec::new();
+    };
+    dm_thread_in(db, handle)
+}
+
+fn dm_thread_in(db: &dyn Storage, handle: &str) -> Vec<MessageRecord> {
+    let dms: Vec<MessageRecord> = db
+        .list_dates()
+        .iter()
+        .flat_map(|date| db.load_structured_day(date))
+        .filter(|r| r.kind == MsgKind::Dm)
+        .collect();
+    let received_from =
+        |r: &MessageRecord| r.from_peer_id.is_some() && r.handle.as_deref() == Some(handle);
+    let mut fingerprints: HashSet<String> = known_peers_in(db)
+        .into_iter()
+        .filter(|p| p.handle.as_deref() == Some(handle))
+        .map(|p| p.fingerprint)
+        .collect();
+    fingerprints.extend(
+        dms.iter()
+            .filter(|r| received_from(r))
+            .filter_map(|r| r.peer_fingerprint.clone()),
+    );
+    let mut thread: Vec<MessageRecord> = dms
+        .into_iter()
+        .filter(|r| match &r.peer_fingerprint {
+            Some(fp) => fingerprints.contains(fp),
+            // 指紋を保存する前の受信 DM はハンドルで見る
+            None => received_from(r),
+        })
+        .collect();
+    thread.sort_by_key(|r| r.ts_millis);
+    thread
+}
+
+/// 別の経路で指紋を確かめた相手 (指紋 → VerifiedPeer) のツリー。/history clear では消えない
+const VERIFIED_TREE: &str = "verified";
+
+/// /trust で検証済みにした相手
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct VerifiedPeer {
+    /// 公開鍵 SHA-256 の先頭16桁
+    pub fingerprint: String,
+    /// 検証した時点で分かっていたハンドル（鍵の変化に気付くために使う）
+    pub handle: Option<String>,
+    pub verified_at_millis: u64,
+}
+
+/// 指紋を検証済みとして保存する
+pub fn set_verified(fingerprint: &str, handle: Option<&str>, at_millis: u64) -> StorageResult<()> {
+    let Some(db) = db_opt() else {
+        return Err(Error::NotInitialized);
+    };
+    set_verified_in(db, fingerprint, handle, at_millis)
+}
+
+pub(crate) fn set_verified_in(
+    db: &dyn Storage,
+    fingerprint: &str,
+    handle: Option<&str>,
+    at_millis: u64,
+) -> StorageResult<()> {
+    let peer = VerifiedPeer {
+        fingerprint: fingerprint.to_string(),
+        handle: handle.map(str::to_string),
+        verified_at_millis: at_millis,
+    };
+    db.insert(
+        VERIFIED_TREE,
+        fingerprint.as_bytes(),
+        &postcard::to_allocvec(&peer)?,
+    )?;
+    db.flush()?;
+    Ok(())
+}
+
+/// 検証済みの相手（検証した順）
+pub fn verified_peers() -> Vec<VerifiedPeer> {
+    let Some(db) = db_opt() else {
+        return Vec::new();
+    };
+    verified_peers_in(db)
+}
+
+pub(crate) fn verified_peers_in(db: &dyn Storage) -> Vec<VerifiedPeer> {
+    let mut peers: Vec<VerifiedPeer> = tree_values(db, VERIFIED_TREE);
+    peers.sort_by_key(|p| p.verified_at_millis);
+    peers
+}
+
+/// handle を名乗る相手が検証済みの指紋と違う鍵で現れたら、その検証済みの記録を返す
+pub fn verified_key_change(handle: &str, fingerprint: &str) -> Option<VerifiedPeer> {
+    verified_key_change_in(db_opt()?, handle, fingerprint)
+}
+
+pub(crate) fn verified_key_change_in(
+    db: &dyn Storage,
+    handle: &str,
+    fingerprint: &str,
+) -> Option<VerifiedPeer> {
+    let peers = verified_peers_in(db);
+    if peers.iter().any(|p| p.fingerprint == fingerprint) {
+        return None;
+    }
+    peers
+        .into_iter()
+        .rev()
+        .find(|p| p.handle.as_deref() == Some(handle))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn temp_db() -> SledStorage {
+        SledStorage::temporary().unwrap()
+    }
+
+    fn record(ts_millis: u64, text: &str) -> MessageRecord {
+        MessageRecord {
+            ts_millis,
+            recv_ts_millis: ts_millis,
+            kind: MsgKind::Chat,
+            from_peer_id: None,
+            to_peer_id: None,
+            handle: Some("@alice".into()),
+            text: text.into(),
+            signature: SigState::Valid,
+            reply_to: None,
+            binary: None,
+            proof: None,
+            peer_fingerprint: None,
+        }
+    }
+
+    #[test]
+    fn clear_all_removes_every_day() {
+        let db = temp_db();
+        // 2023-11-14 と 2023-11-15
+        db.store_structured(&record(1_700_000_000_000, "a"), None)
+            .unwrap();
+        db.store_structured(&record(1_700_000_001_000, "b"), None)
+            .unwrap();
+        db.store_structured(&record(1_700_086_400_000, "c"), None)
+            .unwrap();
+        db.insert(MAIN_TREE, b"other", b"keep").unwrap();
+        assert_eq!(db.list_dates().len(), 2);
+
+        assert_eq!(clear_all_in(&db).unwrap(), 3);
+        assert!(db.list_dates().is_empty());
+        assert!(db.get(MAIN_TREE, b"cnt:20231114").unwrap().is_none());
+        // メッセージ以外のキーは残す
+        assert!(db.get(MAIN_TREE, b"other").unwrap().is_some());
+    }
+
+    #[test]
+    fn audit_log_survives_history_clear() {
+        let db = temp_db();
+        db.store_structured(&record(1_700_000_000_000, "a"), None)
+            .unwrap();
+        for (i, kind) in [AuditKind::BadSignature, AuditKind::Disconnect]
+            .into_iter()
+            .enumerate()
+        {
+            let ev = AuditEvent {
+                ts_millis: 1_700_000_000_000 + i as u64,
+                kind,
+                peer_id: Some(i),
+                fingerprint: None,
+                detail: String::new(),
+            };
+            append_audit_in(&db, &ev).unwrap();
+        }
+        clear_all_in(&db).unwrap();
+
+        let recent = recent_audit_in(&db, 10);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].kind, AuditKind::BadSignature);
+        assert_eq!(recent_audit_in(&db, 1)[0].kind, AuditKind::Disconnect);
+    }
+
+    #[test]
+    fn amend_by_id_replaces_text_and_tombstones() {
+        let db = temp_db();
+        db.store_structured(&record(1_700_000_000_000, "@alice: typo"), Some("aa"))
+            .unwrap();
+        db.store_structured(&record(1_700_000_001_000, "@alice: oops"), Some("bb"))
+            .unwrap();
+
+        assert!(db.amend_by_id("aa", Some("@alice: fixed")).unwrap());
+        assert!(db.amend_by_id("bb", None).unwrap());
+        assert!(!db.amend_by_id("cc", None).unwrap());
+
+        let texts: Vec<String> = (0..2)
+            .map(|i| {
+                let val = db
+                    .get(MAIN_TREE, format!("20231114{}", i).as_bytes())
+                    .unwrap()
+                    .unwrap();
+                decode_record(&val).unwrap().text
+            })
+            .collect();
+        assert_eq!(texts, vec!["@alice: fixed", DELETED_TEXT]);
+
+        clear_all_in(&db).unwrap();
+        assert!(db.get_by_id("aa").is_none());
+    }
+
+    #[test]
+    fn reply_to_is_persisted_and_old_records_still_load() {
+        let db = temp_db();
+        let mut rec = record(1_700_000_000_000, "@bob: re");
+        rec.reply_to = Some("0a1b2c3d4e5f6071".into());
+        db.store_structured(&rec, Some("bb")).unwrap();
+        let loaded = db.get_by_id("bb").unwrap();
+        assert_eq!(loaded.reply_to.as_deref(), Some("0a1b2c3d4e5f6071"));
+
+        // reply_to 追加前に保存されたレコード
+        #[derive(Serialize)]
+        struct V1<'a>(
+            u64,
+            u64,
+            MsgKind,
+            Option<usize>,
+            Option<usize>,
+            Option<&'a str>,
+            &'a str,
+            Option<bool>,
+        );
+        let old =
+            postcard::to_allocvec(&V1(1, 1, MsgKind::Chat, None, None, None, "old", None)).unwrap();
+        let rec = decode_record(&old).unwrap();
+        assert_eq!((rec.text.as_str(), rec.reply_to), ("old", None));
+    }
+
+    #[test]
+    fn get_by_id_hits_indexed_record_and_misses_unknown() {
+        let db = temp_db();
+        db.store_structured(&record(1_700_000_000_000, "@alice: plain"), None)
+            .unwrap();
+        let key = db
+            .store_structured(
+                &record(1_700_000_001_000, "@alice: hi"),
+                Some("0a1b2c3d4e5f6071"),
+            )
+            .unwrap();
+        assert_eq!(key, "202311141");
+        let rec = db.get_by_id("0a1b2c3d4e5f6071").unwrap();
+        assert_eq!(rec.text, "@alice: hi");
+        assert!(db.get_by_id("ffffffffffffffff").is_none());
+    }
+
+    #[test]
+    fn databases_at_different_paths_do_not_share_history() {
+        let root = std::env::temp_dir().join(format!("p2witter-db-{}", std::process::id()));
+        let work = SledStorage::open(root.join("work")).unwrap();
+        let home = SledStorage::open(root.join("home")).unwrap();
+        work.store_structured(&record(1_700_000_000_000, "@alice: hi"), Some("aa"))
+            .unwrap();
+        assert_eq!(work.list_dates().len(), 1);
+        assert!(home.list_dates().is_empty());
+        assert!(home.get_by_id("aa").is_none());
+        drop((work, home));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn storage_calls_are_noops_when_open_failed() {
+        // ディレクトリを作れない場所（通常のファイルの中）を指定する
+        let dir = std::env::temp_dir().join(format!("p2w-broken-{}", std::process::id()));
+        std::fs::write(&dir, b"not a db").unwrap();
+        assert!(init_storage(dir.join("p2witter.db")).is_err());
+        let _ = std::fs::remove_file(&dir);
+
+        assert!(store_structured(&record(1_700_000_000_000, "a"), Some("00")).is_ok());
+        assert!(load_structured_day("20231114").is_empty());
+        assert!(list_dates().is_empty());
+        assert!(get_by_id("00").is_none());
+        assert!(!amend_by_id("00", None).unwrap());
+        assert_eq!(clear_all().unwrap(), 0);
+        assert!(recent_audit(10).is_empty());
+    }
+
+    #[test]
+    fn drafts_are_saved_listed_and_kept_on_history_clear() {
+        let db = temp_db();
+        save_draft_in(&db, "b", "途中の本文").unwrap();
+        save_draft_in(&db, "a", "old").unwrap();
+        save_draft_in(&db, "a", "new").unwrap();
+        assert_eq!(load_draft_in(&db, "a").as_deref(), Some("new"));
+        assert_eq!(load_draft_in(&db, "zz"), None);
+        assert_eq!(list_drafts_in(&db), vec!["a", "b"]);
+
+        clear_all_in(&db).unwrap();
+        assert_eq!(load_draft_in(&db, "b").as_deref(), Some("途中の本文"));
+    }
+
+    #[test]
+    fn bookmark_toggles_and_lists_across_days() {
+        let db = temp_db();
+        db.store_structured(&record(1_700_086_400_000, "@alice: later"), Some("bb"))
+            .unwrap();
+        db.store_structured(&record(1_700_000_000_000, "@alice: first"), Some("aa"))
+            .unwrap();
+
+        set_bookmark_in(&db, "bb", true).unwrap();
+        set_bookmark_in(&db, "aa", true).unwrap();
+        // 履歴に無い ID は一覧に出さない
+        set_bookmark_in(&db, "cc", true).unwrap();
+        let texts: Vec<String> = bookmarks_in(&db).into_iter().map(|(_, r)| r.text).collect();
+        assert_eq!(texts, vec!["@alice: first", "@alice: later"]);
+
+        set_bookmark_in(&db, "aa", false).unwrap();
+        let ids: Vec<String> = bookmarks_in(&db).into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec!["bb"]);
+        assert_eq!(bookmarked_ids_in(&db), vec!["bb", "cc"]);
+    }
+
+    #[test]
+    fn binary_backup_round_trips_and_dedupes() {
+        let db = temp_db();
+        for i in 0..300u64 {
+            // 3日に分け、一部に ID を付ける
+            let ts = 1_700_000_000_000 + i * 1000 + (i % 3) * 86_400_000;
+            let rec = record(ts, &format!("@alice: {}", i));
+            let id = (i % 2 == 0).then(|| format!("{:016x}", i));
+            db.store_structured(&rec, id.as_deref()).unwrap();
+        }
+        // 旧形式 (ts|text) の値もそのまま運ぶ
+        db.append(1_700_000_000_000, "legacy").unwrap();
+
+        let mut buf = Vec::new();
+        assert_eq!(export_binary_in(&db, &mut buf).unwrap(), 301);
+
+        let restored = temp_db();
+        assert_eq!(
+            import_binary_in(&restored, buf.as_slice()).unwrap(),
+            (301, 0)
+        );
+        let dates = db.list_dates();
+        assert_eq!(dates.len(), 3);
+        assert_eq!(restored.list_dates(), dates);
+        for date in &dates {
+            let before: Vec<String> = db
+                .load_structured_day(date)
+                .into_iter()
+                .map(|r| format!("{:?}", r))
+                .collect();
+            let after: Vec<String> = restored
+                .load_structured_day(date)
+                .into_iter()
+                .map(|r| format!("{:?}", r))
+                .collect();
+            assert_eq!(before, after);
+        }
+        assert_eq!(
+            restored.get_by_id(&format!("{:016x}", 42)).unwrap().text,
+            "@alice: 42"
+        );
+
+        // 同じバックアップをもう一度読み込んでも増えない
+        assert_eq!(
+            import_binary_in(&restored, buf.as_slice()).unwrap(),
+            (0, 301)
+        );
+
+        // 形式バージョンが違うものは読まない
+        buf[4] = BACKUP_VERSION + 1;
+        assert!(import_binary_in(&temp_db(), buf.as_slice()).is_err());
+        assert!(import_binary_in(&temp_db(), &b"JSON"[..]).is_err());
+    }
+
+    #[test]
+    fn only_the_expired_kind_is_pruned() {
+        const DAY: u64 = 24 * 60 * 60 * 1000;
+        let db = temp_db();
+        let now = 1_700_000_000_000;
+        let dm = |ts, text| MessageRecord {
+            kind: MsgKind::Dm,
+            ..record(ts, text)
+        };
+        db.store_structured(&record(now - 10 * DAY, "old chat"), Some("c1"))
+            .unwrap();
+        db.store_structured(&dm(now - 10 * DAY, "old dm"), Some("d1"))
+            .unwrap();
+        db.store_structured(&dm(now - DAY, "new dm"), Some("d2"))
+            .unwrap();
+        let retention = Retention {
+            chat_days: Some(30),
+            dm_days: Some(7),
+        };
+
+        let count = prune_expired_in(&db, &retention, now).unwrap();
+        assert_eq!(count, PruneCount { chat: 0, dm: 1 });
+        assert!(db.get_by_id("d1").is_none());
+        assert!(db.get(ID_TREE, b"d1").unwrap().is_none());
+        assert_eq!(db.get_by_id("c1").unwrap().text, "old chat");
+        assert_eq!(db.get_by_id("d2").unwrap().text, "new dm");
+        let audit = recent_audit_in(&db, 10);
+        assert_eq!(audit.len(), 1);
+        assert_eq!(audit[0].kind, AuditKind::Prune);
+
+        // 期限が来れば残りも消え、空になった日は index から外れる
+        let retention = Retention {
+            chat_days: Some(5),
+            dm_days: None,
+        };
+        let count = prune_expired_in(&db, &retention, now).unwrap();
+        assert_eq!(count, PruneCount { chat: 1, dm: 0 });
+        assert_eq!(db.list_dates(), vec![date_string(now - DAY)]);
+        // 何も消さなければ監査ログは増えない
+        prune_expired_in(&db, &retention, now).unwrap();
+        assert_eq!(recent_audit_in(&db, 10).len(), 2);
+    }
+
+    #[test]
+    fn retention_reads_days_from_config() {
+        let tbl: toml::Table = "chat_retention_days = 90\ndm_retention_days = 0\n"
+            .parse()
+            .unwrap();
+        let r = Retention::from_config(&tbl);
+        assert_eq!(r.chat_days, Some(90));
+        assert_eq!(r.dm_days, None);
+        assert!(r.is_enabled());
+        assert!(!Retention::default().is_enabled());
+    }
+
+    #[test]
+    fn known_peer_keeps_the_latest_sighting() {
+        let db = temp_db();
+        touch_known_in(&db, "0a1b2c3d4e5f6071", 2_000, Some("@bob")).unwrap();
+        touch_known_in(&db, "ffffffffffffffff", 1_000, Some("@carol")).unwrap();
+        // 古い時刻では戻らず、ハンドル不明なら前のハンドルを残す
+        touch_known_in(&db, "0a1b2c3d4e5f6071", 1_500, Some("@old")).unwrap();
+        touch_known_in(&db, "ffffffffffffffff", 3_000, None).unwrap();
+
+        let known = known_peers_in(&db);
+        assert_eq!(known.len(), 2);
+        assert_eq!(known[0].fingerprint, "ffffffffffffffff");
+        assert_eq!(known[0].last_seen_millis, 3_000);
+        assert_eq!(known[0].handle.as_deref(), Some("@carol"));
+        assert_eq!(known[1].handle.as_deref(), Some("@bob"));
+        assert_eq!(known[1].last_seen_millis, 2_000);
+        assert!(
+            known[1]
+                .to_string()
+                .starts_with("0a1b2c3d4e5f6071 @bob 最終 ")
+        );
+    }
+
+    #[test]
+    fn signature_states_survive_storage_including_old_records() {
+        let db = temp_db();
+        let ts = 1_700_000_000_000;
+        for (i, sig) in [SigState::Unsigned, SigState::Valid, SigState::Invalid]
+            .into_iter()
+            .enumerate()
+        {
+            let rec = MessageRecord {
+                signature: sig,
+                ..record(ts + i as u64, "hi")
+            };
+            db.store_structured(&rec, None).unwrap();
+        }
+        // Option<bool> で保存されていた頃の不正署名も × のまま読める
+        let old = MessageRecordV2 {
+            ts_millis: ts + 3,
+            recv_ts_millis: ts + 3,
+            kind: MsgKind::Chat,
+            from_peer_id: Some(0),
+            to_peer_id: None,
+            handle: None,
+            text: "old".into(),
+            signed_ok: Some(false),
+            reply_to: None,
+        };
+        db.store_raw(
+            &date_string(ts),
+            &postcard::to_allocvec(&old).unwrap(),
+            None,
+        )
+        .unwrap();
+
+        let marks: Vec<&str> = db
+            .load_structured_day(&date_string(ts))
+            .iter()
+            .map(|r| r.signature.mark())
+            .collect();
+        assert_eq!(marks, vec!["・", "○", "×", "×"]);
+        // 新しく書く値には形式を示す先頭バイトが付く
+        let key = format!("{}0", date_string(ts));
+        assert_eq!(
+            db.get(MAIN_TREE, key.as_bytes()).unwrap().unwrap()[0],
+            RECORD_TAG
+        );
+        // binary 追加前の形式も読める
+        let v3 = MessageRecordV3 {
+            ts_millis: ts,
+            recv_ts_millis: ts,
+            kind: MsgKind::Dm,
+            from_peer_id: Some(0),
+            to_peer_id: None,
+            handle: None,
+            text: "v3".into(),
+            signature: SigState::Valid,
+            reply_to: None,
+        };
+        let mut val = vec![RECORD_TAG_V3];
+        val.extend_from_slice(&postcard::to_allocvec(&v3).unwrap());
+        let rec = decode_record(&val).unwrap();
+        assert_eq!((rec.text.as_str(), rec.binary), ("v3", None));
+    }
+
+    #[test]
+    fn handle_color_is_kept_after_reopening() {
+        let dir = std::env::temp_dir().join(format!("p2w-colors-{}", std::process::id()));
+        {
+            let db = SledStorage::open(&dir).unwrap();
+            set_handle_color_in(&db, "@alice", "cyan").unwrap();
+            set_handle_color_in(&db, "@alice", "dark_red").unwrap();
+            db.flush().unwrap();
+        }
+        // 開き直しても（再起動後も）同じ指定が読める。sled のバックグラウンド
+        // スレッドがロックを手放すまで少し待つことがある
+        let db = (0..50)
+            .find_map(|_| {
+                SledStorage::open(&dir).ok().or_else(|| {
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                    None
+                })
+            })
+            .expect("sled を開き直せない");
+        assert_eq!(
+            handle_colors_in(&db),
+            vec![("@alice".to_string(), "dark_red".to_string())]
+        );
+        drop(db);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn signed_record_is_reverified_from_its_stored_proof() {
+        use crate::core::{crypto, protocol};
+        let db = temp_db();
+        let keys = crypto::generate_ed25519_keypair().unwrap();
+        let msg = protocol::Message::chat("@alice: hi", 1_700_000_000_000);
+        let signed = protocol::signing_bytes(&msg);
+        let proof = SignatureProof {
+            public_key: keys.public.clone(),
+            signature: crypto::sign_ed25519(&signed, &keys.pkcs8).unwrap(),
+            signed,
+        };
+        // 受信時に誤って不正と記録されたもの・改ざんされた材料・材料なしの3件
+        let wrong = MessageRecord {
+            signature: SigState::Invalid,
+            proof: Some(proof.clone()),
+            ..record(1_700_000_000_000, "@alice: hi")
+        };
+        let mut tampered = proof.clone();
+        tampered.signed.push(b'!');
+        let forged = MessageRecord {
+            proof: Some(tampered),
+            ..record(1_700_000_000_001, "@alice: hi!")
+        };
+        for rec in [&wrong, &forged, &record(1_700_000_000_002, "old")] {
+            db.store_structured(rec, None).unwrap();
+        }
+
+        let count = reverify_all_in(&db).unwrap();
+        assert_eq!(
+            count,
+            ReverifyCount {
+                checked: 2,
+                changed: 2,
+                skipped: 1
+            }
+        );
+        let day = db.load_structured_day(&date_string(1_700_000_000_000));
+        let states: Vec<SigState> = day.iter().map(|r| r.signature).collect();
+        assert_eq!(
+            states,
+            vec![SigState::Valid, SigState::Invalid, SigState::Valid]
+        );
+        assert_eq!(day[0].proof.as_ref(), Some(&proof));
+        // もう一度やっても変わらない
+        assert_eq!(reverify_all_in(&db).unwrap().changed, 0);
+    }
+
+    #[test]
+    fn verified_fingerprint_persists_and_flags_a_key_change() {
+        let dir = std::env::temp_dir().join(format!("p2w-verified-{}", std::process::id()));
+        {
+            let db = SledStorage::open(&dir).unwrap();
+            set_verified_in(&db, "0a1b2c3d4e5f6071", Some("@bob"), 1_000).unwrap();
+            db.flush().unwrap();
+        }
+        let db = (0..50)
+            .find_map(|_| {
+                SledStorage::open(&dir).ok().or_else(|| {
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                    None
+                })
+            })
+            .expect("sled を開き直せない");
+        let verified = verified_peers_in(&db);
+        assert_eq!(verified.len(), 1);
+        assert_eq!(verified[0].handle.as_deref(), Some("@bob"));
+
+        // 同じ鍵・別の人なら何もない
+        assert_eq!(
+            verified_key_change_in(&db, "@bob", "0a1b2c3d4e5f6071"),
+            None
+        );
+        assert_eq!(
+            verified_key_change_in(&db, "@carol", "ffffffffffffffff"),
+            None
+        );
+        // @bob が別の鍵で現れた
+        let changed = verified_key_change_in(&db, "@bob", "ffffffffffffffff").unwrap();
+        assert_eq!(changed.fingerprint, "0a1b2c3d4e5f6071");
+        drop(db);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn day_counts_and_indexed_records_for_sync() {
+        let db = temp_db();
+        // 2023-11-14 に ID 付き 11 件と ID なし 1 件、翌日に 1 件
+        for i in 0..11u64 {
+            let rec = record(1_700_000_000_000 + i, &format!("m{}", i));
+            db.store_structured(&rec, Some(&format!("{:016x}", i)))
+                .unwrap();
+        }
+        db.store_structured(&record(1_700_000_000_500, "no id"), None)
+            .unwrap();
+        db.store_structured(&record(1_700_086_400_000, "next"), Some("ff"))
+            .unwrap();
+
+        assert_eq!(
+            db.day_counts(),
+            vec![("20231114".to_string(), 12), ("20231115".to_string(), 1)]
+        );
+        let recs = records_with_ids_in(&db, "20231114");
+        // 連番は数値順（"10" が "2" より前に来ない）
+        let texts: Vec<&str> = recs.iter().map(|(_, r)| r.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            (0..11).map(|i| format!("m{}", i)).collect::<Vec<_>>()
+        );
+        assert_eq!(recs[10].0, format!("{:016x}", 10));
+        assert!(records_with_ids_in(&db, "20231116").is_empty());
+        // 連番で区切って読める（ID なしの 11 番は飛ばす）
+        let (page, next) = records_page_in(&db, "20231114", 9, 2);
+        let seqs: Vec<u64> = page.iter().map(|(seq, ..)| *seq).collect();
+        assert_eq!((seqs, next), (vec![9, 10], 11));
+        let (page, next) = records_page_in(&db, "20231114", next, 2);
+        assert!(page.is_empty());
+        assert_eq!(next, 12);
+    }
+
+    #[test]
+    fn dm_thread_keeps_only_one_correspondent() {
+        let db = temp_db();
+        touch_known_in(&db, "b0b0b0b0b0b0b0b0", 1, Some("@bob")).unwrap();
+        touch_known_in(&db, "ca401ca401ca401c", 1, Some("@carol")).unwrap();
+        let dm = |ts: u64, text: &str, received: bool, fp: &str| MessageRecord {
+            kind: MsgKind::Dm,
+            from_peer_id: received.then_some(1),
+            to_peer_id: (!received).then_some(1),
+            handle: received.then(|| {
+                if fp.starts_with('b') {
+                    "@bob"
+                } else {
+                    "@carol"
+                }
+                .into()
+            }),
+            peer_fingerprint: Some(fp.into()),
+            ..record(ts, text)
+        };
+        // 翌日分を先に保存しても時刻順に並ぶ
+        db.store_structured(
+            &dm(1_700_086_400_000, "to bob 2", false, "b0b0b0b0b0b0b0b0"),
+            None,
+        )
+        .unwrap();
+        db.store_structured(
+            &dm(1_700_000_002_000, "from carol", true, "ca401ca401ca401c"),
+            None,
+        )
+        .unwrap();
+        db.store_structured(
+            &dm(1_700_000_001_000, "from bob", true, "b0b0b0b0b0b0b0b0"),
+            None,
+        )
+        .unwrap();
+        db.store_structured(
+            &dm(1_700_000_000_000, "to bob", false, "b0b0b0b0b0b0b0b0"),
+            None,
+        )
+        .unwrap();
+        db.store_structured(
+            &dm(1_700_000_003_000, "to carol", false, "ca401ca401ca401c"),
+            None,
+        )
+        .unwrap();
+        db.store_structured(&record(1_700_000_004_000, "chat"), None)
+            .unwrap();
+
+        let texts: Vec<String> = dm_thread_in(&db, "@bob")
+            .into_iter()
+            .map(|r| r.text)
+            .collect();
+        assert_eq!(texts, ["to bob", "from bob", "to bob 2"]);
+        assert_eq!(dm_thread_in(&db, "@carol").len(), 2);
+        assert!(dm_thread_in(&db, "@dave").is_empty());
+    }
+
+    // どの実装でも同じ結果になるはずの操作をひととおり試す
+    fn exercise_backend(db: &dyn Storage) {
+        db.append(1_700_000_000_000, "legacy").unwrap();
+        db.store_structured(&record(1_700_000_001_000, "@alice: hi"), Some("aa"))
+            .unwrap();
+        db.store_structured(&record(1_700_086_400_000, "@alice: next"), None)
+            .unwrap();
+        assert_eq!(db.list_dates(), vec!["20231114", "20231115"]);
+        assert_eq!(
+            db.day_counts(),
+            vec![("20231114".to_string(), 2), ("20231115".to_string(), 1)]
+        );
+        let texts: Vec<String> = db
+            .load_structured_day("20231114")
+            .into_iter()
+            .map(|r| r.text)
+            .collect();
+        assert_eq!(texts, ["legacy", "@alice: hi"]);
+        assert!(db.amend_by_id("aa", Some("@alice: fixed")).unwrap());
+        assert_eq!(db.get_by_id("aa").unwrap().text, "@alice: fixed");
+        assert_eq!(records_with_ids_in(db, "20231114")[0].0, "aa");
+
+        save_draft_in(db, "b", "下書き").unwrap();
+        set_bookmark_in(db, "aa", true).unwrap();
+        touch_known_in(db, "0a1b2c3d4e5f6071", 1_000, Some("@bob")).unwrap();
+        append_audit_in(db, &audit(AuditKind::KeyChange)).unwrap();
+        append_audit_in(db, &audit(AuditKind::Disconnect)).unwrap();
+
+        let mut buf = Vec::new();
+        assert_eq!(export_binary_in(db, &mut buf).unwrap(), 3);
+        assert_eq!(clear_all_in(db).unwrap(), 3);
+        assert!(db.list_dates().is_empty());
+        assert_eq!(import_binary_in(db, buf.as_slice()).unwrap(), (3, 0));
+        assert_eq!(db.get_by_id("aa").unwrap().text, "@alice: fixed");
+
+        // 履歴を消しても別の表はそのまま
+        assert_eq!(list_drafts_in(db), ["b"]);
+        assert_eq!(bookmarks_in(db).len(), 1);
+        assert_eq!(known_peers_in(db)[0].handle.as_deref(), Some("@bob"));
+        let kinds: Vec<AuditKind> = recent_audit_in(db, 10).iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, [AuditKind::KeyChange, AuditKind::Disconnect]);
+        assert_eq!(recent_audit_in(db, 1)[0].kind, AuditKind::Disconnect);
+
+        // 前方一致・範囲・末尾の走査
+        for key in ["a1", "a2", "ab", "b1"] {
+            db.insert("scan", key.as_bytes(), b"v").unwrap();
+        }
+        let keys = |kv: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<String> {
+            kv.into_iter()
+                .map(|(k, _)| String::from_utf8(k).unwrap())
+                .collect()
+        };
+        assert_eq!(
+            keys(db.scan_prefix("scan", b"a").unwrap()),
+            ["a1", "a2", "ab"]
+        );
+        assert!(db.scan_prefix("scan", b"c").unwrap().is_empty());
+        assert_eq!(
+            keys(db.scan_range("scan", b"a2", Some(b"b1")).unwrap()),
+            ["a2", "ab"]
+        );
+        assert_eq!(
+            keys(db.scan_range("scan", b"ab", None).unwrap()),
+            ["ab", "b1"]
+        );
+        assert_eq!(keys(db.scan_last("scan", 2).unwrap()), ["ab", "b1"]);
+        assert_eq!(db.scan_last("scan", 10).unwrap().len(), 4);
+    }
+
+    fn audit(kind: AuditKind) -> AuditEvent {
+        AuditEvent {
+            ts_millis: 1,
+            kind,
+            peer_id: None,
+            fingerprint: None,
+            detail: String::new(),
+        }
+    }
+
+    #[test]
+    fn sled_and_memory_backends_behave_the_same() {
+        exercise_backend(&temp_db());
+        exercise_backend(&MemoryStorage::default());
+    }
+
+    #[test]
+    fn concurrent_writers_on_one_day_never_share_a_key() {
+        fn hammer(db: &dyn Storage) {
+            const PER_THREAD: u64 = 200;
+            std::thread::scope(|s| {
+                // 構造化した記録と旧形式の行を同じ日に同時に書く
+                s.spawn(|| {
+                    for i in 0..PER_THREAD {
+                        db.store_structured(&record(1_700_000_000_000 + i, "@alice: hi"), None)
+                            .unwrap();
+                    }
+                });
+                s.spawn(|| {
+                    for i in 0..PER_THREAD {
+                        db.append(1_700_000_000_000 + i, "local echo").unwrap();
+                    }
+                });
+            });
+            assert_eq!(db.day_total("20231114"), 2 * PER_THREAD);
+            let recs = db.load_structured_day("20231114");
+            assert_eq!(recs.len() as u64, 2 * PER_THREAD);
+            let legacy = recs.iter().filter(|r| r.text == "local echo").count();
+            assert_eq!(legacy as u64, PER_THREAD);
+            assert_eq!(db.list_dates(), vec!["20231114"]);
+        }
+        hammer(&temp_db());
+        hammer(&MemoryStorage::default());
+    }
+
+    /// 書き込みだけ失敗する保存先（ディスクが一杯になった状態の代わり）
+    #[derive(Default)]
+    struct FullDisk(MemoryStorage);
+
+    impl Storage for FullDisk {
+        fn get(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+            self.0.get(tree, key)
+        }
+        fn insert(&self, _: &str, _: &[u8], _: &[u8]) -> StorageResult<()> {
+            Err(std::io::Error::from(std::io::ErrorKind::StorageFull).into())
+        }
+        fn fetch_and_update(
+            &self,
+            _: &str,
+            _: &[u8],
+            _: &mut Updater,
+        ) -> StorageResult<Option<Vec<u8>>> {
+            Err(std::io::Error::from(std::io::ErrorKind::StorageFull).into())
+        }
+        fn remove(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+            self.0.remove(tree, key)
+        }
+        fn scan(&self, tree: &str) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+            self.0.scan(tree)
+        }
+        fn scan_prefix(&self, tree: &str, prefix: &[u8]) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+            self.0.scan_prefix(tree, prefix)
+        }
+        fn scan_range(
+            &self,
+            tree: &str,
+            start: &[u8],
+            end: Option<&[u8]>,
+        ) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+            self.0.scan_range(tree, start, end)
+        }
+        fn scan_last(&self, tree: &str, n: usize) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+            self.0.scan_last(tree, n)
+        }
+        fn clear(&self, tree: &str) -> StorageResult<()> {
+            self.0.clear(tree)
+        }
+        fn generate_id(&self) -> StorageResult<u64> {
+            self.0.generate_id()
+        }
+        fn flush(&self) -> StorageResult<()> {
+            self.0.flush()
+        }
+    }
+
+    #[test]
+    fn legacy_lines_are_migrated_once() {
+        let db = MemoryStorage::default();
+        db.append(1_700_000_000_000, "@alice: hi ○").unwrap();
+        db.append(1_700_000_000_500, "接続しました").unwrap();
+        db.store_structured(&record(1_700_000_001_000, "@bob: yo"), Some("aa"))
+            .unwrap();
+
+        assert_eq!(migrate_legacy_in(&db).unwrap(), 2);
+        for i in 0..3 {
+            let val = db
+                .get(MAIN_TREE, format!("20231114{}", i).as_bytes())
+                .unwrap()
+                .unwrap();
+            assert_eq!(val[0], RECORD_TAG);
+        }
+        let recs = db.load_structured_day("20231114");
+        assert_eq!(recs[0].text, "@alice: hi ○");
+        assert_eq!(recs[0].handle.as_deref(), Some("@alice"));
+        assert_eq!(recs[0].kind, MsgKind::System);
+        assert_eq!(recs[0].ts_millis, 1_700_000_000_000);
+        assert_eq!(recs[1].handle, None);
+        assert_eq!(recs[2].text, "@bob: yo");
+        assert!(db.get_by_id("aa").is_some());
+
+        // ID 索引の逆引きが無い古い保存先にも作る
+        db.remove(KEY_ID_TREE, b"202311142").unwrap();
+        db.insert(MAIN_TREE, LEGACY_MIGRATION_KEY, &encode_count(1))
+            .unwrap();
+        assert!(records_with_ids_in(&db, "20231114").is_empty());
+        assert_eq!(migrate_legacy_in(&db).unwrap(), 0);
+        assert_eq!(records_with_ids_in(&db, "20231114")[0].0, "aa");
+
+        // 印が残るので 2 回目は走査しない
+        db.append(1_700_000_002_000, "late").unwrap();
+        assert_eq!(migrate_legacy_in(&db).unwrap(), 0);
+        assert!(decode_record(&db.get(MAIN_TREE, b"202311143").unwrap().unwrap()).is_none());
+    }
+
+    /// flush の回数を数える保存先
+    struct CountingFlush {
+        inner: MemoryStorage,
+        durability: DurabilityMode,
+        flushes: AtomicU64,
+    }
+
+    impl CountingFlush {
+        fn new(durability: DurabilityMode) -> Self {
+            Self {
+                inner: MemoryStorage::default(),
+                durability,
+                flushes: AtomicU64::new(0),
+            }
+        }
+
+        fn flushes(&self) -> u64 {
+            self.flushes.load(Ordering::Relaxed)
+        }
+    }
+
+    impl Storage for CountingFlush {
+        fn get(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+            self.inner.get(tree, key)
+        }
+        fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> StorageResult<()> {
+            self.inner.insert(tree, key, value)
+        }
+        fn fetch_and_update(
+            &self,
+            tree: &str,
+            key: &[u8],
+            f: &mut Updater,
+        ) -> StorageResult<Option<Vec<u8>>> {
+            self.inner.fetch_and_update(tree, key, f)
+        }
+        fn remove(&self, tree: &str, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+            self.inner.remove(tree, key)
+        }
+        fn scan(&self, tree: &str) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+            self.inner.scan(tree)
+        }
+        fn scan_prefix(&self, tree: &str, prefix: &[u8]) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+            self.inner.scan_prefix(tree, prefix)
+        }
+        fn scan_range(
+            &self,
+            tree: &str,
+            start: &[u8],
+            end: Option<&[u8]>,
+        ) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+            self.inner.scan_range(tree, start, end)
+        }
+        fn scan_last(&self, tree: &str, n: usize) -> StorageResult<Vec<(Vec<u8>, Vec<u8>)>> {
+            self.inner.scan_last(tree, n)
+        }
+        fn clear(&self, tree: &str) -> StorageResult<()> {
+            self.inner.clear(tree)
+        }
+        fn generate_id(&self) -> StorageResult<u64> {
+            self.inner.generate_id()
+        }
+        fn flush(&self) -> StorageResult<()> {
+            self.flushes.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+        fn durability(&self) -> DurabilityMode {
+            self.durability
+        }
+    }
+
+    #[test]
+    fn safe_flushes_every_write_and_relaxed_waits_for_an_explicit_flush() {
+        let safe = CountingFlush::new(DurabilityMode::Safe);
+        safe.store_structured(&record(1_700_000_001_000, "@alice: hi"), Some("aa"))
+            .unwrap();
+        assert_eq!(safe.flushes(), 1);
+        safe.amend_by_id("aa", Some("@alice: fixed")).unwrap();
+        assert_eq!(safe.flushes(), 2);
+
+        for mode in [DurabilityMode::Fast, DurabilityMode::Relaxed] {
+            let db = CountingFlush::new(mode);
+            db.store_structured(&record(1_700_000_001_000, "@alice: hi"), Some("aa"))
+                .unwrap();
+            db.amend_by_id("aa", None).unwrap();
+            assert_eq!(db.flushes(), 0, "{:?}", mode);
+        }
+
+        // relaxed でも明示の flush の後は開き直して読める
+        let dir = std::env::temp_dir().join(format!("p2witter-relaxed-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        {
+            let db = SledStorage::open_with(&dir, DurabilityMode::Relaxed).unwrap();
+            db.store_structured(&record(1_700_000_001_000, "@alice: hi"), Some("aa"))
+                .unwrap();
+            db.flush().unwrap();
+        }
+        let reopened = SledStorage::open(&dir).unwrap();
+        assert_eq!(reopened.get_by_id("aa").unwrap().text, "@alice: hi");
+        drop(reopened);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(DurabilityMode::parse("fast"), Some(DurabilityMode::Fast));
+        assert_eq!(DurabilityMode::parse("FAST"), None);
+    }
+
+    #[test]
+    fn audit_log_is_capped_and_flushed_in_batches() {
+        let db = CountingFlush::new(DurabilityMode::Safe);
+        for _ in 0..AUDIT_FLUSH_EVERY * 2 {
+            append_audit_in(&db, &audit(AuditKind::BadSignature)).unwrap();
+        }
+        assert_eq!(db.flushes(), 2);
+
+        for _ in 0..5 {
+            append_audit_in(&db, &audit(AuditKind::Disconnect)).unwrap();
+        }
+        assert_eq!(
+            trim_audit_in(&db, 3).unwrap(),
+            AUDIT_FLUSH_EVERY as usize * 2 + 2
+        );
+        let kept = recent_audit_in(&db, 10);
+        assert_eq!(kept.len(), 3);
+        assert!(kept.iter().all(|e| e.kind == AuditKind::Disconnect));
+
+        // 上限を超えても定期的に古いものから消える
+        let db = MemoryStorage::default();
+        for _ in 0..AUDIT_MAX_ENTRIES as u64 + AUDIT_TRIM_EVERY + 1 {
+            append_audit_in(&db, &audit(AuditKind::BadSignature)).unwrap();
+        }
+        let left = db.scan(AUDIT_TREE).unwrap().len();
+        assert!(
+            left < AUDIT_MAX_ENTRIES + AUDIT_TRIM_EVERY as usize,
+            "{}",
+            left
+        );
+    }
+
+    #[test]
+    fn storage_failures_surface_as_typed_errors() {
+        let db = FullDisk::default();
+        let err = db
+            .store_structured(&record(1_700_000_001_000, "@alice: hi"), Some("aa"))
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::Io(e) if e.kind() == std::io::ErrorKind::StorageFull),
+            "{:?}",
+            err
+        );
+        assert!(matches!(
+            set_bookmark_in(&db, "aa", true),
+            Err(Error::Io(_))
+        ));
+        // 形式の合わないバックアップは理由付きの Invalid になる
+        assert!(matches!(
+            import_binary_in(&MemoryStorage::default(), &b"JSON and more bytes"[..]),
+            Err(Error::Invalid(_))
+        ));
+        // DB として開けない場所は sled のエラーのまま返る
+        let file = std::env::temp_dir().join(format!("p2witter-not-a-db-{}", std::process::id()));
+        std::fs::write(&file, b"x").unwrap();
+        assert!(matches!(SledStorage::open(&file), Err(Error::Db(_))));
+        let _ = std::fs::remove_file(&file);
+    }
 }
@@ -0,0 +1,145 @@
+//! ステータスバーと入力プロンプトの書式。
+//! `{handle}` などを値に置き換えるだけの小さなテンプレートで、起動時に一度だけ解析する
+
+/// 置き換えられる項目
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    /// 自分のハンドル
+    Handle,
+    /// 接続中のピア数
+    Peers,
+    /// スクロール位置 ("現在/最大")
+    Scroll,
+    /// 過去ログの表示範囲（過去ログモード以外は空）
+    Range,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+/// 置き換える値。入力プロンプトでは scroll と range は空
+#[derive(Debug, Default)]
+pub struct Values<'a> {
+    pub handle: &'a str,
+    pub peers: usize,
+    pub scroll: &'a str,
+    pub range: &'a str,
+}
+
+/// prompt_format が無いときの入力プロンプト
+pub const DEFAULT_PROMPT: &str = "> ";
+
+impl Template {
+    /// 置き換えの無い固定の文字列
+    pub fn plain(text: &str) -> Self {
+        Self {
+            parts: vec![Part::Text(text.to_string())],
+        }
+    }
+
+    /// `{{` と `}}` は波括弧そのもの。知らない名前や閉じていない `{` は Err
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(ch) => name.push(ch),
+                            None => return Err(format!("'{{' が閉じていません: {}", s)),
+                        }
+                    }
+                    let field = match name.as_str() {
+                        "handle" => Field::Handle,
+                        "peers" => Field::Peers,
+                        "scroll" => Field::Scroll,
+                        "range" => Field::Range,
+                        _ => {
+                            return Err(format!(
+                                "不明な置き換え {{{}}} (使えるのは {{handle}} {{peers}} {{scroll}} {{range}})",
+                                name
+                            ));
+                        }
+                    };
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                _ => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Self { parts })
+    }
+
+    pub fn render(&self, v: &Values) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(t) => out.push_str(t),
+                Part::Field(Field::Handle) => out.push_str(v.handle),
+                Part::Field(Field::Peers) => out.push_str(&v.peers.to_string()),
+                Part::Field(Field::Scroll) => out.push_str(v.scroll),
+                Part::Field(Field::Range) => out.push_str(v.range),
+            }
+        }
+        out
+    }
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Self::plain(DEFAULT_PROMPT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_placeholders_with_values() {
+        let t = Template::parse(" {handle} | ピア:{peers} | {scroll} {range} {{x}} ").unwrap();
+        let v = Values {
+            handle: "@alice",
+            peers: 3,
+            scroll: "2/10",
+            range: "20250101-20250102",
+        };
+        assert_eq!(
+            t.render(&v),
+            " @alice | ピア:3 | 2/10 20250101-20250102 {x} "
+        );
+        assert_eq!(
+            Template::parse("{handle}> ").unwrap().render(&v),
+            "@alice> "
+        );
+        assert_eq!(Template::default().render(&v), DEFAULT_PROMPT);
+
+        assert!(Template::parse("{nick}").unwrap_err().contains("{nick}"));
+        assert!(Template::parse("abc {handle").is_err());
+    }
+}
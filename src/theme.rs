@@ -0,0 +1,458 @@
+//! TUI の配色テーマ。組み込みプリセットに config の [theme] の値を上書きして作る。
+
+use crossterm::style::Color;
+use p2witter::config::{self, keys};
+use p2witter::core::rpc;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use toml::Table;
+
+/// 表示行の種類（配色の選択に使う）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineKind {
+    /// 自分の投稿
+    Own,
+    /// 署名検証に成功した投稿 (○)
+    Valid,
+    /// /trust で検証済みにした相手の投稿 (○ ✔)
+    Verified,
+    /// 署名検証に失敗した投稿 (×)
+    Invalid,
+    /// 署名なしの投稿 (・)
+    Unsigned,
+    /// システムメッセージ
+    System,
+    /// 他ノードから届いた署名付きのお知らせ
+    Announce,
+    /// 至急の印が付いた投稿・DM
+    Urgent,
+    /// 過去ログで /find が見つけた行（色ではなく反転表示）
+    Found,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    pub status_fg: Color,
+    pub status_bg: Color,
+    pub own: Color,
+    pub valid: Color,
+    pub verified: Color,
+    pub invalid: Color,
+    pub handle: Color,
+    pub system: Color,
+    pub announce: Color,
+    pub urgent: Color,
+    /// 色を一切出さない（ステータスバーは反転表示のみ）
+    pub no_color: bool,
+}
+
+/// 組み込みプリセット名
+pub const PRESETS: &[&str] = &["default", "dark", "light", "mono"];
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            status_fg: Color::Black,
+            status_bg: Color::Grey,
+            own: Color::Cyan,
+            valid: Color::Reset,
+            verified: Color::Blue,
+            invalid: Color::Red,
+            handle: Color::Green,
+            system: Color::DarkGrey,
+            announce: Color::Magenta,
+            urgent: Color::Yellow,
+            no_color: false,
+        }
+    }
+}
+
+impl Theme {
+    pub fn preset(name: &str) -> Option<Self> {
+        let base = Self::default();
+        match name {
+            "default" => Some(base),
+            "dark" => Some(Self {
+                status_fg: Color::White,
+                status_bg: Color::DarkBlue,
+                own: Color::Yellow,
+                valid: Color::White,
+                verified: Color::Cyan,
+                invalid: Color::Magenta,
+                handle: Color::Cyan,
+                system: Color::Grey,
+                announce: Color::Blue,
+                urgent: Color::Red,
+                ..base
+            }),
+            "light" => Some(Self {
+                status_fg: Color::White,
+                status_bg: Color::DarkGrey,
+                own: Color::DarkBlue,
+                valid: Color::Black,
+                verified: Color::DarkBlue,
+                invalid: Color::DarkRed,
+                handle: Color::DarkGreen,
+                system: Color::DarkGrey,
+                announce: Color::DarkMagenta,
+                urgent: Color::DarkYellow,
+                ..base
+            }),
+            "mono" => Some(Self {
+                no_color: true,
+                ..base
+            }),
+            _ => None,
+        }
+    }
+
+    /// [theme] の preset を土台に各色を上書きする。不明な値や未指定のキーは土台のまま
+    pub fn from_config(tbl: &Table) -> Self {
+        let mut theme = config::get_value_in(tbl, keys::THEME_PRESET)
+            .and_then(|v| v.as_str().and_then(Self::preset))
+            .unwrap_or_default();
+        let slots: [(&str, &mut Color); 10] = [
+            (keys::THEME_STATUS_FG, &mut theme.status_fg),
+            (keys::THEME_STATUS_BG, &mut theme.status_bg),
+            (keys::THEME_OWN, &mut theme.own),
+            (keys::THEME_VALID, &mut theme.valid),
+            (keys::THEME_VERIFIED, &mut theme.verified),
+            (keys::THEME_INVALID, &mut theme.invalid),
+            (keys::THEME_HANDLE, &mut theme.handle),
+            (keys::THEME_SYSTEM, &mut theme.system),
+            (keys::THEME_ANNOUNCE, &mut theme.announce),
+            (keys::THEME_URGENT, &mut theme.urgent),
+        ];
+        for (key, slot) in slots {
+            if let Some(c) = config::get_value_in(tbl, key)
+                .and_then(|v| v.as_str().and_then(|s| Color::try_from(s).ok()))
+            {
+                *slot = c;
+            }
+        }
+        if let Some(b) = config::get_value_in(tbl, keys::THEME_NO_COLOR).and_then(|v| v.as_bool()) {
+            theme.no_color |= b;
+        }
+        theme
+    }
+
+    /// 行の種類に対応する文字色。色なしなら None
+    pub fn line_color(&self, kind: LineKind) -> Option<Color> {
+        if self.no_color {
+            return None;
+        }
+        match kind {
+            LineKind::Own => Some(self.own),
+            LineKind::Valid => Some(self.valid),
+            LineKind::Verified => Some(self.verified),
+            LineKind::Invalid => Some(self.invalid),
+            LineKind::Unsigned => None,
+            LineKind::System => Some(self.system),
+            LineKind::Announce => Some(self.announce),
+            LineKind::Urgent => Some(self.urgent),
+            LineKind::Found => None,
+        }
+    }
+}
+
+/// 他人のハンドルに自動で割り当てる色。ハンドルのハッシュで選ぶので、セッションをまたいでも同じ色になる
+pub const HANDLE_PALETTE: &[&str] = &[
+    "green",
+    "cyan",
+    "yellow",
+    "magenta",
+    "blue",
+    "red",
+    "dark_green",
+    "dark_cyan",
+    "dark_yellow",
+    "dark_magenta",
+];
+
+/// ハンドルごとの文字色。/color で指定したもの（保存される）を優先する
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HandleColors {
+    /// ハンドル → 色の名前
+    chosen: HashMap<String, String>,
+}
+
+impl HandleColors {
+    /// 保存済みの指定から作る。色として読めないものは捨てる
+    pub fn from_saved(saved: impl IntoIterator<Item = (String, String)>) -> Self {
+        Self {
+            chosen: saved
+                .into_iter()
+                .filter(|(_, name)| Color::try_from(name.as_str()).is_ok())
+                .collect(),
+        }
+    }
+
+    pub fn set(&mut self, handle: &str, name: &str) {
+        self.chosen.insert(handle.to_string(), name.to_string());
+    }
+
+    /// 色の名前（/legend 用）。指定なしの自分のハンドルはテーマの handle 色
+    pub fn describe(&self, handle: &str, own_handle: &str) -> String {
+        match self.chosen.get(handle) {
+            Some(name) => format!("{} (指定)", name),
+            None if handle == own_handle => "テーマの handle 色".into(),
+            None => auto_color_name(handle).into(),
+        }
+    }
+
+    /// 行頭のハンドル部分を塗る色
+    pub fn color_of(&self, handle: &str, own_handle: &str, theme: &Theme) -> Color {
+        match self.chosen.get(handle) {
+            Some(name) => Color::try_from(name.as_str()).unwrap_or(theme.handle),
+            None if handle == own_handle => theme.handle,
+            None => Color::try_from(auto_color_name(handle)).unwrap_or(theme.handle),
+        }
+    }
+}
+
+/// 指定が無いハンドルの色（FNV-1a で HANDLE_PALETTE から選ぶ）
+fn auto_color_name(handle: &str) -> &'static str {
+    let hash = handle.bytes().fold(0x811c_9dc5u32, |h, b| {
+        (h ^ u32::from(b)).wrapping_mul(0x0100_0193)
+    });
+    HANDLE_PALETTE[hash as usize % HANDLE_PALETTE.len()]
+}
+
+// 行頭の "#<メッセージID> " を除いた残り
+fn strip_id(line: &str) -> &str {
+    match line.strip_prefix('#').and_then(|r| r.split_once(' ')) {
+        Some((id, rest)) if id.chars().all(|c| c.is_ascii_hexdigit()) => rest,
+        _ => line,
+    }
+}
+
+/// ブックマークした行の末尾に付ける印
+pub const BOOKMARK_MARK: &str = " ★";
+
+/// 行頭の #ID がブックマーク済みなら印を付けた表示用の行を返す
+pub fn with_bookmark_mark<'a>(line: &'a str, bookmarks: &HashSet<String>) -> Cow<'a, str> {
+    let id = line
+        .strip_prefix('#')
+        .and_then(|r| r.split_once(' '))
+        .map(|(id, _)| id);
+    match id {
+        Some(id) if bookmarks.contains(id) => Cow::Owned(format!("{}{}", line, BOOKMARK_MARK)),
+        _ => Cow::Borrowed(line),
+    }
+}
+
+/// 表示行を種類に分ける。own_handle は自分のハンドル
+pub fn classify_line(line: &str, own_handle: &str) -> LineKind {
+    let body = strip_id(line);
+    let (body, urgent) = match body.strip_suffix(rpc::URGENT_MARK) {
+        Some(rest) => (rest, true),
+        None => (body, false),
+    };
+    let body = body.strip_suffix(rpc::EPHEMERAL_MARK).unwrap_or(body);
+    if body.ends_with(" ×") || body.contains(" ⚠改ざん") {
+        LineKind::Invalid
+    } else if urgent {
+        LineKind::Urgent
+    } else if body.starts_with(rpc::SYSTEM_MARK) {
+        LineKind::Announce
+    } else if !own_handle.is_empty()
+        && body
+            .strip_prefix(own_handle)
+            .is_some_and(|r| r.starts_with(": "))
+    {
+        LineKind::Own
+    } else if body.ends_with(rpc::VERIFIED_MARK) {
+        LineKind::Verified
+    } else if body.ends_with(" ○") || body.contains(" ○ (編集済み)") {
+        LineKind::Valid
+    } else if body.ends_with(" ・") {
+        LineKind::Unsigned
+    } else {
+        LineKind::System
+    }
+}
+
+/// 行内の "@handle" 部分のバイト範囲
+pub fn handle_span(line: &str) -> Option<(usize, usize)> {
+    let body = strip_id(line);
+    let start = line.len() - body.len();
+    let (name, _) = body.split_once(": ")?;
+    if name.starts_with('@') && !name.chars().any(char::is_whitespace) {
+        Some((start, start + name.len()))
+    } else {
+        None
+    }
+}
+
+/// 同じハンドルの投稿が続くとき、2 行目以降の "@handle: " を同じ幅の空白にする。
+/// before は lines の直前の行（続きから描くとき用）
+pub fn compact_lines<'a>(lines: &'a [String], before: Option<&'a str>) -> Vec<Cow<'a, str>> {
+    let mut prev = before.and_then(|l| handle_span(l).map(|(s, e)| &l[s..e]));
+    lines
+        .iter()
+        .map(|line| {
+            let Some((s, e)) = handle_span(line) else {
+                prev = None;
+                return Cow::Borrowed(line.as_str());
+            };
+            let handle = &line[s..e];
+            if prev.replace(handle) != Some(handle) {
+                return Cow::Borrowed(line.as_str());
+            }
+            let indent = unicode_width::UnicodeWidthStr::width(handle) + 2;
+            Cow::Owned(format!(
+                "{}{}{}",
+                &line[..s],
+                " ".repeat(indent),
+                &line[e + 2..]
+            ))
+        })
+        .collect()
+}
+
+/// max_chars 文字を超える行を先頭だけに縮め、/show で全文を開く案内を付ける。
+/// line_no は /show に渡す 1 始まりの行番号。max_chars が 0 なら縮めない
+pub fn collapse_long(line: &str, max_chars: usize, line_no: usize) -> Cow<'_, str> {
+    match line.char_indices().nth(max_chars) {
+        Some((cut, _)) if max_chars > 0 => {
+            Cow::Owned(format!("{}… (全文: /show {})", &line[..cut], line_no))
+        }
+        _ => Cow::Borrowed(line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_and_invalid_keys_fall_back_to_preset() {
+        let tbl: Table = "[theme]\npreset = \"dark\"\nown = \"green\"\ninvalid = \"nope\"\n"
+            .parse()
+            .unwrap();
+        let theme = Theme::from_config(&tbl);
+        let dark = Theme::preset("dark").unwrap();
+        assert_eq!(theme.own, Color::Green);
+        assert_eq!(theme.invalid, dark.invalid);
+        assert_eq!(theme.status_bg, dark.status_bg);
+        assert_eq!(Theme::from_config(&Table::new()), Theme::default());
+    }
+
+    #[test]
+    fn no_color_suppresses_line_colors() {
+        let tbl: Table = "[theme]\nno_color = true\n".parse().unwrap();
+        let theme = Theme::from_config(&tbl);
+        assert_eq!(theme.line_color(LineKind::Invalid), None);
+        assert!(Theme::preset("mono").unwrap().no_color);
+    }
+
+    #[test]
+    fn lines_are_classified_by_signature_mark_and_author() {
+        let own = "#0a1b2c3d4e5f6071 @me: hi ○";
+        assert_eq!(classify_line(own, "@me"), LineKind::Own);
+        assert_eq!(classify_line("@bob: hi ○", "@me"), LineKind::Valid);
+        assert_eq!(classify_line("@bob: hi ×", "@me"), LineKind::Invalid);
+        assert_eq!(classify_line("@2: hi ・", "@me"), LineKind::Unsigned);
+        assert_eq!(classify_line("@bob: hi ○ (揮発)", "@me"), LineKind::Valid);
+        assert_eq!(classify_line("@bob: hi ○ ✔", "@me"), LineKind::Verified);
+        assert_eq!(
+            classify_line("@bob: hi ○ ✔ (揮発)", "@me"),
+            LineKind::Verified
+        );
+        assert_eq!(classify_line("接続完了 id=0", "@me"), LineKind::System);
+        let joined = "[通知] @bob が参加しました (指紋=0a1b2c3d4e5f6071)";
+        assert_eq!(classify_line(joined, "@me"), LineKind::Announce);
+        assert_eq!(classify_line("@bob: hi ○ ✔ ‼至急", "@me"), LineKind::Urgent);
+        assert_eq!(
+            classify_line("@bob: hi ○ (揮発) ‼至急", "@me"),
+            LineKind::Urgent
+        );
+        assert_eq!(handle_span(own), Some((18, 21)));
+        assert_eq!(handle_span("接続完了 id=0"), None);
+    }
+
+    #[test]
+    fn bookmarked_lines_get_a_marker() {
+        let marks: HashSet<String> = ["0a1b".to_string()].into();
+        let line = "#0a1b @bob: hi ○";
+        assert_eq!(with_bookmark_mark(line, &marks), "#0a1b @bob: hi ○ ★");
+        assert_eq!(classify_line(line, "@me"), LineKind::Valid);
+        assert_eq!(
+            with_bookmark_mark("#ffff @bob: hi ○", &marks),
+            "#ffff @bob: hi ○"
+        );
+        assert_eq!(with_bookmark_mark("接続完了 id=0", &marks), "接続完了 id=0");
+    }
+
+    #[test]
+    fn saved_handle_color_wins_over_the_automatic_one() {
+        let theme = Theme::default();
+        let colors = HandleColors::default();
+        // 自動の色はハンドルだけで決まる
+        let auto = colors.color_of("@bob", "@me", &theme);
+        assert_eq!(
+            HandleColors::default().color_of("@bob", "@me", &theme),
+            auto
+        );
+        assert_eq!(colors.color_of("@me", "@me", &theme), theme.handle);
+
+        let saved = vec![
+            ("@bob".to_string(), "dark_red".to_string()),
+            ("@eve".to_string(), "nope".to_string()),
+        ];
+        let colors = HandleColors::from_saved(saved);
+        assert_eq!(colors.color_of("@bob", "@me", &theme), Color::DarkRed);
+        assert_eq!(colors.describe("@bob", "@me"), "dark_red (指定)");
+        assert_eq!(colors.describe("@eve", "@me"), auto_color_name("@eve"));
+    }
+
+    #[test]
+    fn consecutive_posts_from_one_handle_share_a_header() {
+        let lines = [
+            "@bob: hi ○",
+            "@bob: 元気？ ○",
+            "#0a1b @bob: 続き ○",
+            "接続完了 id=0",
+            "@bob: また ○",
+            "@alice: やあ ○",
+            "@ありす: a ○",
+            "@ありす: b ○",
+        ]
+        .map(String::from);
+        assert_eq!(
+            compact_lines(&lines, None),
+            [
+                "@bob: hi ○",
+                "      元気？ ○",
+                "#0a1b       続き ○",
+                "接続完了 id=0",
+                "@bob: また ○",
+                "@alice: やあ ○",
+                "@ありす: a ○",
+                "         b ○",
+            ]
+        );
+        // 続きから描くときは直前の行も見る
+        assert_eq!(
+            compact_lines(&lines[1..2], Some(&lines[0])),
+            ["      元気？ ○"]
+        );
+        // まとめた行からはハンドルを取り出さない（色付け・/legend の対象外）
+        assert_eq!(handle_span("      元気？ ○"), None);
+    }
+
+    #[test]
+    fn long_lines_are_collapsed_with_a_show_hint() {
+        assert_eq!(collapse_long("@bob: 短い", 10, 3), "@bob: 短い");
+        assert_eq!(collapse_long("@bob: 短い", 0, 3), "@bob: 短い");
+        // 文字数で数えるので、複数バイトの文字の途中では切らない
+        assert_eq!(
+            collapse_long("@bob: あいうえおかきくけこ", 8, 12),
+            "@bob: あい… (全文: /show 12)"
+        );
+        // ちょうど上限なら縮めない
+        assert_eq!(collapse_long("abcd", 4, 1), "abcd");
+        // 改行を含む投稿も先頭から数える
+        assert_eq!(collapse_long("ab\ncdef", 4, 2), "ab\nc… (全文: /show 2)");
+    }
+}
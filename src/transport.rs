@@ -0,0 +1,320 @@
+//! ピア間のバイト列を運ぶ下回り。ネットワークスレッドはこの trait 越しに待受・接続し、
+//! 実際の TCP の代わりにプロセス内の Memory をつないでテストできる
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// 1 本の接続
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    /// 読めるだけ読む。まだ何も届いていなければ待たずに WouldBlock を返す
+    fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    /// 書けるだけ書く。送信バッファが一杯なら待たずに WouldBlock を返す
+    fn try_write(&mut self, buf: &[u8]) -> io::Result<usize>;
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// 待受
+pub trait Acceptor: Send + Sync + 'static {
+    type Conn: Connection;
+    fn accept(&self) -> impl Future<Output = io::Result<(Self::Conn, SocketAddr)>> + Send;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// 待受と接続の作り方
+pub trait Transport: Clone + Send + Sync + 'static {
+    type Conn: Connection;
+    type Listener: Acceptor<Conn = Self::Conn>;
+    /// "host:port" で待ち受ける（port 0 なら空いているものを選ぶ）
+    fn bind(&self, addr: &str) -> impl Future<Output = io::Result<Self::Listener>> + Send;
+    /// "host:port" へつなぐ
+    fn connect(&self, addr: &str) -> impl Future<Output = io::Result<Self::Conn>> + Send;
+}
+
+/// 実際の TCP
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Tcp;
+
+impl Connection for TcpStream {
+    fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        TcpStream::try_read(self, buf)
+    }
+
+    fn try_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        TcpStream::try_write(self, buf)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+}
+
+impl Acceptor for TcpListener {
+    type Conn = TcpStream;
+
+    fn accept(&self) -> impl Future<Output = io::Result<(TcpStream, SocketAddr)>> + Send {
+        TcpListener::accept(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+}
+
+impl Transport for Tcp {
+    type Conn = TcpStream;
+    type Listener = TcpListener;
+
+    fn bind(&self, addr: &str) -> impl Future<Output = io::Result<TcpListener>> + Send {
+        TcpListener::bind(addr.to_string())
+    }
+
+    fn connect(&self, addr: &str) -> impl Future<Output = io::Result<TcpStream>> + Send {
+        TcpStream::connect(addr.to_string())
+    }
+}
+
+/// 片側のバッファの大きさ
+const MEMORY_BUFFER_BYTES: usize = 64 * 1024;
+
+/// プロセス内だけの仮想ネットワーク。clone したものは同じ待受の一覧を共有する。
+/// アドレスのポート番号だけを見て、そのポートの待受へつなぐ
+#[derive(Debug, Default, Clone)]
+pub struct Memory {
+    inner: Arc<Mutex<MemoryNet>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryNet {
+    listeners: HashMap<u16, mpsc::UnboundedSender<MemoryConn>>,
+    next_port: u16,
+}
+
+impl MemoryNet {
+    fn allocate(&mut self) -> u16 {
+        // 動的ポートの範囲から順に使う
+        loop {
+            self.next_port = self.next_port.max(49152).wrapping_add(1).max(49152);
+            if !self.listeners.contains_key(&self.next_port) {
+                return self.next_port;
+            }
+        }
+    }
+}
+
+/// Memory の接続（tokio::io::duplex の片側）
+#[derive(Debug)]
+pub struct MemoryConn {
+    stream: DuplexStream,
+    local: SocketAddr,
+    peer: SocketAddr,
+}
+
+/// Memory の待受
+#[derive(Debug)]
+pub struct MemoryListener {
+    port: u16,
+    incoming: tokio::sync::Mutex<mpsc::UnboundedReceiver<MemoryConn>>,
+    net: Arc<Mutex<MemoryNet>>,
+}
+
+fn port_of(addr: &str) -> io::Result<u16> {
+    addr.rsplit_once(':')
+        .and_then(|(_, p)| p.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, addr.to_string()))
+}
+
+fn loopback(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+impl Transport for Memory {
+    type Conn = MemoryConn;
+    type Listener = MemoryListener;
+
+    async fn bind(&self, addr: &str) -> io::Result<MemoryListener> {
+        let mut net = self.inner.lock().unwrap();
+        let port = match port_of(addr)? {
+            0 => net.allocate(),
+            p if net.listeners.contains_key(&p) => {
+                return Err(io::ErrorKind::AddrInUse.into());
+            }
+            p => p,
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        net.listeners.insert(port, tx);
+        Ok(MemoryListener {
+            port,
+            incoming: tokio::sync::Mutex::new(rx),
+            net: self.inner.clone(),
+        })
+    }
+
+    async fn connect(&self, addr: &str) -> io::Result<MemoryConn> {
+        let port = port_of(addr)?;
+        let mut net = self.inner.lock().unwrap();
+        // 接続元にも番号を振っておく（相手の peer_addr 用）
+        let local = net.allocate();
+        let listener = net
+            .listeners
+            .get(&port)
+            .ok_or(io::ErrorKind::ConnectionRefused)?;
+        let (ours, theirs) = tokio::io::duplex(MEMORY_BUFFER_BYTES);
+        listener
+            .send(MemoryConn {
+                stream: theirs,
+                local: loopback(port),
+                peer: loopback(local),
+            })
+            .map_err(|_| io::ErrorKind::ConnectionRefused)?;
+        Ok(MemoryConn {
+            stream: ours,
+            local: loopback(local),
+            peer: loopback(port),
+        })
+    }
+}
+
+impl Acceptor for MemoryListener {
+    type Conn = MemoryConn;
+
+    async fn accept(&self) -> io::Result<(MemoryConn, SocketAddr)> {
+        let conn = self
+            .incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(io::ErrorKind::NotConnected)?;
+        let peer = conn.peer;
+        Ok((conn, peer))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(loopback(self.port))
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        if let Ok(mut net) = self.net.lock() {
+            net.listeners.remove(&self.port);
+        }
+    }
+}
+
+impl Connection for MemoryConn {
+    fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // 一度だけ poll し、届いていなければ待たない
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut rb = ReadBuf::new(buf);
+        match Pin::new(&mut self.stream).poll_read(&mut cx, &mut rb) {
+            Poll::Ready(Ok(())) => Ok(rb.filled().len()),
+            Poll::Ready(Err(e)) => Err(e),
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn try_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut cx = Context::from_waker(Waker::noop());
+        match Pin::new(&mut self.stream).poll_write(&mut cx, buf) {
+            Poll::Ready(r) => r,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+}
+
+impl AsyncRead for MemoryConn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MemoryConn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn memory_connection_carries_bytes_both_ways() {
+        let net = Memory::default();
+        let listener = net.bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(net.bind(&format!("127.0.0.1:{}", port)).await.is_err());
+
+        let mut client = net.connect(&format!("127.0.0.1:{}", port)).await.unwrap();
+        let (mut server, from) = listener.accept().await.unwrap();
+        assert_eq!(client.peer_addr().unwrap().port(), port);
+        assert_eq!(server.peer_addr().unwrap(), from);
+        assert_eq!(server.local_addr().unwrap().port(), port);
+
+        let mut buf = [0u8; 16];
+        // 何も届いていなければ待たない
+        let e = server.try_read(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        client.write_all(b"hello").await.unwrap();
+        assert_eq!(server.try_read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        server.write_all(b"hi").await.unwrap();
+        assert_eq!(client.try_read(&mut buf).unwrap(), 2);
+
+        // 相手が読まずにバッファが一杯なら、書けるところまで書いて待たない
+        let chunk = vec![0u8; MEMORY_BUFFER_BYTES];
+        assert_eq!(client.try_write(&chunk).unwrap(), MEMORY_BUFFER_BYTES);
+        let e = client.try_write(b"x").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+
+        // 相手が閉じたら 0
+        drop(server);
+        assert_eq!(client.try_read(&mut buf).unwrap(), 0);
+        // 待受を捨てたらつながらない
+        drop(listener);
+        let e = net
+            .connect(&format!("127.0.0.1:{}", port))
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
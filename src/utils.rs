@@ -1,4 +1,4 @@
-/// 雑多なもの置き場
+//! 雑多なもの置き場
 
 pub fn current_unix_millis() -> u64 {
     use std::time::{SystemTime, UNIX_EPOCH};
@@ -7,3 +7,39 @@ pub fn current_unix_millis() -> u64 {
         .unwrap_or_default()
         .as_millis() as u64
 }
+
+/// 現在時刻の取り出し口。テストでは ManualClock を渡して時間を進める
+pub trait Clock: Send + Sync {
+    fn now_millis(&self) -> u64;
+}
+
+/// 実際の時計 (SystemTime::now)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        current_unix_millis()
+    }
+}
+
+/// 手で進める時計。clone したものは同じ時刻を共有する
+#[derive(Debug, Default, Clone)]
+pub struct ManualClock(std::sync::Arc<std::sync::atomic::AtomicU64>);
+
+impl ManualClock {
+    pub fn new(start_millis: u64) -> Self {
+        Self(std::sync::Arc::new(start_millis.into()))
+    }
+
+    pub fn advance(&self, millis: u64) {
+        self.0
+            .fetch_add(millis, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_millis(&self) -> u64 {
+        self.0.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
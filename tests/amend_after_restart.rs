@@ -0,0 +1,84 @@
+mod common;
+
+use common::{next_event, open_port, signed, test_dir};
+use p2witter::core::{crypto, protocol, rpc};
+use p2witter::network_handler::network_handler;
+use p2witter::storage;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::JoinHandle;
+
+type Node = (
+    Sender<rpc::Command>,
+    Receiver<rpc::Event>,
+    JoinHandle<()>,
+    TcpStream,
+);
+
+// ネットワークスレッドを起動し、bob として HELLO まで済ませる
+async fn start_and_join(bob: &crypto::Ed25519KeyPairMaterial) -> Node {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+    let (cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+    let task = tokio::spawn(network_handler(tx, rx_cmd));
+    let port = open_port(&cmd, &mut rx).await;
+    let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let hello = signed(
+        protocol::Message::hello(1_700_000_000_000, "@bob", None),
+        bob,
+    );
+    peer.write_all(&protocol::encode(&hello)).await.unwrap();
+    loop {
+        if let rpc::Event::HandshakeComplete { .. } = next_event(&mut rx).await {
+            break;
+        }
+    }
+    (cmd, rx, task, peer)
+}
+
+// 作者の鍵はメモリにしか覚えていなかったので、起動し直すと前の投稿への編集を捨てていた
+#[tokio::test]
+async fn edit_of_a_post_stored_before_restart_is_applied() {
+    let dir = test_dir("amend");
+    storage::init_storage(&dir).unwrap();
+    let bob = crypto::generate_ed25519_keypair().unwrap();
+
+    let ts = 1_700_000_001_000;
+    let (cmd, mut rx, task, mut peer) = start_and_join(&bob).await;
+    let chat = signed(protocol::Message::chat("@bob: typo", ts), &bob);
+    peer.write_all(&protocol::encode(&chat)).await.unwrap();
+    let id = loop {
+        if let rpc::Event::Chat { id, .. } = next_event(&mut rx).await {
+            break id;
+        }
+    };
+    cmd.send(rpc::Command::Shutdown).await.unwrap();
+    task.await.unwrap();
+    let target: [u8; protocol::MESSAGE_ID_LEN] = crypto::from_hex(&id).unwrap().try_into().unwrap();
+
+    // 起動し直したノードには、作者のキャッシュが空の状態で編集が届く
+    let (cmd, mut rx, task, mut peer) = start_and_join(&bob).await;
+    let mallory = crypto::generate_ed25519_keypair().unwrap();
+    let forged = signed(
+        protocol::Message::edit(ts + 1, &target, "@bob: 乗っ取り"),
+        &mallory,
+    );
+    peer.write_all(&protocol::encode(&forged)).await.unwrap();
+    let edit = signed(
+        protocol::Message::edit(ts + 2, &target, "@bob: 直した"),
+        &bob,
+    );
+    peer.write_all(&protocol::encode(&edit)).await.unwrap();
+    let line = loop {
+        if let rpc::Event::Replace { id: replaced, line } = next_event(&mut rx).await {
+            assert_eq!(replaced, id);
+            break line;
+        }
+    };
+    assert!(line.contains("@bob: 直した"), "{}", line);
+    cmd.send(rpc::Command::Shutdown).await.unwrap();
+    task.await.unwrap();
+    assert_eq!(storage::get_by_id(&id).unwrap().text, "@bob: 直した");
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use common::{next_event, open_port, signed, test_dir};
+use p2witter::core::{crypto, protocol, rpc};
+use p2witter::network_handler::network_handler;
+use p2witter::storage::{self, AuditKind};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{Duration, timeout};
+
+// 署名した後に本文をすり替えたチャット
+fn forged(keys: &crypto::Ed25519KeyPairMaterial, ts: u64) -> Vec<u8> {
+    let mut msg = signed(protocol::Message::chat("@mallory: hi", ts), keys);
+    msg.payload = b"@mallory: bye".to_vec();
+    protocol::encode(&msg)
+}
+
+// 偽の署名は 1 通ごとに監査ログへ 1 件残り、繰り返すと切断されてそれ以上は残らない
+#[tokio::test]
+async fn repeated_forged_signatures_are_audited_once_each_then_disconnected() {
+    let dir = test_dir("bad-sig");
+    storage::init_storage(&dir).unwrap();
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+    let (cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+    let task = tokio::spawn(network_handler(tx, rx_cmd));
+    let port = open_port(&cmd, &mut rx).await;
+
+    let mallory = crypto::generate_ed25519_keypair().unwrap();
+    let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let hello = signed(
+        protocol::Message::hello(1_700_000_000_000, "@mallory", None),
+        &mallory,
+    );
+    peer.write_all(&protocol::encode(&hello)).await.unwrap();
+    loop {
+        if let rpc::Event::HandshakeComplete { .. } = next_event(&mut rx).await {
+            break;
+        }
+    }
+
+    peer.write_all(&forged(&mallory, 1_700_000_001_000))
+        .await
+        .unwrap();
+    loop {
+        if let rpc::Event::SignatureFailed { id } = next_event(&mut rx).await {
+            assert_eq!(id, 0);
+            break;
+        }
+    }
+    let entries = storage::recent_audit(10);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].kind, AuditKind::BadSignature);
+    assert_eq!(entries[0].peer_id, Some(0));
+    assert_eq!(
+        entries[0].fingerprint.as_deref(),
+        Some(&crypto::fingerprint_hex(&mallory.public)[..16])
+    );
+
+    // 上限を超える分をまとめて送ると切断される
+    let mut burst = Vec::new();
+    for i in 0..10 {
+        burst.extend(forged(&mallory, 1_700_000_002_000 + i));
+    }
+    peer.write_all(&burst).await.unwrap();
+    let reason = loop {
+        if let rpc::Event::PeerDisconnected { id, reason } = next_event(&mut rx).await {
+            assert_eq!(id, 0);
+            break reason;
+        }
+    };
+    assert_eq!(reason, "署名不正の繰り返し");
+
+    // 相手には理由ID=10 の切断通知が届く
+    let mut buf = Vec::new();
+    let _ = timeout(Duration::from_secs(5), peer.read_to_end(&mut buf)).await;
+    let mut dec = protocol::Decoder::new();
+    dec.feed(&buf);
+    let reasons: Vec<u32> = dec
+        .drain()
+        .unwrap()
+        .iter()
+        .filter_map(protocol::disconnect_reason_id)
+        .collect();
+    assert_eq!(reasons, [10]);
+
+    cmd.send(rpc::Command::Shutdown).await.unwrap();
+    task.await.unwrap();
+
+    let kinds: Vec<AuditKind> = storage::recent_audit(100).iter().map(|e| e.kind).collect();
+    assert_eq!(
+        kinds
+            .iter()
+            .filter(|k| **k == AuditKind::BadSignature)
+            .count(),
+        5
+    );
+    assert_eq!(kinds.last(), Some(&AuditKind::Disconnect));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
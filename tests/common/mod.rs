@@ -1,9 +1,90 @@
 //! Test utilities for p2witter
 //!
-//! Provides helper functions for creating mesh networks using tokio::io::duplex
+//! Provides helper functions for creating mesh networks using tokio::io::duplex,
+//! and for driving a real network handler from an integration test.
+//!
+//! 設定と保存先はプロセス全体で1つなので、それを使うテストは lib のテストとは別の
+//! テストバイナリ（1 ファイルに 1 テスト）で確かめる。
+
+#![allow(dead_code)]
 
+use p2witter::config;
+use p2witter::core::{crypto, protocol, rpc};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::{Duration, timeout};
+
+/// テストごとの作業ディレクトリ（`<temp>/p2witter-<name>-<pid>`）
+pub fn test_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("p2witter-{}-{}", name, std::process::id()))
+}
+
+/// 設定ファイルを書いて読み込み、作業ディレクトリを返す。
+/// `settings` はトップレベルに置く行で、`keys` を渡すと `[key]` も書く
+pub fn init_config(
+    name: &str,
+    settings: &str,
+    handle: &str,
+    keys: Option<&crypto::Ed25519KeyPairMaterial>,
+) -> PathBuf {
+    let dir = test_dir(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut body = format!("{}[user]\nhandle = \"{}\"\n", settings, handle);
+    if let Some(keys) = keys {
+        body.push_str(&format!(
+            "[key]\npkcs8 = \"{}\"\npublic = \"{}\"\n",
+            crypto::to_hex(&keys.pkcs8),
+            crypto::to_hex(&keys.public)
+        ));
+    }
+    let path = dir.join("config.toml");
+    std::fs::write(&path, body).unwrap();
+    config::init_config_path(&path).unwrap();
+    dir
+}
+
+/// 次のイベントを待つ。届かないまま時間が過ぎたら失敗させる
+pub async fn next_event(rx: &mut Receiver<rpc::Event>) -> rpc::Event {
+    timeout(Duration::from_secs(10), rx.recv())
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+/// `keys` で署名したメッセージ
+pub fn signed(msg: protocol::Message, keys: &crypto::Ed25519KeyPairMaterial) -> protocol::Message {
+    let sig = crypto::sign_ed25519(&protocol::signing_bytes(&msg), &keys.pkcs8).unwrap();
+    msg.with_key_sig(keys.public.clone(), sig)
+}
+
+// `/open 0` を送り、待受開始の告知の "port=" 以降を返す
+async fn open_any(cmd: &Sender<rpc::Command>, rx: &mut Receiver<rpc::Event>) -> String {
+    cmd.send(rpc::Command::Open("0".into(), None))
+        .await
+        .unwrap();
+    loop {
+        if let rpc::Event::Message(m) = next_event(rx).await
+            && let Some(rest) = m.strip_prefix("待受開始 port=")
+        {
+            return rest.to_string();
+        }
+    }
+}
+
+/// 空いているポートで待ち受けを始め、その番号を返す
+pub async fn open_port(cmd: &Sender<rpc::Command>, rx: &mut Receiver<rpc::Event>) -> u16 {
+    let rest = open_any(cmd, rx).await;
+    rest.split(' ').next().unwrap().parse().unwrap()
+}
+
+/// 待ち受けを始め、告知された接続トークンを返す（メモリ上のトランスポート向け）
+pub async fn open_token(cmd: &Sender<rpc::Command>, rx: &mut Receiver<rpc::Event>) -> String {
+    let rest = open_any(cmd, rx).await;
+    let tok = rest.split("token=").nth(1).unwrap();
+    tok.trim_end_matches(')').to_string()
+}
 
 /// Represents a node in the test mesh network
 pub struct MeshNode {
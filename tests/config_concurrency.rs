@@ -0,0 +1,48 @@
+mod common;
+
+use common::test_dir;
+use p2witter::config;
+use toml::Value;
+
+// 複数のスレッドが別々のキーを同時に書いても、どれも失われない
+#[test]
+fn concurrent_upserts_keep_every_key() {
+    let dir = test_dir("config-race");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.toml");
+    config::init_config_path(&path).unwrap();
+
+    let threads: Vec<_> = (0..8)
+        .map(|t| {
+            std::thread::spawn(move || {
+                for i in 0..20 {
+                    let key = format!("race.t{}_{}", t, i);
+                    config::upsert_value_and_save(&key, Value::Integer(t * 100 + i)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    let on_disk: toml::Table = std::fs::read_to_string(&path).unwrap().parse().unwrap();
+    for t in 0..8 {
+        for i in 0..20 {
+            let key = format!("race.t{}_{}", t, i);
+            assert_eq!(
+                config::get_value(&key),
+                Some(Value::Integer(t * 100 + i)),
+                "{}",
+                key
+            );
+            assert_eq!(
+                config::get_value_in(&on_disk, &key),
+                Some(Value::Integer(t * 100 + i)),
+                "{}",
+                key
+            );
+        }
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
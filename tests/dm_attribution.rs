@@ -0,0 +1,92 @@
+mod common;
+
+use common::{next_event, open_port, signed, test_dir};
+use p2witter::core::{crypto, protocol, rpc};
+use p2witter::network_handler::network_handler;
+use p2witter::storage;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::Receiver;
+
+// HELLO まで済ませたピアを用意する
+async fn join(
+    port: u16,
+    handle: &str,
+    keys: &crypto::Ed25519KeyPairMaterial,
+    rx: &mut Receiver<rpc::Event>,
+) -> TcpStream {
+    let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let hello = signed(
+        protocol::Message::hello(1_700_000_000_000, handle, None),
+        keys,
+    );
+    peer.write_all(&protocol::encode(&hello)).await.unwrap();
+    loop {
+        if let rpc::Event::HandshakeComplete { .. } = next_event(rx).await {
+            return peer;
+        }
+    }
+}
+
+// DM を受けた後に手前のピアが抜けて接続番号が詰まっても、送り主は指紋とハンドルで分かる
+#[tokio::test]
+async fn dm_sender_is_known_after_peers_are_renumbered() {
+    let dir = test_dir("dm-attr");
+    storage::init_storage(&dir).unwrap();
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+    let (cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+    let task = tokio::spawn(network_handler(tx, rx_cmd));
+    let port = open_port(&cmd, &mut rx).await;
+
+    let carol = crypto::generate_ed25519_keypair().unwrap();
+    let bob = crypto::generate_ed25519_keypair().unwrap();
+    let carol_peer = join(port, "@carol", &carol, &mut rx).await;
+    let mut bob_peer = join(port, "@bob", &bob, &mut rx).await;
+
+    // bob (接続番号 1) からの DM
+    let payload = crypto::encrypt_dm_payload("@bob: 内緒の話".as_bytes()).unwrap();
+    let dm = signed(
+        protocol::Message::dm_bytes(payload, 1_700_000_001_000),
+        &bob,
+    );
+    bob_peer.write_all(&protocol::encode(&dm)).await.unwrap();
+    loop {
+        if let rpc::Event::Post { line, .. } = next_event(&mut rx).await
+            && line.contains("内緒の話")
+        {
+            break;
+        }
+    }
+    // 通知は保存の後に来る（おやすみモードで通知を止めても DM は残る）
+    loop {
+        if let rpc::Event::DmArrived { from } = next_event(&mut rx).await {
+            assert_eq!(from, "@bob");
+            break;
+        }
+    }
+    assert_eq!(storage::dm_thread("@bob").len(), 1);
+
+    // carol が抜けると bob が接続番号 0 になり、保存した番号 1 は誰も指さない
+    drop(carol_peer);
+    loop {
+        if let rpc::Event::PeerDisconnected { .. } = next_event(&mut rx).await {
+            break;
+        }
+    }
+    cmd.send(rpc::Command::Shutdown).await.unwrap();
+    task.await.unwrap();
+
+    let thread = storage::dm_thread("@bob");
+    assert_eq!(thread.len(), 1);
+    assert_eq!(thread[0].text, "@bob: 内緒の話");
+    assert_eq!(thread[0].from_peer_id, Some(1));
+    assert_eq!(thread[0].handle.as_deref(), Some("@bob"));
+    assert_eq!(
+        thread[0].peer_fingerprint.as_deref(),
+        Some(&crypto::fingerprint_hex(&bob.public)[..16])
+    );
+    assert!(storage::dm_thread("@carol").is_empty());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,221 @@
+mod common;
+
+use common::{init_config, next_event, open_port, signed};
+use p2witter::core::{crypto, protocol, rpc};
+use p2witter::network_handler::network_handler;
+use p2witter::storage;
+use std::collections::VecDeque;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{Duration, timeout};
+
+// 履歴同期は今日からさかのぼった範囲の日だけを扱うので、投稿は今日の 0 時から始める
+fn day_start() -> u64 {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    now - now % 86_400_000
+}
+
+fn id_of(msg: &protocol::Message) -> [u8; protocol::MESSAGE_ID_LEN] {
+    let mut v = msg.public_key.clone().unwrap();
+    v.extend_from_slice(&protocol::signing_bytes(msg));
+    let d = ring::digest::digest(&ring::digest::SHA256, &v);
+    d.as_ref()[..protocol::MESSAGE_ID_LEN].try_into().unwrap()
+}
+
+// 受信した投稿として保存する（/reverify や同期で使う署名材料付き）
+fn store(msg: &protocol::Message) {
+    let text = String::from_utf8_lossy(&msg.payload).to_string();
+    let rec = storage::MessageRecord {
+        ts_millis: msg.timestamp,
+        recv_ts_millis: msg.timestamp,
+        kind: storage::MsgKind::Chat,
+        from_peer_id: None,
+        to_peer_id: None,
+        handle: None,
+        text,
+        signature: rpc::SigState::Valid,
+        reply_to: None,
+        binary: None,
+        proof: Some(storage::SignatureProof {
+            public_key: msg.public_key.clone().unwrap(),
+            signature: msg.signature.clone().unwrap(),
+            signed: protocol::signing_bytes(msg),
+        }),
+        peer_fingerprint: None,
+    };
+    storage::store_structured(&rec, Some(&crypto::to_hex(&id_of(msg)))).unwrap();
+}
+
+// ピアから届いたフレーム。探している kind 以外も取っておき、次に探すときに使う
+struct Inbox {
+    decoder: protocol::Decoder,
+    pending: VecDeque<protocol::Message>,
+}
+
+impl Inbox {
+    fn new() -> Self {
+        Self {
+            decoder: protocol::Decoder::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    // 指定した kind のフレームが届くまで読む
+    async fn next_frame(&mut self, stream: &mut TcpStream, kind: u8) -> protocol::Message {
+        let mut buf = [0u8; 4096];
+        loop {
+            self.pending.extend(self.decoder.drain().unwrap());
+            if let Some(i) = self.pending.iter().position(|m| m.kind == kind) {
+                return self.pending.remove(i).unwrap();
+            }
+            let n = timeout(Duration::from_secs(5), stream.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(n > 0, "切断された");
+            self.decoder.feed(&buf[..n]);
+        }
+    }
+}
+
+// 途中で離れていたノード (bob) を実際のハンドラで動かし、ずっと居たノード (alice) は
+// プロトコルを直接話すピアとして用意する
+#[tokio::test]
+async fn node_that_was_offline_catches_up_after_reconnecting() {
+    let bob = crypto::generate_ed25519_keypair().unwrap();
+    let alice = crypto::generate_ed25519_keypair().unwrap();
+    let dir = init_config("history-sync", "history_sync = true\n", "@bob", Some(&bob));
+    storage::init_storage(dir.join("p2witter.db")).unwrap();
+
+    let base = day_start();
+    let day = storage::date_string(base);
+    // alice の投稿 3 件のうち、bob は最初の 1 件だけ受け取ってから離れた。
+    // bob も離れている間に 1 件書いていた
+    let posts: Vec<protocol::Message> = (0..3)
+        .map(|i| {
+            let text = format!("@alice: post {}", i);
+            signed(protocol::Message::chat(&text, base + i), &alice)
+        })
+        .collect();
+    let own = signed(
+        protocol::Message::chat("@bob: while apart", base + 500),
+        &bob,
+    );
+    store(&posts[0]);
+    store(&own);
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+    let (cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+    let task = tokio::spawn(network_handler(tx, rx_cmd));
+    let port = open_port(&cmd, &mut rx).await;
+
+    // 再接続: HELLO の後に bob から日ごとの件数が届く
+    let mut peer = TcpStream::connect(format!("127.0.0.1:{}", port))
+        .await
+        .unwrap();
+    let mut inbox = Inbox::new();
+    let hello = signed(
+        protocol::Message::hello(base + 1_000, "@alice", None),
+        &alice,
+    );
+    peer.write_all(&protocol::encode(&hello)).await.unwrap();
+    let counts = inbox
+        .next_frame(&mut peer, protocol::MsgKind::SYNC_COUNTS)
+        .await;
+    assert_eq!(protocol::sync_counts(&counts), Some(vec![(day.clone(), 2)]));
+
+    // alice は 3 件持っていると伝える → bob は持っている ID を添えて要求してくる
+    let ours = protocol::Message::sync_counts(1, &[(day.clone(), 3)]);
+    peer.write_all(&protocol::encode(&ours)).await.unwrap();
+    let req = inbox
+        .next_frame(&mut peer, protocol::MsgKind::SYNC_REQUEST)
+        .await;
+    let (date, have) = protocol::sync_request_parts(&req).unwrap();
+    assert_eq!(date, day);
+    assert_eq!(have, vec![id_of(&posts[0]), id_of(&own)]);
+
+    // 持っているものも含めて全部送っても、ID で重複は除かれる
+    let frames: Vec<Vec<u8>> = posts.iter().map(protocol::encode).collect();
+    let records = protocol::Message::sync_records(1, &day, &frames);
+    peer.write_all(&protocol::encode(&records)).await.unwrap();
+    loop {
+        if let rpc::Event::Message(m) = next_event(&mut rx).await
+            && m.starts_with("履歴同期: id=0 から")
+        {
+            assert!(
+                m.contains("投稿 2 件を取り込み (重複 1 件・不正 0 件)"),
+                "{}",
+                m
+            );
+            break;
+        }
+    }
+    let texts: Vec<String> = storage::records_with_ids(&day)
+        .into_iter()
+        .map(|(_, r)| r.text)
+        .collect();
+    assert_eq!(
+        texts,
+        [
+            "@alice: post 0",
+            "@bob: while apart",
+            "@alice: post 1",
+            "@alice: post 2"
+        ]
+    );
+
+    // 逆向き: alice が持っていない bob の投稿だけが署名付きのまま返ってくる
+    let have: Vec<_> = posts.iter().map(id_of).collect();
+    let req = protocol::Message::sync_request(1, &day, &have);
+    peer.write_all(&protocol::encode(&req)).await.unwrap();
+    let reply = inbox
+        .next_frame(&mut peer, protocol::MsgKind::SYNC_RECORDS)
+        .await;
+    let (date, got) = protocol::sync_records_parts(&reply).unwrap();
+    assert_eq!(date, day);
+    assert_eq!(got, vec![own.clone()]);
+
+    // 要求していない日の投稿は受け取らない
+    let yesterday = storage::date_string(base - 1);
+    let stray = signed(protocol::Message::chat("@alice: unasked", base - 1), &alice);
+    let records = protocol::Message::sync_records(1, &yesterday, &[protocol::encode(&stray)]);
+    peer.write_all(&protocol::encode(&records)).await.unwrap();
+    loop {
+        if let rpc::Event::Message(m) = next_event(&mut rx).await
+            && m.starts_with("履歴同期: id=0 から")
+        {
+            assert!(m.contains("要求していない"), "{}", m);
+            break;
+        }
+    }
+    assert!(storage::records_with_ids(&yesterday).is_empty());
+
+    // 1 フレームに収まらない分は、何ページかに分けて返ってくる
+    let long: Vec<protocol::Message> = (0..300)
+        .map(|i| {
+            let text = format!("@bob: {} {}", i, "x".repeat(2000));
+            signed(protocol::Message::chat(&text, base + 2_000 + i), &bob)
+        })
+        .collect();
+    long.iter().for_each(store);
+    let have: Vec<_> = posts.iter().chain([&own]).map(id_of).collect();
+    let req = protocol::Message::sync_request(1, &day, &have);
+    peer.write_all(&protocol::encode(&req)).await.unwrap();
+    let (mut pages, mut got) = (0, Vec::new());
+    while got.len() < long.len() {
+        let reply = inbox
+            .next_frame(&mut peer, protocol::MsgKind::SYNC_RECORDS)
+            .await;
+        got.extend(protocol::sync_records_parts(&reply).unwrap().1);
+        pages += 1;
+    }
+    assert!(pages > 1, "{}", pages);
+    assert_eq!(got, long);
+
+    cmd.send(rpc::Command::Shutdown).await.unwrap();
+    task.await.unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,52 @@
+mod common;
+
+use common::{init_config, next_event, open_port};
+use p2witter::core::rpc;
+use p2witter::network_handler::network_handler_with_clock;
+use p2witter::utils::ManualClock;
+use std::sync::Arc;
+use tokio::time::Duration;
+
+#[tokio::test]
+async fn advancing_the_clock_drops_a_silent_peer() {
+    let dir = init_config("idle-clock", "idle_timeout_secs = 30\n", "@alice", None);
+
+    let clock = ManualClock::new(1_700_000_000_000);
+    let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+    let (cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+    let task = tokio::spawn(network_handler_with_clock(
+        tx,
+        rx_cmd,
+        Arc::new(clock.clone()),
+    ));
+    let port = open_port(&cmd, &mut rx).await;
+
+    // 何も送らないピア
+    let _silent = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port))
+        .await
+        .unwrap();
+    loop {
+        if let rpc::Event::PeerConnected { id, .. } = next_event(&mut rx).await {
+            assert_eq!(id, 0);
+            break;
+        }
+    }
+
+    // 時計が止まっている間は、実時間が過ぎても切断されない
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    while let Ok(ev) = rx.try_recv() {
+        assert!(!matches!(ev, rpc::Event::PeerDisconnected { .. }));
+    }
+
+    clock.advance(31_000);
+    loop {
+        if let rpc::Event::PeerDisconnected { id, reason } = next_event(&mut rx).await {
+            assert_eq!(id, 0);
+            assert_eq!(reason, "無通信タイムアウト");
+            break;
+        }
+    }
+    cmd.send(rpc::Command::Shutdown).await.unwrap();
+    task.await.unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use common::{init_config, next_event, open_token};
+use p2witter::core::{crypto, rpc};
+use p2witter::network_handler::network_handler_with_transport;
+use p2witter::transport::Memory;
+use p2witter::utils::ManualClock;
+use std::sync::Arc;
+use tokio::time::Duration;
+
+// 黙っていても PING に PONG を返すピアは、無通信タイムアウトを過ぎても切られない
+#[tokio::test]
+async fn quiet_peer_answering_pings_is_kept() {
+    let keys = crypto::generate_ed25519_keypair().unwrap();
+    let dir = init_config(
+        "keepalive",
+        "idle_timeout_secs = 30\n",
+        "@alice",
+        Some(&keys),
+    );
+
+    let clock = ManualClock::new(1_700_000_000_000);
+    let net = Memory::default();
+    let spawn = |net: Memory| {
+        let (tx, rx) = tokio::sync::mpsc::channel(256);
+        let (cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler_with_transport(
+            tx,
+            rx_cmd,
+            Arc::new(clock.clone()),
+            net,
+        ));
+        (cmd, rx, task)
+    };
+    let (cmd_a, mut rx_a, task_a) = spawn(net.clone());
+    let (cmd_b, mut rx_b, task_b) = spawn(net);
+    let other = crypto::generate_ed25519_keypair().unwrap();
+    cmd_b
+        .send(rpc::Command::RotateKey(other.pkcs8, other.public))
+        .await
+        .unwrap();
+    cmd_b
+        .send(rpc::Command::Handle("@bob".into()))
+        .await
+        .unwrap();
+
+    let token = open_token(&cmd_a, &mut rx_a).await;
+    cmd_b.send(rpc::Command::Connect(token)).await.unwrap();
+    for rx in [&mut rx_a, &mut rx_b] {
+        loop {
+            if let rpc::Event::HandshakeComplete { .. } = next_event(rx).await {
+                break;
+            }
+        }
+    }
+
+    // PING は 10 秒ごと。タイムアウトの 2 倍を超えるまで時計を進める
+    for _ in 0..7 {
+        clock.advance(11_000);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for rx in [&mut rx_a, &mut rx_b] {
+            while let Ok(ev) = rx.try_recv() {
+                if let rpc::Event::PeerDisconnected { reason, .. } = ev {
+                    panic!("生きているピアが切られた: {}", reason);
+                }
+            }
+        }
+    }
+
+    cmd_a.send(rpc::Command::Shutdown).await.unwrap();
+    task_a.await.unwrap();
+    cmd_b.send(rpc::Command::Shutdown).await.unwrap();
+    task_b.await.unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+}
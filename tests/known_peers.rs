@@ -0,0 +1,54 @@
+mod common;
+
+use common::{next_event, open_port, signed, test_dir};
+use p2witter::core::{crypto, protocol, rpc};
+use p2witter::network_handler::network_handler;
+use p2witter::storage;
+use tokio::io::AsyncWriteExt;
+
+#[tokio::test]
+async fn peer_is_remembered_after_it_disconnects() {
+    let dir = test_dir("known");
+    storage::init_storage(&dir).unwrap();
+
+    let (tx_main, mut rx_main) = tokio::sync::mpsc::channel(64);
+    let (tx_cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+    let task = tokio::spawn(network_handler(tx_main, rx_cmd));
+    let port = open_port(&tx_cmd, &mut rx_main).await;
+
+    // 署名付き HELLO を送ってから切断する
+    let keys = crypto::generate_ed25519_keypair().unwrap();
+    let hello = signed(
+        protocol::Message::hello(1_700_000_000_000, "@bob", None),
+        &keys,
+    );
+    let mut peer = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    peer.write_all(&protocol::encode(&hello)).await.unwrap();
+    loop {
+        if let rpc::Event::HandshakeComplete { .. } = next_event(&mut rx_main).await {
+            break;
+        }
+    }
+    drop(peer);
+    loop {
+        if let rpc::Event::PeerDisconnected { .. } = next_event(&mut rx_main).await {
+            break;
+        }
+    }
+    tx_cmd.send(rpc::Command::Shutdown).await.unwrap();
+    task.await.unwrap();
+
+    // 接続が無くなっても、最後に見た時刻とハンドルが残る
+    let known = storage::known_peers();
+    assert_eq!(known.len(), 1);
+    assert_eq!(
+        known[0].fingerprint,
+        &crypto::fingerprint_hex(&keys.public)[..16]
+    );
+    assert_eq!(known[0].handle.as_deref(), Some("@bob"));
+    assert!(known[0].last_seen_millis > 1_700_000_000_000);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use common::{init_config, next_event, open_port};
+use p2witter::core::{crypto, rpc};
+use p2witter::network_handler::network_handler;
+
+#[tokio::test]
+async fn connecting_two_nodes_reports_typed_lifecycle_events() {
+    let keys = crypto::generate_ed25519_keypair().unwrap();
+    let dir = init_config("lifecycle", "", "@alice", Some(&keys));
+
+    let (tx_a, mut rx_a) = tokio::sync::mpsc::channel(64);
+    let (cmd_a, rx_cmd_a) = tokio::sync::mpsc::channel(8);
+    let task_a = tokio::spawn(network_handler(tx_a, rx_cmd_a));
+    let port = open_port(&cmd_a, &mut rx_a).await;
+
+    let (tx_b, mut rx_b) = tokio::sync::mpsc::channel(64);
+    let (cmd_b, rx_cmd_b) = tokio::sync::mpsc::channel(8);
+    let task_b = tokio::spawn(network_handler(tx_b, rx_cmd_b));
+    let token = crypto::encrypt_conninfo_to_hex(&format!("127.0.0.1:{}", port)).unwrap();
+    cmd_b.send(rpc::Command::Connect(token)).await.unwrap();
+
+    // 受け入れた側: 接続 → HELLO の順に型付きのイベントが届く
+    loop {
+        match next_event(&mut rx_a).await {
+            rpc::Event::PeerConnected { id, inbound, .. } => {
+                assert_eq!((id, inbound), (0, true));
+                break;
+            }
+            rpc::Event::HandshakeComplete { .. } => panic!("接続より先に HELLO が届いた"),
+            _ => {}
+        }
+    }
+    let fingerprint = &crypto::fingerprint_hex(&keys.public)[..16];
+    loop {
+        if let rpc::Event::HandshakeComplete {
+            id,
+            handle,
+            fingerprint: fp,
+        } = next_event(&mut rx_a).await
+        {
+            assert_eq!(id, 0);
+            assert_eq!(handle, "@alice");
+            assert_eq!(fp, fingerprint);
+            break;
+        }
+    }
+    // 接続した側も相手の HELLO を受け取る
+    loop {
+        if let rpc::Event::HandshakeComplete { handle, .. } = next_event(&mut rx_b).await {
+            assert_eq!(handle, "@alice");
+            break;
+        }
+    }
+
+    // 接続した側が止まると、受け入れた側に切断が届く
+    cmd_b.send(rpc::Command::Shutdown).await.unwrap();
+    task_b.await.unwrap();
+    loop {
+        if let rpc::Event::PeerDisconnected { id, reason } = next_event(&mut rx_a).await {
+            assert_eq!(id, 0);
+            assert!(!reason.is_empty());
+            break;
+        }
+    }
+    cmd_a.send(rpc::Command::Shutdown).await.unwrap();
+    task_a.await.unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use common::{init_config, next_event, open_token};
+use p2witter::core::{crypto, rpc};
+use p2witter::network_handler::network_handler_with_transport;
+use p2witter::transport::Memory;
+use p2witter::utils::SystemClock;
+use std::sync::Arc;
+
+// 2 つのノードを実際のソケットを使わずにプロセス内でつなぐ
+#[tokio::test]
+async fn signed_chat_crosses_the_in_memory_transport() {
+    let keys = crypto::generate_ed25519_keypair().unwrap();
+    let dir = init_config("memory", "", "@alice", Some(&keys));
+
+    let net = Memory::default();
+    let spawn = |net: Memory| {
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        let (cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler_with_transport(
+            tx,
+            rx_cmd,
+            Arc::new(SystemClock),
+            net,
+        ));
+        (cmd, rx, task)
+    };
+    let (cmd_a, mut rx_a, task_a) = spawn(net.clone());
+    let (cmd_b, mut rx_b, task_b) = spawn(net);
+
+    let token = open_token(&cmd_a, &mut rx_a).await;
+    cmd_b
+        .send(rpc::Command::Handle("@bob".into()))
+        .await
+        .unwrap();
+    cmd_b.send(rpc::Command::Connect(token)).await.unwrap();
+    loop {
+        if let rpc::Event::HandshakeComplete { handle, .. } = next_event(&mut rx_a).await {
+            assert_eq!(handle, "@bob");
+            break;
+        }
+    }
+    loop {
+        if let rpc::Event::HandshakeComplete { handle, .. } = next_event(&mut rx_b).await {
+            assert_eq!(handle, "@alice");
+            break;
+        }
+    }
+
+    // 署名付きの投稿が届き、受けた側で検証される
+    cmd_b
+        .send(rpc::Command::Chat("memory 越しの投稿".into(), None, false))
+        .await
+        .unwrap();
+    let line = loop {
+        if let rpc::Event::Chat { line, .. } = next_event(&mut rx_a).await {
+            break line;
+        }
+    };
+    assert!(line.contains("@bob: memory 越しの投稿"), "{}", line);
+    assert!(line.contains(rpc::SigState::Valid.mark()), "{}", line);
+
+    cmd_b.send(rpc::Command::Shutdown).await.unwrap();
+    task_b.await.unwrap();
+    cmd_a.send(rpc::Command::Shutdown).await.unwrap();
+    task_a.await.unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+}
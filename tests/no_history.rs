@@ -0,0 +1,38 @@
+mod common;
+
+use common::test_dir;
+use p2witter::core::rpc::SigState;
+use p2witter::storage::{self, MessageRecord, MsgKind};
+
+#[test]
+fn no_history_keeps_every_write_out_of_the_db() {
+    let dir = test_dir("no-history");
+    storage::init_storage(&dir).unwrap();
+    storage::set_history_disabled(true);
+
+    let rec = MessageRecord {
+        ts_millis: 1_700_000_000_000,
+        recv_ts_millis: 1_700_000_000_000,
+        kind: MsgKind::Chat,
+        from_peer_id: None,
+        to_peer_id: None,
+        handle: Some("@alice".into()),
+        text: "@alice: hi".into(),
+        signature: SigState::Valid,
+        reply_to: None,
+        binary: None,
+        proof: None,
+        peer_fingerprint: None,
+    };
+    storage::store_structured(&rec, Some("0a1b2c3d4e5f6071")).unwrap();
+    storage::append_message(1_700_000_000_000, "@alice: hi");
+    assert!(storage::list_dates().is_empty());
+    assert!(storage::get_by_id("0a1b2c3d4e5f6071").is_none());
+
+    // 無効化を解けば通常どおり保存される
+    storage::set_history_disabled(false);
+    storage::store_structured(&rec, None).unwrap();
+    assert_eq!(storage::list_dates(), vec!["20231114"]);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
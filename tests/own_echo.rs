@@ -0,0 +1,59 @@
+mod common;
+
+use common::{init_config, next_event, open_port, signed};
+use p2witter::core::{crypto, protocol, rpc};
+use p2witter::network_handler::network_handler;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+// 自分の鍵の投稿がピアから戻ってきても表示せず、ローカルエコーの 1 行だけが残る
+#[tokio::test]
+async fn own_post_relayed_back_is_not_shown_twice() {
+    let keys = crypto::generate_ed25519_keypair().unwrap();
+    let dir = init_config("own-echo", "suppress_own_echo = true\n", "@me", Some(&keys));
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+    let (cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+    let task = tokio::spawn(network_handler(tx, rx_cmd));
+    let port = open_port(&cmd, &mut rx).await;
+    let bob = crypto::generate_ed25519_keypair().unwrap();
+    let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let hello = signed(
+        protocol::Message::hello(1_700_000_000_000, "@bob", None),
+        &bob,
+    );
+    peer.write_all(&protocol::encode(&hello)).await.unwrap();
+    loop {
+        if let rpc::Event::HandshakeComplete { .. } = next_event(&mut rx).await {
+            break;
+        }
+    }
+
+    // 送信時にローカルエコー済みの自分の投稿が、ピア経由で戻ってくる
+    let echoed = signed(
+        protocol::Message::chat("@me: ただいま", 1_700_000_001_000),
+        &keys,
+    );
+    let other = signed(
+        protocol::Message::chat("@bob: おかえり", 1_700_000_002_000),
+        &bob,
+    );
+    peer.write_all(&protocol::encode(&echoed)).await.unwrap();
+    peer.write_all(&protocol::encode(&other)).await.unwrap();
+    let shown = loop {
+        match next_event(&mut rx).await {
+            rpc::Event::Chat { line, .. } | rpc::Event::Post { line, .. } => {
+                if line.contains("おかえり") {
+                    break line;
+                }
+                assert!(!line.contains("ただいま"), "二重に表示された: {}", line);
+            }
+            _ => {}
+        }
+    };
+    assert!(shown.contains("@bob: おかえり"), "{}", shown);
+
+    cmd.send(rpc::Command::Shutdown).await.unwrap();
+    task.await.unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,49 @@
+use p2witter::core::crypto;
+use std::process::Command;
+
+fn setup(dir: &std::path::Path, args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_p2witter"))
+        .arg("--setup")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+fn read_key(cfg: &toml::Table) -> (Vec<u8>, Vec<u8>) {
+    let hex = |k: &str| crypto::from_hex(cfg["key"][k].as_str().unwrap()).unwrap();
+    (hex("pkcs8"), hex("public"))
+}
+
+// 設定はプロセス全体で1つなので、バイナリを別プロセスで起動して確かめる
+#[test]
+fn non_interactive_setup_writes_handle_and_parseable_key() {
+    let dir = std::env::temp_dir().join(format!("p2witter-setup-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config_path = dir.join("profiles/setup/config.toml");
+
+    let out = setup(&dir, &["--handle", "@alice", "--profile", "setup"]);
+    assert!(out.status.success(), "{:?}", out);
+    let cfg: toml::Table = std::fs::read_to_string(&config_path)
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(cfg["user"]["handle"].as_str(), Some("@alice"));
+    let (pkcs8, public) = read_key(&cfg);
+    assert_eq!(crypto::public_key_from_pkcs8(&pkcs8).unwrap(), public);
+
+    // 2回目はハンドルだけ変わり、鍵は残る
+    let out = setup(&dir, &["--handle=@bob", "--profile", "setup"]);
+    assert!(out.status.success(), "{:?}", out);
+    let cfg: toml::Table = std::fs::read_to_string(&config_path)
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(cfg["user"]["handle"].as_str(), Some("@bob"));
+    assert_eq!(read_key(&cfg).1, public);
+
+    // 不正なハンドルは書かずに失敗する
+    let out = setup(&dir, &["--handle", "bob", "--profile", "setup"]);
+    assert_eq!(out.status.code(), Some(1));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use common::{init_config, next_event, open_token};
+use p2witter::core::{crypto, rpc};
+use p2witter::network_handler::network_handler_with_transport;
+use p2witter::transport::Memory;
+use p2witter::utils::SystemClock;
+use std::sync::Arc;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::JoinHandle;
+
+async fn wait_handshake(rx: &mut Receiver<rpc::Event>) {
+    loop {
+        if let rpc::Event::HandshakeComplete { .. } = next_event(rx).await {
+            break;
+        }
+    }
+}
+
+fn short_fp(public: &[u8]) -> String {
+    crypto::fingerprint_hex(public)[..16].to_string()
+}
+
+type Node = (
+    Sender<rpc::Command>,
+    Receiver<rpc::Event>,
+    JoinHandle<()>,
+    String,
+);
+
+// ノードごとに別の鍵へ切り替えてから使う（設定はプロセスで共有されるため）
+async fn spawn_node(net: Memory) -> Node {
+    let (tx, rx) = tokio::sync::mpsc::channel(64);
+    let (cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+    let task = tokio::spawn(network_handler_with_transport(
+        tx,
+        rx_cmd,
+        Arc::new(SystemClock),
+        net,
+    ));
+    let keys = crypto::generate_ed25519_keypair().unwrap();
+    let fp = short_fp(&keys.public);
+    cmd.send(rpc::Command::RotateKey(keys.pkcs8, keys.public))
+        .await
+        .unwrap();
+    (cmd, rx, task, fp)
+}
+
+// A - B - C の鎖を組み、A から /topology で両端のつながりまで見えること
+#[tokio::test]
+async fn topology_maps_a_chain_of_three_nodes() {
+    let keys = crypto::generate_ed25519_keypair().unwrap();
+    let dir = init_config("topology", "share_topology = true\n", "@node", Some(&keys));
+
+    let net = Memory::default();
+    let (cmd_a, mut rx_a, task_a, fp_a) = spawn_node(net.clone()).await;
+    let (cmd_b, mut rx_b, task_b, fp_b) = spawn_node(net.clone()).await;
+    let (cmd_c, mut rx_c, task_c, fp_c) = spawn_node(net).await;
+
+    let token = open_token(&cmd_b, &mut rx_b).await;
+    cmd_a
+        .send(rpc::Command::Connect(token.clone()))
+        .await
+        .unwrap();
+    wait_handshake(&mut rx_a).await;
+    cmd_c.send(rpc::Command::Connect(token)).await.unwrap();
+    wait_handshake(&mut rx_c).await;
+
+    cmd_a.send(rpc::Command::Topology).await.unwrap();
+    let map = loop {
+        if let rpc::Event::Message(m) = next_event(&mut rx_a).await
+            && m.starts_with("構成 (")
+        {
+            break m;
+        }
+    };
+    assert!(map.starts_with("構成 (3 ノード, 返事 2 件):"), "{}", map);
+    let line = |fp: &str| {
+        map.lines()
+            .find(|l| l.starts_with(fp))
+            .unwrap_or_else(|| panic!("{} がない: {}", fp, map))
+            .to_string()
+    };
+    let a = line(&fp_a);
+    assert!(a.contains("(自分)"), "{}", a);
+    assert!(a.contains(&fp_b) && !a.contains(&fp_c), "{}", a);
+    let b = line(&fp_b);
+    assert!(b.contains(&fp_a) && b.contains(&fp_c), "{}", b);
+    let c = line(&fp_c);
+    assert!(c.contains(&fp_b) && !c.contains(&fp_a), "{}", c);
+
+    for (cmd, task) in [(cmd_c, task_c), (cmd_a, task_a), (cmd_b, task_b)] {
+        cmd.send(rpc::Command::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use common::{init_config, next_event, open_token};
+use p2witter::core::{crypto, rpc};
+use p2witter::network_handler::network_handler_with_transport;
+use p2witter::transport::Memory;
+use p2witter::utils::SystemClock;
+use std::sync::Arc;
+use tokio::time::{Duration, timeout};
+
+// 設定はプロセスで共有されるので、両方のノードに welcome_message が入っている。
+// 受け入れた側だけが送り、つないだ側からは送り返さない
+#[tokio::test]
+async fn accepted_peer_is_welcomed_after_hello() {
+    let keys = crypto::generate_ed25519_keypair().unwrap();
+    let dir = init_config(
+        "welcome",
+        "welcome_message = \"ようこそ、この部屋へ\"\n",
+        "@alice",
+        Some(&keys),
+    );
+
+    let net = Memory::default();
+    let spawn = |net: Memory| {
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        let (cmd, rx_cmd) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(network_handler_with_transport(
+            tx,
+            rx_cmd,
+            Arc::new(SystemClock),
+            net,
+        ));
+        (cmd, rx, task)
+    };
+    let (cmd_a, mut rx_a, task_a) = spawn(net.clone());
+    let (cmd_b, mut rx_b, task_b) = spawn(net);
+    let other = crypto::generate_ed25519_keypair().unwrap();
+    cmd_b
+        .send(rpc::Command::RotateKey(other.pkcs8, other.public))
+        .await
+        .unwrap();
+    cmd_b
+        .send(rpc::Command::Handle("@bob".into()))
+        .await
+        .unwrap();
+
+    let token = open_token(&cmd_a, &mut rx_a).await;
+    cmd_b.send(rpc::Command::Connect(token)).await.unwrap();
+
+    let line = loop {
+        if let rpc::Event::Post { line, .. } = next_event(&mut rx_b).await {
+            break line;
+        }
+    };
+    assert!(line.contains("@alice: ようこそ、この部屋へ"), "{}", line);
+
+    // つないだ側 (bob) からは送らない
+    cmd_a.send(rpc::Command::Shutdown).await.unwrap();
+    while let Ok(Some(ev)) = timeout(Duration::from_secs(5), rx_a.recv()).await {
+        if let rpc::Event::Post { line, .. } = ev {
+            panic!("歓迎メッセージが送り返された: {}", line);
+        }
+    }
+    task_a.await.unwrap();
+    cmd_b.send(rpc::Command::Shutdown).await.unwrap();
+    task_b.await.unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+}